@@ -0,0 +1,122 @@
+/* Automatic assignment of inner IPv6 addresses from a prefix (see Configuration::set_auto_prefix):
+ *
+ * The address of a peer is derived from its public key by hashing,
+ * hence every node of a mesh configured with the same prefix derives the same address
+ * for a peer without coordination, and the address is stable across restarts.
+ *
+ * Should the derived address collide with the allowed IPs of another peer,
+ * the next candidate (hashing a counter along the key) is used instead.
+ */
+use std::net::{IpAddr, Ipv6Addr};
+
+use blake2::{Blake2s, Digest};
+use x25519_dalek::PublicKey;
+
+use super::provision::contains;
+
+// domain separation of the hash
+const LABEL_AUTO_ADDRESS: &[u8] = b"wireguard-rs auto address v1";
+
+// candidate addresses tried before giving up (collisions are improbable in practice)
+const MAX_ATTEMPTS: u32 = 16;
+
+/// Derives the candidate address of the peer in the prefix
+///
+/// # Arguments
+///
+/// - `prefix`: The prefix (address, prefix length) of the addresses
+/// - `pk`: The public key of the peer
+/// - `attempt`: The index of the candidate (0 for the first candidate)
+pub fn derive_address(prefix: (Ipv6Addr, u32), pk: &PublicKey, attempt: u32) -> Ipv6Addr {
+    let (network, len) = prefix;
+    let host_mask: u128 = if len >= 128 { 0 } else { !0 >> len };
+    let mut counter = attempt;
+    loop {
+        let mut hsh = Blake2s::new();
+        hsh.input(LABEL_AUTO_ADDRESS);
+        hsh.input(&counter.to_le_bytes());
+        hsh.input(pk.as_bytes());
+        let digest = hsh.result();
+        let mut bytes = [0u8; 16];
+        bytes.copy_from_slice(&digest[..16]);
+        let host = u128::from_be_bytes(bytes) & host_mask;
+
+        // avoid the subnet-router anycast address (host part of zero),
+        // by a counter beyond those of the other candidates
+        if host != 0 || host_mask == 0 {
+            return Ipv6Addr::from((u128::from(network) & !host_mask) | host);
+        }
+        counter = counter.wrapping_add(MAX_ATTEMPTS);
+    }
+}
+
+/// Assigns the address of the peer: the first candidate not in use by another peer
+///
+/// # Arguments
+///
+/// - `prefix`: The prefix (address, prefix length) of the addresses
+/// - `pk`: The public key of the peer
+/// - `in_use`: The allowed IPs of the other peers
+///
+/// # Returns
+///
+/// The address or None if every candidate is in use
+pub fn assign_address(
+    prefix: (Ipv6Addr, u32),
+    pk: &PublicKey,
+    in_use: &[(IpAddr, u32)],
+) -> Option<Ipv6Addr> {
+    (0..MAX_ATTEMPTS)
+        .map(|attempt| derive_address(prefix, pk, attempt))
+        .find(|addr| {
+            let addr = IpAddr::V6(*addr);
+            !in_use.iter().any(|prefix| contains(prefix, &addr))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefix() -> (Ipv6Addr, u32) {
+        ("fd00:1234::".parse().unwrap(), 64)
+    }
+
+    #[test]
+    fn test_derive_address() {
+        let pk1 = PublicKey::from([1u8; 32]);
+        let pk2 = PublicKey::from([2u8; 32]);
+
+        // stable and within the prefix
+        let addr = derive_address(prefix(), &pk1, 0);
+        assert_eq!(addr, derive_address(prefix(), &pk1, 0));
+        assert_eq!(addr.segments()[..4], [0xfd00, 0x1234, 0, 0]);
+        assert_ne!(u128::from(addr) & (!0 >> 64), 0);
+
+        // distinct for other keys and candidates
+        assert_ne!(addr, derive_address(prefix(), &pk2, 0));
+        assert_ne!(addr, derive_address(prefix(), &pk1, 1));
+
+        // a /128 prefix is the address itself
+        let host = ("fd00::1".parse().unwrap(), 128);
+        assert_eq!(derive_address(host, &pk1, 0), host.0);
+    }
+
+    #[test]
+    fn test_assign_address() {
+        let pk = PublicKey::from([1u8; 32]);
+        let first = derive_address(prefix(), &pk, 0);
+        assert_eq!(assign_address(prefix(), &pk, &[]), Some(first));
+
+        // collision with the allowed IPs of another peer
+        let in_use = vec![(IpAddr::V6(first), 128)];
+        assert_eq!(
+            assign_address(prefix(), &pk, &in_use),
+            Some(derive_address(prefix(), &pk, 1))
+        );
+
+        // the whole prefix in use
+        let in_use = vec![("fd00:1234::".parse().unwrap(), 64)];
+        assert_eq!(assign_address(prefix(), &pk, &in_use), None);
+    }
+}
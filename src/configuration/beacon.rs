@@ -0,0 +1,313 @@
+/* Discovery of peers on the local network by beacons (opt-in, see Beacon::start):
+ *
+ * Every interval, the device broadcasts a beacon announcing its public key and listen port
+ * to every pre-approved peer. Upon a valid beacon from a pre-approved peer,
+ * the peer is added (if not configured yet) with the source of the beacon as its endpoint,
+ * hence devices on the same LAN form tunnels without any configuration of endpoints.
+ *
+ * Beacon (all integers in network byte order):
+ *
+ * | magic (8) | public key (32) | port (2) | timestamp (8) | mac (16) |
+ *
+ * X25519 keys cannot sign, hence the beacon is authenticated by a MAC keyed by a key derived
+ * (HMAC-BLAKE2s with a label) from the static-static Diffie-Hellman secret of the sender
+ * and the approved recipient (a beacon is sent for every approved peer).
+ *
+ * The source address of a beacon is not authenticated, hence a beacon could be replayed
+ * (from another address) to redirect the peer: beacons older than BEACON_MAX_AGE are ignored
+ * and a beacon is only accepted if it is newer than every beacon accepted from the sender.
+ */
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clear_on_drop::clear::Clear;
+use subtle::ConstantTimeEq;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use super::config::Configuration;
+use super::crypto::{Backend, Selected};
+use super::keys::PeerId;
+
+// default UDP port of beacons
+pub const BEACON_PORT: u16 = 51819;
+
+// default interval between beacons
+pub const BEACON_INTERVAL: Duration = Duration::from_secs(5);
+
+// beacons older than this (or from further in the future) are ignored (replays)
+const BEACON_MAX_AGE: Duration = Duration::from_secs(30);
+
+const BEACON_MAGIC: &[u8; 8] = b"WGRSBCN2";
+const BEACON_LABEL: &[u8] = b"wireguard-rs beacon mac key";
+const SIZE_MAC: usize = 16;
+const SIZE_BEACON: usize = 8 + 32 + 2 + 8 + SIZE_MAC;
+
+/// A peer which may be discovered by beacons
+#[derive(Debug, Clone)]
+pub struct Approved {
+    pub public_key: PublicKey,
+    pub allowed_ips: Vec<(IpAddr, u32)>, // added to the peer when discovered
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// the MAC key shared by the sender and the recipient (None for a low-order key)
+fn mac_key(sk: &StaticSecret, pk: &PublicKey) -> Option<[u8; 32]> {
+    let mut ss = *sk.diffie_hellman(pk).as_bytes();
+    let key = if ss.ct_eq(&[0u8; 32]).into() {
+        None
+    } else {
+        Some(Selected::hmac(&ss, &[BEACON_LABEL]))
+    };
+    ss.clear();
+    key
+}
+
+fn mac(key: &[u8; 32], body: &[u8]) -> [u8; SIZE_MAC] {
+    Selected::mac(key, &[body])
+}
+
+/// Encodes the beacon of the device (public key of the secret) for the recipient
+pub fn encode(
+    sk: &StaticSecret,
+    port: u16,
+    timestamp: u64,
+    recipient: &PublicKey,
+) -> Option<[u8; SIZE_BEACON]> {
+    let key = mac_key(sk, recipient)?;
+    let mut beacon = [0u8; SIZE_BEACON];
+    beacon[..8].copy_from_slice(BEACON_MAGIC);
+    beacon[8..40].copy_from_slice(PublicKey::from(sk).as_bytes());
+    beacon[40..42].copy_from_slice(&port.to_be_bytes());
+    beacon[42..50].copy_from_slice(&timestamp.to_be_bytes());
+    let tag = mac(&key, &beacon[..50]);
+    beacon[50..].copy_from_slice(&tag);
+    Some(beacon)
+}
+
+/// Verifies a beacon received by the device (of the secret)
+///
+/// # Returns
+///
+/// The public key, port and timestamp of the sender, if the beacon is authentic and fresh
+pub fn decode(sk: &StaticSecret, beacon: &[u8], now: u64) -> Option<(PublicKey, u16, u64)> {
+    if beacon.len() != SIZE_BEACON || &beacon[..8] != BEACON_MAGIC {
+        return None;
+    }
+    let mut pk = [0u8; 32];
+    pk.copy_from_slice(&beacon[8..40]);
+    let pk = PublicKey::from(pk);
+    let mut port = [0u8; 2];
+    port.copy_from_slice(&beacon[40..42]);
+    let mut timestamp = [0u8; 8];
+    timestamp.copy_from_slice(&beacon[42..50]);
+    let timestamp = u64::from_be_bytes(timestamp);
+
+    let key = mac_key(sk, &pk)?;
+    if !bool::from(mac(&key, &beacon[..50]).ct_eq(&beacon[50..])) {
+        return None;
+    }
+    if now.max(timestamp) - now.min(timestamp) > BEACON_MAX_AGE.as_secs() {
+        return None;
+    }
+    Some((pk, u16::from_be_bytes(port), timestamp))
+}
+
+/* The timestamps of the last beacon accepted from every sender:
+ * a beacon is accepted only if newer (replays of accepted beacons are ignored).
+ */
+#[derive(Default)]
+struct Replay {
+    last: HashMap<[u8; 32], u64>,
+}
+
+impl Replay {
+    fn accept(&mut self, pk: &PublicKey, timestamp: u64) -> bool {
+        match self.last.get(pk.as_bytes()) {
+            Some(&last) if timestamp <= last => false,
+            _ => {
+                self.last.insert(*pk.as_bytes(), timestamp);
+                true
+            }
+        }
+    }
+}
+
+/// Discovery of peers by beacons on the local network, stopped when dropped
+pub struct Beacon {
+    stop: Arc<AtomicBool>,
+}
+
+impl Beacon {
+    /// Starts sending and receiving beacons
+    ///
+    /// # Arguments
+    ///
+    /// - `config`: The configuration of the device (discovered peers are added to it)
+    /// - `approved`: The peers which may be discovered (beacons of other keys are ignored)
+    /// - `port`: The UDP port of beacons (e.g. BEACON_PORT)
+    /// - `interval`: The interval between beacons (e.g. BEACON_INTERVAL)
+    pub fn start<C: Configuration + Clone + Send + 'static>(
+        config: C,
+        approved: Vec<Approved>,
+        port: u16,
+        interval: Duration,
+    ) -> io::Result<Beacon> {
+        let socket = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port))?;
+        socket.set_broadcast(true)?;
+        socket.set_read_timeout(Some(interval))?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let approved = Arc::new(approved);
+
+        // send beacons to every approved peer
+        {
+            let socket = socket.try_clone()?;
+            let stop = stop.clone();
+            let config = config.clone();
+            let approved = approved.clone();
+            let dst = SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), port);
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    if let (Some(sk), Some(listen_port)) =
+                        (config.get_private_key(), config.get_listen_port())
+                    {
+                        for peer in approved.iter() {
+                            if let Some(beacon) = encode(&sk, listen_port, now(), &peer.public_key)
+                            {
+                                if let Err(e) = socket.send_to(&beacon, dst) {
+                                    log::debug!("Config, failed to send beacon: {}", e);
+                                }
+                            }
+                        }
+                    }
+                    thread::sleep(interval);
+                }
+            });
+        }
+
+        // receive beacons and add / update the approved peers
+        {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let mut endpoints: HashMap<[u8; 32], SocketAddr> = HashMap::new();
+                let mut replay = Replay::default();
+                let mut buf = [0u8; SIZE_BEACON + 1];
+                while !stop.load(Ordering::Relaxed) {
+                    let (len, src) = match socket.recv_from(&mut buf) {
+                        Ok(res) => res,
+                        Err(_) => continue, // timeout (checks the stop flag)
+                    };
+                    let sk = match config.get_private_key() {
+                        Some(sk) => sk,
+                        None => continue,
+                    };
+                    let (pk, port, timestamp) = match decode(&sk, &buf[..len], now()) {
+                        Some(res) => res,
+                        None => continue,
+                    };
+                    let peer = match approved
+                        .iter()
+                        .find(|peer| peer.public_key.as_bytes() == pk.as_bytes())
+                    {
+                        Some(peer) => peer,
+                        None => continue,
+                    };
+                    if !replay.accept(&pk, timestamp) {
+                        continue;
+                    }
+                    let endpoint = SocketAddr::new(src.ip(), port);
+                    if endpoints.get(pk.as_bytes()) == Some(&endpoint) {
+                        continue;
+                    }
+                    log::info!(
+                        "Config, discovered peer {} at {}",
+                        PeerId::new(pk),
+                        endpoint
+                    );
+                    match config.add_peer(&pk) {
+                        Ok(added) => {
+                            if added {
+                                for (ip, cidr) in peer.allowed_ips.iter() {
+                                    config.add_allowed_ip(&pk, *ip, *cidr);
+                                }
+                            }
+                            config.set_endpoint(&pk, endpoint);
+                            endpoints.insert(*pk.as_bytes(), endpoint);
+                        }
+                        Err(e) => log::info!("Config, discovered peer not added: {}", e),
+                    }
+                }
+            });
+        }
+
+        Ok(Beacon { stop })
+    }
+}
+
+impl Drop for Beacon {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_beacon() {
+        let sk1 = StaticSecret::from([1u8; 32]);
+        let sk2 = StaticSecret::from([2u8; 32]);
+        let sk3 = StaticSecret::from([3u8; 32]);
+        let pk1 = PublicKey::from(&sk1);
+        let pk2 = PublicKey::from(&sk2);
+        let now = 1_000_000;
+
+        let beacon = encode(&sk1, 51820, now, &pk2).unwrap();
+        let (pk, port, timestamp) = decode(&sk2, &beacon, now + 1).unwrap();
+        assert_eq!(pk.as_bytes(), pk1.as_bytes());
+        assert_eq!(port, 51820);
+        assert_eq!(timestamp, now);
+
+        // not the recipient
+        assert!(decode(&sk3, &beacon, now).is_none());
+
+        // stale
+        assert!(decode(&sk2, &beacon, now + BEACON_MAX_AGE.as_secs() + 1).is_none());
+
+        // tampered (port)
+        let mut tampered = beacon;
+        tampered[41] ^= 1;
+        assert!(decode(&sk2, &tampered, now).is_none());
+
+        // the MAC is not keyed by the raw Diffie-Hellman secret
+        let ss = *sk1.diffie_hellman(&pk2).as_bytes();
+        assert_ne!(mac(&ss, &beacon[..50]), beacon[50..]);
+    }
+
+    #[test]
+    fn test_replay() {
+        let pk = PublicKey::from(&StaticSecret::from([1u8; 32]));
+        let other = PublicKey::from(&StaticSecret::from([2u8; 32]));
+        let mut replay = Replay::default();
+        assert!(replay.accept(&pk, 100));
+
+        // a replayed (or older) beacon is ignored, a newer beacon is accepted
+        assert!(!replay.accept(&pk, 100));
+        assert!(!replay.accept(&pk, 99));
+        assert!(replay.accept(&pk, 105));
+
+        // per sender
+        assert!(replay.accept(&other, 100));
+    }
+}
@@ -1,11 +1,14 @@
 use std::mem;
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
 use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex, MutexGuard};
 use std::time::{Duration, SystemTime};
 
+use log::LevelFilter;
 use x25519_dalek::{PublicKey, StaticSecret};
 
+use super::autoaddr;
 use super::udp::Owner;
 use super::*;
 
@@ -23,8 +26,36 @@ pub struct PeerState {
     pub last_handshake_time: Option<(u64, u64)>,
     pub public_key: PublicKey,
     pub allowed_ips: Vec<(IpAddr, u32)>,
+    pub policy_routes: Vec<((IpAddr, u32), (IpAddr, u32))>, // (source, destination) subnets
+    pub ecmp_ips: Vec<(IpAddr, u32)>, // subnets shared with other peers (ECMP)
     pub endpoint: Option<SocketAddr>,
+    pub endpoint_policy: EndpointPolicy, // learning of the endpoint (roaming)
+    pub endpoint_port: Option<u16>,      // port of learned endpoints (None = the source port)
+    pub handshake_role: HandshakeRole,   // which end of a handshake the device may take
     pub persistent_keepalive_interval: u64,
+    pub keepalive_suppression: KeepaliveSuppression,
+    pub cover_traffic_interval: u64,  // milliseconds, 0 = disabled
+    pub session: Option<SessionInfo>, // current session (if any)
+    pub send_errors: SendErrors,      // messages which could not be sent
+    pub source: Source,               // pinned source of outbound messages
+    pub hop_limit: Option<u8>,        // hop limit of outbound messages (None = of the device)
+    pub mtu: Option<usize>,           // inner MTU toward the peer (None = of the device)
+    pub encap: Option<Encapsulation>, // relay encapsulation (if any)
+    pub relayed: bool,                // messages enclosed in relay frames (no direct path)
+    pub endpoint_changes: u64,        // times the endpoint was set or learned anew
+    pub endpoint_candidates: Vec<SocketAddr>, // alternative endpoints (e.g. for dual-stack peers)
+    pub handshakes_suspended: bool,   // peer unreachable, handshake retries suspended
+    pub staged_packets: usize,        // packets awaiting a session
+    pub flows: Vec<(Flow, FlowStats)>, // inner flows (empty unless flow tracking is enabled)
+    pub quota: Option<Quota>,         // usage quota (if any)
+    pub quota_usage: u64,             // bytes used in the current quota period
+    pub quota_exceeded: bool,         // peer disabled by its quota
+    pub relay_policy: RelayPolicy,    // relaying of packets to other peers
+    pub relay: RelayStats,            // packets relayed to other peers
+    pub exit_weight: u32,             // weight as an exit candidate (0 = not a candidate)
+    pub exit_active: bool,            // the peer owns the default routes (selected exit)
+    pub handshake_rtt: Option<Duration>, // round-trip time of the last handshake initiated
+    pub cookie_retries: u64, // initiations retried with a cookie (mac2) after a cookie reply
     pub preshared_key: [u8; 32], // 0^32 is the "default value" (though treated like any other psk)
 }
 
@@ -33,25 +64,104 @@ pub struct WireGuardConfig<T: tun::Tun, B: udp::PlatformUDP>(Arc<Mutex<Inner<T,
 struct Inner<T: tun::Tun, B: udp::PlatformUDP> {
     wireguard: WireGuard<T, B>,
     port: u16,
+    bind_options: BindOptions,
     bind: Option<B::Owner>,
-    fwmark: Option<u32>,
+    store: Option<Arc<dyn Store>>, // persistence of the configuration (if any)
+    auto_prefix: Option<(Ipv6Addr, u32)>, // prefix of the addresses assigned to peers (if any)
+    mesh: Arc<Mesh>,               // nodes of the full mesh (applied without holding the lock)
 }
 
 impl<T: tun::Tun, B: udp::PlatformUDP> WireGuardConfig<T, B> {
     fn lock(&self) -> MutexGuard<Inner<T, B>> {
         self.0.lock().unwrap()
     }
+
+    /* Updates the transport options,
+     * which are applied to the current sockets (if bound) and every future socket.
+     */
+    fn update_transport<F: FnOnce(&mut TransportOptions)>(&self, f: F) -> Result<(), ConfigError> {
+        let mut cfg = self.lock();
+        let mut opts = cfg.bind_options.transport.clone();
+        f(&mut opts);
+        if let Some(bind) = cfg.bind.as_mut() {
+            if bind.set_options(&opts).is_err() {
+                return Err(ConfigError::IOError);
+            }
+        }
+        cfg.bind_options.transport = opts;
+        Ok(())
+    }
 }
 
 impl<T: tun::Tun, B: udp::PlatformUDP> WireGuardConfig<T, B> {
     pub fn new(wg: WireGuard<T, B>) -> WireGuardConfig<T, B> {
+        // busy polling of the sockets in performance mode
+        let mut bind_options = BindOptions::default();
+        bind_options.transport.busy_poll = wg.workers.performance.map(|mode| mode.busy_poll);
         WireGuardConfig(Arc::new(Mutex::new(Inner {
             wireguard: wg,
             port: 0,
+            bind_options,
             bind: None,
-            fwmark: None,
+            store: None,
+            auto_prefix: None,
+            mesh: Arc::new(Mesh::new(vec![], 0)),
         })))
     }
+
+    /* Assigns the address of the peer derived from the prefix of automatic addresses (if any),
+     * avoiding the allowed IPs of the other peers (see autoaddr).
+     */
+    fn assign_auto_address(cfg: &Inner<T, B>, pk: &PublicKey) {
+        let prefix = match cfg.auto_prefix {
+            Some(prefix) => prefix,
+            None => return,
+        };
+        let peers = cfg.wireguard.peers.read();
+        let in_use: Vec<(IpAddr, u32)> = peers
+            .iter()
+            .filter(|(other, _)| other.as_bytes() != pk.as_bytes())
+            .flat_map(|(_, peer)| peer.list_allowed_ips())
+            .collect();
+        if let Some(peer) = peers.get(pk) {
+            match autoaddr::assign_address(prefix, pk, &in_use) {
+                Some(addr) => peer.add_allowed_ip(IpAddr::V6(addr), 128),
+                None => log::warn!(
+                    "Config, no address of {}/{} available for peer {}",
+                    prefix.0,
+                    prefix.1,
+                    PeerId::new(*pk)
+                ),
+            }
+        }
+    }
+
+    /* Replaces the configuration of the device and every peer with the stored configuration */
+    fn restore(&self, stored: &StoredConfig) -> Result<(), ConfigError> {
+        log::info!(
+            "configuration, restoring {} peers from the store",
+            stored.peers.len()
+        );
+        self.set_private_key(stored.private_key.clone());
+        self.set_listen_port(stored.listen_port)?;
+        self.replace_peers();
+        for peer in stored.peers.iter() {
+            let pk = &peer.public_key;
+            if self.add_peer(pk).is_err() {
+                continue; // access revoked since the peer was stored
+            }
+            self.set_preshared_key(pk, peer.preshared_key);
+            if let Some(addr) = peer.endpoint {
+                self.set_endpoint(pk, addr);
+            }
+            for (ip, cidr) in peer.allowed_ips.iter() {
+                self.add_allowed_ip(pk, *ip, *cidr);
+            }
+            self.set_persistent_keepalive_interval(pk, peer.persistent_keepalive_interval);
+            self.set_quota(pk, peer.quota);
+        }
+        Ok(())
+    }
 }
 
 impl<T: tun::Tun, B: udp::PlatformUDP> Clone for WireGuardConfig<T, B> {
@@ -80,6 +190,25 @@ pub trait Configuration {
     /// The private if set, otherwise None.
     fn get_private_key(&self) -> Option<StaticSecret>;
 
+    /// Adds a secondary private key to the device
+    /// (initiations addressed to either the primary or any secondary key are accepted)
+    ///
+    /// # Arguments
+    ///
+    /// - `sk`: The secondary private key
+    /// - `peers`: The peers permitted to use the key (or None, if every peer may use the key)
+    fn add_secondary_private_key(&self, sk: StaticSecret, peers: Option<Vec<PublicKey>>);
+
+    /// Removes the secondary private key with the given public key
+    fn remove_secondary_private_key(&self, pk: &PublicKey);
+
+    /// Removes every secondary private key of the device
+    fn clear_secondary_private_keys(&self);
+
+    /// Returns the secondary private keys of the device
+    /// and the peers permitted to use each (None = all peers)
+    fn get_secondary_private_keys(&self) -> Vec<(StaticSecret, Option<Vec<PublicKey>>)>;
+
     /// Returns the protocol version of the device
     ///
     /// # Returns
@@ -89,6 +218,65 @@ pub trait Configuration {
 
     fn set_listen_port(&self, port: u16) -> Result<(), ConfigError>;
 
+    /// Set the address families on which to listen
+    ///
+    /// # Arguments
+    ///
+    /// - `mode`: IPv4 only, IPv6 only or both
+    ///
+    /// # Returns
+    ///
+    /// An error if the device is up and any of the requested families cannot be bound
+    fn set_bind_mode(&self, mode: BindMode) -> Result<(), ConfigError>;
+
+    fn get_bind_mode(&self) -> BindMode;
+
+    /// Set the transport protocol of the sockets (restarting the listener if bound)
+    ///
+    /// # Arguments
+    ///
+    /// - `protocol`: UDP or UDP-Lite (falls back to UDP if unsupported)
+    ///
+    /// # Returns
+    ///
+    /// An error if the device is up and the sockets cannot be bound
+    fn set_protocol(&self, protocol: Protocol) -> Result<(), ConfigError>;
+
+    fn get_protocol(&self) -> Protocol;
+
+    /// Set the number of attempts at finding a port available on both address families
+    /// (when the listen port is chosen by the OS)
+    ///
+    /// # Arguments
+    ///
+    /// - `attempts`: The number of attempts (at least 1)
+    fn set_bind_attempts(&self, attempts: usize);
+
+    fn get_bind_attempts(&self) -> usize;
+
+    /// Set the number of sockets (and readers) per address family sharing the listen port
+    /// (restarting the listener if bound), see BindOptions::fanout
+    ///
+    /// # Arguments
+    ///
+    /// - `sockets`: The number of sockets (1 disables the fan-out)
+    ///
+    /// # Returns
+    ///
+    /// An error if the device is up and the sockets cannot be bound
+    fn set_fanout(&self, sockets: usize) -> Result<(), ConfigError>;
+
+    fn get_fanout(&self) -> usize;
+
+    /// Set the behavior when the port chosen by the OS on IPv6 is unavailable on IPv4
+    ///
+    /// # Arguments
+    ///
+    /// - `mismatch`: Retry with another port, fail, or bind IPv4 on another port
+    fn set_port_mismatch(&self, mismatch: PortMismatch);
+
+    fn get_port_mismatch(&self) -> PortMismatch;
+
     /// Set the firewall mark (or similar, depending on platform)
     ///
     /// # Arguments
@@ -101,6 +289,260 @@ pub trait Configuration {
     /// "bind" implementation.
     fn set_fwmark(&self, mark: Option<u32>) -> Result<(), ConfigError>;
 
+    /// Set the type of service (IPv4) and traffic class (IPv6) of outbound messages
+    ///
+    /// # Arguments
+    ///
+    /// - `tos`: The TOS value (or None, for the platform default)
+    fn set_tos(&self, tos: Option<u8>) -> Result<(), ConfigError>;
+
+    fn get_tos(&self) -> Option<u8>;
+
+    /// Set the TTL (IPv4) and unicast hop limit (IPv6) of outbound messages
+    ///
+    /// # Arguments
+    ///
+    /// - `hops`: The hop limit (or None, for the platform default)
+    fn set_hop_limit(&self, hops: Option<u8>) -> Result<(), ConfigError>;
+
+    fn get_hop_limit(&self) -> Option<u8>;
+
+    /// Set the handling of outbound messages exceeding the path MTU
+    ///
+    /// # Arguments
+    ///
+    /// - `policy`: Leave to the platform, drop (DF set), let the kernel fragment (DF cleared)
+    ///    or fragment inner IPv4 packets before encryption to fit the given datagram size (DF set)
+    fn set_fragment_policy(&self, policy: FragmentPolicy) -> Result<(), ConfigError>;
+
+    fn get_fragment_policy(&self) -> FragmentPolicy;
+
+    /// Bind the sockets to the interface with the given name
+    ///
+    /// # Arguments
+    ///
+    /// - `name`: The name of the interface (or None, to remove the binding)
+    fn set_bind_device(&self, name: Option<String>) -> Result<(), ConfigError>;
+
+    fn get_bind_device(&self) -> Option<String>;
+
+    /// Set the padding policy for outbound IP packets
+    fn set_padding(&self, padding: Padding);
+
+    fn get_padding(&self) -> Padding;
+
+    /// Set the addresses of the interface for which ICMP(v6) echo requests are answered
+    /// directly by the device, rather than delivered to the TUN device
+    ///
+    /// # Arguments
+    ///
+    /// - `addrs`: The addresses (an empty list disables the responder)
+    fn set_echo_addresses(&self, addrs: Vec<IpAddr>);
+
+    fn get_echo_addresses(&self) -> Vec<IpAddr>;
+
+    /// Enable tracking of the inner flows (5-tuples) to and from every peer
+    ///
+    /// # Arguments
+    ///
+    /// - `timeout`: Idle time after which a flow is expired (None disables tracking)
+    fn set_flow_timeout(&self, timeout: Option<Duration>);
+
+    fn get_flow_timeout(&self) -> Option<Duration>;
+
+    /// Set the maximum age of packets staged while no session is established with the peer,
+    /// older packets are dropped rather than sent once the session is established
+    ///
+    /// # Arguments
+    ///
+    /// - `age`: The maximum age (None keeps staged packets until sent)
+    fn set_max_staged_age(&self, age: Option<Duration>);
+
+    fn get_max_staged_age(&self) -> Option<Duration>;
+
+    /// Assign every peer an IPv6 address (as an allowed IP) derived from its public key,
+    /// such that every device configured with the prefix agrees on the addresses of the peers
+    /// (addresses assigned from a previous prefix are not removed)
+    ///
+    /// # Arguments
+    ///
+    /// - `prefix`: The prefix (address, prefix length) of the addresses (None disables assignment)
+    fn set_auto_prefix(&self, prefix: Option<(Ipv6Addr, u32)>);
+
+    fn get_auto_prefix(&self) -> Option<(Ipv6Addr, u32)>;
+
+    /// Add (or replace) a node of the full mesh of the device (see Mesh):
+    /// a peer is configured for every other node and the peers of nodes which left are removed
+    ///
+    /// # Arguments
+    ///
+    /// - `node`: The node (the node of the private key of the device is not added as a peer)
+    fn add_mesh_node(&self, node: Node) -> Result<(), ConfigError>;
+
+    /// Remove a node from the full mesh of the device (and the peer of the node)
+    fn remove_mesh_node(&self, pk: &PublicKey) -> Result<(), ConfigError>;
+
+    fn get_mesh_nodes(&self) -> Vec<Node>;
+
+    /// Relay packets between peers (hub-and-spoke): packets from a peer destined to
+    /// the allowed IPs of another peer are forwarded to the other peer,
+    /// rather than delivered to the TUN device (applies to peers with the default relay policy)
+    ///
+    /// # Arguments
+    ///
+    /// - `relay`: Relay packets between peers
+    fn set_relay(&self, relay: bool);
+
+    fn get_relay(&self) -> bool;
+
+    /// Select the exit (the peer owning the default routes) among the exit candidates,
+    /// failing over automatically once the exit goes stale
+    ///
+    /// # Arguments
+    ///
+    /// - `policy`: The selection policy (None stops the selection and removes the default routes)
+    fn set_exit_policy(&self, policy: Option<ExitPolicy>);
+
+    fn get_exit_policy(&self) -> Option<ExitPolicy>;
+
+    /// Never answer initiations from unknown static keys (not even by cookie replies under load)
+    ///
+    /// # Arguments
+    ///
+    /// - `stealth`: Enable stealth
+    fn set_stealth(&self, stealth: bool);
+
+    fn get_stealth(&self) -> bool;
+
+    /// Returns the number of messages left unanswered by stealth
+    /// (unknown initiators and receiver identifiers under load), e.g. scan attempts
+    fn get_stealth_dropped(&self) -> u64;
+
+    /// Returns the messages received by kind and address family of the source
+    /// (counted before any validation, including garbage)
+    fn get_received(&self) -> RxStats;
+
+    /// Returns the counters of the interface
+    /// (in the buckets of a kernel network interface, as reported by /proc/net/dev)
+    fn get_interface_stats(&self) -> InterfaceStats;
+
+    /// Override the log level of a subsystem (applies to the entire process)
+    ///
+    /// # Arguments
+    ///
+    /// - `subsystem`: The subsystem (handshake, router, udp or tun)
+    /// - `level`: The level (None restores the level given by RUST_LOG)
+    fn set_log_level(&self, subsystem: Subsystem, level: Option<LevelFilter>);
+
+    fn get_log_level(&self, subsystem: Subsystem) -> Option<LevelFilter>;
+
+    /// Set the file in which the usage of the peer quotas is persisted
+    ///
+    /// # Arguments
+    ///
+    /// - `path`: The path of the file (None stops persisting the usage)
+    fn set_quota_file(&self, path: Option<PathBuf>) -> Result<(), ConfigError>;
+
+    fn get_quota_file(&self) -> Option<PathBuf>;
+
+    /// Set the store persisting the configuration of the device and its peers:
+    /// the stored configuration (if any) replaces the current configuration,
+    /// otherwise the current configuration is stored.
+    ///
+    /// # Arguments
+    ///
+    /// - `store`: The storage backend (None stops persisting the configuration)
+    fn set_store(&self, store: Option<Arc<dyn Store>>) -> Result<(), ConfigError>;
+
+    /// Save the configuration to the store (if any), e.g. after a UAPI set operation
+    fn persist(&self) -> Result<(), ConfigError>;
+
+    /// Export the tracked inner flows as IPFIX records
+    ///
+    /// # Arguments
+    ///
+    /// - `collector`: The address of the IPFIX collector (None stops the export)
+    #[cfg(feature = "ipfix")]
+    fn set_ipfix_collector(&self, collector: Option<SocketAddr>);
+
+    #[cfg(feature = "ipfix")]
+    fn get_ipfix_collector(&self) -> Option<SocketAddr>;
+
+    /// Set the policy for peers which do not answer handshake initiations
+    ///
+    /// # Arguments
+    ///
+    /// - `blackhole`: The number of unanswered initiations before the peer is deemed unreachable
+    ///   (0 disables detection) and the cool-down for which retries are then suspended
+    ///   (0 keeps retrying)
+    fn set_blackhole(&self, blackhole: Blackhole);
+
+    fn get_blackhole(&self) -> Blackhole;
+
+    /// Set the window over which the handshakes with peers with a persistent keepalive
+    /// are spread when the device comes up
+    ///
+    /// # Arguments
+    ///
+    /// - `window`: The window (0 initiates all handshakes at once)
+    fn set_handshake_spread(&self, window: Duration);
+
+    fn get_handshake_spread(&self) -> Duration;
+
+    /// Flush the outbound queues (and send a final round of keepalives)
+    /// before the sockets are closed when the device is brought down
+    ///
+    /// # Arguments
+    ///
+    /// - `timeout`: Bound on the flush (None disables the flush)
+    fn set_shutdown_flush(&self, timeout: Option<Duration>);
+
+    fn get_shutdown_flush(&self) -> Option<Duration>;
+
+    /// Emit the traffic of every peer as usage events for accounting
+    /// (see WireGuard::set_usage_interval)
+    ///
+    /// # Arguments
+    ///
+    /// - `interval`: The accounting interval (None stops the events)
+    fn set_usage_interval(&self, interval: Option<Duration>);
+
+    fn get_usage_interval(&self) -> Option<Duration>;
+
+    /// Enter or leave power-save, e.g. when a mobile embedder signals "screen off" / doze
+    /// (see WireGuard::set_power_save)
+    ///
+    /// # Arguments
+    ///
+    /// - `power_save`: The lengthening of keepalive intervals and the coalescing window
+    ///   (None leaves power-save)
+    fn set_power_save(&self, power_save: Option<PowerSave>);
+
+    fn get_power_save(&self) -> Option<PowerSave>;
+
+    /// Set a cap on the memory used by the device (queued messages and peer state)
+    ///
+    /// # Arguments
+    ///
+    /// - `limit`: The cap in bytes (or None, if the memory usage is unbounded)
+    fn set_memory_limit(&self, limit: Option<usize>);
+
+    fn get_memory_limit(&self) -> Option<usize>;
+
+    /// Returns the number of bytes currently used by the device
+    /// (queued messages and peer state)
+    fn get_memory_usage(&self) -> usize;
+
+    /// Returns the number of receiver ids allocated by the device
+    fn get_receiver_ids(&self) -> usize;
+
+    /// Releases the receiver ids of expired sessions
+    ///
+    /// # Returns
+    ///
+    /// The number of ids released
+    fn gc_receiver_ids(&self) -> usize;
+
     /// Removes all peers from the device
     fn replace_peers(&self);
 
@@ -123,10 +565,11 @@ pub trait Configuration {
     ///
     /// # Returns
     ///
-    /// A bool indicating if the peer was added.
+    /// A bool indicating if the peer was added,
+    /// or ConfigError::PeerDenied if the authorization of peers (if any) denied the peer.
     ///
     /// If the peer already exists this operation is a noop
-    fn add_peer(&self, peer: &PublicKey) -> bool;
+    fn add_peer(&self, peer: &PublicKey) -> Result<bool, ConfigError>;
 
     /// Update the psk of a peer
     ///
@@ -148,6 +591,15 @@ pub trait Configuration {
     /// - `psk`
     fn set_endpoint(&self, peer: &PublicKey, addr: SocketAddr);
 
+    /// Set the candidate endpoints of the peer (e.g. the IPv4 and IPv6 addresses of a dual-stack peer):
+    /// initiations are raced over the candidates, until a handshake completes.
+    ///
+    /// # Arguments
+    ///
+    /// - `peer': The public key of the peer
+    /// - `addrs`: The candidate endpoints (an empty list disables the race)
+    fn set_endpoint_candidates(&self, peer: &PublicKey, addrs: Vec<SocketAddr>);
+
     /// Update the endpoint of the
     ///
     /// # Arguments
@@ -156,6 +608,122 @@ pub trait Configuration {
     /// - `psk`
     fn set_persistent_keepalive_interval(&self, peer: &PublicKey, secs: u64);
 
+    /// Enable constant-rate cover traffic for the peer:
+    /// a dummy transport message (padded to the MTU) is sent every interval while a session exists.
+    ///
+    /// # Arguments
+    ///
+    /// - `peer': The public key of the peer
+    /// - `millis`: The interval in milliseconds (0 disables cover traffic)
+    fn set_cover_traffic_interval(&self, peer: &PublicKey, millis: u64);
+
+    /// Set which traffic postpones the persistent keepalives to the peer
+    ///
+    /// # Arguments
+    ///
+    /// - `peer': The public key of the peer
+    /// - `suppression`: Any authenticated message (default), only sent messages or none
+    fn set_keepalive_suppression(&self, peer: &PublicKey, suppression: KeepaliveSuppression);
+
+    /// Pin the outgoing interface for messages to the peer
+    ///
+    /// # Arguments
+    ///
+    /// - `peer': The public key of the peer
+    /// - `ifindex`: The index of the interface (0 removes the pin)
+    fn set_source_interface(&self, peer: &PublicKey, ifindex: u32);
+
+    /// Pin the source address of messages to the peer
+    ///
+    /// # Arguments
+    ///
+    /// - `peer': The public key of the peer
+    /// - `addr`: The source address (None removes the pin)
+    fn set_source_address(&self, peer: &PublicKey, addr: Option<IpAddr>);
+
+    /// Override the hop limit (IPv4 TTL) of messages to the peer
+    ///
+    /// # Arguments
+    ///
+    /// - `peer': The public key of the peer
+    /// - `hops`: The hop limit (None = the hop limit of the device)
+    fn set_peer_hop_limit(&self, peer: &PublicKey, hops: Option<u8>);
+
+    /// Override the inner MTU toward the peer (padding is clamped, larger packets are dropped)
+    ///
+    /// # Arguments
+    ///
+    /// - `peer': The public key of the peer
+    /// - `mtu`: The MTU (None = the MTU of the device)
+    fn set_peer_mtu(&self, peer: &PublicKey, mtu: Option<usize>);
+
+    /// Enclose the messages to the peer in frames toward a relay,
+    /// until a handshake over the direct path is authenticated (e.g. for peers behind symmetric NATs)
+    ///
+    /// # Arguments
+    ///
+    /// - `peer': The public key of the peer
+    /// - `encap`: The relay and the tag of the peer at the relay (None = send directly)
+    fn set_peer_encapsulation(&self, peer: &PublicKey, encap: Option<Encapsulation>);
+
+    /// Set the usage quota of the peer (a peer exceeding its quota is disabled)
+    ///
+    /// # Arguments
+    ///
+    /// - `peer': The public key of the peer
+    /// - `quota`: The quota (None removes the quota)
+    fn set_quota(&self, peer: &PublicKey, quota: Option<Quota>);
+
+    fn get_quota(&self, peer: &PublicKey) -> Option<Quota>;
+
+    /// Set whether packets from the peer destined to another peer are relayed
+    ///
+    /// # Arguments
+    ///
+    /// - `peer': The public key of the peer
+    /// - `policy`: The policy (RelayPolicy::Default follows the device, see set_relay)
+    fn set_relay_policy(&self, peer: &PublicKey, policy: RelayPolicy);
+
+    /// Set how the endpoint of the peer is learned from the source of authenticated messages
+    ///
+    /// # Arguments
+    ///
+    /// - `peer': The public key of the peer
+    /// - `policy`: The policy (learn, fixed or learn within a subnet)
+    fn set_endpoint_policy(&self, peer: &PublicKey, policy: EndpointPolicy);
+
+    /// Set the port of endpoints learned from the source of authenticated messages
+    ///
+    /// # Arguments
+    ///
+    /// - `peer': The public key of the peer
+    /// - `port`: The port (None = the source port of the message)
+    fn set_endpoint_port(&self, peer: &PublicKey, port: Option<u16>);
+
+    /// Set which end of a handshake the device may take with the peer
+    ///
+    /// # Arguments
+    ///
+    /// - `peer': The public key of the peer
+    /// - `role`: The role (both, responder only or initiator only)
+    fn set_handshake_role(&self, peer: &PublicKey, role: HandshakeRole);
+
+    /// Set the weight of the peer as an exit candidate (see set_exit_policy)
+    ///
+    /// # Arguments
+    ///
+    /// - `peer': The public key of the peer
+    /// - `weight`: The weight (0 = not a candidate)
+    fn set_exit_weight(&self, peer: &PublicKey, weight: u32);
+
+    /// Force a new handshake with the peer,
+    /// discarding the current session keys once the new session is confirmed
+    ///
+    /// # Arguments
+    ///
+    /// - `peer': The public key of the peer
+    fn rekey_peer(&self, peer: &PublicKey);
+
     /// Remove all allowed IPs from the peer
     ///
     /// # Arguments
@@ -180,8 +748,55 @@ pub trait Configuration {
     /// An error if the peer does not exist
     fn add_allowed_ip(&self, peer: &PublicKey, ip: IpAddr, masklen: u32);
 
+    /// Remove all policy routes from the peer
+    ///
+    /// # Arguments
+    ///
+    /// - `peer': The public key of the peer
+    fn replace_policy_routes(&self, peer: &PublicKey);
+
+    /// Route packets from the source subnet to the destination subnet to the peer
+    /// (regardless of the allowed IPs), e.g. to select the exit peer by the source of the packet
+    ///
+    /// # Arguments
+    ///
+    /// - `peer': The public key of the peer
+    /// - `source`: The source subnet (address, masklen)
+    /// - `destination`: The destination subnet (address, masklen)
+    ///
+    /// # Returns
+    ///
+    /// An error if the address families of the subnets differ
+    fn add_policy_route(
+        &self,
+        peer: &PublicKey,
+        source: (IpAddr, u32),
+        destination: (IpAddr, u32),
+    ) -> Result<(), ConfigError>;
+
+    /// Remove the peer from all shared subnets
+    ///
+    /// # Arguments
+    ///
+    /// - `peer': The public key of the peer
+    fn replace_ecmp_ips(&self, peer: &PublicKey);
+
+    /// Add a subnet shared with other peers (ECMP):
+    /// outbound flows to the subnet are spread over the peers sharing it (each flow sticks to a peer)
+    ///
+    /// # Arguments
+    ///
+    /// - `peer`: The public key of the peer
+    /// - `ip`: Subnet mask
+    /// - `masklen`:
+    fn add_ecmp_ip(&self, peer: &PublicKey, ip: IpAddr, masklen: u32);
+
     fn get_listen_port(&self) -> Option<u16>;
 
+    /// Returns the port bound on each address family, None if not bound
+    /// (the ports differ only with PortMismatch::Accept, peers must then be told both)
+    fn get_listen_ports(&self) -> Option<BoundPorts>;
+
     /// Returns the state of all peers
     ///
     /// # Returns
@@ -198,16 +813,13 @@ fn start_listener<T: tun::Tun, B: udp::PlatformUDP>(
     cfg.bind = None;
 
     // create new listener
-    let (mut readers, writer, mut owner) = match B::bind(cfg.port) {
+    let (mut readers, writer, owner) = match B::bind_with(cfg.port, &cfg.bind_options) {
         Ok(r) => r,
-        Err(_) => {
-            return Err(ConfigError::FailedToBind);
+        Err(e) => {
+            return Err(ConfigError::FailedToBind(TransportError::new(e)));
         }
     };
 
-    // set fwmark
-    let _ = owner.set_fwmark(cfg.fwmark); // TODO: handle
-
     // set writer on WireGuard
     cfg.wireguard.set_writer(writer);
 
@@ -237,7 +849,7 @@ impl<T: tun::Tun, B: udp::PlatformUDP> Configuration for WireGuardConfig<T, B> {
     }
 
     fn get_fwmark(&self) -> Option<u32> {
-        self.lock().fwmark
+        self.lock().bind_options.transport.fwmark
     }
 
     fn set_private_key(&self, sk: Option<StaticSecret>) {
@@ -249,6 +861,26 @@ impl<T: tun::Tun, B: udp::PlatformUDP> Configuration for WireGuardConfig<T, B> {
         self.lock().wireguard.get_sk()
     }
 
+    fn add_secondary_private_key(&self, sk: StaticSecret, peers: Option<Vec<PublicKey>>) {
+        log::info!("configuration, add secondary private key");
+        let peers = peers.as_ref().map(|peers| &peers[..]);
+        self.lock().wireguard.add_secondary_key(sk, peers);
+    }
+
+    fn remove_secondary_private_key(&self, pk: &PublicKey) {
+        log::info!("configuration, remove secondary private key");
+        self.lock().wireguard.remove_secondary_key(pk);
+    }
+
+    fn clear_secondary_private_keys(&self) {
+        log::info!("configuration, clear secondary private keys");
+        self.lock().wireguard.clear_secondary_keys();
+    }
+
+    fn get_secondary_private_keys(&self) -> Vec<(StaticSecret, Option<Vec<PublicKey>>)> {
+        self.lock().wireguard.get_secondary_sks()
+    }
+
     fn get_protocol_version(&self) -> usize {
         1
     }
@@ -259,6 +891,10 @@ impl<T: tun::Tun, B: udp::PlatformUDP> Configuration for WireGuardConfig<T, B> {
         st.bind.as_ref().map(|bind| bind.get_port())
     }
 
+    fn get_listen_ports(&self) -> Option<BoundPorts> {
+        self.lock().bind.as_ref().map(|bind| bind.get_ports())
+    }
+
     fn set_listen_port(&self, port: u16) -> Result<(), ConfigError> {
         log::trace!("Config, Set listen port: {:?}", port);
 
@@ -278,20 +914,401 @@ impl<T: tun::Tun, B: udp::PlatformUDP> Configuration for WireGuardConfig<T, B> {
         }
     }
 
+    fn set_bind_mode(&self, mode: BindMode) -> Result<(), ConfigError> {
+        log::trace!("Config, Set bind mode: {:?}", mode);
+
+        // update mode and take old bind
+        let mut cfg = self.lock();
+        let bound: bool = {
+            let old = mem::replace(&mut cfg.bind, None);
+            cfg.bind_options.mode = mode;
+            old.is_some()
+        };
+
+        // restart listener if bound
+        if bound {
+            start_listener(cfg)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn get_bind_mode(&self) -> BindMode {
+        self.lock().bind_options.mode
+    }
+
+    fn set_protocol(&self, protocol: Protocol) -> Result<(), ConfigError> {
+        log::trace!("Config, Set protocol: {:?}", protocol);
+
+        // update protocol and take old bind
+        let mut cfg = self.lock();
+        let bound: bool = {
+            let old = mem::replace(&mut cfg.bind, None);
+            cfg.bind_options.protocol = protocol;
+            old.is_some()
+        };
+
+        // restart listener if bound
+        if bound {
+            start_listener(cfg)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn get_protocol(&self) -> Protocol {
+        self.lock().bind_options.protocol
+    }
+
+    fn set_bind_attempts(&self, attempts: usize) {
+        log::trace!("Config, Set bind attempts: {}", attempts);
+        self.lock().bind_options.attempts = attempts;
+    }
+
+    fn get_bind_attempts(&self) -> usize {
+        self.lock().bind_options.attempts
+    }
+
+    fn set_fanout(&self, sockets: usize) -> Result<(), ConfigError> {
+        log::trace!("Config, Set fan-out: {}", sockets);
+
+        // update fan-out and take old bind
+        let mut cfg = self.lock();
+        let bound: bool = {
+            let old = mem::replace(&mut cfg.bind, None);
+            cfg.bind_options.fanout = sockets;
+            old.is_some()
+        };
+
+        // restart listener if bound
+        if bound {
+            start_listener(cfg)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn get_fanout(&self) -> usize {
+        self.lock().bind_options.fanout
+    }
+
+    fn set_port_mismatch(&self, mismatch: PortMismatch) {
+        log::trace!("Config, Set port mismatch: {:?}", mismatch);
+        self.lock().bind_options.mismatch = mismatch;
+    }
+
+    fn get_port_mismatch(&self) -> PortMismatch {
+        self.lock().bind_options.mismatch
+    }
+
     fn set_fwmark(&self, mark: Option<u32>) -> Result<(), ConfigError> {
         log::trace!("Config, Set fwmark: {:?}", mark);
-        match self.lock().bind.as_mut() {
-            Some(bind) => {
-                if bind.set_fwmark(mark).is_err() {
-                    Err(ConfigError::IOError)
-                } else {
-                    Ok(())
-                }
+        self.update_transport(|opts| opts.fwmark = mark)
+    }
+
+    fn set_tos(&self, tos: Option<u8>) -> Result<(), ConfigError> {
+        log::trace!("Config, Set tos: {:?}", tos);
+        self.update_transport(|opts| opts.tos = tos)
+    }
+
+    fn get_tos(&self) -> Option<u8> {
+        self.lock().bind_options.transport.tos
+    }
+
+    fn set_hop_limit(&self, hops: Option<u8>) -> Result<(), ConfigError> {
+        log::trace!("Config, Set hop limit: {:?}", hops);
+        self.update_transport(|opts| opts.hop_limit = hops)
+    }
+
+    fn get_hop_limit(&self) -> Option<u8> {
+        self.lock().bind_options.transport.hop_limit
+    }
+
+    fn set_fragment_policy(&self, policy: FragmentPolicy) -> Result<(), ConfigError> {
+        log::trace!("Config, Set fragment policy: {:?}", policy);
+        self.update_transport(|opts| opts.fragment = policy)?;
+        self.lock().wireguard.set_prefragment(match policy {
+            FragmentPolicy::Prefragment(size) => Some(size as usize),
+            _ => None,
+        });
+        Ok(())
+    }
+
+    fn get_fragment_policy(&self) -> FragmentPolicy {
+        self.lock().bind_options.transport.fragment
+    }
+
+    fn set_bind_device(&self, name: Option<String>) -> Result<(), ConfigError> {
+        log::trace!("Config, Set bind device: {:?}", name);
+        self.update_transport(|opts| opts.bind_device = name)
+    }
+
+    fn get_bind_device(&self) -> Option<String> {
+        self.lock().bind_options.transport.bind_device.clone()
+    }
+
+    fn set_padding(&self, padding: Padding) {
+        log::trace!("Config, Set padding: {:?}", padding);
+        self.lock().wireguard.set_padding(padding);
+    }
+
+    fn get_padding(&self) -> Padding {
+        self.lock().wireguard.get_padding()
+    }
+
+    fn set_echo_addresses(&self, addrs: Vec<IpAddr>) {
+        log::trace!("Config, Set echo addresses: {:?}", addrs);
+        self.lock().wireguard.router.set_echo_addresses(addrs);
+    }
+
+    fn get_echo_addresses(&self) -> Vec<IpAddr> {
+        self.lock().wireguard.router.get_echo_addresses()
+    }
+
+    fn set_flow_timeout(&self, timeout: Option<Duration>) {
+        log::trace!("Config, Set flow timeout: {:?}", timeout);
+        let cfg = self.lock();
+        cfg.wireguard.router.set_flow_timeout(timeout);
+        if timeout.is_none() {
+            for (_, peer) in cfg.wireguard.peers.read().iter() {
+                peer.clear_flows();
             }
-            None => Ok(()),
         }
     }
 
+    fn get_flow_timeout(&self) -> Option<Duration> {
+        self.lock().wireguard.router.get_flow_timeout()
+    }
+
+    fn set_max_staged_age(&self, age: Option<Duration>) {
+        log::trace!("Config, Set max staged age: {:?}", age);
+        self.lock().wireguard.router.set_max_staged_age(age);
+    }
+
+    fn get_max_staged_age(&self) -> Option<Duration> {
+        self.lock().wireguard.router.get_max_staged_age()
+    }
+
+    fn set_auto_prefix(&self, prefix: Option<(Ipv6Addr, u32)>) {
+        log::trace!("Config, Set auto prefix: {:?}", prefix);
+        let mut cfg = self.lock();
+        cfg.auto_prefix = prefix;
+        let peers: Vec<PublicKey> = cfg
+            .wireguard
+            .peers
+            .read()
+            .iter()
+            .map(|(pk, _)| pk)
+            .collect();
+        for pk in peers.iter() {
+            Self::assign_auto_address(&cfg, pk);
+        }
+    }
+
+    fn get_auto_prefix(&self) -> Option<(Ipv6Addr, u32)> {
+        self.lock().auto_prefix
+    }
+
+    fn add_mesh_node(&self, node: Node) -> Result<(), ConfigError> {
+        log::trace!("Config, Add mesh node: {:?}", node);
+        let mesh = self.lock().mesh.clone();
+        mesh.join(node, self)
+    }
+
+    fn remove_mesh_node(&self, pk: &PublicKey) -> Result<(), ConfigError> {
+        log::trace!("Config, Remove mesh node: {}", PeerId::new(*pk));
+        let mesh = self.lock().mesh.clone();
+        mesh.leave(pk, self)
+    }
+
+    fn get_mesh_nodes(&self) -> Vec<Node> {
+        self.lock().mesh.nodes()
+    }
+
+    fn set_relay(&self, relay: bool) {
+        log::trace!("Config, Set relay: {}", relay);
+        self.lock().wireguard.router.set_relay(relay);
+    }
+
+    fn get_relay(&self) -> bool {
+        self.lock().wireguard.router.get_relay()
+    }
+
+    fn set_stealth(&self, stealth: bool) {
+        log::trace!("Config, Set stealth: {}", stealth);
+        self.lock().wireguard.set_stealth(stealth);
+    }
+
+    fn get_stealth(&self) -> bool {
+        self.lock().wireguard.get_stealth()
+    }
+
+    fn get_stealth_dropped(&self) -> u64 {
+        self.lock().wireguard.stealth_dropped()
+    }
+
+    fn get_received(&self) -> RxStats {
+        self.lock().wireguard.received()
+    }
+
+    fn get_interface_stats(&self) -> InterfaceStats {
+        self.lock().wireguard.interface_stats()
+    }
+
+    fn set_exit_policy(&self, policy: Option<ExitPolicy>) {
+        log::trace!("Config, Set exit policy: {:?}", policy);
+        self.lock().wireguard.set_exit_policy(policy);
+    }
+
+    fn get_exit_policy(&self) -> Option<ExitPolicy> {
+        self.lock().wireguard.get_exit_policy()
+    }
+
+    fn set_log_level(&self, subsystem: Subsystem, level: Option<LevelFilter>) {
+        log::info!("Config, Set log level of {}: {:?}", subsystem, level);
+        logging::set_level(subsystem, level);
+    }
+
+    fn get_log_level(&self, subsystem: Subsystem) -> Option<LevelFilter> {
+        logging::get_level(subsystem)
+    }
+
+    fn set_quota_file(&self, path: Option<PathBuf>) -> Result<(), ConfigError> {
+        log::trace!("Config, Set quota file: {:?}", path);
+        self.lock()
+            .wireguard
+            .set_quota_file(path)
+            .map_err(|_| ConfigError::IOError)
+    }
+
+    fn get_quota_file(&self) -> Option<PathBuf> {
+        self.lock().wireguard.get_quota_file()
+    }
+
+    fn set_store(&self, store: Option<Arc<dyn Store>>) -> Result<(), ConfigError> {
+        log::trace!("Config, Set store: {}", store.is_some());
+        let stored = match store.as_ref() {
+            Some(store) => store.load().map_err(|e| {
+                log::warn!("Config, failed to load the stored configuration: {}", e);
+                ConfigError::IOError
+            })?,
+            None => None,
+        };
+        self.lock().store = store;
+        match stored {
+            Some(stored) => self.restore(&stored),
+            None => self.persist(),
+        }
+    }
+
+    fn persist(&self) -> Result<(), ConfigError> {
+        let store = match self.lock().store.clone() {
+            Some(store) => store,
+            None => return Ok(()),
+        };
+        // the lock is released before the peers are collected (which takes it again)
+        let listen_port = self.lock().port;
+        let stored = StoredConfig {
+            private_key: self.get_private_key(),
+            listen_port,
+            peers: self
+                .get_peers()
+                .into_iter()
+                .map(|p| StoredPeer {
+                    public_key: p.public_key,
+                    preshared_key: p.preshared_key,
+                    endpoint: p.endpoint,
+                    allowed_ips: p.allowed_ips,
+                    persistent_keepalive_interval: p.persistent_keepalive_interval,
+                    quota: p.quota,
+                })
+                .collect(),
+        };
+        store.save(&stored).map_err(|e| {
+            log::warn!("Config, failed to store the configuration: {}", e);
+            ConfigError::IOError
+        })
+    }
+
+    #[cfg(feature = "ipfix")]
+    fn set_ipfix_collector(&self, collector: Option<SocketAddr>) {
+        log::trace!("Config, Set IPFIX collector: {:?}", collector);
+        self.lock().wireguard.set_ipfix_collector(collector);
+    }
+
+    #[cfg(feature = "ipfix")]
+    fn get_ipfix_collector(&self) -> Option<SocketAddr> {
+        self.lock().wireguard.get_ipfix_collector()
+    }
+
+    fn set_blackhole(&self, blackhole: Blackhole) {
+        log::trace!("Config, Set blackhole policy: {:?}", blackhole);
+        self.lock().wireguard.set_blackhole(blackhole);
+    }
+
+    fn get_blackhole(&self) -> Blackhole {
+        self.lock().wireguard.get_blackhole()
+    }
+
+    fn set_handshake_spread(&self, window: Duration) {
+        log::trace!("Config, Set handshake spread: {:?}", window);
+        self.lock().wireguard.set_handshake_spread(window);
+    }
+
+    fn get_handshake_spread(&self) -> Duration {
+        self.lock().wireguard.get_handshake_spread()
+    }
+
+    fn set_shutdown_flush(&self, timeout: Option<Duration>) {
+        log::trace!("Config, Set shutdown flush: {:?}", timeout);
+        self.lock().wireguard.set_shutdown_flush(timeout);
+    }
+
+    fn get_shutdown_flush(&self) -> Option<Duration> {
+        self.lock().wireguard.get_shutdown_flush()
+    }
+
+    fn set_usage_interval(&self, interval: Option<Duration>) {
+        log::trace!("Config, Set usage interval: {:?}", interval);
+        self.lock().wireguard.set_usage_interval(interval);
+    }
+
+    fn get_usage_interval(&self) -> Option<Duration> {
+        self.lock().wireguard.get_usage_interval()
+    }
+
+    fn set_power_save(&self, power_save: Option<PowerSave>) {
+        log::trace!("Config, Set power save: {:?}", power_save);
+        self.lock().wireguard.set_power_save(power_save);
+    }
+
+    fn get_power_save(&self) -> Option<PowerSave> {
+        self.lock().wireguard.get_power_save()
+    }
+
+    fn set_memory_limit(&self, limit: Option<usize>) {
+        log::trace!("Config, Set memory limit: {:?}", limit);
+        self.lock().wireguard.set_memory_limit(limit);
+    }
+
+    fn get_memory_limit(&self) -> Option<usize> {
+        self.lock().wireguard.get_memory_limit()
+    }
+
+    fn get_memory_usage(&self) -> usize {
+        self.lock().wireguard.get_memory_usage()
+    }
+
+    fn get_receiver_ids(&self) -> usize {
+        self.lock().wireguard.receiver_ids()
+    }
+
+    fn gc_receiver_ids(&self) -> usize {
+        let released = self.lock().wireguard.gc();
+        log::trace!("Config, Released {} receiver ids", released);
+        released
+    }
+
     fn replace_peers(&self) {
         self.lock().wireguard.clear_peers();
     }
@@ -300,8 +1317,20 @@ impl<T: tun::Tun, B: udp::PlatformUDP> Configuration for WireGuardConfig<T, B> {
         self.lock().wireguard.remove_peer(peer);
     }
 
-    fn add_peer(&self, peer: &PublicKey) -> bool {
-        self.lock().wireguard.add_peer(*peer)
+    fn add_peer(&self, peer: &PublicKey) -> Result<bool, ConfigError> {
+        let cfg = self.lock();
+        if cfg.wireguard.peers.read().get(peer).is_some() {
+            return Ok(false);
+        }
+        if !cfg.wireguard.authorize_peer(peer) {
+            log::info!("configuration, peer {} denied", PeerId::new(*peer));
+            return Err(ConfigError::PeerDenied);
+        }
+        let added = cfg.wireguard.add_peer(*peer);
+        if added {
+            Self::assign_auto_address(&cfg, peer);
+        }
+        Ok(added)
     }
 
     fn set_preshared_key(&self, peer: &PublicKey, psk: [u8; 32]) {
@@ -311,6 +1340,13 @@ impl<T: tun::Tun, B: udp::PlatformUDP> Configuration for WireGuardConfig<T, B> {
     fn set_endpoint(&self, peer: &PublicKey, addr: SocketAddr) {
         if let Some(peer) = self.lock().wireguard.peers.read().get(peer) {
             peer.set_endpoint(B::Endpoint::from_address(addr));
+            peer.opaque().resume_handshakes();
+        }
+    }
+
+    fn set_endpoint_candidates(&self, peer: &PublicKey, addrs: Vec<SocketAddr>) {
+        if !self.lock().wireguard.set_endpoint_candidates(peer, addrs) {
+            log::trace!("Config, Set endpoint candidates of unknown peer");
         }
     }
 
@@ -320,18 +1356,154 @@ impl<T: tun::Tun, B: udp::PlatformUDP> Configuration for WireGuardConfig<T, B> {
         }
     }
 
-    fn replace_allowed_ips(&self, peer: &PublicKey) {
+    fn set_cover_traffic_interval(&self, peer: &PublicKey, millis: u64) {
         if let Some(peer) = self.lock().wireguard.peers.read().get(peer) {
-            peer.remove_allowed_ips();
+            peer.opaque().set_cover_traffic_interval(millis);
         }
     }
 
+    fn set_keepalive_suppression(&self, peer: &PublicKey, suppression: KeepaliveSuppression) {
+        log::trace!("Config, Set keepalive suppression: {:?}", suppression);
+        if let Some(peer) = self.lock().wireguard.peers.read().get(peer) {
+            peer.opaque().set_keepalive_suppression(suppression);
+        }
+    }
+
+    fn set_source_interface(&self, peer: &PublicKey, ifindex: u32) {
+        if let Some(peer) = self.lock().wireguard.peers.read().get(peer) {
+            peer.set_source_interface(ifindex);
+        }
+    }
+
+    fn set_source_address(&self, peer: &PublicKey, addr: Option<IpAddr>) {
+        if let Some(peer) = self.lock().wireguard.peers.read().get(peer) {
+            peer.set_source_address(addr);
+        }
+    }
+
+    fn set_peer_hop_limit(&self, peer: &PublicKey, hops: Option<u8>) {
+        if let Some(peer) = self.lock().wireguard.peers.read().get(peer) {
+            peer.set_hop_limit(hops);
+        }
+    }
+
+    fn set_peer_mtu(&self, peer: &PublicKey, mtu: Option<usize>) {
+        log::trace!("Config, Set peer MTU: {:?}", mtu);
+        if let Some(peer) = self.lock().wireguard.peers.read().get(peer) {
+            peer.set_mtu(mtu);
+        }
+    }
+
+    fn set_peer_encapsulation(&self, peer: &PublicKey, encap: Option<Encapsulation>) {
+        log::trace!("Config, Set encapsulation: {:?}", encap);
+        if let Some(peer) = self.lock().wireguard.peers.read().get(peer) {
+            peer.set_encapsulation(encap);
+        }
+    }
+
+    fn set_quota(&self, peer: &PublicKey, quota: Option<Quota>) {
+        log::trace!("Config, Set quota: {:?}", quota);
+        if !self.lock().wireguard.set_quota(peer, quota) {
+            log::trace!("Config, Set quota of unknown peer");
+        }
+    }
+
+    fn get_quota(&self, peer: &PublicKey) -> Option<Quota> {
+        self.lock().wireguard.get_quota(peer)
+    }
+
+    fn set_exit_weight(&self, peer: &PublicKey, weight: u32) {
+        log::trace!("Config, Set exit weight: {}", weight);
+        if !self.lock().wireguard.set_exit_weight(peer, weight) {
+            log::trace!("Config, Set exit weight of unknown peer");
+        }
+    }
+
+    fn set_relay_policy(&self, peer: &PublicKey, policy: RelayPolicy) {
+        log::trace!("Config, Set relay policy: {:?}", policy);
+        if let Some(peer) = self.lock().wireguard.peers.read().get(peer) {
+            peer.set_relay_policy(policy);
+        }
+    }
+
+    fn set_endpoint_policy(&self, peer: &PublicKey, policy: EndpointPolicy) {
+        log::trace!("Config, Set endpoint policy: {:?}", policy);
+        if let Some(peer) = self.lock().wireguard.peers.read().get(peer) {
+            peer.set_endpoint_policy(policy);
+        }
+    }
+
+    fn set_endpoint_port(&self, peer: &PublicKey, port: Option<u16>) {
+        log::trace!("Config, Set endpoint port: {:?}", port);
+        if let Some(peer) = self.lock().wireguard.peers.read().get(peer) {
+            peer.set_endpoint_port(port);
+        }
+    }
+
+    fn set_handshake_role(&self, peer: &PublicKey, role: HandshakeRole) {
+        log::trace!("Config, Set handshake role: {:?}", role);
+        self.lock().wireguard.set_handshake_role(*peer, role);
+    }
+
+    fn rekey_peer(&self, peer: &PublicKey) {
+        if !self.lock().wireguard.rekey_peer(peer) {
+            log::trace!("Config, Rekey of unknown peer");
+        }
+    }
+
+    fn replace_allowed_ips(&self, peer: &PublicKey) {
+        let cfg = self.lock();
+        if let Some(handle) = cfg.wireguard.peers.read().get(peer) {
+            handle.remove_allowed_ips();
+        }
+        // the assigned address is retained
+        Self::assign_auto_address(&cfg, peer);
+    }
+
     fn add_allowed_ip(&self, peer: &PublicKey, ip: IpAddr, masklen: u32) {
         if let Some(peer) = self.lock().wireguard.peers.read().get(peer) {
             peer.add_allowed_ip(ip, masklen);
         }
     }
 
+    fn replace_policy_routes(&self, peer: &PublicKey) {
+        if let Some(peer) = self.lock().wireguard.peers.read().get(peer) {
+            peer.remove_policy_routes();
+        }
+    }
+
+    fn add_policy_route(
+        &self,
+        peer: &PublicKey,
+        source: (IpAddr, u32),
+        destination: (IpAddr, u32),
+    ) -> Result<(), ConfigError> {
+        log::trace!(
+            "Config, Add policy route: {:?} -> {:?}",
+            source,
+            destination
+        );
+        if let Some(peer) = self.lock().wireguard.peers.read().get(peer) {
+            if !peer.add_policy_route(source, destination) {
+                return Err(ConfigError::InvalidAllowedIp);
+            }
+        }
+        Ok(())
+    }
+
+    fn replace_ecmp_ips(&self, peer: &PublicKey) {
+        if let Some(peer) = self.lock().wireguard.peers.read().get(peer) {
+            peer.remove_ecmp_ips();
+        }
+    }
+
+    fn add_ecmp_ip(&self, peer: &PublicKey, ip: IpAddr, masklen: u32) {
+        log::trace!("Config, Add ECMP IP: {}/{}", ip, masklen);
+        if let Some(peer) = self.lock().wireguard.peers.read().get(peer) {
+            peer.add_ecmp_ip(ip, masklen);
+        }
+    }
+
     /*
 
 
@@ -353,6 +1525,7 @@ impl<T: tun::Tun, B: udp::PlatformUDP> Configuration for WireGuardConfig<T, B> {
 
     fn get_peers(&self) -> Vec<PeerState> {
         let cfg = self.lock();
+        let exit = cfg.wireguard.get_exit();
         let peers = cfg.wireguard.peers.read();
         let mut state = Vec::with_capacity(peers.len());
 
@@ -366,14 +1539,44 @@ impl<T: tun::Tun, B: udp::PlatformUDP> Configuration for WireGuardConfig<T, B> {
             });
 
             if let Some(psk) = cfg.wireguard.get_psk(&pk) {
+                let traffic = p.traffic.get();
+
                 // extract state into PeerState
                 state.push(PeerState {
                     preshared_key: psk,
                     endpoint: p.get_endpoint(),
-                    rx_bytes: p.rx_bytes.load(Ordering::Relaxed),
-                    tx_bytes: p.tx_bytes.load(Ordering::Relaxed),
+                    endpoint_policy: p.get_endpoint_policy(),
+                    endpoint_port: p.get_endpoint_port(),
+                    handshake_role: cfg.wireguard.get_handshake_role(&pk).unwrap_or_default(),
+                    rx_bytes: traffic.rx_bytes,
+                    tx_bytes: traffic.tx_bytes,
                     persistent_keepalive_interval: p.get_keepalive_interval(),
+                    keepalive_suppression: p.get_keepalive_suppression(),
+                    cover_traffic_interval: p.get_cover_traffic_interval(),
+                    session: p.session(),
+                    send_errors: p.send_errors(),
+                    source: p.get_source(),
+                    hop_limit: p.get_hop_limit(),
+                    mtu: p.get_mtu(),
+                    encap: p.get_encapsulation(),
+                    relayed: p.is_relayed(),
+                    endpoint_changes: p.get_endpoint_changes(),
+                    staged_packets: p.staged_packets(),
+                    endpoint_candidates: p.endpoint_candidates.lock().clone(),
+                    handshakes_suspended: p.suspended.lock().is_some(),
+                    flows: p.flows(),
+                    quota: *p.quota.read(),
+                    quota_usage: p.quota_usage.load(Ordering::Relaxed),
+                    quota_exceeded: p.disabled.load(Ordering::Relaxed),
+                    relay_policy: p.get_relay_policy(),
+                    relay: p.relay_stats(),
+                    exit_weight: p.exit_weight.load(Ordering::Relaxed),
+                    exit_active: exit == Some(pk),
+                    handshake_rtt: *p.handshake_rtt.lock(),
+                    cookie_retries: p.cookie_retries.load(Ordering::Relaxed),
                     allowed_ips: p.list_allowed_ips(),
+                    policy_routes: p.list_policy_routes(),
+                    ecmp_ips: p.list_ecmp_ips(),
                     last_handshake_time,
                     public_key: pk,
                 })
@@ -1,12 +1,14 @@
 use std::error::Error;
 use std::fmt;
 
+use super::TransportError;
+
 #[cfg(unix)]
 use libc::*;
 
 #[derive(Debug)]
 pub enum ConfigError {
-    FailedToBind,
+    FailedToBind(TransportError), // the source is the failure of the platform
     InvalidHexValue,
     InvalidPortNumber,
     InvalidFwmark,
@@ -19,6 +21,7 @@ pub enum ConfigError {
     IOError,
     UnsupportedValue,
     UnsupportedProtocolVersion,
+    PeerDenied,
 }
 
 impl fmt::Display for ConfigError {
@@ -33,7 +36,10 @@ impl Error for ConfigError {
     }
 
     fn source(&self) -> Option<&(dyn Error + 'static)> {
-        None
+        match self {
+            ConfigError::FailedToBind(e) => Some(e),
+            _ => None,
+        }
     }
 }
 
@@ -43,7 +49,7 @@ impl ConfigError {
         // TODO: obtain the correct errorno values
         match self {
             // insufficient perms
-            ConfigError::FailedToBind => EPERM,
+            ConfigError::FailedToBind(_) => EPERM,
 
             // parsing of value failed
             ConfigError::InvalidHexValue => EINVAL,
@@ -60,6 +66,9 @@ impl ConfigError {
             ConfigError::InvalidKey => EPROTO,
             ConfigError::UnsupportedProtocolVersion => EPROTO,
 
+            // vetoed by the authorization of peers
+            ConfigError::PeerDenied => EACCES,
+
             // IO
             ConfigError::IOError => EIO,
         }
@@ -0,0 +1,181 @@
+/* Full-mesh connectivity between a set of nodes:
+ *
+ * Every node of the mesh is a peer of every other node,
+ * reached at its endpoint (if known) with its inner address as the allowed IP.
+ * The same set of nodes is applied to the device of every node (see Mesh::apply),
+ * each device skipping the node of its own key.
+ *
+ * Only the peers added by the mesh are managed: nodes leaving the mesh are removed,
+ * while other peers of the device are left untouched.
+ */
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+
+use x25519_dalek::PublicKey;
+
+use super::config::Configuration;
+use super::keys;
+use super::quick::PeerConfig;
+use super::ConfigError;
+
+/// A node of the mesh
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub public_key: PublicKey,
+    pub endpoint: Option<SocketAddr>, // None = learned when the node initiates a handshake
+    pub address: (IpAddr, u32),       // inner address (or subnet) of the node
+}
+
+struct MeshState {
+    nodes: Vec<Node>,
+    managed: HashSet<[u8; 32]>, // peers added to the device by the mesh
+}
+
+/// A full mesh of nodes
+pub struct Mesh {
+    persistent_keepalive: u64, // seconds (0 = disabled)
+    state: Mutex<MeshState>,
+}
+
+impl Mesh {
+    /// Creates a mesh of the nodes
+    ///
+    /// # Arguments
+    ///
+    /// - `nodes`: The nodes of the mesh (including the local node)
+    /// - `persistent_keepalive`: The persistent keepalive interval between nodes (in seconds)
+    pub fn new(nodes: Vec<Node>, persistent_keepalive: u64) -> Mesh {
+        Mesh {
+            persistent_keepalive,
+            state: Mutex::new(MeshState {
+                nodes,
+                managed: HashSet::new(),
+            }),
+        }
+    }
+
+    /// Returns the nodes of the mesh
+    pub fn nodes(&self) -> Vec<Node> {
+        self.state.lock().unwrap().nodes.clone()
+    }
+
+    /// Returns the peer configuration of the node (the other nodes),
+    /// e.g. to provision a node which does not run this device
+    pub fn peers_of(&self, pk: &PublicKey) -> Vec<PeerConfig> {
+        self.state
+            .lock()
+            .unwrap()
+            .nodes
+            .iter()
+            .filter(|node| node.public_key.as_bytes() != pk.as_bytes())
+            .map(|node| PeerConfig {
+                public_key: node.public_key.into(),
+                preshared_key: None,
+                endpoint: node.endpoint.map(|addr| addr.to_string()),
+                allowed_ips: vec![node.address],
+                persistent_keepalive: self.persistent_keepalive,
+            })
+            .collect()
+    }
+
+    /// Applies the mesh to the device: adds (or updates) a peer for every other node
+    /// and removes the peers of nodes which left the mesh
+    pub fn apply<C: Configuration>(&self, config: &C) -> Result<(), ConfigError> {
+        let local = config.get_private_key().map(|sk| PublicKey::from(&sk));
+        let mut state = self.state.lock().unwrap();
+
+        // remove the nodes which left
+        let current: HashSet<[u8; 32]> = state
+            .nodes
+            .iter()
+            .map(|node| *node.public_key.as_bytes())
+            .collect();
+        let left: Vec<[u8; 32]> = state.managed.difference(&current).cloned().collect();
+        for pk in left {
+            log::debug!(
+                "Config, mesh node {} left",
+                keys::PeerId::new(PublicKey::from(pk))
+            );
+            config.remove_peer(&PublicKey::from(pk));
+            state.managed.remove(&pk);
+        }
+
+        // add or update the other nodes
+        let nodes = state.nodes.clone();
+        let mut res = Ok(());
+        for node in nodes.iter() {
+            let pk = &node.public_key;
+            if local.map_or(false, |local| local.as_bytes() == pk.as_bytes()) {
+                continue;
+            }
+            match config.add_peer(pk) {
+                Ok(added) => {
+                    if added {
+                        log::debug!("Config, mesh node {} joined", keys::PeerId::new(*pk));
+                        state.managed.insert(*pk.as_bytes());
+                    }
+                }
+                Err(e) => {
+                    res = Err(e); // e.g. denied, the other nodes are still applied
+                    continue;
+                }
+            }
+            if let Some(addr) = node.endpoint {
+                config.set_endpoint(pk, addr);
+            }
+            config.replace_allowed_ips(pk);
+            config.add_allowed_ip(pk, node.address.0, node.address.1);
+            config.set_persistent_keepalive_interval(pk, self.persistent_keepalive);
+        }
+        res
+    }
+
+    /// Adds (or replaces) a node of the mesh and applies the mesh to the device
+    pub fn join<C: Configuration>(&self, node: Node, config: &C) -> Result<(), ConfigError> {
+        {
+            let mut state = self.state.lock().unwrap();
+            let pk = *node.public_key.as_bytes();
+            state
+                .nodes
+                .retain(|other| other.public_key.as_bytes() != &pk);
+            state.nodes.push(node);
+        }
+        self.apply(config)
+    }
+
+    /// Removes a node from the mesh and applies the mesh to the device
+    pub fn leave<C: Configuration>(&self, pk: &PublicKey, config: &C) -> Result<(), ConfigError> {
+        self.state
+            .lock()
+            .unwrap()
+            .nodes
+            .retain(|node| node.public_key.as_bytes() != pk.as_bytes());
+        self.apply(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(n: u8) -> Node {
+        Node {
+            public_key: PublicKey::from([n; 32]),
+            endpoint: Some(format!("192.0.2.{}:51820", n).parse().unwrap()),
+            address: (format!("10.0.0.{}", n).parse().unwrap(), 32),
+        }
+    }
+
+    #[test]
+    fn test_peers_of() {
+        let mesh = Mesh::new(vec![node(1), node(2), node(3)], 25);
+        let peers = mesh.peers_of(&PublicKey::from([2u8; 32]));
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[0].public_key.as_bytes(), &[1u8; 32]);
+        assert_eq!(peers[0].endpoint.as_deref(), Some("192.0.2.1:51820"));
+        assert_eq!(peers[0].allowed_ips, vec![node(1).address]);
+        assert_eq!(peers[1].public_key.as_bytes(), &[3u8; 32]);
+        assert_eq!(peers[1].persistent_keepalive, 25);
+    }
+}
@@ -1,12 +1,40 @@
+mod autoaddr;
+mod beacon;
 mod config;
 mod error;
+mod mesh;
+mod provision;
+mod quick;
+mod store;
 pub mod uapi;
 
+use super::error::TransportError;
+use super::logging::{self, Subsystem};
+use super::platform::udp::{
+    BindMode, BindOptions, BoundPorts, FragmentPolicy, PortMismatch, Protocol, TransportOptions,
+    DEFAULT_BIND_ATTEMPTS,
+};
 use super::platform::Endpoint;
 use super::platform::{tun, udp};
-use super::wireguard::WireGuard;
+use super::wireguard::crypto;
+use super::wireguard::keys;
+use super::wireguard::{
+    Blackhole, Encapsulation, EndpointPolicy, ExitPolicy, Flow, FlowStats, HandshakeRole,
+    InterfaceStats, KeepaliveSuppression, MessageKind, Padding, PeerId, PowerSave, Quota,
+    QuotaPeriod, RelayPolicy, RelayStats, RxStats, SendErrors, SessionInfo, Source, WireGuard,
+    DEFAULT_MAX_STAGED_AGE,
+};
 
 pub use error::ConfigError;
 
+pub use beacon::{Approved, Beacon, BEACON_INTERVAL, BEACON_PORT};
 pub use config::Configuration;
 pub use config::WireGuardConfig;
+
+pub use mesh::{Mesh, Node};
+pub use provision::{AddressPool, ProvisionError, Provisioner};
+pub use quick::{InterfaceConfig, PeerConfig, QuickConfig, QuickConfigError};
+
+#[cfg(feature = "sqlite")]
+pub use store::SqliteStore;
+pub use store::{FileStore, Store, StoredConfig, StoredPeer};
@@ -0,0 +1,468 @@
+/* Provisioning of clients by a server device:
+ *
+ * A client is provisioned by generating its keys, allocating an unused tunnel address
+ * from the pool of the server, adding it as a peer and emitting its configuration
+ * (in the format of wg-quick, see QuickConfig), ready to be imported by the client.
+ *
+ * The allocations of the pool are persisted in a text file of "<address> <public key>" lines,
+ * such that the addresses of provisioned clients are not handed out again after a restart.
+ */
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use x25519_dalek::PublicKey;
+
+use super::config::Configuration;
+use super::keys;
+use super::quick::{InterfaceConfig, PeerConfig, QuickConfig};
+use super::ConfigError;
+
+#[derive(Debug)]
+pub enum ProvisionError {
+    NoPrivateKey,        // the server device has no private key
+    PoolExhausted,       // no unused address left in the pool
+    Config(ConfigError), // the peer could not be added (e.g. denied)
+    Io(io::Error),       // the pool could not be persisted
+}
+
+impl fmt::Display for ProvisionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProvisionError::NoPrivateKey => write!(f, "The device has no private key"),
+            ProvisionError::PoolExhausted => write!(f, "No unused address in the pool"),
+            ProvisionError::Config(e) => write!(f, "Failed to add the peer: {}", e),
+            ProvisionError::Io(e) => write!(f, "Failed to persist the pool: {}", e),
+        }
+    }
+}
+
+impl Error for ProvisionError {
+    fn description(&self) -> &str {
+        "Provision Error"
+    }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ProvisionError::Config(e) => Some(e),
+            ProvisionError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// addresses as integers (IPv4 addresses in the low 32 bits)
+fn to_bits(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u32::from(ip) as u128,
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+fn from_bits(v4: bool, bits: u128) -> IpAddr {
+    if v4 {
+        IpAddr::V4(Ipv4Addr::from(bits as u32))
+    } else {
+        IpAddr::V6(Ipv6Addr::from(bits))
+    }
+}
+
+fn host_len(ip: &IpAddr) -> u32 {
+    if ip.is_ipv4() {
+        32
+    } else {
+        128
+    }
+}
+
+// the prefix (address, prefix length) contains the address
+pub(super) fn contains(prefix: &(IpAddr, u32), ip: &IpAddr) -> bool {
+    let (network, cidr) = prefix;
+    if network.is_ipv4() != ip.is_ipv4() {
+        return false;
+    }
+    let shift = host_len(network) - cidr;
+    shift >= 128 || (to_bits(*network) >> shift) == (to_bits(*ip) >> shift)
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid {}", what))
+}
+
+/// Pool of tunnel addresses handed out to clients
+pub struct AddressPool {
+    network: (IpAddr, u32),
+    reserved: HashSet<IpAddr>, // never allocated, e.g. the address of the server
+    path: Option<PathBuf>,
+    allocated: Mutex<Vec<(IpAddr, PublicKey)>>,
+}
+
+impl AddressPool {
+    /// Creates a pool of the addresses of a network, which is not persisted
+    ///
+    /// # Arguments
+    ///
+    /// - `network`: The network (address, prefix length), the network address is never allocated
+    /// - `reserved`: Addresses never allocated (e.g. the address of the server)
+    pub fn new(network: (IpAddr, u32), reserved: Vec<IpAddr>) -> AddressPool {
+        debug_assert!(network.1 <= host_len(&network.0));
+        AddressPool {
+            network,
+            reserved: reserved.into_iter().collect(),
+            path: None,
+            allocated: Mutex::new(vec![]),
+        }
+    }
+
+    /// Creates a pool persisted in the file at the path,
+    /// restoring the allocations from the file (if it exists)
+    pub fn open<P: AsRef<Path>>(
+        network: (IpAddr, u32),
+        reserved: Vec<IpAddr>,
+        path: P,
+    ) -> io::Result<AddressPool> {
+        let mut pool = AddressPool::new(network, reserved);
+        pool.path = Some(path.as_ref().to_owned());
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(pool),
+            Err(e) => return Err(e),
+        };
+        {
+            let mut allocated = pool.allocated.lock().unwrap();
+            for line in content.lines().filter(|line| !line.trim().is_empty()) {
+                let mut split = line.split_whitespace();
+                let ip: IpAddr = split
+                    .next()
+                    .and_then(|ip| ip.parse().ok())
+                    .ok_or_else(|| invalid("address"))?;
+                let pk = split
+                    .next()
+                    .and_then(|pk| keys::PublicKey::from_base64(pk).ok())
+                    .ok_or_else(|| invalid("public key"))?;
+                allocated.push((ip, pk.into()));
+            }
+        }
+        Ok(pool)
+    }
+
+    /// Returns the allocated addresses and the peers they are allocated to
+    pub fn allocated(&self) -> Vec<(IpAddr, PublicKey)> {
+        self.allocated.lock().unwrap().clone()
+    }
+
+    /// Allocates the lowest unused address of the pool to the peer
+    ///
+    /// Every candidate is either allocated, reserved or skipped with the prefix in use
+    /// containing it, hence the scan is bounded by the number of those (not the size of the pool).
+    ///
+    /// # Arguments
+    ///
+    /// - `peer`: The public key of the peer
+    /// - `in_use`: Prefixes of addresses which must not be allocated (e.g. the allowed IPs of peers)
+    ///
+    /// # Returns
+    ///
+    /// The allocated address or None if the pool is exhausted
+    pub fn allocate(&self, peer: &PublicKey, in_use: &[(IpAddr, u32)]) -> Option<IpAddr> {
+        let (network, cidr) = self.network;
+        let v4 = network.is_ipv4();
+        let shift = host_len(&network) - cidr;
+        let size: u128 = if shift >= 128 { u128::MAX } else { 1 << shift };
+        let base = if shift >= 128 {
+            0
+        } else {
+            to_bits(network) >> shift << shift
+        };
+
+        let mut allocated = self.allocated.lock().unwrap();
+        let taken: HashSet<IpAddr> = allocated.iter().map(|(ip, _)| *ip).collect();
+        let mut offset: u128 = 1;
+        while offset < size && !(v4 && offset == size - 1) {
+            let ip = from_bits(v4, base + offset);
+
+            // skip to the end of the prefix in use
+            if let Some((network, cidr)) = in_use.iter().find(|prefix| contains(prefix, &ip)) {
+                let shift = host_len(network) - cidr;
+                let mask = if shift >= 128 {
+                    u128::MAX
+                } else {
+                    (1 << shift) - 1
+                };
+                offset = ((base + offset) | mask).wrapping_sub(base).checked_add(1)?;
+                continue;
+            }
+            if self.reserved.contains(&ip) || taken.contains(&ip) {
+                offset += 1;
+                continue;
+            }
+
+            allocated.push((ip, *peer));
+            return Some(ip);
+        }
+        None
+    }
+
+    /// Releases the addresses allocated to the peer
+    pub fn release(&self, peer: &PublicKey) {
+        self.allocated
+            .lock()
+            .unwrap()
+            .retain(|(_, pk)| pk.as_bytes() != peer.as_bytes());
+    }
+
+    /// Releases the addresses of peers which are no longer configured
+    pub fn retain(&self, peers: &[PublicKey]) {
+        self.allocated
+            .lock()
+            .unwrap()
+            .retain(|(_, pk)| peers.iter().any(|peer| peer.as_bytes() == pk.as_bytes()));
+    }
+
+    /// Writes the allocations to the file of the pool (if any) atomically
+    pub fn save(&self) -> io::Result<()> {
+        let path = match self.path.as_ref() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let tmp = path.with_extension("tmp");
+        {
+            let mut file = fs::File::create(&tmp)?;
+            for (ip, pk) in self.allocated.lock().unwrap().iter() {
+                writeln!(file, "{} {}", ip, keys::to_base64(pk.as_bytes()))?;
+            }
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, path)
+    }
+}
+
+/// Provisions clients of a server device
+pub struct Provisioner {
+    pub endpoint: String, // endpoint of the server for the clients (host:port)
+    pub dns: Vec<IpAddr>, // DNS servers of the clients (if any)
+    pub allowed_ips: Vec<(IpAddr, u32)>, // routed through the tunnel by the clients
+    pub persistent_keepalive: u64, // of the clients, in seconds (0 = disabled)
+    pub pool: AddressPool,
+}
+
+impl Provisioner {
+    /// Provisions a new client: generates its keys, allocates its tunnel address,
+    /// adds it as a peer of the device (with a fresh pre-shared key) and persists the pool.
+    ///
+    /// # Returns
+    ///
+    /// The configuration of the client (holding its private key, which is not retained)
+    pub fn provision<C: Configuration>(&self, config: &C) -> Result<QuickConfig, ProvisionError> {
+        let server = config
+            .get_private_key()
+            .map(|sk| PublicKey::from(&sk))
+            .ok_or(ProvisionError::NoPrivateKey)?;
+
+        // generate the keys of the client
+        let sk = keys::generate();
+        let pk: PublicKey = sk.public_key().into();
+        let psk = keys::generate_preshared();
+
+        // allocate an address unused by the pool and by the current peers
+        let peers = config.get_peers();
+        let in_use: Vec<(IpAddr, u32)> = peers
+            .iter()
+            .flat_map(|p| p.allowed_ips.iter().cloned())
+            .collect();
+        self.pool
+            .retain(&peers.iter().map(|p| p.public_key).collect::<Vec<_>>());
+        let ip = self
+            .pool
+            .allocate(&pk, &in_use)
+            .ok_or(ProvisionError::PoolExhausted)?;
+        let address = (ip, host_len(&ip));
+
+        // add the peer (rolled back if the pool cannot be persisted)
+        match config.add_peer(&pk) {
+            Ok(true) => (),
+            Ok(false) => unreachable!("generated key of an existing peer"),
+            Err(e) => {
+                self.pool.release(&pk);
+                return Err(ProvisionError::Config(e));
+            }
+        }
+        config.set_preshared_key(&pk, *psk.as_bytes());
+        config.add_allowed_ip(&pk, address.0, address.1);
+        if let Err(e) = self.pool.save() {
+            config.remove_peer(&pk);
+            self.pool.release(&pk);
+            return Err(ProvisionError::Io(e));
+        }
+        let _ = config.persist();
+        log::info!(
+            "Config, provisioned peer {} with address {}",
+            keys::PeerId::new(pk),
+            ip
+        );
+
+        Ok(QuickConfig {
+            interface: InterfaceConfig {
+                private_key: sk,
+                addresses: vec![address],
+                dns: self.dns.clone(),
+                dns_search: vec![],
+                listen_port: None,
+                mtu: None,
+            },
+            peers: vec![PeerConfig {
+                public_key: server.into(),
+                preshared_key: Some(psk),
+                endpoint: Some(self.endpoint.clone()),
+                allowed_ips: self.allowed_ips.clone(),
+                persistent_keepalive: self.persistent_keepalive,
+            }],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use x25519_dalek::StaticSecret;
+
+    use super::super::{WireGuard, WireGuardConfig};
+
+    fn pk(n: u8) -> PublicKey {
+        PublicKey::from([n; 32])
+    }
+
+    #[test]
+    fn test_pool_allocate() {
+        let pool = AddressPool::new(
+            ("10.0.0.0".parse().unwrap(), 30),
+            vec!["10.0.0.1".parse().unwrap()],
+        );
+        // network, reserved and broadcast addresses are skipped
+        assert_eq!(
+            pool.allocate(&pk(1), &[]),
+            Some("10.0.0.2".parse().unwrap())
+        );
+        assert_eq!(pool.allocate(&pk(2), &[]), None);
+
+        // released addresses are reused, unless in use by a peer
+        pool.release(&pk(1));
+        let in_use = vec![("10.0.0.2".parse().unwrap(), 32)];
+        assert_eq!(pool.allocate(&pk(2), &in_use), None);
+        pool.retain(&[pk(3)]);
+        assert_eq!(
+            pool.allocate(&pk(3), &[]),
+            Some("10.0.0.2".parse().unwrap())
+        );
+
+        let pool = AddressPool::new(("fd00::".parse().unwrap(), 64), vec![]);
+        assert_eq!(pool.allocate(&pk(1), &[]), Some("fd00::1".parse().unwrap()));
+        assert_eq!(pool.allocate(&pk(2), &[]), Some("fd00::2".parse().unwrap()));
+
+        // prefixes in use are skipped at once (the pool may be as large as the address space)
+        let pool = AddressPool::new(("fd00::".parse().unwrap(), 16), vec![]);
+        let in_use = vec![("fd00::".parse().unwrap(), 17)];
+        assert_eq!(
+            pool.allocate(&pk(3), &in_use),
+            Some("fd00:8000::".parse().unwrap())
+        );
+        let pool = AddressPool::new(("::".parse().unwrap(), 0), vec![]);
+        assert_eq!(pool.allocate(&pk(1), &[("::".parse().unwrap(), 0)]), None);
+        let in_use = vec![("::".parse().unwrap(), 1)];
+        assert_eq!(
+            pool.allocate(&pk(1), &in_use),
+            Some("8000::".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_provision() {
+        let (wg, _fake, _remote) = WireGuard::new_dummy();
+        let config = WireGuardConfig::new(wg);
+        let provisioner = Provisioner {
+            endpoint: "vpn.example.com:51820".to_owned(),
+            dns: vec!["10.0.0.1".parse().unwrap()],
+            allowed_ips: vec![("0.0.0.0".parse().unwrap(), 0)],
+            persistent_keepalive: 25,
+            pool: AddressPool::new(
+                ("10.0.0.0".parse().unwrap(), 24),
+                vec!["10.0.0.1".parse().unwrap()],
+            ),
+        };
+
+        // the device must have a key to provision clients
+        match provisioner.provision(&config) {
+            Err(ProvisionError::NoPrivateKey) => (),
+            r => panic!("unexpected result: {:?}", r.map(|c| c.to_string())),
+        }
+        let sk = StaticSecret::from([1u8; 32]);
+        let server = PublicKey::from(&sk);
+        config.set_private_key(Some(sk));
+
+        // the address of an existing peer is not allocated
+        config.add_peer(&pk(9)).unwrap();
+        config.add_allowed_ip(&pk(9), "10.0.0.2".parse().unwrap(), 32);
+
+        let client = provisioner.provision(&config).unwrap();
+        let address: IpAddr = "10.0.0.3".parse().unwrap();
+        assert_eq!(client.interface.addresses, vec![(address, 32)]);
+        assert_eq!(client.interface.dns, provisioner.dns);
+        assert_eq!(client.peers.len(), 1);
+        assert_eq!(client.peers[0].public_key.as_bytes(), server.as_bytes());
+        assert_eq!(
+            client.peers[0].endpoint.as_deref(),
+            Some("vpn.example.com:51820")
+        );
+        assert_eq!(client.peers[0].persistent_keepalive, 25);
+
+        // the client is a peer of the device, with the allocated address
+        let pk: PublicKey = client.interface.private_key.public_key().into();
+        let peer = config
+            .get_peers()
+            .into_iter()
+            .find(|p| p.public_key.as_bytes() == pk.as_bytes())
+            .unwrap();
+        assert_eq!(peer.allowed_ips, vec![(address, 32)]);
+        assert_eq!(provisioner.pool.allocated()[0].0, address);
+
+        // the configuration can be imported by the client
+        assert!(QuickConfig::parse(&client.to_string()).is_ok());
+    }
+
+    #[test]
+    fn test_pool_persist() {
+        let path = std::env::temp_dir().join(format!("wg-pool-test-{}", std::process::id()));
+        let network = ("10.0.0.0".parse().unwrap(), 24);
+        let pool = AddressPool::open(network, vec![], &path).unwrap();
+        assert!(pool.allocated().is_empty());
+        assert_eq!(
+            pool.allocate(&pk(1), &[]),
+            Some("10.0.0.1".parse().unwrap())
+        );
+        assert_eq!(
+            pool.allocate(&pk(2), &[]),
+            Some("10.0.0.2".parse().unwrap())
+        );
+        pool.save().unwrap();
+
+        let pool = AddressPool::open(network, vec![], &path).unwrap();
+        let allocated = pool.allocated();
+        assert_eq!(allocated.len(), 2);
+        assert_eq!(allocated[1].0, "10.0.0.2".parse::<IpAddr>().unwrap());
+        assert_eq!(allocated[1].1.as_bytes(), &[2u8; 32]);
+        assert_eq!(
+            pool.allocate(&pk(3), &[]),
+            Some("10.0.0.3".parse().unwrap())
+        );
+
+        fs::write(&path, "10.0.0.1\n").unwrap();
+        assert!(AddressPool::open(network, vec![], &path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,600 @@
+/* Configurations in the format of wg-quick, as shared with clients (e.g. as QR codes):
+ *
+ * [Interface]
+ * PrivateKey = <base64>
+ * Address = 10.0.0.2/32, fd00::2/128
+ * DNS = 10.0.0.1
+ *
+ * [Peer]
+ * PublicKey = <base64>
+ * PresharedKey = <base64>
+ * Endpoint = vpn.example.com:51820
+ * AllowedIPs = 0.0.0.0/0, ::/0
+ * PersistentKeepalive = 25
+ *
+ * Keys are case-insensitive and lists are comma-separated (or repeated), as with wg-quick.
+ * Keys of wg-quick which do not concern a client (e.g. PostUp) are rejected, not ignored,
+ * rather than silently dropping part of a provisioning payload.
+ */
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, ToSocketAddrs};
+
+use super::config::Configuration;
+use super::keys::{PresharedKey, PrivateKey, PublicKey};
+use super::ConfigError;
+
+// scheme of provisioning URIs (followed by the percent-encoded configuration)
+const URI_SCHEME: &str = "wireguard:";
+
+/// The [Interface] section of a configuration
+#[derive(Debug, Clone)]
+pub struct InterfaceConfig {
+    pub private_key: PrivateKey,
+    pub addresses: Vec<(IpAddr, u32)>, // addresses of the tunnel interface
+    pub dns: Vec<IpAddr>,              // DNS servers (reached through the tunnel)
+    pub dns_search: Vec<String>,       // DNS search domains (non-address entries of DNS)
+    pub listen_port: Option<u16>,      // None = chosen by the OS
+    pub mtu: Option<usize>,            // None = chosen by the client
+}
+
+/// A [Peer] section of a configuration
+#[derive(Debug, Clone)]
+pub struct PeerConfig {
+    pub public_key: PublicKey,
+    pub preshared_key: Option<PresharedKey>,
+    pub endpoint: Option<String>, // host:port (the host may be a name, resolved by the client)
+    pub allowed_ips: Vec<(IpAddr, u32)>,
+    pub persistent_keepalive: u64, // seconds (0 = disabled)
+}
+
+/// A configuration of wg-quick: the interface and its peers
+#[derive(Debug, Clone)]
+pub struct QuickConfig {
+    pub interface: InterfaceConfig,
+    pub peers: Vec<PeerConfig>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuickConfigError {
+    InvalidUri,                // not a provisioning URI (or invalid percent-encoding)
+    InvalidLine(usize),        // neither a section, a key = value pair nor a comment
+    UnknownSection(usize),     // section other than [Interface] or [Peer]
+    UnknownKey(usize),         // key not supported in the section
+    InvalidValue(usize),       // value could not be parsed
+    MissingSection(usize),     // key = value pair before any section
+    DuplicateInterface(usize), // a second [Interface] section
+    MissingInterface,          // no [Interface] section
+    MissingPrivateKey,         // [Interface] without a PrivateKey
+    MissingPublicKey(usize),   // [Peer] (at the line) without a PublicKey
+}
+
+impl fmt::Display for QuickConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuickConfigError::InvalidUri => write!(f, "Invalid provisioning URI"),
+            QuickConfigError::InvalidLine(n) => write!(f, "Invalid line {}", n),
+            QuickConfigError::UnknownSection(n) => write!(f, "Unknown section at line {}", n),
+            QuickConfigError::UnknownKey(n) => write!(f, "Unknown key at line {}", n),
+            QuickConfigError::InvalidValue(n) => write!(f, "Invalid value at line {}", n),
+            QuickConfigError::MissingSection(n) => {
+                write!(f, "Key outside of a section at line {}", n)
+            }
+            QuickConfigError::DuplicateInterface(n) => {
+                write!(f, "Duplicate [Interface] section at line {}", n)
+            }
+            QuickConfigError::MissingInterface => write!(f, "No [Interface] section"),
+            QuickConfigError::MissingPrivateKey => write!(f, "No PrivateKey in [Interface]"),
+            QuickConfigError::MissingPublicKey(n) => {
+                write!(f, "No PublicKey in [Peer] at line {}", n)
+            }
+        }
+    }
+}
+
+impl Error for QuickConfigError {
+    fn description(&self) -> &str {
+        "Quick Config Error"
+    }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+// sections being parsed (the keys are collected before the mandatory keys are checked)
+struct PartialInterface {
+    private_key: Option<PrivateKey>,
+    addresses: Vec<(IpAddr, u32)>,
+    dns: Vec<IpAddr>,
+    dns_search: Vec<String>,
+    listen_port: Option<u16>,
+    mtu: Option<usize>,
+}
+
+struct PartialPeer {
+    line: usize,
+    public_key: Option<PublicKey>,
+    preshared_key: Option<PresharedKey>,
+    endpoint: Option<String>,
+    allowed_ips: Vec<(IpAddr, u32)>,
+    persistent_keepalive: u64,
+}
+
+enum Section {
+    Interface,
+    Peer,
+}
+
+// parses "<ip>/<cidr>", an address without prefix length is a host route
+fn parse_prefix(value: &str) -> Option<(IpAddr, u32)> {
+    let mut split = value.splitn(2, '/');
+    let ip: IpAddr = split.next()?.trim().parse().ok()?;
+    let max = if ip.is_ipv4() { 32 } else { 128 };
+    let cidr = match split.next() {
+        Some(cidr) => cidr.trim().parse().ok()?,
+        None => max,
+    };
+    if cidr > max {
+        return None;
+    }
+    Some((ip, cidr))
+}
+
+// "host:port" or "[v6]:port"
+fn valid_endpoint(value: &str) -> bool {
+    match value.rfind(':') {
+        Some(idx) => {
+            let host = &value[..idx];
+            !host.is_empty() && value[idx + 1..].parse::<u16>().is_ok()
+        }
+        None => false,
+    }
+}
+
+fn list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').map(str::trim).filter(|v| !v.is_empty())
+}
+
+// decodes a percent-encoded string ("+" is not a space in URIs)
+fn percent_decode(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = value.get(i + 1..i + 3)?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+// encodes every byte which is not unreserved (RFC 3986)
+fn percent_encode(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for &b in value.as_bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(b as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+impl QuickConfig {
+    /// Parses a configuration in the format of wg-quick
+    /// (e.g. the content of a QR code)
+    pub fn parse(content: &str) -> Result<QuickConfig, QuickConfigError> {
+        let mut interface: Option<PartialInterface> = None;
+        let mut peers: Vec<PartialPeer> = vec![];
+        let mut section: Option<Section> = None;
+
+        for (idx, line) in content.lines().enumerate() {
+            let n = idx + 1;
+
+            // strip comments and whitespace
+            let line = line.splitn(2, '#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            // section header
+            if line.starts_with('[') {
+                if !line.ends_with(']') {
+                    return Err(QuickConfigError::InvalidLine(n));
+                }
+                match line[1..line.len() - 1].trim().to_ascii_lowercase().as_str() {
+                    "interface" => {
+                        if interface.is_some() {
+                            return Err(QuickConfigError::DuplicateInterface(n));
+                        }
+                        interface = Some(PartialInterface {
+                            private_key: None,
+                            addresses: vec![],
+                            dns: vec![],
+                            dns_search: vec![],
+                            listen_port: None,
+                            mtu: None,
+                        });
+                        section = Some(Section::Interface);
+                    }
+                    "peer" => {
+                        peers.push(PartialPeer {
+                            line: n,
+                            public_key: None,
+                            preshared_key: None,
+                            endpoint: None,
+                            allowed_ips: vec![],
+                            persistent_keepalive: 0,
+                        });
+                        section = Some(Section::Peer);
+                    }
+                    _ => return Err(QuickConfigError::UnknownSection(n)),
+                }
+                continue;
+            }
+
+            // key = value pair
+            let mut split = line.splitn(2, '=');
+            let (key, value) = match (split.next(), split.next()) {
+                (Some(key), Some(value)) => (key.trim().to_ascii_lowercase(), value.trim()),
+                _ => return Err(QuickConfigError::InvalidLine(n)),
+            };
+            let invalid = QuickConfigError::InvalidValue(n);
+
+            match section {
+                None => return Err(QuickConfigError::MissingSection(n)),
+                Some(Section::Interface) => {
+                    let interface = interface.as_mut().unwrap();
+                    match key.as_str() {
+                        "privatekey" => {
+                            interface.private_key =
+                                Some(PrivateKey::from_base64(value).map_err(|_| invalid)?)
+                        }
+                        "address" => {
+                            for prefix in list(value) {
+                                interface
+                                    .addresses
+                                    .push(parse_prefix(prefix).ok_or(invalid)?);
+                            }
+                        }
+                        "dns" => {
+                            for entry in list(value) {
+                                match entry.parse() {
+                                    Ok(ip) => interface.dns.push(ip),
+                                    Err(_) => interface.dns_search.push(entry.to_owned()),
+                                }
+                            }
+                        }
+                        "listenport" => {
+                            interface.listen_port = Some(value.parse().map_err(|_| invalid)?)
+                        }
+                        "mtu" => interface.mtu = Some(value.parse().map_err(|_| invalid)?),
+                        _ => return Err(QuickConfigError::UnknownKey(n)),
+                    }
+                }
+                Some(Section::Peer) => {
+                    let peer = peers.last_mut().unwrap();
+                    match key.as_str() {
+                        "publickey" => {
+                            peer.public_key =
+                                Some(PublicKey::from_base64(value).map_err(|_| invalid)?)
+                        }
+                        "presharedkey" => {
+                            peer.preshared_key =
+                                Some(PresharedKey::from_base64(value).map_err(|_| invalid)?)
+                        }
+                        "endpoint" => {
+                            if !valid_endpoint(value) {
+                                return Err(invalid);
+                            }
+                            peer.endpoint = Some(value.to_owned());
+                        }
+                        "allowedips" => {
+                            for prefix in list(value) {
+                                peer.allowed_ips.push(parse_prefix(prefix).ok_or(invalid)?);
+                            }
+                        }
+                        "persistentkeepalive" => {
+                            peer.persistent_keepalive = if value == "off" {
+                                0
+                            } else {
+                                value.parse().map_err(|_| invalid)?
+                            }
+                        }
+                        _ => return Err(QuickConfigError::UnknownKey(n)),
+                    }
+                }
+            }
+        }
+
+        // check the mandatory keys
+        let interface = interface.ok_or(QuickConfigError::MissingInterface)?;
+        let interface = InterfaceConfig {
+            private_key: interface
+                .private_key
+                .ok_or(QuickConfigError::MissingPrivateKey)?,
+            addresses: interface.addresses,
+            dns: interface.dns,
+            dns_search: interface.dns_search,
+            listen_port: interface.listen_port,
+            mtu: interface.mtu,
+        };
+        let peers = peers
+            .into_iter()
+            .map(|peer| {
+                Ok(PeerConfig {
+                    public_key: peer
+                        .public_key
+                        .ok_or(QuickConfigError::MissingPublicKey(peer.line))?,
+                    preshared_key: peer.preshared_key,
+                    endpoint: peer.endpoint,
+                    allowed_ips: peer.allowed_ips,
+                    persistent_keepalive: peer.persistent_keepalive,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(QuickConfig { interface, peers })
+    }
+
+    /// Parses a provisioning URI: "wireguard:" (or "wireguard://")
+    /// followed by the percent-encoded configuration
+    pub fn from_uri(uri: &str) -> Result<QuickConfig, QuickConfigError> {
+        let uri = uri.trim();
+        if uri.len() < URI_SCHEME.len() || !uri[..URI_SCHEME.len()].eq_ignore_ascii_case(URI_SCHEME)
+        {
+            return Err(QuickConfigError::InvalidUri);
+        }
+        let encoded = &uri[URI_SCHEME.len()..];
+        let encoded = encoded.strip_prefix("//").unwrap_or(encoded);
+        let content = percent_decode(encoded).ok_or(QuickConfigError::InvalidUri)?;
+        QuickConfig::parse(&content)
+    }
+
+    /// Encodes the configuration as a provisioning URI (see QuickConfig::from_uri)
+    pub fn to_uri(&self) -> String {
+        format!("{}{}", URI_SCHEME, percent_encode(&self.to_string()))
+    }
+
+    /// Parses a configuration or a provisioning URI (e.g. the content of a file or a QR code)
+    pub fn import(content: &str) -> Result<QuickConfig, QuickConfigError> {
+        let scheme = content.trim_start().get(..URI_SCHEME.len());
+        if scheme.map_or(false, |scheme| scheme.eq_ignore_ascii_case(URI_SCHEME)) {
+            QuickConfig::from_uri(content)
+        } else {
+            QuickConfig::parse(content)
+        }
+    }
+
+    /// Applies the configuration to the device: sets the private key (and listen port)
+    /// and adds (or updates) the peers.
+    ///
+    /// The addresses, DNS servers and MTU concern the tunnel interface,
+    /// hence are left to the host (see platform::net).
+    /// The endpoints are resolved once (host names included).
+    pub fn apply<C: Configuration>(&self, config: &C) -> Result<(), ConfigError> {
+        config.set_private_key(Some((&self.interface.private_key).into()));
+        if let Some(port) = self.interface.listen_port {
+            config.set_listen_port(port)?;
+        }
+        for peer in self.peers.iter() {
+            let pk: x25519_dalek::PublicKey = peer.public_key.into();
+            config.add_peer(&pk)?;
+            if let Some(psk) = peer.preshared_key.as_ref() {
+                config.set_preshared_key(&pk, *psk.as_bytes());
+            }
+            if let Some(endpoint) = peer.endpoint.as_ref() {
+                let addr = endpoint
+                    .to_socket_addrs()
+                    .ok()
+                    .and_then(|mut addrs| addrs.next())
+                    .ok_or(ConfigError::InvalidSocketAddr)?;
+                config.set_endpoint(&pk, addr);
+            }
+            config.replace_allowed_ips(&pk);
+            for (ip, cidr) in peer.allowed_ips.iter() {
+                config.add_allowed_ip(&pk, *ip, *cidr);
+            }
+            config.set_persistent_keepalive_interval(&pk, peer.persistent_keepalive);
+        }
+        Ok(())
+    }
+}
+
+fn join<T: fmt::Display>(items: impl Iterator<Item = T>) -> String {
+    items
+        .map(|item| item.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Formats the configuration in the format of wg-quick (parsed by QuickConfig::parse)
+impl fmt::Display for QuickConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let interface = &self.interface;
+        writeln!(f, "[Interface]")?;
+        writeln!(f, "PrivateKey = {}", interface.private_key.to_base64())?;
+        if !interface.addresses.is_empty() {
+            let addresses = interface
+                .addresses
+                .iter()
+                .map(|(ip, cidr)| format!("{}/{}", ip, cidr));
+            writeln!(f, "Address = {}", join(addresses))?;
+        }
+        if !interface.dns.is_empty() || !interface.dns_search.is_empty() {
+            let servers = interface.dns.iter().map(|ip| ip.to_string());
+            let search = interface.dns_search.iter().cloned();
+            writeln!(f, "DNS = {}", join(servers.chain(search)))?;
+        }
+        if let Some(port) = interface.listen_port {
+            writeln!(f, "ListenPort = {}", port)?;
+        }
+        if let Some(mtu) = interface.mtu {
+            writeln!(f, "MTU = {}", mtu)?;
+        }
+        for peer in self.peers.iter() {
+            writeln!(f)?;
+            writeln!(f, "[Peer]")?;
+            writeln!(f, "PublicKey = {}", peer.public_key)?;
+            if let Some(psk) = peer.preshared_key.as_ref() {
+                writeln!(f, "PresharedKey = {}", psk.to_base64())?;
+            }
+            if let Some(endpoint) = peer.endpoint.as_ref() {
+                writeln!(f, "Endpoint = {}", endpoint)?;
+            }
+            if !peer.allowed_ips.is_empty() {
+                let allowed_ips = peer
+                    .allowed_ips
+                    .iter()
+                    .map(|(ip, cidr)| format!("{}/{}", ip, cidr));
+                writeln!(f, "AllowedIPs = {}", join(allowed_ips))?;
+            }
+            if peer.persistent_keepalive > 0 {
+                writeln!(f, "PersistentKeepalive = {}", peer.persistent_keepalive)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::{WireGuard, WireGuardConfig};
+
+    const CONFIG: &str = "
+# provisioned by the server
+[Interface]
+PrivateKey = AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=
+Address = 10.0.0.2/32, fd00::2
+DNS = 10.0.0.1, vpn.example.com
+MTU = 1420
+
+[Peer]
+publickey = AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=
+Endpoint = vpn.example.com:51820 # the server
+AllowedIPs = 0.0.0.0/0
+AllowedIPs = ::/0
+PersistentKeepalive = 25
+";
+
+    #[test]
+    fn test_parse() {
+        let config = QuickConfig::parse(CONFIG).unwrap();
+        let interface = &config.interface;
+        let bytes: Vec<u8> = (0..32).collect();
+        assert_eq!(&interface.private_key.as_bytes()[..], &bytes[..]);
+        assert_eq!(
+            interface.addresses,
+            vec![
+                ("10.0.0.2".parse().unwrap(), 32),
+                ("fd00::2".parse().unwrap(), 128)
+            ]
+        );
+        assert_eq!(interface.dns, vec!["10.0.0.1".parse::<IpAddr>().unwrap()]);
+        assert_eq!(interface.dns_search, vec!["vpn.example.com".to_owned()]);
+        assert_eq!(interface.listen_port, None);
+        assert_eq!(interface.mtu, Some(1420));
+
+        assert_eq!(config.peers.len(), 1);
+        let peer = &config.peers[0];
+        assert_eq!(peer.public_key.as_bytes(), &[1u8; 32]);
+        assert!(peer.preshared_key.is_none());
+        assert_eq!(peer.endpoint.as_deref(), Some("vpn.example.com:51820"));
+        assert_eq!(
+            peer.allowed_ips,
+            vec![("0.0.0.0".parse().unwrap(), 0), ("::".parse().unwrap(), 0)]
+        );
+        assert_eq!(peer.persistent_keepalive, 25);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let config = QuickConfig::parse(CONFIG).unwrap();
+        let again = QuickConfig::parse(&config.to_string()).unwrap();
+        assert_eq!(again.to_string(), config.to_string());
+
+        let uri = config.to_uri();
+        assert!(uri.starts_with("wireguard:%5BInterface%5D"));
+        let again = QuickConfig::from_uri(&uri).unwrap();
+        assert_eq!(again.to_string(), config.to_string());
+
+        let uri = format!("wireguard://{}", &uri["wireguard:".len()..]);
+        assert!(QuickConfig::from_uri(&uri).is_ok());
+    }
+
+    #[test]
+    fn test_apply() {
+        let (wg, _fake, _remote) = WireGuard::new_dummy();
+        let config = WireGuardConfig::new(wg);
+        let content = CONFIG.replace("vpn.example.com:51820", "192.0.2.1:51820");
+
+        // configurations and URIs are both imported
+        let quick = QuickConfig::import(&content).unwrap();
+        let uri = QuickConfig::import(&quick.to_uri()).unwrap();
+        assert_eq!(uri.to_string(), quick.to_string());
+
+        quick.apply(&config).unwrap();
+        let sk = config.get_private_key().unwrap();
+        assert_eq!(
+            x25519_dalek::PublicKey::from(&sk).as_bytes(),
+            quick.interface.private_key.public_key().as_bytes()
+        );
+        let peers = config.get_peers();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].public_key.as_bytes(), &[1u8; 32]);
+        assert_eq!(peers[0].endpoint, Some("192.0.2.1:51820".parse().unwrap()));
+        assert_eq!(peers[0].allowed_ips.len(), 2);
+        assert_eq!(peers[0].persistent_keepalive_interval, 25);
+    }
+
+    #[test]
+    fn test_errors() {
+        let key = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+        let parse = |s: &str| QuickConfig::parse(s).map(|_| ()).unwrap_err();
+        assert_eq!(parse("PrivateKey = x"), QuickConfigError::MissingSection(1));
+        assert_eq!(parse("[Interface]\nfoo"), QuickConfigError::InvalidLine(2));
+        assert_eq!(parse("[Other]"), QuickConfigError::UnknownSection(1));
+        assert_eq!(
+            parse("[Interface]\nPostUp = x"),
+            QuickConfigError::UnknownKey(2)
+        );
+        assert_eq!(
+            parse("[Interface]\nAddress = 10.0.0.1/33"),
+            QuickConfigError::InvalidValue(2)
+        );
+        assert_eq!(
+            parse("[Interface]\n[Interface]"),
+            QuickConfigError::DuplicateInterface(2)
+        );
+        assert_eq!(parse(""), QuickConfigError::MissingInterface);
+        assert_eq!(parse("[Interface]"), QuickConfigError::MissingPrivateKey);
+        assert_eq!(
+            parse(&format!("[Interface]\nPrivateKey = {}\n[Peer]", key)),
+            QuickConfigError::MissingPublicKey(3)
+        );
+        assert_eq!(
+            parse(&format!(
+                "[Interface]\nPrivateKey = {}\n[Peer]\nEndpoint = host",
+                key
+            )),
+            QuickConfigError::InvalidValue(4)
+        );
+        assert_eq!(
+            QuickConfig::from_uri("https://example.com").map(|_| ()),
+            Err(QuickConfigError::InvalidUri)
+        );
+        assert_eq!(
+            QuickConfig::from_uri("wireguard:%ZZ").map(|_| ()),
+            Err(QuickConfigError::InvalidUri)
+        );
+    }
+}
@@ -0,0 +1,461 @@
+/* Persistence of the configuration of the device and its peers (see Configuration::set_store),
+ * such that a headless server restores its peers at startup without an external daemon.
+ *
+ * The configuration is restored when the store is set and saved after every UAPI set operation.
+ */
+use std::fs;
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+use hex::FromHex;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use super::{Quota, QuotaPeriod};
+
+/// Persisted configuration of a peer
+#[derive(Clone)]
+pub struct StoredPeer {
+    pub public_key: PublicKey,
+    pub preshared_key: [u8; 32],
+    pub endpoint: Option<SocketAddr>,
+    pub allowed_ips: Vec<(IpAddr, u32)>,
+    pub persistent_keepalive_interval: u64,
+    pub quota: Option<Quota>,
+}
+
+/// Persisted configuration of the device
+#[derive(Clone, Default)]
+pub struct StoredConfig {
+    pub private_key: Option<StaticSecret>,
+    pub listen_port: u16, // 0 = chosen by the OS
+    pub peers: Vec<StoredPeer>,
+}
+
+/// Storage backend of the configuration
+pub trait Store: Send + Sync {
+    /// Returns the stored configuration (None if none was stored yet)
+    fn load(&self) -> io::Result<Option<StoredConfig>>;
+
+    /// Replaces the stored configuration
+    fn save(&self, config: &StoredConfig) -> io::Result<()>;
+}
+
+fn period_name(period: QuotaPeriod) -> &'static str {
+    match period {
+        QuotaPeriod::Absolute => "absolute",
+        QuotaPeriod::Monthly => "monthly",
+    }
+}
+
+fn period_from_name(name: &str) -> Option<QuotaPeriod> {
+    match name {
+        "absolute" => Some(QuotaPeriod::Absolute),
+        "monthly" => Some(QuotaPeriod::Monthly),
+        _ => None,
+    }
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid {}", what))
+}
+
+fn parse_key(value: &str) -> io::Result<[u8; 32]> {
+    <[u8; 32]>::from_hex(value).map_err(|_| invalid("key"))
+}
+
+fn parse_allowed_ip(value: &str) -> io::Result<(IpAddr, u32)> {
+    let mut split = value.splitn(2, '/');
+    match (split.next().map(str::parse), split.next().map(str::parse)) {
+        (Some(Ok(ip)), Some(Ok(cidr))) => Ok((ip, cidr)),
+        _ => Err(invalid("allowed_ip")),
+    }
+}
+
+/// Stores the configuration in a text file of key=value lines (the keys of the UAPI),
+/// readable only by the owner since it holds the private key:
+///
+/// ```text
+/// private_key=<hex>
+/// listen_port=<port>
+/// public_key=<hex>
+/// preshared_key=<hex>
+/// endpoint=<address>
+/// allowed_ip=<ip>/<cidr>
+/// persistent_keepalive_interval=<seconds>
+/// quota_bytes=<bytes>
+/// quota_period=absolute|monthly
+/// ```
+pub struct FileStore {
+    path: PathBuf,
+}
+
+impl FileStore {
+    pub fn new<P: AsRef<Path>>(path: P) -> FileStore {
+        FileStore {
+            path: path.as_ref().to_owned(),
+        }
+    }
+
+    fn parse(content: &str) -> io::Result<StoredConfig> {
+        let mut config = StoredConfig::default();
+        for line in content.lines().filter(|line| !line.trim().is_empty()) {
+            let mut split = line.splitn(2, '=');
+            let (key, value) = match (split.next(), split.next()) {
+                (Some(key), Some(value)) => (key, value),
+                _ => return Err(invalid("line")),
+            };
+
+            // lines following a public key configure that peer
+            if key == "public_key" {
+                config.peers.push(StoredPeer {
+                    public_key: PublicKey::from(parse_key(value)?),
+                    preshared_key: [0u8; 32],
+                    endpoint: None,
+                    allowed_ips: vec![],
+                    persistent_keepalive_interval: 0,
+                    quota: None,
+                });
+                continue;
+            }
+            let peer = match config.peers.last_mut() {
+                Some(peer) => peer,
+                None => {
+                    match key {
+                        "private_key" => {
+                            config.private_key = Some(StaticSecret::from(parse_key(value)?))
+                        }
+                        "listen_port" => {
+                            config.listen_port = value.parse().map_err(|_| invalid(key))?
+                        }
+                        _ => return Err(invalid(key)),
+                    }
+                    continue;
+                }
+            };
+            match key {
+                "preshared_key" => peer.preshared_key = parse_key(value)?,
+                "endpoint" => peer.endpoint = Some(value.parse().map_err(|_| invalid(key))?),
+                "allowed_ip" => peer.allowed_ips.push(parse_allowed_ip(value)?),
+                "persistent_keepalive_interval" => {
+                    peer.persistent_keepalive_interval = value.parse().map_err(|_| invalid(key))?
+                }
+                "quota_bytes" => {
+                    let limit = value.parse().map_err(|_| invalid(key))?;
+                    let period = peer
+                        .quota
+                        .map(|q| q.period)
+                        .unwrap_or(QuotaPeriod::Absolute);
+                    peer.quota = Some(Quota { limit, period });
+                }
+                "quota_period" => {
+                    let period = period_from_name(value).ok_or_else(|| invalid(key))?;
+                    let limit = peer.quota.map(|q| q.limit).unwrap_or(0);
+                    peer.quota = Some(Quota { limit, period });
+                }
+                _ => return Err(invalid(key)),
+            }
+        }
+        Ok(config)
+    }
+
+    fn serialize<W: Write>(out: &mut W, config: &StoredConfig) -> io::Result<()> {
+        if let Some(sk) = config.private_key.as_ref() {
+            writeln!(out, "private_key={}", hex::encode(sk.to_bytes()))?;
+        }
+        writeln!(out, "listen_port={}", config.listen_port)?;
+        for peer in config.peers.iter() {
+            writeln!(
+                out,
+                "public_key={}",
+                hex::encode(peer.public_key.as_bytes())
+            )?;
+            writeln!(out, "preshared_key={}", hex::encode(peer.preshared_key))?;
+            if let Some(endpoint) = peer.endpoint {
+                writeln!(out, "endpoint={}", endpoint)?;
+            }
+            for (ip, cidr) in peer.allowed_ips.iter() {
+                writeln!(out, "allowed_ip={}/{}", ip, cidr)?;
+            }
+            writeln!(
+                out,
+                "persistent_keepalive_interval={}",
+                peer.persistent_keepalive_interval
+            )?;
+            if let Some(quota) = peer.quota {
+                writeln!(out, "quota_bytes={}", quota.limit)?;
+                writeln!(out, "quota_period={}", period_name(quota.period))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Store for FileStore {
+    fn load(&self) -> io::Result<Option<StoredConfig>> {
+        match fs::read_to_string(&self.path) {
+            Ok(content) => Self::parse(&content).map(Some),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Writes the file atomically (by replacing the file)
+    fn save(&self, config: &StoredConfig) -> io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        {
+            let mut options = fs::OpenOptions::new();
+            options.write(true).create(true).truncate(true);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.mode(0o600);
+            }
+            let mut file = options.open(&tmp)?;
+            Self::serialize(&mut file, config)?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+/// Stores the configuration in an SQLite database
+#[cfg(feature = "sqlite")]
+pub struct SqliteStore {
+    conn: std::sync::Mutex<rusqlite::Connection>,
+}
+
+#[cfg(feature = "sqlite")]
+fn sql_error(e: rusqlite::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteStore {
+    /// Opens (or creates) the database
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<SqliteStore> {
+        let conn = rusqlite::Connection::open(path).map_err(sql_error)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS interface (
+                id INTEGER PRIMARY KEY CHECK (id = 0),
+                private_key TEXT,
+                listen_port INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS peers (
+                public_key TEXT PRIMARY KEY,
+                preshared_key TEXT NOT NULL,
+                endpoint TEXT,
+                persistent_keepalive_interval INTEGER NOT NULL,
+                quota_bytes INTEGER,
+                quota_period TEXT
+            );
+            CREATE TABLE IF NOT EXISTS allowed_ips (
+                public_key TEXT NOT NULL,
+                ip TEXT NOT NULL,
+                cidr INTEGER NOT NULL
+            );",
+        )
+        .map_err(sql_error)?;
+        Ok(SqliteStore {
+            conn: std::sync::Mutex::new(conn),
+        })
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl Store for SqliteStore {
+    fn load(&self) -> io::Result<Option<StoredConfig>> {
+        use rusqlite::{OptionalExtension, NO_PARAMS};
+
+        let conn = self.conn.lock().unwrap();
+        let interface: Option<(Option<String>, i64)> = conn
+            .query_row(
+                "SELECT private_key, listen_port FROM interface WHERE id = 0",
+                NO_PARAMS,
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .map_err(sql_error)?;
+        let (private_key, listen_port) = match interface {
+            Some(interface) => interface,
+            None => return Ok(None),
+        };
+
+        let mut config = StoredConfig {
+            private_key: match private_key {
+                Some(sk) => Some(StaticSecret::from(parse_key(&sk)?)),
+                None => None,
+            },
+            listen_port: listen_port as u16,
+            peers: vec![],
+        };
+
+        let mut stmt = conn
+            .prepare(
+                "SELECT public_key, preshared_key, endpoint, persistent_keepalive_interval,
+                        quota_bytes, quota_period
+                 FROM peers",
+            )
+            .map_err(sql_error)?;
+        let rows = stmt
+            .query_map(NO_PARAMS, |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                    row.get::<_, i64>(3)?,
+                    row.get::<_, Option<i64>>(4)?,
+                    row.get::<_, Option<String>>(5)?,
+                ))
+            })
+            .map_err(sql_error)?;
+        for row in rows {
+            let (pk, psk, endpoint, keepalive, quota_bytes, quota_period) =
+                row.map_err(sql_error)?;
+            config.peers.push(StoredPeer {
+                public_key: PublicKey::from(parse_key(&pk)?),
+                preshared_key: parse_key(&psk)?,
+                endpoint: match endpoint {
+                    Some(endpoint) => Some(endpoint.parse().map_err(|_| invalid("endpoint"))?),
+                    None => None,
+                },
+                allowed_ips: vec![],
+                persistent_keepalive_interval: keepalive as u64,
+                quota: match (quota_bytes, quota_period) {
+                    (Some(limit), Some(period)) => Some(Quota {
+                        limit: limit as u64,
+                        period: period_from_name(&period).ok_or_else(|| invalid("quota_period"))?,
+                    }),
+                    _ => None,
+                },
+            });
+        }
+
+        let mut stmt = conn
+            .prepare("SELECT public_key, ip, cidr FROM allowed_ips")
+            .map_err(sql_error)?;
+        let rows = stmt
+            .query_map(NO_PARAMS, |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, i64>(2)?,
+                ))
+            })
+            .map_err(sql_error)?;
+        for row in rows {
+            let (pk, ip, cidr) = row.map_err(sql_error)?;
+            let pk = PublicKey::from(parse_key(&pk)?);
+            let ip = ip.parse().map_err(|_| invalid("allowed_ip"))?;
+            if let Some(peer) = config.peers.iter_mut().find(|p| p.public_key == pk) {
+                peer.allowed_ips.push((ip, cidr as u32));
+            }
+        }
+        Ok(Some(config))
+    }
+
+    fn save(&self, config: &StoredConfig) -> io::Result<()> {
+        use rusqlite::params;
+
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(sql_error)?;
+        tx.execute_batch("DELETE FROM interface; DELETE FROM peers; DELETE FROM allowed_ips;")
+            .map_err(sql_error)?;
+        tx.execute(
+            "INSERT INTO interface (id, private_key, listen_port) VALUES (0, ?1, ?2)",
+            params![
+                config
+                    .private_key
+                    .as_ref()
+                    .map(|sk| hex::encode(sk.to_bytes())),
+                config.listen_port as i64
+            ],
+        )
+        .map_err(sql_error)?;
+        for peer in config.peers.iter() {
+            let pk = hex::encode(peer.public_key.as_bytes());
+            tx.execute(
+                "INSERT INTO peers (public_key, preshared_key, endpoint,
+                                    persistent_keepalive_interval, quota_bytes, quota_period)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    pk,
+                    hex::encode(peer.preshared_key),
+                    peer.endpoint.map(|e| e.to_string()),
+                    peer.persistent_keepalive_interval as i64,
+                    peer.quota.map(|q| q.limit as i64),
+                    peer.quota.map(|q| period_name(q.period)),
+                ],
+            )
+            .map_err(sql_error)?;
+            for (ip, cidr) in peer.allowed_ips.iter() {
+                tx.execute(
+                    "INSERT INTO allowed_ips (public_key, ip, cidr) VALUES (?1, ?2, ?3)",
+                    params![pk, ip.to_string(), *cidr as i64],
+                )
+                .map_err(sql_error)?;
+            }
+        }
+        tx.commit().map_err(sql_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_store() {
+        let path = std::env::temp_dir().join(format!("wg-store-test-{}", std::process::id()));
+        let store = FileStore::new(&path);
+        assert!(store.load().unwrap().is_none());
+
+        let config = StoredConfig {
+            private_key: Some(StaticSecret::from([1u8; 32])),
+            listen_port: 51820,
+            peers: vec![
+                StoredPeer {
+                    public_key: PublicKey::from([2u8; 32]),
+                    preshared_key: [3u8; 32],
+                    endpoint: Some("192.0.2.1:51820".parse().unwrap()),
+                    allowed_ips: vec![
+                        ("10.0.0.0".parse().unwrap(), 24),
+                        ("fd00::".parse().unwrap(), 64),
+                    ],
+                    persistent_keepalive_interval: 25,
+                    quota: Some(Quota {
+                        limit: 1 << 30,
+                        period: QuotaPeriod::Monthly,
+                    }),
+                },
+                StoredPeer {
+                    public_key: PublicKey::from([4u8; 32]),
+                    preshared_key: [0u8; 32],
+                    endpoint: None,
+                    allowed_ips: vec![],
+                    persistent_keepalive_interval: 0,
+                    quota: None,
+                },
+            ],
+        };
+        store.save(&config).unwrap();
+
+        let loaded = store.load().unwrap().unwrap();
+        assert_eq!(loaded.private_key.map(|sk| sk.to_bytes()), Some([1u8; 32]));
+        assert_eq!(loaded.listen_port, 51820);
+        assert_eq!(loaded.peers.len(), 2);
+        let peer = &loaded.peers[0];
+        assert_eq!(peer.public_key.as_bytes(), &[2u8; 32]);
+        assert_eq!(peer.preshared_key, [3u8; 32]);
+        assert_eq!(peer.endpoint, config.peers[0].endpoint);
+        assert_eq!(peer.allowed_ips, config.peers[0].allowed_ips);
+        assert_eq!(peer.persistent_keepalive_interval, 25);
+        assert_eq!(peer.quota, config.peers[0].quota);
+        assert!(loaded.peers[1].endpoint.is_none());
+        assert!(loaded.peers[1].quota.is_none());
+
+        fs::write(&path, "listen_port=51820\nendpoint=192.0.2.1:1\n").unwrap();
+        assert!(store.load().is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
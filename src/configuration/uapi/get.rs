@@ -1,7 +1,12 @@
 use log;
 use std::io;
+use std::net::SocketAddr;
 
-use super::Configuration;
+use super::{
+    BindMode, Configuration, EndpointPolicy, ExitPolicy, FragmentPolicy, HandshakeRole,
+    KeepaliveSuppression, MessageKind, Padding, PortMismatch, Protocol, QuotaPeriod, RelayPolicy,
+    Subsystem, DEFAULT_BIND_ATTEMPTS, DEFAULT_MAX_STAGED_AGE,
+};
 
 pub fn serialize<C: Configuration, W: io::Write>(writer: &mut W, config: &C) -> io::Result<()> {
     let mut write = |key: &'static str, value: String| {
@@ -19,14 +24,228 @@ pub fn serialize<C: Configuration, W: io::Write>(writer: &mut W, config: &C) ->
         .get_private_key()
         .map(|sk| write("private_key", hex::encode(sk.to_bytes())));
 
+    for (sk, peers) in config.get_secondary_private_keys() {
+        write("secondary_private_key", hex::encode(sk.to_bytes()))?;
+        for pk in peers.unwrap_or_default() {
+            write("secondary_private_key_peer", hex::encode(pk.as_bytes()))?;
+        }
+    }
+
     config
         .get_listen_port()
         .map(|port| write("listen_port", port.to_string()));
 
+    // IPv4 bound on another port than IPv6 (see PortMismatch::Accept)
+    if let Some(ports) = config.get_listen_ports().filter(|ports| ports.mismatch()) {
+        if let Some(port) = ports.v4 {
+            write("listen_port_ipv4", port.to_string())?;
+        }
+    }
+
     config
         .get_fwmark()
         .map(|fwmark| write("fwmark", fwmark.to_string()));
 
+    if let Some(tos) = config.get_tos() {
+        write("tos", tos.to_string())?;
+    }
+
+    if let Some(hops) = config.get_hop_limit() {
+        write("hop_limit", hops.to_string())?;
+    }
+
+    match config.get_fragment_policy() {
+        FragmentPolicy::System => (),
+        FragmentPolicy::Drop => write("fragment", "drop".to_string())?,
+        FragmentPolicy::Fragment => write("fragment", "kernel".to_string())?,
+        FragmentPolicy::Prefragment(size) => write("fragment", size.to_string())?,
+    }
+
+    if let Some(name) = config.get_bind_device() {
+        write("bind_device", name)?;
+    }
+
+    match config.get_bind_mode() {
+        BindMode::Dual => (),
+        BindMode::V4Only => write("bind_mode", "v4".to_string())?,
+        BindMode::V6Only => write("bind_mode", "v6".to_string())?,
+    }
+
+    match config.get_protocol() {
+        Protocol::Udp => (),
+        Protocol::UdpLite => write("protocol", "udplite".to_string())?,
+    }
+
+    let attempts = config.get_bind_attempts();
+    if attempts != DEFAULT_BIND_ATTEMPTS {
+        write("bind_attempts", attempts.to_string())?;
+    }
+
+    let fanout = config.get_fanout();
+    if fanout != 1 {
+        write("fanout", fanout.to_string())?;
+    }
+
+    match config.get_port_mismatch() {
+        PortMismatch::Retry => (),
+        PortMismatch::Fail => write("port_mismatch", "fail".to_string())?,
+        PortMismatch::Accept => write("port_mismatch", "accept".to_string())?,
+    }
+
+    match config.get_padding() {
+        Padding::Multiple => (),
+        Padding::Mtu => write("padding", "mtu".to_string())?,
+        Padding::Disabled => write("padding", "none".to_string())?,
+    }
+
+    for addr in config.get_echo_addresses() {
+        write("echo_address", addr.to_string())?;
+    }
+
+    let blackhole = config.get_blackhole();
+    if blackhole.attempts > 0 {
+        write("blackhole_attempts", blackhole.attempts.to_string())?;
+    }
+    if blackhole.cooldown.as_secs() > 0 {
+        write(
+            "blackhole_cooldown",
+            blackhole.cooldown.as_secs().to_string(),
+        )?;
+    }
+
+    let spread = config.get_handshake_spread();
+    if spread.as_secs() > 0 {
+        write("handshake_spread", spread.as_secs().to_string())?;
+    }
+
+    if let Some(timeout) = config.get_shutdown_flush() {
+        write("shutdown_flush", timeout.as_millis().to_string())?;
+    }
+
+    if let Some(interval) = config.get_usage_interval() {
+        write("usage_interval", interval.as_secs().to_string())?;
+    }
+
+    if let Some(ps) = config.get_power_save() {
+        write(
+            "power_save",
+            format!("{}/{}", ps.keepalive_multiplier, ps.coalesce.as_secs()),
+        )?;
+    }
+
+    if let Some(timeout) = config.get_flow_timeout() {
+        write("flow_timeout", timeout.as_secs().to_string())?;
+    }
+
+    let age = config.get_max_staged_age();
+    if age != Some(DEFAULT_MAX_STAGED_AGE) {
+        let ms = age.map_or(0, |age| age.as_millis());
+        write("max_staged_age_ms", ms.to_string())?;
+    }
+
+    if let Some((addr, len)) = config.get_auto_prefix() {
+        write("auto_prefix", format!("{}/{}", addr, len))?;
+    }
+
+    for node in config.get_mesh_nodes() {
+        let (addr, cidr) = node.address;
+        let mut value = format!(
+            "{},{}/{}",
+            hex::encode(node.public_key.as_bytes()),
+            addr,
+            cidr
+        );
+        if let Some(endpoint) = node.endpoint {
+            value.push_str(&format!(",{}", endpoint));
+        }
+        write("mesh_node", value)?;
+    }
+
+    if config.get_relay() {
+        write("relay", "true".to_string())?;
+    }
+
+    if config.get_stealth() {
+        write("stealth", "true".to_string())?;
+    }
+
+    let dropped = config.get_stealth_dropped();
+    if dropped > 0 {
+        write("stealth_dropped", dropped.to_string())?;
+    }
+
+    // rx_messages=<family>,<kind>,<packets>,<bytes> (for every kind received)
+    let received = config.get_received();
+    for (family, kinds) in [("v4", received.v4), ("v6", received.v6)].iter() {
+        for kind in MessageKind::ALL.iter() {
+            let count = kinds.get(*kind);
+            if count.packets > 0 {
+                write(
+                    "rx_messages",
+                    format!(
+                        "{},{},{},{}",
+                        family,
+                        kind.name(),
+                        count.packets,
+                        count.bytes
+                    ),
+                )?;
+            }
+        }
+    }
+
+    // interface_stats=<rx_bytes>,<rx_packets>,<rx_errs>,<rx_drop>,<rx_frame>,
+    //                 <tx_bytes>,<tx_packets>,<tx_errs>,<tx_drop> (the columns of /proc/net/dev)
+    let stats = config.get_interface_stats();
+    if stats != Default::default() {
+        write(
+            "interface_stats",
+            format!(
+                "{},{},{},{},{},{},{},{},{}",
+                stats.rx_bytes,
+                stats.rx_packets,
+                stats.rx_errors,
+                stats.rx_dropped,
+                stats.rx_frame(),
+                stats.tx_bytes,
+                stats.tx_packets,
+                stats.tx_errors,
+                stats.tx_dropped
+            ),
+        )?;
+    }
+
+    match config.get_exit_policy() {
+        None => (),
+        Some(ExitPolicy::Rtt) => write("exit_policy", "rtt".to_string())?,
+        Some(ExitPolicy::Weight) => write("exit_policy", "weight".to_string())?,
+    }
+
+    #[cfg(feature = "ipfix")]
+    {
+        if let Some(collector) = config.get_ipfix_collector() {
+            write("ipfix_collector", collector.to_string())?;
+        }
+    }
+
+    if let Some(path) = config.get_quota_file() {
+        write("quota_file", path.to_string_lossy().into_owned())?;
+    }
+
+    for subsystem in Subsystem::all() {
+        if let Some(level) = config.get_log_level(*subsystem) {
+            let level = level.to_string().to_lowercase();
+            write("log_level", format!("{}:{}", subsystem, level))?;
+        }
+    }
+
+    config
+        .get_memory_limit()
+        .map(|limit| write("memory_limit", limit.to_string()));
+
+    write("memory_usage", config.get_memory_usage().to_string())?;
+    write("receiver_ids", config.get_receiver_ids().to_string())?;
+
     // serialize all peers
     let mut peers = config.get_peers();
     while let Some(p) = peers.pop() {
@@ -39,19 +258,205 @@ pub fn serialize<C: Configuration, W: io::Write>(writer: &mut W, config: &C) ->
             p.persistent_keepalive_interval.to_string(),
         )?;
 
+        match p.keepalive_suppression {
+            KeepaliveSuppression::Any => (),
+            KeepaliveSuppression::Sent => write("keepalive_suppression", "sent".to_string())?,
+            KeepaliveSuppression::Never => write("keepalive_suppression", "never".to_string())?,
+        }
+
+        if p.cover_traffic_interval > 0 {
+            write(
+                "cover_traffic_interval",
+                p.cover_traffic_interval.to_string(),
+            )?;
+        }
+
         if let Some((secs, nsecs)) = p.last_handshake_time {
             write("last_handshake_time_sec", secs.to_string())?;
             write("last_handshake_time_nsec", nsecs.to_string())?;
         }
 
+        if let Some(session) = p.session {
+            write("session_local_index", session.local_id.to_string())?;
+            write("session_remote_index", session.remote_id.to_string())?;
+            write("session_age_sec", session.age.as_secs().to_string())?;
+            write("session_tx_messages", session.sent.to_string())?;
+            write("session_rx_messages", session.received.to_string())?;
+
+            // replay protection of the session (only if any message was dropped or reordered)
+            let replay = session.replay;
+            if replay.too_old > 0 {
+                write("session_replay_too_old", replay.too_old.to_string())?;
+            }
+            if replay.duplicate > 0 {
+                write("session_replay_duplicate", replay.duplicate.to_string())?;
+            }
+            if replay.max_reorder > 0 {
+                write("session_max_reorder", replay.max_reorder.to_string())?;
+            }
+        }
+
+        // failed sends by class (only if any)
+        let errors = p.send_errors;
+        for (key, count) in [
+            ("tx_errors_unreachable", errors.unreachable),
+            ("tx_errors_permission", errors.permission),
+            ("tx_errors_nobufs", errors.nobufs),
+            ("tx_errors_too_big", errors.too_big),
+            ("tx_errors_other", errors.other),
+        ]
+        .iter()
+        {
+            if *count > 0 {
+                write(*key, count.to_string())?;
+            }
+        }
+
         if let Some(endpoint) = p.endpoint {
             write("endpoint", endpoint.to_string())?;
         }
 
+        if p.endpoint_changes > 0 {
+            write("endpoint_changes", p.endpoint_changes.to_string())?;
+        }
+
+        for addr in p.endpoint_candidates {
+            write("endpoint_candidate", addr.to_string())?;
+        }
+
+        if p.staged_packets > 0 {
+            write("staged_packets", p.staged_packets.to_string())?;
+        }
+
+        if p.handshakes_suspended {
+            write("handshakes_suspended", "true".to_string())?;
+        }
+
+        if let Some(quota) = p.quota {
+            write("quota_bytes", quota.limit.to_string())?;
+            if quota.period == QuotaPeriod::Monthly {
+                write("quota_period", "monthly".to_string())?;
+            }
+            write("quota_usage", p.quota_usage.to_string())?;
+            if p.quota_exceeded {
+                write("quota_exceeded", "true".to_string())?;
+            }
+        }
+
+        if p.exit_weight > 0 {
+            write("exit_weight", p.exit_weight.to_string())?;
+            if p.exit_active {
+                write("exit_active", "true".to_string())?;
+            }
+        }
+
+        if let Some(rtt) = p.handshake_rtt {
+            write("handshake_rtt_ms", rtt.as_millis().to_string())?;
+        }
+
+        if p.cookie_retries > 0 {
+            write("cookie_retries", p.cookie_retries.to_string())?;
+        }
+
+        match p.endpoint_policy {
+            EndpointPolicy::Learn => (),
+            EndpointPolicy::Fixed => write("endpoint_policy", "fixed".to_string())?,
+            EndpointPolicy::LearnWithin(ip, cidr) => {
+                write("endpoint_policy", format!("{}/{}", ip, cidr))?
+            }
+        }
+
+        if let Some(port) = p.endpoint_port {
+            write("endpoint_port", port.to_string())?;
+        }
+
+        match p.handshake_role {
+            HandshakeRole::Both => (),
+            HandshakeRole::Responder => write("handshake_role", "responder".to_string())?,
+            HandshakeRole::Initiator => write("handshake_role", "initiator".to_string())?,
+        }
+
+        match p.relay_policy {
+            RelayPolicy::Default => (),
+            RelayPolicy::Forward => write("relay", "forward".to_string())?,
+            RelayPolicy::Deliver => write("relay", "deliver".to_string())?,
+        }
+
+        // packets relayed to other peers (only if any)
+        if p.relay.packets > 0 || p.relay.dropped > 0 {
+            write("relay_packets", p.relay.packets.to_string())?;
+            write("relay_bytes", p.relay.bytes.to_string())?;
+            write("relay_dropped", p.relay.dropped.to_string())?;
+        }
+
+        if p.source.ifindex != 0 {
+            write("source_interface", p.source.ifindex.to_string())?;
+        }
+
+        if let Some(addr) = p.source.addr {
+            write("source_address", addr.to_string())?;
+        }
+
+        if let Some(hops) = p.hop_limit {
+            write("hop_limit", hops.to_string())?;
+        }
+
+        if let Some(mtu) = p.mtu {
+            write("mtu", mtu.to_string())?;
+        }
+
+        if let Some(encap) = p.encap {
+            write("encapsulation", format!("{}@{}", encap.tag, encap.relay))?;
+            write("encapsulation_relayed", p.relayed.to_string())?;
+        }
+
         for (ip, cidr) in p.allowed_ips {
             write("allowed_ip", ip.to_string() + "/" + &cidr.to_string())?;
         }
+
+        for ((src, src_cidr), (dst, dst_cidr)) in p.policy_routes {
+            write(
+                "policy_route",
+                format!("{}/{},{}/{}", src, src_cidr, dst, dst_cidr),
+            )?;
+        }
+
+        for (ip, cidr) in p.ecmp_ips {
+            write("ecmp_ip", ip.to_string() + "/" + &cidr.to_string())?;
+        }
+
+        // protocol,local,remote,rx_bytes,tx_bytes,rx_packets,tx_packets,idle seconds
+        for (flow, stats) in p.flows {
+            write(
+                "flow",
+                format!(
+                    "{},{},{},{},{},{},{},{}",
+                    flow.protocol,
+                    SocketAddr::new(flow.local, flow.local_port),
+                    SocketAddr::new(flow.remote, flow.remote_port),
+                    stats.rx_bytes,
+                    stats.tx_bytes,
+                    stats.rx_packets,
+                    stats.tx_packets,
+                    stats.last_seen.elapsed().as_secs()
+                ),
+            )?;
+        }
     }
 
     Ok(())
 }
+
+/// Serializes the counters of the interface as the fields of struct rtnl_link_stats64,
+/// i.e. the statistics rtnetlink (ip -s link) reports for a kernel interface,
+/// hence collectors of link statistics can query the device in place of the TUN interface.
+pub fn serialize_stats<C: Configuration, W: io::Write>(
+    writer: &mut W,
+    config: &C,
+) -> io::Result<()> {
+    for (key, value) in config.get_interface_stats().link_stats64().iter() {
+        log::trace!("UAPI: return : {}={}", key, value);
+        writer.write_all(format!("{}={}\n", key, value).as_ref())?;
+    }
+    Ok(())
+}
@@ -4,9 +4,13 @@ mod set;
 use log;
 use std::io::{Read, Write};
 
-use super::{ConfigError, Configuration};
+use super::{
+    BindMode, ConfigError, Configuration, Encapsulation, EndpointPolicy, ExitPolicy,
+    FragmentPolicy, HandshakeRole, Node, Padding, PortMismatch, Protocol, Quota, QuotaPeriod,
+    RelayPolicy, Subsystem, DEFAULT_BIND_ATTEMPTS, DEFAULT_MAX_STAGED_AGE,
+};
 
-use get::serialize;
+use get::{serialize, serialize_stats};
 use set::LineParser;
 
 const MAX_LINE_LENGTH: usize = 256;
@@ -49,6 +53,10 @@ pub fn handle<S: Read + Write, C: Configuration>(stream: &mut S, config: &C) {
                 log::debug!("UAPI, Get operation");
                 serialize(stream, config).map_err(|_| ConfigError::IOError)
             }
+            "stats=1" => {
+                log::debug!("UAPI, Stats operation");
+                serialize_stats(stream, config).map_err(|_| ConfigError::IOError)
+            }
             "set=1" => {
                 log::debug!("UAPI, Set operation");
                 let mut parser = LineParser::new(config);
@@ -60,7 +68,11 @@ pub fn handle<S: Read + Write, C: Configuration>(stream: &mut S, config: &C) {
                     let (k, v) = keypair(ln.as_str())?;
                     parser.parse_line(k, v)?;
                 }
-                parser.parse_line("", "")
+                parser.parse_line("", "")?;
+
+                // a failure to persist the configuration (logged) does not fail the operation
+                let _ = config.persist();
+                Ok(())
             }
             _ => Err(ConfigError::InvalidOperation),
         }
@@ -81,3 +93,119 @@ pub fn handle<S: Read + Write, C: Configuration>(stream: &mut S, config: &C) {
     );
     let _ = stream.write("\n\n".as_ref());
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::io::{self, Cursor};
+    use std::sync::Arc;
+
+    use hex::FromHex;
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    use super::super::{FileStore, Store, WireGuard, WireGuardConfig};
+
+    // a connection to the UAPI: the request is read from and the response written to memory
+    struct Connection {
+        request: Cursor<Vec<u8>>,
+        response: Vec<u8>,
+    }
+
+    impl Read for Connection {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.request.read(buf)
+        }
+    }
+
+    impl Write for Connection {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.response.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_set_persists() {
+        let (wg, _fake, _remote) = WireGuard::new_dummy();
+        let config = WireGuardConfig::new(wg);
+
+        // the (empty) configuration is stored when the store is set
+        let path = std::env::temp_dir().join(format!("wg-uapi-test-{}", std::process::id()));
+        let store = Arc::new(FileStore::new(&path));
+        config.set_store(Some(store.clone())).unwrap();
+        assert!(store.load().unwrap().unwrap().peers.is_empty());
+
+        // every set operation is stored
+        let pk = hex::encode([2u8; 32]);
+        let request = format!("set=1\npublic_key={}\nallowed_ip=10.0.0.0/24\n\n", pk);
+        let mut conn = Connection {
+            request: Cursor::new(request.into_bytes()),
+            response: vec![],
+        };
+        handle(&mut conn, &config);
+        assert_eq!(&conn.response[..], &b"errno=0\n\n"[..]);
+
+        let stored = store.load().unwrap().unwrap();
+        assert_eq!(stored.peers.len(), 1);
+        assert_eq!(stored.peers[0].public_key.as_bytes(), &[2u8; 32]);
+        assert_eq!(
+            stored.peers[0].allowed_ips,
+            vec![("10.0.0.0".parse().unwrap(), 24)]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    fn request<C: Configuration>(config: &C, request: &str) -> String {
+        let mut conn = Connection {
+            request: Cursor::new(request.as_bytes().to_vec()),
+            response: vec![],
+        };
+        handle(&mut conn, config);
+        String::from_utf8(conn.response).unwrap()
+    }
+
+    #[test]
+    fn test_mesh() {
+        let (wg, _fake, _remote) = WireGuard::new_dummy();
+        let config = WireGuardConfig::new(wg);
+        let local = hex::encode([1u8; 32]);
+        let node = hex::encode(PublicKey::from(&StaticSecret::from([2u8; 32])).as_bytes());
+        let set = format!(
+            "set=1\nprivate_key={}\nmesh_node={},10.0.0.1/32\nmesh_node={},10.0.0.2/32,192.0.2.2:51820\n\n",
+            local,
+            hex::encode(PublicKey::from(&StaticSecret::from([1u8; 32])).as_bytes()),
+            node
+        );
+        assert_eq!(request(&config, &set), "errno=0\n\n");
+
+        // the other node is a peer (the local node is not)
+        let peers = config.get_peers();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(
+            peers[0].public_key.as_bytes(),
+            &<[u8; 32]>::from_hex(&node).unwrap()
+        );
+        assert_eq!(
+            peers[0].allowed_ips,
+            vec![("10.0.0.2".parse().unwrap(), 32)]
+        );
+        assert_eq!(peers[0].endpoint, Some("192.0.2.2:51820".parse().unwrap()));
+
+        let get = request(&config, "get=1\n\n");
+        assert!(get.contains(&format!("mesh_node={},10.0.0.2/32,192.0.2.2:51820\n", node)));
+
+        // the peer of a node leaving the mesh is removed
+        let set = format!("set=1\nmesh_remove={}\n\n", node);
+        assert_eq!(request(&config, &set), "errno=0\n\n");
+        assert!(config.get_peers().is_empty());
+        assert_eq!(config.get_mesh_nodes().len(), 1);
+
+        let set = "set=1\nmesh_node=00,10.0.0.3/32\n\n";
+        assert_ne!(request(&config, set), "errno=0\n\n");
+    }
+}
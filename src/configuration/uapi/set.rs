@@ -1,9 +1,46 @@
 use hex::FromHex;
-use std::net::{IpAddr, SocketAddr};
+#[cfg(unix)]
+use std::ffi::CString;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
 use subtle::ConstantTimeEq;
 use x25519_dalek::{PublicKey, StaticSecret};
 
-use super::{ConfigError, Configuration};
+use super::{
+    BindMode, ConfigError, Configuration, Encapsulation, EndpointPolicy, ExitPolicy,
+    FragmentPolicy, HandshakeRole, KeepaliveSuppression, Node, Padding, PortMismatch, PowerSave,
+    Protocol, Quota, QuotaPeriod, RelayPolicy, Subsystem,
+};
+
+// smallest datagram size accepted for fragmentation before encryption
+// (the datagram size every IPv4 host must be able to receive, RFC 791)
+const MIN_PREFRAGMENT_SIZE: u16 = 576;
+
+// smallest inner MTU accepted toward a peer (IPv6 packets require 1280)
+const MIN_PEER_MTU: usize = 576;
+
+// largest number of sockets per address family sharing the listen port (one reader thread each)
+const MAX_FANOUT: usize = 64;
+
+// resolves an interface given by index or name
+fn interface_index(value: &str) -> Option<u32> {
+    value.parse().ok().or_else(|| interface_by_name(value))
+}
+
+#[cfg(unix)]
+fn interface_by_name(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => None,
+        ifindex => Some(ifindex),
+    }
+}
+
+#[cfg(not(unix))]
+fn interface_by_name(_name: &str) -> Option<u32> {
+    None
+}
 
 enum ParserState {
     Peer(ParsedPeer),
@@ -14,17 +51,38 @@ struct ParsedPeer {
     public_key: PublicKey,
     update_only: bool,
     allowed_ips: Vec<(IpAddr, u32)>,
+    policy_routes: Vec<((IpAddr, u32), (IpAddr, u32))>,
+    replace_policy_routes: bool,
+    ecmp_ips: Vec<(IpAddr, u32)>,
+    replace_ecmp_ips: bool,
     remove: bool,
+    rekey: bool,
     preshared_key: Option<[u8; 32]>,
     replace_allowed_ips: bool,
     persistent_keepalive_interval: Option<u64>,
+    cover_traffic_interval: Option<u64>,
+    keepalive_suppression: Option<KeepaliveSuppression>,
     protocol_version: Option<usize>,
     endpoint: Option<SocketAddr>,
+    endpoint_candidates: Option<Vec<SocketAddr>>,
+    source_interface: Option<u32>,
+    source_address: Option<Option<IpAddr>>,
+    hop_limit: Option<Option<u8>>,
+    mtu: Option<Option<usize>>,
+    encapsulation: Option<Option<Encapsulation>>,
+    quota_bytes: Option<u64>,
+    quota_period: Option<QuotaPeriod>,
+    relay: Option<RelayPolicy>,
+    endpoint_policy: Option<EndpointPolicy>,
+    endpoint_port: Option<Option<u16>>,
+    handshake_role: Option<HandshakeRole>,
+    exit_weight: Option<u32>,
 }
 
 pub struct LineParser<'a, C: Configuration> {
     config: &'a C,
     state: ParserState,
+    secondary: Option<([u8; 32], Vec<PublicKey>)>, // last secondary key added (and its permitted peers)
 }
 
 impl<'a, C: Configuration> LineParser<'a, C> {
@@ -32,6 +90,7 @@ impl<'a, C: Configuration> LineParser<'a, C> {
         LineParser {
             config,
             state: ParserState::Interface,
+            secondary: None,
         }
     }
 
@@ -40,13 +99,33 @@ impl<'a, C: Configuration> LineParser<'a, C> {
             Ok(pk) => Ok(ParserState::Peer(ParsedPeer {
                 public_key: PublicKey::from(pk),
                 remove: false,
+                rekey: false,
                 update_only: false,
                 allowed_ips: vec![],
+                policy_routes: vec![],
+                replace_policy_routes: false,
+                ecmp_ips: vec![],
+                replace_ecmp_ips: false,
                 preshared_key: None,
                 replace_allowed_ips: false,
                 persistent_keepalive_interval: None,
+                cover_traffic_interval: None,
+                keepalive_suppression: None,
                 protocol_version: None,
                 endpoint: None,
+                endpoint_candidates: None,
+                source_interface: None,
+                source_address: None,
+                hop_limit: None,
+                mtu: None,
+                encapsulation: None,
+                quota_bytes: None,
+                quota_period: None,
+                relay: None,
+                endpoint_policy: None,
+                endpoint_port: None,
+                handshake_role: None,
+                exit_weight: None,
             })),
             Err(_) => Err(ConfigError::InvalidHexValue),
         }
@@ -70,7 +149,9 @@ impl<'a, C: Configuration> LineParser<'a, C> {
 
             if !peer.update_only {
                 log::trace!("flush peer, add peer");
-                config.add_peer(&peer.public_key);
+                if let Err(e) = config.add_peer(&peer.public_key) {
+                    return Some(e);
+                }
             }
 
             for (ip, cidr) in &peer.allowed_ips {
@@ -78,8 +159,34 @@ impl<'a, C: Configuration> LineParser<'a, C> {
                 config.add_allowed_ip(&peer.public_key, *ip, *cidr);
             }
 
+            if peer.replace_policy_routes {
+                log::trace!("flush peer, replace policy routes");
+                config.replace_policy_routes(&peer.public_key);
+            }
+
+            for (source, destination) in &peer.policy_routes {
+                log::trace!(
+                    "flush peer, add policy route {:?} -> {:?}",
+                    source,
+                    destination
+                );
+                if let Err(e) = config.add_policy_route(&peer.public_key, *source, *destination) {
+                    return Some(e);
+                }
+            }
+
+            if peer.replace_ecmp_ips {
+                log::trace!("flush peer, replace ECMP IPs");
+                config.replace_ecmp_ips(&peer.public_key);
+            }
+
+            for (ip, cidr) in &peer.ecmp_ips {
+                log::trace!("flush peer, add ECMP IP {}/{}", ip.to_string(), cidr);
+                config.add_ecmp_ip(&peer.public_key, *ip, *cidr);
+            }
+
             if let Some(psk) = peer.preshared_key {
-                log::trace!("flush peer, set preshared_key {}", hex::encode(psk));
+                log::trace!("flush peer, set preshared_key");
                 config.set_preshared_key(&peer.public_key, psk);
             }
 
@@ -88,6 +195,16 @@ impl<'a, C: Configuration> LineParser<'a, C> {
                 config.set_persistent_keepalive_interval(&peer.public_key, secs);
             }
 
+            if let Some(millis) = peer.cover_traffic_interval {
+                log::trace!("flush peer, set cover_traffic_interval {}", millis);
+                config.set_cover_traffic_interval(&peer.public_key, millis);
+            }
+
+            if let Some(suppression) = peer.keepalive_suppression {
+                log::trace!("flush peer, set keepalive_suppression {:?}", suppression);
+                config.set_keepalive_suppression(&peer.public_key, suppression);
+            }
+
             if let Some(version) = peer.protocol_version {
                 log::trace!("flush peer, set protocol_version {}", version);
                 if version == 0 || version > config.get_protocol_version() {
@@ -100,6 +217,84 @@ impl<'a, C: Configuration> LineParser<'a, C> {
                 config.set_endpoint(&peer.public_key, endpoint);
             };
 
+            if let Some(addrs) = &peer.endpoint_candidates {
+                log::trace!("flush peer, set endpoint_candidates {:?}", addrs);
+                config.set_endpoint_candidates(&peer.public_key, addrs.clone());
+            }
+
+            if let Some(ifindex) = peer.source_interface {
+                log::trace!("flush peer, set source_interface {}", ifindex);
+                config.set_source_interface(&peer.public_key, ifindex);
+            }
+
+            if let Some(addr) = peer.source_address {
+                log::trace!("flush peer, set source_address {:?}", addr);
+                config.set_source_address(&peer.public_key, addr);
+            }
+
+            if let Some(hops) = peer.hop_limit {
+                log::trace!("flush peer, set hop_limit {:?}", hops);
+                config.set_peer_hop_limit(&peer.public_key, hops);
+            }
+
+            if let Some(mtu) = peer.mtu {
+                log::trace!("flush peer, set mtu {:?}", mtu);
+                config.set_peer_mtu(&peer.public_key, mtu);
+            }
+
+            if let Some(encap) = peer.encapsulation {
+                log::trace!("flush peer, set encapsulation {:?}", encap);
+                config.set_peer_encapsulation(&peer.public_key, encap);
+            }
+
+            if peer.quota_bytes.is_some() || peer.quota_period.is_some() {
+                let current = config.get_quota(&peer.public_key);
+                let limit = peer.quota_bytes.or(current.map(|q| q.limit)).unwrap_or(0);
+                let period = peer
+                    .quota_period
+                    .or(current.map(|q| q.period))
+                    .unwrap_or(QuotaPeriod::Absolute);
+                log::trace!("flush peer, set quota {} ({:?})", limit, period);
+                config.set_quota(
+                    &peer.public_key,
+                    if limit == 0 {
+                        None
+                    } else {
+                        Some(Quota { limit, period })
+                    },
+                );
+            }
+
+            if let Some(weight) = peer.exit_weight {
+                log::trace!("flush peer, set exit_weight {}", weight);
+                config.set_exit_weight(&peer.public_key, weight);
+            }
+
+            if let Some(policy) = peer.endpoint_policy {
+                log::trace!("flush peer, set endpoint_policy {:?}", policy);
+                config.set_endpoint_policy(&peer.public_key, policy);
+            }
+
+            if let Some(port) = peer.endpoint_port {
+                log::trace!("flush peer, set endpoint_port {:?}", port);
+                config.set_endpoint_port(&peer.public_key, port);
+            }
+
+            if let Some(role) = peer.handshake_role {
+                log::trace!("flush peer, set handshake_role {:?}", role);
+                config.set_handshake_role(&peer.public_key, role);
+            }
+
+            if let Some(policy) = peer.relay {
+                log::trace!("flush peer, set relay {:?}", policy);
+                config.set_relay_policy(&peer.public_key, policy);
+            }
+
+            if peer.rekey {
+                log::trace!("flush peer, rekey");
+                config.rekey_peer(&peer.public_key);
+            }
+
             None
         };
 
@@ -120,6 +315,38 @@ impl<'a, C: Configuration> LineParser<'a, C> {
                     Err(_) => Err(ConfigError::InvalidHexValue),
                 },
 
+                // opt: add secondary private key (or remove all if zero)
+                "secondary_private_key" => match <[u8; 32]>::from_hex(value) {
+                    Ok(sk) => {
+                        if sk.ct_eq(&[0u8; 32]).into() {
+                            self.config.clear_secondary_private_keys();
+                            self.secondary = None;
+                        } else {
+                            self.config
+                                .add_secondary_private_key(StaticSecret::from(sk), None);
+                            self.secondary = Some((sk, vec![]));
+                        }
+                        Ok(())
+                    }
+                    Err(_) => Err(ConfigError::InvalidHexValue),
+                },
+
+                // opt: restrict the preceding secondary private key to the peer (repeatable)
+                "secondary_private_key_peer" => match <[u8; 32]>::from_hex(value) {
+                    Ok(pk) => match self.secondary.as_mut() {
+                        Some((sk, peers)) => {
+                            peers.push(PublicKey::from(pk));
+                            self.config.add_secondary_private_key(
+                                StaticSecret::from(*sk),
+                                Some(peers.clone()),
+                            );
+                            Ok(())
+                        }
+                        None => Err(ConfigError::InvalidOperation),
+                    },
+                    Err(_) => Err(ConfigError::InvalidHexValue),
+                },
+
                 // opt: set listen port
                 "listen_port" => match value.parse() {
                     Ok(port) => {
@@ -129,6 +356,57 @@ impl<'a, C: Configuration> LineParser<'a, C> {
                     Err(_) => Err(ConfigError::InvalidPortNumber),
                 },
 
+                // opt: set address families to bind
+                "bind_mode" => {
+                    let mode = match value {
+                        "dual" => BindMode::Dual,
+                        "v4" => BindMode::V4Only,
+                        "v6" => BindMode::V6Only,
+                        _ => return Err(ConfigError::UnsupportedValue),
+                    };
+                    self.config.set_bind_mode(mode)
+                }
+
+                // opt: set transport protocol of the sockets, udp|udplite
+                "protocol" => {
+                    let protocol = match value {
+                        "udp" => Protocol::Udp,
+                        "udplite" => Protocol::UdpLite,
+                        _ => return Err(ConfigError::UnsupportedValue),
+                    };
+                    self.config.set_protocol(protocol)
+                }
+
+                // opt: set number of attempts at binding both families on the same port
+                "bind_attempts" => match value.parse() {
+                    Ok(attempts) if attempts > 0 => {
+                        self.config.set_bind_attempts(attempts);
+                        Ok(())
+                    }
+                    _ => Err(ConfigError::UnsupportedValue),
+                },
+
+                // opt: set number of sockets per address family sharing the listen port
+                "fanout" => match value.parse() {
+                    Ok(sockets) if sockets > 0 && sockets <= MAX_FANOUT => {
+                        self.config.set_fanout(sockets)
+                    }
+                    _ => Err(ConfigError::UnsupportedValue),
+                },
+
+                // opt: set the behavior when the port chosen on IPv6 is taken on IPv4,
+                // retry|fail|accept
+                "port_mismatch" => {
+                    let mismatch = match value {
+                        "retry" => PortMismatch::Retry,
+                        "fail" => PortMismatch::Fail,
+                        "accept" => PortMismatch::Accept,
+                        _ => return Err(ConfigError::UnsupportedValue),
+                    };
+                    self.config.set_port_mismatch(mismatch);
+                    Ok(())
+                }
+
                 // opt: set fwmark
                 "fwmark" => match value.parse() {
                     Ok(fwmark) => {
@@ -139,6 +417,331 @@ impl<'a, C: Configuration> LineParser<'a, C> {
                     Err(_) => Err(ConfigError::InvalidFwmark),
                 },
 
+                // opt: set type of service / traffic class
+                "tos" => match value.parse() {
+                    Ok(tos) => self.config.set_tos(if tos == 0 { None } else { Some(tos) }),
+                    Err(_) => Err(ConfigError::UnsupportedValue),
+                },
+
+                // opt: set TTL / hop limit of outbound messages (0 = the platform default)
+                "hop_limit" => match value.parse() {
+                    Ok(hops) => {
+                        self.config
+                            .set_hop_limit(if hops == 0 { None } else { Some(hops) })
+                    }
+                    Err(_) => Err(ConfigError::UnsupportedValue),
+                },
+
+                // opt: handling of messages exceeding the path MTU
+                // (a number fragments inner IPv4 packets to fit datagrams of the size)
+                "fragment" => match value {
+                    "system" => self.config.set_fragment_policy(FragmentPolicy::System),
+                    "drop" => self.config.set_fragment_policy(FragmentPolicy::Drop),
+                    "kernel" => self.config.set_fragment_policy(FragmentPolicy::Fragment),
+                    _ => match value.parse() {
+                        Ok(size) if size >= MIN_PREFRAGMENT_SIZE => self
+                            .config
+                            .set_fragment_policy(FragmentPolicy::Prefragment(size)),
+                        _ => Err(ConfigError::UnsupportedValue),
+                    },
+                },
+
+                // opt: bind sockets to interface (or remove binding if empty)
+                "bind_device" => {
+                    if value.len() >= 16 || !value.is_ascii() {
+                        return Err(ConfigError::UnsupportedValue);
+                    }
+                    self.config.set_bind_device(if value.is_empty() {
+                        None
+                    } else {
+                        Some(value.to_owned())
+                    })
+                }
+
+                // opt: set padding policy
+                "padding" => {
+                    let padding = match value {
+                        "multiple" => Padding::Multiple,
+                        "mtu" => Padding::Mtu,
+                        "none" => Padding::Disabled,
+                        _ => return Err(ConfigError::UnsupportedValue),
+                    };
+                    self.config.set_padding(padding);
+                    Ok(())
+                }
+
+                // opt: answer echo requests to the address (empty value removes all addresses)
+                "echo_address" => {
+                    let mut addrs = if value.is_empty() {
+                        vec![]
+                    } else {
+                        self.config.get_echo_addresses()
+                    };
+                    if !value.is_empty() {
+                        match value.parse() {
+                            Ok(addr) => addrs.push(addr),
+                            Err(_) => return Err(ConfigError::InvalidAllowedIp),
+                        }
+                    }
+                    self.config.set_echo_addresses(addrs);
+                    Ok(())
+                }
+
+                // opt: set number of unanswered initiations before a peer is unreachable (0 disables)
+                "blackhole_attempts" => match value.parse() {
+                    Ok(attempts) => {
+                        let mut blackhole = self.config.get_blackhole();
+                        blackhole.attempts = attempts;
+                        self.config.set_blackhole(blackhole);
+                        Ok(())
+                    }
+                    Err(_) => Err(ConfigError::UnsupportedValue),
+                },
+
+                // opt: spread the initial handshakes over the window (in seconds) on device up
+                "handshake_spread" => match value.parse() {
+                    Ok(secs) => {
+                        self.config.set_handshake_spread(Duration::from_secs(secs));
+                        Ok(())
+                    }
+                    Err(_) => Err(ConfigError::UnsupportedValue),
+                },
+
+                // opt: flush the outbound queues on device down, bounded by the timeout (in ms, 0 = off)
+                "shutdown_flush" => match value.parse() {
+                    Ok(0) => {
+                        self.config.set_shutdown_flush(None);
+                        Ok(())
+                    }
+                    Ok(ms) => {
+                        self.config
+                            .set_shutdown_flush(Some(Duration::from_millis(ms)));
+                        Ok(())
+                    }
+                    Err(_) => Err(ConfigError::UnsupportedValue),
+                },
+
+                // opt: emit usage events for accounting every interval (in seconds, 0 = off)
+                "usage_interval" => match value.parse() {
+                    Ok(0) => {
+                        self.config.set_usage_interval(None);
+                        Ok(())
+                    }
+                    Ok(secs) => {
+                        self.config
+                            .set_usage_interval(Some(Duration::from_secs(secs)));
+                        Ok(())
+                    }
+                    Err(_) => Err(ConfigError::UnsupportedValue),
+                },
+
+                // opt: enter / leave power-save, on|off|<keepalive multiplier>/<coalesce secs>
+                "power_save" => {
+                    let power_save = match value {
+                        "off" => None,
+                        "on" => Some(PowerSave::default()),
+                        _ => {
+                            let mut split = value.splitn(2, "/");
+                            let multiplier = split.next().and_then(|x| x.parse().ok());
+                            let coalesce = split.next().and_then(|x| x.parse().ok());
+                            match (multiplier, coalesce) {
+                                (Some(multiplier), Some(coalesce)) if multiplier > 0 => {
+                                    Some(PowerSave {
+                                        keepalive_multiplier: multiplier,
+                                        coalesce: Duration::from_secs(coalesce),
+                                    })
+                                }
+                                _ => return Err(ConfigError::UnsupportedValue),
+                            }
+                        }
+                    };
+                    self.config.set_power_save(power_save);
+                    Ok(())
+                }
+
+                // opt: set cool-down (in seconds) of unreachable peers (0 keeps retrying)
+                "blackhole_cooldown" => match value.parse() {
+                    Ok(secs) => {
+                        let mut blackhole = self.config.get_blackhole();
+                        blackhole.cooldown = Duration::from_secs(secs);
+                        self.config.set_blackhole(blackhole);
+                        Ok(())
+                    }
+                    Err(_) => Err(ConfigError::UnsupportedValue),
+                },
+
+                // opt: track inner flows, expired after being idle for the timeout (in seconds, 0 disables)
+                "flow_timeout" => match value.parse() {
+                    Ok(secs) => {
+                        self.config.set_flow_timeout(if secs == 0 {
+                            None
+                        } else {
+                            Some(Duration::from_secs(secs))
+                        });
+                        Ok(())
+                    }
+                    Err(_) => Err(ConfigError::UnsupportedValue),
+                },
+
+                // opt: drop packets staged (awaiting a session) for longer (in milliseconds, 0 disables)
+                "max_staged_age_ms" => match value.parse() {
+                    Ok(ms) => {
+                        self.config.set_max_staged_age(if ms == 0 {
+                            None
+                        } else {
+                            Some(Duration::from_millis(ms))
+                        });
+                        Ok(())
+                    }
+                    Err(_) => Err(ConfigError::UnsupportedValue),
+                },
+
+                // opt: assign peers addresses derived from their keys in the IPv6 prefix (empty value disables)
+                "auto_prefix" => {
+                    if value.is_empty() {
+                        self.config.set_auto_prefix(None);
+                        return Ok(());
+                    }
+                    let mut split = value.splitn(2, '/');
+                    let addr: Option<Ipv6Addr> = split.next().and_then(|x| x.parse().ok());
+                    let len: Option<u32> = split.next().and_then(|x| x.parse().ok());
+                    match (addr, len) {
+                        (Some(addr), Some(len)) if len <= 128 => {
+                            self.config.set_auto_prefix(Some((addr, len)));
+                            Ok(())
+                        }
+                        _ => Err(ConfigError::InvalidAllowedIp),
+                    }
+                }
+
+                // opt: add (or replace) a node of the full mesh,
+                // as <public key>,<address>/<cidr>[,<endpoint>]
+                "mesh_node" => {
+                    let mut parts = value.splitn(3, ',');
+                    let public_key = match parts.next().map(<[u8; 32]>::from_hex) {
+                        Some(Ok(pk)) => PublicKey::from(pk),
+                        _ => return Err(ConfigError::InvalidHexValue),
+                    };
+                    let address = {
+                        let mut split = parts.next().unwrap_or("").splitn(2, '/');
+                        let addr: Option<IpAddr> = split.next().and_then(|x| x.parse().ok());
+                        let cidr: Option<u32> = split.next().and_then(|x| x.parse().ok());
+                        match (addr, cidr) {
+                            (Some(addr), Some(cidr))
+                                if cidr <= if addr.is_ipv4() { 32 } else { 128 } =>
+                            {
+                                (addr, cidr)
+                            }
+                            _ => return Err(ConfigError::InvalidAllowedIp),
+                        }
+                    };
+                    let endpoint = match parts.next().map(|x| x.parse()) {
+                        None => None,
+                        Some(Ok(endpoint)) => Some(endpoint),
+                        Some(Err(_)) => return Err(ConfigError::InvalidSocketAddr),
+                    };
+                    self.config.add_mesh_node(Node {
+                        public_key,
+                        endpoint,
+                        address,
+                    })
+                }
+
+                // opt: remove a node from the full mesh (and its peer)
+                "mesh_remove" => match <[u8; 32]>::from_hex(value) {
+                    Ok(pk) => self.config.remove_mesh_node(&PublicKey::from(pk)),
+                    Err(_) => Err(ConfigError::InvalidHexValue),
+                },
+
+                // opt: never answer initiations from unknown static keys
+                "stealth" => match value {
+                    "true" => {
+                        self.config.set_stealth(true);
+                        Ok(())
+                    }
+                    "false" => {
+                        self.config.set_stealth(false);
+                        Ok(())
+                    }
+                    _ => Err(ConfigError::UnsupportedValue),
+                },
+
+                // opt: relay packets between peers (rather than delivering them to TUN)
+                "relay" => match value {
+                    "true" => {
+                        self.config.set_relay(true);
+                        Ok(())
+                    }
+                    "false" => {
+                        self.config.set_relay(false);
+                        Ok(())
+                    }
+                    _ => Err(ConfigError::UnsupportedValue),
+                },
+
+                // opt: select the exit among the exit candidates (empty value stops the selection)
+                "exit_policy" => {
+                    self.config.set_exit_policy(match value {
+                        "" => None,
+                        "rtt" => Some(ExitPolicy::Rtt),
+                        "weight" => Some(ExitPolicy::Weight),
+                        _ => return Err(ConfigError::UnsupportedValue),
+                    });
+                    Ok(())
+                }
+
+                // opt: export the tracked flows to the IPFIX collector (empty value stops the export)
+                #[cfg(feature = "ipfix")]
+                "ipfix_collector" => {
+                    if value.is_empty() {
+                        self.config.set_ipfix_collector(None);
+                        return Ok(());
+                    }
+                    match value.parse() {
+                        Ok(collector) => {
+                            self.config.set_ipfix_collector(Some(collector));
+                            Ok(())
+                        }
+                        Err(_) => Err(ConfigError::UnsupportedValue),
+                    }
+                }
+
+                // opt: persist the usage of peer quotas in the file (empty value stops persisting)
+                "quota_file" => self.config.set_quota_file(if value.is_empty() {
+                    None
+                } else {
+                    Some(PathBuf::from(value))
+                }),
+
+                // opt: override the log level of a subsystem, as <subsystem>:<level>
+                // (an empty level restores the level given by RUST_LOG)
+                "log_level" => {
+                    let mut split = value.splitn(2, ':');
+                    let subsystem: Subsystem = match split.next().and_then(|s| s.parse().ok()) {
+                        Some(subsystem) => subsystem,
+                        None => return Err(ConfigError::UnsupportedValue),
+                    };
+                    match split.next() {
+                        Some("") => self.config.set_log_level(subsystem, None),
+                        Some(level) => match level.parse() {
+                            Ok(level) => self.config.set_log_level(subsystem, Some(level)),
+                            Err(_) => return Err(ConfigError::UnsupportedValue),
+                        },
+                        None => return Err(ConfigError::UnsupportedValue),
+                    }
+                    Ok(())
+                }
+
+                // opt: set memory limit
+                "memory_limit" => match value.parse() {
+                    Ok(limit) => {
+                        self.config
+                            .set_memory_limit(if limit == 0 { None } else { Some(limit) });
+                        Ok(())
+                    }
+                    Err(_) => Err(ConfigError::UnsupportedValue),
+                },
+
                 // opt: remove all peers
                 "replace_peers" => match value {
                     "true" => {
@@ -178,6 +781,12 @@ impl<'a, C: Configuration> LineParser<'a, C> {
                     Ok(())
                 }
 
+                // opt: force new session keys
+                "rekey" => {
+                    peer.rekey = true;
+                    Ok(())
+                }
+
                 // opt: update only
                 "update_only" => {
                     peer.update_only = true;
@@ -202,6 +811,173 @@ impl<'a, C: Configuration> LineParser<'a, C> {
                     Err(_) => Err(ConfigError::InvalidSocketAddr),
                 },
 
+                // opt: add candidate endpoint (empty value removes all candidates)
+                "endpoint_candidate" => {
+                    let addrs = peer.endpoint_candidates.get_or_insert_with(Vec::new);
+                    if value.is_empty() {
+                        addrs.clear();
+                        return Ok(());
+                    }
+                    match value.parse() {
+                        Ok(addr) => {
+                            addrs.push(addr);
+                            Ok(())
+                        }
+                        Err(_) => Err(ConfigError::InvalidSocketAddr),
+                    }
+                }
+
+                // opt: pin outgoing interface (by index or name, 0 removes the pin)
+                "source_interface" => match interface_index(value) {
+                    Some(ifindex) => {
+                        peer.source_interface = Some(ifindex);
+                        Ok(())
+                    }
+                    None => Err(ConfigError::UnsupportedValue),
+                },
+
+                // opt: pin source address (empty value removes the pin)
+                "source_address" => {
+                    if value.is_empty() {
+                        peer.source_address = Some(None);
+                        return Ok(());
+                    }
+                    match value.parse() {
+                        Ok(addr) => {
+                            peer.source_address = Some(Some(addr));
+                            Ok(())
+                        }
+                        Err(_) => Err(ConfigError::UnsupportedValue),
+                    }
+                }
+
+                // opt: override the hop limit / TTL of messages to the peer (0 removes the override)
+                "hop_limit" => match value.parse() {
+                    Ok(0) => {
+                        peer.hop_limit = Some(None);
+                        Ok(())
+                    }
+                    Ok(hops) => {
+                        peer.hop_limit = Some(Some(hops));
+                        Ok(())
+                    }
+                    Err(_) => Err(ConfigError::UnsupportedValue),
+                },
+
+                // opt: inner MTU toward the peer (0 = the MTU of the device)
+                "mtu" => match value.parse() {
+                    Ok(0) => {
+                        peer.mtu = Some(None);
+                        Ok(())
+                    }
+                    Ok(mtu) if mtu >= MIN_PEER_MTU => {
+                        peer.mtu = Some(Some(mtu));
+                        Ok(())
+                    }
+                    _ => Err(ConfigError::UnsupportedValue),
+                },
+
+                // opt: enclose messages in frames toward a relay, as <tag>@<relay endpoint>
+                // (empty removes the encapsulation)
+                "encapsulation" => {
+                    if value.is_empty() {
+                        peer.encapsulation = Some(None);
+                        return Ok(());
+                    }
+                    let mut parts = value.splitn(2, '@');
+                    match (
+                        parts.next().map(|tag| tag.parse()),
+                        parts.next().map(|relay| relay.parse()),
+                    ) {
+                        (Some(Ok(tag)), Some(Ok(relay))) => {
+                            peer.encapsulation = Some(Some(Encapsulation { relay, tag }));
+                            Ok(())
+                        }
+                        _ => Err(ConfigError::UnsupportedValue),
+                    }
+                }
+
+                // opt: set quota on the transport traffic in bytes (0 removes the quota)
+                "quota_bytes" => match value.parse() {
+                    Ok(bytes) => {
+                        peer.quota_bytes = Some(bytes);
+                        Ok(())
+                    }
+                    Err(_) => Err(ConfigError::UnsupportedValue),
+                },
+
+                // opt: set when the quota usage is reset
+                "quota_period" => {
+                    peer.quota_period = Some(match value {
+                        "absolute" => QuotaPeriod::Absolute,
+                        "monthly" => QuotaPeriod::Monthly,
+                        _ => return Err(ConfigError::UnsupportedValue),
+                    });
+                    Ok(())
+                }
+
+                // opt: set the weight of the peer as an exit candidate (0 = not a candidate)
+                "exit_weight" => match value.parse() {
+                    Ok(weight) => {
+                        peer.exit_weight = Some(weight);
+                        Ok(())
+                    }
+                    Err(_) => Err(ConfigError::UnsupportedValue),
+                },
+
+                // opt: set how the endpoint is learned (roaming), learn|fixed|<ip>/<cidr>
+                "endpoint_policy" => {
+                    peer.endpoint_policy = Some(match value {
+                        "learn" => EndpointPolicy::Learn,
+                        "fixed" => EndpointPolicy::Fixed,
+                        _ => {
+                            let mut split = value.splitn(2, "/");
+                            let addr = split.next().and_then(|x| x.parse().ok());
+                            let cidr = split.next().and_then(|x| x.parse().ok());
+                            match (addr, cidr) {
+                                (Some(addr), Some(cidr)) => EndpointPolicy::LearnWithin(addr, cidr),
+                                _ => return Err(ConfigError::UnsupportedValue),
+                            }
+                        }
+                    });
+                    Ok(())
+                }
+
+                // opt: set the port of learned endpoints (0 = the source port)
+                "endpoint_port" => match value.parse() {
+                    Ok(0) => {
+                        peer.endpoint_port = Some(None);
+                        Ok(())
+                    }
+                    Ok(port) => {
+                        peer.endpoint_port = Some(Some(port));
+                        Ok(())
+                    }
+                    Err(_) => Err(ConfigError::InvalidPortNumber),
+                },
+
+                // opt: set which end of a handshake the device may take, both|responder|initiator
+                "handshake_role" => {
+                    peer.handshake_role = Some(match value {
+                        "both" => HandshakeRole::Both,
+                        "responder" => HandshakeRole::Responder,
+                        "initiator" => HandshakeRole::Initiator,
+                        _ => return Err(ConfigError::UnsupportedValue),
+                    });
+                    Ok(())
+                }
+
+                // opt: set whether packets from the peer to other peers are relayed
+                "relay" => {
+                    peer.relay = Some(match value {
+                        "default" => RelayPolicy::Default,
+                        "forward" => RelayPolicy::Forward,
+                        "deliver" => RelayPolicy::Deliver,
+                        _ => return Err(ConfigError::UnsupportedValue),
+                    });
+                    Ok(())
+                }
+
                 // opt: set persistent keepalive interval
                 "persistent_keepalive_interval" => match value.parse() {
                     Ok(secs) => {
@@ -211,6 +987,26 @@ impl<'a, C: Configuration> LineParser<'a, C> {
                     Err(_) => Err(ConfigError::InvalidKeepaliveInterval),
                 },
 
+                // opt: set cover traffic interval (in milliseconds)
+                "cover_traffic_interval" => match value.parse() {
+                    Ok(millis) => {
+                        peer.cover_traffic_interval = Some(millis);
+                        Ok(())
+                    }
+                    Err(_) => Err(ConfigError::UnsupportedValue),
+                },
+
+                // opt: set which traffic postpones persistent keepalives, any|sent|never
+                "keepalive_suppression" => {
+                    peer.keepalive_suppression = Some(match value {
+                        "any" => KeepaliveSuppression::Any,
+                        "sent" => KeepaliveSuppression::Sent,
+                        "never" => KeepaliveSuppression::Never,
+                        _ => return Err(ConfigError::UnsupportedValue),
+                    });
+                    Ok(())
+                }
+
                 // opt replace allowed ips
                 "replace_allowed_ips" => {
                     peer.replace_allowed_ips = true;
@@ -232,6 +1028,60 @@ impl<'a, C: Configuration> LineParser<'a, C> {
                     }
                 }
 
+                // opt: replace the policy routes of the peer
+                "replace_policy_routes" => match value {
+                    "true" => {
+                        peer.replace_policy_routes = true;
+                        peer.policy_routes.clear();
+                        Ok(())
+                    }
+                    _ => Err(ConfigError::UnsupportedValue),
+                },
+
+                // opt: add policy route, <source>/<cidr>,<destination>/<cidr>
+                "policy_route" => {
+                    fn subnet(value: &str) -> Option<(IpAddr, u32)> {
+                        let mut split = value.splitn(2, "/");
+                        let addr = split.next()?.parse().ok()?;
+                        let cidr = split.next()?.parse().ok()?;
+                        Some((addr, cidr))
+                    }
+                    let mut split = value.splitn(2, ",");
+                    let source = split.next().and_then(subnet);
+                    let destination = split.next().and_then(subnet);
+                    match (source, destination) {
+                        (Some(source), Some(destination)) => {
+                            peer.policy_routes.push((source, destination));
+                            Ok(())
+                        }
+                        _ => Err(ConfigError::InvalidAllowedIp),
+                    }
+                }
+
+                // opt: replace the subnets shared with other peers
+                "replace_ecmp_ips" => match value {
+                    "true" => {
+                        peer.replace_ecmp_ips = true;
+                        peer.ecmp_ips.clear();
+                        Ok(())
+                    }
+                    _ => Err(ConfigError::UnsupportedValue),
+                },
+
+                // opt: add subnet shared with other peers (ECMP), <ip>/<cidr>
+                "ecmp_ip" => {
+                    let mut split = value.splitn(2, "/");
+                    let addr = split.next().and_then(|x| x.parse().ok());
+                    let cidr = split.next().and_then(|x| x.parse().ok());
+                    match (addr, cidr) {
+                        (Some(addr), Some(cidr)) => {
+                            peer.ecmp_ips.push((addr, cidr));
+                            Ok(())
+                        }
+                        _ => Err(ConfigError::InvalidAllowedIp),
+                    }
+                }
+
                 // set protocol version of peer
                 "protocol_version" => {
                     let parse_res: Result<usize, _> = value.parse();
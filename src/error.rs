@@ -0,0 +1,146 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+use super::configuration::ConfigError;
+use super::wireguard::{HandshakeError, TunnelError};
+
+/* Errors of the crate by class of failure:
+ *
+ * The class is matched on (e.g. a failure to bind the sockets is a transport error,
+ * regardless of the platform), while the platform-specific error remains available
+ * as the source (Error::source).
+ */
+
+/// A failure of the UDP transport (e.g. binding the sockets), the source is the platform error
+#[derive(Debug)]
+pub struct TransportError(Box<dyn error::Error + Send + Sync>);
+
+/// A failure of the TUN device (e.g. creating the device), the source is the platform error
+#[derive(Debug)]
+pub struct TunError(Box<dyn error::Error + Send + Sync>);
+
+#[derive(Debug)]
+pub enum Error {
+    Config(ConfigError),       // invalid or failed configuration (e.g. over the UAPI)
+    Transport(TransportError), // the UDP sockets
+    Handshake(HandshakeError), // a handshake message was rejected
+    Tun(TunError),             // the TUN device
+    Io(io::Error),             // other OS resources (e.g. the UAPI socket or the store)
+}
+
+impl TransportError {
+    pub fn new<E: error::Error + Send + Sync + 'static>(err: E) -> TransportError {
+        TransportError(Box::new(err))
+    }
+}
+
+impl TunError {
+    pub fn new<E: error::Error + Send + Sync + 'static>(err: E) -> TunError {
+        TunError(Box::new(err))
+    }
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Transport failure: {}", self.0)
+    }
+}
+
+impl fmt::Display for TunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TUN device failure: {}", self.0)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config(e) => write!(f, "Configuration failed: {}", e),
+            Error::Transport(e) => e.fmt(f),
+            Error::Handshake(e) => write!(f, "Handshake failed: {}", e),
+            Error::Tun(e) => e.fmt(f),
+            Error::Io(e) => write!(f, "IO failure: {}", e),
+        }
+    }
+}
+
+impl error::Error for TransportError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&*self.0)
+    }
+}
+
+impl error::Error for TunError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&*self.0)
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Config(e) => Some(e),
+            Error::Transport(e) => Some(e),
+            Error::Handshake(e) => Some(e),
+            Error::Tun(e) => Some(e),
+            Error::Io(e) => Some(e),
+        }
+    }
+}
+
+impl From<ConfigError> for Error {
+    fn from(e: ConfigError) -> Self {
+        Error::Config(e)
+    }
+}
+
+impl From<TransportError> for Error {
+    fn from(e: TransportError) -> Self {
+        Error::Transport(e)
+    }
+}
+
+impl From<HandshakeError> for Error {
+    fn from(e: HandshakeError) -> Self {
+        Error::Handshake(e)
+    }
+}
+
+impl From<TunError> for Error {
+    fn from(e: TunError) -> Self {
+        Error::Tun(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+// messages of the tunnel are rejected either by the handshake or by the transport
+impl From<TunnelError> for Error {
+    fn from(e: TunnelError) -> Self {
+        match e {
+            TunnelError::Handshake(e) => Error::Handshake(e),
+            e => Error::Transport(TransportError::new(e)),
+        }
+    }
+}
+
+/// Formats the error and its chain of sources, e.g.
+/// "Configuration failed: ...: Transport failure: ..."
+pub fn report(err: &dyn error::Error) -> String {
+    let mut msg = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        let text = err.to_string();
+        if !msg.contains(&text) {
+            msg.push_str(": ");
+            msg.push_str(&text);
+        }
+        source = err.source();
+    }
+    msg
+}
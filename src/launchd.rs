@@ -0,0 +1,87 @@
+/* Integration with launchd (macOS):
+ *
+ * "--launchd-plist" prints a property list running the device as a launchd daemon, e.g.
+ *
+ *   wireguard-rs --launchd-plist utun3 > /Library/LaunchDaemons/com.wireguard-rs.utun3.plist
+ *   launchctl load /Library/LaunchDaemons/com.wireguard-rs.utun3.plist
+ *
+ * launchd supervises the process itself, hence the daemon runs in the foreground
+ * and is restarted by launchd if it exits.
+ */
+use std::fmt::Write;
+
+// prefix of the label of the job (followed by the device name)
+const LABEL_PREFIX: &str = "com.wireguard-rs.";
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Label of the launchd job of the device
+pub fn label(name: &str) -> String {
+    format!("{}{}", LABEL_PREFIX, name)
+}
+
+/// Property list of a launchd job running the daemon for the device
+///
+/// # Arguments
+///
+/// - `name`: The name of the device
+/// - `program`: The path of the daemon binary
+/// - `args`: The options of the daemon (the device name and "--foreground" are appended)
+pub fn plist(name: &str, program: &str, args: &[String]) -> String {
+    let mut out = String::new();
+    out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    out.push_str(
+        "<!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \
+         \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n",
+    );
+    out.push_str("<plist version=\"1.0\">\n<dict>\n");
+    let _ = writeln!(
+        out,
+        "  <key>Label</key>\n  <string>{}</string>",
+        escape(&label(name))
+    );
+    out.push_str("  <key>ProgramArguments</key>\n  <array>\n");
+    let program = Some(program);
+    let foreground = Some("--foreground");
+    let args = args.iter().map(|arg| arg.as_str());
+    for arg in program
+        .into_iter()
+        .chain(args)
+        .chain(foreground)
+        .chain(Some(name))
+    {
+        let _ = writeln!(out, "    <string>{}</string>", escape(arg));
+    }
+    out.push_str("  </array>\n");
+    out.push_str("  <key>RunAtLoad</key>\n  <true/>\n");
+    out.push_str("  <key>KeepAlive</key>\n  <true/>\n");
+    out.push_str("</dict>\n</plist>\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plist() {
+        let plist = plist(
+            "utun3",
+            "/usr/local/bin/wireguard-rs",
+            &["--store=/var/db/<wg>".to_owned()],
+        );
+        assert!(plist.contains("<string>com.wireguard-rs.utun3</string>"));
+        assert!(plist.contains(
+            "    <string>/usr/local/bin/wireguard-rs</string>\n    \
+             <string>--store=/var/db/&lt;wg&gt;</string>\n    \
+             <string>--foreground</string>\n    \
+             <string>utun3</string>\n"
+        ));
+        assert!(plist.ends_with("</dict>\n</plist>\n"));
+    }
+}
@@ -0,0 +1,14 @@
+#![cfg_attr(feature = "unstable", feature(test))]
+
+/* The WireGuard implementation as a library:
+ * the daemon (main.rs) drives it over the platform of the host,
+ * other crates may embed the device (or the dummy platform, with the "dummy" feature).
+ */
+
+extern crate alloc;
+
+pub mod configuration;
+pub mod error;
+pub mod logging;
+pub mod platform;
+pub mod wireguard;
@@ -0,0 +1,183 @@
+/* Logger allowing the level of every subsystem to be changed at runtime
+ * (e.g. over the UAPI), hence a running device can be debugged without a restart.
+ *
+ * Records of subsystems without an override are filtered by RUST_LOG (as by env_logger).
+ * The records are written to stderr, syslog or the journal (see Sink).
+ */
+mod sink;
+
+pub use sink::Sink;
+
+use std::fmt;
+use std::str::FromStr;
+
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use spin::RwLock;
+
+use sink::Output;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subsystem {
+    Handshake,
+    Router,
+    Udp,
+    Tun,
+}
+
+const SUBSYSTEMS: [Subsystem; 4] = [
+    Subsystem::Handshake,
+    Subsystem::Router,
+    Subsystem::Udp,
+    Subsystem::Tun,
+];
+
+// level overrides, indexed by subsystem
+static LEVELS: RwLock<[Option<LevelFilter>; 4]> = RwLock::new([None; 4]);
+
+impl Subsystem {
+    pub fn all() -> &'static [Subsystem] {
+        &SUBSYSTEMS
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Subsystem::Handshake => "handshake",
+            Subsystem::Router => "router",
+            Subsystem::Udp => "udp",
+            Subsystem::Tun => "tun",
+        }
+    }
+
+    /// Returns the subsystem of a log target (the module path of the record)
+    pub fn of(target: &str) -> Option<Subsystem> {
+        target.split("::").find_map(|module| module.parse().ok())
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl FromStr for Subsystem {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        SUBSYSTEMS
+            .iter()
+            .find(|subsystem| subsystem.name() == name)
+            .cloned()
+            .ok_or(())
+    }
+}
+
+impl fmt::Display for Subsystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+struct Logger {
+    env: env_logger::Logger, // filtered by RUST_LOG
+    all: env_logger::Logger, // unfiltered (for subsystems with an override)
+    output: Option<Output>,  // syslog / journal (None = stderr by env_logger)
+}
+
+impl Logger {
+    fn write(&self, stderr: &env_logger::Logger, record: &Record) {
+        match self.output.as_ref() {
+            Some(output) => output.write(record),
+            None => stderr.log(record),
+        }
+    }
+}
+
+// the level override of the subsystem of the target (if any)
+fn override_of(target: &str) -> Option<LevelFilter> {
+    Subsystem::of(target).and_then(get_level)
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        match override_of(metadata.target()) {
+            Some(level) => metadata.level() <= level,
+            None => self.env.enabled(metadata),
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        match override_of(record.target()) {
+            Some(level) if record.level() <= level => self.write(&self.all, record),
+            Some(_) => (),
+            None if self.env.matches(record) => self.write(&self.env, record),
+            None => (),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+// level of the environment filter (RUST_LOG), set once the logger is installed
+static ENV_LEVEL: RwLock<LevelFilter> = RwLock::new(LevelFilter::Off);
+
+// the global maximum must admit the most verbose of the filters
+fn update_max_level() {
+    let levels = LEVELS.read();
+    let max = levels
+        .iter()
+        .filter_map(|level| *level)
+        .fold(*ENV_LEVEL.read(), |max, level| max.max(level));
+    log::set_max_level(max);
+}
+
+/// Installs the logger writing to the sink (replaces env_logger::init),
+/// falls back to stderr if the sink cannot be opened
+pub fn init(sink: Sink) -> Result<(), SetLoggerError> {
+    let env = env_logger::Builder::from_default_env().build();
+    let all = env_logger::Builder::new()
+        .filter_level(LevelFilter::Trace)
+        .build();
+    let output = Output::open(sink).unwrap_or_else(|e| {
+        eprintln!("Failed to open log sink {}, logging to stderr: {}", sink, e);
+        None
+    });
+    *ENV_LEVEL.write() = env.filter();
+    log::set_boxed_logger(Box::new(Logger { env, all, output }))?;
+    update_max_level();
+    Ok(())
+}
+
+/// Overrides the log level of the subsystem (None restores the RUST_LOG filter)
+pub fn set_level(subsystem: Subsystem, level: Option<LevelFilter>) {
+    LEVELS.write()[subsystem.index()] = level;
+    update_max_level();
+}
+
+pub fn get_level(subsystem: Subsystem) -> Option<LevelFilter> {
+    LEVELS.read()[subsystem.index()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subsystem_of() {
+        assert_eq!(
+            Subsystem::of("wireguard_rs::wireguard::handshake::device"),
+            Some(Subsystem::Handshake)
+        );
+        assert_eq!(
+            Subsystem::of("wireguard_rs::wireguard::router::receive"),
+            Some(Subsystem::Router)
+        );
+        assert_eq!(
+            Subsystem::of("wireguard_rs::platform::linux::udp"),
+            Some(Subsystem::Udp)
+        );
+        assert_eq!(
+            Subsystem::of("wireguard_rs::platform::bsd::tun"),
+            Some(Subsystem::Tun)
+        );
+        assert_eq!(Subsystem::of("wireguard_rs::configuration::uapi"), None);
+    }
+}
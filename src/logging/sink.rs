@@ -0,0 +1,297 @@
+/* Output backends of the logger, selected with the "--log=" option of the daemon:
+ *
+ * - stderr:   formatted by env_logger (default)
+ * - syslog:   RFC 5424 messages to the local syslog daemon
+ * - journald: the native protocol of systemd-journald, with structured fields
+ *
+ * The id of the peer (see wireguard::PeerId) is extracted from the message of the record,
+ * hence the journal can be filtered by peer (journalctl WG_PEER=<id>).
+ */
+use std::fmt;
+use std::io::{self, Write};
+use std::os::unix::net::UnixDatagram;
+use std::process;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{Level, Record};
+
+use super::Subsystem;
+
+const SYSLOG_SOCKET: &str = "/dev/log";
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+const IDENTIFIER: &str = "wireguard-rs";
+
+// syslog facility of system daemons
+const FACILITY_DAEMON: u8 = 3;
+
+// SD-ID of the structured data (enterprise number reserved for documentation, RFC 5612)
+const SD_ID: &str = "wireguard@32473";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sink {
+    Stderr,
+    Syslog,
+    Journald,
+}
+
+impl Default for Sink {
+    fn default() -> Self {
+        Sink::Stderr
+    }
+}
+
+impl FromStr for Sink {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "stderr" => Ok(Sink::Stderr),
+            "syslog" => Ok(Sink::Syslog),
+            "journald" => Ok(Sink::Journald),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Sink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Sink::Stderr => "stderr",
+            Sink::Syslog => "syslog",
+            Sink::Journald => "journald",
+        })
+    }
+}
+
+/// A connection to the syslog daemon or the journal
+pub(super) struct Output {
+    sink: Sink,
+    socket: UnixDatagram,
+}
+
+impl Output {
+    /// Connects to the sink (None for stderr, which is written by env_logger)
+    pub fn open(sink: Sink) -> io::Result<Option<Output>> {
+        let path = match sink {
+            Sink::Stderr => return Ok(None),
+            Sink::Syslog => SYSLOG_SOCKET,
+            Sink::Journald => JOURNALD_SOCKET,
+        };
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        Ok(Some(Output { sink, socket }))
+    }
+
+    pub fn write(&self, record: &Record) {
+        let msg = record.args().to_string();
+        let datagram = match self.sink {
+            Sink::Stderr => unreachable!(),
+            Sink::Syslog => syslog(record.level(), record.target(), &msg, SystemTime::now()),
+            Sink::Journald => journald(record, &msg),
+        };
+
+        // fall back to stderr rather than losing the record (e.g. the daemon restarted)
+        if self.socket.send(&datagram).is_err() {
+            let _ = writeln!(io::stderr(), "[{}] {}", record.level(), msg);
+        }
+    }
+}
+
+/// Returns the id of the peer the message is about (if any),
+/// i.e. the id in the Display form of a peer: "peer(id = <id>)" or "peer(device = .., id = <id>)"
+fn peer_of(msg: &str) -> Option<&str> {
+    let start = msg.find("peer(")?;
+    let rest = &msg[start..];
+    let end = rest.find(')')?;
+    let id = rest[..end].rsplit("id = ").next()?;
+    if id.len() == rest[..end].len() || id.is_empty() {
+        return None;
+    }
+    Some(id)
+}
+
+// syslog severity of the level
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Formats the record as an RFC 5424 message
+fn syslog(level: Level, target: &str, msg: &str, now: SystemTime) -> Vec<u8> {
+    let msgid = Subsystem::of(target).map(Subsystem::name).unwrap_or("-");
+    let sd = match peer_of(msg) {
+        Some(peer) => format!("[{} peer=\"{}\"]", SD_ID, escape_param(peer)),
+        None => "-".to_owned(),
+    };
+    format!(
+        "<{}>1 {} - {} {} {} {} {}",
+        FACILITY_DAEMON * 8 + severity(level),
+        timestamp(now),
+        IDENTIFIER,
+        process::id(),
+        msgid,
+        sd,
+        msg
+    )
+    .into_bytes()
+}
+
+// escapes a PARAM-VALUE of the structured data (RFC 5424, section 6.3.3)
+fn escape_param(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == '"' || c == '\\' || c == ']' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// RFC 3339 timestamp (UTC) of the walltime
+fn timestamp(now: SystemTime) -> String {
+    let since = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let time = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60,
+        since.subsec_micros()
+    )
+}
+
+// date of the day since the epoch (the algorithm of "chrono-Compatible Low-Level Date Algorithms")
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Encodes the record as the fields of a journal entry (the native protocol of journald)
+fn journald(record: &Record, msg: &str) -> Vec<u8> {
+    let mut entry = vec![];
+    field(&mut entry, "MESSAGE", msg);
+    field(
+        &mut entry,
+        "PRIORITY",
+        &severity(record.level()).to_string(),
+    );
+    field(&mut entry, "SYSLOG_IDENTIFIER", IDENTIFIER);
+    field(&mut entry, "SYSLOG_PID", &process::id().to_string());
+    field(&mut entry, "WG_TARGET", record.target());
+    if let Some(subsystem) = Subsystem::of(record.target()) {
+        field(&mut entry, "WG_SUBSYSTEM", subsystem.name());
+    }
+    if let Some(peer) = peer_of(msg) {
+        field(&mut entry, "WG_PEER", peer);
+    }
+    if let Some(file) = record.file() {
+        field(&mut entry, "CODE_FILE", file);
+    }
+    if let Some(line) = record.line() {
+        field(&mut entry, "CODE_LINE", &line.to_string());
+    }
+    entry
+}
+
+// appends a field to the entry,
+// values containing a newline are length-prefixed (little-endian u64) rather than terminated
+fn field(entry: &mut Vec<u8>, name: &str, value: &str) {
+    entry.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn test_sink_from_str() {
+        for sink in [Sink::Stderr, Sink::Syslog, Sink::Journald].iter() {
+            assert_eq!(sink.to_string().parse(), Ok(*sink));
+        }
+        assert_eq!("kmsg".parse::<Sink>(), Err(()));
+    }
+
+    #[test]
+    fn test_peer_of() {
+        assert_eq!(peer_of("peer(id = AbCd+/12) : rekey"), Some("AbCd+/12"));
+        assert_eq!(
+            peer_of("peer(device = wg0, id = AbCd+/12) : timer fired"),
+            Some("AbCd+/12")
+        );
+        assert_eq!(peer_of("wireguard(wg0) : up"), None);
+        assert_eq!(peer_of("peer(device = wg0) : no id"), None);
+    }
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000000Z");
+        assert_eq!(
+            timestamp(UNIX_EPOCH + Duration::from_micros(951_782_400_123_456)),
+            "2000-02-29T00:00:00.123456Z"
+        );
+        assert_eq!(
+            timestamp(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+            "2023-11-14T22:13:20.000000Z"
+        );
+    }
+
+    #[test]
+    fn test_syslog() {
+        let msg = syslog(
+            Level::Warn,
+            "wireguard_rs::wireguard::handshake::device",
+            "peer(id = AbCd+/12) : invalid mac1",
+            UNIX_EPOCH,
+        );
+        assert_eq!(
+            String::from_utf8(msg).unwrap(),
+            format!(
+                "<28>1 1970-01-01T00:00:00.000000Z - wireguard-rs {} handshake \
+                 [wireguard@32473 peer=\"AbCd+/12\"] peer(id = AbCd+/12) : invalid mac1",
+                process::id()
+            )
+        );
+        assert_eq!(escape_param("a\"b]c\\"), "a\\\"b\\]c\\\\");
+    }
+
+    #[test]
+    fn test_journald_field() {
+        let mut entry = vec![];
+        field(&mut entry, "MESSAGE", "up");
+        field(&mut entry, "MESSAGE", "a\nb");
+        let mut expected = b"MESSAGE=up\nMESSAGE\n".to_vec();
+        expected.extend_from_slice(&3u64.to_le_bytes());
+        expected.extend_from_slice(b"a\nb\n");
+        assert_eq!(entry, expected);
+    }
+}
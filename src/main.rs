@@ -1,32 +1,39 @@
-#![cfg_attr(feature = "unstable", feature(test))]
-
-extern crate alloc;
-
 #[cfg(feature = "profiler")]
 extern crate cpuprofiler;
 
 #[cfg(feature = "profiler")]
 use cpuprofiler::PROFILER;
 
-mod configuration;
-mod platform;
-mod wireguard;
+#[cfg(any(target_os = "macos", test))]
+mod launchd;
 
 mod util;
 
+use wireguard_rs::{configuration, error, logging, platform, wireguard};
+
 use log;
 
 use std::env;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
 use std::process::exit;
+use std::sync::Arc;
 use std::thread;
 
-use configuration::Configuration;
+use configuration::{Configuration, FileStore, QuickConfig, Store};
+use error::{Error, TunError};
 
-use platform::tun::{PlatformTun, Status};
+use platform::net::Net;
+use platform::tun::{PlatformTun, Status, TunOptions};
 use platform::uapi::{BindUAPI, PlatformUAPI};
+use platform::udp::BindMode;
 use platform::*;
 
-use wireguard::WireGuard;
+use wireguard::{
+    ExecAuthorization, HandshakePriority, PerformanceMode, PersistedClock, RelayDiscovery,
+    WireGuard, WorkerConfig,
+};
 
 #[cfg(feature = "profiler")]
 fn profiler_stop() {
@@ -54,16 +61,96 @@ fn profiler_start(name: &str) {
     }
 }
 
+// opens the store of the configuration ("sqlite:<path>" for an SQLite database)
+fn open_store(path: &str) -> io::Result<Arc<dyn Store>> {
+    #[cfg(feature = "sqlite")]
+    {
+        if path.starts_with("sqlite:") {
+            let store = configuration::SqliteStore::open(&path["sqlite:".len()..])?;
+            return Ok(Arc::new(store));
+        }
+    }
+    Ok(Arc::new(FileStore::new(path)))
+}
+
 fn main() {
     // parse command line arguments
     let mut name = None;
     let mut drop_privileges = true;
     let mut foreground = false;
+    let mut options = TunOptions::default();
+    let mut mode = BindMode::default();
+    let mut workers = WorkerConfig::default();
+    let mut store = None;
+    let mut clock = None;
+    let mut relay = None;
+    let mut authorize = None;
+    let mut log_sink = logging::Sink::default();
+    let mut addresses = vec![];
+    let mut import = None;
     let mut args = env::args();
 
+    // print a launchd property list for the device rather than running it (macOS)
+    #[cfg(target_os = "macos")]
+    let mut launchd_plist = false;
+    #[cfg(target_os = "macos")]
+    let mut daemon_args = vec![];
+
+    // parse user/group id (of the "--owner=" and "--group=" options)
+    fn parse_id(arg: &str, prefix: &str) -> u32 {
+        arg[prefix.len()..].parse().unwrap_or_else(|_| {
+            eprintln!("Invalid id: {}", arg);
+            exit(-1);
+        })
+    }
+
+    // parse the cores of the performance mode ("--performance=<reader>,<crypto>,<writer>")
+    fn parse_cores(arg: &str, prefix: &str) -> PerformanceMode {
+        let cores: Vec<usize> = arg[prefix.len()..]
+            .split(',')
+            .map(|core| core.parse())
+            .collect::<Result<_, _>>()
+            .unwrap_or_default();
+        if cores.len() != 3 {
+            eprintln!(
+                "Invalid cores (expected <reader>,<crypto>,<writer>): {}",
+                arg
+            );
+            exit(-1);
+        }
+        PerformanceMode::new(cores[0], cores[1], cores[2])
+    }
+
+    // parse an address of the interface ("--address=<ip>/<prefix>")
+    fn parse_address(arg: &str, prefix: &str) -> (IpAddr, u8) {
+        let mut split = arg[prefix.len()..].splitn(2, '/');
+        let addr: Option<IpAddr> = split.next().and_then(|ip| ip.parse().ok());
+        let len: Option<u8> = split.next().and_then(|len| len.parse().ok());
+        match (addr, len) {
+            (Some(addr), Some(len)) if len <= if addr.is_ipv4() { 32 } else { 128 } => (addr, len),
+            _ => {
+                eprintln!("Invalid address (expected <ip>/<prefix>): {}", arg);
+                exit(-1);
+            }
+        }
+    }
+
     // skip path (argv[0])
     args.next();
     for arg in args {
+        #[cfg(target_os = "macos")]
+        {
+            // retain the options of the daemon (for the property list)
+            match arg.as_str() {
+                "--launchd-plist" => {
+                    launchd_plist = true;
+                    continue;
+                }
+                "--foreground" | "-f" => (),
+                opt if opt.starts_with('-') => daemon_args.push(arg.clone()),
+                _ => (),
+            }
+        }
         match arg.as_str() {
             "--foreground" | "-f" => {
                 foreground = true;
@@ -71,6 +158,63 @@ fn main() {
             "--disable-drop-privileges" => {
                 drop_privileges = false;
             }
+            "--ipv4-only" => {
+                mode = BindMode::V4Only;
+            }
+            "--ipv6-only" => {
+                mode = BindMode::V6Only;
+            }
+            "--low-priority-handshakes" => {
+                workers.handshake_priority = HandshakePriority::Low;
+            }
+            "--persist" => {
+                options.persist = Some(true);
+            }
+            "--no-persist" => {
+                options.persist = Some(false);
+            }
+            opt if opt.starts_with("--owner=") => {
+                options.owner = Some(parse_id(opt, "--owner="));
+            }
+            opt if opt.starts_with("--group=") => {
+                options.group = Some(parse_id(opt, "--group="));
+            }
+            opt if opt.starts_with("--rename=") => {
+                options.rename = Some(opt["--rename=".len()..].to_owned());
+            }
+            opt if opt.starts_with("--performance=") => {
+                workers.performance = Some(parse_cores(opt, "--performance="));
+            }
+            opt if opt.starts_with("--address=") => {
+                addresses.push(parse_address(opt, "--address="));
+            }
+            opt if opt.starts_with("--import=") => {
+                import = Some(opt["--import=".len()..].to_owned());
+            }
+            opt if opt.starts_with("--store=") => {
+                store = Some(opt["--store=".len()..].to_owned());
+            }
+            opt if opt.starts_with("--clock=") => {
+                clock = Some(opt["--clock=".len()..].to_owned());
+            }
+            opt if opt.starts_with("--relay=") => {
+                relay = Some(opt["--relay=".len()..].parse().unwrap_or_else(|_| {
+                    eprintln!("Invalid relay address: {}", opt);
+                    exit(-1);
+                }));
+            }
+            opt if opt.starts_with("--authorize=") => {
+                authorize = Some(opt["--authorize=".len()..].to_owned());
+            }
+            opt if opt.starts_with("--log=") => {
+                log_sink = opt["--log=".len()..].parse().unwrap_or_else(|_| {
+                    eprintln!(
+                        "Invalid log sink (expected stderr, syslog or journald): {}",
+                        opt
+                    );
+                    exit(-1);
+                });
+            }
             dev => name = Some(dev.to_owned()),
         }
     }
@@ -84,6 +228,43 @@ fn main() {
         Some(name) => name,
     };
 
+    // the device is created under the given name (or "%d" template) and renamed if requested,
+    // the interface is configured (and the UAPI socket bound) under the final name
+    let device = name;
+    let name = options.rename.clone().unwrap_or_else(|| device.clone());
+
+    #[cfg(target_os = "macos")]
+    {
+        if launchd_plist {
+            let program = env::current_exe().unwrap_or_else(|e| {
+                eprintln!("Failed to find the path of the daemon: {}", e);
+                exit(-1);
+            });
+            print!(
+                "{}",
+                launchd::plist(&name, &program.to_string_lossy(), &daemon_args)
+            );
+            exit(0);
+        }
+    }
+
+    // read the configuration of wg-quick to import (a file or a provisioning URI)
+    // (before dropping privileges, the addresses and MTU of the interface are assigned)
+    let import = import.map(|path| {
+        let quick = fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|content| QuickConfig::import(&content).map_err(|e| e.to_string()))
+            .unwrap_or_else(|e| {
+                eprintln!("Failed to import the configuration {}: {}", path, e);
+                exit(-1);
+            });
+        for &(addr, cidr) in quick.interface.addresses.iter() {
+            addresses.push((addr, cidr as u8));
+        }
+        quick
+    });
+    let mtu = import.as_ref().and_then(|quick| quick.interface.mtu);
+
     // create UAPI socket
     let uapi = plt::UAPI::bind(name.as_str()).unwrap_or_else(|e| {
         eprintln!("Failed to create UAPI listener: {}", e);
@@ -91,10 +272,29 @@ fn main() {
     });
 
     // create TUN device
-    let (mut readers, writer, status) = plt::Tun::create(name.as_str()).unwrap_or_else(|e| {
-        eprintln!("Failed to create TUN device: {}", e);
-        exit(-3);
-    });
+    let (mut readers, writer, status) = plt::Tun::create_with(device.as_str(), &options)
+        .unwrap_or_else(|e| {
+            let err = Error::from(TunError::new(e));
+            eprintln!("Failed to create TUN device: {}", error::report(&err));
+            exit(-3);
+        });
+
+    // assign the addresses (and MTU) and bring the interface up (requires privileges)
+    if !addresses.is_empty() || mtu.is_some() {
+        let net = plt::Net::open().and_then(|net| {
+            if let Some(mtu) = mtu {
+                net.set_mtu(name.as_str(), mtu)?;
+            }
+            for &(addr, len) in addresses.iter() {
+                net.add_address(name.as_str(), addr, len)?;
+            }
+            net.set_link(name.as_str(), true)
+        });
+        if let Err(e) = net {
+            eprintln!("Failed to configure TUN device: {}", e);
+            exit(-3);
+        }
+    }
 
     // drop privileges
     if drop_privileges {
@@ -119,9 +319,7 @@ fn main() {
     }
 
     // start logging
-    env_logger::builder()
-        .try_init()
-        .expect("Failed to initialize event logger");
+    logging::init(log_sink).expect("Failed to initialize event logger");
 
     log::info!("Starting {} WireGuard device.", name);
 
@@ -130,7 +328,32 @@ fn main() {
     profiler_start(name.as_str());
 
     // create WireGuard device
-    let wg: WireGuard<plt::Tun, plt::UDP> = WireGuard::new(writer);
+    let wg: WireGuard<plt::Tun, plt::UDP> = WireGuard::with_config(writer, workers);
+    wg.set_name(name.as_str());
+    log::info!("Capabilities: {}", wg.capabilities());
+
+    // timestamp handshakes by the persisted clock (for systems without a trustworthy RTC)
+    // (the file must be writable after dropping privileges)
+    if let Some(path) = clock {
+        match PersistedClock::open(&path) {
+            Ok(clock) => wg.set_clock(Arc::new(clock)),
+            Err(e) => {
+                log::error!("Failed to open the clock {}: {}", path, e);
+                profiler_stop();
+                exit(-6);
+            }
+        }
+    }
+
+    // race the relay once the handshake over the endpoint of a peer fails
+    if let Some(relay) = relay {
+        wg.set_endpoint_discovery(Some(Arc::new(RelayDiscovery::new(relay))));
+    }
+
+    // permit the peers by the program (run with the public key of every peer added)
+    if let Some(program) = authorize {
+        wg.set_peer_authorization(Some(Arc::new(ExecAuthorization::new(program))));
+    }
 
     // add all Tun readers
     while let Some(reader) = readers.pop() {
@@ -139,6 +362,37 @@ fn main() {
 
     // wrap in configuration interface
     let cfg = configuration::WireGuardConfig::new(wg.clone());
+    let _ = cfg.set_bind_mode(mode); // not yet bound (cannot fail)
+
+    // restore the persisted configuration
+    // (the store must be writable after dropping privileges)
+    if let Some(path) = store {
+        let res = open_store(&path)
+            .map_err(Error::from)
+            .and_then(|store| cfg.set_store(Some(store)).map_err(Error::from));
+        if let Err(e) = res {
+            log::error!(
+                "Failed to restore the configuration from {}: {}",
+                path,
+                error::report(&e)
+            );
+            profiler_stop();
+            exit(-6);
+        }
+    }
+
+    // apply the imported configuration (over the restored configuration)
+    if let Some(quick) = import {
+        if let Err(e) = quick.apply(&cfg) {
+            log::error!(
+                "Failed to apply the imported configuration: {}",
+                error::report(&Error::from(e))
+            );
+            profiler_stop();
+            exit(-6);
+        }
+        let _ = cfg.persist();
+    }
 
     // start Tun event thread
     {
@@ -153,7 +407,12 @@ fn main() {
                 }
                 Ok(tun::TunEvent::Up(mtu)) => {
                     log::info!("Tun up (mtu = {})", mtu);
-                    let _ = cfg.up(mtu); // TODO: handle
+                    if let Err(e) = cfg.up(mtu) {
+                        log::error!(
+                            "Failed to set device up: {}",
+                            error::report(&Error::from(e))
+                        );
+                    }
                 }
                 Ok(tun::TunEvent::Down) => {
                     log::info!("Tun down");
@@ -183,5 +442,6 @@ fn main() {
 
     // block until all tun readers closed
     wg.wait();
+    wg.save_quotas();
     profiler_stop();
 }
@@ -0,0 +1,414 @@
+use super::super::net::*;
+
+use libc;
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::io::RawFd;
+use std::slice;
+use std::sync::Mutex;
+
+// ioctl request encoding (sys/ioccom.h)
+const IOC_IN: libc::c_ulong = 0x8000_0000;
+const IOC_OUT: libc::c_ulong = 0x4000_0000;
+const IOCPARM_MASK: libc::c_ulong = 0x1fff;
+
+const fn ioc(dir: libc::c_ulong, group: u8, num: u8, len: usize) -> libc::c_ulong {
+    dir | ((len as libc::c_ulong & IOCPARM_MASK) << 16)
+        | ((group as libc::c_ulong) << 8)
+        | num as libc::c_ulong
+}
+
+// Request codes from: sys/sockio.h
+const SIOCSIFFLAGS: libc::c_ulong = ioc(IOC_IN, b'i', 16, mem::size_of::<IfreqFlags>());
+const SIOCGIFFLAGS: libc::c_ulong = ioc(IOC_IN | IOC_OUT, b'i', 17, mem::size_of::<IfreqFlags>());
+const SIOCDIFADDR: libc::c_ulong = ioc(IOC_IN, b'i', 25, mem::size_of::<IfreqAddr>());
+const SIOCAIFADDR: libc::c_ulong = ioc(IOC_IN, b'i', 26, mem::size_of::<IfAliasReq>());
+const SIOCSIFMTU: libc::c_ulong = ioc(IOC_IN, b'i', 52, mem::size_of::<IfreqMtu>());
+
+// Sockaddrs in routing messages are padded (net/route.h: SA_SIZE)
+#[cfg(target_os = "macos")]
+const SA_ALIGN: usize = mem::size_of::<u32>();
+
+#[cfg(not(target_os = "macos"))]
+const SA_ALIGN: usize = mem::size_of::<libc::c_long>();
+
+// Variants of "struct ifreq" (32 bytes)
+
+#[repr(C)]
+struct IfreqFlags {
+    name: [u8; libc::IFNAMSIZ],
+    flags: libc::c_short,
+    _pad: [u8; 14],
+}
+
+#[repr(C)]
+struct IfreqMtu {
+    name: [u8; libc::IFNAMSIZ],
+    mtu: libc::c_int,
+    _pad: [u8; 12],
+}
+
+#[repr(C)]
+struct IfreqAddr {
+    name: [u8; libc::IFNAMSIZ],
+    addr: libc::sockaddr_in,
+}
+
+// Layout from: net/if.h
+#[repr(C)]
+struct IfAliasReq {
+    name: [u8; libc::IFNAMSIZ],
+    addr: libc::sockaddr_in,
+    broadaddr: libc::sockaddr_in,
+    mask: libc::sockaddr_in,
+    #[cfg(target_os = "freebsd")]
+    vhid: libc::c_int,
+}
+
+/// Configures interfaces using the routing socket (man 4 route) and interface ioctls
+///
+/// # Note
+///
+/// Assigning IPv6 addresses is not yet supported.
+pub struct BsdNet {
+    route: RawFd, // routing socket
+    inet: RawFd,  // socket for interface ioctls
+    seq: Mutex<libc::c_int>,
+}
+
+#[derive(Debug)]
+pub enum BsdNetError {
+    InvalidInterfaceName,
+    NoSuchInterface,
+    InvalidPrefix,
+    Unsupported,
+    SocketFailed(i32),  // errno when creating the sockets
+    IoctlFailed(i32),   // errno of the failed interface ioctl
+    RequestFailed(i32), // errno of the failed routing message
+}
+
+impl fmt::Display for BsdNetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BsdNetError::InvalidInterfaceName => write!(f, "Invalid interface name"),
+            BsdNetError::NoSuchInterface => write!(f, "No such interface"),
+            BsdNetError::InvalidPrefix => write!(f, "Prefix longer than address"),
+            BsdNetError::Unsupported => write!(f, "Operation not supported on this platform"),
+            BsdNetError::SocketFailed(errno) => {
+                write!(f, "Failed to create socket (errno = {})", errno)
+            }
+            BsdNetError::IoctlFailed(errno) => {
+                write!(f, "Interface ioctl failed (errno = {})", errno)
+            }
+            BsdNetError::RequestFailed(errno) => {
+                write!(f, "Routing request rejected (errno = {})", errno)
+            }
+        }
+    }
+}
+
+impl Error for BsdNetError {
+    fn description(&self) -> &str {
+        "Routing Socket Error"
+    }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+fn errno() -> i32 {
+    io::Error::last_os_error().raw_os_error().unwrap_or(0)
+}
+
+fn as_bytes<T>(v: &T) -> &[u8] {
+    unsafe { slice::from_raw_parts(v as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+fn ifname(name: &str) -> Result<[u8; libc::IFNAMSIZ], BsdNetError> {
+    let mut buf = [0u8; libc::IFNAMSIZ];
+    let bs = name.as_bytes();
+    if bs.len() > libc::IFNAMSIZ - 1 || bs.contains(&0) {
+        return Err(BsdNetError::InvalidInterfaceName);
+    }
+    buf[..bs.len()].copy_from_slice(bs);
+    Ok(buf)
+}
+
+fn ifindex(name: &[u8; libc::IFNAMSIZ]) -> Result<u16, BsdNetError> {
+    match unsafe { libc::if_nametoindex(name.as_ptr() as *const libc::c_char) } {
+        0 => Err(BsdNetError::NoSuchInterface),
+        idx => Ok(idx as u16),
+    }
+}
+
+fn sockaddr_v4(addr: Ipv4Addr) -> libc::sockaddr_in {
+    let mut sa: libc::sockaddr_in = unsafe { mem::zeroed() };
+    sa.sin_len = mem::size_of::<libc::sockaddr_in>() as u8;
+    sa.sin_family = libc::AF_INET as libc::sa_family_t;
+    sa.sin_addr.s_addr = u32::from_ne_bytes(addr.octets());
+    sa
+}
+
+fn sockaddr_v6(addr: Ipv6Addr) -> libc::sockaddr_in6 {
+    let mut sa: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+    sa.sin6_len = mem::size_of::<libc::sockaddr_in6>() as u8;
+    sa.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+    sa.sin6_addr.s6_addr = addr.octets();
+    sa
+}
+
+fn mask_v4(prefix: u8) -> Ipv4Addr {
+    match prefix {
+        0 => Ipv4Addr::from(0),
+        _ => Ipv4Addr::from(!0u32 << (32 - prefix as u32)),
+    }
+}
+
+fn mask_v6(prefix: u8) -> Ipv6Addr {
+    match prefix {
+        0 => Ipv6Addr::from(0),
+        _ => Ipv6Addr::from(!0u128 << (128 - prefix as u32)),
+    }
+}
+
+// Append a sockaddr to a routing message (padded)
+fn push_sockaddr<T>(msg: &mut Vec<u8>, sa: &T) {
+    msg.extend_from_slice(as_bytes(sa));
+    while msg.len() % SA_ALIGN != 0 {
+        msg.push(0);
+    }
+}
+
+impl Drop for BsdNet {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.route);
+            libc::close(self.inet);
+        }
+    }
+}
+
+impl BsdNet {
+    fn ioctl<T>(&self, request: libc::c_ulong, arg: &mut T) -> Result<(), BsdNetError> {
+        if unsafe { libc::ioctl(self.inet, request, arg as *mut T) } < 0 {
+            Err(BsdNetError::IoctlFailed(errno()))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn route(&self, ty: u8, name: &str, dst: IpAddr, prefix: u8) -> Result<(), BsdNetError> {
+        let index = ifindex(&ifname(name)?)?;
+
+        // a host route carries no netmask
+        let host = match dst {
+            IpAddr::V4(_) => prefix == 32,
+            IpAddr::V6(_) => prefix == 128,
+        };
+
+        // construct routing message: header, destination, gateway (the interface), netmask
+        let mut hdr: libc::rt_msghdr = unsafe { mem::zeroed() };
+        hdr.rtm_version = libc::RTM_VERSION as u8;
+        hdr.rtm_type = ty;
+        hdr.rtm_flags = libc::RTF_UP | libc::RTF_STATIC;
+        if host {
+            hdr.rtm_flags |= libc::RTF_HOST;
+        }
+        hdr.rtm_addrs = libc::RTA_DST | libc::RTA_GATEWAY;
+        if !host {
+            hdr.rtm_addrs |= libc::RTA_NETMASK;
+        }
+        hdr.rtm_seq = {
+            let mut seq = self.seq.lock().unwrap();
+            *seq = seq.wrapping_add(1);
+            *seq
+        };
+
+        let mut msg = Vec::with_capacity(256);
+        msg.extend_from_slice(as_bytes(&hdr));
+        while msg.len() % SA_ALIGN != 0 {
+            msg.push(0);
+        }
+
+        let mut link: libc::sockaddr_dl = unsafe { mem::zeroed() };
+        link.sdl_len = mem::size_of::<libc::sockaddr_dl>() as u8;
+        link.sdl_family = libc::AF_LINK as libc::sa_family_t;
+        link.sdl_index = index;
+
+        match dst {
+            IpAddr::V4(addr) => {
+                if prefix > 32 {
+                    return Err(BsdNetError::InvalidPrefix);
+                }
+                push_sockaddr(&mut msg, &sockaddr_v4(addr));
+                push_sockaddr(&mut msg, &link);
+                if !host {
+                    push_sockaddr(&mut msg, &sockaddr_v4(mask_v4(prefix)));
+                }
+            }
+            IpAddr::V6(addr) => {
+                if prefix > 128 {
+                    return Err(BsdNetError::InvalidPrefix);
+                }
+                push_sockaddr(&mut msg, &sockaddr_v6(addr));
+                push_sockaddr(&mut msg, &link);
+                if !host {
+                    push_sockaddr(&mut msg, &sockaddr_v6(mask_v6(prefix)));
+                }
+            }
+        }
+
+        // fill in the length and send: errors are reported by the write
+        let len = (msg.len() as u16).to_ne_bytes();
+        msg[..2].copy_from_slice(&len);
+        if unsafe { libc::write(self.route, msg.as_ptr() as _, msg.len()) } < 0 {
+            return Err(BsdNetError::RequestFailed(errno()));
+        }
+        Ok(())
+    }
+
+    fn address(
+        &self,
+        request: libc::c_ulong,
+        name: &str,
+        addr: IpAddr,
+        prefix: u8,
+    ) -> Result<(), BsdNetError> {
+        let name = ifname(name)?;
+        let addr = match addr {
+            IpAddr::V4(addr) => addr,
+            IpAddr::V6(_) => return Err(BsdNetError::Unsupported),
+        };
+        if prefix > 32 {
+            return Err(BsdNetError::InvalidPrefix);
+        }
+
+        if request == SIOCAIFADDR {
+            let mut req: IfAliasReq = unsafe { mem::zeroed() };
+            req.name = name;
+            req.addr = sockaddr_v4(addr);
+            req.mask = sockaddr_v4(mask_v4(prefix));
+            self.ioctl(request, &mut req)
+        } else {
+            let mut req = IfreqAddr {
+                name,
+                addr: sockaddr_v4(addr),
+            };
+            self.ioctl(request, &mut req)
+        }
+    }
+}
+
+impl Net for BsdNet {
+    type Error = BsdNetError;
+
+    fn open() -> Result<Self, Self::Error> {
+        let route = unsafe { libc::socket(libc::PF_ROUTE, libc::SOCK_RAW, libc::AF_UNSPEC) };
+        if route < 0 {
+            return Err(BsdNetError::SocketFailed(errno()));
+        }
+
+        // replies are never read: routing errors are reported by write
+        unsafe { libc::shutdown(route, libc::SHUT_RD) };
+
+        let inet = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+        if inet < 0 {
+            let err = errno();
+            unsafe { libc::close(route) };
+            return Err(BsdNetError::SocketFailed(err));
+        }
+
+        Ok(BsdNet {
+            route,
+            inet,
+            seq: Mutex::new(0),
+        })
+    }
+
+    fn add_address(&self, name: &str, addr: IpAddr, prefix: u8) -> Result<(), Self::Error> {
+        log::debug!("route socket, add address {}/{} to {}", addr, prefix, name);
+        self.address(SIOCAIFADDR, name, addr, prefix)
+    }
+
+    fn remove_address(&self, name: &str, addr: IpAddr, prefix: u8) -> Result<(), Self::Error> {
+        log::debug!(
+            "route socket, remove address {}/{} from {}",
+            addr,
+            prefix,
+            name
+        );
+        self.address(SIOCDIFADDR, name, addr, prefix)
+    }
+
+    fn add_route(&self, name: &str, dst: IpAddr, prefix: u8) -> Result<(), Self::Error> {
+        log::debug!("route socket, add route {}/{} via {}", dst, prefix, name);
+        self.route(libc::RTM_ADD as u8, name, dst, prefix)
+    }
+
+    fn remove_route(&self, name: &str, dst: IpAddr, prefix: u8) -> Result<(), Self::Error> {
+        log::debug!("route socket, remove route {}/{} via {}", dst, prefix, name);
+        self.route(libc::RTM_DELETE as u8, name, dst, prefix)
+    }
+
+    fn set_link(&self, name: &str, up: bool) -> Result<(), Self::Error> {
+        log::debug!(
+            "route socket, set {} {}",
+            name,
+            if up { "up" } else { "down" }
+        );
+        let mut req = IfreqFlags {
+            name: ifname(name)?,
+            flags: 0,
+            _pad: [0u8; 14],
+        };
+        self.ioctl(SIOCGIFFLAGS, &mut req)?;
+        if up {
+            req.flags |= libc::IFF_UP as libc::c_short;
+        } else {
+            req.flags &= !(libc::IFF_UP as libc::c_short);
+        }
+        self.ioctl(SIOCSIFFLAGS, &mut req)
+    }
+
+    fn set_mtu(&self, name: &str, mtu: usize) -> Result<(), Self::Error> {
+        log::debug!("route socket, set mtu of {} to {}", name, mtu);
+        let mut req = IfreqMtu {
+            name: ifname(name)?,
+            mtu: mtu as libc::c_int,
+            _pad: [0u8; 12],
+        };
+        self.ioctl(SIOCSIFMTU, &mut req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_masks() {
+        assert_eq!(mask_v4(0), Ipv4Addr::new(0, 0, 0, 0));
+        assert_eq!(mask_v4(20), Ipv4Addr::new(255, 255, 240, 0));
+        assert_eq!(mask_v4(32), Ipv4Addr::new(255, 255, 255, 255));
+        assert_eq!(mask_v6(0), "::".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(
+            mask_v6(64),
+            "ffff:ffff:ffff:ffff::".parse::<Ipv6Addr>().unwrap()
+        );
+        assert_eq!(mask_v6(128), Ipv6Addr::from(!0u128));
+    }
+
+    #[test]
+    fn test_push_sockaddr() {
+        let mut msg = vec![0u8; 3];
+        push_sockaddr(&mut msg, &sockaddr_v4(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(msg.len() % SA_ALIGN, 0);
+        assert_eq!(msg[3], mem::size_of::<libc::sockaddr_in>() as u8);
+        assert_eq!(msg[4], libc::AF_INET as u8);
+    }
+}
@@ -0,0 +1,450 @@
+/* In-process chaining of two WireGuard devices (multi-hop):
+ *
+ * The transport messages of the inner device are wrapped in IP/UDP headers
+ * and read by the outer device as inner packets (in place of a TUN device),
+ * while the inner packets of the outer device addressed to the inner device
+ * are unwrapped and read by the inner device as datagrams (in place of UDP sockets).
+ *
+ * e.g. for a path over two hops:
+ *
+ *   let (bind, tun) = chain::pair(local);
+ *   let outer: WireGuard<chain::ChainTun, plt::UDP> = WireGuard::new(tun.1);
+ *   outer.add_tun_reader(tun.0);
+ *   inner.set_writer(bind.1);
+ *   inner.add_udp_reader(bind.0);
+ *
+ * Where `local` is the address (and port) of the inner device inside the outer tunnel.
+ * The MTU of the inner device should be at most the MTU of the outer device less CHAIN_OVERHEAD.
+ */
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use byteorder::{BigEndian, ByteOrder};
+use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
+use log;
+
+use super::tun;
+use super::udp;
+use super::Endpoint;
+
+// bound on the number of packets in transit between the devices (further packets are dropped)
+const CHAIN_QUEUE_SIZE: usize = 1024;
+
+const SIZE_IP4_HEADER: usize = 20;
+const SIZE_IP6_HEADER: usize = 40;
+const SIZE_UDP_HEADER: usize = 8;
+
+const PROTOCOL_UDP: u8 = 17;
+const DEFAULT_TTL: u8 = 64;
+
+/// Largest overhead of wrapping a transport message of the inner device
+/// (IPv6 and UDP headers of the wrapping, transport header and tag of the inner message)
+pub const CHAIN_OVERHEAD: usize = SIZE_IP6_HEADER + SIZE_UDP_HEADER + 16 + 16;
+
+#[derive(Debug)]
+pub enum ChainError {
+    Closed,   // the other device is gone
+    Full,     // too many packets in transit
+    Mismatch, // the destination and source address families differ
+}
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainError::Closed => write!(f, "Chained device closed"),
+            ChainError::Full => write!(f, "Chain queue full"),
+            ChainError::Mismatch => write!(f, "Destination not reachable from chain address"),
+        }
+    }
+}
+
+impl Error for ChainError {
+    fn description(&self) -> &str {
+        "Chain Error"
+    }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+impl tun::ReadError for ChainError {
+    fn kind(&self) -> tun::ReadErrorKind {
+        tun::ReadErrorKind::Closed
+    }
+}
+
+impl udp::ReadError for ChainError {
+    fn kind(&self) -> udp::ReadErrorKind {
+        udp::ReadErrorKind::Closed
+    }
+}
+
+impl udp::WriteError for ChainError {
+    fn kind(&self) -> udp::WriteErrorKind {
+        match self {
+            ChainError::Closed => udp::WriteErrorKind::Other,
+            ChainError::Full => udp::WriteErrorKind::NoBuffers,
+            ChainError::Mismatch => udp::WriteErrorKind::Unreachable,
+        }
+    }
+}
+
+/// Endpoint of a peer of the inner device (reached through the outer device)
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ChainEndpoint(SocketAddr);
+
+impl Endpoint for ChainEndpoint {
+    fn from_address(addr: SocketAddr) -> Self {
+        ChainEndpoint(addr)
+    }
+
+    fn into_address(&self) -> SocketAddr {
+        self.0
+    }
+
+    fn clear_src(&mut self) {}
+
+    // the source is always the chain address
+    fn set_src(&mut self, _ifindex: u32, _addr: Option<IpAddr>) {}
+
+    // the hop limit is that of the outer device
+    fn set_hop_limit(&mut self, _hops: Option<u8>) {}
+}
+
+/* Bind (used by the inner device) */
+
+pub struct ChainBind {}
+
+pub struct ChainBindReader {
+    rx: Receiver<(Vec<u8>, SocketAddr)>,
+}
+
+pub struct ChainBindWriter {
+    local: SocketAddr,
+    tx: Sender<Vec<u8>>,
+}
+
+impl udp::UDP for ChainBind {
+    type Error = ChainError;
+    type Endpoint = ChainEndpoint;
+    type Reader = ChainBindReader;
+    type Writer = ChainBindWriter;
+}
+
+impl udp::Reader<ChainEndpoint> for ChainBindReader {
+    type Error = ChainError;
+
+    fn read(&self, buf: &mut [u8]) -> Result<(usize, ChainEndpoint), Self::Error> {
+        let (msg, src) = self.rx.recv().map_err(|_| ChainError::Closed)?;
+        let len = msg.len().min(buf.len());
+        buf[..len].copy_from_slice(&msg[..len]);
+        Ok((len, ChainEndpoint(src)))
+    }
+}
+
+impl udp::Writer<ChainEndpoint> for ChainBindWriter {
+    type Error = ChainError;
+
+    fn write(&self, buf: &[u8], dst: &mut ChainEndpoint) -> Result<(), Self::Error> {
+        let packet = wrap(self.local, dst.0, buf).ok_or(ChainError::Mismatch)?;
+        self.tx.try_send(packet).map_err(|e| match e {
+            TrySendError::Full(_) => ChainError::Full,
+            TrySendError::Disconnected(_) => ChainError::Closed,
+        })
+    }
+}
+
+/* Tun (used by the outer device) */
+
+pub struct ChainTun {}
+
+pub struct ChainTunReader {
+    rx: Receiver<Vec<u8>>,
+}
+
+pub struct ChainTunWriter {
+    local: SocketAddr,
+    tx: Sender<(Vec<u8>, SocketAddr)>,
+}
+
+impl tun::Tun for ChainTun {
+    type Writer = ChainTunWriter;
+    type Reader = ChainTunReader;
+    type Error = ChainError;
+}
+
+impl tun::Reader for ChainTunReader {
+    type Error = ChainError;
+
+    fn read(&self, buf: &mut [u8], offset: usize) -> Result<usize, Self::Error> {
+        let packet = self.rx.recv().map_err(|_| ChainError::Closed)?;
+        let len = packet.len().min(buf.len() - offset);
+        buf[offset..offset + len].copy_from_slice(&packet[..len]);
+        Ok(len)
+    }
+}
+
+impl tun::Writer for ChainTunWriter {
+    type Error = ChainError;
+
+    fn write(&self, src: &[u8]) -> Result<(), Self::Error> {
+        match unwrap(self.local, src) {
+            Some((payload, from)) => {
+                // a full queue is not an error of the outer device (datagrams may be lost)
+                match self.tx.try_send((payload.to_vec(), from)) {
+                    Err(TrySendError::Disconnected(_)) => Err(ChainError::Closed),
+                    _ => Ok(()),
+                }
+            }
+            None => {
+                log::trace!("chain: dropping packet not addressed to the inner device");
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Creates the bind of the inner device and the TUN device of the outer device
+///
+/// # Arguments
+///
+/// - `local`: The address (and port) of the inner device inside the outer tunnel
+///
+/// # Returns
+///
+/// The (reader, writer) of the bind and the (reader, writer) of the TUN device
+pub fn pair(
+    local: SocketAddr,
+) -> (
+    (ChainBindReader, ChainBindWriter),
+    (ChainTunReader, ChainTunWriter),
+) {
+    let (tx_out, rx_out) = bounded(CHAIN_QUEUE_SIZE);
+    let (tx_in, rx_in) = bounded(CHAIN_QUEUE_SIZE);
+    (
+        (
+            ChainBindReader { rx: rx_in },
+            ChainBindWriter { local, tx: tx_out },
+        ),
+        (
+            ChainTunReader { rx: rx_out },
+            ChainTunWriter { local, tx: tx_in },
+        ),
+    )
+}
+
+// one's complement sum of the big-endian 16-bit words (odd lengths padded with zero)
+fn sum(data: &[u8], mut acc: u32) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for word in &mut chunks {
+        acc += BigEndian::read_u16(word) as u32;
+    }
+    if let Some(&last) = chunks.remainder().first() {
+        acc += (last as u32) << 8;
+    }
+    acc
+}
+
+// folds and complements a one's complement sum
+fn checksum(mut acc: u32) -> u16 {
+    while acc >> 16 != 0 {
+        acc = (acc & 0xffff) + (acc >> 16);
+    }
+    !(acc as u16)
+}
+
+// appends the UDP header and payload, the checksum covers the pseudo-header
+fn write_udp(packet: &mut Vec<u8>, src: SocketAddr, dst: SocketAddr, payload: &[u8], acc: u32) {
+    let start = packet.len();
+    let len = (SIZE_UDP_HEADER + payload.len()) as u16;
+    let mut header = [0u8; SIZE_UDP_HEADER];
+    BigEndian::write_u16(&mut header[0..2], src.port());
+    BigEndian::write_u16(&mut header[2..4], dst.port());
+    BigEndian::write_u16(&mut header[4..6], len);
+    packet.extend(&header);
+    packet.extend(payload);
+
+    let acc = sum(&packet[start..], acc + len as u32 + PROTOCOL_UDP as u32);
+    let csum = match checksum(acc) {
+        0 => 0xffff, // zero denotes "no checksum"
+        csum => csum,
+    };
+    BigEndian::write_u16(&mut packet[start + 6..start + 8], csum);
+}
+
+/// Wraps a datagram in IP/UDP headers (None if the address families differ)
+fn wrap(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Option<Vec<u8>> {
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            let total = SIZE_IP4_HEADER + SIZE_UDP_HEADER + payload.len();
+            let mut packet = Vec::with_capacity(total);
+            packet.extend(&[
+                0x45,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0x40,
+                0,
+                DEFAULT_TTL,
+                PROTOCOL_UDP,
+                0,
+                0,
+            ]);
+            BigEndian::write_u16(&mut packet[2..4], total as u16);
+            packet.extend(&s.octets());
+            packet.extend(&d.octets());
+            let csum = checksum(sum(&packet[..SIZE_IP4_HEADER], 0));
+            BigEndian::write_u16(&mut packet[10..12], csum);
+            let pseudo = sum(&packet[12..20], 0);
+            write_udp(&mut packet, src, dst, payload, pseudo);
+            Some(packet)
+        }
+        (IpAddr::V6(s), IpAddr::V6(d)) => {
+            let len = SIZE_UDP_HEADER + payload.len();
+            let mut packet = Vec::with_capacity(SIZE_IP6_HEADER + len);
+            packet.extend(&[0x60, 0, 0, 0, 0, 0, PROTOCOL_UDP, DEFAULT_TTL]);
+            BigEndian::write_u16(&mut packet[4..6], len as u16);
+            packet.extend(&s.octets());
+            packet.extend(&d.octets());
+            let pseudo = sum(&packet[8..40], 0);
+            write_udp(&mut packet, src, dst, payload, pseudo);
+            Some(packet)
+        }
+        _ => None,
+    }
+}
+
+/// Unwraps a UDP datagram addressed to the local address,
+/// returns the payload and the source of the datagram.
+fn unwrap(local: SocketAddr, packet: &[u8]) -> Option<(&[u8], SocketAddr)> {
+    let (src, dst, protocol, body): (IpAddr, IpAddr, u8, &[u8]) = match packet.first()? >> 4 {
+        4 => {
+            let ihl = ((packet[0] & 0xf) as usize) * 4;
+            if packet.len() < SIZE_IP4_HEADER || ihl < SIZE_IP4_HEADER {
+                return None;
+            }
+            let total = BigEndian::read_u16(&packet[2..4]) as usize;
+            if total > packet.len() || total < ihl {
+                return None;
+            }
+            let mut s = [0u8; 4];
+            let mut d = [0u8; 4];
+            s.copy_from_slice(&packet[12..16]);
+            d.copy_from_slice(&packet[16..20]);
+
+            // fragments are not reassembled
+            if BigEndian::read_u16(&packet[6..8]) & 0x3fff != 0 {
+                return None;
+            }
+            (
+                Ipv4Addr::from(s).into(),
+                Ipv4Addr::from(d).into(),
+                packet[9],
+                &packet[ihl..total],
+            )
+        }
+        6 => {
+            // extension headers are not supported
+            if packet.len() < SIZE_IP6_HEADER {
+                return None;
+            }
+            let total = SIZE_IP6_HEADER + BigEndian::read_u16(&packet[4..6]) as usize;
+            if total > packet.len() {
+                return None;
+            }
+            let mut s = [0u8; 16];
+            let mut d = [0u8; 16];
+            s.copy_from_slice(&packet[8..24]);
+            d.copy_from_slice(&packet[24..40]);
+            (
+                Ipv6Addr::from(s).into(),
+                Ipv6Addr::from(d).into(),
+                packet[6],
+                &packet[SIZE_IP6_HEADER..total],
+            )
+        }
+        _ => return None,
+    };
+
+    if protocol != PROTOCOL_UDP || dst != local.ip() || body.len() < SIZE_UDP_HEADER {
+        return None;
+    }
+    let len = BigEndian::read_u16(&body[4..6]) as usize;
+    if BigEndian::read_u16(&body[2..4]) != local.port() || len < SIZE_UDP_HEADER || len > body.len()
+    {
+        return None;
+    }
+    let sport = BigEndian::read_u16(&body[0..2]);
+    Some((&body[SIZE_UDP_HEADER..len], SocketAddr::new(src, sport)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap_unwrap() {
+        for (local, remote) in [
+            ("10.0.0.2:51820", "192.0.2.1:51820"),
+            ("[fd00::2]:51820", "[2001:db8::1]:51820"),
+        ]
+        .iter()
+        {
+            let local: SocketAddr = local.parse().unwrap();
+            let remote: SocketAddr = remote.parse().unwrap();
+
+            // a reply from the remote is unwrapped (padding is ignored)
+            let mut packet = wrap(remote, local, b"message").unwrap();
+            packet.extend(&[0u8; 9]);
+            let (payload, src) = unwrap(local, &packet).unwrap();
+            assert_eq!(payload, b"message");
+            assert_eq!(src, remote);
+
+            // packets to other addresses are not
+            let packet = wrap(local, remote, b"message").unwrap();
+            assert!(unwrap(local, &packet).is_none());
+        }
+    }
+
+    #[test]
+    fn test_checksums() {
+        let local: SocketAddr = "10.0.0.2:51820".parse().unwrap();
+        let remote: SocketAddr = "192.0.2.1:4500".parse().unwrap();
+        let packet = wrap(local, remote, b"odd").unwrap();
+
+        // valid checksums sum to zero
+        assert_eq!(checksum(sum(&packet[..SIZE_IP4_HEADER], 0)), 0);
+        let udp = &packet[SIZE_IP4_HEADER..];
+        let acc = sum(&packet[12..20], 0) + udp.len() as u32 + PROTOCOL_UDP as u32;
+        assert_eq!(checksum(sum(udp, acc)), 0);
+
+        assert!(wrap(local, "[::1]:1".parse().unwrap(), b"").is_none());
+    }
+
+    #[test]
+    fn test_pair() {
+        let local: SocketAddr = "10.0.0.2:51820".parse().unwrap();
+        let remote: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let ((bind_reader, bind_writer), (tun_reader, tun_writer)) = pair(local);
+
+        // inner device -> outer device
+        let mut dst = ChainEndpoint::from_address(remote);
+        udp::Writer::write(&bind_writer, b"initiation", &mut dst).unwrap();
+        let mut buf = vec![0u8; 128];
+        let len = tun::Reader::read(&tun_reader, &mut buf, 16).unwrap();
+        assert_eq!(
+            &buf[16..16 + len],
+            &wrap(local, remote, b"initiation").unwrap()[..]
+        );
+
+        // outer device -> inner device
+        tun::Writer::write(&tun_writer, &wrap(remote, local, b"response").unwrap()).unwrap();
+        let (len, src) = udp::Reader::read(&bind_reader, &mut buf).unwrap();
+        assert_eq!(&buf[..len], b"response");
+        assert_eq!(src.into_address(), remote);
+    }
+}
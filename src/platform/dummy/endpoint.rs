@@ -1,8 +1,8 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 
 use super::super::Endpoint;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
 pub struct UnitEndpoint {}
 
 impl Endpoint for UnitEndpoint {
@@ -15,6 +15,10 @@ impl Endpoint for UnitEndpoint {
     }
 
     fn clear_src(&mut self) {}
+
+    fn set_src(&mut self, _ifindex: u32, _addr: Option<IpAddr>) {}
+
+    fn set_hop_limit(&mut self, _hops: Option<u8>) {}
 }
 
 impl UnitEndpoint {
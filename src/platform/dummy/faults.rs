@@ -0,0 +1,124 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// type of transport messages (corrupted in transit)
+const TYPE_TRANSPORT: u8 = 4;
+
+/* Programmable failures of the dummy platform:
+ *
+ * A handle shared by the readers / writers created with it (see PairBind::pair_with and
+ * TunTest::create_with), hence failures can be enabled / disabled while the device runs,
+ * e.g. once the handshake has completed.
+ */
+#[derive(Clone, Default)]
+pub struct Faults(Arc<FaultsInner>);
+
+#[derive(Default)]
+struct FaultsInner {
+    write_error_every: AtomicUsize, // every Nth UDP write fails (0 = never)
+    corrupt_every: AtomicUsize,     // every Nth transport message is corrupted (0 = never)
+    read_stall: AtomicU64,          // TUN reads stall for this long (in milliseconds)
+
+    writes: AtomicUsize,
+    transports: AtomicUsize,
+    write_errors: AtomicUsize,
+    corrupted: AtomicUsize,
+}
+
+impl Faults {
+    /// Creates a handle without failures
+    pub fn new() -> Faults {
+        Faults::default()
+    }
+
+    /// Fails every Nth write to the UDP transport (0 disables the failure)
+    pub fn fail_writes(&self, every: usize) {
+        self.0.write_error_every.store(every, Ordering::SeqCst);
+    }
+
+    /// Corrupts every Nth transport message written (0 disables the failure),
+    /// which fails to decrypt at the receiver
+    pub fn corrupt_messages(&self, every: usize) {
+        self.0.corrupt_every.store(every, Ordering::SeqCst);
+    }
+
+    /// Stalls every read from the TUN device (0 disables the failure)
+    pub fn stall_reads(&self, stall: Duration) {
+        self.0
+            .read_stall
+            .store(stall.as_millis() as u64, Ordering::SeqCst);
+    }
+
+    /// Returns the number of failed writes so far
+    pub fn write_errors(&self) -> usize {
+        self.0.write_errors.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of corrupted messages so far
+    pub fn corrupted(&self) -> usize {
+        self.0.corrupted.load(Ordering::SeqCst)
+    }
+
+    fn nth(counter: &AtomicUsize, every: &AtomicUsize) -> bool {
+        let every = every.load(Ordering::SeqCst);
+        let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+        every > 0 && n % every == 0
+    }
+
+    // returns true if the write should fail
+    pub(super) fn write_fails(&self) -> bool {
+        let fails = Self::nth(&self.0.writes, &self.0.write_error_every);
+        if fails {
+            self.0.write_errors.fetch_add(1, Ordering::SeqCst);
+        }
+        fails
+    }
+
+    // corrupts the tag of the message (if a transport message due for corruption)
+    pub(super) fn corrupt(&self, msg: &mut [u8]) {
+        if msg.first() != Some(&TYPE_TRANSPORT) {
+            return;
+        }
+        if Self::nth(&self.0.transports, &self.0.corrupt_every) {
+            if let Some(last) = msg.last_mut() {
+                *last ^= 0xff;
+                self.0.corrupted.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    pub(super) fn stall(&self) {
+        let stall = self.0.read_stall.load(Ordering::SeqCst);
+        if stall > 0 {
+            thread::sleep(Duration::from_millis(stall));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_faults() {
+        let faults = Faults::new();
+        assert!(!(0..10).any(|_| faults.write_fails()));
+
+        faults.fail_writes(3);
+        let fails: Vec<bool> = (0..6).map(|_| faults.write_fails()).collect();
+        assert_eq!(fails.iter().filter(|fails| **fails).count(), 2);
+        assert_eq!(faults.write_errors(), 2);
+
+        // only transport messages are corrupted
+        faults.corrupt_messages(1);
+        let mut handshake = [1u8, 0, 0, 0, 7];
+        faults.corrupt(&mut handshake);
+        assert_eq!(handshake, [1u8, 0, 0, 0, 7]);
+        let mut transport = [4u8, 0, 0, 0, 7];
+        faults.corrupt(&mut transport);
+        assert_eq!(transport, [4u8, 0, 0, 0, 0xf8]);
+        assert_eq!(faults.corrupted(), 1);
+    }
+}
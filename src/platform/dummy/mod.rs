@@ -1,13 +1,16 @@
 mod endpoint;
+mod faults;
 mod tun;
 mod udp;
 
 /* A pure dummy platform available during "test-time"
+ * (and to crates using the wireguard_rs library with the "dummy" feature, see WireGuard::new_dummy)
  *
  * The use of the dummy platform is to enable unit testing of full WireGuard,
  * the configuration interface and the UAPI parser.
  */
 
 pub use endpoint::*;
+pub use faults::Faults;
 pub use tun::*;
 pub use udp::*;
@@ -7,6 +7,7 @@
 //
 // Can be executed.
 
+use super::super::Faults;
 use super::*;
 
 use std::cmp::min;
@@ -37,6 +38,7 @@ pub struct TunFakeIO {
 pub struct TunReader {
     id: u32,
     rx: Receiver<Vec<u8>>,
+    faults: Faults,
 }
 
 pub struct TunWriter {
@@ -83,10 +85,19 @@ impl fmt::Display for TunError {
     }
 }
 
+impl ReadError for TunError {
+    fn kind(&self) -> ReadErrorKind {
+        match self {
+            TunError::Disconnected => ReadErrorKind::Closed,
+        }
+    }
+}
+
 impl Reader for TunReader {
     type Error = TunError;
 
     fn read(&self, buf: &mut [u8], offset: usize) -> Result<usize, Self::Error> {
+        self.faults.stall();
         match self.rx.recv() {
             Ok(msg) => {
                 let n = min(buf.len() - offset, msg.len());
@@ -157,10 +168,23 @@ impl TunFakeIO {
     pub fn read(&self) -> Vec<u8> {
         self.rx.recv().unwrap()
     }
+
+    /// Reads a packet written by the device, None if none is written within the timeout
+    pub fn read_timeout(&self, timeout: Duration) -> Option<Vec<u8>> {
+        self.rx.recv_timeout(timeout).ok()
+    }
 }
 
 impl TunTest {
     pub fn create(store: bool) -> (TunFakeIO, TunReader, TunWriter, TunStatus) {
+        Self::create_with(store, &Faults::new())
+    }
+
+    /// Creates a TUN device with failures injected into the reader (see Faults::stall_reads)
+    pub fn create_with(
+        store: bool,
+        faults: &Faults,
+    ) -> (TunFakeIO, TunReader, TunWriter, TunStatus) {
         let (tx1, rx1) = if store {
             sync_channel(32)
         } else {
@@ -180,7 +204,11 @@ impl TunTest {
             rx: rx2,
             store,
         };
-        let reader = TunReader { id, rx: rx1 };
+        let reader = TunReader {
+            id,
+            rx: rx1,
+            faults: faults.clone(),
+        };
         let writer = TunWriter {
             id,
             tx: Mutex::new(tx2),
@@ -194,7 +222,10 @@ impl TunTest {
 impl PlatformTun for TunTest {
     type Status = TunStatus;
 
-    fn create(_name: &str) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Status), Self::Error> {
+    fn create_with(
+        _name: &str,
+        _options: &TunOptions,
+    ) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Status), Self::Error> {
         Err(TunError::Disconnected)
     }
 }
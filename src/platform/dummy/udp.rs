@@ -13,13 +13,14 @@ use std::sync::Mutex;
 
 use super::super::udp::*;
 
-use super::UnitEndpoint;
+use super::{Faults, UnitEndpoint};
 
 pub struct VoidOwner {}
 
 #[derive(Debug)]
 pub enum BindError {
     Disconnected,
+    Injected, // failure injected (see Faults)
 }
 
 impl Error for BindError {
@@ -36,11 +37,27 @@ impl fmt::Display for BindError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             BindError::Disconnected => write!(f, "PairBind disconnected"),
+            BindError::Injected => write!(f, "PairBind failure injected"),
         }
     }
 }
 
-#[derive(Clone, Copy)]
+impl ReadError for BindError {
+    fn kind(&self) -> ReadErrorKind {
+        match self {
+            BindError::Disconnected => ReadErrorKind::Closed,
+            BindError::Injected => ReadErrorKind::Transient,
+        }
+    }
+}
+
+impl WriteError for BindError {
+    fn kind(&self) -> WriteErrorKind {
+        WriteErrorKind::Other
+    }
+}
+
+#[derive(Clone, Copy, Default)]
 pub struct VoidBind {}
 
 impl Reader<UnitEndpoint> for VoidBind {
@@ -112,7 +129,12 @@ impl Writer<UnitEndpoint> for PairWriter<UnitEndpoint> {
             buf.len(),
             hex::encode(buf)
         );
-        let owned = buf.to_owned();
+        if self.faults.write_fails() {
+            debug!("dummy({}): write failure injected", self.id);
+            return Err(BindError::Injected);
+        }
+        let mut owned = buf.to_owned();
+        self.faults.corrupt(&mut owned);
         match self.send.lock().unwrap().send(owned) {
             Err(_) => Err(BindError::Disconnected),
             Ok(_) => Ok(()),
@@ -123,6 +145,7 @@ impl Writer<UnitEndpoint> for PairWriter<UnitEndpoint> {
 #[derive(Clone)]
 pub struct PairWriter<E> {
     id: u32,
+    faults: Faults,
     send: Arc<Mutex<SyncSender<Vec<u8>>>>,
     _marker: marker::PhantomData<E>,
 }
@@ -134,6 +157,16 @@ impl PairBind {
     pub fn pair<E>() -> (
         (PairReader<E>, PairWriter<E>),
         (PairReader<E>, PairWriter<E>),
+    ) {
+        Self::pair_with(&Faults::new())
+    }
+
+    /// Creates a pair with failures injected into the writers of both ends
+    pub fn pair_with<E>(
+        faults: &Faults,
+    ) -> (
+        (PairReader<E>, PairWriter<E>),
+        (PairReader<E>, PairWriter<E>),
     ) {
         let id1: u32 = OsRng.gen();
         let id2: u32 = OsRng.gen();
@@ -149,6 +182,7 @@ impl PairBind {
                 },
                 PairWriter {
                     id: id1,
+                    faults: faults.clone(),
                     send: Arc::new(Mutex::new(tx2)),
                     _marker: marker::PhantomData,
                 },
@@ -161,6 +195,7 @@ impl PairBind {
                 },
                 PairWriter {
                     id: id2,
+                    faults: faults.clone(),
                     send: Arc::new(Mutex::new(tx1)),
                     _marker: marker::PhantomData,
                 },
@@ -179,18 +214,25 @@ impl UDP for PairBind {
 impl Owner for VoidOwner {
     type Error = BindError;
 
-    fn set_fwmark(&mut self, _value: Option<u32>) -> Result<(), Self::Error> {
+    fn set_options(&mut self, _opts: &TransportOptions) -> Result<(), Self::Error> {
         Ok(())
     }
 
     fn get_port(&self) -> u16 {
         0
     }
+
+    fn get_ports(&self) -> BoundPorts {
+        BoundPorts::default()
+    }
 }
 
 impl PlatformUDP for PairBind {
     type Owner = VoidOwner;
-    fn bind(_port: u16) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Owner), Self::Error> {
+    fn bind_with(
+        _port: u16,
+        _opts: &BindOptions,
+    ) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Owner), Self::Error> {
         Err(BindError::Disconnected)
     }
 }
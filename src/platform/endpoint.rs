@@ -1,7 +1,28 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 
-pub trait Endpoint: Send + 'static {
+/// Endpoints are equal if both the address and the source (if set) are equal,
+/// the hop limit is ignored (it is applied when sending).
+pub trait Endpoint: Send + PartialEq + 'static {
     fn from_address(addr: SocketAddr) -> Self;
     fn into_address(&self) -> SocketAddr;
     fn clear_src(&mut self);
+
+    /// Sets the source of messages sent to the endpoint
+    ///
+    /// # Arguments
+    ///
+    /// - `ifindex`: Index of the outgoing interface (0 = selected by the routing table)
+    /// - `addr`: Source address (None = selected by the routing table),
+    ///           ignored if the address family does not match the endpoint.
+    ///
+    /// A pinned source is not replaced if sending from it fails,
+    /// i.e. the message is dropped rather than sent from another source.
+    fn set_src(&mut self, ifindex: u32, addr: Option<IpAddr>);
+
+    /// Sets the hop limit (IPv4 TTL) of messages sent to the endpoint
+    ///
+    /// # Arguments
+    ///
+    /// - `hops`: The hop limit (None = the hop limit of the socket)
+    fn set_hop_limit(&mut self, hops: Option<u8>);
 }
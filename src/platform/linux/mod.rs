@@ -1,7 +1,9 @@
+mod net;
 mod tun;
 mod uapi;
 mod udp;
 
+pub use net::LinuxNet as Net;
 pub use tun::LinuxTun as Tun;
 pub use uapi::LinuxUAPI as UAPI;
 pub use udp::LinuxUDP as UDP;
@@ -0,0 +1,467 @@
+use super::super::net::*;
+use super::tun::IfInfomsg;
+
+use libc;
+
+use std::cmp;
+use std::error::Error;
+use std::ffi::CString;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::net::IpAddr;
+use std::os::unix::io::RawFd;
+use std::slice;
+use std::sync::Mutex;
+
+// Layouts and constants from:
+// https://elixir.bootlin.com/linux/latest/source/include/uapi/linux/if_addr.h
+// https://elixir.bootlin.com/linux/latest/source/include/uapi/linux/rtnetlink.h
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+const IFLA_IFNAME: u16 = 3;
+const IFLA_MTU: u16 = 4;
+const RTA_DST: u16 = 1;
+const RTA_OIF: u16 = 4;
+const RT_TABLE_MAIN: u8 = 254;
+const RTPROT_BOOT: u8 = 3;
+const RT_SCOPE_UNIVERSE: u8 = 0;
+const RT_SCOPE_LINK: u8 = 253;
+const RT_SCOPE_NOWHERE: u8 = 255;
+const RTN_UNICAST: u8 = 1;
+
+const HDR_SIZE: usize = mem::size_of::<libc::nlmsghdr>();
+
+#[repr(C)]
+struct IfAddrmsg {
+    ifa_family: libc::c_uchar,
+    ifa_prefixlen: libc::c_uchar,
+    ifa_flags: libc::c_uchar,
+    ifa_scope: libc::c_uchar,
+    ifa_index: libc::c_uint,
+}
+
+#[repr(C)]
+struct Rtmsg {
+    rtm_family: libc::c_uchar,
+    rtm_dst_len: libc::c_uchar,
+    rtm_src_len: libc::c_uchar,
+    rtm_tos: libc::c_uchar,
+    rtm_table: libc::c_uchar,
+    rtm_protocol: libc::c_uchar,
+    rtm_scope: libc::c_uchar,
+    rtm_type: libc::c_uchar,
+    rtm_flags: libc::c_uint,
+}
+
+/// Configures interfaces using rtnetlink (man 7 rtnetlink)
+pub struct LinuxNet {
+    fd: RawFd,
+    seq: Mutex<u32>, // serializes requests (and matches the acknowledgements)
+}
+
+#[derive(Debug)]
+pub enum LinuxNetError {
+    InvalidInterfaceName,
+    NoSuchInterface,
+    InvalidPrefix,
+    SocketFailed(i32),  // errno when creating the netlink socket
+    SendFailed(i32),    // errno when sending the request
+    RecvFailed(i32),    // errno when receiving the acknowledgement
+    RequestFailed(i32), // errno returned by the kernel
+    MalformedResponse,
+}
+
+impl fmt::Display for LinuxNetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinuxNetError::InvalidInterfaceName => write!(f, "Invalid interface name"),
+            LinuxNetError::NoSuchInterface => write!(f, "No such interface"),
+            LinuxNetError::InvalidPrefix => write!(f, "Prefix longer than address"),
+            LinuxNetError::SocketFailed(errno) => {
+                write!(f, "Failed to create netlink socket (errno = {})", errno)
+            }
+            LinuxNetError::SendFailed(errno) => {
+                write!(f, "Failed to send netlink request (errno = {})", errno)
+            }
+            LinuxNetError::RecvFailed(errno) => {
+                write!(f, "Failed to receive netlink response (errno = {})", errno)
+            }
+            LinuxNetError::RequestFailed(errno) => {
+                write!(f, "Netlink request rejected (errno = {})", errno)
+            }
+            LinuxNetError::MalformedResponse => write!(f, "Malformed netlink response"),
+        }
+    }
+}
+
+impl Error for LinuxNetError {
+    fn description(&self) -> &str {
+        "Netlink Error"
+    }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+fn errno() -> i32 {
+    io::Error::last_os_error().raw_os_error().unwrap_or(0)
+}
+
+// Pad the message to the netlink alignment (4 bytes)
+fn align(buf: &mut Vec<u8>) {
+    while buf.len() % 4 != 0 {
+        buf.push(0);
+    }
+}
+
+fn as_bytes<T>(v: &T) -> &[u8] {
+    unsafe { slice::from_raw_parts(v as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+fn ifindex(name: &str) -> Result<u32, LinuxNetError> {
+    let name = CString::new(name).map_err(|_| LinuxNetError::InvalidInterfaceName)?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(LinuxNetError::NoSuchInterface),
+        idx => Ok(idx),
+    }
+}
+
+// Returns the null-terminated interface name (of at most IFNAMSIZ bytes)
+fn ifname(name: &str) -> Result<Vec<u8>, LinuxNetError> {
+    let name = CString::new(name).map_err(|_| LinuxNetError::InvalidInterfaceName)?;
+    let name = name.into_bytes_with_nul();
+    if name.len() == 1 || name.len() > libc::IFNAMSIZ {
+        return Err(LinuxNetError::InvalidInterfaceName);
+    }
+    Ok(name)
+}
+
+// Returns the address family and the address in network byte order
+fn family(addr: &IpAddr, prefix: u8) -> Result<(u8, Vec<u8>), LinuxNetError> {
+    let (family, octets) = match addr {
+        IpAddr::V4(addr) => (libc::AF_INET as u8, addr.octets().to_vec()),
+        IpAddr::V6(addr) => (libc::AF_INET6 as u8, addr.octets().to_vec()),
+    };
+    if prefix as usize > octets.len() * 8 {
+        return Err(LinuxNetError::InvalidPrefix);
+    }
+    Ok((family, octets))
+}
+
+/* A netlink request:
+ * netlink header, followed by a fixed size body (e.g. ifaddrmsg) and attributes
+ */
+struct Request {
+    ty: u16,
+    flags: u16,
+    buf: Vec<u8>,
+}
+
+impl Request {
+    fn new<T>(ty: u16, flags: libc::c_int, body: &T) -> Request {
+        let mut buf = vec![0u8; HDR_SIZE];
+        buf.extend_from_slice(as_bytes(body));
+        align(&mut buf);
+        Request {
+            ty,
+            flags: (flags | libc::NLM_F_REQUEST | libc::NLM_F_ACK) as u16,
+            buf,
+        }
+    }
+
+    fn attr(mut self, ty: u16, data: &[u8]) -> Request {
+        let len = (mem::size_of::<u16>() * 2 + data.len()) as u16;
+        self.buf.extend_from_slice(&len.to_ne_bytes());
+        self.buf.extend_from_slice(&ty.to_ne_bytes());
+        self.buf.extend_from_slice(data);
+        align(&mut self.buf);
+        self
+    }
+
+    fn finish(mut self, seq: u32) -> Vec<u8> {
+        let hdr = libc::nlmsghdr {
+            nlmsg_len: self.buf.len() as u32,
+            nlmsg_type: self.ty,
+            nlmsg_flags: self.flags,
+            nlmsg_seq: seq,
+            nlmsg_pid: 0,
+        };
+        self.buf[..HDR_SIZE].copy_from_slice(as_bytes(&hdr));
+        self.buf
+    }
+}
+
+impl Drop for LinuxNet {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+impl LinuxNet {
+    // Send the request and wait for the acknowledgement
+    fn request(&self, req: Request) -> Result<(), LinuxNetError> {
+        const ERROR: u16 = libc::NLMSG_ERROR as u16;
+
+        let mut seq = self.seq.lock().unwrap();
+        *seq = seq.wrapping_add(1);
+
+        // send request
+        let msg = req.finish(*seq);
+        let size = unsafe { libc::send(self.fd, msg.as_ptr() as _, msg.len(), 0) };
+        if size < 0 {
+            return Err(LinuxNetError::SendFailed(errno()));
+        }
+
+        // wait for acknowledgement
+        let mut buf = [0u8; 1 << 12];
+        loop {
+            let size = unsafe { libc::recv(self.fd, buf.as_mut_ptr() as _, buf.len(), 0) };
+            if size < 0 {
+                return Err(LinuxNetError::RecvFailed(errno()));
+            }
+
+            let mut remain = &buf[..size as usize];
+            while remain.len() >= HDR_SIZE {
+                let hdr: libc::nlmsghdr = unsafe {
+                    let mut hdr = [0u8; HDR_SIZE];
+                    hdr.copy_from_slice(&remain[..HDR_SIZE]);
+                    mem::transmute(hdr)
+                };
+                let msg_len = hdr.nlmsg_len as usize;
+                if msg_len < HDR_SIZE || msg_len > remain.len() {
+                    return Err(LinuxNetError::MalformedResponse);
+                }
+
+                // the error message carries the errno (0 = acknowledgement)
+                if hdr.nlmsg_type == ERROR && hdr.nlmsg_seq == *seq {
+                    let body = &remain[HDR_SIZE..msg_len];
+                    if body.len() < mem::size_of::<i32>() {
+                        return Err(LinuxNetError::MalformedResponse);
+                    }
+                    let mut code = [0u8; 4];
+                    code.copy_from_slice(&body[..4]);
+                    return match i32::from_ne_bytes(code) {
+                        0 => Ok(()),
+                        code => Err(LinuxNetError::RequestFailed(-code)),
+                    };
+                }
+
+                // go to next message (aligned)
+                remain = &remain[cmp::min((msg_len + 3) & !3, remain.len())..];
+            }
+        }
+    }
+
+    fn address(
+        &self,
+        ty: u16,
+        flags: libc::c_int,
+        name: &str,
+        addr: IpAddr,
+        prefix: u8,
+    ) -> Result<(), LinuxNetError> {
+        let (family, octets) = family(&addr, prefix)?;
+        let msg = IfAddrmsg {
+            ifa_family: family,
+            ifa_prefixlen: prefix,
+            ifa_flags: 0,
+            ifa_scope: RT_SCOPE_UNIVERSE,
+            ifa_index: ifindex(name)?,
+        };
+        self.request(
+            Request::new(ty, flags, &msg)
+                .attr(IFA_LOCAL, &octets)
+                .attr(IFA_ADDRESS, &octets),
+        )
+    }
+
+    fn route(
+        &self,
+        ty: u16,
+        flags: libc::c_int,
+        name: &str,
+        dst: IpAddr,
+        prefix: u8,
+    ) -> Result<(), LinuxNetError> {
+        let (family, octets) = family(&dst, prefix)?;
+        let add = ty == libc::RTM_NEWROUTE;
+        let msg = Rtmsg {
+            rtm_family: family,
+            rtm_dst_len: prefix,
+            rtm_src_len: 0,
+            rtm_tos: 0,
+            rtm_table: RT_TABLE_MAIN,
+            rtm_protocol: if add { RTPROT_BOOT } else { 0 },
+            rtm_scope: if add { RT_SCOPE_LINK } else { RT_SCOPE_NOWHERE },
+            rtm_type: RTN_UNICAST,
+            rtm_flags: 0,
+        };
+        let mut req = Request::new(ty, flags, &msg);
+        if prefix > 0 {
+            req = req.attr(RTA_DST, &octets);
+        }
+        let index = ifindex(name)?;
+        self.request(req.attr(RTA_OIF, &index.to_ne_bytes()))
+    }
+
+    fn link(&self, name: &str, flags: u32, change: u32) -> Result<Request, LinuxNetError> {
+        let msg = IfInfomsg {
+            ifi_family: libc::AF_UNSPEC as u8,
+            __ifi_pad: 0,
+            ifi_type: 0,
+            ifi_index: ifindex(name)? as libc::c_int,
+            ifi_flags: flags,
+            ifi_change: change,
+        };
+        Ok(Request::new(libc::RTM_NEWLINK, 0, &msg))
+    }
+
+    /// Renames the interface (the interface must be down)
+    pub fn rename(&self, name: &str, new: &str) -> Result<(), LinuxNetError> {
+        log::debug!("netlink, rename {} to {}", name, new);
+        let ifname = ifname(new)?;
+        let req = self.link(name, 0, 0)?;
+        self.request(req.attr(IFLA_IFNAME, &ifname))
+    }
+}
+
+impl Net for LinuxNet {
+    type Error = LinuxNetError;
+
+    fn open() -> Result<Self, Self::Error> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(LinuxNetError::SocketFailed(errno()));
+        }
+        Ok(LinuxNet {
+            fd,
+            seq: Mutex::new(0),
+        })
+    }
+
+    fn add_address(&self, name: &str, addr: IpAddr, prefix: u8) -> Result<(), Self::Error> {
+        log::debug!("netlink, add address {}/{} to {}", addr, prefix, name);
+        let flags = libc::NLM_F_CREATE | libc::NLM_F_EXCL;
+        self.address(libc::RTM_NEWADDR, flags, name, addr, prefix)
+    }
+
+    fn remove_address(&self, name: &str, addr: IpAddr, prefix: u8) -> Result<(), Self::Error> {
+        log::debug!("netlink, remove address {}/{} from {}", addr, prefix, name);
+        self.address(libc::RTM_DELADDR, 0, name, addr, prefix)
+    }
+
+    fn add_route(&self, name: &str, dst: IpAddr, prefix: u8) -> Result<(), Self::Error> {
+        log::debug!("netlink, add route {}/{} via {}", dst, prefix, name);
+        let flags = libc::NLM_F_CREATE | libc::NLM_F_EXCL;
+        self.route(libc::RTM_NEWROUTE, flags, name, dst, prefix)
+    }
+
+    fn remove_route(&self, name: &str, dst: IpAddr, prefix: u8) -> Result<(), Self::Error> {
+        log::debug!("netlink, remove route {}/{} via {}", dst, prefix, name);
+        self.route(libc::RTM_DELROUTE, 0, name, dst, prefix)
+    }
+
+    fn set_link(&self, name: &str, up: bool) -> Result<(), Self::Error> {
+        log::debug!("netlink, set {} {}", name, if up { "up" } else { "down" });
+        let up_flag = libc::IFF_UP as u32;
+        let req = self.link(name, if up { up_flag } else { 0 }, up_flag)?;
+        self.request(req)
+    }
+
+    fn set_mtu(&self, name: &str, mtu: usize) -> Result<(), Self::Error> {
+        log::debug!("netlink, set mtu of {} to {}", name, mtu);
+        let req = self.link(name, 0, 0)?;
+        self.request(req.attr(IFLA_MTU, &(mtu as u32).to_ne_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(buf: &[u8], off: usize) -> u16 {
+        u16::from_ne_bytes([buf[off], buf[off + 1]])
+    }
+
+    #[test]
+    fn test_request() {
+        let msg = IfAddrmsg {
+            ifa_family: libc::AF_INET as u8,
+            ifa_prefixlen: 24,
+            ifa_flags: 0,
+            ifa_scope: RT_SCOPE_UNIVERSE,
+            ifa_index: 7,
+        };
+        let buf = Request::new(libc::RTM_NEWADDR, libc::NLM_F_CREATE, &msg)
+            .attr(IFA_LOCAL, &[10, 0, 0, 1])
+            .attr(IFA_ADDRESS, &[10, 0, 0])
+            .finish(42);
+
+        // header: the length covers the (aligned) attributes, acknowledgements are requested
+        let size = HDR_SIZE + mem::size_of::<IfAddrmsg>();
+        assert_eq!(buf.len(), size + 8 + 8);
+        assert_eq!(
+            u32::from_ne_bytes([buf[0], buf[1], buf[2], buf[3]]),
+            buf.len() as u32
+        );
+        assert_eq!(u16_at(&buf, 4), libc::RTM_NEWADDR);
+        let flags = u16_at(&buf, 6) as libc::c_int;
+        assert_eq!(
+            flags,
+            libc::NLM_F_CREATE | libc::NLM_F_REQUEST | libc::NLM_F_ACK
+        );
+        assert_eq!(u32::from_ne_bytes([buf[8], buf[9], buf[10], buf[11]]), 42);
+
+        // body and attributes (with the length of the unpadded attribute)
+        assert_eq!(buf[HDR_SIZE], libc::AF_INET as u8);
+        assert_eq!(buf[HDR_SIZE + 1], 24);
+        assert_eq!(u16_at(&buf, size), 8);
+        assert_eq!(u16_at(&buf, size + 2), IFA_LOCAL);
+        assert_eq!(&buf[size + 4..size + 8], &[10, 0, 0, 1]);
+        assert_eq!(u16_at(&buf, size + 8), 7);
+        assert_eq!(u16_at(&buf, size + 10), IFA_ADDRESS);
+        assert_eq!(buf[size + 15], 0);
+    }
+
+    #[test]
+    fn test_ifname() {
+        assert_eq!(ifname("wg0").unwrap(), b"wg0\0".to_vec());
+        assert_eq!(ifname("wireguard012345").unwrap().len(), libc::IFNAMSIZ);
+        assert!(ifname("wireguard0123456").is_err());
+        assert!(ifname("").is_err());
+        assert!(ifname("wg\00").is_err());
+    }
+
+    #[test]
+    fn test_family() {
+        let v4: IpAddr = "10.0.0.1".parse().unwrap();
+        let v6: IpAddr = "fd00::1".parse().unwrap();
+        assert_eq!(
+            family(&v4, 32).unwrap(),
+            (libc::AF_INET as u8, vec![10, 0, 0, 1])
+        );
+        assert_eq!(family(&v6, 128).unwrap().1.len(), 16);
+        assert!(family(&v4, 33).is_err());
+        assert!(family(&v6, 129).is_err());
+    }
+
+    #[test]
+    fn test_unknown_interface() {
+        // fails before any request is sent (hence requires no privileges)
+        if let Ok(net) = LinuxNet::open() {
+            let addr = "10.0.0.1".parse().unwrap();
+            match net.add_address("wg-no-such-if", addr, 24) {
+                Err(LinuxNetError::NoSuchInterface) => (),
+                r => panic!("unexpected result: {:?}", r),
+            }
+        }
+    }
+}
@@ -1,14 +1,22 @@
+use super::super::net::Net;
 use super::super::tun::*;
+use super::net::LinuxNet;
 
 use libc;
 
 use std::error::Error;
 use std::fmt;
+use std::io;
 use std::mem;
 use std::os::raw::c_short;
 use std::os::unix::io::RawFd;
 
 const TUNSETIFF: u64 = 0x4004_54ca;
+const TUNSETPERSIST: u64 = 0x4004_54cb;
+const TUNSETOWNER: u64 = 0x4004_54cc;
+const TUNSETGROUP: u64 = 0x4004_54ce;
+const TUNGETIFF: u64 = 0x8004_54d2;
+const IFF_PERSIST: c_short = 0x0800;
 const CLONE_DEVICE_PATH: &'static [u8] = b"/dev/net/tun\0";
 
 #[repr(C)]
@@ -21,13 +29,13 @@ struct Ifreq {
 // man 7 rtnetlink
 // Layout from: https://elixir.bootlin.com/linux/latest/source/include/uapi/linux/rtnetlink.h#L516
 #[repr(C)]
-struct IfInfomsg {
-    ifi_family: libc::c_uchar,
-    __ifi_pad: libc::c_uchar,
-    ifi_type: libc::c_ushort,
-    ifi_index: libc::c_int,
-    ifi_flags: libc::c_uint,
-    ifi_change: libc::c_uint,
+pub(super) struct IfInfomsg {
+    pub(super) ifi_family: libc::c_uchar,
+    pub(super) __ifi_pad: libc::c_uchar,
+    pub(super) ifi_type: libc::c_ushort,
+    pub(super) ifi_index: libc::c_int,
+    pub(super) ifi_flags: libc::c_uint,
+    pub(super) ifi_change: libc::c_uint,
 }
 
 pub struct LinuxTun {}
@@ -52,9 +60,14 @@ pub enum LinuxTunError {
     InvalidTunDeviceName,
     FailedToOpenCloneDevice,
     SetIFFIoctlFailed,
+    SetOwnerIoctlFailed,
+    SetPersistIoctlFailed,
+    RenameFailed,
     GetMTUIoctlFailed,
     NetlinkFailure,
-    Closed, // TODO
+    ReadTransient(i32), // errno of a temporary read failure
+    ReadClosed(i32),    // errno of a read failure on a device which is gone
+    Closed,             // TODO
 }
 
 impl fmt::Display for LinuxTunError {
@@ -67,9 +80,24 @@ impl fmt::Display for LinuxTunError {
             LinuxTunError::SetIFFIoctlFailed => {
                 write!(f, "set_iff ioctl failed (insufficient permissions?)")
             }
+            LinuxTunError::SetOwnerIoctlFailed => write!(f, "Failed to set owner of tunnel"),
+            LinuxTunError::SetPersistIoctlFailed => {
+                write!(f, "Failed to set persistence of tunnel")
+            }
+            LinuxTunError::RenameFailed => write!(f, "Failed to rename tunnel interface"),
             LinuxTunError::Closed => write!(f, "The tunnel has been closed"),
             LinuxTunError::GetMTUIoctlFailed => write!(f, "ifmtu ioctl failed"),
             LinuxTunError::NetlinkFailure => write!(f, "Netlink listener error"),
+            LinuxTunError::ReadTransient(errno) => {
+                write!(
+                    f,
+                    "Temporary failure reading from tunnel (errno = {})",
+                    errno
+                )
+            }
+            LinuxTunError::ReadClosed(errno) => {
+                write!(f, "The tunnel device is gone (errno = {})", errno)
+            }
         }
     }
 }
@@ -84,6 +112,15 @@ impl Error for LinuxTunError {
     }
 }
 
+impl ReadError for LinuxTunError {
+    fn kind(&self) -> ReadErrorKind {
+        match self {
+            LinuxTunError::ReadTransient(_) => ReadErrorKind::Transient,
+            _ => ReadErrorKind::Closed,
+        }
+    }
+}
+
 impl Reader for LinuxTunReader {
     type Error = LinuxTunError;
 
@@ -97,12 +134,33 @@ impl Reader for LinuxTunReader {
         let n: isize =
             unsafe { libc::read(self.fd, buf[offset..].as_mut_ptr() as _, buf.len() - offset) };
         if n < 0 {
-            Err(LinuxTunError::Closed)
+            // classify the error: e.g. EBADFD is returned once the interface is deleted
+            let errno = io::Error::last_os_error().raw_os_error().unwrap_or(0);
+            Err(match errno {
+                libc::EAGAIN | libc::EINTR | libc::ENOBUFS | libc::ENOMEM => {
+                    LinuxTunError::ReadTransient(errno)
+                }
+                _ => LinuxTunError::ReadClosed(errno),
+            })
         } else {
             // conversion is safe
             Ok(n as usize)
         }
     }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> bool {
+        // the writer shares the file descriptor: writes to the TUN device do not block regardless
+        let flags = unsafe { libc::fcntl(self.fd, libc::F_GETFL) };
+        if flags < 0 {
+            return false;
+        }
+        let flags = if nonblocking {
+            flags | libc::O_NONBLOCK
+        } else {
+            flags & !libc::O_NONBLOCK
+        };
+        unsafe { libc::fcntl(self.fd, libc::F_SETFL, flags) == 0 }
+    }
 }
 
 impl Writer for LinuxTunWriter {
@@ -320,7 +378,10 @@ impl Tun for LinuxTun {
 impl PlatformTun for LinuxTun {
     type Status = LinuxTunStatus;
 
-    fn create(name: &str) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Status), Self::Error> {
+    fn create_with(
+        name: &str,
+        options: &TunOptions,
+    ) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Status), Self::Error> {
         // construct request struct
         let mut req = Ifreq {
             name: [0u8; libc::IFNAMSIZ],
@@ -342,16 +403,65 @@ impl PlatformTun for LinuxTun {
         };
         assert!(fd >= 0);
 
-        // create TUN device
+        // create TUN device (or attach to an existing persistent device)
         if unsafe { libc::ioctl(fd, TUNSETIFF as _, &req) } < 0 {
             return Err(LinuxTunError::SetIFFIoctlFailed);
         }
 
+        // set ownership of the interface
+        if let Some(uid) = options.owner {
+            if unsafe { libc::ioctl(fd, TUNSETOWNER as _, uid as libc::c_ulong) } < 0 {
+                return Err(LinuxTunError::SetOwnerIoctlFailed);
+            }
+        }
+        if let Some(gid) = options.group {
+            if unsafe { libc::ioctl(fd, TUNSETGROUP as _, gid as libc::c_ulong) } < 0 {
+                return Err(LinuxTunError::SetOwnerIoctlFailed);
+            }
+        }
+
+        // update persistence if requested and it differs:
+        // a non-persistent device is removed when the process exits
+        let mut current = Ifreq {
+            name: [0u8; libc::IFNAMSIZ],
+            flags: 0,
+            _pad: [0u8; 64],
+        };
+        if unsafe { libc::ioctl(fd, TUNGETIFF as _, &mut current) } < 0 {
+            return Err(LinuxTunError::SetPersistIoctlFailed);
+        }
+        if let Some(persist) = options.persist {
+            if (current.flags & IFF_PERSIST != 0) != persist {
+                let persist = persist as libc::c_ulong;
+                if unsafe { libc::ioctl(fd, TUNSETPERSIST as _, persist) } < 0 {
+                    return Err(LinuxTunError::SetPersistIoctlFailed);
+                }
+            }
+        }
+
+        // rename the interface over rtnetlink (the interface must be down),
+        // the index is unchanged: events continue to match the interface
+        if let Some(new) = options.rename.as_ref() {
+            let bs = new.as_bytes();
+            if bs.len() > libc::IFNAMSIZ - 1 {
+                return Err(LinuxTunError::InvalidTunDeviceName);
+            }
+            let len = current.name.iter().position(|b| *b == 0).unwrap_or(0);
+            let old = String::from_utf8_lossy(&current.name[..len]).into_owned();
+            if old.as_bytes() != bs {
+                LinuxNet::open()
+                    .and_then(|net| net.rename(&old, new))
+                    .map_err(|_| LinuxTunError::RenameFailed)?;
+                current.name = [0u8; libc::IFNAMSIZ];
+                current.name[..bs.len()].copy_from_slice(bs);
+            }
+        }
+
         // create PlatformTunMTU instance
         Ok((
             vec![LinuxTunReader { fd }], // TODO: use multi-queue for Linux
             LinuxTunWriter { fd },
-            LinuxTunStatus::new(req.name)?,
+            LinuxTunStatus::new(current.name)?,
         ))
     }
 }
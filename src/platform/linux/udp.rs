@@ -3,12 +3,16 @@ use super::super::Endpoint;
 
 use log;
 
+use std::cmp;
 use std::convert::TryInto;
-use std::io;
+use std::error::Error;
+use std::fmt;
 use std::mem;
-use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::os::unix::io::RawFd;
 use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicI32, Ordering};
 use std::sync::Arc;
 
 pub struct FD(RawFd);
@@ -24,39 +28,174 @@ impl Drop for FD {
     }
 }
 
-#[repr(C, align(1))]
-struct ControlHeaderV4 {
-    hdr: libc::cmsghdr,
-    info: libc::in_pktinfo,
-}
-
-#[repr(C, align(1))]
-struct ControlHeaderV6 {
-    hdr: libc::cmsghdr,
-    info: libc::in6_pktinfo,
-}
+/* Control data passed to sendmsg:
+ * room for the pktinfo and hop limit messages (aligned as a cmsghdr).
+ */
+#[repr(C, align(8))]
+struct SendControl([u8; 64]);
 
 pub struct EndpointV4 {
     dst: libc::sockaddr_in, // destination IP
     info: libc::in_pktinfo, // src & ifindex
+    hops: Option<u8>,       // TTL (None = the TTL of the socket)
+    pinned: bool,           // the source is pinned (see Endpoint::set_src)
 }
 
 pub struct EndpointV6 {
     dst: libc::sockaddr_in6, // destination IP
     info: libc::in6_pktinfo, // src & zone id
+    hops: Option<u8>,        // hop limit (None = the hop limit of the socket)
+    pinned: bool,            // the source is pinned (see Endpoint::set_src)
 }
 
 pub struct LinuxUDP();
 
 pub struct LinuxOwner {
-    port: u16,
+    ports: BoundPorts,
     sock4: Option<Arc<FD>>,
     sock6: Option<Arc<FD>>,
+    fanout4: Vec<Arc<FD>>, // additional sockets sharing the port (see BindOptions::fanout)
+    fanout6: Vec<Arc<FD>>,
+    options: TransportOptions, // options currently applied to the sockets
+}
+
+pub struct LinuxUDPReader {
+    sock: Arc<FD>,
+    v6: bool,
+    flags: AtomicI32, // flags of every read (MSG_DONTWAIT if the reads are nonblocking)
+}
+
+#[derive(Debug)]
+pub enum LinuxUDPError {
+    SocketFailed(libc::c_int),
+    SetSockOptFailed(libc::c_int),
+    BindFailed(libc::c_int),
+    GetSockNameFailed(libc::c_int),
+    SendFailed(libc::c_int),
+    RecvFailed(libc::c_int),
+    PortMismatch(u16, u16), // (IPv6 port, IPv4 port)
+    Shutdown,
+}
+
+impl LinuxUDPError {
+    /// Returns the errno of the failed operation (0 if none)
+    pub fn errno(&self) -> libc::c_int {
+        match self {
+            LinuxUDPError::SocketFailed(errno) => *errno,
+            LinuxUDPError::SetSockOptFailed(errno) => *errno,
+            LinuxUDPError::BindFailed(errno) => *errno,
+            LinuxUDPError::GetSockNameFailed(errno) => *errno,
+            LinuxUDPError::SendFailed(errno) => *errno,
+            LinuxUDPError::RecvFailed(errno) => *errno,
+            LinuxUDPError::PortMismatch(_, _) => libc::EADDRINUSE,
+            LinuxUDPError::Shutdown => 0,
+        }
+    }
 }
 
-pub enum LinuxUDPReader {
-    V4(Arc<FD>),
-    V6(Arc<FD>),
+impl fmt::Display for LinuxUDPError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinuxUDPError::SocketFailed(errno) => {
+                write!(f, "Failed to create socket (errno = {})", errno)
+            }
+            LinuxUDPError::SetSockOptFailed(errno) => {
+                write!(f, "Failed to set socket option (errno = {})", errno)
+            }
+            LinuxUDPError::BindFailed(errno) => {
+                write!(f, "Failed to bind socket (errno = {})", errno)
+            }
+            LinuxUDPError::GetSockNameFailed(errno) => {
+                write!(f, "Failed to get socket address (errno = {})", errno)
+            }
+            LinuxUDPError::SendFailed(errno) => {
+                write!(f, "Failed to send message (errno = {})", errno)
+            }
+            LinuxUDPError::RecvFailed(errno) => {
+                write!(f, "Failed to receive message (errno = {})", errno)
+            }
+            LinuxUDPError::PortMismatch(v6, v4) => {
+                write!(f, "Bound IPv6 on port {} but IPv4 on port {}", v6, v4)
+            }
+            LinuxUDPError::Shutdown => write!(f, "Socket was shut down"),
+        }
+    }
+}
+
+impl Error for LinuxUDPError {
+    fn description(&self) -> &str {
+        "Linux UDP error"
+    }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+impl WriteError for LinuxUDPError {
+    fn kind(&self) -> WriteErrorKind {
+        match self.errno() {
+            libc::ENETUNREACH
+            | libc::EHOSTUNREACH
+            | libc::ECONNREFUSED
+            | libc::EHOSTDOWN
+            | libc::ENETDOWN => WriteErrorKind::Unreachable,
+            libc::EPERM | libc::EACCES => WriteErrorKind::Permission,
+            libc::ENOBUFS | libc::ENOMEM | libc::EAGAIN => WriteErrorKind::NoBuffers,
+            libc::EMSGSIZE => WriteErrorKind::TooBig,
+            _ => WriteErrorKind::Other,
+        }
+    }
+}
+
+/// Error returned when reading from the UDP sockets
+#[derive(Debug)]
+pub enum LinuxUDPReadError {
+    Closed(LinuxUDPError),
+    Interrupted,
+    Empty, // datagram without payload
+    Unreachable(Vec<SocketAddr>),
+}
+
+impl fmt::Display for LinuxUDPReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinuxUDPReadError::Closed(err) => write!(f, "Socket closed: {}", err),
+            LinuxUDPReadError::Interrupted => write!(f, "Read interrupted"),
+            LinuxUDPReadError::Empty => write!(f, "Empty datagram"),
+            LinuxUDPReadError::Unreachable(addrs) => {
+                write!(f, "Destinations unreachable: {:?}", addrs)
+            }
+        }
+    }
+}
+
+impl Error for LinuxUDPReadError {
+    fn description(&self) -> &str {
+        "Linux UDP read error"
+    }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+impl ReadError for LinuxUDPReadError {
+    fn kind(&self) -> ReadErrorKind {
+        match self {
+            LinuxUDPReadError::Closed(_) => ReadErrorKind::Closed,
+            LinuxUDPReadError::Interrupted => ReadErrorKind::Transient,
+            LinuxUDPReadError::Empty => ReadErrorKind::Transient,
+            LinuxUDPReadError::Unreachable(_) => ReadErrorKind::Unreachable,
+        }
+    }
+
+    fn unreachable(&self) -> &[SocketAddr] {
+        match self {
+            LinuxUDPReadError::Unreachable(addrs) => &addrs[..],
+            _ => &[],
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -86,7 +225,7 @@ fn setsockopt<V: Sized>(
     level: libc::c_int,
     name: libc::c_int,
     value: &V,
-) -> Result<(), io::Error> {
+) -> Result<(), LinuxUDPError> {
     let res = unsafe {
         libc::setsockopt(
             fd,
@@ -99,10 +238,7 @@ fn setsockopt<V: Sized>(
     if res == 0 {
         Ok(())
     } else {
-        Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!("Failed to set sockopt (res = {}, errno = {})", res, errno()),
-        ))
+        Err(LinuxUDPError::SetSockOptFailed(errno()))
     }
 }
 
@@ -112,18 +248,283 @@ fn setsockopt_int(
     level: libc::c_int,
     name: libc::c_int,
     value: libc::c_int,
-) -> Result<(), io::Error> {
+) -> Result<(), LinuxUDPError> {
     setsockopt(fd, level, name, &value)
 }
 
-#[allow(non_snake_case)]
-const fn CMSG_ALIGN(len: usize) -> usize {
-    ((len) + mem::size_of::<u32>() - 1) & !(mem::size_of::<u32>() - 1)
+/* Original destination of received IPv4 datagrams (linux/in.h),
+ * used when IP_PKTINFO is unavailable (e.g. filtered by the seccomp profile of a container runtime).
+ */
+const IP_ORIGDSTADDR: libc::c_int = 20;
+const IP_RECVORIGDSTADDR: libc::c_int = IP_ORIGDSTADDR;
+
+/* Enables reporting the local destination of datagrams received on an IPv4 socket,
+ * probing IP_PKTINFO and falling back to IP_RECVORIGDSTADDR.
+ *
+ * Returns false if neither is available:
+ * replies are then sent from the source chosen by the routing table (no sticky source).
+ */
+fn recv_dst4(fd: RawFd) -> bool {
+    if setsockopt_int(fd, libc::IPPROTO_IP, libc::IP_PKTINFO, 1).is_ok() {
+        return true;
+    }
+    match setsockopt_int(fd, libc::IPPROTO_IP, IP_RECVORIGDSTADDR, 1) {
+        Ok(()) => {
+            log::info!(
+                "IP_PKTINFO unavailable, using IP_RECVORIGDSTADDR (fd = {})",
+                fd
+            );
+            true
+        }
+        Err(err) => {
+            log::warn!(
+                "IP_PKTINFO and IP_RECVORIGDSTADDR unavailable ({}), replies use the source of the route (fd = {})",
+                err,
+                fd
+            );
+            false
+        }
+    }
+}
+
+/* Extracts the local destination of a received IPv4 datagram from the control messages:
+ * from the pktinfo or (lacking pktinfo) the original destination address.
+ */
+fn find_dst4(control: &[u8], flags: libc::c_int) -> Option<libc::in_pktinfo> {
+    find_cmsg(control, flags, libc::IPPROTO_IP, libc::IP_PKTINFO).or_else(|| {
+        let dst: libc::sockaddr_in = find_cmsg(control, flags, libc::IPPROTO_IP, IP_ORIGDSTADDR)?;
+        let mut info: libc::in_pktinfo = unsafe { mem::zeroed() };
+        info.ipi_spec_dst = dst.sin_addr;
+        Some(info)
+    })
+}
+
+/* Path MTU discovery (linux/in.h, linux/in6.h) */
+const IP_MTU_DISCOVER: libc::c_int = 10;
+const IPV6_MTU_DISCOVER: libc::c_int = 23;
+const PMTUDISC_DONT: libc::c_int = 0; // never set DF
+const PMTUDISC_WANT: libc::c_int = 1; // set DF, fragment locally if the route MTU is exceeded
+const PMTUDISC_DO: libc::c_int = 2; // always set DF, fail with EMSGSIZE if the MTU is exceeded
+
+/* Busy polling of the receive queue (asm-generic/socket.h) */
+const SO_BUSY_POLL: libc::c_int = 46;
+
+/* UDP-Lite socket options (linux/udp.h) */
+const IPPROTO_UDPLITE: libc::c_int = 136;
+const SOL_UDPLITE: libc::c_int = 136;
+const UDPLITE_SEND_CSCOV: libc::c_int = 10;
+const UDPLITE_RECV_CSCOV: libc::c_int = 11;
+
+/* Checksum coverage of UDP-Lite datagrams:
+ * the UDP-Lite header (8 bytes) and the header of the WireGuard message (16 bytes),
+ * the remainder is authenticated by WireGuard (a corrupted message fails decryption).
+ */
+const UDPLITE_COVERAGE: libc::c_int = 8 + 16;
+
+/* Create a datagram socket of the protocol,
+ * falling back to UDP if UDP-Lite is not supported (e.g. by the kernel).
+ */
+fn socket(family: libc::c_int, protocol: Protocol) -> Result<FD, LinuxUDPError> {
+    if protocol == Protocol::UdpLite {
+        let fd: RawFd = unsafe { libc::socket(family, libc::SOCK_DGRAM, IPPROTO_UDPLITE) };
+        if fd >= 0 {
+            let sock = FD(fd);
+            setsockopt_int(fd, SOL_UDPLITE, UDPLITE_SEND_CSCOV, UDPLITE_COVERAGE)?;
+            setsockopt_int(fd, SOL_UDPLITE, UDPLITE_RECV_CSCOV, UDPLITE_COVERAGE)?;
+            return Ok(sock);
+        }
+        log::warn!(
+            "failed to create UDP-Lite socket ({}), falling back to UDP",
+            LinuxUDPError::SocketFailed(errno())
+        );
+    }
+
+    let fd: RawFd = unsafe { libc::socket(family, libc::SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(LinuxUDPError::SocketFailed(errno()));
+    }
+    Ok(FD(fd))
+}
+
+/* Fan-out of a port over several sockets (asm-generic/socket.h, linux/filter.h) */
+const SO_ATTACH_REUSEPORT_CBPF: libc::c_int = 51;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+#[repr(C)]
+struct SockFprog {
+    len: u16,
+    filter: *const SockFilter,
+}
+
+const BPF_LD_W_ABS: u16 = 0x20; // A := packet[k..k+4] (big-endian)
+const BPF_JEQ_K: u16 = 0x15; // if A == k goto jt else goto jf
+const BPF_MOD_K: u16 = 0x94; // A := A % k
+const BPF_RET_A: u16 = 0x16; // return A
+const BPF_RET_K: u16 = 0x06; // return k
+
+/* The reuseport program of a group of sockets (run on the UDP payload):
+ * transport messages are steered by their receiver index
+ * (the index of the socket in the group is the receiver index modulo the size of the group),
+ * hence every message of a session is received by the same socket (and reader),
+ * preserving the locality of its state (e.g. the replay window) in the caches of the core.
+ * Other messages yield an index beyond the group: the kernel falls back to the hash of the flow.
+ */
+fn reuseport_program(sockets: u32) -> [SockFilter; 6] {
+    let insn = |code, jt, jf, k| SockFilter { code, jt, jf, k };
+    let transport = u32::from_be_bytes(4u32.to_le_bytes()); // type field as loaded (big-endian)
+    [
+        insn(BPF_LD_W_ABS, 0, 0, 0),      // A := type
+        insn(BPF_JEQ_K, 0, 3, transport), // not a transport message: goto fallback
+        insn(BPF_LD_W_ABS, 0, 0, 4),      // A := receiver index
+        insn(BPF_MOD_K, 0, 0, sockets),   // A := A % sockets
+        insn(BPF_RET_A, 0, 0, 0),         // return A
+        insn(BPF_RET_K, 0, 0, !0),        // fallback: return an index beyond the group
+    ]
+}
+
+/* Steers the messages received by the group of the socket (see reuseport_program) */
+fn attach_reuseport_program(fd: RawFd, sockets: u32) -> Result<(), LinuxUDPError> {
+    let program = reuseport_program(sockets);
+    let fprog = SockFprog {
+        len: program.len() as u16,
+        filter: program.as_ptr(),
+    };
+    setsockopt(fd, libc::SOL_SOCKET, SO_ATTACH_REUSEPORT_CBPF, &fprog)
+}
+
+/* Size of the control buffer passed to recvmsg:
+ * room for the pktinfo message and any messages enabled by other socket options
+ * (e.g. IP_RECVTOS or IP_RECVERR).
+ */
+const CONTROL_BUFFER_SIZE: usize = 256;
+
+/* struct sock_extended_err (linux/errqueue.h):
+ * the payload of IP_RECVERR and IPV6_RECVERR control messages.
+ */
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SockExtendedErr {
+    ee_errno: u32,
+    ee_origin: u8,
+    ee_type: u8,
+    ee_code: u8,
+    ee_pad: u8,
+    ee_info: u32,
+    ee_data: u32,
+}
+
+const SO_EE_ORIGIN_ICMP: u8 = 2;
+const SO_EE_ORIGIN_ICMP6: u8 = 3;
+
+/* Returns true if the errno signals that the source address (pktinfo) cannot be used,
+ * e.g. because the address was removed from the interface.
+ */
+fn is_stale_source(errno: libc::c_int) -> bool {
+    errno == libc::EINVAL || errno == libc::EADDRNOTAVAIL
+}
+
+// maximum number of errors read from the error queue at once
+const MAX_DRAINED_ERRORS: usize = 64;
+
+/* Returns true if the errno is (likely) an asynchronous error:
+ * reported by ICMP for an earlier message and returned by the next operation on the socket.
+ */
+fn is_async_error(errno: libc::c_int) -> bool {
+    match errno {
+        libc::ECONNREFUSED
+        | libc::EHOSTUNREACH
+        | libc::ENETUNREACH
+        | libc::EHOSTDOWN
+        | libc::EMSGSIZE => true,
+        _ => false,
+    }
+}
+
+/* Finds the first control message of the given level and type
+ * in the control data returned by recvmsg.
+ *
+ * Returns None if the control data was truncated (MSG_CTRUNC),
+ * or does not contain a complete message of the given type.
+ */
+fn find_cmsg<T: Copy>(
+    control: &[u8],
+    flags: libc::c_int,
+    level: libc::c_int,
+    typ: libc::c_int,
+) -> Option<T> {
+    // the kernel aligns messages to the size of a long
+    const fn align(len: usize) -> usize {
+        (len + mem::size_of::<usize>() - 1) & !(mem::size_of::<usize>() - 1)
+    }
+
+    if flags & libc::MSG_CTRUNC != 0 {
+        return None;
+    }
+
+    let hdr_len = mem::size_of::<libc::cmsghdr>();
+    let mut offset = 0;
+    while offset + hdr_len <= control.len() {
+        let hdr: libc::cmsghdr =
+            unsafe { ptr::read_unaligned(control[offset..].as_ptr() as *const libc::cmsghdr) };
+
+        // check that the message is within the control data
+        let len = hdr.cmsg_len as usize;
+        if len < hdr_len || len > control.len() - offset {
+            return None;
+        }
+
+        if hdr.cmsg_level == level && hdr.cmsg_type == typ {
+            let data = align(hdr_len);
+            if len < data + mem::size_of::<T>() {
+                return None;
+            }
+            return Some(unsafe {
+                ptr::read_unaligned(control[offset + data..].as_ptr() as *const T)
+            });
+        }
+
+        offset += align(len);
+    }
+    None
 }
 
-#[allow(non_snake_case)]
-const fn CMSG_LEN(len: usize) -> usize {
-    CMSG_ALIGN(len + mem::size_of::<libc::cmsghdr>())
+/* Appends a control message at the offset of the control data passed to sendmsg
+ * (messages are aligned to the size of a long, as by the kernel).
+ *
+ * Returns the offset following the message.
+ */
+fn put_cmsg<T: Copy>(
+    control: &mut [u8],
+    offset: usize,
+    level: libc::c_int,
+    typ: libc::c_int,
+    value: &T,
+) -> usize {
+    const fn align(len: usize) -> usize {
+        (len + mem::size_of::<usize>() - 1) & !(mem::size_of::<usize>() - 1)
+    }
+
+    let data = align(mem::size_of::<libc::cmsghdr>());
+    let len = data + mem::size_of::<T>();
+    assert!(offset + align(len) <= control.len());
+
+    let mut hdr: libc::cmsghdr = unsafe { mem::zeroed() };
+    hdr.cmsg_len = len as _;
+    hdr.cmsg_level = level;
+    hdr.cmsg_type = typ;
+    unsafe {
+        ptr::write_unaligned(control[offset..].as_mut_ptr() as *mut libc::cmsghdr, hdr);
+        ptr::write_unaligned(control[offset + data..].as_mut_ptr() as *mut T, *value);
+    }
+    offset + align(len)
 }
 
 #[inline(always)]
@@ -131,20 +532,102 @@ fn safe_cast<T, D>(v: &mut T) -> *mut D {
     (v as *mut T) as *mut D
 }
 
+// the source (ifindex and address) is compared, the hop limit is not
+impl PartialEq for LinuxEndpoint {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (LinuxEndpoint::V4(a), LinuxEndpoint::V4(b)) => {
+                a.dst.sin_port == b.dst.sin_port
+                    && a.dst.sin_addr.s_addr == b.dst.sin_addr.s_addr
+                    && a.info.ipi_ifindex == b.info.ipi_ifindex
+                    && a.info.ipi_spec_dst.s_addr == b.info.ipi_spec_dst.s_addr
+            }
+            (LinuxEndpoint::V6(a), LinuxEndpoint::V6(b)) => {
+                a.dst.sin6_port == b.dst.sin6_port
+                    && a.dst.sin6_addr.s6_addr == b.dst.sin6_addr.s6_addr
+                    && a.dst.sin6_scope_id == b.dst.sin6_scope_id
+                    && a.info.ipi6_ifindex == b.info.ipi6_ifindex
+                    && a.info.ipi6_addr.s6_addr == b.info.ipi6_addr.s6_addr
+            }
+            _ => false,
+        }
+    }
+}
+
 impl Endpoint for LinuxEndpoint {
     fn clear_src(&mut self) {
         match self {
-            LinuxEndpoint::V4(EndpointV4 { ref mut info, .. }) => {
+            LinuxEndpoint::V4(EndpointV4 {
+                ref mut info,
+                ref mut pinned,
+                ..
+            }) => {
                 info.ipi_ifindex = 0;
                 info.ipi_spec_dst = libc::in_addr { s_addr: 0 };
+                *pinned = false;
             }
-            LinuxEndpoint::V6(EndpointV6 { ref mut info, .. }) => {
+            LinuxEndpoint::V6(EndpointV6 {
+                ref mut info,
+                ref mut pinned,
+                ..
+            }) => {
                 info.ipi6_addr = libc::in6_addr { s6_addr: [0; 16] };
                 info.ipi6_ifindex = 0;
+                *pinned = false;
             }
         };
     }
 
+    fn set_src(&mut self, ifindex: u32, addr: Option<IpAddr>) {
+        match self {
+            LinuxEndpoint::V4(EndpointV4 {
+                ref mut info,
+                ref mut pinned,
+                ..
+            }) => {
+                *pinned = match addr {
+                    Some(IpAddr::V4(_)) => true,
+                    _ => ifindex != 0,
+                };
+                info.ipi_ifindex = ifindex as libc::c_int;
+                info.ipi_spec_dst = libc::in_addr {
+                    s_addr: match addr {
+                        Some(IpAddr::V4(addr)) => u32::from(addr).to_be(),
+                        _ => 0,
+                    },
+                };
+            }
+            LinuxEndpoint::V6(EndpointV6 {
+                ref mut info,
+                ref mut pinned,
+                ..
+            }) => {
+                *pinned = match addr {
+                    Some(IpAddr::V6(_)) => true,
+                    _ => ifindex != 0,
+                };
+                info.ipi6_ifindex = ifindex as _;
+                info.ipi6_addr = libc::in6_addr {
+                    s6_addr: match addr {
+                        Some(IpAddr::V6(addr)) => addr.octets(),
+                        _ => [0; 16],
+                    },
+                };
+            }
+        };
+    }
+
+    fn set_hop_limit(&mut self, hops: Option<u8>) {
+        match self {
+            LinuxEndpoint::V4(EndpointV4 {
+                hops: ref mut h, ..
+            }) => *h = hops,
+            LinuxEndpoint::V6(EndpointV6 {
+                hops: ref mut h, ..
+            }) => *h = hops,
+        }
+    }
+
     fn from_address(addr: SocketAddr) -> Self {
         match addr {
             SocketAddr::V4(addr) => LinuxEndpoint::V4(EndpointV4 {
@@ -161,6 +644,8 @@ impl Endpoint for LinuxEndpoint {
                     ipi_spec_dst: libc::in_addr { s_addr: 0 }, // src IP (dst of incoming packet)
                     ipi_addr: libc::in_addr { s_addr: 0 },
                 },
+                hops: None,
+                pinned: false,
             }),
             SocketAddr::V6(addr) => LinuxEndpoint::V6(EndpointV6 {
                 dst: libc::sockaddr_in6 {
@@ -176,6 +661,8 @@ impl Endpoint for LinuxEndpoint {
                     ipi6_addr: libc::in6_addr { s6_addr: [0; 16] }, // src IP
                     ipi6_ifindex: 0,                                // zone id
                 },
+                hops: None,
+                pinned: false,
             }),
         }
     }
@@ -199,11 +686,145 @@ impl Endpoint for LinuxEndpoint {
 }
 
 impl LinuxUDPReader {
-    fn read6(fd: RawFd, buf: &mut [u8]) -> Result<(usize, LinuxEndpoint), io::Error> {
+    fn new(sock: Arc<FD>, v6: bool) -> LinuxUDPReader {
+        LinuxUDPReader {
+            sock,
+            v6,
+            flags: AtomicI32::new(0),
+        }
+    }
+
+    /* Classifies a failed read (must be called immediately after recvmsg, to obtain errno) */
+    fn read_error(fd: RawFd, len: isize, v6: bool) -> LinuxUDPReadError {
+        let errno = errno();
+        if len == 0 && !Self::is_shutdown(fd) {
+            return LinuxUDPReadError::Empty;
+        }
+        if len < 0 {
+            if errno == libc::EINTR || errno == libc::EAGAIN {
+                return LinuxUDPReadError::Interrupted;
+            }
+            if is_async_error(errno) {
+                return LinuxUDPReadError::Unreachable(Self::drain_errors(fd, v6));
+            }
+        }
+        LinuxUDPReadError::Closed(if len < 0 {
+            LinuxUDPError::RecvFailed(errno)
+        } else {
+            LinuxUDPError::Shutdown
+        })
+    }
+
+    /* A read of zero bytes is either a datagram without payload
+     * or the socket being shut down (see LinuxOwner::drop),
+     * in the latter case the socket polls as hung up.
+     */
+    fn is_shutdown(fd: RawFd) -> bool {
+        let mut pfd = libc::pollfd {
+            fd,
+            events: libc::POLLRDHUP,
+            revents: 0,
+        };
+        let res = unsafe { libc::poll(&mut pfd, 1, 0) };
+        res > 0 && pfd.revents & (libc::POLLRDHUP | libc::POLLHUP) != 0
+    }
+
+    /* Drains the error queue of the socket,
+     * returning the destinations of messages reported unreachable by ICMP.
+     */
+    fn drain_errors(fd: RawFd, v6: bool) -> Vec<SocketAddr> {
+        let (level, typ, origin) = if v6 {
+            (libc::IPPROTO_IPV6, libc::IPV6_RECVERR, SO_EE_ORIGIN_ICMP6)
+        } else {
+            (libc::IPPROTO_IP, libc::IP_RECVERR, SO_EE_ORIGIN_ICMP)
+        };
+
+        let mut unreachable = Vec::new();
+        for _ in 0..MAX_DRAINED_ERRORS {
+            // the payload (a copy of the original message) is discarded
+            let mut buf = [0u8; 1];
+            let mut iovs: [libc::iovec; 1] = [libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut core::ffi::c_void,
+                iov_len: buf.len(),
+            }];
+            let mut dst: libc::sockaddr_in6 = unsafe { mem::zeroed() }; // fits either family
+            let mut control = [0u64; CONTROL_BUFFER_SIZE / 8];
+            let mut hdr = libc::msghdr {
+                msg_name: safe_cast(&mut dst),
+                msg_namelen: mem::size_of_val(&dst) as u32,
+                msg_iov: iovs.as_mut_ptr(),
+                msg_iovlen: iovs.len(),
+                msg_control: safe_cast(&mut control),
+                msg_controllen: mem::size_of_val(&control),
+                msg_flags: 0,
+            };
+
+            let flags = libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT;
+            if unsafe { libc::recvmsg(fd, &mut hdr as *mut libc::msghdr, flags) } < 0 {
+                break; // queue is empty
+            }
+
+            let control = unsafe {
+                slice::from_raw_parts(
+                    control.as_ptr() as *const u8,
+                    cmp::min(hdr.msg_controllen, CONTROL_BUFFER_SIZE),
+                )
+            };
+            let err: SockExtendedErr = match find_cmsg(control, hdr.msg_flags, level, typ) {
+                Some(err) => err,
+                None => continue,
+            };
+            log::trace!(
+                "socket error (fd = {}, errno = {}, origin = {}, type = {}, code = {})",
+                fd,
+                err.ee_errno,
+                err.ee_origin,
+                err.ee_type,
+                err.ee_code
+            );
+
+            if err.ee_origin != origin {
+                continue;
+            }
+            match err.ee_errno as libc::c_int {
+                libc::ECONNREFUSED | libc::EHOSTUNREACH | libc::ENETUNREACH | libc::EHOSTDOWN => (),
+                _ => continue,
+            }
+
+            // the name is the destination of the original message
+            let addr = if v6 {
+                LinuxEndpoint::V6(EndpointV6 {
+                    dst,
+                    info: unsafe { mem::zeroed() },
+                    hops: None,
+                    pinned: false,
+                })
+            } else {
+                LinuxEndpoint::V4(EndpointV4 {
+                    dst: unsafe { ptr::read(&dst as *const _ as *const libc::sockaddr_in) },
+                    info: unsafe { mem::zeroed() },
+                    hops: None,
+                    pinned: false,
+                })
+            }
+            .into_address();
+            if !unreachable.contains(&addr) {
+                unreachable.push(addr);
+            }
+        }
+        unreachable
+    }
+
+    fn read6(
+        fd: RawFd,
+        buf: &mut [u8],
+        flags: libc::c_int,
+    ) -> Result<(usize, LinuxEndpoint), LinuxUDPReadError> {
         log::trace!(
-            "receive IPv6 packet (block), (fd {}, max-len {})",
+            "receive IPv6 packet, (fd {}, max-len {}, flags {})",
             fd,
-            buf.len()
+            buf.len(),
+            flags
         );
 
         debug_assert!(buf.len() > 0, "reading into empty buffer (will fail)");
@@ -212,8 +833,8 @@ impl LinuxUDPReader {
             iov_base: buf.as_mut_ptr() as *mut core::ffi::c_void,
             iov_len: buf.len(),
         }];
-        let mut src: libc::sockaddr_in6 = unsafe { mem::MaybeUninit::uninit().assume_init() };
-        let mut control: ControlHeaderV6 = unsafe { mem::MaybeUninit::uninit().assume_init() };
+        let mut src: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+        let mut control = [0u64; CONTROL_BUFFER_SIZE / 8];
         let mut hdr = libc::msghdr {
             msg_name: safe_cast(&mut src),
             msg_namelen: mem::size_of_val(&src) as u32,
@@ -224,40 +845,55 @@ impl LinuxUDPReader {
             msg_flags: 0,
         };
 
-        debug_assert!(
-            hdr.msg_controllen
-                >= mem::size_of::<libc::cmsghdr>() + mem::size_of::<libc::in6_pktinfo>(),
-        );
-
-        let len = unsafe { libc::recvmsg(fd, &mut hdr as *mut libc::msghdr, 0) };
+        let len = unsafe { libc::recvmsg(fd, &mut hdr as *mut libc::msghdr, flags) };
 
         if len <= 0 {
-            // TODO: FIX!
-            return Err(io::Error::new(
-                io::ErrorKind::NotConnected,
-                format!(
-                    "failed to receive (len = {}, fd = {}, errno = {})",
-                    len,
-                    fd,
-                    errno()
-                ),
-            ));
+            return Err(Self::read_error(fd, len, true));
         }
 
+        // extract pktinfo: if absent (or truncated)
+        // the source is left to the routing table
+        let control = unsafe {
+            slice::from_raw_parts(
+                control.as_ptr() as *const u8,
+                cmp::min(hdr.msg_controllen, CONTROL_BUFFER_SIZE),
+            )
+        };
+        let info = find_cmsg(
+            control,
+            hdr.msg_flags,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_PKTINFO,
+        )
+        .unwrap_or_else(|| {
+            log::trace!(
+                "no pktinfo for received IPv6 packet (flags = {})",
+                hdr.msg_flags
+            );
+            unsafe { mem::zeroed() }
+        });
+
         Ok((
             len.try_into().unwrap(),
             LinuxEndpoint::V6(EndpointV6 {
-                info: control.info, // save pktinfo (sticky source)
-                dst: src,           // our future destination is the source address
+                info,       // save pktinfo (sticky source)
+                dst: src,   // our future destination is the source address
+                hops: None, // the hop limit of the socket
+                pinned: false,
             }),
         ))
     }
 
-    fn read4(fd: RawFd, buf: &mut [u8]) -> Result<(usize, LinuxEndpoint), io::Error> {
+    fn read4(
+        fd: RawFd,
+        buf: &mut [u8],
+        flags: libc::c_int,
+    ) -> Result<(usize, LinuxEndpoint), LinuxUDPReadError> {
         log::trace!(
-            "receive IPv4 packet (block), (fd {}, max-len {})",
+            "receive IPv4 packet, (fd {}, max-len {}, flags {})",
             fd,
-            buf.len()
+            buf.len(),
+            flags
         );
 
         debug_assert!(buf.len() > 0, "reading into empty buffer (will fail)");
@@ -266,8 +902,8 @@ impl LinuxUDPReader {
             iov_base: buf.as_mut_ptr() as *mut core::ffi::c_void,
             iov_len: buf.len(),
         }];
-        let mut src: libc::sockaddr_in = unsafe { mem::MaybeUninit::uninit().assume_init() };
-        let mut control: ControlHeaderV4 = unsafe { mem::MaybeUninit::uninit().assume_init() };
+        let mut src: libc::sockaddr_in = unsafe { mem::zeroed() };
+        let mut control = [0u64; CONTROL_BUFFER_SIZE / 8];
         let mut hdr = libc::msghdr {
             msg_name: safe_cast(&mut src),
             msg_namelen: mem::size_of_val(&src) as u32,
@@ -278,48 +914,129 @@ impl LinuxUDPReader {
             msg_flags: 0,
         };
 
-        debug_assert!(
-            hdr.msg_controllen
-                >= mem::size_of::<libc::cmsghdr>() + mem::size_of::<libc::in_pktinfo>(),
-        );
-
-        let len = unsafe { libc::recvmsg(fd, &mut hdr as *mut libc::msghdr, 0) };
+        let len = unsafe { libc::recvmsg(fd, &mut hdr as *mut libc::msghdr, flags) };
 
         if len <= 0 {
-            return Err(io::Error::new(
-                io::ErrorKind::NotConnected,
-                format!(
-                    "failed to receive (len = {}, fd = {}, errno = {})",
-                    len,
-                    fd,
-                    errno()
-                ),
-            ));
+            return Err(Self::read_error(fd, len, false));
         }
 
+        // extract pktinfo (or the original destination): if absent (or truncated)
+        // the source is left to the routing table
+        let control = unsafe {
+            slice::from_raw_parts(
+                control.as_ptr() as *const u8,
+                cmp::min(hdr.msg_controllen, CONTROL_BUFFER_SIZE),
+            )
+        };
+        let info = find_dst4(control, hdr.msg_flags).unwrap_or_else(|| {
+            log::trace!(
+                "no pktinfo for received IPv4 packet (flags = {})",
+                hdr.msg_flags
+            );
+            unsafe { mem::zeroed() }
+        });
+
         Ok((
             len.try_into().unwrap(),
             LinuxEndpoint::V4(EndpointV4 {
-                info: control.info, // save pktinfo (sticky source)
-                dst: src,           // our future destination is the source address
+                info,       // save pktinfo (sticky source)
+                dst: src,   // our future destination is the source address
+                hops: None, // the hop limit of the socket
+                pinned: false,
             }),
         ))
     }
 }
 
 impl Reader<LinuxEndpoint> for LinuxUDPReader {
-    type Error = io::Error;
+    type Error = LinuxUDPReadError;
 
     fn read(&self, buf: &mut [u8]) -> Result<(usize, LinuxEndpoint), Self::Error> {
-        match self {
-            Self::V4(fd) => Self::read4(fd.0, buf),
-            Self::V6(fd) => Self::read6(fd.0, buf),
+        let flags = self.flags.load(Ordering::Relaxed);
+        if self.v6 {
+            Self::read6(self.sock.0, buf, flags)
+        } else {
+            Self::read4(self.sock.0, buf, flags)
         }
     }
+
+    fn peek_type(&self) -> Result<Option<(u32, usize)>, Self::Error> {
+        let (fd, v6) = (self.sock.0, self.v6);
+        let flags = self.flags.load(Ordering::Relaxed);
+
+        // MSG_TRUNC: the length of the datagram is returned (rather than the bytes peeked)
+        let mut ty = [0u8; 4];
+        let len = unsafe {
+            libc::recv(
+                fd,
+                ty.as_mut_ptr() as *mut core::ffi::c_void,
+                ty.len(),
+                libc::MSG_PEEK | libc::MSG_TRUNC | flags,
+            )
+        };
+        if len <= 0 {
+            // the empty datagram is left to the read (which discards it)
+            return match Self::read_error(fd, len, v6) {
+                LinuxUDPReadError::Empty => Ok(Some((0, 0))),
+                err => Err(err),
+            };
+        }
+        Ok(Some((u32::from_le_bytes(ty), len.try_into().unwrap())))
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> bool {
+        // per read (rather than O_NONBLOCK): the writers share the socket and continue to block
+        let flags = if nonblocking { libc::MSG_DONTWAIT } else { 0 };
+        self.flags.store(flags, Ordering::Relaxed);
+        true
+    }
 }
 
 impl LinuxUDPWriter {
-    fn write6(fd: RawFd, buf: &[u8], dst: &mut EndpointV6) -> Result<(), io::Error> {
+    /* Writes the control messages of a message to the endpoint
+     * (the sticky source and the hop limit), returning the length of the control data.
+     */
+    fn control6(control: &mut SendControl, dst: &EndpointV6) -> usize {
+        let len = put_cmsg(
+            &mut control.0,
+            0,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_PKTINFO,
+            &dst.info,
+        );
+        match dst.hops {
+            Some(hops) => put_cmsg(
+                &mut control.0,
+                len,
+                libc::IPPROTO_IPV6,
+                libc::IPV6_HOPLIMIT,
+                &libc::c_int::from(hops),
+            ),
+            None => len,
+        }
+    }
+
+    fn control4(control: &mut SendControl, dst: &EndpointV4) -> usize {
+        let len = put_cmsg(
+            &mut control.0,
+            0,
+            libc::IPPROTO_IP,
+            libc::IP_PKTINFO,
+            &dst.info,
+        );
+        match dst.hops {
+            Some(hops) => put_cmsg(
+                &mut control.0,
+                len,
+                libc::IPPROTO_IP,
+                libc::IP_TTL,
+                &libc::c_int::from(hops),
+            ),
+            None => len,
+        }
+    }
+
+    fn write6(fd: RawFd, buf: &[u8], dst: &mut EndpointV6) -> Result<(), LinuxUDPError> {
         log::debug!("sending IPv6 packet ({} fd, {} bytes)", fd, buf.len());
 
         let mut iovs: [libc::iovec; 1] = [libc::iovec {
@@ -327,20 +1044,8 @@ impl LinuxUDPWriter {
             iov_len: buf.len(),
         }];
 
-        let mut control = ControlHeaderV6 {
-            hdr: libc::cmsghdr {
-                cmsg_len: CMSG_LEN(mem::size_of::<libc::in6_pktinfo>()),
-                cmsg_level: libc::IPPROTO_IPV6,
-                cmsg_type: libc::IPV6_PKTINFO,
-            },
-            info: dst.info,
-        };
-
-        debug_assert_eq!(
-            control.hdr.cmsg_len % mem::size_of::<u32>(),
-            0,
-            "cmsg_len must be aligned to a long"
-        );
+        let mut control = SendControl([0; 64]);
+        let controllen = Self::control6(&mut control, dst);
 
         debug_assert_eq!(
             dst.dst.sin6_family,
@@ -354,37 +1059,37 @@ impl LinuxUDPWriter {
             msg_iov: iovs.as_mut_ptr(),
             msg_iovlen: iovs.len(),
             msg_control: safe_cast(&mut control),
-            msg_controllen: mem::size_of_val(&control),
+            msg_controllen: controllen,
             msg_flags: 0,
         };
 
-        let ret = unsafe { libc::sendmsg(fd, &hdr, 0) };
+        let mut ret = unsafe { libc::sendmsg(fd, &hdr, 0) };
+
+        // the send failed with an error reported for an earlier message (see IP_RECVERR),
+        // which is cleared from the socket: retry
+        if ret < 0 && is_async_error(errno()) {
+            log::trace!("pending socket error, retry");
+            ret = unsafe { libc::sendmsg(fd, &hdr, 0) };
+        }
+
+        // the cached source address may no longer exist (e.g. the interface was reconfigured):
+        // clear the source of the endpoint and retry using the source selected by the routing table
+        // (unless the source is pinned: the message must not leave through another interface)
+        if ret < 0 && !dst.pinned && is_stale_source(errno()) {
+            log::trace!("clear source and retry");
+            dst.info = unsafe { mem::zeroed() };
+            hdr.msg_controllen = Self::control6(&mut control, dst);
+            ret = unsafe { libc::sendmsg(fd, &hdr, 0) };
+        }
 
         if ret < 0 {
-            if errno() == libc::EINVAL {
-                log::trace!("clear source and retry");
-                hdr.msg_control = ptr::null_mut();
-                hdr.msg_controllen = 0;
-                dst.info = unsafe { mem::zeroed() };
-                if unsafe { libc::sendmsg(fd, &hdr, 0) } < 0 {
-                    return Err(io::Error::new(
-                        io::ErrorKind::NotConnected,
-                        "failed to send IPv6 packet",
-                    ));
-                } else {
-                    return Ok(());
-                }
-            }
-            return Err(io::Error::new(
-                io::ErrorKind::NotConnected,
-                "failed to send IPv6 packet",
-            ));
+            return Err(LinuxUDPError::SendFailed(errno()));
         }
 
         Ok(())
     }
 
-    fn write4(fd: RawFd, buf: &[u8], dst: &mut EndpointV4) -> Result<(), io::Error> {
+    fn write4(fd: RawFd, buf: &[u8], dst: &mut EndpointV4) -> Result<(), LinuxUDPError> {
         log::debug!("sending IPv4 packet ({} fd, {} bytes)", fd, buf.len());
 
         let mut iovs: [libc::iovec; 1] = [libc::iovec {
@@ -392,20 +1097,8 @@ impl LinuxUDPWriter {
             iov_len: buf.len(),
         }];
 
-        let mut control = ControlHeaderV4 {
-            hdr: libc::cmsghdr {
-                cmsg_len: CMSG_LEN(mem::size_of::<libc::in_pktinfo>()),
-                cmsg_level: libc::IPPROTO_IP,
-                cmsg_type: libc::IP_PKTINFO,
-            },
-            info: dst.info,
-        };
-
-        debug_assert_eq!(
-            control.hdr.cmsg_len % mem::size_of::<u32>(),
-            0,
-            "cmsg_len must be aligned to a long"
-        );
+        let mut control = SendControl([0; 64]);
+        let controllen = Self::control4(&mut control, dst);
 
         debug_assert_eq!(
             dst.dst.sin_family,
@@ -419,31 +1112,31 @@ impl LinuxUDPWriter {
             msg_iov: iovs.as_mut_ptr(),
             msg_iovlen: iovs.len(),
             msg_control: safe_cast(&mut control),
-            msg_controllen: mem::size_of_val(&control),
+            msg_controllen: controllen,
             msg_flags: 0,
         };
 
-        let ret = unsafe { libc::sendmsg(fd, &hdr, 0) };
+        let mut ret = unsafe { libc::sendmsg(fd, &hdr, 0) };
+
+        // the send failed with an error reported for an earlier message (see IP_RECVERR),
+        // which is cleared from the socket: retry
+        if ret < 0 && is_async_error(errno()) {
+            log::trace!("pending socket error, retry");
+            ret = unsafe { libc::sendmsg(fd, &hdr, 0) };
+        }
+
+        // the cached source address may no longer exist (e.g. the interface was reconfigured):
+        // clear the source of the endpoint and retry using the source selected by the routing table
+        // (unless the source is pinned: the message must not leave through another interface)
+        if ret < 0 && !dst.pinned && is_stale_source(errno()) {
+            log::trace!("clear source and retry");
+            dst.info = unsafe { mem::zeroed() };
+            hdr.msg_controllen = Self::control4(&mut control, dst);
+            ret = unsafe { libc::sendmsg(fd, &hdr, 0) };
+        }
 
         if ret < 0 {
-            if errno() == libc::EINVAL {
-                log::trace!("clear source and retry");
-                hdr.msg_control = ptr::null_mut();
-                hdr.msg_controllen = 0;
-                dst.info = unsafe { mem::zeroed() };
-                if unsafe { libc::sendmsg(fd, &hdr, 0) } < 0 {
-                    return Err(io::Error::new(
-                        io::ErrorKind::NotConnected,
-                        "failed to send IPv4 packet",
-                    ));
-                } else {
-                    return Ok(());
-                }
-            }
-            return Err(io::Error::new(
-                io::ErrorKind::NotConnected,
-                "failed to send IPv4 packet",
-            ));
+            return Err(LinuxUDPError::SendFailed(errno()));
         }
 
         Ok(())
@@ -451,7 +1144,7 @@ impl LinuxUDPWriter {
 }
 
 impl Writer<LinuxEndpoint> for LinuxUDPWriter {
-    type Error = io::Error;
+    type Error = LinuxUDPError;
 
     fn write(&self, buf: &[u8], dst: &mut LinuxEndpoint) -> Result<(), Self::Error> {
         match dst {
@@ -462,29 +1155,150 @@ impl Writer<LinuxEndpoint> for LinuxUDPWriter {
 }
 
 impl Owner for LinuxOwner {
-    type Error = io::Error;
+    type Error = LinuxUDPError;
 
     fn get_port(&self) -> u16 {
-        self.port
+        self.ports.v6.or(self.ports.v4).unwrap_or(0)
     }
 
-    fn set_fwmark(&mut self, value: Option<u32>) -> Result<(), Self::Error> {
-        fn set_mark(fd: Option<RawFd>, value: u32) -> Result<(), io::Error> {
-            if let Some(fd) = fd {
-                setsockopt(fd, libc::SOL_SOCKET, libc::SO_MARK, &value)
-            } else {
-                Ok(())
+    fn get_ports(&self) -> BoundPorts {
+        self.ports
+    }
+
+    fn set_options(&mut self, opts: &TransportOptions) -> Result<(), Self::Error> {
+        // only changed options are set
+        // (setting e.g. the mark requires privileges, even when clearing it)
+        //
+        // every changed option is attempted, even if another fails:
+        // an option which could not be set retains its previous value (and is retried by the next call)
+        // and the first failure is returned.
+        let socks: Vec<(RawFd, bool)> = self
+            .sock6
+            .iter()
+            .chain(self.fanout6.iter())
+            .map(|sock| (sock.0, true))
+            .chain(
+                self.sock4
+                    .iter()
+                    .chain(self.fanout4.iter())
+                    .map(|sock| (sock.0, false)),
+            )
+            .collect();
+        let mut applied = self.options.clone();
+        let mut result = Ok(());
+        let mut apply = |option: &str, set: &dyn Fn(RawFd, bool) -> Result<(), LinuxUDPError>| {
+            for &(fd, v6) in socks.iter() {
+                if let Err(e) = set(fd, v6) {
+                    log::warn!("failed to set {} (fd = {}): {}", option, fd, e);
+                    if result.is_ok() {
+                        result = Err(e);
+                    }
+                    return false;
+                }
+            }
+            true
+        };
+
+        if opts.fwmark != applied.fwmark {
+            let mark = opts.fwmark.unwrap_or(0);
+            if apply("fwmark", &|fd, _| {
+                setsockopt(fd, libc::SOL_SOCKET, libc::SO_MARK, &mark)
+            }) {
+                applied.fwmark = opts.fwmark;
+            }
+        }
+
+        if opts.tos != applied.tos {
+            let tos = libc::c_int::from(opts.tos.unwrap_or(0));
+            if apply("tos", &|fd, v6| {
+                if v6 {
+                    setsockopt_int(fd, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos)
+                } else {
+                    setsockopt_int(fd, libc::IPPROTO_IP, libc::IP_TOS, tos)
+                }
+            }) {
+                applied.tos = opts.tos;
+            }
+        }
+
+        if opts.hop_limit != applied.hop_limit {
+            // -1 resets the hop limit to the default of the system
+            let hops = opts.hop_limit.map(libc::c_int::from).unwrap_or(-1);
+            if apply("hop limit", &|fd, v6| {
+                if v6 {
+                    setsockopt_int(fd, libc::IPPROTO_IPV6, libc::IPV6_UNICAST_HOPS, hops)
+                } else {
+                    setsockopt_int(fd, libc::IPPROTO_IP, libc::IP_TTL, hops)
+                }
+            }) {
+                applied.hop_limit = opts.hop_limit;
+            }
+        }
+
+        if opts.fragment != applied.fragment {
+            let pmtu = match opts.fragment {
+                FragmentPolicy::System => PMTUDISC_WANT,
+                FragmentPolicy::Fragment => PMTUDISC_DONT,
+                FragmentPolicy::Drop | FragmentPolicy::Prefragment(_) => PMTUDISC_DO,
+            };
+            if apply("path MTU discovery", &|fd, v6| {
+                if v6 {
+                    setsockopt_int(fd, libc::IPPROTO_IPV6, IPV6_MTU_DISCOVER, pmtu)
+                } else {
+                    setsockopt_int(fd, libc::IPPROTO_IP, IP_MTU_DISCOVER, pmtu)
+                }
+            }) {
+                applied.fragment = opts.fragment;
+            }
+        }
+
+        if opts.busy_poll != applied.busy_poll {
+            // 0 disables busy polling
+            // (best effort: raising the time above the system default requires CAP_NET_ADMIN)
+            let usecs = opts.busy_poll.unwrap_or(0) as libc::c_int;
+            for &(fd, _) in socks.iter() {
+                if let Err(e) = setsockopt_int(fd, libc::SOL_SOCKET, SO_BUSY_POLL, usecs) {
+                    log::warn!("failed to set busy polling (fd = {}): {}", fd, e);
+                }
+            }
+            applied.busy_poll = opts.busy_poll;
+        }
+
+        if opts.bind_device != applied.bind_device {
+            // an empty name removes the binding
+            let name = opts
+                .bind_device
+                .as_ref()
+                .map(|s| s.as_bytes())
+                .unwrap_or(&[]);
+            if apply("bound device", &|fd, _| {
+                let res = unsafe {
+                    libc::setsockopt(
+                        fd,
+                        libc::SOL_SOCKET,
+                        libc::SO_BINDTODEVICE,
+                        name.as_ptr() as *const libc::c_void,
+                        name.len() as libc::socklen_t,
+                    )
+                };
+                if res == 0 {
+                    Ok(())
+                } else {
+                    Err(LinuxUDPError::SetSockOptFailed(errno()))
+                }
+            }) {
+                applied.bind_device = opts.bind_device.clone();
             }
         }
-        let value = value.unwrap_or(0);
-        set_mark(self.sock6.as_ref().map(|fd| fd.0), value)?;
-        set_mark(self.sock4.as_ref().map(|fd| fd.0), value)
+
+        self.options = applied;
+        result
     }
 }
 
 impl Drop for LinuxOwner {
     fn drop(&mut self) {
-        log::debug!("closing the bind (port = {})", self.port);
+        log::debug!("closing the bind (port = {})", self.get_port());
         self.sock4.as_ref().map(|fd| unsafe {
             log::debug!("shutdown IPv4 (fd = {})", fd.0);
             libc::shutdown(fd.0, libc::SHUT_RDWR);
@@ -493,11 +1307,15 @@ impl Drop for LinuxOwner {
             log::debug!("shutdown IPv6 (fd = {})", fd.0);
             libc::shutdown(fd.0, libc::SHUT_RDWR);
         });
+        for fd in self.fanout4.iter().chain(self.fanout6.iter()) {
+            log::debug!("shutdown fan-out (fd = {})", fd.0);
+            unsafe { libc::shutdown(fd.0, libc::SHUT_RDWR) };
+        }
     }
 }
 
 impl UDP for LinuxUDP {
-    type Error = io::Error;
+    type Error = LinuxUDPError;
     type Endpoint = LinuxEndpoint;
     type Reader = LinuxUDPReader;
     type Writer = LinuxUDPWriter;
@@ -509,26 +1327,29 @@ impl LinuxUDP {
      * Arguments:
      *
      * - 'port', port to bind to (0 = any)
+     * - 'protocol', transport protocol of the socket
+     * - 'reuseport', share the port with other sockets (see BindOptions::fanout)
      *
      * Returns:
      *
      * Returns a tuple of the resulting port and socket.
      */
-    fn bind6(port: u16) -> Result<(u16, RawFd), io::Error> {
+    fn bind6(port: u16, protocol: Protocol, reuseport: bool) -> Result<(u16, FD), LinuxUDPError> {
         log::trace!("attempting to bind on IPv6 (port {})", port);
 
-        // create socket fd
-        let fd: RawFd = unsafe { libc::socket(libc::AF_INET6, libc::SOCK_DGRAM, 0) };
-        if fd < 0 {
-            log::debug!("failed to create IPv6 socket (errno = {})", errno());
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "failed to create socket",
-            ));
-        }
+        // create socket (closed on failure)
+        let sock = socket(libc::AF_INET6, protocol).map_err(|err| {
+            log::debug!("failed to create IPv6 socket ({})", err);
+            err
+        })?;
+        let fd = sock.0;
 
         setsockopt_int(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
+        if reuseport {
+            setsockopt_int(fd, libc::SOL_SOCKET, libc::SO_REUSEPORT, 1)?;
+        }
         setsockopt_int(fd, libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO, 1)?;
+        setsockopt_int(fd, libc::IPPROTO_IPV6, libc::IPV6_RECVERR, 1)?;
         setsockopt_int(fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, 1)?;
 
         const INADDR_ANY: libc::in6_addr = libc::in6_addr { s6_addr: [0; 16] };
@@ -550,11 +1371,9 @@ impl LinuxUDP {
             )
         };
         if err != 0 {
-            log::debug!("failed to bind IPv6 socket (errno = {})", errno());
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "failed to create socket",
-            ));
+            let err = LinuxUDPError::BindFailed(errno());
+            log::debug!("failed to bind IPv6 socket ({})", err);
+            return Err(err);
         }
 
         // get the assigned port
@@ -567,11 +1386,9 @@ impl LinuxUDP {
             )
         };
         if err != 0 {
-            log::debug!("failed to get port of IPv6 socket (errno  = {})", errno());
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "failed to create socket",
-            ));
+            let err = LinuxUDPError::GetSockNameFailed(errno());
+            log::debug!("failed to get port of IPv6 socket ({})", err);
+            return Err(err);
         }
 
         // basic sanity checks
@@ -580,7 +1397,7 @@ impl LinuxUDP {
         debug_assert_eq!(sockaddr.sin6_family, libc::AF_INET6 as libc::sa_family_t);
         debug_assert_eq!(new_port, if port != 0 { port } else { new_port });
         log::trace!("bound IPv6 socket (port {}, fd {})", new_port, fd);
-        return Ok((new_port, fd));
+        return Ok((new_port, sock));
     }
 
     /* Bind on all IPv4 interfaces.
@@ -588,26 +1405,29 @@ impl LinuxUDP {
      * Arguments:
      *
      * - 'port', port to bind to (0 = any)
+     * - 'protocol', transport protocol of the socket
+     * - 'reuseport', share the port with other sockets (see BindOptions::fanout)
      *
      * Returns:
      *
      * Returns a tuple of the resulting port and socket.
      */
-    fn bind4(port: u16) -> Result<(u16, RawFd), io::Error> {
+    fn bind4(port: u16, protocol: Protocol, reuseport: bool) -> Result<(u16, FD), LinuxUDPError> {
         log::trace!("attempting to bind on IPv4 (port {})", port);
 
-        // create socket fd
-        let fd: RawFd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
-        if fd < 0 {
-            log::debug!("failed to create IPv4 socket (errno = {})", errno());
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "failed to create socket",
-            ));
-        }
+        // create socket (closed on failure)
+        let sock = socket(libc::AF_INET, protocol).map_err(|err| {
+            log::debug!("failed to create IPv4 socket ({})", err);
+            err
+        })?;
+        let fd = sock.0;
 
         setsockopt_int(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
-        setsockopt_int(fd, libc::IPPROTO_IP, libc::IP_PKTINFO, 1)?;
+        if reuseport {
+            setsockopt_int(fd, libc::SOL_SOCKET, libc::SO_REUSEPORT, 1)?;
+        }
+        recv_dst4(fd);
+        setsockopt_int(fd, libc::IPPROTO_IP, libc::IP_RECVERR, 1)?;
 
         const INADDR_ANY: libc::in_addr = libc::in_addr { s_addr: 0 };
 
@@ -627,11 +1447,9 @@ impl LinuxUDP {
             )
         };
         if err != 0 {
-            log::debug!("failed to bind IPv4 socket (errno = {})", errno());
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "failed to create socket",
-            ));
+            let err = LinuxUDPError::BindFailed(errno());
+            log::debug!("failed to bind IPv4 socket ({})", err);
+            return Err(err);
         }
 
         // get the assigned port
@@ -644,11 +1462,9 @@ impl LinuxUDP {
             )
         };
         if err != 0 {
-            log::debug!("failed to get port of IPv4 socket (errno = {})", errno());
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "failed to create socket",
-            ));
+            let err = LinuxUDPError::GetSockNameFailed(errno());
+            log::debug!("failed to get port of IPv4 socket ({})", err);
+            return Err(err);
         }
 
         // basic sanity checks
@@ -657,52 +1473,184 @@ impl LinuxUDP {
         debug_assert_eq!(sockaddr.sin_family, libc::AF_INET as libc::sa_family_t);
         debug_assert_eq!(new_port, if port != 0 { port } else { new_port });
         log::trace!("bound IPv4 socket (port {}, fd {})", new_port, fd);
-        return Ok((new_port, fd));
+        return Ok((new_port, sock));
     }
 }
 
-impl PlatformUDP for LinuxUDP {
-    type Owner = LinuxOwner;
+impl LinuxUDP {
+    /* Binds the families of the mode on the same port
+     * (choosing a port if the port is 0).
+     *
+     * If the port chosen on IPv6 is unavailable on IPv4,
+     * IPv4 is bound on another port if the mismatch is accepted.
+     */
+    fn bind_families(
+        port: u16,
+        opts: &BindOptions,
+    ) -> Result<(BoundPorts, Option<Arc<FD>>, Option<Arc<FD>>), LinuxUDPError> {
+        let mut ports = BoundPorts::default();
+        let reuseport = opts.fanout > 1;
+
+        // bind on ipv6
+        let sock6 = if opts.mode.ipv6() {
+            let (new_port, sock) = Self::bind6(port, opts.protocol, reuseport).map_err(|e| {
+                log::info!("failed to bind on IPv6 (port {}): {}", port, e);
+                e
+            })?;
+            ports.v6 = Some(new_port);
+            Some(Arc::new(sock))
+        } else {
+            None
+        };
 
-    fn bind(mut port: u16) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Owner), Self::Error> {
-        log::debug!("bind to port {}", port);
+        // bind on ipv4 on the same port
+        // (the IPv6 socket is closed on failure)
+        let sock4 = if opts.mode.ipv4() {
+            let same = ports.v6.unwrap_or(port);
+            let (new_port, sock) = match Self::bind4(same, opts.protocol, reuseport) {
+                Err(ref e)
+                    if port == 0
+                        && same != 0
+                        && e.errno() == libc::EADDRINUSE
+                        && opts.mismatch == PortMismatch::Accept =>
+                {
+                    log::info!("port {} in use on IPv4, binding another port", same);
+                    Self::bind4(0, opts.protocol, reuseport)
+                }
+                res => res,
+            }
+            .map_err(|e| {
+                log::info!("failed to bind on IPv4 (port {}): {}", same, e);
+                e
+            })?;
+            ports.v4 = Some(new_port);
+            Some(Arc::new(sock))
+        } else {
+            None
+        };
 
-        // attempt to bind on ipv6
-        let bind6 = Self::bind6(port);
-        if let Ok((new_port, _)) = bind6 {
-            port = new_port;
+        // peers must reach the device on a single port (unless accepted otherwise)
+        if let (Some(v6), Some(v4)) = (ports.v6, ports.v4) {
+            if v6 != v4 && opts.mismatch != PortMismatch::Accept {
+                return Err(LinuxUDPError::PortMismatch(v6, v4));
+            }
         }
+        Ok((ports, sock6, sock4))
+    }
 
-        // attempt to bind on ipv4 on the same port
-        let bind4 = Self::bind4(port);
-        if let Ok((new_port, _)) = bind4 {
-            port = new_port;
+    /* Binds the additional sockets of the fan-out on the port of the first socket
+     * and steers the messages received by the group (see reuseport_program).
+     *
+     * Returns the additional sockets (the first socket excluded).
+     */
+    fn bind_fanout(
+        first: &FD,
+        port: u16,
+        v6: bool,
+        opts: &BindOptions,
+    ) -> Result<Vec<Arc<FD>>, LinuxUDPError> {
+        let mut socks = Vec::with_capacity(opts.fanout - 1);
+        for _ in 1..opts.fanout {
+            let (_, sock) = if v6 {
+                Self::bind6(port, opts.protocol, true)?
+            } else {
+                Self::bind4(port, opts.protocol, true)?
+            };
+            socks.push(Arc::new(sock));
         }
 
-        // check if failed to bind on both
-        if bind4.is_err() && bind6.is_err() {
-            log::trace!("failed to bind for either IP version");
-            return Err(bind6.unwrap_err());
+        // without the program (e.g. an older kernel) messages are distributed by flow hash
+        if let Err(e) = attach_reuseport_program(first.0, opts.fanout as u32) {
+            log::warn!(
+                "failed to steer the fan-out on port {} by receiver index: {}",
+                port,
+                e
+            );
         }
+        Ok(socks)
+    }
+}
 
-        let sock6 = bind6.ok().map(|(_, fd)| Arc::new(FD(fd)));
-        let sock4 = bind4.ok().map(|(_, fd)| Arc::new(FD(fd)));
+impl PlatformUDP for LinuxUDP {
+    type Owner = LinuxOwner;
 
-        // create owner
-        let owner = LinuxOwner {
+    fn bind_with(
+        port: u16,
+        opts: &BindOptions,
+    ) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Owner), Self::Error> {
+        log::debug!(
+            "bind to port {} ({:?}, {:?})",
             port,
+            opts.mode,
+            opts.protocol
+        );
+
+        // when the port is chosen by the OS for IPv6 it may be taken on IPv4:
+        // retry with a new port, since peers must reach us on a single port.
+        let mut attempt = 1;
+        let (ports, sock6, sock4) = loop {
+            match Self::bind_families(port, opts) {
+                Ok(socks) => break socks,
+                Err(e) => {
+                    let retry = port == 0
+                        && opts.mode == BindMode::Dual
+                        && opts.mismatch == PortMismatch::Retry
+                        && e.errno() == libc::EADDRINUSE
+                        && attempt < opts.attempts;
+                    if !retry {
+                        return Err(e);
+                    }
+                    log::debug!("port in use on IPv4, retry bind (attempt {})", attempt);
+                    attempt += 1;
+                }
+            }
+        };
+
+        if ports.mismatch() {
+            log::warn!(
+                "bound IPv6 on port {:?} and IPv4 on port {:?}",
+                ports.v6,
+                ports.v4
+            );
+        }
+
+        // bind the additional sockets of the fan-out (closing every socket on failure)
+        let mut fanout6 = vec![];
+        let mut fanout4 = vec![];
+        if opts.fanout > 1 {
+            if let (Some(sock), Some(port)) = (sock6.as_ref(), ports.v6) {
+                fanout6 = Self::bind_fanout(sock, port, true, opts)?;
+            }
+            if let (Some(sock), Some(port)) = (sock4.as_ref(), ports.v4) {
+                fanout4 = Self::bind_fanout(sock, port, false, opts)?;
+            }
+        }
+
+        // create owner
+        let mut owner = LinuxOwner {
+            ports,
             sock6: sock6.clone(),
             sock4: sock4.clone(),
+            fanout6: fanout6.clone(),
+            fanout4: fanout4.clone(),
+            options: TransportOptions::default(),
         };
 
-        // create readers
-        let mut readers: Vec<Self::Reader> = Vec::with_capacity(2);
+        // apply transport options to the new sockets
+        // (the options are optional: a failure is logged and the sockets are used without the option,
+        //  e.g. when the mark requires privileges the process lacks)
+        let _ = owner.set_options(&opts.transport);
+
+        // create readers (one for every socket)
+        let mut readers: Vec<Self::Reader> = Vec::with_capacity(2 * opts.fanout);
         sock6
-            .clone()
-            .map(|sock| readers.push(LinuxUDPReader::V6(sock)));
+            .iter()
+            .chain(fanout6.iter())
+            .for_each(|sock| readers.push(LinuxUDPReader::new(sock.clone(), true)));
         sock4
-            .clone()
-            .map(|sock| readers.push(LinuxUDPReader::V4(sock)));
+            .iter()
+            .chain(fanout4.iter())
+            .for_each(|sock| readers.push(LinuxUDPReader::new(sock.clone(), false)));
         debug_assert!(readers.len() > 0);
 
         // create writer
@@ -714,3 +1662,361 @@ impl PlatformUDP for LinuxUDP {
         Ok((readers, writer, owner))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::{Ipv6Addr, UdpSocket};
+    use std::os::unix::io::IntoRawFd;
+
+    // serializes control messages as laid out by the kernel
+    fn cmsgs(msgs: &[(libc::c_int, libc::c_int, &[u8])]) -> Vec<u8> {
+        let align =
+            |len: usize| (len + mem::size_of::<usize>() - 1) & !(mem::size_of::<usize>() - 1);
+        let hdr_len = align(mem::size_of::<libc::cmsghdr>());
+        let mut buf = vec![];
+        for (level, typ, data) in msgs {
+            let mut hdr: libc::cmsghdr = unsafe { mem::zeroed() };
+            hdr.cmsg_len = (hdr_len + data.len()) as _;
+            hdr.cmsg_level = *level;
+            hdr.cmsg_type = *typ;
+            let start = buf.len();
+            buf.resize(start + align(hdr_len + data.len()), 0);
+            unsafe {
+                ptr::write_unaligned(buf[start..].as_mut_ptr() as *mut libc::cmsghdr, hdr);
+            }
+            buf[start + hdr_len..start + hdr_len + data.len()].copy_from_slice(data);
+        }
+        buf
+    }
+
+    fn pktinfo() -> [u8; 12] {
+        let mut info: libc::in_pktinfo = unsafe { mem::zeroed() };
+        info.ipi_ifindex = 7;
+        info.ipi_spec_dst = libc::in_addr {
+            s_addr: u32::from_ne_bytes([10, 0, 0, 1]),
+        };
+        unsafe { mem::transmute(info) }
+    }
+
+    fn find(control: &[u8], flags: libc::c_int) -> Option<libc::in_pktinfo> {
+        find_cmsg(control, flags, libc::IPPROTO_IP, libc::IP_PKTINFO)
+    }
+
+    #[test]
+    fn test_cmsg_pktinfo() {
+        let control = cmsgs(&[(libc::IPPROTO_IP, libc::IP_PKTINFO, &pktinfo())]);
+        let info = find(&control, 0).unwrap();
+        assert_eq!(info.ipi_ifindex, 7);
+        assert_eq!(info.ipi_spec_dst.s_addr.to_ne_bytes(), [10, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_cmsg_pktinfo_after_other() {
+        let control = cmsgs(&[
+            (libc::IPPROTO_IP, libc::IP_TOS, &[0x10]),
+            (libc::IPPROTO_IP, libc::IP_TTL, &[64, 0, 0, 0]),
+            (libc::IPPROTO_IP, libc::IP_PKTINFO, &pktinfo()),
+        ]);
+        assert_eq!(find(&control, 0).unwrap().ipi_ifindex, 7);
+    }
+
+    #[test]
+    fn test_cmsg_missing_pktinfo() {
+        assert!(find(&[], 0).is_none());
+        let control = cmsgs(&[(libc::IPPROTO_IP, libc::IP_TOS, &[0x10])]);
+        assert!(find(&control, 0).is_none());
+
+        // pktinfo of the other address family
+        let control = cmsgs(&[(libc::IPPROTO_IPV6, libc::IPV6_PKTINFO, &pktinfo())]);
+        assert!(find(&control, 0).is_none());
+    }
+
+    #[test]
+    fn test_cmsg_truncated() {
+        let control = cmsgs(&[(libc::IPPROTO_IP, libc::IP_PKTINFO, &pktinfo())]);
+
+        // control data truncated by the kernel
+        assert!(find(&control, libc::MSG_CTRUNC).is_none());
+
+        // message extends beyond the control data
+        for len in 0..control.len() - 4 {
+            assert!(find(&control[..len], 0).is_none());
+        }
+
+        // message too short for the pktinfo
+        let control = cmsgs(&[(libc::IPPROTO_IP, libc::IP_PKTINFO, &pktinfo()[..8])]);
+        assert!(find(&control, 0).is_none());
+    }
+
+    #[test]
+    fn test_cmsg_origdstaddr() {
+        let mut dst: libc::sockaddr_in = unsafe { mem::zeroed() };
+        dst.sin_family = libc::AF_INET as libc::sa_family_t;
+        dst.sin_addr = libc::in_addr {
+            s_addr: u32::from_ne_bytes([10, 0, 0, 2]),
+        };
+        let dst: [u8; 16] = unsafe { mem::transmute(dst) };
+
+        // the original destination is the source of replies (any interface)
+        let control = cmsgs(&[(libc::IPPROTO_IP, IP_ORIGDSTADDR, &dst)]);
+        let info = find_dst4(&control, 0).unwrap();
+        assert_eq!(info.ipi_ifindex, 0);
+        assert_eq!(info.ipi_spec_dst.s_addr, u32::from_ne_bytes([10, 0, 0, 2]));
+
+        // pktinfo takes precedence
+        let control = cmsgs(&[
+            (libc::IPPROTO_IP, IP_ORIGDSTADDR, &dst),
+            (libc::IPPROTO_IP, libc::IP_PKTINFO, &pktinfo()),
+        ]);
+        let info = find_dst4(&control, 0).unwrap();
+        assert_eq!(info.ipi_ifindex, 7);
+        assert_eq!(info.ipi_spec_dst.s_addr, u32::from_ne_bytes([10, 0, 0, 1]));
+
+        assert!(find_dst4(&cmsgs(&[]), 0).is_none());
+    }
+
+    #[test]
+    fn test_put_cmsg() {
+        let mut control = SendControl([0; 64]);
+        let len = put_cmsg(
+            &mut control.0,
+            0,
+            libc::IPPROTO_IP,
+            libc::IP_PKTINFO,
+            &pktinfo(),
+        );
+        let len = put_cmsg(
+            &mut control.0,
+            len,
+            libc::IPPROTO_IP,
+            libc::IP_TTL,
+            &(7 as libc::c_int),
+        );
+
+        // laid out as by the kernel
+        let expected = cmsgs(&[
+            (libc::IPPROTO_IP, libc::IP_PKTINFO, &pktinfo()),
+            (libc::IPPROTO_IP, libc::IP_TTL, &7i32.to_ne_bytes()),
+        ]);
+        assert_eq!(&control.0[..len], &expected[..]);
+        assert_eq!(
+            find_cmsg::<libc::c_int>(&control.0[..len], 0, libc::IPPROTO_IP, libc::IP_TTL),
+            Some(7)
+        );
+    }
+
+    #[test]
+    fn test_cmsg_invalid_length() {
+        let mut control = cmsgs(&[
+            (libc::IPPROTO_IP, libc::IP_TOS, &[0x10]),
+            (libc::IPPROTO_IP, libc::IP_PKTINFO, &pktinfo()),
+        ]);
+        let mut hdr: libc::cmsghdr = unsafe { mem::zeroed() };
+        hdr.cmsg_len = 0; // would loop forever if not rejected
+        unsafe {
+            ptr::write_unaligned(control.as_mut_ptr() as *mut libc::cmsghdr, hdr);
+        }
+        assert!(find(&control, 0).is_none());
+    }
+
+    #[test]
+    fn test_endpoint_eq() {
+        let addr = "192.0.2.1:51820".parse().unwrap();
+        let mut endpoint = LinuxEndpoint::from_address(addr);
+        assert!(endpoint == LinuxEndpoint::from_address(addr));
+        assert!(endpoint != LinuxEndpoint::from_address("192.0.2.1:51821".parse().unwrap()));
+        assert!(endpoint != LinuxEndpoint::from_address("[2001:db8::1]:51820".parse().unwrap()));
+
+        // the hop limit is ignored, the source is not
+        endpoint.set_hop_limit(Some(7));
+        assert!(endpoint == LinuxEndpoint::from_address(addr));
+        endpoint.set_src(2, Some("192.0.2.2".parse().unwrap()));
+        assert!(endpoint != LinuxEndpoint::from_address(addr));
+        endpoint.clear_src();
+        assert!(endpoint == LinuxEndpoint::from_address(addr));
+    }
+
+    #[test]
+    fn test_pinned_source_v4() {
+        let mut endpoint = LinuxEndpoint::from_address("192.0.2.1:51820".parse().unwrap());
+        endpoint.set_src(3, Some("192.0.2.2".parse().unwrap()));
+
+        // the pinned interface and address reach the pktinfo of the message
+        let dst = match endpoint {
+            LinuxEndpoint::V4(ref dst) => dst,
+            _ => unreachable!(),
+        };
+        assert!(dst.pinned);
+        let mut control = SendControl([0; 64]);
+        let len = LinuxUDPWriter::control4(&mut control, dst);
+        let info = find(&control.0[..len], 0).unwrap();
+        assert_eq!(info.ipi_ifindex, 3);
+        assert_eq!(info.ipi_spec_dst.s_addr.to_ne_bytes(), [192, 0, 2, 2]);
+
+        // only the interface pinned: the address is selected by the routing table
+        endpoint.set_src(3, None);
+        let dst = match endpoint {
+            LinuxEndpoint::V4(ref dst) => dst,
+            _ => unreachable!(),
+        };
+        assert!(dst.pinned);
+        let len = LinuxUDPWriter::control4(&mut control, dst);
+        let info = find(&control.0[..len], 0).unwrap();
+        assert_eq!(info.ipi_ifindex, 3);
+        assert_eq!(info.ipi_spec_dst.s_addr, 0);
+
+        // a cleared source is no longer pinned
+        endpoint.clear_src();
+        match endpoint {
+            LinuxEndpoint::V4(ref dst) => assert!(!dst.pinned),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_pinned_source_v6() {
+        let addr: Ipv6Addr = "2001:db8::2".parse().unwrap();
+        let mut endpoint = LinuxEndpoint::from_address("[2001:db8::1]:51820".parse().unwrap());
+        endpoint.set_src(4, Some(IpAddr::V6(addr)));
+
+        let dst = match endpoint {
+            LinuxEndpoint::V6(ref dst) => dst,
+            _ => unreachable!(),
+        };
+        assert!(dst.pinned);
+        let mut control = SendControl([0; 64]);
+        let len = LinuxUDPWriter::control6(&mut control, dst);
+        let info: libc::in6_pktinfo =
+            find_cmsg(&control.0[..len], 0, libc::IPPROTO_IPV6, libc::IPV6_PKTINFO).unwrap();
+        assert_eq!(info.ipi6_ifindex, 4);
+        assert_eq!(info.ipi6_addr.s6_addr, addr.octets());
+
+        // an address of the other family is ignored
+        endpoint.set_src(0, Some("192.0.2.2".parse().unwrap()));
+        let dst = match endpoint {
+            LinuxEndpoint::V6(ref dst) => dst,
+            _ => unreachable!(),
+        };
+        let len = LinuxUDPWriter::control6(&mut control, dst);
+        let info: libc::in6_pktinfo =
+            find_cmsg(&control.0[..len], 0, libc::IPPROTO_IPV6, libc::IPV6_PKTINFO).unwrap();
+        assert!(!dst.pinned);
+        assert_eq!(info.ipi6_ifindex, 0);
+        assert_eq!(info.ipi6_addr.s6_addr, [0; 16]);
+    }
+
+    #[test]
+    fn test_read_empty_datagram() {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = sock.local_addr().unwrap();
+        let reader = LinuxUDPReader::new(Arc::new(FD(sock.into_raw_fd())), false);
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        // an empty datagram is discarded, rather than closing the reader
+        sender.send_to(&[], addr).unwrap();
+        sender.send_to(&[1, 0, 0, 0], addr).unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(reader.peek_type().unwrap(), Some((0, 0)));
+        match reader.read(&mut buf) {
+            Err(e) => assert_eq!(e.kind(), ReadErrorKind::Transient),
+            Ok(_) => panic!("expected the empty datagram to be discarded"),
+        }
+        assert_eq!(reader.peek_type().unwrap(), Some((1, 4)));
+        assert_eq!(reader.read(&mut buf).unwrap().0, 4);
+
+        // a socket shut down closes the reader
+        unsafe { libc::shutdown(reader.sock.0, libc::SHUT_RDWR) };
+        match reader.read(&mut buf) {
+            Err(e) => assert_eq!(e.kind(), ReadErrorKind::Closed),
+            Ok(_) => panic!("expected the reader to be closed"),
+        }
+    }
+
+    #[test]
+    fn test_options_best_effort() {
+        // the device does not exist (or the binding requires privileges)
+        let mut opts = BindOptions::default();
+        opts.mode = BindMode::V4Only;
+        opts.transport.tos = Some(0x10);
+        opts.transport.bind_device = Some("wg-missing0".to_string());
+
+        // the bind succeeds without the failed option
+        let (_readers, _writer, mut owner) = LinuxUDP::bind_with(0, &opts).unwrap();
+        assert_eq!(owner.options.tos, Some(0x10));
+        assert_eq!(owner.options.bind_device, None);
+
+        // explicitly setting the option reports the failure (and is retried)
+        assert!(owner.set_options(&opts.transport).is_err());
+        assert_eq!(owner.options.tos, Some(0x10));
+        assert_eq!(owner.options.bind_device, None);
+    }
+
+    #[test]
+    fn test_write_error_kind() {
+        let kind = |errno| LinuxUDPError::SendFailed(errno).kind();
+        assert_eq!(kind(libc::EHOSTUNREACH), WriteErrorKind::Unreachable);
+        assert_eq!(kind(libc::ENETUNREACH), WriteErrorKind::Unreachable);
+        assert_eq!(kind(libc::EPERM), WriteErrorKind::Permission);
+        assert_eq!(kind(libc::ENOBUFS), WriteErrorKind::NoBuffers);
+        assert_eq!(kind(libc::EMSGSIZE), WriteErrorKind::TooBig);
+        assert_eq!(kind(libc::EBADF), WriteErrorKind::Other);
+        assert_eq!(LinuxUDPError::SendFailed(libc::EPERM).errno(), libc::EPERM);
+    }
+
+    // interprets the instructions of the reuseport program
+    // (out of bounds loads abort the program: return 0)
+    fn run(program: &[SockFilter], payload: &[u8]) -> u32 {
+        let mut acc = 0u32;
+        let mut pc = 0;
+        loop {
+            let insn = program[pc];
+            pc += 1;
+            match insn.code {
+                BPF_LD_W_ABS => {
+                    let k = insn.k as usize;
+                    match payload.get(k..k + 4) {
+                        Some(word) => acc = u32::from_be_bytes(word.try_into().unwrap()),
+                        None => return 0,
+                    }
+                }
+                BPF_JEQ_K => pc += (if acc == insn.k { insn.jt } else { insn.jf }) as usize,
+                BPF_MOD_K => acc %= insn.k,
+                BPF_RET_A => return acc,
+                BPF_RET_K => return insn.k,
+                code => panic!("unexpected instruction {:x}", code),
+            }
+        }
+    }
+
+    #[test]
+    fn test_reuseport_program() {
+        let program = reuseport_program(4);
+        let transport = |receiver: u32| {
+            let mut msg = vec![4, 0, 0, 0];
+            msg.extend_from_slice(&receiver.to_le_bytes());
+            msg.extend_from_slice(&[0u8; 24]);
+            msg
+        };
+
+        // transport messages: by receiver index (every message of a session on the same socket)
+        let mut sockets = [0; 4];
+        for receiver in 0..64u32 {
+            let idx = run(&program, &transport(receiver.wrapping_mul(0x9e3779b9)));
+            assert!(idx < 4);
+            assert_eq!(
+                idx,
+                run(&program, &transport(receiver.wrapping_mul(0x9e3779b9)))
+            );
+            sockets[idx as usize] += 1;
+        }
+        assert!(sockets.iter().all(|count| *count > 0));
+
+        // other messages (and garbage): beyond the group (the kernel hashes the flow)
+        let mut initiation = vec![1, 0, 0, 0];
+        initiation.extend_from_slice(&[0u8; 144]);
+        assert_eq!(run(&program, &initiation), !0);
+        assert_eq!(run(&program, &[4, 0, 0, 1, 0, 0, 0, 0]), !0);
+    }
+}
@@ -1,5 +1,7 @@
 mod endpoint;
 
+pub mod chain;
+pub mod net;
 pub mod tun;
 pub mod uapi;
 pub mod udp;
@@ -9,7 +11,10 @@ pub use endpoint::Endpoint;
 #[cfg(target_os = "linux")]
 pub mod linux;
 
-#[cfg(test)]
+#[cfg(any(target_os = "freebsd", target_os = "macos"))]
+pub mod bsd;
+
+#[cfg(any(test, feature = "dummy"))]
 pub mod dummy;
 
 #[cfg(target_os = "linux")]
@@ -0,0 +1,66 @@
+use std::error::Error;
+use std::net::IpAddr;
+
+/// Configuration of addresses, routes and link state of a network interface.
+///
+/// This enables wg-quick style configuration of the tunnel interface,
+/// without invoking platform specific tools (ip, ifconfig, route).
+pub trait Net: Sized {
+    type Error: Error;
+
+    /// Open a handle for configuring interfaces
+    /// (e.g. a netlink or routing socket)
+    fn open() -> Result<Self, Self::Error>;
+
+    /// Assign an address to the interface
+    ///
+    /// # Arguments
+    ///
+    /// - `name`: The name of the interface
+    /// - `addr`: The address to assign
+    /// - `prefix`: The length of the subnet prefix
+    fn add_address(&self, name: &str, addr: IpAddr, prefix: u8) -> Result<(), Self::Error>;
+
+    /// Remove an address from the interface
+    ///
+    /// # Arguments
+    ///
+    /// - `name`: The name of the interface
+    /// - `addr`: The address to remove
+    /// - `prefix`: The length of the subnet prefix
+    fn remove_address(&self, name: &str, addr: IpAddr, prefix: u8) -> Result<(), Self::Error>;
+
+    /// Route the subnet through the interface
+    ///
+    /// # Arguments
+    ///
+    /// - `name`: The name of the interface
+    /// - `dst`: The subnet (must not have any bits set right of `prefix`)
+    /// - `prefix`: The length of the subnet prefix
+    fn add_route(&self, name: &str, dst: IpAddr, prefix: u8) -> Result<(), Self::Error>;
+
+    /// Remove the route for the subnet through the interface
+    ///
+    /// # Arguments
+    ///
+    /// - `name`: The name of the interface
+    /// - `dst`: The subnet
+    /// - `prefix`: The length of the subnet prefix
+    fn remove_route(&self, name: &str, dst: IpAddr, prefix: u8) -> Result<(), Self::Error>;
+
+    /// Set the administrative state of the interface
+    ///
+    /// # Arguments
+    ///
+    /// - `name`: The name of the interface
+    /// - `up`: Bring the interface up (true) or down (false)
+    fn set_link(&self, name: &str, up: bool) -> Result<(), Self::Error>;
+
+    /// Set the MTU of the interface
+    ///
+    /// # Arguments
+    ///
+    /// - `name`: The name of the interface
+    /// - `mtu`: The new maximum transmission unit
+    fn set_mtu(&self, name: &str, mtu: usize) -> Result<(), Self::Error>;
+}
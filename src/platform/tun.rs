@@ -5,6 +5,20 @@ pub enum TunEvent {
     Down,      // interface is down
 }
 
+/// Classification of errors returned when reading from the tunnel device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadErrorKind {
+    /// The device is gone (e.g. the interface was deleted): no further reads will succeed.
+    Closed,
+    /// Temporary failure (e.g. EAGAIN or EINTR): the read should be retried.
+    Transient,
+}
+
+pub trait ReadError: Error {
+    /// Returns the classification of the error
+    fn kind(&self) -> ReadErrorKind;
+}
+
 pub trait Status: Send + 'static {
     type Error: Error;
 
@@ -29,7 +43,7 @@ pub trait Writer: Send + Sync + 'static {
 }
 
 pub trait Reader: Send + 'static {
-    type Error: Error;
+    type Error: ReadError;
 
     /// Reads an IP packet into dst[offset:] from the tunnel device
     ///
@@ -44,19 +58,60 @@ pub trait Reader: Send + 'static {
     ///
     /// # Returns
     ///
-    /// The size of the IP packet (ignoring the header) or an std::error::Error instance:
+    /// The size of the IP packet (ignoring the header) or an error,
+    /// which can be classified as either transient or fatal (see ReadError).
     fn read(&self, buf: &mut [u8], offset: usize) -> Result<usize, Self::Error>;
+
+    /// Makes reads return a transient error (rather than block) when no packet is available,
+    /// used by the busy polling readers of the performance mode.
+    ///
+    /// # Returns
+    ///
+    /// False if nonblocking reads are not supported by the reader (the default)
+    fn set_nonblocking(&self, _nonblocking: bool) -> bool {
+        false
+    }
 }
 
 pub trait Tun: Send + Sync + 'static {
     type Writer: Writer;
     type Reader: Reader;
-    type Error: Error;
+    type Error: Error + Send + Sync + 'static;
+}
+
+/// Options for creating the TUN device
+#[derive(Debug, Clone, Default)]
+pub struct TunOptions {
+    /// Keep the interface after the device is closed (e.g. when the process exits),
+    /// or remove it on exit (even if it existed beforehand).
+    /// When None, the persistence of an existing interface is left unchanged.
+    pub persist: Option<bool>,
+    /// User permitted to attach to the (persistent) interface
+    pub owner: Option<u32>,
+    /// Group permitted to attach to the (persistent) interface
+    pub group: Option<u32>,
+    /// Name to give the interface once created (e.g. when the requested name is a "%d" template),
+    /// an existing interface can only be renamed while it is down.
+    pub rename: Option<String>,
 }
 
 /// On some platforms the application can create the TUN device itself.
 pub trait PlatformTun: Tun {
     type Status: Status;
 
-    fn create(name: &str) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Status), Self::Error>;
+    /// Create the TUN device with the default options
+    fn create(name: &str) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Status), Self::Error> {
+        Self::create_with(name, &TunOptions::default())
+    }
+
+    /// Create (or attach to an existing) TUN device
+    ///
+    /// # Arguments
+    ///
+    /// - name: The name of the interface
+    /// - options: Persistence and ownership of the interface
+    fn create_with(
+        name: &str,
+        options: &TunOptions,
+    ) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Status), Self::Error>;
 }
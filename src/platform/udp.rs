@@ -1,20 +1,87 @@
 use super::Endpoint;
 use std::error::Error;
+use std::net::SocketAddr;
+
+/// Classification of errors returned when reading from the UDP sockets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadErrorKind {
+    /// The socket is closed: no further reads will succeed.
+    Closed,
+    /// Temporary failure (e.g. EINTR): the read should be retried.
+    Transient,
+    /// Messages sent earlier could not be delivered (e.g. ICMP port unreachable),
+    /// the destinations are available through `unreachable`: the read should be retried.
+    Unreachable,
+}
+
+pub trait ReadError: Error {
+    /// Returns the classification of the error
+    fn kind(&self) -> ReadErrorKind;
+
+    /// Returns the destinations reported unreachable
+    fn unreachable(&self) -> &[SocketAddr] {
+        &[]
+    }
+}
 
 pub trait Reader<E: Endpoint>: Send + Sync {
-    type Error: Error;
+    type Error: ReadError;
 
     fn read(&self, buf: &mut [u8]) -> Result<(usize, E), Self::Error>;
+
+    /// Returns the type (the first 4 bytes, little-endian) and the size of the next message,
+    /// without consuming it: the message is then read into a buffer of its exact size
+    /// (rather than a buffer of the maximum size).
+    /// The type of messages shorter than 4 bytes is zero padded.
+    ///
+    /// Only a single thread reads from a reader, hence the next read returns the peeked message.
+    ///
+    /// # Returns
+    ///
+    /// None if peeking is not supported by the reader (the default)
+    fn peek_type(&self) -> Result<Option<(u32, usize)>, Self::Error> {
+        Ok(None)
+    }
+
+    /// Makes reads (and peeks) return a transient error (rather than block)
+    /// when no message is available, used by the busy polling readers of the performance mode.
+    ///
+    /// # Returns
+    ///
+    /// False if nonblocking reads are not supported by the reader (the default)
+    fn set_nonblocking(&self, _nonblocking: bool) -> bool {
+        false
+    }
+}
+
+/// Classification of errors returned when writing to the UDP sockets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteErrorKind {
+    /// The destination (or network) is unreachable
+    Unreachable,
+    /// Sending is not permitted (e.g. by a firewall rule)
+    Permission,
+    /// Out of buffer space: the message was dropped
+    NoBuffers,
+    /// The message exceeds the path MTU and may not be fragmented (see FragmentPolicy)
+    TooBig,
+    /// Any other failure
+    Other,
+}
+
+pub trait WriteError: Error {
+    /// Returns the classification of the error
+    fn kind(&self) -> WriteErrorKind;
 }
 
 pub trait Writer<E: Endpoint>: Send + Sync + 'static {
-    type Error: Error;
+    type Error: WriteError;
 
     fn write(&self, buf: &[u8], dst: &mut E) -> Result<(), Self::Error>;
 }
 
 pub trait UDP: Send + Sync + 'static {
-    type Error: Error;
+    type Error: Error + Send + Sync + 'static;
     type Endpoint: Endpoint;
 
     /* Until Rust gets type equality constraints these have to be generic */
@@ -22,15 +89,182 @@ pub trait UDP: Send + Sync + 'static {
     type Reader: Reader<Self::Endpoint>;
 }
 
-/// On platforms where fwmark can be set and the
+/// Options applied to every socket of the transport
+/// (including sockets created later, i.e. when binding to a new port).
+///
+/// The transport consists of the sockets bound by PlatformUDP only:
+/// there are no per-peer (connected) sockets nor a TCP fallback to apply the options to.
+/// Failing to apply an option when binding is not fatal (the sockets are used without it).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransportOptions {
+    /// Firewall mark (or similar, depending on platform)
+    pub fwmark: Option<u32>,
+    /// Type of service (IPv4) and traffic class (IPv6)
+    pub tos: Option<u8>,
+    /// TTL (IPv4) and unicast hop limit (IPv6)
+    pub hop_limit: Option<u8>,
+    /// Name of the interface to which the sockets are bound
+    pub bind_device: Option<String>,
+    /// Handling of messages exceeding the path MTU
+    pub fragment: FragmentPolicy,
+    /// Time (in microseconds) to busy poll the receive queue before blocking
+    /// (see PerformanceMode)
+    pub busy_poll: Option<u32>,
+}
+
+/// Handling of outer datagrams exceeding the path MTU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FragmentPolicy {
+    /// The platform default (Linux: DF is set, but the host fragments datagrams exceeding the MTU of the route)
+    System,
+    /// DF is set and datagrams exceeding the path MTU are dropped (counted as too big)
+    Drop,
+    /// DF is cleared and datagrams exceeding the path MTU are fragmented by the kernel (and routers)
+    Fragment,
+    /// DF is set and inner IPv4 packets are fragmented before encryption,
+    /// such that every outer datagram carries at most the given number of bytes of UDP payload
+    /// (inner packets which may not be fragmented are sent as is)
+    Prefragment(u16),
+}
+
+impl Default for FragmentPolicy {
+    fn default() -> Self {
+        FragmentPolicy::System
+    }
+}
+
+/// On platforms where transport options (e.g. fwmark) can be set and the
 /// implementation can bind to a new port during later configuration (UAPI support),
-/// this type provides the ability to set the fwmark and close the socket (by dropping the instance)
+/// this type provides the ability to set the options and close the socket (by dropping the instance)
 pub trait Owner: Send {
     type Error: Error;
 
     fn get_port(&self) -> u16;
 
-    fn set_fwmark(&mut self, value: Option<u32>) -> Result<(), Self::Error>;
+    /// Returns the port bound on each address family
+    /// (the ports differ only if permitted by PortMismatch::Accept)
+    fn get_ports(&self) -> BoundPorts;
+
+    /// Apply the transport options to the sockets
+    /// (options absent from `opts` are reset to the platform default)
+    ///
+    /// Every option is attempted: the options which failed retain their previous value
+    /// and the first failure is returned.
+    fn set_options(&mut self, opts: &TransportOptions) -> Result<(), Self::Error>;
+}
+
+/// The address families to bind
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BindMode {
+    /// Bind both IPv4 and IPv6 (on the same port)
+    Dual,
+    /// Bind only IPv4
+    V4Only,
+    /// Bind only IPv6
+    V6Only,
+}
+
+impl Default for BindMode {
+    fn default() -> Self {
+        BindMode::Dual
+    }
+}
+
+impl BindMode {
+    pub fn ipv4(self) -> bool {
+        self != BindMode::V6Only
+    }
+
+    pub fn ipv6(self) -> bool {
+        self != BindMode::V4Only
+    }
+}
+
+/// The transport protocol of the sockets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// UDP (IPPROTO_UDP)
+    Udp,
+    /// UDP-Lite (IPPROTO_UDPLITE) with partial checksum coverage, for lossy radio links
+    /// (experimental: the peer must use UDP-Lite as well, falls back to UDP if unsupported)
+    UdpLite,
+}
+
+impl Default for Protocol {
+    fn default() -> Self {
+        Protocol::Udp
+    }
+}
+
+/// Behavior when the port chosen by the OS on IPv6 is unavailable on IPv4
+/// (when binding both address families with the listen port 0)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortMismatch {
+    /// Retry with another port (see BindOptions::attempts), then fail
+    Retry,
+    /// Fail without retrying
+    Fail,
+    /// Bind IPv4 on another port chosen by the OS (see Owner::get_ports)
+    Accept,
+}
+
+impl Default for PortMismatch {
+    fn default() -> Self {
+        PortMismatch::Retry
+    }
+}
+
+/// The ports bound on each address family (None if the family is not bound)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BoundPorts {
+    pub v4: Option<u16>,
+    pub v6: Option<u16>,
+}
+
+impl BoundPorts {
+    /// Returns true if both families are bound on different ports
+    pub fn mismatch(&self) -> bool {
+        match (self.v4, self.v6) {
+            (Some(v4), Some(v6)) => v4 != v6,
+            _ => false,
+        }
+    }
+}
+
+/// Default number of attempts at finding a port available on both address families
+pub const DEFAULT_BIND_ATTEMPTS: usize = 10;
+
+/// Options for binding the UDP sockets
+#[derive(Debug, Clone)]
+pub struct BindOptions {
+    /// Address families to bind
+    pub mode: BindMode,
+    /// Transport protocol of the sockets
+    pub protocol: Protocol,
+    /// Options applied to the sockets
+    pub transport: TransportOptions,
+    /// Number of attempts at finding a port available on both address families
+    /// (when the port is chosen by the OS)
+    pub attempts: usize,
+    /// Behavior when the port chosen on IPv6 is unavailable on IPv4
+    pub mismatch: PortMismatch,
+    /// Number of sockets (each with a reader) per address family sharing the port (SO_REUSEPORT),
+    /// messages of a session are received by the same socket (1 = a single socket).
+    /// Note that other processes of the same user may then bind the port as well.
+    pub fanout: usize,
+}
+
+impl Default for BindOptions {
+    fn default() -> Self {
+        BindOptions {
+            mode: BindMode::default(),
+            protocol: Protocol::default(),
+            transport: TransportOptions::default(),
+            attempts: DEFAULT_BIND_ATTEMPTS,
+            mismatch: PortMismatch::default(),
+            fanout: 1,
+        }
+    }
 }
 
 /// On some platforms the application can itself bind to a socket.
@@ -40,6 +274,19 @@ pub trait PlatformUDP: UDP {
 
     /// Bind to a new port, returning the reader/writer and
     /// an associated instance of the owner type, which closes the UDP socket upon "drop"
-    /// and enables configuration of the fwmark value.
-    fn bind(port: u16) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Owner), Self::Error>;
+    /// and enables configuration of the transport options.
+    fn bind(port: u16) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Owner), Self::Error> {
+        Self::bind_with(port, &BindOptions::default())
+    }
+
+    /// Bind to a new port on the address families of the mode.
+    ///
+    /// Fails if any of the requested address families cannot be bound,
+    /// rather than falling back to the remaining family.
+    /// Every address family is bound on the same port (unless PortMismatch::Accept),
+    /// the resulting ports are reported by Owner::get_ports.
+    fn bind_with(
+        port: u16,
+        opts: &BindOptions,
+    ) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Owner), Self::Error>;
 }
@@ -0,0 +1,25 @@
+/* Pins the calling thread to a single core (see PerformanceMode).
+ *
+ * The affinity is an attribute of the thread on Linux,
+ * on other platforms the thread is left unpinned.
+ *
+ * # Returns
+ *
+ * True if the thread was pinned
+ */
+#[cfg(target_os = "linux")]
+pub fn pin_to_core(core: usize) -> bool {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        if core >= 8 * std::mem::size_of::<libc::cpu_set_t>() {
+            return false;
+        }
+        libc::CPU_SET(core, &mut set);
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) == 0
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin_to_core(_core: usize) -> bool {
+    false
+}
@@ -0,0 +1,81 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use hex;
+
+use super::keys::PeerId;
+
+/// Integration point for an external authentication / authorization of peers,
+/// e.g. access control backed by RADIUS or OIDC in VPN-as-a-service deployments.
+///
+/// The authorization is consulted whenever the configuration adds a peer
+/// (a denied peer is not added and the configuration fails with ConfigError::PeerDenied),
+/// and may veto every completed handshake of a peer already added,
+/// e.g. to revoke the access of a peer without removing it.
+pub trait PeerAuthorization: Send + Sync + 'static {
+    /// Returns true if the peer may be added to the device,
+    /// invoked from the configuration (UAPI) thread
+    fn authorize_peer(&self, peer: PeerId) -> bool;
+
+    /// Returns true if the handshake of the peer over the endpoint may complete,
+    /// a denied handshake is dropped (no response is sent and no keypair is derived).
+    ///
+    /// Invoked from the handshake workers, hence must not block
+    /// (e.g. answer from a cache refreshed by a separate thread).
+    fn authorize_handshake(&self, _peer: PeerId, _endpoint: SocketAddr) -> bool {
+        true
+    }
+}
+
+/// Authorization by an external program ("--authorize=<program>"):
+/// the program is run with the hex encoded public key of the peer (as over the UAPI)
+/// whenever a peer is added, and permits the peer by exiting with status 0.
+///
+/// Handshakes are not vetoed (the handshake workers must not wait for the program).
+pub struct ExecAuthorization {
+    program: PathBuf,
+}
+
+impl ExecAuthorization {
+    pub fn new<P: Into<PathBuf>>(program: P) -> ExecAuthorization {
+        ExecAuthorization {
+            program: program.into(),
+        }
+    }
+}
+
+impl PeerAuthorization for ExecAuthorization {
+    fn authorize_peer(&self, peer: PeerId) -> bool {
+        let status = Command::new(&self.program)
+            .arg(hex::encode(peer.public_key().as_bytes()))
+            .stdin(Stdio::null())
+            .status();
+        match status {
+            Ok(status) => status.success(),
+            Err(e) => {
+                log::error!(
+                    "Failed to run the authorization {}: {}",
+                    self.program.display(),
+                    e
+                );
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use x25519_dalek::PublicKey;
+
+    #[test]
+    fn test_exec_authorization() {
+        let peer = PeerId::new(PublicKey::from([1u8; 32]));
+        assert!(ExecAuthorization::new("/bin/true").authorize_peer(peer));
+        assert!(!ExecAuthorization::new("/bin/false").authorize_peer(peer));
+        assert!(!ExecAuthorization::new("/nonexistent").authorize_peer(peer));
+    }
+}
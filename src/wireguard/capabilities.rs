@@ -0,0 +1,256 @@
+/* Report of the optional features compiled into the build, those active on the device
+ * and the support of the host (kernel and CPU), see WireGuard::capabilities.
+ *
+ * Intended for support requests and for orchestration of heterogeneous fleets,
+ * the host is probed every time the report is created (the probes are cheap).
+ */
+use std::fmt;
+
+use super::crypto::{Backend, Selected};
+
+// optional (cargo) features compiled in
+const FEATURES: &[(&str, bool)] = &[
+    ("ipfix", cfg!(feature = "ipfix")),
+    ("rustcrypto", cfg!(feature = "rustcrypto")),
+    ("sqlite", cfg!(feature = "sqlite")),
+    ("profiler", cfg!(feature = "profiler")),
+    ("start_up", cfg!(feature = "start_up")),
+    ("dummy", cfg!(feature = "dummy")),
+];
+
+/// Capabilities of the build, the device and the host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    pub version: &'static str,       // version of the crate
+    pub crypto: &'static str,        // AEAD backend (see the "rustcrypto" feature)
+    pub features: Vec<&'static str>, // optional features compiled in
+    pub gso: bool,                   // UDP segmentation offload used by the data path
+    pub io_uring: bool,              // io_uring used by the readers / writers
+    pub multi_queue_tun: bool,       // a TUN reader per queue of the interface
+    pub uapi: bool,                  // configuration over the cross-platform UAPI
+    pub performance_mode: bool,      // the data path runs on dedicated cores (active)
+    pub simd: Vec<&'static str>,     // CPU extensions detected at runtime
+    pub host: HostSupport,
+}
+
+/// Support of the kernel for the optional features (probed at runtime)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HostSupport {
+    pub kernel: Option<String>, // name and release of the kernel (e.g. "Linux 5.10.0")
+    pub udp_gso: bool,          // UDP_SEGMENT socket option
+    pub io_uring: bool,         // io_uring_setup system call
+    pub multi_queue_tun: bool,  // IFF_MULTI_QUEUE of the TUN driver
+}
+
+impl Capabilities {
+    pub(super) fn new(performance_mode: bool) -> Capabilities {
+        Capabilities {
+            version: env!("CARGO_PKG_VERSION"),
+            crypto: Selected::NAME,
+            features: FEATURES
+                .iter()
+                .filter(|(_, enabled)| *enabled)
+                .map(|(name, _)| *name)
+                .collect(),
+            gso: false,
+            io_uring: false,
+            multi_queue_tun: false,
+            uapi: true,
+            performance_mode,
+            simd: simd(),
+            host: HostSupport::probe(),
+        }
+    }
+}
+
+fn list(f: &mut fmt::Formatter<'_>, names: &[&str]) -> fmt::Result {
+    if names.is_empty() {
+        f.write_str("none")
+    } else {
+        f.write_str(&names.join(","))
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "version={} crypto={} features=",
+            self.version, self.crypto
+        )?;
+        list(f, &self.features)?;
+        write!(
+            f,
+            " gso={} io_uring={} multi_queue_tun={} uapi={} performance_mode={} simd=",
+            self.gso, self.io_uring, self.multi_queue_tun, self.uapi, self.performance_mode
+        )?;
+        list(f, &self.simd)?;
+        write!(
+            f,
+            " kernel={} kernel_udp_gso={} kernel_io_uring={} kernel_multi_queue_tun={}",
+            self.host.kernel.as_deref().unwrap_or("unknown"),
+            self.host.udp_gso,
+            self.host.io_uring,
+            self.host.multi_queue_tun
+        )
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn simd() -> Vec<&'static str> {
+    let mut simd = vec![];
+    if is_x86_feature_detected!("ssse3") {
+        simd.push("ssse3");
+    }
+    if is_x86_feature_detected!("avx2") {
+        simd.push("avx2");
+    }
+    if is_x86_feature_detected!("avx512f") {
+        simd.push("avx512f");
+    }
+    if is_x86_feature_detected!("aes") {
+        simd.push("aes");
+    }
+    simd
+}
+
+#[cfg(target_arch = "aarch64")]
+fn simd() -> Vec<&'static str> {
+    if cfg!(target_feature = "neon") {
+        vec!["neon"]
+    } else {
+        vec![]
+    }
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+fn simd() -> Vec<&'static str> {
+    vec![]
+}
+
+// returns the name and release of the kernel
+#[cfg(unix)]
+fn kernel() -> Option<String> {
+    use std::ffi::CStr;
+
+    unsafe {
+        let mut name: libc::utsname = std::mem::zeroed();
+        if libc::uname(&mut name) != 0 {
+            return None;
+        }
+        let sysname = CStr::from_ptr(name.sysname.as_ptr()).to_string_lossy();
+        let release = CStr::from_ptr(name.release.as_ptr()).to_string_lossy();
+        Some(format!("{} {}", sysname, release))
+    }
+}
+
+#[cfg(not(unix))]
+fn kernel() -> Option<String> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+impl HostSupport {
+    pub fn probe() -> HostSupport {
+        HostSupport {
+            kernel: kernel(),
+            udp_gso: probe_udp_gso(),
+            io_uring: probe_io_uring(),
+            multi_queue_tun: probe_multi_queue_tun(),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl HostSupport {
+    pub fn probe() -> HostSupport {
+        HostSupport {
+            kernel: kernel(),
+            ..Default::default()
+        }
+    }
+}
+
+// the UDP_SEGMENT option can be set on a UDP socket (Linux 4.18)
+#[cfg(target_os = "linux")]
+fn probe_udp_gso() -> bool {
+    const UDP_SEGMENT: libc::c_int = 103;
+    unsafe {
+        let fd = libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+        if fd < 0 {
+            return false;
+        }
+        let size: libc::c_int = 1280;
+        let res = libc::setsockopt(
+            fd,
+            libc::SOL_UDP,
+            UDP_SEGMENT,
+            &size as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        );
+        libc::close(fd);
+        res == 0
+    }
+}
+
+// the io_uring_setup system call exists (Linux 5.1),
+// invalid arguments are rejected with EFAULT / EINVAL rather than ENOSYS
+#[cfg(target_os = "linux")]
+fn probe_io_uring() -> bool {
+    unsafe {
+        let res = libc::syscall(
+            libc::SYS_io_uring_setup,
+            0,
+            std::ptr::null_mut::<libc::c_void>(),
+        );
+        if res >= 0 {
+            libc::close(res as libc::c_int);
+            return true;
+        }
+        std::io::Error::last_os_error().raw_os_error() != Some(libc::ENOSYS)
+    }
+}
+
+// the TUN driver reports IFF_MULTI_QUEUE among its features (Linux 3.8)
+#[cfg(target_os = "linux")]
+fn probe_multi_queue_tun() -> bool {
+    const TUNGETFEATURES: u64 = 0x8004_54cf;
+    const IFF_MULTI_QUEUE: libc::c_uint = 0x0100;
+    unsafe {
+        let fd = libc::open(
+            b"/dev/net/tun\0".as_ptr() as _,
+            libc::O_RDWR | libc::O_CLOEXEC,
+        );
+        if fd < 0 {
+            return false;
+        }
+        let mut features: libc::c_uint = 0;
+        let res = libc::ioctl(fd, TUNGETFEATURES as _, &mut features as *mut libc::c_uint);
+        libc::close(fd);
+        res == 0 && features & IFF_MULTI_QUEUE != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities() {
+        let caps = Capabilities::new(false);
+        assert_eq!(caps.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(caps.crypto, Selected::NAME);
+        assert_eq!(
+            caps.features.contains(&"rustcrypto"),
+            cfg!(feature = "rustcrypto")
+        );
+        assert!(caps.uapi);
+
+        let report = caps.to_string();
+        assert!(report.starts_with(&format!("version={} crypto=", caps.version)));
+        assert!(report.contains(" performance_mode=false "));
+
+        #[cfg(unix)]
+        assert!(caps.host.kernel.is_some());
+    }
+}
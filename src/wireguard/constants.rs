@@ -19,15 +19,49 @@ pub const MAX_TIMER_HANDSHAKES: usize =
 pub const MAX_QUEUED_INCOMING_HANDSHAKES: usize = 4096;
 
 // Semantics:
-// When the number of queued handshake requests exceeds this number
+// When a handshake message waits in the queue for longer than this duration
 // the device is considered under load and DoS mitigation is triggered.
-pub const THRESHOLD_UNDER_LOAD: usize = MAX_QUEUED_INCOMING_HANDSHAKES / 8;
+// (measuring the wait, rather than the length of the queue,
+// accounts for the speed at which the handshakes are actually processed)
+pub const THRESHOLD_UNDER_LOAD: Duration = Duration::from_millis(10);
+
+// Semantics:
+// When the handshake queue reaches this length the device is considered under load,
+// it leaves the state only once the queue has drained below the low watermark
+// (see load::UnderLoad).
+pub const UNDER_LOAD_QUEUE_HIGH: usize = MAX_QUEUED_INCOMING_HANDSHAKES / 8;
+pub const UNDER_LOAD_QUEUE_LOW: usize = MAX_QUEUED_INCOMING_HANDSHAKES / 32;
+
+// Semantics:
+// Maximum number of events buffered for a subscriber (see WireGuard::subscribe),
+// further events are dropped until the subscriber catches up.
+pub const MAX_QUEUED_EVENTS: usize = 1024;
+
+// Semantics:
+// Nice value of handshake workers with HandshakePriority::Low
+// (the other workers retain the nice value of the process).
+pub const HANDSHAKE_LOW_PRIORITY_NICE: i32 = 10;
+
+// Performance:
+// Time (in microseconds) the UDP sockets busy poll their receive queue in performance mode
+// (see PerformanceMode).
+pub const PERFORMANCE_BUSY_POLL: u32 = 50;
 
 // Semantics:
 // When a device is detected to go under load,
 // it will remain under load for at least the following duration.
 pub const DURATION_UNDER_LOAD: Duration = Duration::from_secs(1);
 
+// Semantics:
+// Identical handshake initiations (same source and mac1)
+// received within this duration are processed only once.
+pub const DEDUP_INITIATION_WINDOW: Duration = Duration::from_secs(1);
+
+// Performance:
+// Interval at which the outbound queues are polled while flushed on device down
+// (see WireGuard::set_shutdown_flush).
+pub const SHUTDOWN_FLUSH_POLL: Duration = Duration::from_millis(5);
+
 // Semantics:
 // The payload of transport messages are padded to this multiple
 pub const MESSAGE_PADDING_MULTIPLE: usize = 16;
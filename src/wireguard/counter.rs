@@ -0,0 +1,195 @@
+use std::cell::Cell;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use super::stats::{MessageKind, RxKinds, RxStats};
+
+/* Traffic counters sharded over the threads updating them:
+ *
+ * Every transport message updates the counters of its peer,
+ * from whichever worker thread encrypted or decrypted it.
+ * A single set of atomics shared by all the workers bounces its cache line between the cores
+ * at every message, hence every thread updates its own shard (on a cache line of its own)
+ * and the shards are summed when the counters are read (which is rare in comparison).
+ */
+
+// number of shards per counter set (threads beyond this share shards)
+pub const SHARDS: usize = 16;
+
+/// Aligns (and pads) the value to a cache line (64 bytes on the common architectures),
+/// avoiding false sharing with the neighbouring fields
+#[derive(Default)]
+#[repr(align(64))]
+pub struct CachePadded<T>(T);
+
+impl<T> CachePadded<T> {
+    pub fn new(value: T) -> CachePadded<T> {
+        CachePadded(value)
+    }
+}
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+/// Counters of the messages received by kind (see stats::RxStats),
+/// every address family on a cache line of its own (updated by the reader of its socket)
+#[derive(Default)]
+pub struct RxCounters {
+    families: [CachePadded<[(AtomicU64, AtomicU64); 5]>; 2],
+}
+
+impl RxCounters {
+    /// Accounts a message received from a source of the address family
+    pub fn add(&self, v6: bool, kind: MessageKind, bytes: usize) {
+        let (packets, total) = &self.families[v6 as usize][kind as usize];
+        packets.fetch_add(1, Ordering::Relaxed);
+        total.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> RxStats {
+        let family = |idx: usize| {
+            let mut kinds = RxKinds::default();
+            for (count, (packets, bytes)) in kinds.kinds.iter_mut().zip(self.families[idx].iter()) {
+                count.packets = packets.load(Ordering::Relaxed);
+                count.bytes = bytes.load(Ordering::Relaxed);
+            }
+            kinds
+        };
+        RxStats {
+            v4: family(0),
+            v6: family(1),
+        }
+    }
+}
+
+/// The sum of the counters over all the shards
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Traffic {
+    pub rx_bytes: u64,   // received bytes
+    pub tx_bytes: u64,   // transmitted bytes
+    pub rx_packets: u64, // received transport messages
+    pub tx_packets: u64, // transmitted transport messages
+}
+
+#[derive(Default)]
+struct Shard {
+    rx_bytes: AtomicU64,
+    tx_bytes: AtomicU64,
+    rx_packets: AtomicU64,
+    tx_packets: AtomicU64,
+}
+
+#[derive(Default)]
+pub struct TrafficCounters {
+    shards: [CachePadded<Shard>; SHARDS],
+}
+
+// shard of the current thread (assigned round-robin on first use)
+pub fn shard() -> usize {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    thread_local! {
+        static SHARD: Cell<Option<usize>> = Cell::new(None);
+    }
+    SHARD.with(|shard| match shard.get() {
+        Some(idx) => idx,
+        None => {
+            let idx = NEXT.fetch_add(1, Ordering::Relaxed) % SHARDS;
+            shard.set(Some(idx));
+            idx
+        }
+    })
+}
+
+impl TrafficCounters {
+    pub fn new() -> TrafficCounters {
+        TrafficCounters::default()
+    }
+
+    /// Accounts bytes and messages received from the peer
+    #[inline(always)]
+    pub fn add_rx(&self, bytes: u64, packets: u64) {
+        let shard = &self.shards[shard()];
+        shard.rx_bytes.fetch_add(bytes, Ordering::Relaxed);
+        shard.rx_packets.fetch_add(packets, Ordering::Relaxed);
+    }
+
+    /// Accounts bytes and messages transmitted to the peer
+    #[inline(always)]
+    pub fn add_tx(&self, bytes: u64, packets: u64) {
+        let shard = &self.shards[shard()];
+        shard.tx_bytes.fetch_add(bytes, Ordering::Relaxed);
+        shard.tx_packets.fetch_add(packets, Ordering::Relaxed);
+    }
+
+    /// Sums the counters over all the shards
+    ///
+    /// Concurrent updates may or may not be included,
+    /// but the totals never decrease between reads.
+    pub fn get(&self) -> Traffic {
+        let mut sum = Traffic::default();
+        for shard in self.shards.iter() {
+            sum.rx_bytes = sum
+                .rx_bytes
+                .wrapping_add(shard.rx_bytes.load(Ordering::Relaxed));
+            sum.tx_bytes = sum
+                .tx_bytes
+                .wrapping_add(shard.tx_bytes.load(Ordering::Relaxed));
+            sum.rx_packets = sum
+                .rx_packets
+                .wrapping_add(shard.rx_packets.load(Ordering::Relaxed));
+            sum.tx_packets = sum
+                .tx_packets
+                .wrapping_add(shard.tx_packets.load(Ordering::Relaxed));
+        }
+        sum
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_traffic_counters() {
+        let counters = Arc::new(TrafficCounters::new());
+        let threads: Vec<_> = (0..2 * SHARDS)
+            .map(|_| {
+                let counters = counters.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        counters.add_rx(100, 1);
+                        counters.add_tx(50, 1);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let threads = 2 * SHARDS as u64;
+        assert_eq!(
+            counters.get(),
+            Traffic {
+                rx_bytes: threads * 100_000,
+                tx_bytes: threads * 50_000,
+                rx_packets: threads * 1000,
+                tx_packets: threads * 1000,
+            }
+        );
+    }
+
+    #[test]
+    fn test_cache_padded() {
+        assert_eq!(std::mem::align_of::<CachePadded<u8>>(), 64);
+        assert_eq!(std::mem::size_of::<CachePadded<AtomicU64>>(), 64);
+    }
+}
@@ -0,0 +1,468 @@
+/* Backends of the symmetric primitives (see Backend):
+ *
+ * The AEAD (ChaCha20Poly1305), BLAKE2s (hash, HMAC and MAC) and HChaCha20 are reached
+ * through the Backend trait, by the router as well as by the handshake (noise and cookies),
+ * hence alternative implementations (e.g. bindings to libsodium or to the crypto engine
+ * of an embedded SoC) are added as another implementation and selected at compile time
+ * by feature flag (see Selected).
+ *
+ * The transport messages are sealed / opened in batches (see Backend::seal_batch),
+ * hence multi-buffer (SIMD) implementations may process several messages per invocation.
+ *
+ * Every backend is run against the same conformance vectors (see the tests below).
+ */
+use blake2::{Blake2s, VarBlake2s};
+use clear_on_drop::clear::Clear;
+use digest::{Input, VariableOutput};
+
+pub const SIZE_KEY: usize = 32;
+pub const SIZE_NONCE: usize = 12;
+pub const SIZE_TAG: usize = 16;
+pub const SIZE_HASH: usize = 32;
+pub const SIZE_MAC: usize = 16;
+
+/// A transport message to seal in-place (without associated data)
+pub struct Seal<'a> {
+    pub key: &'a [u8; SIZE_KEY],
+    pub nonce: [u8; SIZE_NONCE],
+    pub buf: &'a mut [u8], // plaintext followed by room for the tag
+}
+
+/// A transport message to open in-place (without associated data)
+pub struct Open<'a> {
+    pub key: &'a [u8; SIZE_KEY],
+    pub nonce: [u8; SIZE_NONCE],
+    pub buf: &'a mut [u8], // ciphertext followed by the tag
+    pub ok: bool,          // set by Backend::open_batch
+}
+
+/// Implementation of the symmetric primitives
+///
+/// Only the AEAD must be implemented,
+/// the defaults of the other primitives are portable implementations.
+pub trait Backend {
+    /// Name of the backend (for logging)
+    const NAME: &'static str;
+
+    /// Encrypts the buffer in-place (ChaCha20Poly1305)
+    ///
+    /// # Returns
+    ///
+    /// The authentication tag of the ciphertext
+    fn seal(
+        key: &[u8; SIZE_KEY],
+        nonce: &[u8; SIZE_NONCE],
+        aad: &[u8],
+        buf: &mut [u8],
+    ) -> [u8; SIZE_TAG];
+
+    /// Authenticates and decrypts the buffer in-place (ChaCha20Poly1305)
+    ///
+    /// # Arguments
+    ///
+    /// - `buf`: The ciphertext followed by the tag,
+    ///    the ciphertext is replaced by the plaintext (the tag is left as is)
+    ///
+    /// # Returns
+    ///
+    /// Ok if the ciphertext is authentic, the content of the buffer is unspecified otherwise
+    fn open(
+        key: &[u8; SIZE_KEY],
+        nonce: &[u8; SIZE_NONCE],
+        aad: &[u8],
+        buf: &mut [u8],
+    ) -> Result<(), ()>;
+
+    /// Seals a batch of transport messages (the tag is written after the plaintext)
+    fn seal_batch(batch: &mut [Seal]) {
+        for msg in batch.iter_mut() {
+            let tag_offset = msg.buf.len() - SIZE_TAG;
+            let (body, tag) = msg.buf.split_at_mut(tag_offset);
+            tag.copy_from_slice(&Self::seal(msg.key, &msg.nonce, &[], body));
+        }
+    }
+
+    /// Opens a batch of transport messages (sets the ok flag of the authentic messages)
+    fn open_batch(batch: &mut [Open]) {
+        for msg in batch.iter_mut() {
+            msg.ok = Self::open(msg.key, &msg.nonce, &[], msg.buf).is_ok();
+        }
+    }
+
+    /// BLAKE2s-256 of the concatenated inputs
+    fn hash(inputs: &[&[u8]]) -> [u8; SIZE_HASH] {
+        use blake2::Digest;
+        let mut hsh = Blake2s::new();
+        for input in inputs {
+            hsh.input(input);
+        }
+        let mut out = [0u8; SIZE_HASH];
+        out.copy_from_slice(&hsh.result());
+        out
+    }
+
+    /// HMAC-BLAKE2s of the concatenated inputs (RFC 2104, the block size of BLAKE2s is 64 bytes)
+    fn hmac(key: &[u8], inputs: &[&[u8]]) -> [u8; SIZE_HASH] {
+        const BLOCK: usize = 64;
+        let mut ipad = [0x36u8; BLOCK];
+        let mut opad = [0x5cu8; BLOCK];
+        let mut hkey = [0u8; SIZE_HASH];
+        let key = if key.len() > BLOCK {
+            hkey = Self::hash(&[key]);
+            &hkey[..]
+        } else {
+            key
+        };
+        for (i, b) in key.iter().enumerate() {
+            ipad[i] ^= b;
+            opad[i] ^= b;
+        }
+        let mut inner: Vec<&[u8]> = Vec::with_capacity(inputs.len() + 1);
+        inner.push(&ipad[..]);
+        inner.extend_from_slice(inputs);
+        let mut digest = Self::hash(&inner[..]);
+        let tag = Self::hash(&[&opad[..], &digest[..]]);
+        ipad.clear();
+        opad.clear();
+        hkey.clear();
+        digest.clear();
+        tag
+    }
+
+    /// Keyed BLAKE2s-128 of the concatenated inputs
+    fn mac(key: &[u8], inputs: &[&[u8]]) -> [u8; SIZE_MAC] {
+        let mut tag = [0u8; SIZE_MAC];
+        let mut mac = VarBlake2s::new_keyed(key, SIZE_MAC);
+        for input in inputs {
+            mac.input(input);
+        }
+        mac.variable_result(|buf| tag.copy_from_slice(buf));
+        tag
+    }
+
+    /// HChaCha20 (the subkey derivation of XChaCha20)
+    fn hchacha20(key: &[u8; SIZE_KEY], nonce: &[u8; 16]) -> [u8; 32] {
+        hchacha20(key, nonce)
+    }
+}
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+fn le32(bytes: &[u8]) -> u32 {
+    let mut word = [0u8; 4];
+    word.copy_from_slice(&bytes[..4]);
+    u32::from_le_bytes(word)
+}
+
+// portable HChaCha20 (draft-irtf-cfrg-xchacha, section 2.2)
+fn hchacha20(key: &[u8; SIZE_KEY], nonce: &[u8; 16]) -> [u8; 32] {
+    let mut s = [0u32; 16];
+    s[..4].copy_from_slice(&[0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574]);
+    for i in 0..8 {
+        s[4 + i] = le32(&key[4 * i..]);
+    }
+    for i in 0..4 {
+        s[12 + i] = le32(&nonce[4 * i..]);
+    }
+    for _ in 0..10 {
+        quarter_round(&mut s, 0, 4, 8, 12);
+        quarter_round(&mut s, 1, 5, 9, 13);
+        quarter_round(&mut s, 2, 6, 10, 14);
+        quarter_round(&mut s, 3, 7, 11, 15);
+        quarter_round(&mut s, 0, 5, 10, 15);
+        quarter_round(&mut s, 1, 6, 11, 12);
+        quarter_round(&mut s, 2, 7, 8, 13);
+        quarter_round(&mut s, 3, 4, 9, 14);
+    }
+    let mut out = [0u8; 32];
+    for (i, word) in s[..4].iter().chain(s[12..].iter()).enumerate() {
+        out[4 * i..4 * (i + 1)].copy_from_slice(&word.to_le_bytes());
+    }
+    out
+}
+
+/// ChaCha20Poly1305 of ring (default)
+pub struct Ring;
+
+impl Backend for Ring {
+    const NAME: &'static str = "ring";
+
+    fn seal(
+        key: &[u8; SIZE_KEY],
+        nonce: &[u8; SIZE_NONCE],
+        aad: &[u8],
+        buf: &mut [u8],
+    ) -> [u8; SIZE_TAG] {
+        use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+        let key = LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, &key[..]).unwrap());
+        let tag = key
+            .seal_in_place_separate_tag(Nonce::assume_unique_for_key(*nonce), Aad::from(aad), buf)
+            .unwrap();
+        let mut out = [0u8; SIZE_TAG];
+        out.copy_from_slice(tag.as_ref());
+        out
+    }
+
+    fn open(
+        key: &[u8; SIZE_KEY],
+        nonce: &[u8; SIZE_NONCE],
+        aad: &[u8],
+        buf: &mut [u8],
+    ) -> Result<(), ()> {
+        use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+        let key = LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, &key[..]).unwrap());
+        key.open_in_place(Nonce::assume_unique_for_key(*nonce), Aad::from(aad), buf)
+            .map(|_| ())
+            .map_err(|_| ())
+    }
+}
+
+/// ChaCha20Poly1305 of RustCrypto (pure Rust, e.g. for targets not supported by ring)
+pub struct RustCrypto;
+
+impl Backend for RustCrypto {
+    const NAME: &'static str = "rustcrypto";
+
+    fn seal(
+        key: &[u8; SIZE_KEY],
+        nonce: &[u8; SIZE_NONCE],
+        aad: &[u8],
+        buf: &mut [u8],
+    ) -> [u8; SIZE_TAG] {
+        use aead::{Aead, NewAead};
+        use chacha20poly1305::ChaCha20Poly1305;
+        use generic_array::GenericArray;
+        let tag = ChaCha20Poly1305::new(*GenericArray::from_slice(key))
+            .encrypt_in_place_detached(GenericArray::from_slice(nonce), aad, buf)
+            .unwrap();
+        let mut out = [0u8; SIZE_TAG];
+        out.copy_from_slice(&tag);
+        out
+    }
+
+    fn open(
+        key: &[u8; SIZE_KEY],
+        nonce: &[u8; SIZE_NONCE],
+        aad: &[u8],
+        buf: &mut [u8],
+    ) -> Result<(), ()> {
+        use aead::{Aead, NewAead};
+        use chacha20poly1305::ChaCha20Poly1305;
+        use generic_array::GenericArray;
+        if buf.len() < SIZE_TAG {
+            return Err(());
+        }
+        let (ct, tag) = buf.split_at_mut(buf.len() - SIZE_TAG);
+        ChaCha20Poly1305::new(*GenericArray::from_slice(key))
+            .decrypt_in_place_detached(
+                GenericArray::from_slice(nonce),
+                aad,
+                ct,
+                GenericArray::from_slice(tag),
+            )
+            .map_err(|_| ())
+    }
+}
+
+/// The backend selected by the feature flags
+#[cfg(not(feature = "rustcrypto"))]
+pub type Selected = Ring;
+
+/// The backend selected by the feature flags
+#[cfg(feature = "rustcrypto")]
+pub type Selected = RustCrypto;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    // RFC 8439, section 2.8.2
+    fn conformance_aead<B: Backend>() {
+        let mut key = [0u8; SIZE_KEY];
+        for (i, b) in key.iter_mut().enumerate() {
+            *b = 0x80 + i as u8;
+        }
+        let mut nonce = [0u8; SIZE_NONCE];
+        nonce.copy_from_slice(&hex("070000004041424344454647"));
+        let aad = hex("50515253c0c1c2c3c4c5c6c7");
+        let pt: &[u8] = b"Ladies and Gentlemen of the class of '99: \
+            If I could offer you only one tip for the future, sunscreen would be it.";
+
+        let mut buf = pt.to_vec();
+        let tag = B::seal(&key, &nonce, &aad, &mut buf);
+        assert_eq!(
+            &buf[..16],
+            &hex("d31a8d34648e60db7b86afbc53ef7ec2")[..],
+            "{}",
+            B::NAME
+        );
+        assert_eq!(
+            &tag[..],
+            &hex("1ae10b594f09e26a7e902ecbd0600691")[..],
+            "{}",
+            B::NAME
+        );
+
+        // round trip
+        buf.extend_from_slice(&tag);
+        let mut sealed = buf.clone();
+        B::open(&key, &nonce, &aad, &mut sealed).unwrap();
+        assert_eq!(&sealed[..pt.len()], pt, "{}", B::NAME);
+
+        // tampered ciphertext, tag and aad
+        let mut tampered = buf.clone();
+        tampered[0] ^= 1;
+        assert!(B::open(&key, &nonce, &aad, &mut tampered).is_err());
+        let mut tampered = buf.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(B::open(&key, &nonce, &aad, &mut tampered).is_err());
+        assert!(B::open(&key, &nonce, &[], &mut buf.clone()).is_err());
+
+        // shorter than a tag
+        assert!(B::open(&key, &nonce, &[], &mut [0u8; SIZE_TAG - 1]).is_err());
+    }
+
+    fn conformance_hash<B: Backend>() {
+        assert_eq!(
+            &B::hash(&[&b"a"[..], &b"bc"[..]])[..],
+            &hex("508c5e8c327c14e2e1a72ba34eeb452f37458b209ed63a294d999b4c86675982")[..],
+            "{}",
+            B::NAME
+        );
+        let key = [7u8; 32];
+        assert_eq!(
+            B::mac(&key, &[&b"a"[..], &b"bc"[..]]),
+            B::mac(&key, &[&b"abc"[..]])
+        );
+        assert_ne!(
+            B::mac(&key, &[&b"abc"[..]]),
+            B::mac(&[8u8; 32], &[&b"abc"[..]])
+        );
+
+        // compare against the generic HMAC construction (short, block sized and long keys)
+        for len in &[0, 32, 64, 65, 100] {
+            use hmac::{Hmac, Mac};
+            let key = vec![0xa5u8; *len];
+            let mut mac = Hmac::<Blake2s>::new_varkey(&key).unwrap();
+            mac.input(b"abc");
+            assert_eq!(
+                &B::hmac(&key, &[&b"a"[..], &b"bc"[..]])[..],
+                &mac.result().code()[..],
+                "{}",
+                B::NAME
+            );
+        }
+    }
+
+    // draft-irtf-cfrg-xchacha, section 2.2.1
+    fn conformance_hchacha20<B: Backend>() {
+        let mut key = [0u8; SIZE_KEY];
+        for (i, b) in key.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let mut nonce = [0u8; 16];
+        nonce.copy_from_slice(&hex("000000090000004a0000000031415927"));
+        assert_eq!(
+            &B::hchacha20(&key, &nonce)[..],
+            &hex("82413b4227b27bfed30e42508a877d73a0f9e4d58a74a853c12ec41326d3ecdc")[..],
+            "{}",
+            B::NAME
+        );
+    }
+
+    fn conformance<B: Backend>() {
+        conformance_aead::<B>();
+        conformance_hash::<B>();
+        conformance_hchacha20::<B>();
+    }
+
+    #[test]
+    fn test_ring() {
+        conformance::<Ring>();
+    }
+
+    #[test]
+    fn test_rustcrypto() {
+        conformance::<RustCrypto>();
+    }
+
+    #[test]
+    fn test_batch() {
+        let keys = [[1u8; SIZE_KEY], [2u8; SIZE_KEY], [3u8; SIZE_KEY]];
+        let mut bufs: Vec<Vec<u8>> = (0..3).map(|i| vec![i as u8; 10 * i + SIZE_TAG]).collect();
+        let expected: Vec<Vec<u8>> = bufs
+            .iter()
+            .zip(keys.iter())
+            .map(|(buf, key)| {
+                let mut buf = buf.clone();
+                let tag_offset = buf.len() - SIZE_TAG;
+                let tag = Selected::seal(key, &[0u8; SIZE_NONCE], &[], &mut buf[..tag_offset]);
+                buf[tag_offset..].copy_from_slice(&tag);
+                buf
+            })
+            .collect();
+
+        // sealed as a batch, equal to sealed one by one
+        let mut batch: Vec<Seal> = bufs
+            .iter_mut()
+            .zip(keys.iter())
+            .map(|(buf, key)| Seal {
+                key,
+                nonce: [0u8; SIZE_NONCE],
+                buf: &mut buf[..],
+            })
+            .collect();
+        Selected::seal_batch(&mut batch);
+        assert_eq!(bufs, expected);
+
+        // opened as a batch, the tampered message is rejected
+        bufs[1][0] ^= 1;
+        let mut batch: Vec<Open> = bufs
+            .iter_mut()
+            .zip(keys.iter())
+            .map(|(buf, key)| Open {
+                key,
+                nonce: [0u8; SIZE_NONCE],
+                buf: &mut buf[..],
+                ok: false,
+            })
+            .collect();
+        Selected::open_batch(&mut batch);
+        let ok: Vec<bool> = batch.iter().map(|msg| msg.ok).collect();
+        assert_eq!(ok, vec![true, false, true]);
+        assert_eq!(bufs[2][..20], [2u8; 20][..]);
+    }
+
+    #[test]
+    fn test_interoperable() {
+        let key = [3u8; SIZE_KEY];
+        let nonce = [4u8; SIZE_NONCE];
+        let mut buf1 = vec![5u8; 100];
+        let mut buf2 = buf1.clone();
+        let tag1 = Ring::seal(&key, &nonce, &[], &mut buf1);
+        let tag2 = RustCrypto::seal(&key, &nonce, &[], &mut buf2);
+        assert_eq!(buf1, buf2);
+        assert_eq!(tag1, tag2);
+
+        // sealed by one, opened by the other
+        buf1.extend_from_slice(&tag1);
+        RustCrypto::open(&key, &nonce, &[], &mut buf1).unwrap();
+        assert_eq!(&buf1[..100], &[5u8; 100][..]);
+    }
+}
@@ -0,0 +1,115 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use spin::Mutex;
+
+use super::handshake::SIZE_MAC;
+
+/* Deduplication of handshake initiations:
+ *
+ * Identical initiations (e.g. duplicated by a lossy network or replayed in a flood)
+ * carry identical mac1 fields. Only the first initiation from a given source is processed
+ * within the window, avoiding the Diffie-Hellman operations and duplicate responses.
+ *
+ * Note that a legitimate retransmission by the initiator uses a fresh ephemeral key
+ * (and hence a different mac1), so is never dropped.
+ */
+pub struct Dedup {
+    window: Duration,
+    capacity: usize, // per shard
+    hasher: RandomState,
+    shards: Vec<Mutex<HashMap<(SocketAddr, [u8; SIZE_MAC]), Instant>>>,
+}
+
+/* The table is sharded by source address,
+ * such that readers receiving initiations from different sources rarely contend for a lock.
+ */
+const SHARDS: usize = 16;
+
+impl Dedup {
+    pub fn new(window: Duration, capacity: usize) -> Dedup {
+        Dedup {
+            window,
+            capacity: (capacity + SHARDS - 1) / SHARDS,
+            hasher: RandomState::new(),
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+        }
+    }
+
+    /// Records an initiation
+    ///
+    /// # Arguments
+    ///
+    /// - `src`: The source address of the message
+    /// - `mac1`: The mac1 field of the message
+    ///
+    /// # Returns
+    ///
+    /// True if the initiation should be processed,
+    /// false if it is a duplicate of an initiation seen within the window.
+    pub fn check(&self, src: SocketAddr, mac1: &[u8]) -> bool {
+        self.check_at(src, mac1, Instant::now())
+    }
+
+    fn check_at(&self, src: SocketAddr, mac1: &[u8], now: Instant) -> bool {
+        let mut key = (src, [0u8; SIZE_MAC]);
+        key.1.copy_from_slice(mac1);
+
+        let mut hasher = self.hasher.build_hasher();
+        src.hash(&mut hasher);
+        let mut seen = self.shards[hasher.finish() as usize % SHARDS].lock();
+        if let Some(time) = seen.get(&key) {
+            if now.duration_since(*time) < self.window {
+                return false;
+            }
+        }
+
+        // evict expired entries when full,
+        // if none have expired (flood of distinct initiations) start over
+        if seen.len() >= self.capacity {
+            let window = self.window;
+            seen.retain(|_, time| now.duration_since(*time) < window);
+            if seen.len() >= self.capacity {
+                seen.clear();
+            }
+        }
+        seen.insert(key, now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dedup_window() {
+        let dedup = Dedup::new(Duration::from_millis(100), 2 * SHARDS);
+        let src1: SocketAddr = "10.0.0.1:51820".parse().unwrap();
+        let src2: SocketAddr = "10.0.0.2:51820".parse().unwrap();
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        // duplicates are dropped, but only from the same source
+        assert!(dedup.check_at(src1, &[1u8; SIZE_MAC], at(0)));
+        assert!(!dedup.check_at(src1, &[1u8; SIZE_MAC], at(10)));
+        assert!(dedup.check_at(src2, &[1u8; SIZE_MAC], at(20)));
+        assert!(!dedup.check_at(src2, &[1u8; SIZE_MAC], at(30)));
+
+        // duplicates are accepted after the window
+        assert!(!dedup.check_at(src1, &[1u8; SIZE_MAC], at(99)));
+        assert!(dedup.check_at(src1, &[1u8; SIZE_MAC], at(100)));
+        assert!(!dedup.check_at(src1, &[1u8; SIZE_MAC], at(150)));
+
+        // table is bounded (a flood of distinct initiations)
+        for i in 0..=255u8 {
+            assert!(dedup.check_at(src1, &[i; SIZE_MAC], at(200)));
+        }
+        for shard in dedup.shards.iter() {
+            assert!(shard.lock().len() <= 2);
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use std::net::SocketAddr;
+
+use super::keys::PeerId;
+
+/// Integration point for an external discovery of endpoints,
+/// e.g. a relay-assisted connection layer (in the style of DERP) built on top of the device.
+///
+/// The discovery supplies candidate endpoints of a peer whenever the handshake over
+/// the current endpoint fails, the candidates are then raced by the following initiations
+/// (as those set with WireGuard::set_endpoint_candidates).
+/// A relay is supplied as any other candidate, e.g. as the address of a local relay proxy.
+///
+/// The methods are invoked from the timers and the handshake workers, hence must not block.
+pub trait EndpointDiscovery: Send + Sync + 'static {
+    /// Returns the candidate endpoints of the peer
+    /// (an empty list retains the current candidates)
+    fn candidates(&self, peer: PeerId) -> Vec<SocketAddr>;
+
+    /// A handshake with the peer was authenticated over the endpoint,
+    /// invoked for every handshake (at least every REKEY_AFTER_TIME while the peer is active)
+    fn path_up(&self, _peer: PeerId, _endpoint: SocketAddr) {}
+
+    /// The handshake with the peer did not complete over the endpoint
+    /// (None if the peer has no endpoint), `exhausted` is set once the device gives up retrying
+    fn path_failed(&self, _peer: PeerId, _endpoint: Option<SocketAddr>, _exhausted: bool) {}
+}
+
+/// Discovery falling back to a single relay for every peer
+/// (e.g. a local relay proxy forwarding to the peers, "--relay=<addr>"):
+/// once the handshake over the endpoint of a peer fails, the relay is raced as a candidate.
+pub struct RelayDiscovery {
+    relay: SocketAddr,
+}
+
+impl RelayDiscovery {
+    pub fn new(relay: SocketAddr) -> RelayDiscovery {
+        RelayDiscovery { relay }
+    }
+}
+
+impl EndpointDiscovery for RelayDiscovery {
+    fn candidates(&self, _peer: PeerId) -> Vec<SocketAddr> {
+        vec![self.relay]
+    }
+}
@@ -0,0 +1,119 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::keys::PeerId;
+use super::types::{ExitCandidate, ExitPolicy};
+
+// Semantics:
+// Interval at which the selection of the exit is re-evaluated,
+// hence the time for failing over once the exit goes stale.
+pub const EXIT_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// Semantics:
+// The active exit is retained unless the round-trip time of another candidate
+// is less than this fraction (in percent) of the round-trip time of the active exit.
+pub const EXIT_RTT_HYSTERESIS: u32 = 80;
+
+/// Selects the exit of the device, i.e. the peer to which the default routes
+/// (0.0.0.0/0 and ::/0) are routed, among the live candidates:
+/// peers with an exit weight and a current session, which are neither suspended nor disabled.
+///
+/// Invoked every EXIT_CHECK_INTERVAL (and whenever a candidate is added or removed),
+/// with a non-empty list of candidates, e.g. an external selector may choose by GeoIP.
+/// When no candidate is live the current exit is retained.
+pub trait ExitSelector: Send + Sync + 'static {
+    /// Returns the exit among the candidates (None removes the default routes)
+    fn select(&self, candidates: &[ExitCandidate]) -> Option<PeerId>;
+}
+
+/// Selects the candidate with the lowest handshake round-trip time
+/// (candidates without a measurement are only selected if no other is available)
+pub struct LowestRtt;
+
+impl ExitSelector for LowestRtt {
+    fn select(&self, candidates: &[ExitCandidate]) -> Option<PeerId> {
+        let best = candidates
+            .iter()
+            .min_by_key(|c| (c.rtt.is_none(), c.rtt, !c.active))?;
+        match (candidates.iter().find(|c| c.active), best.rtt) {
+            (Some(active), Some(rtt)) => match active.rtt {
+                Some(current) if rtt * 100 >= current * EXIT_RTT_HYSTERESIS => Some(active.peer),
+                _ => Some(best.peer),
+            },
+            _ => Some(best.peer),
+        }
+    }
+}
+
+/// Selects the candidate with the highest static weight
+/// (the active exit is retained among candidates of equal weight)
+pub struct HighestWeight;
+
+impl ExitSelector for HighestWeight {
+    fn select(&self, candidates: &[ExitCandidate]) -> Option<PeerId> {
+        candidates
+            .iter()
+            .max_by_key(|c| (c.weight, c.active))
+            .map(|c| c.peer)
+    }
+}
+
+/// Returns the selector implementing the built-in policy
+pub fn selector(policy: ExitPolicy) -> Arc<dyn ExitSelector> {
+    match policy {
+        ExitPolicy::Rtt => Arc::new(LowestRtt),
+        ExitPolicy::Weight => Arc::new(HighestWeight),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use x25519_dalek::PublicKey;
+
+    fn candidate(id: u8, weight: u32, rtt: Option<u64>, active: bool) -> ExitCandidate {
+        ExitCandidate {
+            peer: PeerId::new(PublicKey::from([id; 32])),
+            weight,
+            rtt: rtt.map(Duration::from_millis),
+            active,
+        }
+    }
+
+    #[test]
+    fn test_lowest_rtt() {
+        let selector = LowestRtt;
+        let id = |id| Some(PeerId::new(PublicKey::from([id; 32])));
+        assert_eq!(selector.select(&[]), None);
+
+        // unmeasured candidates are last
+        let candidates = [
+            candidate(1, 1, None, false),
+            candidate(2, 1, Some(50), false),
+        ];
+        assert_eq!(selector.select(&candidates), id(2));
+
+        // the active exit is retained unless another is markedly faster
+        let candidates = [
+            candidate(1, 1, Some(45), false),
+            candidate(2, 1, Some(50), true),
+        ];
+        assert_eq!(selector.select(&candidates), id(2));
+        let candidates = [
+            candidate(1, 1, Some(30), false),
+            candidate(2, 1, Some(50), true),
+        ];
+        assert_eq!(selector.select(&candidates), id(1));
+    }
+
+    #[test]
+    fn test_highest_weight() {
+        let selector = HighestWeight;
+        let id = |id| Some(PeerId::new(PublicKey::from([id; 32])));
+        let candidates = [candidate(1, 10, None, true), candidate(2, 20, None, false)];
+        assert_eq!(selector.select(&candidates), id(2));
+        let candidates = [candidate(1, 20, None, false), candidate(2, 20, None, true)];
+        assert_eq!(selector.select(&candidates), id(2));
+    }
+}
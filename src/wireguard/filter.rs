@@ -0,0 +1,37 @@
+use std::sync::Arc;
+
+use super::keys::PeerId;
+use super::peer::PeerInner;
+use super::router::{Action, PacketFilter};
+use super::tun::Tun;
+use super::udp::UDP;
+
+/// A filter of the inner IP packets of the device,
+/// e.g. to implement access control ("peer X may only reach port 443") in the data plane.
+///
+/// Both methods are invoked with the (unpadded) IP packet and the identity of the peer,
+/// concurrently from multiple worker threads. By default every packet is allowed.
+pub trait Filter: Send + Sync + 'static {
+    /// Invoked on a decrypted packet from the peer (before it is written to the TUN device)
+    fn inbound(&self, _peer: &PeerId, _packet: &[u8]) -> Action {
+        Action::Allow
+    }
+
+    /// Invoked on a packet to the peer (before it is encrypted)
+    fn outbound(&self, _peer: &PeerId, _packet: &[u8]) -> Action {
+        Action::Allow
+    }
+}
+
+// presents the peer to the filter by its identity
+pub struct PeerFilter(pub Arc<dyn Filter>);
+
+impl<T: Tun, B: UDP> PacketFilter<PeerInner<T, B>> for PeerFilter {
+    fn inbound(&self, peer: &PeerInner<T, B>, packet: &[u8]) -> Action {
+        self.0.inbound(&peer.id(), packet)
+    }
+
+    fn outbound(&self, peer: &PeerInner<T, B>, packet: &[u8]) -> Action {
+        self.0.outbound(&peer.id(), packet)
+    }
+}
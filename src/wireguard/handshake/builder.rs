@@ -0,0 +1,149 @@
+/* Construction and parsing of handshake messages without a WireGuard device,
+ * e.g. for conformance testers and external control planes crafting protocol messages.
+ *
+ * The builder holds the keys of one end of a handshake with a single peer
+ * (no router, timers or IO), the messages are returned as byte vectors.
+ */
+use std::net::SocketAddr;
+
+use byteorder::{ByteOrder, LittleEndian};
+use rand::prelude::{CryptoRng, RngCore};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use super::device::{Device, KeyState};
+use super::messages::{CookieReply, Initiation, Response};
+use super::messages::{TYPE_COOKIE_REPLY, TYPE_INITIATION, TYPE_RESPONSE};
+use super::noise;
+use super::types::{HandshakeError, Psk};
+
+use super::super::types::KeyPair;
+
+/// The cleartext fields of a handshake message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeMessage {
+    Initiation {
+        sender: u32,         // identifier of the initiator
+        ephemeral: [u8; 32], // ephemeral public key of the initiator
+        mac2: bool,          // the mac2 field is set (i.e. the initiator holds a cookie)
+    },
+    Response {
+        sender: u32,         // identifier of the responder
+        receiver: u32,       // identifier of the initiator
+        ephemeral: [u8; 32], // ephemeral public key of the responder
+        mac2: bool,          // the mac2 field is set
+    },
+    CookieReply {
+        receiver: u32, // identifier of the sender of the message answered by the cookie reply
+    },
+}
+
+/// Parses the cleartext fields of a handshake message (untrusted input),
+/// no keys are required and no MAC is verified.
+pub fn parse_message(msg: &[u8]) -> Result<HandshakeMessage, HandshakeError> {
+    if msg.len() < 4 {
+        return Err(HandshakeError::InvalidMessageFormat);
+    }
+    match LittleEndian::read_u32(msg) {
+        TYPE_INITIATION => {
+            let msg = Initiation::parse(msg)?;
+            Ok(HandshakeMessage::Initiation {
+                sender: msg.noise.f_sender.get(),
+                ephemeral: msg.noise.f_ephemeral,
+                mac2: msg.macs.f_mac2 != [0u8; 16],
+            })
+        }
+        TYPE_RESPONSE => {
+            let msg = Response::parse(msg)?;
+            Ok(HandshakeMessage::Response {
+                sender: msg.noise.f_sender.get(),
+                receiver: msg.noise.f_receiver.get(),
+                ephemeral: msg.noise.f_ephemeral,
+                mac2: msg.macs.f_mac2 != [0u8; 16],
+            })
+        }
+        TYPE_COOKIE_REPLY => {
+            let msg = CookieReply::parse(msg)?;
+            Ok(HandshakeMessage::CookieReply {
+                receiver: msg.f_receiver.get(),
+            })
+        }
+        _ => Err(HandshakeError::InvalidMessageFormat),
+    }
+}
+
+/// Decrypts the static public key of the initiator of an initiation
+/// addressed to the secret key (the timestamp and MACs are not verified).
+pub fn peek_initiator(sk: &StaticSecret, msg: &[u8]) -> Result<PublicKey, HandshakeError> {
+    let msg = Initiation::parse(msg)?;
+    let keyst = KeyState::new(StaticSecret::from(sk.to_bytes()), false, None);
+    noise::peek_initiation(&keyst, &msg.noise)
+}
+
+/// One end of a handshake with a single peer
+pub struct MessageBuilder {
+    device: Device<()>,
+    peer: PublicKey,
+}
+
+impl MessageBuilder {
+    /// Create a new builder
+    ///
+    /// # Arguments
+    ///
+    /// - `sk`: The static private key of the local end
+    /// - `peer`: The static public key of the remote end
+    /// - `psk`: The pre-shared key (all zero if none)
+    pub fn new(
+        sk: StaticSecret,
+        peer: PublicKey,
+        psk: Psk,
+    ) -> Result<MessageBuilder, HandshakeError> {
+        let mut device = Device::new();
+        device.set_sk(Some(sk));
+        device
+            .add(peer, ())
+            .and_then(|_| device.set_psk(peer, psk))
+            .map_err(|_| HandshakeError::UnknownPublicKey)?;
+        Ok(MessageBuilder { device, peer })
+    }
+
+    /// Construct an initiation to the peer
+    /// (with mac2 if a cookie from the peer is held, see consume)
+    pub fn initiation<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+    ) -> Result<Vec<u8>, HandshakeError> {
+        self.device.begin(rng, &self.peer)
+    }
+
+    /// Consume a message from the peer:
+    /// an initiation is answered by a response, a response completes the handshake
+    /// initiated by this builder and a cookie reply is stored for computing mac2.
+    ///
+    /// # Arguments
+    ///
+    /// - `msg`: The message (untrusted input)
+    /// - `src`: The source of the message, if set mac2 is demanded (as under load)
+    ///   and a message without a valid mac2 is answered by a cookie reply
+    ///
+    /// # Returns
+    ///
+    /// The reply to the message (if any) and the derived key-pair (if any)
+    pub fn consume<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        msg: &[u8],
+        src: Option<SocketAddr>,
+    ) -> Result<(Option<Vec<u8>>, Option<KeyPair>), HandshakeError> {
+        let (_, reply, keypair) = self.device.process(rng, msg, src)?;
+        Ok((reply, keypair))
+    }
+
+    /// Release the identifiers allocated for the key-pairs derived by this builder
+    /// (every initiation and response allocates a new identifier)
+    pub fn reset(&self) {
+        for id in self.device.peer_ids(&self.peer) {
+            self.device.release(id);
+        }
+    }
+}
@@ -1,7 +1,7 @@
-use std::collections::hash_map;
-use std::collections::HashMap;
+use std::collections::HashSet;
 use std::net::SocketAddr;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use byteorder::{ByteOrder, LittleEndian};
 use dashmap::mapref::entry::Entry;
@@ -17,33 +17,72 @@ use x25519_dalek::PublicKey;
 use x25519_dalek::StaticSecret;
 
 use super::macs;
-use super::messages::{CookieReply, Initiation, Response};
+use super::messages::{CookieReply, Initiation, MacsFooter, Response};
 use super::messages::{TYPE_COOKIE_REPLY, TYPE_INITIATION, TYPE_RESPONSE};
 use super::noise;
-use super::peer::Peer;
+use super::peer::{Peer, State};
+use super::peermap::{self, PeerMap};
 use super::ratelimiter::RateLimiter;
+use super::timestamp::{self, Clock, SystemClock};
 use super::types::*;
 
+use super::super::types::HandshakeRole;
+
 const MAX_PEER_PER_DEVICE: usize = 1 << 20;
 
 pub struct KeyState {
     pub(super) sk: StaticSecret, // static secret key
     pub(super) pk: PublicKey,    // static public key
     macs: macs::Validator,       // validator for the mac fields
+
+    // secondary keys are only used to respond to initiations
+    // and may be restricted to a subset of the peers (None = all peers)
+    pub(super) secondary: bool,
+    pub(super) peers: Option<HashSet<[u8; 32]>>,
+}
+
+impl KeyState {
+    pub(super) fn new(
+        sk: StaticSecret,
+        secondary: bool,
+        peers: Option<HashSet<[u8; 32]>>,
+    ) -> KeyState {
+        let pk = PublicKey::from(&sk);
+        let macs = macs::Validator::new(pk);
+        KeyState {
+            pk,
+            sk,
+            macs,
+            secondary,
+            peers,
+        }
+    }
+
+    /// Checks if the peer is permitted to handshake using this key
+    pub(super) fn permits(&self, pk: &PublicKey) -> bool {
+        self.peers
+            .as_ref()
+            .map(|peers| peers.contains(pk.as_bytes()))
+            .unwrap_or(true)
+    }
 }
 
 /// The device is generic over an "opaque" type
 /// which can be used to associate the public key with this value.
 /// (the instance is a Peer object in the parent module)
 pub struct Device<O> {
-    keyst: Option<KeyState>,
-    id_map: DashMap<u32, [u8; 32]>, // concurrent map
-    pk_map: HashMap<[u8; 32], Peer<O>>,
-    limiter: Mutex<RateLimiter>,
+    keyst: Option<Arc<KeyState>>,
+    secondary: Vec<Arc<KeyState>>, // additional identities (e.g. during key rotation)
+    id_map: Arc<DashMap<u32, [u8; 32]>>, // concurrent map
+    pk_map: PeerMap<O>,
+    limiter: Arc<Mutex<RateLimiter>>,
+    stealth: bool, // never answer initiators with unknown static keys (not even by cookie replies)
+    clock: Arc<dyn Clock>, // source of the timestamps of initiations
+    last_timestamp: Arc<Mutex<Duration>>, // timestamp of the last initiation
 }
 
 pub struct Iter<'a, O> {
-    iter: hash_map::Iter<'a, [u8; 32], Peer<O>>,
+    iter: peermap::Iter<'a, O>,
 }
 
 impl<'a, O> Iterator for Iter<'a, O> {
@@ -56,6 +95,26 @@ impl<'a, O> Iterator for Iter<'a, O> {
     }
 }
 
+/* A copy of the device shares the keys, the allocated ids, the rate limiter, the last timestamp
+ * and the mutable state of every peer with the original,
+ * such that a copy can be configured and then replace the original (see wireguard::Snapshot).
+ * The peers are shared by shard (see PeerMap), hence a copy is cheap regardless of the number of peers.
+ */
+impl<O> Clone for Device<O> {
+    fn clone(&self) -> Self {
+        Device {
+            keyst: self.keyst.clone(),
+            secondary: self.secondary.clone(),
+            id_map: self.id_map.clone(),
+            pk_map: self.pk_map.clone(),
+            limiter: self.limiter.clone(),
+            stealth: self.stealth,
+            clock: self.clock.clone(),
+            last_timestamp: self.last_timestamp.clone(),
+        }
+    }
+}
+
 /* These methods enable the Device to act as a map
  * from public keys to the set of contained opaque values.
  *
@@ -97,12 +156,49 @@ impl<O> Device<O> {
     pub fn new() -> Device<O> {
         Device {
             keyst: None,
-            id_map: DashMap::new(),
-            pk_map: HashMap::new(),
-            limiter: Mutex::new(RateLimiter::new()),
+            secondary: vec![],
+            id_map: Arc::new(DashMap::new()),
+            pk_map: PeerMap::new(),
+            limiter: Arc::new(Mutex::new(RateLimiter::new())),
+            stealth: false,
+            clock: Arc::new(SystemClock),
+            last_timestamp: Arc::new(Mutex::new(Duration::from_secs(0))),
         }
     }
 
+    /// Enable/disable stealth:
+    /// by default an initiation with a valid mac1 (i.e. by anyone knowing the public key of the device)
+    /// is answered by a cookie reply under load, before the static key of the initiator is known.
+    /// In stealth the static key is decrypted first (at the cost of a DH computation)
+    /// and initiations from unknown keys (or responses to no initiation) are never answered.
+    pub fn set_stealth(&mut self, stealth: bool) {
+        self.stealth = stealth;
+    }
+
+    pub fn get_stealth(&self) -> bool {
+        self.stealth
+    }
+
+    /// Set the source of the timestamps of initiations (the system clock by default)
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    // The timestamp of a new initiation: the time of the clock,
+    // but strictly greater than that of every previous initiation.
+    // Should the clock be stepped backwards (e.g. by NTP),
+    // initiations would otherwise be rejected as replays until it caught up.
+    fn timestamp(&self) -> timestamp::TAI64N {
+        let mut last = self.last_timestamp.lock().unwrap();
+        let now = self.clock.now();
+        *last = if now > *last {
+            now
+        } else {
+            *last + Duration::from_nanos(1)
+        };
+        timestamp::tai64n(*last)
+    }
+
     fn update_ss(&mut self) -> (Vec<u32>, Option<PublicKey>) {
         let mut same = None;
         let mut ids = Vec::with_capacity(self.pk_map.len());
@@ -131,11 +227,7 @@ impl<O> Device<O> {
     /// * `sk` - x25519 scalar representing the local private key
     pub fn set_sk(&mut self, sk: Option<StaticSecret>) -> Option<PublicKey> {
         // update secret and public key
-        self.keyst = sk.map(|sk| {
-            let pk = PublicKey::from(&sk);
-            let macs = macs::Validator::new(pk);
-            KeyState { pk, sk, macs }
-        });
+        self.keyst = sk.map(|sk| Arc::new(KeyState::new(sk, false, None)));
 
         // recalculate / erase the shared secrets for every peer
         let (ids, same) = self.update_ss();
@@ -162,6 +254,68 @@ impl<O> Device<O> {
         self.keyst.as_ref().map(|key| &key.sk)
     }
 
+    /// Add a secondary secret key to the device
+    ///
+    /// Initiations addressed to the public key of a secondary key are accepted
+    /// (in addition to those addressed to the primary key set with set_sk),
+    /// which allows rotation of the device key with an overlap window.
+    /// Initiations created by the device always use the primary key.
+    ///
+    /// # Arguments
+    ///
+    /// * `sk` - x25519 scalar representing the secondary private key
+    /// * `peers` - The peers permitted to use the key (None, if every peer may use it)
+    ///
+    /// # Returns
+    ///
+    /// The public key of the secondary key.
+    /// Any prior secondary key with the same public key is replaced.
+    pub fn add_secondary_sk(&mut self, sk: StaticSecret, peers: Option<&[PublicKey]>) -> PublicKey {
+        let peers = peers.map(|peers| peers.iter().map(|pk| *pk.as_bytes()).collect());
+        let keyst = KeyState::new(sk, true, peers);
+        let pk = keyst.pk;
+        self.secondary
+            .retain(|key| key.pk.as_bytes() != pk.as_bytes());
+        self.secondary.push(Arc::new(keyst));
+        pk
+    }
+
+    /// Remove a secondary secret key from the device
+    ///
+    /// # Arguments
+    ///
+    /// * `pk` - The public key of the secondary key
+    ///
+    /// # Returns
+    ///
+    /// A bool indicating if the key was found (and removed)
+    pub fn remove_secondary_sk(&mut self, pk: &PublicKey) -> bool {
+        let len = self.secondary.len();
+        self.secondary
+            .retain(|key| key.pk.as_bytes() != pk.as_bytes());
+        len != self.secondary.len()
+    }
+
+    /// Remove every secondary secret key from the device
+    pub fn clear_secondary_sks(&mut self) {
+        self.secondary.clear();
+    }
+
+    /// Return the secondary secret keys of the device
+    /// and the peers permitted to use each (None, if every peer may use it)
+    pub fn get_secondary_sks(&self) -> Vec<(&StaticSecret, Option<Vec<PublicKey>>)> {
+        self.secondary
+            .iter()
+            .map(|key| {
+                let peers = key
+                    .peers
+                    .as_ref()
+                    .map(|peers| peers.iter().map(|pk| PublicKey::from(*pk)).collect());
+                (&key.sk, peers)
+            })
+            .collect()
+    }
+
     /// Add a new public key to the state machine
     /// To remove public keys, you must create a new machine instance
     ///
@@ -172,13 +326,13 @@ impl<O> Device<O> {
     pub fn add(&mut self, pk: PublicKey, opaque: O) -> Result<(), ConfigError> {
         // ensure less than 2^20 peers
         if self.pk_map.len() > MAX_PEER_PER_DEVICE {
-            return Err(ConfigError::new("Too many peers for device"));
+            return Err(ConfigError::TooManyPeers);
         }
 
         // error if public key matches device
         if let Some(key) = self.keyst.as_ref() {
             if pk.as_bytes() == key.pk.as_bytes() {
-                return Err(ConfigError::new("Public key of peer matches the device"));
+                return Err(ConfigError::KeyOfDevice);
             }
         }
 
@@ -212,7 +366,7 @@ impl<O> Device<O> {
         // remove the peer
         self.pk_map
             .remove(pk.as_bytes())
-            .ok_or(ConfigError::new("Public key not in device"))?;
+            .ok_or(ConfigError::UnknownPeer)?;
 
         // remove every id entry for the peer in the public key map
         // O(n) operations, however it is rare: only when removing peers.
@@ -236,7 +390,7 @@ impl<O> Device<O> {
                 peer.psk = psk;
                 Ok(())
             }
-            _ => Err(ConfigError::new("No such public key")),
+            _ => Err(ConfigError::UnknownPeer),
         }
     }
 
@@ -254,7 +408,35 @@ impl<O> Device<O> {
     pub fn get_psk(&self, pk: &PublicKey) -> Result<Psk, ConfigError> {
         match self.pk_map.get(pk.as_bytes()) {
             Some(peer) => Ok(peer.psk),
-            _ => Err(ConfigError::new("No such public key")),
+            _ => Err(ConfigError::UnknownPeer),
+        }
+    }
+
+    /// Set which end of a handshake the device may take with the peer
+    ///
+    /// # Arguments
+    ///
+    /// * `pk` - The public key of the peer
+    /// * `role` - Responder: the device never initiates a handshake,
+    ///   Initiator: initiations from the peer are rejected (before the costly DH operations)
+    ///
+    /// # Returns
+    ///
+    /// The call might fail if the public key is not found
+    pub fn set_role(&mut self, pk: PublicKey, role: HandshakeRole) -> Result<(), ConfigError> {
+        match self.pk_map.get_mut(pk.as_bytes()) {
+            Some(mut peer) => {
+                peer.role = role;
+                Ok(())
+            }
+            _ => Err(ConfigError::UnknownPeer),
+        }
+    }
+
+    pub fn get_role(&self, pk: &PublicKey) -> Result<HandshakeRole, ConfigError> {
+        match self.pk_map.get(pk.as_bytes()) {
+            Some(peer) => Ok(peer.role),
+            _ => Err(ConfigError::UnknownPeer),
         }
     }
 
@@ -268,6 +450,60 @@ impl<O> Device<O> {
         assert!(old.is_some(), "released id not allocated");
     }
 
+    /// Returns the number of allocated (receiver) ids
+    pub fn allocated_ids(&self) -> usize {
+        self.id_map.len()
+    }
+
+    /// Returns the (receiver) ids currently allocated (to any peer)
+    pub fn ids(&self) -> HashSet<u32> {
+        self.id_map.iter().map(|entry| *entry.key()).collect()
+    }
+
+    /// Returns the (receiver) ids currently allocated to the peer
+    ///
+    /// # Arguments
+    ///
+    /// * `pk` - Public key of the peer
+    pub fn peer_ids(&self, pk: &PublicKey) -> Vec<u32> {
+        self.id_map
+            .iter()
+            .filter(|entry| entry.value() == pk.as_bytes())
+            .map(|entry| *entry.key())
+            .collect()
+    }
+
+    /// Reclaim (receiver) ids which are no longer in use
+    ///
+    /// # Arguments
+    ///
+    /// * `live` - Returns true if the id is still held by a key-pair (e.g. in the router)
+    ///
+    /// # Returns
+    ///
+    /// The number of ids reclaimed.
+    ///
+    /// # Note
+    ///
+    /// Ids held by pending initiations are always retained.
+    /// The caller must deem live the ids allocated by handshakes processed concurrently
+    /// (by any copy of the device), which could be holding an id not yet handed to the router.
+    pub fn gc<F: Fn(u32) -> bool>(&self, live: F) -> usize {
+        let pending: HashSet<u32> = self
+            .pk_map
+            .values()
+            .filter_map(|peer| match *peer.state.lock() {
+                State::InitiationSent { local, .. } => Some(local),
+                _ => None,
+            })
+            .collect();
+
+        let before = self.id_map.len();
+        self.id_map
+            .retain(|id, _| pending.contains(id) || live(*id));
+        before - self.id_map.len()
+    }
+
     /// Begin a new handshake
     ///
     /// # Arguments
@@ -281,12 +517,14 @@ impl<O> Device<O> {
         match (self.keyst.as_ref(), self.pk_map.get(pk.as_bytes())) {
             (_, None) => Err(HandshakeError::UnknownPublicKey),
             (None, _) => Err(HandshakeError::UnknownPublicKey),
+            (_, Some(peer)) if !peer.role.initiates() => Err(HandshakeError::RoleRefused),
             (Some(keyst), Some(peer)) => {
                 let local = self.allocate(rng, pk);
                 let mut msg = Initiation::default();
 
                 // create noise part of initation
-                noise::create_initiation(rng, keyst, peer, pk, local, &mut msg.noise)?;
+                let ts = self.timestamp();
+                noise::create_initiation(rng, keyst, peer, pk, local, &ts, &mut msg.noise)?;
 
                 // add macs to initation
                 peer.macs
@@ -330,12 +568,23 @@ impl<O> Device<O> {
                 let msg = Initiation::parse(msg)?;
 
                 // check mac1 field
-                keyst.macs.check_mac1(msg.noise.as_bytes(), &msg.macs)?;
+                // (this also determines which of our keys the initiation is addressed to)
+                let keyst = self.lookup_keyst(keyst, msg.noise.as_bytes(), &msg.macs)?;
 
                 // address validation & DoS mitigation
                 if let Some(src) = src {
                     // check mac2 field
                     if !keyst.macs.check_mac2(msg.noise.as_bytes(), &src, &msg.macs) {
+                        // stealth: the initiator must be a peer (permitted to use the key)
+                        if self.stealth {
+                            let permitted = noise::peek_initiation(keyst, &msg.noise)
+                                .map(|pk| self.lookup_pk(&pk).is_ok() && keyst.permits(&pk))
+                                .unwrap_or(false);
+                            if !permitted {
+                                return Err(HandshakeError::Stealth);
+                            }
+                        }
+
                         let mut reply = Default::default();
                         keyst.macs.create_cookie_reply(
                             rng,
@@ -394,6 +643,11 @@ impl<O> Device<O> {
 
                     // check mac2 field
                     if !keyst.macs.check_mac2(msg.noise.as_bytes(), &src, &msg.macs) {
+                        // stealth: the response must answer an initiation of the device
+                        if self.stealth && self.lookup_id(msg.noise.f_receiver.get()).is_err() {
+                            return Err(HandshakeError::Stealth);
+                        }
+
                         let mut reply = Default::default();
                         keyst.macs.create_cookie_reply(
                             rng,
@@ -431,6 +685,105 @@ impl<O> Device<O> {
         }
     }
 
+    /// Determine the peer a handshake message belongs to
+    /// and whether the message would be accepted,
+    /// without altering the state of the device (used for diagnostics).
+    ///
+    /// # Note
+    ///
+    /// Checks which depend on the state of the peer (timestamps, rate limits)
+    /// are not performed.
+    ///
+    /// # Arguments
+    ///
+    /// * `msg` - Byte slice containing the message (untrusted input)
+    ///
+    /// # Returns
+    ///
+    /// The public key of the peer (if it could be determined)
+    /// and the reason the message would be rejected (if any).
+    pub fn inspect(&self, msg: &[u8]) -> (Option<PublicKey>, Result<(), HandshakeError>) {
+        let keyst = match self.keyst.as_ref() {
+            Some(key) => key,
+            None => return (None, Err(HandshakeError::UnknownPublicKey)),
+        };
+
+        if msg.len() < 4 {
+            return (None, Err(HandshakeError::InvalidMessageFormat));
+        }
+
+        match LittleEndian::read_u32(msg) {
+            TYPE_INITIATION => {
+                let msg = match Initiation::parse(msg) {
+                    Ok(msg) => msg,
+                    Err(e) => return (None, Err(e)),
+                };
+                let keyst = match self.lookup_keyst(keyst, msg.noise.as_bytes(), &msg.macs) {
+                    Ok(keyst) => keyst,
+                    Err(e) => return (None, Err(e)),
+                };
+                match noise::peek_initiation(keyst, &msg.noise) {
+                    Ok(pk) => {
+                        if !self.pk_map.contains_key(pk.as_bytes()) || !keyst.permits(&pk) {
+                            (Some(pk), Err(HandshakeError::UnknownPublicKey))
+                        } else {
+                            (Some(pk), Ok(()))
+                        }
+                    }
+                    Err(e) => (None, Err(e)),
+                }
+            }
+            TYPE_RESPONSE => {
+                let msg = match Response::parse(msg) {
+                    Ok(msg) => msg,
+                    Err(e) => return (None, Err(e)),
+                };
+                let pk = self
+                    .lookup_id(msg.noise.f_receiver.get())
+                    .ok()
+                    .map(|(_, pk)| pk);
+                let res = keyst
+                    .macs
+                    .check_mac1(msg.noise.as_bytes(), &msg.macs)
+                    .and_then(|_| pk.map(|_| ()).ok_or(HandshakeError::UnknownReceiverId));
+                (pk, res)
+            }
+            TYPE_COOKIE_REPLY => {
+                let msg = match CookieReply::parse(msg) {
+                    Ok(msg) => msg,
+                    Err(e) => return (None, Err(e)),
+                };
+                // the cookie is authenticated by the mac1 of the message it answers
+                match self.lookup_id(msg.f_receiver.get()) {
+                    Ok((peer, pk)) => (Some(pk), peer.macs.lock().verify(&msg)),
+                    Err(e) => (None, Err(e)),
+                }
+            }
+            _ => (None, Err(HandshakeError::InvalidMessageFormat)),
+        }
+    }
+
+    // Internal function
+    //
+    // Return the key (primary or secondary) for which the mac1 field is valid,
+    // mac1 is computed using the public key of the receiver.
+    fn lookup_keyst<'a>(
+        &'a self,
+        primary: &'a KeyState,
+        inner: &[u8],
+        macs: &MacsFooter,
+    ) -> Result<&'a KeyState, HandshakeError> {
+        match primary.macs.check_mac1(inner, macs) {
+            Ok(()) => Ok(primary),
+            Err(e) => self
+                .secondary
+                .iter()
+                .find(|key| key.macs.check_mac1(inner, macs).is_ok())
+                .map(|key| &**key)
+                .ok_or(e),
+        }
+    }
+
     // Internal function
     //
     // Return the peer associated with the public key
@@ -1,4 +1,3 @@
-use generic_array::GenericArray;
 use rand::{CryptoRng, RngCore};
 use spin::RwLock;
 use std::time::{Duration, Instant};
@@ -7,14 +6,10 @@ use std::time::{Duration, Instant};
 use std::net::SocketAddr;
 use x25519_dalek::PublicKey;
 
-// AEAD
-use aead::{Aead, NewAead, Payload};
-use chacha20poly1305::XChaCha20Poly1305;
-
 // MAC
-use blake2::Blake2s;
 use subtle::ConstantTimeEq;
 
+use super::super::crypto::{Backend, Selected};
 use super::messages::{CookieReply, MacsFooter, TYPE_COOKIE_REPLY};
 use super::types::HandshakeError;
 
@@ -28,55 +23,52 @@ const SIZE_TAG: usize = 16; // xchacha20poly1305 tag
 
 const COOKIE_UPDATE_INTERVAL: Duration = Duration::from_secs(120);
 
+// a cookie received is used for less than COOKIE_UPDATE_INTERVAL,
+// since it may have been created (by the responder) up to this long before it was received
+const COOKIE_LATENCY: Duration = Duration::from_secs(5);
+
 macro_rules! HASH {
     ( $($input:expr),* ) => {{
-        use blake2::Digest;
-        let mut hsh = Blake2s::new();
-        $(
-            hsh.input($input);
-        )*
-        hsh.result()
+        Selected::hash(&[$(&$input[..]),*])
     }};
 }
 
 macro_rules! MAC {
     ( $key:expr, $($input:expr),* ) => {{
-        use blake2::VarBlake2s;
-        use digest::Input;
-        use digest::VariableOutput;
-        let mut tag = [0u8; SIZE_MAC];
-        let mut mac = VarBlake2s::new_keyed($key, SIZE_MAC);
-        $(
-            mac.input($input);
-        )*
-        mac.variable_result(|buf| tag.copy_from_slice(buf));
-        tag
+        Selected::mac($key, &[$(&$input[..]),*])
     }};
 }
 
+// XChaCha20Poly1305: ChaCha20Poly1305 keyed by the HChaCha20 subkey
+fn xnonce(key: &[u8; 32], nonce: &[u8; 24]) -> ([u8; 32], [u8; 12]) {
+    let mut prefix = [0u8; 16];
+    let mut suffix = [0u8; 12];
+    prefix.copy_from_slice(&nonce[..16]);
+    suffix[4..].copy_from_slice(&nonce[16..]);
+    (Selected::hchacha20(key, &prefix), suffix)
+}
+
 macro_rules! XSEAL {
     ($key:expr, $nonce:expr, $ad:expr, $pt:expr, $ct:expr) => {{
-        let ct = XChaCha20Poly1305::new(*GenericArray::from_slice($key))
-            .encrypt(
-                GenericArray::from_slice($nonce),
-                Payload { msg: $pt, aad: $ad },
-            )
-            .unwrap();
-        debug_assert_eq!(ct.len(), $pt.len() + SIZE_TAG);
-        $ct.copy_from_slice(&ct);
+        let pt = $pt;
+        let ct = $ct;
+        debug_assert_eq!(ct.len(), pt.len() + SIZE_TAG);
+        let (key, nonce) = xnonce($key, $nonce);
+        let (body, tag) = ct.split_at_mut(pt.len());
+        body.copy_from_slice(pt);
+        tag.copy_from_slice(&Selected::seal(&key, &nonce, $ad, body));
     }};
 }
 
 macro_rules! XOPEN {
     ($key:expr, $nonce:expr, $ad:expr, $pt:expr, $ct:expr) => {{
-        debug_assert_eq!($ct.len(), $pt.len() + SIZE_TAG);
-        XChaCha20Poly1305::new(*GenericArray::from_slice($key))
-            .decrypt(
-                GenericArray::from_slice($nonce),
-                Payload { msg: $ct, aad: $ad },
-            )
+        let pt = $pt;
+        let mut buf = *$ct;
+        debug_assert_eq!(buf.len(), pt.len() + SIZE_TAG);
+        let (key, nonce) = xnonce($key, $nonce);
+        Selected::open(&key, &nonce, $ad, &mut buf[..])
             .map_err(|_| HandshakeError::DecryptionFailure)
-            .map(|pt| $pt.copy_from_slice(&pt))
+            .map(|_| pt.copy_from_slice(&buf[..pt.len()]))
     }};
 }
 
@@ -121,8 +113,8 @@ impl Generator {
     /// A freshly initated generator
     pub fn new(pk: PublicKey) -> Generator {
         Generator {
-            mac1_key: HASH!(LABEL_MAC1, pk.as_bytes()).into(),
-            cookie_key: HASH!(LABEL_COOKIE, pk.as_bytes()).into(),
+            mac1_key: HASH!(LABEL_MAC1, pk.as_bytes()),
+            cookie_key: HASH!(LABEL_COOKIE, pk.as_bytes()),
             last_mac1: None,
             cookie: None,
         }
@@ -139,6 +131,29 @@ impl Generator {
     /// Can fail if the cookie reply fails to validate
     /// (either indicating that it is outdated or malformed)
     pub fn process(&mut self, reply: &CookieReply) -> Result<(), HandshakeError> {
+        let tau = self.open(reply)?;
+        self.cookie = Some(Cookie {
+            birth: Instant::now(),
+            value: tau,
+        });
+        Ok(())
+    }
+
+    /// Verify a CookieReply message without storing the cookie
+    ///
+    /// # Arguments
+    ///
+    /// - reply: CookieReply to verify
+    ///
+    /// # Returns
+    ///
+    /// Can fail if the cookie reply would fail to validate in Generator::process
+    pub fn verify(&self, reply: &CookieReply) -> Result<(), HandshakeError> {
+        self.open(reply).map(|_| ())
+    }
+
+    // decrypts the cookie, authenticated by the mac1 of the last message sent
+    fn open(&self, reply: &CookieReply) -> Result<[u8; SIZE_COOKIE], HandshakeError> {
         let mac1 = self.last_mac1.ok_or(HandshakeError::InvalidState)?;
         let mut tau = [0u8; SIZE_COOKIE];
         XOPEN!(
@@ -148,11 +163,7 @@ impl Generator {
             &mut tau,         // pt
             &reply.f_cookie   // ct || tag
         )?;
-        self.cookie = Some(Cookie {
-            birth: Instant::now(),
-            value: tau,
-        });
-        Ok(())
+        Ok(tau)
     }
 
     /// Generate both mac fields for an inner message
@@ -165,7 +176,7 @@ impl Generator {
         macs.f_mac1 = MAC!(&self.mac1_key, inner);
         macs.f_mac2 = match &self.cookie {
             Some(cookie) => {
-                if cookie.birth.elapsed() > COOKIE_UPDATE_INTERVAL {
+                if cookie.birth.elapsed() > COOKIE_UPDATE_INTERVAL - COOKIE_LATENCY {
                     self.cookie = None;
                     [0u8; SIZE_MAC]
                 } else {
@@ -192,8 +203,8 @@ pub struct Validator {
 impl Validator {
     pub fn new(pk: PublicKey) -> Validator {
         Validator {
-            mac1_key: HASH!(LABEL_MAC1, pk.as_bytes()).into(),
-            cookie_key: HASH!(LABEL_COOKIE, pk.as_bytes()).into(),
+            mac1_key: HASH!(LABEL_MAC1, pk.as_bytes()),
+            cookie_key: HASH!(LABEL_COOKIE, pk.as_bytes()),
             secret: RwLock::new(Secret {
                 value: [0u8; SIZE_SECRET],
                 birth: Instant::now() - Duration::new(86400, 0),
@@ -12,7 +12,7 @@ use zerocopy::{AsBytes, ByteSlice, FromBytes, LayoutVerified};
 
 use super::types::*;
 
-const SIZE_MAC: usize = 16;
+pub const SIZE_MAC: usize = 16;
 const SIZE_TAG: usize = 16; // poly1305 tag
 const SIZE_XNONCE: usize = 24; // xchacha20 nonce
 const SIZE_COOKIE: usize = 16; //
@@ -23,16 +23,20 @@ pub const TYPE_INITIATION: u32 = 1;
 pub const TYPE_RESPONSE: u32 = 2;
 pub const TYPE_COOKIE_REPLY: u32 = 3;
 
-const fn max(a: usize, b: usize) -> usize {
-    let m: usize = (a > b) as usize;
-    m * a + (1 - m) * b
+/// Returns the exact size of a handshake message of the given type
+///
+/// # Returns
+///
+/// None if the type is not a handshake message type
+pub fn handshake_message_size(ty: u32) -> Option<usize> {
+    match ty {
+        TYPE_INITIATION => Some(mem::size_of::<Initiation>()),
+        TYPE_RESPONSE => Some(mem::size_of::<Response>()),
+        TYPE_COOKIE_REPLY => Some(mem::size_of::<CookieReply>()),
+        _ => None,
+    }
 }
 
-pub const MAX_HANDSHAKE_MSG_SIZE: usize = max(
-    max(mem::size_of::<Response>(), mem::size_of::<Initiation>()),
-    mem::size_of::<CookieReply>(),
-);
-
 /* Handshake messsages */
 
 #[repr(packed)]
@@ -6,11 +6,13 @@
  * For documentation.
  */
 
+mod builder;
 mod device;
 mod macs;
 mod messages;
 mod noise;
 mod peer;
+mod peermap;
 mod ratelimiter;
 mod timestamp;
 mod types;
@@ -20,5 +22,9 @@ mod tests;
 
 // publicly exposed interface
 
+pub use builder::{parse_message, peek_initiator, HandshakeMessage, MessageBuilder};
 pub use device::Device;
-pub use messages::{MAX_HANDSHAKE_MSG_SIZE, TYPE_COOKIE_REPLY, TYPE_INITIATION, TYPE_RESPONSE};
+pub use messages::{handshake_message_size, SIZE_MAC};
+pub use messages::{TYPE_COOKIE_REPLY, TYPE_INITIATION, TYPE_RESPONSE};
+pub use timestamp::{Clock, PersistedClock, SystemClock};
+pub use types::HandshakeError;
@@ -3,14 +3,6 @@ use std::time::Instant;
 // DH
 use x25519_dalek::{PublicKey, SharedSecret, StaticSecret};
 
-// HASH & MAC
-use blake2::Blake2s;
-use hmac::Hmac;
-
-// AEAD
-use aead::{Aead, NewAead, Payload};
-use chacha20poly1305::ChaCha20Poly1305;
-
 use log;
 
 use rand::prelude::{CryptoRng, RngCore};
@@ -30,12 +22,9 @@ use super::peer::{Peer, State};
 use super::timestamp;
 use super::types::*;
 
+use super::super::crypto::{Backend, Selected, SIZE_KEY, SIZE_NONCE, SIZE_TAG};
 use super::super::types::{Key, KeyPair};
 
-// HMAC hasher (generic construction)
-
-type HMACBlake2s = Hmac<Blake2s>;
-
 // convenient alias to pass state temporarily into device.rs and back
 
 type TemporaryState = (u32, PublicKey, GenericArray<u8, U32>, GenericArray<u8, U32>);
@@ -58,27 +47,19 @@ const INITIAL_HS: [u8; SIZE_HS] = [
     0x2d, 0x9c, 0x6c, 0x66, 0x22, 0x93, 0xe8, 0xb7, 0x0e, 0xe1, 0x9c, 0x65, 0xba, 0x07, 0x9e, 0xf3,
 ];
 
-const ZERO_NONCE: [u8; 12] = [0u8; 12];
+const ZERO_NONCE: [u8; SIZE_NONCE] = [0u8; SIZE_NONCE];
 
 macro_rules! HASH {
     ( $($input:expr),* ) => {{
-        use blake2::Digest;
-        let mut hsh = Blake2s::new();
-        $(
-            hsh.input($input);
-        )*
-        hsh.result()
+        let inputs: &[&[u8]] = &[$(&$input[..]),*];
+        GenericArray::<u8, U32>::clone_from_slice(&Selected::hash(inputs))
     }};
 }
 
 macro_rules! HMAC {
     ($key:expr, $($input:expr),*) => {{
-        use hmac::Mac;
-        let mut mac = HMACBlake2s::new_varkey($key).unwrap();
-        $(
-            mac.input($input);
-        )*
-        mac.result().code()
+        let inputs: &[&[u8]] = &[$(&$input[..]),*];
+        GenericArray::<u8, U32>::clone_from_slice(&Selected::hmac(&$key[..], inputs))
     }};
 }
 
@@ -113,21 +94,33 @@ macro_rules! KDF3 {
 }
 
 macro_rules! SEAL {
-    ($key:expr, $ad:expr, $pt:expr, $ct:expr) => {
-        ChaCha20Poly1305::new(*GenericArray::from_slice($key))
-            .encrypt(&ZERO_NONCE.into(), Payload { msg: $pt, aad: $ad })
-            .map(|ct| $ct.copy_from_slice(&ct))
-            .unwrap()
-    };
+    ($key:expr, $ad:expr, $pt:expr, $ct:expr) => {{
+        let mut key = [0u8; SIZE_KEY];
+        key.copy_from_slice(&$key[..]);
+        let pt: &[u8] = $pt;
+        let ct: &mut [u8] = $ct;
+        let (body, tag) = ct.split_at_mut(pt.len());
+        body.copy_from_slice(pt);
+        tag.copy_from_slice(&Selected::seal(&key, &ZERO_NONCE, &$ad[..], body));
+        key.clear();
+    }};
 }
 
 macro_rules! OPEN {
-    ($key:expr, $ad:expr, $pt:expr, $ct:expr) => {
-        ChaCha20Poly1305::new(*GenericArray::from_slice($key))
-            .decrypt(&ZERO_NONCE.into(), Payload { msg: $ct, aad: $ad })
+    ($key:expr, $ad:expr, $pt:expr, $ct:expr) => {{
+        let mut key = [0u8; SIZE_KEY];
+        key.copy_from_slice(&$key[..]);
+        let pt: &mut [u8] = $pt;
+        let ct: &[u8] = &$ct[..];
+        let mut buf = [0u8; SIZE_KEY + SIZE_TAG]; // largest ciphertext (msg.static)
+        buf[..ct.len()].copy_from_slice(ct);
+        let res = Selected::open(&key, &ZERO_NONCE, &$ad[..], &mut buf[..ct.len()])
             .map_err(|_| HandshakeError::DecryptionFailure)
-            .map(|pt| $pt.copy_from_slice(&pt))
-    };
+            .map(|_| pt.copy_from_slice(&buf[..pt.len()]));
+        key.clear();
+        buf.clear();
+        res
+    }};
 }
 
 #[cfg(test)]
@@ -235,6 +228,7 @@ pub(super) fn create_initiation<R: RngCore + CryptoRng, O>(
     peer: &Peer<O>,
     pk: &PublicKey,
     local: u32,
+    ts: &timestamp::TAI64N,
     msg: &mut NoiseInitiation,
 ) -> Result<(), HandshakeError> {
     log::debug!("create initiation");
@@ -297,7 +291,7 @@ pub(super) fn create_initiation<R: RngCore + CryptoRng, O>(
         SEAL!(
             &key,
             &hs,                  // ad
-            &timestamp::now(),    // pt
+            ts,                   // pt
             &mut msg.f_timestamp  // ct || tag
         );
 
@@ -358,9 +352,29 @@ pub(super) fn consume_initiation<'a, O>(
 
         let peer = device.lookup_pk(&PublicKey::from(pk))?;
 
+        // check that the device accepts initiations from the peer
+
+        if !peer.role.accepts() {
+            return Err(HandshakeError::RoleRefused);
+        }
+
+        // check that the peer may use the (secondary) key
+
+        if !keyst.permits(&PublicKey::from(pk)) {
+            return Err(HandshakeError::UnknownPublicKey);
+        }
+
+        // the precomputed shared secret is for the primary key of the device
+
+        let ss = if keyst.secondary {
+            *shared_secret(&keyst.sk, &PublicKey::from(pk))?.as_bytes()
+        } else {
+            peer.ss
+        };
+
         // check for zero shared-secret (see "shared_secret" note).
 
-        if peer.ss.ct_eq(&[0u8; 32]).into() {
+        if ss.ct_eq(&[0u8; 32]).into() {
             return Err(HandshakeError::InvalidSharedSecret);
         }
 
@@ -374,7 +388,7 @@ pub(super) fn consume_initiation<'a, O>(
 
         // (C, k) := Kdf2(C, DH(S_priv, S_pub))
 
-        let (ck, key) = KDF2!(&ck, &peer.ss);
+        let (ck, key) = KDF2!(&ck, &ss);
 
         // msg.timestamp := Aead(k, 0, Timestamp(), H)
 
@@ -405,6 +419,34 @@ pub(super) fn consume_initiation<'a, O>(
     })
 }
 
+/* Decrypts the static public key of the initiator,
+ * without altering the state of any peer (used for diagnostics).
+ */
+pub(super) fn peek_initiation(
+    keyst: &KeyState,
+    msg: &NoiseInitiation,
+) -> Result<PublicKey, HandshakeError> {
+    clear_stack_on_return(CLEAR_PAGES, || {
+        let ck = INITIAL_CK;
+        let hs = INITIAL_HS;
+        let hs = HASH!(&hs, keyst.pk.as_bytes());
+        let ck = KDF1!(&ck, &msg.f_ephemeral);
+        let hs = HASH!(&hs, &msg.f_ephemeral);
+
+        let eph_r_pk = PublicKey::from(msg.f_ephemeral);
+        let (_, key) = KDF2!(&ck, shared_secret(&keyst.sk, &eph_r_pk)?.as_bytes());
+
+        let mut pk = [0u8; 32];
+        OPEN!(
+            &key,
+            &hs,           // ad
+            &mut pk,       // pt
+            &msg.f_static  // ct || tag
+        )?;
+        Ok(PublicKey::from(pk))
+    })
+}
+
 pub(super) fn create_response<R: RngCore + CryptoRng, O>(
     rng: &mut R,
     peer: &Peer<O>,
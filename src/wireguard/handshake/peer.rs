@@ -1,6 +1,8 @@
 use spin::Mutex;
 
 use std::mem;
+use std::ops::Deref;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use generic_array::typenum::U32;
@@ -16,12 +18,26 @@ use super::macs;
 use super::timestamp;
 use super::types::*;
 
+use super::super::types::HandshakeRole;
+
 const TIME_BETWEEN_INITIATIONS: Duration = Duration::from_millis(20);
 
 // Represents the state of a peer.
 //
+// The mutable state is shared by every copy of the peer (in snapshots of the device),
+// while the constant state is copied and replaced by the configuration.
+//
 // This type is only for internal use and not exposed.
 pub(super) struct Peer<O> {
+    shared: Arc<SharedState<O>>,
+
+    // constant state
+    pub ss: [u8; 32],        // precomputed DH(static, static)
+    pub psk: Psk,            // psk of peer
+    pub role: HandshakeRole, // which end of a handshake the device may take
+}
+
+pub(super) struct SharedState<O> {
     // opaque type which identifies a peer
     pub opaque: O,
 
@@ -32,10 +48,25 @@ pub(super) struct Peer<O> {
 
     // state related to DoS mitigation fields
     pub macs: Mutex<macs::Generator>,
+}
 
-    // constant state
-    pub ss: [u8; 32], // precomputed DH(static, static)
-    pub psk: Psk,     // psk of peer
+impl<O> Clone for Peer<O> {
+    fn clone(&self) -> Self {
+        Peer {
+            shared: self.shared.clone(),
+            ss: self.ss,
+            psk: self.psk,
+            role: self.role,
+        }
+    }
+}
+
+impl<O> Deref for Peer<O> {
+    type Target = SharedState<O>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.shared
+    }
 }
 
 pub enum State {
@@ -64,13 +95,16 @@ impl Drop for State {
 impl<O> Peer<O> {
     pub fn new(pk: PublicKey, ss: [u8; 32], opaque: O) -> Self {
         Self {
-            opaque,
-            macs: Mutex::new(macs::Generator::new(pk)),
-            state: Mutex::new(State::Reset),
-            timestamp: Mutex::new(None),
-            last_initiation_consumption: Mutex::new(None),
+            shared: Arc::new(SharedState {
+                opaque,
+                macs: Mutex::new(macs::Generator::new(pk)),
+                state: Mutex::new(State::Reset),
+                timestamp: Mutex::new(None),
+                last_initiation_consumption: Mutex::new(None),
+            }),
             ss,
             psk: [0u8; 32],
+            role: HandshakeRole::Both,
         }
     }
 
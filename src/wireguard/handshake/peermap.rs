@@ -0,0 +1,149 @@
+/* Map from public keys to peers, shared by the copies of the device (see wireguard::Snapshot):
+ *
+ * The peers are split into shards (by public key),
+ * a copy of the map shares every shard and a modification copies only the shard modified,
+ * hence configuring a peer copies O(peers / SHARDS) entries rather than every peer.
+ */
+use std::collections::hash_map;
+use std::collections::HashMap;
+use std::iter;
+use std::slice;
+use std::sync::Arc;
+
+use super::peer::Peer;
+
+const SHARDS: usize = 64;
+
+type Shard<O> = Arc<HashMap<[u8; 32], Peer<O>>>;
+
+pub(super) type Iter<'a, O> = iter::FlatMap<
+    slice::Iter<'a, Shard<O>>,
+    hash_map::Iter<'a, [u8; 32], Peer<O>>,
+    fn(&'a Shard<O>) -> hash_map::Iter<'a, [u8; 32], Peer<O>>,
+>;
+
+fn shard_iter<O>(shard: &Shard<O>) -> hash_map::Iter<[u8; 32], Peer<O>> {
+    shard.iter()
+}
+
+pub(super) struct PeerMap<O> {
+    shards: Vec<Shard<O>>,
+}
+
+impl<O> Clone for PeerMap<O> {
+    fn clone(&self) -> Self {
+        PeerMap {
+            shards: self.shards.clone(),
+        }
+    }
+}
+
+impl<O> PeerMap<O> {
+    pub fn new() -> Self {
+        PeerMap {
+            shards: (0..SHARDS).map(|_| Arc::new(HashMap::new())).collect(),
+        }
+    }
+
+    fn index(pk: &[u8; 32]) -> usize {
+        pk[0] as usize % SHARDS
+    }
+
+    // the shard of the public key, copied if shared with another copy of the map
+    fn shard_mut(&mut self, pk: &[u8; 32]) -> &mut HashMap<[u8; 32], Peer<O>> {
+        Arc::make_mut(&mut self.shards[Self::index(pk)])
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.len()).sum()
+    }
+
+    pub fn clear(&mut self) {
+        for shard in self.shards.iter_mut() {
+            *shard = Arc::new(HashMap::new());
+        }
+    }
+
+    pub fn get(&self, pk: &[u8; 32]) -> Option<&Peer<O>> {
+        self.shards[Self::index(pk)].get(pk)
+    }
+
+    pub fn contains_key(&self, pk: &[u8; 32]) -> bool {
+        self.shards[Self::index(pk)].contains_key(pk)
+    }
+
+    pub fn get_mut(&mut self, pk: &[u8; 32]) -> Option<&mut Peer<O>> {
+        if !self.contains_key(pk) {
+            return None;
+        }
+        self.shard_mut(pk).get_mut(pk)
+    }
+
+    pub fn insert(&mut self, pk: [u8; 32], peer: Peer<O>) -> Option<Peer<O>> {
+        self.shard_mut(&pk).insert(pk, peer)
+    }
+
+    pub fn remove(&mut self, pk: &[u8; 32]) -> Option<Peer<O>> {
+        if !self.contains_key(pk) {
+            return None;
+        }
+        self.shard_mut(pk).remove(pk)
+    }
+
+    pub fn iter<'a>(&'a self) -> Iter<'a, O> {
+        let f: fn(&'a Shard<O>) -> hash_map::Iter<'a, [u8; 32], Peer<O>> = shard_iter;
+        self.shards.iter().flat_map(f)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &Peer<O>> {
+        self.iter().map(|(_, peer)| peer)
+    }
+
+    // copies every shard shared with another copy of the map
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&[u8; 32], &mut Peer<O>)> {
+        self.shards
+            .iter_mut()
+            .flat_map(|shard| Arc::make_mut(shard).iter_mut())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use x25519_dalek::PublicKey;
+
+    fn peer(pk: [u8; 32]) -> Peer<u32> {
+        Peer::new(PublicKey::from(pk), [0u8; 32], pk[0] as u32)
+    }
+
+    #[test]
+    fn test_peermap_copy_on_write() {
+        let mut map = PeerMap::new();
+        for i in 0..=255u8 {
+            map.insert([i; 32], peer([i; 32]));
+        }
+        assert_eq!(map.len(), 256);
+        assert_eq!(map.iter().count(), 256);
+
+        // a copy shares the shards not modified since
+        let mut copy = map.clone();
+        copy.remove(&[1u8; 32]);
+        copy.get_mut(&[2u8; 32]).unwrap().psk = [1u8; 32];
+        let shared = map
+            .shards
+            .iter()
+            .zip(copy.shards.iter())
+            .filter(|(a, b)| Arc::ptr_eq(a, b))
+            .count();
+        assert_eq!(shared, SHARDS - 2);
+
+        // the original is unchanged
+        assert_eq!(map.len(), 256);
+        assert_eq!(copy.len(), 255);
+        assert!(map.contains_key(&[1u8; 32]));
+        assert!(!copy.contains_key(&[1u8; 32]));
+        assert_eq!(map.get(&[2u8; 32]).unwrap().psk, [0u8; 32]);
+        assert_eq!(copy.get(&[2u8; 32]).unwrap().psk, [1u8; 32]);
+    }
+}
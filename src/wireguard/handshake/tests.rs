@@ -1,6 +1,7 @@
 use super::*;
 
 use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 
@@ -14,6 +15,8 @@ use x25519_dalek::StaticSecret;
 
 use super::messages::{Initiation, Response};
 
+use super::super::types::HandshakeRole;
+
 fn setup_devices<R: RngCore + CryptoRng, O: Default>(
     rng: &mut R,
 ) -> (PublicKey, Device<O>, PublicKey, Device<O>) {
@@ -197,3 +200,387 @@ fn handshake_no_load() {
     dev1.remove(&pk2).unwrap();
     dev2.remove(&pk1).unwrap();
 }
+
+// a wall-clock which is stepped by the test
+struct SteppedClock(Mutex<Duration>);
+
+impl Clock for SteppedClock {
+    fn now(&self) -> Duration {
+        *self.0.lock().unwrap()
+    }
+}
+
+/* Test handshakes across steps of the wall-clock of the initiator (e.g. NTP steps):
+ * initiations after a backward step are not rejected as replays,
+ * while replayed initiations still are.
+ */
+#[test]
+fn handshake_clock_step() {
+    let (_pk1, mut dev1, pk2, dev2): (_, Device<usize>, _, _) = setup_devices(&mut OsRng);
+    let clock = Arc::new(SteppedClock(Mutex::new(Duration::from_secs(1_600_000_000))));
+    dev1.set_clock(clock.clone());
+
+    let handshake = |step: i64| {
+        {
+            let mut now = clock.0.lock().unwrap();
+            *now = if step < 0 {
+                *now - Duration::from_secs(-step as u64)
+            } else {
+                *now + Duration::from_secs(step as u64)
+            };
+        }
+        let msg = dev1.begin(&mut OsRng, &pk2).unwrap();
+        let res = dev2
+            .process(&mut OsRng, &msg, None)
+            .map(|(_, _, kp)| kp.unwrap());
+        if let Ok(kp) = res.as_ref() {
+            dev2.release(kp.local_id());
+        }
+        wait(); // avoid initiation flood detection
+        (msg, res.is_ok())
+    };
+
+    // in order
+    let (msg, ok) = handshake(0);
+    assert!(ok);
+
+    // backward step (by an hour), repeatedly
+    assert!(handshake(-3600).1);
+    assert!(handshake(-1).1);
+
+    // forward step (e.g. correcting the backward step)
+    assert!(handshake(7200).1);
+
+    // replay of the first initiation
+    assert!(dev2.process(&mut OsRng, &msg, None).is_err());
+}
+
+/* Test rotation of the responder key:
+ * initiations addressed to a secondary key are accepted,
+ * but only from peers permitted to use the key.
+ */
+#[test]
+fn handshake_secondary_key() {
+    let (pk1, dev1, pk2, mut dev2): (_, Device<usize>, _, _) = setup_devices(&mut OsRng);
+
+    // device-2 : rotate key, keep the old key as secondary
+
+    let sk2 = StaticSecret::from(dev2.get_sk().unwrap().to_bytes());
+    dev2.set_sk(Some(StaticSecret::new(&mut OsRng)));
+    assert_eq!(dev2.add_secondary_sk(sk2, Some(&[pk1])), pk2);
+
+    // device-1 : initiation addressed to the old key is accepted
+
+    let msg1 = dev1.begin(&mut OsRng, &pk2).unwrap();
+    let (_, msg2, ks_r) = dev2
+        .process(&mut OsRng, &msg1, None)
+        .expect("failed to process initiation for secondary key");
+
+    let (_, _, ks_i) = dev1
+        .process(&mut OsRng, &msg2.unwrap(), None)
+        .expect("failed to process response");
+
+    let ks_r = ks_r.unwrap();
+    let ks_i = ks_i.unwrap();
+    assert_eq!(ks_i.send, ks_r.recv, "KeyI.send != KeyR.recv");
+    assert_eq!(ks_i.recv, ks_r.send, "KeyI.recv != KeyR.send");
+
+    dev1.release(ks_i.local_id());
+    dev2.release(ks_r.local_id());
+
+    // avoid initiation flood detection
+    wait();
+
+    // device-2 : restrict the secondary key to another peer
+
+    let other = PublicKey::from(&StaticSecret::new(&mut OsRng));
+    let sk2 = StaticSecret::from(dev2.get_secondary_sks()[0].0.to_bytes());
+    assert_eq!(dev2.add_secondary_sk(sk2, Some(&[other])), pk2);
+    let secondary = dev2.get_secondary_sks();
+    assert_eq!(secondary.len(), 1);
+    let peers = secondary[0].1.as_ref().unwrap();
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peers[0].as_bytes(), other.as_bytes());
+
+    let msg1 = dev1.begin(&mut OsRng, &pk2).unwrap();
+    assert!(dev2.process(&mut OsRng, &msg1, None).is_err());
+
+    // device-2 : remove the secondary key
+
+    assert!(dev2.remove_secondary_sk(&pk2));
+    assert!(dev2.get_secondary_sks().is_empty());
+
+    let msg1 = dev1.begin(&mut OsRng, &pk2).unwrap();
+    assert!(dev2.process(&mut OsRng, &msg1, None).is_err());
+}
+
+#[test]
+fn handshake_inspect() {
+    let (pk1, dev1, pk2, dev2): (_, Device<usize>, _, _) = setup_devices(&mut OsRng);
+
+    // initiation identifies the initiator without consuming the initiation
+    let mut msg1 = dev1.begin(&mut OsRng, &pk2).unwrap();
+    let (peer, res) = dev2.inspect(&msg1);
+    assert_eq!(peer.unwrap().as_bytes(), pk1.as_bytes());
+    assert!(res.is_ok());
+
+    // response identifies the initiator by receiver id
+    let (_, msg2, ks_r) = dev2.process(&mut OsRng, &msg1, None).unwrap();
+    let (peer, res) = dev1.inspect(&msg2.unwrap());
+    assert_eq!(peer.unwrap().as_bytes(), pk2.as_bytes());
+    assert!(res.is_ok());
+    dev2.release(ks_r.unwrap().local_id());
+
+    // tampered initiation fails the mac1 check
+    msg1[10] ^= 1;
+    match dev2.inspect(&msg1) {
+        (None, Err(HandshakeError::InvalidMac1)) => (),
+        _ => panic!("expected invalid mac1"),
+    }
+
+    // truncated message
+    assert!(dev2.inspect(&msg1[..100]).1.is_err());
+
+    // cookie reply is verified with the mac1 of the initiation it answers
+    let src1: SocketAddr = "172.16.0.1:8080".parse().unwrap();
+    let msg1 = dev1.begin(&mut OsRng, &pk2).unwrap();
+    let mut msg_cookie = match dev2.process(&mut OsRng, &msg1, Some(src1)).unwrap() {
+        (None, Some(msg), None) => msg,
+        _ => panic!("expected cookie reply"),
+    };
+    let (peer, res) = dev1.inspect(&msg_cookie);
+    assert_eq!(peer.unwrap().as_bytes(), pk2.as_bytes());
+    assert!(res.is_ok());
+
+    // tampered cookie reply fails to decrypt
+    let last = msg_cookie.len() - 1;
+    msg_cookie[last] ^= 1;
+    match dev1.inspect(&msg_cookie) {
+        (Some(_), Err(HandshakeError::DecryptionFailure)) => (),
+        _ => panic!("expected decryption failure"),
+    }
+}
+
+#[test]
+fn handshake_gc() {
+    let (_pk1, mut dev1, pk2, dev2): (_, Device<usize>, _, _) = setup_devices(&mut OsRng);
+
+    // pending initiation holds an id
+    let msg_init = dev1.begin(&mut OsRng, &pk2).unwrap();
+    assert_eq!(dev1.allocated_ids(), 1);
+    assert_eq!(dev1.peer_ids(&pk2).len(), 1);
+    assert_eq!(
+        dev1.gc(|_| false),
+        0,
+        "pending initiation must not be reclaimed"
+    );
+
+    // complete the handshake: the id is moved to the key-pair
+    let msg_response = match dev2.process(&mut OsRng, &msg_init, None).unwrap() {
+        (Some(_), Some(msg), Some(_)) => msg,
+        _ => panic!("unexpected response"),
+    };
+    let kp = match dev1.process(&mut OsRng, &msg_response, None).unwrap() {
+        (Some(_), None, Some(kp)) => kp,
+        _ => panic!("unexpected response"),
+    };
+    assert_eq!(dev1.peer_ids(&pk2), vec![kp.local_id()]);
+
+    // ids held by a key-pair are retained
+    assert_eq!(dev1.gc(|id| id == kp.local_id()), 0);
+    assert_eq!(dev1.allocated_ids(), 1);
+
+    // ids no longer held are reclaimed
+    assert_eq!(dev1.gc(|_| false), 1);
+    assert_eq!(dev1.allocated_ids(), 0);
+    assert!(dev1.peer_ids(&pk2).is_empty());
+}
+
+/* Test that the cookie of a cookie-demanding responder is used for mac2
+ * on the retried initiation (and only accepted from the source it was issued to).
+ */
+#[test]
+fn handshake_cookie_retry() {
+    let (_pk1, dev1, pk2, dev2): (_, Device<usize>, _, _) = setup_devices(&mut OsRng);
+
+    let src1: SocketAddr = "172.16.0.1:8080".parse().unwrap();
+    let src3: SocketAddr = "172.16.0.3:8080".parse().unwrap();
+
+    // the first initiation carries no mac2 and is answered by a cookie reply
+    let msg_init = dev1.begin(&mut OsRng, &pk2).unwrap();
+    match parse_message(&msg_init).unwrap() {
+        HandshakeMessage::Initiation { mac2, .. } => assert!(!mac2),
+        _ => panic!("expected initiation"),
+    }
+    let msg_cookie = match dev2.process(&mut OsRng, &msg_init, Some(src1)).unwrap() {
+        (None, Some(msg), None) => msg,
+        _ => panic!("unexpected response"),
+    };
+    match dev1.process(&mut OsRng, &msg_cookie, None).unwrap() {
+        (None, None, None) => (),
+        _ => panic!("unexpected response"),
+    }
+
+    // the retried initiation carries mac2 (computed with the cookie)
+    let msg_init = dev1.begin(&mut OsRng, &pk2).unwrap();
+    match parse_message(&msg_init).unwrap() {
+        HandshakeMessage::Initiation { mac2, .. } => assert!(mac2),
+        _ => panic!("expected initiation"),
+    }
+
+    // the cookie is bound to the source address
+    match dev2.process(&mut OsRng, &msg_init, Some(src3)).unwrap() {
+        (None, Some(msg), None) => {
+            assert_eq!(msg[0], TYPE_COOKIE_REPLY as u8);
+        }
+        _ => panic!("unexpected response"),
+    }
+
+    // accepted from the source the cookie was issued to
+    match dev2.process(&mut OsRng, &msg_init, Some(src1)).unwrap() {
+        (Some(_), Some(_), Some(_)) => (),
+        _ => panic!("unexpected response"),
+    }
+}
+
+/* Test that a device in stealth never answers initiations from unknown static keys,
+ * not even by a cookie reply under load.
+ */
+#[test]
+fn handshake_stealth() {
+    let (_pk1, dev1, pk2, mut dev2): (_, Device<usize>, _, _) = setup_devices(&mut OsRng);
+    let src: SocketAddr = "172.16.0.1:8080".parse().unwrap();
+
+    // a third device knowing the public key of device-2 (but unknown to device-2)
+    let mut dev3: Device<usize> = Device::new();
+    dev3.set_sk(Some(StaticSecret::new(&mut OsRng)));
+    dev3.add(pk2, 0).unwrap();
+
+    // by default the unknown initiator is answered by a cookie reply under load
+    let msg_init = dev3.begin(&mut OsRng, &pk2).unwrap();
+    match dev2.process(&mut OsRng, &msg_init, Some(src)) {
+        Ok((None, Some(_), None)) => (),
+        _ => panic!("expected cookie reply"),
+    }
+
+    // in stealth the unknown initiator receives no answer
+    dev2.set_stealth(true);
+    match dev2.process(&mut OsRng, &msg_init, Some(src)) {
+        Err(HandshakeError::Stealth) => (),
+        _ => panic!("expected no answer"),
+    }
+    match dev2.process(&mut OsRng, &msg_init, None) {
+        Err(HandshakeError::UnknownPublicKey) => (),
+        _ => panic!("expected no answer"),
+    }
+
+    // while peers are still answered by a cookie reply
+    let msg_init = dev1.begin(&mut OsRng, &pk2).unwrap();
+    match dev2.process(&mut OsRng, &msg_init, Some(src)) {
+        Ok((None, Some(_), None)) => (),
+        _ => panic!("expected cookie reply"),
+    }
+}
+
+/* Responder-only peers are never sent initiations,
+ * initiations from initiator-only peers are rejected.
+ */
+#[test]
+fn handshake_role() {
+    let (pk1, mut dev1, pk2, mut dev2): (_, Device<usize>, _, _) = setup_devices(&mut OsRng);
+
+    // device-1 only responds to device-2
+    dev1.set_role(pk2, HandshakeRole::Responder).unwrap();
+    assert_eq!(dev1.get_role(&pk2).unwrap(), HandshakeRole::Responder);
+    match dev1.begin(&mut OsRng, &pk2) {
+        Err(HandshakeError::RoleRefused) => (),
+        _ => panic!("expected initiation to be refused"),
+    }
+
+    // device-2 only initiates handshakes with device-1
+    dev2.set_role(pk1, HandshakeRole::Initiator).unwrap();
+    let msg_init = dev2.begin(&mut OsRng, &pk1).unwrap();
+    let (_, msg_response, _) = dev1.process(&mut OsRng, &msg_init, None).unwrap();
+    let (_, _, keypair) = dev2
+        .process(&mut OsRng, &msg_response.unwrap(), None)
+        .unwrap();
+    assert!(keypair.is_some());
+
+    // the initiation of device-1 (no longer responder only) is rejected
+    dev1.set_role(pk2, HandshakeRole::Both).unwrap();
+    let msg_init = dev1.begin(&mut OsRng, &pk2).unwrap();
+    match dev2.process(&mut OsRng, &msg_init, None) {
+        Err(HandshakeError::RoleRefused) => (),
+        _ => panic!("expected initiation to be rejected"),
+    }
+}
+
+#[test]
+fn handshake_builder() {
+    let sk1 = StaticSecret::new(&mut OsRng);
+    let pk1 = PublicKey::from(&sk1);
+    let sk2 = StaticSecret::new(&mut OsRng);
+    let pk2 = PublicKey::from(&sk2);
+    let sk2_copy = StaticSecret::from(sk2.to_bytes());
+
+    let initiator_end = MessageBuilder::new(sk1, pk2, [0u8; 32]).unwrap();
+    let responder_end = MessageBuilder::new(sk2, pk1, [0u8; 32]).unwrap();
+    let src: SocketAddr = "172.16.0.1:8080".parse().unwrap();
+
+    // the initiation is answered by a cookie reply when mac2 is demanded
+    let msg_init = initiator_end.initiation(&mut OsRng).unwrap();
+    let sender = match parse_message(&msg_init).unwrap() {
+        HandshakeMessage::Initiation { sender, mac2, .. } => {
+            assert!(!mac2);
+            sender
+        }
+        _ => panic!("expected initiation"),
+    };
+    assert_eq!(
+        peek_initiator(&sk2_copy, &msg_init).unwrap().as_bytes(),
+        pk1.as_bytes()
+    );
+    let msg_cookie = match responder_end.consume(&mut OsRng, &msg_init, Some(src)) {
+        Ok((Some(msg), None)) => msg,
+        _ => panic!("expected cookie reply"),
+    };
+    assert_eq!(
+        parse_message(&msg_cookie).unwrap(),
+        HandshakeMessage::CookieReply { receiver: sender }
+    );
+    assert!(initiator_end
+        .consume(&mut OsRng, &msg_cookie, None)
+        .unwrap()
+        .0
+        .is_none());
+
+    // the retried initiation carries mac2 and is answered by a response
+    let msg_init = initiator_end.initiation(&mut OsRng).unwrap();
+    match parse_message(&msg_init).unwrap() {
+        HandshakeMessage::Initiation { mac2, .. } => assert!(mac2),
+        _ => panic!("expected initiation"),
+    }
+    let (msg_resp, ks_r) = match responder_end.consume(&mut OsRng, &msg_init, Some(src)) {
+        Ok((Some(msg), Some(ks))) => (msg, ks),
+        _ => panic!("expected response"),
+    };
+    match parse_message(&msg_resp).unwrap() {
+        HandshakeMessage::Response { receiver, .. } => {
+            assert_eq!(receiver, ks_r.send.id);
+        }
+        _ => panic!("expected response"),
+    }
+
+    // the response completes the handshake
+    let ks_i = match initiator_end.consume(&mut OsRng, &msg_resp, None) {
+        Ok((None, Some(ks))) => ks,
+        _ => panic!("expected key-pair"),
+    };
+    assert_eq!(ks_i.send, ks_r.recv);
+    assert_eq!(ks_i.recv, ks_r.send);
+
+    // malformed messages are rejected
+    assert!(parse_message(&msg_resp[..10]).is_err());
+    initiator_end.reset();
+    responder_end.reset();
+}
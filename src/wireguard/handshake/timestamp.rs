@@ -1,4 +1,8 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 pub type TAI64N = [u8; 12];
 
@@ -6,11 +10,89 @@ const TAI64_EPOCH: u64 = 0x400000000000000a;
 
 pub const ZERO: TAI64N = [0u8; 12];
 
-pub fn now() -> TAI64N {
-    // get system time as duration
-    let sysnow = SystemTime::now();
-    let delta = sysnow.duration_since(UNIX_EPOCH).unwrap();
+// interval between writes of the persisted clock
+// (also the margin added when the clock is restored, covering the time since the last write)
+const PERSIST_INTERVAL: Duration = Duration::from_secs(60);
 
+/// A source of the wall-clock time of handshake initiations
+///
+/// Responders reject initiations with a timestamp not greater than the last accepted one,
+/// hence the time must never go backwards (not even across restarts).
+pub trait Clock: Send + Sync {
+    /// Returns the time since the UNIX epoch
+    fn now(&self) -> Duration;
+}
+
+/// The system clock (default)
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+}
+
+/// A clock for systems without a trustworthy real-time clock (e.g. embedded boards at boot):
+/// the monotonic time since the clock was opened, offset by the greater of
+/// the system time and the time persisted to a file (plus a margin).
+/// The time is persisted periodically, hence it never goes backwards across restarts,
+/// even if the system clock regresses.
+pub struct PersistedClock {
+    path: PathBuf,
+    start: Instant,
+    offset: Duration,
+    saved: Mutex<Duration>,
+}
+
+impl PersistedClock {
+    /// Opens the clock persisted to the file (created if it does not exist)
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<PersistedClock> {
+        let path = path.into();
+        let persisted = match fs::read_to_string(&path) {
+            Ok(s) => Duration::from_secs(
+                s.trim()
+                    .parse()
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid clock"))?,
+            ),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Duration::from_secs(0),
+            Err(e) => return Err(e),
+        };
+        let offset = SystemClock.now().max(persisted + PERSIST_INTERVAL);
+        let clock = PersistedClock {
+            path,
+            start: Instant::now(),
+            offset,
+            saved: Mutex::new(offset),
+        };
+        clock.save(offset)?;
+        Ok(clock)
+    }
+
+    fn save(&self, now: Duration) -> io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, format!("{}\n", now.as_secs()))?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+impl Clock for PersistedClock {
+    fn now(&self) -> Duration {
+        let now = self.offset + self.start.elapsed();
+        let mut saved = self.saved.lock().unwrap();
+        if now >= *saved + PERSIST_INTERVAL {
+            if let Err(e) = self.save(now) {
+                log::warn!("Failed to persist the clock to {:?}: {}", self.path, e);
+            }
+            *saved = now; // do not retry on every call
+        }
+        now
+    }
+}
+
+/// Converts a time since the UNIX epoch to a TAI64N timestamp
+pub fn tai64n(delta: Duration) -> TAI64N {
     // convert to tai64n
     let tai64_secs = delta.as_secs() + TAI64_EPOCH;
     let tai64_nano = delta.subsec_nanos();
@@ -22,11 +104,38 @@ pub fn now() -> TAI64N {
     res
 }
 
+// big-endian serialization: the lexicographic order is the order of time
 pub fn compare(old: &TAI64N, new: &TAI64N) -> bool {
-    for i in 0..12 {
-        if new[i] > old[i] {
-            return true;
-        }
+    new > old
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn test_persisted_clock() {
+        let path = env::temp_dir().join(format!("wg-clock-{}", process::id()));
+        let _ = fs::remove_file(&path);
+
+        // a fresh clock follows the system clock
+        let first = PersistedClock::open(&path).unwrap().now();
+        assert!(first >= SystemClock.now() - Duration::from_secs(1));
+
+        // a persisted time ahead of the system clock (e.g. regressed at boot) is never undercut
+        let ahead = Duration::from_secs(SystemClock.now().as_secs() + 3600);
+        fs::write(&path, format!("{}\n", ahead.as_secs())).unwrap();
+        let clock = PersistedClock::open(&path).unwrap();
+        let t1 = clock.now();
+        assert!(t1 >= ahead + PERSIST_INTERVAL);
+        assert!(clock.now() >= t1);
+        assert!(compare(&tai64n(ahead), &tai64n(t1)));
+
+        // restored past the last persisted time
+        assert!(PersistedClock::open(&path).unwrap().now() > t1);
+        let _ = fs::remove_file(&path);
     }
-    return false;
 }
@@ -7,24 +7,26 @@ use std::fmt;
 
 // config error
 
-#[derive(Debug)]
-pub struct ConfigError(String);
-
-impl ConfigError {
-    pub fn new(s: &str) -> Self {
-        ConfigError(s.to_string())
-    }
+#[derive(Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    TooManyPeers, // the device holds the maximum number of peers
+    KeyOfDevice,  // the public key of the peer matches the device
+    UnknownPeer,  // no peer with the public key
 }
 
 impl fmt::Display for ConfigError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "ConfigError({})", self.0)
+        match self {
+            ConfigError::TooManyPeers => write!(f, "Too many peers for device"),
+            ConfigError::KeyOfDevice => write!(f, "Public key of peer matches the device"),
+            ConfigError::UnknownPeer => write!(f, "No such public key"),
+        }
     }
 }
 
 impl Error for ConfigError {
     fn description(&self) -> &str {
-        &self.0
+        "Handshake Configuration Error"
     }
 
     fn source(&self) -> Option<&(dyn Error + 'static)> {
@@ -46,6 +48,8 @@ pub enum HandshakeError {
     InvalidMac1,
     RateLimited,
     InitiationFlood,
+    RoleRefused,
+    Stealth,
 }
 
 impl fmt::Display for HandshakeError {
@@ -65,6 +69,8 @@ impl fmt::Display for HandshakeError {
             HandshakeError::InitiationFlood => {
                 write!(f, "Message was dropped because of initiation flood")
             }
+            HandshakeError::RoleRefused => write!(f, "Handshake refused by the role of the peer"),
+            HandshakeError::Stealth => write!(f, "Message not answered in stealth"),
         }
     }
 }
@@ -0,0 +1,143 @@
+/* Liveness of the worker threads, the handshake queue and the timer wheel,
+ * e.g. for liveness probes of containerized deployments (see WireGuard::health).
+ */
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use spin::Mutex;
+
+// Semantics:
+// Interval at which the health timer ticks (proving the timer wheel is running).
+pub const HEALTH_TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Semantics:
+// The timer wheel is considered stopped when the health timer has not ticked for this duration.
+pub const HEALTH_TICK_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Semantics:
+// The handshake queue is considered stalled when jobs are pending,
+// but no job was taken from the queue for this duration.
+pub const HEALTH_STALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// State updated by the workers and the health timer
+pub struct Health {
+    pub handshake_expected: usize, // handshake workers started with the device
+    pub handshake_workers: AtomicUsize, // handshake workers running
+    pub udp_readers: AtomicUsize,  // UDP readers running
+    pub tun_readers: AtomicUsize,  // TUN readers running
+    pub last_dequeue: Mutex<Instant>, // a handshake job was last taken from the queue
+    pub last_tick: Mutex<Instant>, // the health timer last ticked
+}
+
+impl Health {
+    pub fn new(handshake_expected: usize) -> Health {
+        Health {
+            handshake_expected,
+            handshake_workers: AtomicUsize::new(0),
+            udp_readers: AtomicUsize::new(0),
+            tun_readers: AtomicUsize::new(0),
+            last_dequeue: Mutex::new(Instant::now()),
+            last_tick: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+/// Counts a running worker, until dropped
+/// (also when the worker panics)
+pub struct Alive<'a>(&'a AtomicUsize);
+
+impl<'a> Alive<'a> {
+    pub fn new(counter: &'a AtomicUsize) -> Alive<'a> {
+        counter.fetch_add(1, Ordering::SeqCst);
+        Alive(counter)
+    }
+}
+
+impl Drop for Alive<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Health of the device (see WireGuard::health)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HealthReport {
+    pub up: bool,                  // the device is up
+    pub handshake_workers: usize,  // handshake workers running
+    pub handshake_expected: usize, // handshake workers started
+    pub udp_readers: usize,        // UDP readers running
+    pub tun_readers: usize,        // TUN readers running
+    pub pending_handshakes: usize, // handshake jobs in the queue
+    pub queue_stalled: bool,       // jobs pending, but none taken for HEALTH_STALL_TIMEOUT
+    pub last_timer_tick: Duration, // time since the timer wheel last fired the health timer
+}
+
+impl HealthReport {
+    /// The report is healthy if every handshake worker is running,
+    /// the handshake queue drains, the timer wheel runs
+    /// and (while the device is up) the sockets and the TUN device are read.
+    pub fn is_healthy(&self) -> bool {
+        self.handshake_workers == self.handshake_expected
+            && !self.queue_stalled
+            && self.last_timer_tick < HEALTH_TICK_TIMEOUT
+            && (!self.up || (self.udp_readers > 0 && self.tun_readers > 0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alive() {
+        let counter = AtomicUsize::new(0);
+        {
+            let _a = Alive::new(&counter);
+            let _b = Alive::new(&counter);
+            assert_eq!(counter.load(Ordering::SeqCst), 2);
+        }
+        assert_eq!(counter.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_is_healthy() {
+        let report = HealthReport {
+            up: false,
+            handshake_workers: 4,
+            handshake_expected: 4,
+            udp_readers: 0,
+            tun_readers: 0,
+            pending_handshakes: 0,
+            queue_stalled: false,
+            last_timer_tick: Duration::from_millis(500),
+        };
+        assert!(report.is_healthy());
+
+        // an up device must read from the sockets and the TUN device
+        assert!(!HealthReport { up: true, ..report }.is_healthy());
+        assert!(HealthReport {
+            up: true,
+            udp_readers: 2,
+            tun_readers: 1,
+            ..report
+        }
+        .is_healthy());
+
+        // a worker died, the queue stalled or the timers stopped
+        assert!(!HealthReport {
+            handshake_workers: 3,
+            ..report
+        }
+        .is_healthy());
+        assert!(!HealthReport {
+            queue_stalled: true,
+            ..report
+        }
+        .is_healthy());
+        assert!(!HealthReport {
+            last_timer_tick: HEALTH_TICK_TIMEOUT,
+            ..report
+        }
+        .is_healthy());
+    }
+}
@@ -0,0 +1,105 @@
+use super::handshake::{parse_message, HandshakeError, HandshakeMessage};
+use super::handshake::{TYPE_COOKIE_REPLY, TYPE_INITIATION, TYPE_RESPONSE};
+use super::router::{RouterError, TYPE_TRANSPORT};
+use super::tun::Tun;
+use super::udp::UDP;
+use super::wireguard::WireGuard;
+
+use std::fmt;
+
+use byteorder::{ByteOrder, LittleEndian};
+use x25519_dalek::PublicKey;
+
+/// The outcome of inspecting a captured (outer) WireGuard message
+#[derive(Debug)]
+pub enum Verdict {
+    Accepted,
+    UnknownMessageType,
+    Handshake(HandshakeError), // handshake message would be rejected
+    Transport(RouterError),    // transport message would be rejected
+}
+
+/// Describes which peer / session a captured message belongs to
+/// and why it would be accepted or rejected.
+#[derive(Debug)]
+pub struct Inspection {
+    pub msg_type: Option<u32>,   // type field of the message
+    pub sender: Option<u32>,     // sender index (for initiations and responses)
+    pub receiver: Option<u32>,   // receiver index (for all but initiations)
+    pub peer: Option<PublicKey>, // peer associated with the message (if known)
+    pub verdict: Verdict,
+}
+
+impl fmt::Display for Inspection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "type = {:?}", self.msg_type)?;
+        if let Some(sender) = self.sender {
+            write!(f, ", sender = {}", sender)?;
+        }
+        write!(f, ", receiver = {:?}", self.receiver)?;
+        if let Some(pk) = self.peer.as_ref() {
+            write!(f, ", peer = {}", hex::encode(pk.as_bytes()))?;
+        }
+        match &self.verdict {
+            Verdict::Accepted => write!(f, ", accepted"),
+            Verdict::UnknownMessageType => write!(f, ", rejected: unknown message type"),
+            Verdict::Handshake(e) => write!(f, ", rejected: {}", e),
+            Verdict::Transport(e) => write!(f, ", rejected: {}", e),
+        }
+    }
+}
+
+impl<T: Tun, B: UDP> WireGuard<T, B> {
+    /// Determine which peer / session a raw captured message (UDP payload) belongs to,
+    /// and whether it would be accepted, without side effects on the device.
+    ///
+    /// Intended for field debugging (e.g. "packets arrive but nothing decrypts").
+    pub fn inspect(&self, msg: &[u8]) -> Inspection {
+        let msg_type = if msg.len() >= 4 {
+            Some(LittleEndian::read_u32(msg))
+        } else {
+            None
+        };
+
+        // the indices of handshake messages (cleartext fields, see parse_message)
+        let (sender, receiver) = match parse_message(msg) {
+            Ok(HandshakeMessage::Initiation { sender, .. }) => (Some(sender), None),
+            Ok(HandshakeMessage::Response {
+                sender, receiver, ..
+            }) => (Some(sender), Some(receiver)),
+            Ok(HandshakeMessage::CookieReply { receiver }) => (None, Some(receiver)),
+            Err(_) => match msg_type {
+                Some(TYPE_TRANSPORT) if msg.len() >= 8 => {
+                    (None, Some(LittleEndian::read_u32(&msg[4..])))
+                }
+                _ => (None, None),
+            },
+        };
+
+        let (peer, verdict) = match msg_type {
+            Some(TYPE_INITIATION) | Some(TYPE_RESPONSE) | Some(TYPE_COOKIE_REPLY) => {
+                let (peer, res) = self.peers.read().inspect(msg);
+                match res {
+                    Ok(()) => (peer, Verdict::Accepted),
+                    Err(e) => (peer, Verdict::Handshake(e)),
+                }
+            }
+            Some(TYPE_TRANSPORT) => {
+                let (peer, res) = self.router.inspect(msg, |peer| peer.pk);
+                match res {
+                    Ok(()) => (peer, Verdict::Accepted),
+                    Err(e) => (peer, Verdict::Transport(e)),
+                }
+            }
+            _ => (None, Verdict::UnknownMessageType),
+        };
+
+        Inspection {
+            msg_type,
+            sender,
+            receiver,
+            peer,
+            verdict,
+        }
+    }
+}
@@ -0,0 +1,350 @@
+/* Export of the inner flows (see router flow tracking) as IPFIX (RFC 7011) over UDP.
+ *
+ * Every flow is exported as up to two records (one per direction),
+ * carrying the total number of bytes and packets of the flow.
+ * The templates are sent with every message, as required for UDP transport.
+ */
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::Thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use super::{Flow, FlowStats};
+
+// Semantics:
+// Interval between exports of the flow table to the collector
+pub const IPFIX_EXPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+// largest message, chosen to avoid fragmentation on common paths
+const MAX_MESSAGE_SIZE: usize = 1400;
+
+const IPFIX_VERSION: u16 = 10;
+const SET_ID_TEMPLATE: u16 = 2;
+const TEMPLATE_ID_IP4: u16 = 256;
+const TEMPLATE_ID_IP6: u16 = 257;
+
+const SIZE_MESSAGE_HEADER: usize = 16;
+const SIZE_SET_HEADER: usize = 4;
+
+// information elements (id, length) shared by both templates
+const FIELDS_COMMON: [(u16, u16); 7] = [
+    (4, 1),   // protocolIdentifier
+    (7, 2),   // sourceTransportPort
+    (11, 2),  // destinationTransportPort
+    (85, 8),  // octetTotalCount
+    (86, 8),  // packetTotalCount
+    (151, 4), // flowEndSeconds
+    (61, 1),  // flowDirection (0 = ingress, 1 = egress)
+];
+
+const FIELDS_IP4: [(u16, u16); 2] = [
+    (8, 4),  // sourceIPv4Address
+    (12, 4), // destinationIPv4Address
+];
+
+const FIELDS_IP6: [(u16, u16); 2] = [
+    (27, 16), // sourceIPv6Address
+    (28, 16), // destinationIPv6Address
+];
+
+const SIZE_RECORD_COMMON: usize = 1 + 2 + 2 + 8 + 8 + 4 + 1;
+
+/// A unidirectional flow record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record {
+    pub protocol: u8,
+    pub src: IpAddr,
+    pub src_port: u16,
+    pub dst: IpAddr,
+    pub dst_port: u16,
+    pub bytes: u64,
+    pub packets: u64,
+    pub end: u32, // seconds since the epoch of the last packet
+    pub egress: bool,
+}
+
+impl Record {
+    fn len(&self) -> usize {
+        match self.src {
+            IpAddr::V4(_) => SIZE_RECORD_COMMON + 8,
+            IpAddr::V6(_) => SIZE_RECORD_COMMON + 32,
+        }
+    }
+
+    fn write(&self, buf: &mut Vec<u8>) {
+        match (self.src, self.dst) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                buf.extend(&src.octets());
+                buf.extend(&dst.octets());
+            }
+            (IpAddr::V6(src), IpAddr::V6(dst)) => {
+                buf.extend(&src.octets());
+                buf.extend(&dst.octets());
+            }
+            _ => unreachable!("flow with mixed address families"),
+        }
+        let mut fields = [0u8; SIZE_RECORD_COMMON];
+        fields[0] = self.protocol;
+        BigEndian::write_u16(&mut fields[1..3], self.src_port);
+        BigEndian::write_u16(&mut fields[3..5], self.dst_port);
+        BigEndian::write_u64(&mut fields[5..13], self.bytes);
+        BigEndian::write_u64(&mut fields[13..21], self.packets);
+        BigEndian::write_u32(&mut fields[21..25], self.end);
+        fields[25] = self.egress as u8;
+        buf.extend(&fields);
+    }
+}
+
+/// Returns the records (one per direction with traffic) of a flow
+pub fn records(flow: &Flow, stats: &FlowStats, now: SystemTime) -> Vec<Record> {
+    let end = now
+        .checked_sub(stats.last_seen.elapsed())
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or(0);
+    let mut records = Vec::with_capacity(2);
+    if stats.rx_packets > 0 {
+        records.push(Record {
+            protocol: flow.protocol,
+            src: flow.remote,
+            src_port: flow.remote_port,
+            dst: flow.local,
+            dst_port: flow.local_port,
+            bytes: stats.rx_bytes,
+            packets: stats.rx_packets,
+            end,
+            egress: false,
+        });
+    }
+    if stats.tx_packets > 0 {
+        records.push(Record {
+            protocol: flow.protocol,
+            src: flow.local,
+            src_port: flow.local_port,
+            dst: flow.remote,
+            dst_port: flow.remote_port,
+            bytes: stats.tx_bytes,
+            packets: stats.tx_packets,
+            end,
+            egress: true,
+        });
+    }
+    records
+}
+
+fn write_template(buf: &mut Vec<u8>, id: u16, fields: &[(u16, u16)]) {
+    let mut header = [0u8; 4];
+    BigEndian::write_u16(&mut header[0..2], id);
+    BigEndian::write_u16(
+        &mut header[2..4],
+        (fields.len() + FIELDS_COMMON.len()) as u16,
+    );
+    buf.extend(&header);
+    for (id, len) in fields.iter().chain(FIELDS_COMMON.iter()) {
+        let mut field = [0u8; 4];
+        BigEndian::write_u16(&mut field[0..2], *id);
+        BigEndian::write_u16(&mut field[2..4], *len);
+        buf.extend(&field);
+    }
+}
+
+// appends a set header, returns the offset of the set (to fill in the length)
+fn begin_set(buf: &mut Vec<u8>, id: u16) -> usize {
+    let offset = buf.len();
+    let mut header = [0u8; SIZE_SET_HEADER];
+    BigEndian::write_u16(&mut header[0..2], id);
+    buf.extend(&header);
+    offset
+}
+
+fn end_set(buf: &mut Vec<u8>, offset: usize) {
+    let len = (buf.len() - offset) as u16;
+    BigEndian::write_u16(&mut buf[offset + 2..offset + 4], len);
+}
+
+// appends a data set with as many of the records as fit in the message,
+// returns the number of records written (and removes them from the slice)
+fn write_data(buf: &mut Vec<u8>, id: u16, records: &mut &[&Record]) -> usize {
+    let fits = |buf: &Vec<u8>, extra: usize| buf.len() + extra <= MAX_MESSAGE_SIZE;
+    if records.is_empty() || !fits(buf, SIZE_SET_HEADER + records[0].len()) {
+        return 0;
+    }
+    let set = begin_set(buf, id);
+    let mut count = 0;
+    while let Some(record) = records.first() {
+        if !fits(buf, record.len()) {
+            break;
+        }
+        record.write(buf);
+        *records = &records[1..];
+        count += 1;
+    }
+    end_set(buf, set);
+    count
+}
+
+/// Encodes flow records into IPFIX messages
+pub struct Encoder {
+    domain: u32,   // observation domain (identifies the device)
+    sequence: u32, // number of data records exported
+}
+
+impl Encoder {
+    pub fn new(domain: u32) -> Encoder {
+        Encoder {
+            domain,
+            sequence: 0,
+        }
+    }
+
+    /// Encodes the records into as many messages as required
+    /// (none if there are no records)
+    pub fn encode(&mut self, records: &[Record], now: SystemTime) -> Vec<Vec<u8>> {
+        let export_time = now
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as u32)
+            .unwrap_or(0);
+
+        let (v4, v6): (Vec<&Record>, Vec<&Record>) = records.iter().partition(|r| r.src.is_ipv4());
+
+        let mut messages = vec![];
+        let (mut v4, mut v6) = (&v4[..], &v6[..]);
+        while !v4.is_empty() || !v6.is_empty() {
+            let mut msg = Vec::with_capacity(MAX_MESSAGE_SIZE);
+            msg.extend(&[0u8; SIZE_MESSAGE_HEADER]);
+
+            // templates
+            let set = begin_set(&mut msg, SET_ID_TEMPLATE);
+            write_template(&mut msg, TEMPLATE_ID_IP4, &FIELDS_IP4);
+            write_template(&mut msg, TEMPLATE_ID_IP6, &FIELDS_IP6);
+            end_set(&mut msg, set);
+
+            // data sets, filled up to the size limit
+            let sequence = self.sequence;
+            let count = write_data(&mut msg, TEMPLATE_ID_IP4, &mut v4)
+                + write_data(&mut msg, TEMPLATE_ID_IP6, &mut v6);
+            self.sequence = self.sequence.wrapping_add(count as u32);
+
+            // message header
+            let len = msg.len() as u16;
+            BigEndian::write_u16(&mut msg[0..2], IPFIX_VERSION);
+            BigEndian::write_u16(&mut msg[2..4], len);
+            BigEndian::write_u32(&mut msg[4..8], export_time);
+            BigEndian::write_u32(&mut msg[8..12], sequence);
+            BigEndian::write_u32(&mut msg[12..16], self.domain);
+            messages.push(msg);
+        }
+        messages
+    }
+}
+
+/// Handle of a running exporter: the export thread is stopped when the handle is dropped.
+pub struct Exporter {
+    pub collector: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Thread,
+}
+
+impl Exporter {
+    pub fn new(collector: SocketAddr, stop: Arc<AtomicBool>, thread: Thread) -> Exporter {
+        Exporter {
+            collector,
+            stop,
+            thread,
+        }
+    }
+}
+
+impl Drop for Exporter {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        self.thread.unpark();
+    }
+}
+
+/// Sleeps for the export interval (or until stopped),
+/// returns false if the exporter has been stopped.
+pub fn wait(stop: &AtomicBool) -> bool {
+    let deadline = Instant::now() + IPFIX_EXPORT_INTERVAL;
+    loop {
+        if stop.load(Ordering::SeqCst) {
+            return false;
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return true;
+        }
+        std::thread::park_timeout(deadline - now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(src: &str, dst: &str) -> Record {
+        Record {
+            protocol: 17,
+            src: src.parse().unwrap(),
+            src_port: 5353,
+            dst: dst.parse().unwrap(),
+            dst_port: 53,
+            bytes: 64,
+            packets: 2,
+            end: 1,
+            egress: true,
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut encoder = Encoder::new(7);
+        assert!(encoder.encode(&[], SystemTime::now()).is_empty());
+
+        let records = [record("10.0.0.1", "10.0.0.2"), record("fd00::1", "fd00::2")];
+        let msgs = encoder.encode(&records, SystemTime::now());
+        assert_eq!(msgs.len(), 1);
+        let msg = &msgs[0];
+        assert_eq!(BigEndian::read_u16(&msg[0..2]), IPFIX_VERSION);
+        assert_eq!(BigEndian::read_u16(&msg[2..4]) as usize, msg.len());
+        assert_eq!(BigEndian::read_u32(&msg[8..12]), 0);
+        assert_eq!(BigEndian::read_u32(&msg[12..16]), 7);
+
+        // walk the sets: template set, IPv4 data set, IPv6 data set
+        let mut offset = SIZE_MESSAGE_HEADER;
+        let mut sets = vec![];
+        while offset < msg.len() {
+            let id = BigEndian::read_u16(&msg[offset..offset + 2]);
+            let len = BigEndian::read_u16(&msg[offset + 2..offset + 4]) as usize;
+            sets.push((id, len));
+            offset += len;
+        }
+        assert_eq!(offset, msg.len());
+        assert_eq!(sets[0].0, SET_ID_TEMPLATE);
+        assert_eq!(
+            sets[1],
+            (TEMPLATE_ID_IP4, SIZE_SET_HEADER + records[0].len())
+        );
+        assert_eq!(
+            sets[2],
+            (TEMPLATE_ID_IP6, SIZE_SET_HEADER + records[1].len())
+        );
+
+        // the sequence number counts the exported records
+        let msgs = encoder.encode(&records[..1], SystemTime::now());
+        assert_eq!(BigEndian::read_u32(&msgs[0][8..12]), 2);
+    }
+
+    #[test]
+    fn test_encode_split() {
+        let mut encoder = Encoder::new(0);
+        let records = vec![record("10.0.0.1", "10.0.0.2"); 100];
+        let msgs = encoder.encode(&records, SystemTime::now());
+        assert!(msgs.len() > 1);
+        assert!(msgs.iter().all(|msg| msg.len() <= MAX_MESSAGE_SIZE));
+        assert_eq!(encoder.sequence, 100);
+    }
+}
@@ -0,0 +1,401 @@
+/* Types and encodings of WireGuard keys, as used in the configuration of a device:
+ *
+ * Keys are exchanged as (padded) base64, e.g. by wg and in configuration files,
+ * or as hex over the UAPI. Secret keys are zeroed on drop and never displayed.
+ */
+use std::error::Error;
+use std::fmt;
+
+use clear_on_drop::clear::Clear;
+use hex::FromHex;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use subtle::ConstantTimeEq;
+use x25519_dalek::StaticSecret;
+
+pub const KEY_LENGTH: usize = 32;
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// number of base64 characters used to identify a peer (as shown by wg)
+const PEER_ID_LENGTH: usize = 8;
+
+/// Encodes the bytes as (padded) standard base64, the encoding used by wg for keys
+pub fn to_base64(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity((bytes.len() + 2) / 3 * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+// maps a base64 character to its 6-bit value
+fn base64_value(c: u8) -> Option<u8> {
+    match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    }
+}
+
+/// Decodes a key from (padded) standard base64.
+///
+/// Only the canonical encoding is accepted:
+/// 43 characters followed by a single '=', with the unused low bits of the last character cleared.
+/// Surrounding whitespace (e.g. a trailing newline) is ignored.
+pub fn key_from_base64(value: &str) -> Result<[u8; KEY_LENGTH], KeyError> {
+    let value = value.trim().as_bytes();
+    if value.len() != 44 {
+        return Err(KeyError::InvalidLength);
+    }
+    if value[43] != b'=' {
+        return Err(KeyError::InvalidEncoding);
+    }
+
+    let mut key = [0u8; KEY_LENGTH];
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    let mut pos = 0;
+    for &c in &value[..43] {
+        acc = acc << 6 | base64_value(c).ok_or(KeyError::InvalidEncoding)? as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            key[pos] = (acc >> bits) as u8;
+            pos += 1;
+        }
+    }
+
+    // 43 * 6 = 258 bits: the last 2 bits are padding and must be zero
+    debug_assert_eq!(pos, KEY_LENGTH);
+    if acc & ((1 << bits) - 1) != 0 {
+        return Err(KeyError::InvalidEncoding);
+    }
+    Ok(key)
+}
+
+/// Decodes a key from hex (either case), ignoring surrounding whitespace
+pub fn key_from_hex(value: &str) -> Result<[u8; KEY_LENGTH], KeyError> {
+    let value = value.trim();
+    if value.len() != 2 * KEY_LENGTH {
+        return Err(KeyError::InvalidLength);
+    }
+    <[u8; KEY_LENGTH]>::from_hex(value).map_err(|_| KeyError::InvalidEncoding)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyError {
+    InvalidLength,   // the encoding does not have the length of a key
+    InvalidEncoding, // invalid character or non-canonical encoding
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::InvalidLength => write!(f, "Invalid key length"),
+            KeyError::InvalidEncoding => write!(f, "Invalid key encoding"),
+        }
+    }
+}
+
+impl Error for KeyError {
+    fn description(&self) -> &str {
+        "Key Error"
+    }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+// common methods of all key types
+macro_rules! key_type {
+    ($name:ident) => {
+        impl $name {
+            pub fn from_bytes(bytes: [u8; KEY_LENGTH]) -> $name {
+                $name(bytes)
+            }
+
+            pub fn from_base64(value: &str) -> Result<$name, KeyError> {
+                key_from_base64(value).map($name)
+            }
+
+            pub fn from_hex(value: &str) -> Result<$name, KeyError> {
+                key_from_hex(value).map($name)
+            }
+
+            pub fn as_bytes(&self) -> &[u8; KEY_LENGTH] {
+                &self.0
+            }
+
+            pub fn to_base64(&self) -> String {
+                to_base64(&self.0)
+            }
+
+            pub fn to_hex(&self) -> String {
+                hex::encode(&self.0)
+            }
+        }
+
+        impl ConstantTimeEq for $name {
+            fn ct_eq(&self, other: &Self) -> subtle::Choice {
+                self.0.ct_eq(&other.0)
+            }
+        }
+
+        impl PartialEq for $name {
+            fn eq(&self, other: &Self) -> bool {
+                self.ct_eq(other).into()
+            }
+        }
+
+        impl Eq for $name {}
+    };
+}
+
+// secret keys are zeroed on drop and never displayed
+macro_rules! secret_key_type {
+    ($name:ident) => {
+        key_type!($name);
+
+        impl Drop for $name {
+            fn drop(&mut self) {
+                self.0.clear()
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}(<redacted>)", stringify!($name))
+            }
+        }
+    };
+}
+
+/// The static private key of a device (as generated by `wg genkey`)
+#[derive(Clone)]
+pub struct PrivateKey([u8; KEY_LENGTH]);
+
+/// The public key of a device or peer (as generated by `wg pubkey`)
+#[derive(Clone, Copy)]
+pub struct PublicKey([u8; KEY_LENGTH]);
+
+/// A pre-shared symmetric key (as generated by `wg genpsk`)
+#[derive(Clone)]
+pub struct PresharedKey([u8; KEY_LENGTH]);
+
+secret_key_type!(PrivateKey);
+key_type!(PublicKey);
+secret_key_type!(PresharedKey);
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_base64())
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PublicKey({})", self)
+    }
+}
+
+impl From<&PrivateKey> for StaticSecret {
+    fn from(sk: &PrivateKey) -> StaticSecret {
+        StaticSecret::from(sk.0)
+    }
+}
+
+impl From<PublicKey> for x25519_dalek::PublicKey {
+    fn from(pk: PublicKey) -> x25519_dalek::PublicKey {
+        x25519_dalek::PublicKey::from(pk.0)
+    }
+}
+
+impl From<x25519_dalek::PublicKey> for PublicKey {
+    fn from(pk: x25519_dalek::PublicKey) -> PublicKey {
+        PublicKey(*pk.as_bytes())
+    }
+}
+
+/// Clamps a Curve25519 private key:
+/// clears the 3 low bits and the high bit, then sets the second highest bit.
+pub fn clamp(mut bytes: [u8; KEY_LENGTH]) -> [u8; KEY_LENGTH] {
+    bytes[0] &= 248;
+    bytes[31] &= 127;
+    bytes[31] |= 64;
+    bytes
+}
+
+/// Generates a new (clamped) private key from the OS random number generator,
+/// equivalent to `wg genkey`
+pub fn generate() -> PrivateKey {
+    let mut bytes = [0u8; KEY_LENGTH];
+    OsRng.fill_bytes(&mut bytes);
+    let sk = PrivateKey(clamp(bytes));
+    bytes.clear();
+    sk
+}
+
+/// Generates a new pre-shared key, equivalent to `wg genpsk`
+pub fn generate_preshared() -> PresharedKey {
+    let mut psk = PresharedKey([0u8; KEY_LENGTH]);
+    OsRng.fill_bytes(&mut psk.0);
+    psk
+}
+
+/// Derives the public key of a private key, equivalent to `wg pubkey`
+/// (the private key is clamped before use, hence unclamped keys yield the same public key)
+pub fn public_from_private(sk: &PrivateKey) -> PublicKey {
+    PublicKey::from(x25519_dalek::PublicKey::from(&StaticSecret::from(sk)))
+}
+
+impl PrivateKey {
+    pub fn public_key(&self) -> PublicKey {
+        public_from_private(self)
+    }
+}
+
+/// Identifies a peer in logs, events and errors.
+///
+/// Renders as the first 8 characters of the base64 encoded public key
+/// (both with Display and Debug), hence the full key never ends up in log output.
+/// The full key is only available through PeerId::public_key.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PeerId(x25519_dalek::PublicKey);
+
+impl PeerId {
+    pub fn new(pk: x25519_dalek::PublicKey) -> PeerId {
+        PeerId(pk)
+    }
+
+    /// Returns the full public key of the peer
+    pub fn public_key(&self) -> &x25519_dalek::PublicKey {
+        &self.0
+    }
+}
+
+impl From<x25519_dalek::PublicKey> for PeerId {
+    fn from(pk: x25519_dalek::PublicKey) -> PeerId {
+        PeerId(pk)
+    }
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&to_base64(self.0.as_bytes())[..PEER_ID_LENGTH])
+    }
+}
+
+impl fmt::Debug for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PeerId({})", self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(to_base64(b""), "");
+        assert_eq!(to_base64(b"f"), "Zg==");
+        assert_eq!(to_base64(b"fo"), "Zm8=");
+        assert_eq!(to_base64(b"foo"), "Zm9v");
+        assert_eq!(to_base64(b"foobar"), "Zm9vYmFy");
+        assert_eq!(to_base64(&[0xff; 32]).len(), 44);
+    }
+
+    #[test]
+    fn test_key_encodings() {
+        let bytes: Vec<u8> = (0..32).collect();
+        let encoded = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+        assert_eq!(to_base64(&bytes), encoded);
+
+        let key = PublicKey::from_base64(encoded).unwrap();
+        assert_eq!(&key.as_bytes()[..], &bytes[..]);
+        assert_eq!(key.to_base64(), encoded);
+        assert_eq!(PublicKey::from_hex(&key.to_hex()).unwrap(), key);
+        assert_eq!(
+            PublicKey::from_base64(&format!(" {}\n", encoded)).unwrap(),
+            key
+        );
+        assert_eq!(
+            PresharedKey::from_hex(&key.to_hex().to_uppercase()).unwrap(),
+            PresharedKey::from_bytes(*key.as_bytes())
+        );
+
+        // invalid length
+        assert_eq!(
+            PrivateKey::from_base64(&encoded[..40]).unwrap_err(),
+            KeyError::InvalidLength
+        );
+        assert_eq!(
+            PrivateKey::from_hex("00").unwrap_err(),
+            KeyError::InvalidLength
+        );
+
+        // invalid characters, missing padding and non-canonical encodings
+        assert!(PrivateKey::from_base64(&encoded.replace("A", "*")).is_err());
+        assert!(PrivateKey::from_base64(&encoded.replace("=", "A")).is_err());
+        assert!(PrivateKey::from_base64(&encoded.replace("8=", "9=")).is_err());
+        assert!(PrivateKey::from_hex(&"zz".repeat(32)).is_err());
+
+        // secrets are never displayed
+        let sk = PrivateKey::from_bytes([1u8; 32]);
+        assert_eq!(format!("{:?}", sk), "PrivateKey(<redacted>)");
+    }
+
+    #[test]
+    fn test_generate() {
+        // test vector from RFC 7748 (section 6.1)
+        let sk = PrivateKey::from_hex(
+            "77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a",
+        )
+        .unwrap();
+        assert_eq!(
+            public_from_private(&sk).to_hex(),
+            "8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a"
+        );
+
+        // generated keys are clamped
+        for _ in 0..16 {
+            let sk = generate();
+            assert_eq!(clamp(*sk.as_bytes()), *sk.as_bytes());
+            assert_eq!(
+                sk.public_key(),
+                PrivateKey::from_base64(&sk.to_base64())
+                    .unwrap()
+                    .public_key()
+            );
+        }
+
+        assert_ne!(generate_preshared(), generate_preshared());
+    }
+
+    #[test]
+    fn test_peer_id() {
+        let pk = x25519_dalek::PublicKey::from([0u8; 32]);
+        let id = PeerId::from(pk);
+        assert_eq!(id.to_string(), "AAAAAAAA");
+        assert_eq!(format!("{:?}", id), "PeerId(AAAAAAAA)");
+        assert_eq!(id.public_key().as_bytes(), pk.as_bytes());
+    }
+}
@@ -0,0 +1,161 @@
+use std::time::{Duration, Instant};
+
+use spin::Mutex;
+
+/* Under-load state of the device (DoS mitigation):
+ *
+ * While under load, handshake initiations without a valid cookie (mac2) are answered
+ * with a cookie reply rather than processed.
+ * The state is shared between the readers, which report the length of the handshake queue
+ * as they enqueue messages, and the handshake workers, which report the time messages waited
+ * in the queue and consume the state.
+ *
+ * The state has hysteresis: the device enters the under-load state as soon as either
+ * the queue exceeds the high watermark or a message waited too long,
+ * but leaves it only once no trigger occurred for the hold duration
+ * and the queue has drained below the low watermark
+ * (a single short queue does not end the state while the flood continues).
+ */
+pub struct UnderLoad {
+    high: usize,                   // queue length entering the state
+    low: usize,                    // queue length below which the state may be left
+    threshold: Duration,           // wait in the queue entering the state
+    hold: Duration,                // minimum duration of the state since the last trigger
+    state: Mutex<Option<Instant>>, // last trigger (None = not under load)
+}
+
+impl UnderLoad {
+    pub fn new(high: usize, low: usize, threshold: Duration, hold: Duration) -> UnderLoad {
+        debug_assert!(low <= high);
+        UnderLoad {
+            high,
+            low,
+            threshold,
+            hold,
+            state: Mutex::new(None),
+        }
+    }
+
+    fn trigger(&self, now: Instant) {
+        *self.state.lock() = Some(now);
+    }
+
+    fn report_queue_at(&self, pending: usize, now: Instant) -> bool {
+        if pending >= self.high {
+            self.trigger(now);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn report_wait_at(&self, wait: Duration, now: Instant) -> bool {
+        if wait > self.threshold {
+            self.trigger(now);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn check_at(&self, pending: usize, now: Instant) -> bool {
+        let mut state = self.state.lock();
+        match *state {
+            None => false,
+            Some(last) => {
+                if now.saturating_duration_since(last) >= self.hold && pending <= self.low {
+                    *state = None;
+                    false
+                } else {
+                    true
+                }
+            }
+        }
+    }
+
+    /// Reports the length of the handshake queue (by the readers, after enqueuing a message)
+    ///
+    /// # Returns
+    ///
+    /// True if the length triggered the under-load state
+    pub fn report_queue(&self, pending: usize) -> bool {
+        self.report_queue_at(pending, Instant::now())
+    }
+
+    /// Reports the time a handshake message waited in the queue (by the handshake workers)
+    ///
+    /// # Returns
+    ///
+    /// True if the wait triggered the under-load state
+    pub fn report_wait(&self, wait: Duration) -> bool {
+        self.report_wait_at(wait, Instant::now())
+    }
+
+    /// Returns whether the device is under load (by the handshake workers)
+    ///
+    /// # Arguments
+    ///
+    /// - `pending`: The current length of the handshake queue
+    pub fn check(&self, pending: usize) -> bool {
+        self.check_at(pending, Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLD: Duration = Duration::from_millis(10);
+    const HOLD: Duration = Duration::from_secs(1);
+
+    fn under_load() -> UnderLoad {
+        UnderLoad::new(100, 10, THRESHOLD, HOLD)
+    }
+
+    #[test]
+    fn test_under_load_queue() {
+        let load = under_load();
+        let now = Instant::now();
+
+        // below the high watermark
+        assert!(!load.report_queue_at(99, now));
+        assert!(!load.check_at(99, now));
+
+        // entered at the high watermark, held while the queue drains
+        assert!(load.report_queue_at(100, now));
+        assert!(load.check_at(50, now));
+        assert!(load.check_at(5, now + HOLD / 2));
+
+        // left after the hold duration (queue below the low watermark)
+        assert!(!load.check_at(5, now + HOLD));
+        assert!(!load.check_at(50, now + HOLD));
+    }
+
+    #[test]
+    fn test_under_load_wait() {
+        let load = under_load();
+        let now = Instant::now();
+
+        assert!(!load.report_wait_at(THRESHOLD, now));
+        assert!(!load.check_at(0, now));
+
+        assert!(load.report_wait_at(THRESHOLD * 2, now));
+        assert!(load.check_at(0, now));
+        assert!(!load.check_at(0, now + HOLD));
+    }
+
+    #[test]
+    fn test_under_load_hysteresis() {
+        let load = under_load();
+        let now = Instant::now();
+        assert!(load.report_queue_at(100, now));
+
+        // not left while the queue is above the low watermark (flood continues)
+        assert!(load.check_at(11, now + HOLD * 2));
+
+        // a new trigger restarts the hold duration
+        assert!(load.report_wait_at(THRESHOLD * 2, now + HOLD * 2));
+        assert!(load.check_at(0, now + HOLD * 2 + HOLD / 2));
+        assert!(!load.check_at(0, now + HOLD * 3));
+    }
+}
@@ -0,0 +1,138 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/* Accounting of the memory held by the device:
+ * buffers of queued messages (handshake queue, staged packets, in-flight jobs)
+ * and the (fixed size) state of every peer.
+ *
+ * Every allocation is represented by a Reservation,
+ * which returns the accounted bytes to the device when dropped.
+ */
+pub struct Memory {
+    used: AtomicUsize,
+    limit: AtomicUsize, // 0 = unlimited
+}
+
+pub struct Reservation {
+    memory: Arc<Memory>,
+    size: usize,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let used = self.memory.used.fetch_sub(self.size, Ordering::Relaxed);
+        debug_assert!(used >= self.size, "memory accounting underflow");
+    }
+}
+
+impl Memory {
+    pub fn new() -> Memory {
+        Memory {
+            used: AtomicUsize::new(0),
+            limit: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the number of bytes currently accounted for
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Returns the cap on accounted memory (if any)
+    pub fn limit(&self) -> Option<usize> {
+        match self.limit.load(Ordering::Relaxed) {
+            0 => None,
+            limit => Some(limit),
+        }
+    }
+
+    /// Sets the cap on accounted memory
+    ///
+    /// # Note
+    ///
+    /// Lowering the limit below the current usage does not free any memory,
+    /// but causes all further optional reservations to fail until usage has dropped.
+    pub fn set_limit(&self, limit: Option<usize>) {
+        self.limit.store(limit.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    /// Account for `size` bytes regardless of the limit.
+    ///
+    /// Used for allocations which are already bounded by other means
+    /// (e.g. jobs in bounded queues and peer state).
+    pub fn reserve(self: &Arc<Self>, size: usize) -> Reservation {
+        self.used.fetch_add(size, Ordering::Relaxed);
+        Reservation {
+            memory: self.clone(),
+            size,
+        }
+    }
+
+    /// Account for `size` bytes if this does not exceed the limit.
+    ///
+    /// # Returns
+    ///
+    /// None if the reservation would exceed the limit:
+    /// the caller should apply backpressure (e.g. drop the message).
+    pub fn try_reserve(self: &Arc<Self>, size: usize) -> Option<Reservation> {
+        let limit = self.limit.load(Ordering::Relaxed);
+        let mut used = self.used.load(Ordering::Relaxed);
+        loop {
+            if limit != 0 && used.saturating_add(size) > limit {
+                return None;
+            }
+            match self.used.compare_exchange_weak(
+                used,
+                used + size,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => {
+                    return Some(Reservation {
+                        memory: self.clone(),
+                        size,
+                    })
+                }
+                Err(current) => used = current,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_limit() {
+        let memory = Arc::new(Memory::new());
+
+        // unlimited by default
+        let r1 = memory.try_reserve(1000).unwrap();
+        assert_eq!(memory.used(), 1000);
+
+        // optional reservations fail above the limit
+        memory.set_limit(Some(1500));
+        assert!(memory.try_reserve(501).is_none());
+        let r2 = memory.try_reserve(500).unwrap();
+        assert_eq!(memory.used(), 1500);
+
+        // forced reservations always succeed
+        let r3 = memory.reserve(100);
+        assert_eq!(memory.used(), 1600);
+        assert!(memory.try_reserve(1).is_none());
+
+        // memory is returned on drop
+        drop(r1);
+        drop(r3);
+        assert_eq!(memory.used(), 500);
+        assert!(memory.try_reserve(1000).is_some());
+        assert_eq!(memory.used(), 500);
+
+        drop(r2);
+        assert_eq!(memory.used(), 0);
+
+        memory.set_limit(None);
+        assert_eq!(memory.limit(), None);
+    }
+}
@@ -7,12 +7,32 @@
  * and the crypto-key router code together,
  * e.g. every WireGuard peer consists of a handshake and router peer.
  */
+mod affinity;
+mod authorization;
+mod capabilities;
 mod constants;
+mod counter;
+pub mod crypto;
+mod dedup;
+mod discovery;
+mod exit;
+mod filter;
 mod handshake;
+mod health;
+mod inspect;
+#[cfg(feature = "ipfix")]
+mod ipfix;
+pub mod keys;
+mod load;
+mod memory;
 mod peer;
 mod queue;
+mod quota;
 mod router;
+mod snapshot;
+mod stats;
 mod timers;
+mod tunnel;
 mod types;
 mod wireguard;
 mod workers;
@@ -21,9 +41,33 @@ mod workers;
 mod tests;
 
 // represents a WireGuard interface
+pub use authorization::{ExecAuthorization, PeerAuthorization};
+pub use capabilities::{Capabilities, HostSupport};
+pub use discovery::{EndpointDiscovery, RelayDiscovery};
+pub use exit::ExitSelector;
+pub use filter::Filter;
+pub use handshake::{
+    parse_message, peek_initiator, Clock, HandshakeError, HandshakeMessage, MessageBuilder,
+    PersistedClock, SystemClock,
+};
+pub use health::HealthReport;
+pub use inspect::{Inspection, Verdict};
+pub use keys::PeerId;
+pub use router::{Action, InterfaceStats, DEFAULT_MAX_STAGED_AGE};
+pub use stats::{
+    MessageKind, PeerRates, PeerStats, PeerUsage, RxCount, RxKinds, RxStats, StatsRates,
+    StatsSnapshot,
+};
+pub use tunnel::{Received, Tunnel, TunnelError};
+pub use types::{
+    Blackhole, Encapsulation, EndpointPolicy, ExitCandidate, ExitPolicy, Flow, FlowStats,
+    HandshakePriority, HandshakeRole, HandshakeState, KeepaliveSuppression, Padding, PeerEvent,
+    PerformanceMode, PowerSave, ProtocolTimers, Quota, QuotaPeriod, RelayPolicy, RelayStats,
+    ReplayStats, SendErrors, SessionInfo, Source, WorkerConfig,
+};
 pub use wireguard::WireGuard;
 
-#[cfg(test)]
+#[cfg(any(test, feature = "dummy"))]
 use super::platform::dummy;
 
 use super::platform::{tun, udp, Endpoint};
@@ -3,22 +3,23 @@ use super::timers::Timers;
 use super::tun::Tun;
 use super::udp::UDP;
 
-use super::constants::REKEY_TIMEOUT;
+use super::counter::{CachePadded, TrafficCounters};
+use super::keys::PeerId;
+use super::quota::period_of;
+use super::types::{HandshakeState, PeerEvent, Quota};
 use super::wireguard::WireGuard;
-use super::workers::HandshakeJob;
+use super::workers::{HandshakeJob, Pending};
 
 use std::fmt;
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::time::{Instant, SystemTime};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 
 use spin::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 use x25519_dalek::PublicKey;
 
 pub struct PeerInner<T: Tun, B: UDP> {
-    // internal id (for logging)
-    pub id: u64,
-
     // wireguard device state
     pub wg: WireGuard<T, B>,
 
@@ -29,10 +30,30 @@ pub struct PeerInner<T: Tun, B: UDP> {
     pub walltime_last_handshake: Mutex<Option<SystemTime>>, // walltime for last handshake (for UAPI status)
     pub last_handshake_sent: Mutex<Instant>,                // instant for last handshake
     pub handshake_queued: AtomicBool,                       // is a handshake job currently queued?
+    pub suspended: Mutex<Option<Instant>>, // handshake retries suspended since (peer unreachable)
+    pub handshake_state: Mutex<(HandshakeState, SystemTime)>, // state and walltime of the last transition
+
+    // endpoint selection (for dual-stack peers)
+    pub endpoint_candidates: Mutex<Vec<SocketAddr>>, // alternative endpoints of the peer
+    pub race_endpoints: AtomicBool, // send initiations to every candidate (no endpoint confirmed)
 
     // stats and configuration
-    pub rx_bytes: AtomicU64, // received bytes
-    pub tx_bytes: AtomicU64, // transmitted bytes
+    pub traffic: TrafficCounters, // received / transmitted bytes and messages (per worker)
+    pub handshakes: AtomicU64,    // completed handshakes (key-pairs derived)
+
+    // usage quota
+    pub quota: RwLock<Option<Quota>>,
+    pub quota_period: AtomicU64, // current accounting period (see quota::period_of)
+    pub quota_usage: CachePadded<AtomicU64>, // bytes used in the current period
+    pub quota_limit: AtomicU64,  // limit of the quota (u64::MAX without a quota)
+    pub disabled: AtomicBool,    // quota exceeded: handshakes and traffic stopped
+
+    // exit selection
+    pub exit_weight: AtomicU32, // weight as an exit candidate (0 = not a candidate)
+    pub handshake_rtt: Mutex<Option<Duration>>, // round-trip time of the last handshake initiated
+
+    // initiations carrying a cookie (mac2) of the peer, i.e. retried after a cookie reply
+    pub cookie_retries: AtomicU64,
 
     // timer model
     pub timers: RwLock<Timers>,
@@ -47,10 +68,32 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
     pub fn packet_send_handshake_initiation(&self) {
         log::trace!("{} : packet_send_handshake_initiation", self);
 
+        // no handshakes with peers disabled by their quota
+        if self.quota_exceeded() {
+            log::trace!(
+                "{} : packet_send_handshake_initiation, quota exceeded",
+                self
+            );
+            return;
+        }
+
+        // no initiations to peers for which the device is only the responder
+        if !self
+            .wg
+            .get_handshake_role(&self.pk)
+            .map_or(true, |role| role.initiates())
+        {
+            log::trace!(
+                "{} : packet_send_handshake_initiation, responder only",
+                self
+            );
+            return;
+        }
+
         // the function is rate limited
         {
             let mut lhs = self.last_handshake_sent.lock();
-            if lhs.elapsed() < REKEY_TIMEOUT {
+            if lhs.elapsed() < self.wg.protocol_timers.rekey_timeout {
                 log::trace!("{} : packet_send_handshake_initiation, rate-limited!", self);
                 return;
             }
@@ -59,12 +102,21 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
 
         // create a new handshake job for the peer
         if !self.handshake_queued.swap(true, Ordering::SeqCst) {
-            self.wg.pending.fetch_add(1, Ordering::SeqCst);
-            self.wg.queue.send(HandshakeJob::New(self.pk));
+            let pending = Pending::new(&self.wg.pending);
+            self.wg.queue.send(HandshakeJob::New(
+                self.pk,
+                pending,
+                self.wg.epoch.load(Ordering::SeqCst),
+            ));
             log::trace!(
                 "{} : packet_send_handshake_initiation, handshake queued",
                 self
             );
+
+            // the embedder wakes the radio in power-save
+            if self.wg.power_save.read().is_some() {
+                self.wg.emit(PeerEvent::WakeRequired { peer: self.id() });
+            }
         } else {
             log::trace!(
                 "{} : packet_send_handshake_initiation, handshake already queued",
@@ -73,6 +125,182 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
         }
     }
 
+    /* Returns true if handshake retries are suspended
+     * (the peer did not answer initiations and the cool-down has not expired)
+     */
+    pub fn handshakes_suspended(&self) -> bool {
+        let mut suspended = self.suspended.lock();
+        match *suspended {
+            Some(since) if since.elapsed() < self.wg.get_blackhole().cooldown => true,
+            Some(_) => {
+                log::debug!("{} : cool-down expired, resuming handshakes", self);
+                *suspended = None;
+                false
+            }
+            None => false,
+        }
+    }
+
+    /* Resume handshakes with a peer deemed unreachable
+     * (called when data is received from the peer or the endpoint changes)
+     */
+    pub fn resume_handshakes(&self) {
+        if self.suspended.lock().take().is_some() {
+            log::debug!("{} : resuming handshakes", self);
+        }
+    }
+
+    /* Accounts transport traffic against the quota of the peer (if any),
+     * disabling the peer once the quota is exceeded.
+     *
+     * Called for every transport message, hence only touches atomics:
+     * new periods are started and the usage is saved by the quota timer of the device.
+     */
+    pub fn account_quota(&self, bytes: usize) {
+        let usage = self.quota_usage.fetch_add(bytes as u64, Ordering::Relaxed) + bytes as u64;
+        if usage > self.quota_limit.load(Ordering::Relaxed)
+            && !self.disabled.load(Ordering::Relaxed)
+        {
+            self.check_quota();
+        }
+    }
+
+    /* Disables the peer if the usage exceeds the quota (re-enables it otherwise).
+     *
+     * Returns true if the peer was disabled by the call.
+     */
+    pub fn check_quota(&self) -> bool {
+        let quota = match *self.quota.read() {
+            Some(quota) => quota,
+            None => {
+                self.enable();
+                return false;
+            }
+        };
+
+        let usage = self.quota_usage.load(Ordering::Relaxed);
+        if usage <= quota.limit {
+            self.enable();
+            return false;
+        }
+        if self.disabled.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+
+        log::info!(
+            "{} : quota exceeded ({} of {} bytes), disabling peer",
+            self,
+            usage,
+            quota.limit
+        );
+        self.timers_peer_disabled();
+        self.wg.emit(PeerEvent::QuotaExceeded {
+            peer: self.id(),
+            usage,
+            limit: quota.limit,
+        });
+        true
+    }
+
+    /* Returns true if the peer is disabled (quota exceeded in the current period),
+     * handshakes with disabled peers are neither initiated nor answered.
+     */
+    pub fn quota_exceeded(&self) -> bool {
+        if let Some(quota) = *self.quota.read() {
+            self.rollover_quota(quota);
+        }
+        self.disabled.load(Ordering::SeqCst)
+    }
+
+    /* Starts a new accounting period (resetting the usage) once the current has passed */
+    fn rollover_quota(&self, quota: Quota) {
+        let period = period_of(quota.period, SystemTime::now());
+        if self.quota_period.swap(period, Ordering::SeqCst) != period {
+            log::debug!("{} : new quota period, usage reset", self);
+            self.quota_usage.store(0, Ordering::SeqCst);
+            self.enable();
+        }
+    }
+
+    /* Re-enables a peer disabled by its quota */
+    fn enable(&self) {
+        if self.disabled.load(Ordering::Relaxed) && self.disabled.swap(false, Ordering::SeqCst) {
+            log::info!("{} : within quota, peer re-enabled", self);
+        }
+    }
+
+    /* Records a transition of the handshake state
+     * and emits a Handshake event (if the state changed)
+     */
+    pub fn set_handshake_state(&self, state: HandshakeState) {
+        let at = SystemTime::now();
+        {
+            let mut current = self.handshake_state.lock();
+            if current.0 == state {
+                return;
+            }
+            *current = (state, at);
+        }
+        log::debug!("{} : handshake state {:?}", self, state);
+        self.wg.emit(PeerEvent::Handshake {
+            peer: self.id(),
+            state,
+            at,
+        });
+    }
+
+    /* Returns the identifier of the peer (for logging) */
+    pub fn id(&self) -> PeerId {
+        PeerId::new(self.pk)
+    }
+
+    /* Race the next initiations over every candidate endpoint
+     * (called when a handshake with the current endpoint fails)
+     */
+    pub fn restart_endpoint_race(&self) {
+        if self.endpoint_candidates.lock().len() > 1 {
+            log::debug!("{} : racing initiations over candidate endpoints", self);
+            self.race_endpoints.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /* Reports the failed handshake to the endpoint discovery (if any),
+     * racing the candidates it supplies in the next initiations
+     * (unless the device gave up retrying)
+     */
+    pub fn report_path_failed(&self, endpoint: Option<SocketAddr>, exhausted: bool) {
+        let discovery = match self.wg.endpoint_discovery.read().clone() {
+            Some(discovery) => discovery,
+            None => return,
+        };
+        discovery.path_failed(self.id(), endpoint, exhausted);
+        if exhausted {
+            return;
+        }
+
+        let addrs = discovery.candidates(self.id());
+        if !addrs.is_empty() {
+            log::debug!("{} : {} candidate endpoints discovered", self, addrs.len());
+            *self.endpoint_candidates.lock() = addrs;
+            self.race_endpoints.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /* Reports the handshake authenticated over the endpoint to the endpoint discovery (if any) */
+    pub fn report_path_up(&self, endpoint: SocketAddr) {
+        if let Some(discovery) = self.wg.endpoint_discovery.read().clone() {
+            discovery.path_up(self.id(), endpoint);
+        }
+    }
+
+    /* Returns true if the authorization (if any) permits the handshake over the endpoint */
+    pub fn authorize_handshake(&self, endpoint: SocketAddr) -> bool {
+        match self.wg.peer_authorization.read().clone() {
+            Some(authorization) => authorization.authorize_handshake(self.id(), endpoint),
+            None => true,
+        }
+    }
+
     #[inline(always)]
     pub fn timers(&self) -> RwLockReadGuard<Timers> {
         self.timers.read()
@@ -86,6 +314,9 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
 
 impl<T: Tun, B: UDP> fmt::Display for PeerInner<T, B> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "peer(id = {})", self.id)
+        match self.wg.name.read().as_ref() {
+            Some(name) => write!(f, "peer(device = {}, id = {})", name, self.id()),
+            None => write!(f, "peer(id = {})", self.id()),
+        }
     }
 }
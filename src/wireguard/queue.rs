@@ -1,4 +1,4 @@
-use crossbeam_channel::{bounded, Receiver, Sender};
+use crossbeam_channel::{bounded, select, Receiver, Sender};
 use std::sync::Mutex;
 
 pub struct ParallelQueue<T> {
@@ -28,12 +28,149 @@ impl<T> ParallelQueue<T> {
     }
 
     pub fn send(&self, v: T) {
-        self.queue.lock().unwrap().as_ref().map(|s| {
+        // block on a clone of the sender (rather than while holding the lock),
+        // hence a full queue does not block senders which must not wait (see try_send)
+        let sender = self.queue.lock().unwrap().clone();
+        if let Some(s) = sender {
             let _ = s.send(v);
-        });
+        }
+    }
+
+    /// Add a job without blocking
+    ///
+    /// # Returns
+    ///
+    /// The job if the queue is full (or has been closed)
+    pub fn try_send(&self, v: T) -> Result<(), T> {
+        match self.queue.lock().unwrap().as_ref() {
+            Some(s) => s.try_send(v).map_err(|e| e.into_inner()),
+            None => Err(v),
+        }
     }
 
     pub fn close(&self) {
         *self.queue.lock().unwrap() = None;
     }
 }
+
+/// A parallel queue with two lanes:
+/// readers always receive pending jobs from the priority lane
+/// before any job from the normal lane,
+/// i.e. priority jobs are never stuck behind a backlog of normal jobs.
+pub struct PriorityQueue<T> {
+    priority: ParallelQueue<T>,
+    normal: ParallelQueue<T>,
+}
+
+pub struct PriorityReceiver<T> {
+    priority: Receiver<T>,
+    normal: Receiver<T>,
+}
+
+impl<T> PriorityQueue<T> {
+    /// Create a new PriorityQueue instance
+    ///
+    /// # Arguments
+    ///
+    /// - `queues`: number of readers
+    /// - `capacity`: capacity of each lane
+    ///
+    pub fn new(queues: usize, capacity: usize) -> (Self, Vec<PriorityReceiver<T>>) {
+        let (priority, mut priority_rxs) = ParallelQueue::new(queues, capacity);
+        let (normal, mut normal_rxs) = ParallelQueue::new(queues, capacity);
+        let mut receivers = Vec::with_capacity(queues);
+        while let (Some(priority), Some(normal)) = (priority_rxs.pop(), normal_rxs.pop()) {
+            receivers.push(PriorityReceiver { priority, normal });
+        }
+        (PriorityQueue { priority, normal }, receivers)
+    }
+
+    /// Add a job to the normal lane
+    pub fn send(&self, v: T) {
+        self.normal.send(v)
+    }
+
+    /// Add a job to the priority lane
+    pub fn send_priority(&self, v: T) {
+        self.priority.send(v)
+    }
+
+    pub fn close(&self) {
+        self.priority.close();
+        self.normal.close();
+    }
+}
+
+impl<T> PriorityReceiver<T> {
+    /// Blocks until a job is available from either lane
+    ///
+    /// # Returns
+    ///
+    /// The next job or None if both lanes have been closed (and drained)
+    pub fn recv(&self) -> Option<T> {
+        // prefer jobs from the priority lane
+        if let Ok(v) = self.priority.try_recv() {
+            return Some(v);
+        }
+
+        // otherwise wait for a job on either lane
+        // (if one lane is closed fall back to the other)
+        select! {
+            recv(self.priority) -> v => v.ok().or_else(|| self.normal.recv().ok()),
+            recv(self.normal) -> v => v.ok().or_else(|| self.priority.recv().ok()),
+        }
+    }
+}
+
+impl<T> Iterator for PriorityReceiver<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.recv()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_priority_queue() {
+        let (queue, mut rxs) = PriorityQueue::new(1, 16);
+        let rx = rxs.pop().unwrap();
+
+        // priority jobs overtake pending normal jobs
+        queue.send(1);
+        queue.send(2);
+        queue.send_priority(3);
+        assert_eq!(rx.recv(), Some(3));
+        assert_eq!(rx.recv(), Some(1));
+        queue.send_priority(4);
+        assert_eq!(rx.recv(), Some(4));
+        assert_eq!(rx.recv(), Some(2));
+
+        // remaining jobs are drained after close
+        queue.send(5);
+        queue.send_priority(6);
+        queue.close();
+        let rest: Vec<u32> = rx.collect();
+        assert_eq!(rest, vec![6, 5]);
+    }
+
+    #[test]
+    fn test_try_send_full() {
+        let (queue, mut rxs) = ParallelQueue::new(1, 2);
+        let rx = rxs.pop().unwrap();
+
+        // the job is returned (rather than blocking) once the queue is full
+        assert_eq!(queue.try_send(1), Ok(()));
+        assert_eq!(queue.try_send(2), Ok(()));
+        assert_eq!(queue.try_send(3), Err(3));
+        assert_eq!(rx.recv(), Ok(1));
+        assert_eq!(queue.try_send(4), Ok(()));
+
+        // and after close
+        queue.close();
+        assert_eq!(queue.try_send(5), Err(5));
+    }
+}
@@ -0,0 +1,128 @@
+/* Accounting periods and persistence of the usage of peer quotas.
+ *
+ * The usage is stored in a plain text file, one peer per line:
+ *
+ * <public key (hex)> <period> <bytes used in the period>
+ */
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hex::FromHex;
+
+use super::types::QuotaPeriod;
+
+// Semantics:
+// Interval at which periods are rolled over and the usage is written to the quota file
+// (in addition to whenever a quota is set and when the device is brought down)
+pub const QUOTA_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Usage restored from / written to the quota file: public key -> (period, bytes)
+pub type QuotaUsage = HashMap<[u8; 32], (u64, u64)>;
+
+// (year, month) of the day (since the epoch), see http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, u64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month as u64)
+}
+
+/// Returns the accounting period containing the time
+/// (the number of months since the epoch for monthly quotas)
+pub fn period_of(period: QuotaPeriod, now: SystemTime) -> u64 {
+    match period {
+        QuotaPeriod::Absolute => 0,
+        QuotaPeriod::Monthly => {
+            let secs = now
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            let (year, month) = civil_from_days((secs / 86400) as i64);
+            ((year - 1970) * 12) as u64 + month - 1
+        }
+    }
+}
+
+/// Reads the quota file (a missing file holds no usage)
+pub fn load(path: &Path) -> io::Result<QuotaUsage> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(e),
+    };
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed quota file");
+    let mut usage = HashMap::new();
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        let mut fields = line.split_whitespace();
+        let pk = <[u8; 32]>::from_hex(fields.next().ok_or_else(invalid)?).map_err(|_| invalid())?;
+        let period = fields
+            .next()
+            .and_then(|v| v.parse().ok())
+            .ok_or_else(invalid)?;
+        let bytes = fields
+            .next()
+            .and_then(|v| v.parse().ok())
+            .ok_or_else(invalid)?;
+        usage.insert(pk, (period, bytes));
+    }
+    Ok(usage)
+}
+
+/// Writes the quota file (atomically, by replacing the file)
+pub fn save(path: &Path, usage: &QuotaUsage) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    {
+        let mut file = fs::File::create(&tmp)?;
+        for (pk, (period, bytes)) in usage.iter() {
+            writeln!(file, "{} {} {}", hex::encode(pk), period, bytes)?;
+        }
+        file.sync_all()?;
+    }
+    fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_period_of() {
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        assert_eq!(period_of(QuotaPeriod::Monthly, at(0)), 0);
+        assert_eq!(period_of(QuotaPeriod::Absolute, at(1_000_000_000)), 0);
+
+        // 2020-02-29 23:59:59 and 2020-03-01 00:00:00
+        assert_eq!(
+            period_of(QuotaPeriod::Monthly, at(1_583_020_799)),
+            50 * 12 + 1
+        );
+        assert_eq!(
+            period_of(QuotaPeriod::Monthly, at(1_583_020_800)),
+            50 * 12 + 2
+        );
+    }
+
+    #[test]
+    fn test_load_save() {
+        let path = std::env::temp_dir().join(format!("wg-quota-test-{}", std::process::id()));
+        assert!(load(&path).unwrap().is_empty());
+
+        let mut usage = HashMap::new();
+        usage.insert([1u8; 32], (0, 1234));
+        usage.insert([2u8; 32], (612, 5678));
+        save(&path, &usage).unwrap();
+        assert_eq!(load(&path).unwrap(), usage);
+
+        fs::write(&path, "00 1\n").unwrap();
+        assert!(load(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
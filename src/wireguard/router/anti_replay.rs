@@ -1,5 +1,7 @@
 use core::mem;
 
+use super::super::ReplayStats;
+
 // Implementation of RFC 6479.
 // https://tools.ietf.org/html/rfc6479
 
@@ -26,6 +28,7 @@ const WINDOW_SIZE: u64 = (BITMAP_BITLEN - SIZE_OF_WORD) as u64;
 pub struct AntiReplay {
     bitmap: [Word; BITMAP_LEN],
     last: u64,
+    stats: ReplayStats,
 }
 
 impl Default for AntiReplay {
@@ -41,13 +44,14 @@ impl AntiReplay {
         AntiReplay {
             last: 0,
             bitmap: [0; BITMAP_LEN],
+            stats: ReplayStats::default(),
         }
     }
 
     // Returns true if check is passed, i.e., not a replay or too old.
     //
     // Unlike RFC 6479, zero is allowed.
-    fn check(&self, seq: u64) -> bool {
+    pub fn check(&self, seq: u64) -> bool {
         // Larger is always good.
         if seq > self.last {
             return true;
@@ -63,6 +67,17 @@ impl AntiReplay {
         self.bitmap[index as usize] & (1 << bit_location) == 0
     }
 
+    // Returns the highest sequence number seen (0 if none).
+    pub fn highest(&self) -> u64 {
+        self.last
+    }
+
+    // Returns the drops and the reordering observed by update
+    // (sequence numbers only checked are not counted).
+    pub fn stats(&self) -> ReplayStats {
+        self.stats
+    }
+
     // Should only be called if check returns true.
     fn update_store(&mut self, seq: u64) {
         debug_assert!(self.check(seq));
@@ -101,12 +116,24 @@ impl AntiReplay {
     /// Ok(()) if sequence number is valid (not marked and not behind the moving window).
     /// Err if the sequence number is invalid (already marked or "too old").
     pub fn update(&mut self, seq: u64) -> bool {
-        if self.check(seq) {
+        let ok = self.check(seq);
+        if seq < self.last {
+            let distance = self.last - seq;
+            if distance > WINDOW_SIZE {
+                self.stats.too_old += 1;
+            } else if !ok {
+                self.stats.duplicate += 1;
+            }
+            if ok || distance > WINDOW_SIZE {
+                self.stats.max_reorder = self.stats.max_reorder.max(distance);
+            }
+        } else if !ok {
+            self.stats.duplicate += 1; // the highest sequence number
+        }
+        if ok {
             self.update_store(seq);
-            true
-        } else {
-            false
         }
+        ok
     }
 }
 
@@ -154,4 +181,24 @@ mod tests {
             assert!(!ar.check(i));
         }
     }
+
+    #[test]
+    fn anti_replay_stats() {
+        let mut ar = AntiReplay::new();
+        assert!(ar.update(100));
+        assert!(ar.update(90)); // reordered
+        assert!(!ar.update(90)); // duplicate
+        assert!(!ar.update(100)); // duplicate (highest)
+        assert!(ar.update(WINDOW_SIZE + 200));
+        assert!(!ar.update(50)); // too old
+        assert!(ar.check(WINDOW_SIZE + 150));
+        assert_eq!(
+            ar.stats(),
+            ReplayStats {
+                too_old: 1,
+                duplicate: 2,
+                max_reorder: WINDOW_SIZE + 150,
+            }
+        );
+    }
 }
@@ -0,0 +1,93 @@
+use super::super::constants::MESSAGE_PADDING_MULTIPLE;
+use super::{CAPACITY_MESSAGE_POSTFIX, SIZE_MESSAGE_PREFIX};
+
+/* A transport message under construction:
+ *
+ * [ prefix (header) | payload (IP packet + padding) | postfix (tag) ]
+ *
+ * The allocation always reserves room for the postfix,
+ * hence appending the tag during encryption never reallocates the buffer.
+ * The prefix is written by the router, the payload by the caller.
+ */
+pub struct PacketBuffer {
+    buf: Vec<u8>,
+}
+
+impl PacketBuffer {
+    /// Allocate a zeroed payload of the given length
+    pub fn new(payload: usize) -> Self {
+        let mut buf = Vec::with_capacity(SIZE_MESSAGE_PREFIX + payload + CAPACITY_MESSAGE_POSTFIX);
+        buf.resize(SIZE_MESSAGE_PREFIX + payload, 0);
+        PacketBuffer { buf }
+    }
+
+    /// Copy the packet into a payload padded to a multiple of MESSAGE_PADDING_MULTIPLE
+    pub fn padded(packet: &[u8]) -> Self {
+        let padded = (packet.len() + MESSAGE_PADDING_MULTIPLE - 1) / MESSAGE_PADDING_MULTIPLE
+            * MESSAGE_PADDING_MULTIPLE;
+        let mut buffer = Self::new(padded);
+        buffer.payload_mut()[..packet.len()].copy_from_slice(packet);
+        buffer
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.buf[SIZE_MESSAGE_PREFIX..]
+    }
+
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[SIZE_MESSAGE_PREFIX..]
+    }
+
+    /// The prefix and payload, e.g. for reading from a TUN device at offset SIZE_MESSAGE_PREFIX
+    pub fn message_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[..]
+    }
+
+    /// Shorten the payload (e.g. to the length of the packet read into it and its padding)
+    ///
+    /// # Returns
+    ///
+    /// False (and the payload is left unchanged) if the payload is shorter than the length
+    pub fn truncate(&mut self, payload: usize) -> bool {
+        if SIZE_MESSAGE_PREFIX + payload > self.buf.len() {
+            return false;
+        }
+        self.buf.truncate(SIZE_MESSAGE_PREFIX + payload);
+        true
+    }
+
+    /// Returns the message (prefix and payload) with capacity for the postfix
+    pub fn into_message(self) -> Vec<u8> {
+        debug_assert!(self.buf.capacity() >= self.buf.len() + CAPACITY_MESSAGE_POSTFIX);
+        self.buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packet_buffer() {
+        // the postfix is reserved
+        let buffer = PacketBuffer::new(100);
+        assert_eq!(buffer.payload().len(), 100);
+        let msg = buffer.into_message();
+        assert_eq!(msg.len(), SIZE_MESSAGE_PREFIX + 100);
+        assert!(msg.capacity() >= msg.len() + CAPACITY_MESSAGE_POSTFIX);
+
+        // the payload is padded and can only be shortened
+        let mut buffer = PacketBuffer::padded(&[1, 2, 3]);
+        assert_eq!(buffer.payload().len(), MESSAGE_PADDING_MULTIPLE);
+        assert_eq!(&buffer.payload()[..4], &[1, 2, 3, 0]);
+        assert!(!buffer.truncate(MESSAGE_PADDING_MULTIPLE + 1));
+        assert!(buffer.truncate(3));
+        assert_eq!(buffer.payload(), &[1, 2, 3]);
+        assert_eq!(buffer.message_mut().len(), SIZE_MESSAGE_PREFIX + 3);
+
+        // keep-alive
+        let msg = PacketBuffer::new(0).into_message();
+        assert_eq!(msg.len(), SIZE_MESSAGE_PREFIX);
+        assert!(msg.capacity() >= msg.len() + CAPACITY_MESSAGE_POSTFIX);
+    }
+}
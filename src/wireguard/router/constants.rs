@@ -1,9 +1,19 @@
+use std::time::Duration;
+
 // WireGuard semantics constants
 
 pub const MAX_QUEUED_PACKETS: usize = 1024;
 
+// packets staged (awaiting a key-pair) for longer are dropped rather than sent
+// (a stalled handshake must not release a burst of stale packets)
+pub const DEFAULT_MAX_STAGED_AGE: Duration = Duration::from_secs(3);
+
 // performance constants
 
 pub const PARALLEL_QUEUE_SIZE: usize = 4 * MAX_QUEUED_PACKETS;
 
 pub const INORDER_QUEUE_SIZE: usize = MAX_QUEUED_PACKETS;
+
+// upper bound on the jobs processed per invocation of the crypto stage
+// (the batch grows with the depth of the work queue, a lone job is not delayed)
+pub const MAX_CRYPTO_BATCH: usize = 32;
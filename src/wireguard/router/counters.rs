@@ -0,0 +1,269 @@
+/* Interface counters of the device, in the buckets of a kernel network interface
+ * (struct rtnl_link_stats64 and the columns of /proc/net/dev),
+ * hence monitoring built for kernel WireGuard interfaces applies unchanged.
+ *
+ * Packets are accounted as follows:
+ *
+ * - rx_packets / rx_bytes: inner packets delivered to the interface (keepalives excluded)
+ * - rx_errors: packets failing authentication, crypto-key routing or the inner IP header
+ * - rx_dropped: replayed packets, packets dropped by the filter or by the TUN device
+ * - tx_packets / tx_bytes: inner packets transmitted to a peer (keepalives excluded)
+ * - tx_errors: packets without a route, endpoint or exceeding the MTU of the peer
+ * - tx_dropped: packets dropped by the filter, the queue of the peer or while staged
+ *
+ * Bytes are counted from the inner IP packets (including any padding).
+ *
+ * The TUN driver keeps its own counters (of the packets exchanged with the daemon),
+ * which cannot be replaced from userspace: rtnetlink ignores IFLA_STATS64 on RTM_NEWLINK.
+ * Hence the counters are queried from the device (see InterfaceStats::link_stats64),
+ * rather than being pushed to the TUN interface.
+ */
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::super::counter::{shard, CachePadded, SHARDS};
+
+#[derive(Default)]
+struct Shard {
+    rx_packets: AtomicU64,
+    rx_bytes: AtomicU64,
+    rx_errors: AtomicU64,
+    rx_dropped: AtomicU64,
+    rx_length_errors: AtomicU64,
+    rx_crc_errors: AtomicU64,
+    rx_frame_errors: AtomicU64,
+    tx_packets: AtomicU64,
+    tx_bytes: AtomicU64,
+    tx_errors: AtomicU64,
+    tx_dropped: AtomicU64,
+}
+
+// every packet updates the counters: sharded over the workers (see counter::TrafficCounters)
+#[derive(Default)]
+pub(super) struct Counters {
+    shards: [CachePadded<Shard>; SHARDS],
+}
+
+/// Reasons for a received packet being counted as an error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum RxError {
+    Crc,    // authentication failure (or a counter beyond the limit)
+    Frame,  // source not allowed for the peer (crypto-key routing), or not an IP packet
+    Length, // inner length beyond the message
+}
+
+impl Counters {
+    #[inline(always)]
+    fn shard(&self) -> &Shard {
+        &self.shards[shard()]
+    }
+
+    pub fn rx(&self, len: usize) {
+        let shard = self.shard();
+        shard.rx_packets.fetch_add(1, Ordering::Relaxed);
+        shard.rx_bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn rx_error(&self, error: RxError) {
+        let shard = self.shard();
+        shard.rx_errors.fetch_add(1, Ordering::Relaxed);
+        match error {
+            RxError::Crc => &shard.rx_crc_errors,
+            RxError::Frame => &shard.rx_frame_errors,
+            RxError::Length => &shard.rx_length_errors,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn rx_dropped(&self) {
+        self.shard().rx_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn tx(&self, len: usize) {
+        let shard = self.shard();
+        shard.tx_packets.fetch_add(1, Ordering::Relaxed);
+        shard.tx_bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn tx_error(&self) {
+        self.shard().tx_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn tx_dropped(&self) {
+        self.shard().tx_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Sums the counters over all the shards
+    pub fn get(&self) -> InterfaceStats {
+        let mut sum = InterfaceStats::default();
+        for shard in self.shards.iter() {
+            let add = |total: &mut u64, counter: &AtomicU64| {
+                *total = total.wrapping_add(counter.load(Ordering::Relaxed))
+            };
+            add(&mut sum.rx_packets, &shard.rx_packets);
+            add(&mut sum.rx_bytes, &shard.rx_bytes);
+            add(&mut sum.rx_errors, &shard.rx_errors);
+            add(&mut sum.rx_dropped, &shard.rx_dropped);
+            add(&mut sum.rx_length_errors, &shard.rx_length_errors);
+            add(&mut sum.rx_crc_errors, &shard.rx_crc_errors);
+            add(&mut sum.rx_frame_errors, &shard.rx_frame_errors);
+            add(&mut sum.tx_packets, &shard.tx_packets);
+            add(&mut sum.tx_bytes, &shard.tx_bytes);
+            add(&mut sum.tx_errors, &shard.tx_errors);
+            add(&mut sum.tx_dropped, &shard.tx_dropped);
+        }
+        sum
+    }
+}
+
+// number of fields of struct rtnl_link_stats64 (up to rx_nohandler, Linux 4.6)
+pub const LINK_STATS64_FIELDS: usize = 24;
+
+/// Counters of the interface (named as the fields of struct rtnl_link_stats64)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InterfaceStats {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    pub rx_errors: u64,
+    pub rx_dropped: u64,
+    pub rx_length_errors: u64,
+    pub rx_crc_errors: u64,
+    pub rx_frame_errors: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    pub tx_errors: u64,
+    pub tx_dropped: u64,
+}
+
+impl InterfaceStats {
+    /// Returns the "frame" column of /proc/net/dev (the sum of the framing errors)
+    pub fn rx_frame(&self) -> u64 {
+        self.rx_length_errors + self.rx_crc_errors + self.rx_frame_errors
+    }
+
+    /// Returns the counters as the fields of struct rtnl_link_stats64 (in the order of the struct),
+    /// i.e. the IFLA_STATS64 attribute reported by rtnetlink (ip -s link) for a kernel interface
+    /// (fields which do not apply to a tunnel are zero)
+    pub fn link_stats64(&self) -> [(&'static str, u64); LINK_STATS64_FIELDS] {
+        [
+            ("rx_packets", self.rx_packets),
+            ("tx_packets", self.tx_packets),
+            ("rx_bytes", self.rx_bytes),
+            ("tx_bytes", self.tx_bytes),
+            ("rx_errors", self.rx_errors),
+            ("tx_errors", self.tx_errors),
+            ("rx_dropped", self.rx_dropped),
+            ("tx_dropped", self.tx_dropped),
+            ("multicast", 0),
+            ("collisions", 0),
+            ("rx_length_errors", self.rx_length_errors),
+            ("rx_over_errors", 0),
+            ("rx_crc_errors", self.rx_crc_errors),
+            ("rx_frame_errors", self.rx_frame_errors),
+            ("rx_fifo_errors", 0),
+            ("rx_missed_errors", 0),
+            ("tx_aborted_errors", 0),
+            ("tx_carrier_errors", 0),
+            ("tx_fifo_errors", 0),
+            ("tx_heartbeat_errors", 0),
+            ("tx_window_errors", 0),
+            ("rx_compressed", 0),
+            ("tx_compressed", 0),
+            ("rx_nohandler", 0),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_counters() {
+        let counters = Counters::default();
+        counters.rx(1420);
+        counters.rx(60);
+        counters.rx_error(RxError::Crc);
+        counters.rx_error(RxError::Frame);
+        counters.rx_error(RxError::Length);
+        counters.rx_dropped();
+        counters.tx(100);
+        counters.tx_error();
+        counters.tx_dropped();
+        counters.tx_dropped();
+
+        let stats = counters.get();
+        assert_eq!(
+            stats,
+            InterfaceStats {
+                rx_packets: 2,
+                rx_bytes: 1480,
+                rx_errors: 3,
+                rx_dropped: 1,
+                rx_length_errors: 1,
+                rx_crc_errors: 1,
+                rx_frame_errors: 1,
+                tx_packets: 1,
+                tx_bytes: 100,
+                tx_errors: 1,
+                tx_dropped: 2,
+            }
+        );
+        assert_eq!(stats.rx_frame(), 3);
+    }
+
+    #[test]
+    fn test_counters_threads() {
+        let counters = Arc::new(Counters::default());
+        let threads: Vec<_> = (0..2 * SHARDS)
+            .map(|_| {
+                let counters = counters.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        counters.rx(100);
+                        counters.tx_dropped();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // the shards of every thread are summed
+        let stats = counters.get();
+        let threads = 2 * SHARDS as u64;
+        assert_eq!(stats.rx_packets, threads * 1000);
+        assert_eq!(stats.rx_bytes, threads * 100_000);
+        assert_eq!(stats.tx_dropped, threads * 1000);
+    }
+
+    #[test]
+    fn test_link_stats64() {
+        let stats = InterfaceStats {
+            rx_packets: 1,
+            tx_packets: 2,
+            rx_bytes: 3,
+            tx_bytes: 4,
+            rx_errors: 5,
+            tx_errors: 6,
+            rx_dropped: 7,
+            tx_dropped: 8,
+            rx_length_errors: 9,
+            rx_crc_errors: 10,
+            rx_frame_errors: 11,
+        };
+        let fields = stats.link_stats64();
+        assert_eq!(
+            fields[..8].iter().map(|(_, v)| *v).collect::<Vec<_>>(),
+            (1..=8).collect::<Vec<_>>()
+        );
+        assert_eq!(fields[10], ("rx_length_errors", 9));
+        assert_eq!(fields[12], ("rx_crc_errors", 10));
+        assert_eq!(fields[13], ("rx_frame_errors", 11));
+        assert_eq!(fields[LINK_STATS64_FIELDS - 1], ("rx_nohandler", 0));
+        assert_eq!(fields.iter().map(|(_, v)| v).sum::<u64>(), 66);
+    }
+}
@@ -1,26 +1,36 @@
-use std::collections::HashMap;
+use std::net::IpAddr;
 use std::ops::Deref;
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 
+use crossbeam_channel::{bounded, Receiver};
 use log;
 use spin::{Mutex, RwLock};
-use zerocopy::LayoutVerified;
+use zerocopy::{AsBytes, LayoutVerified};
 
 use super::anti_replay::AntiReplay;
+use super::buffer::PacketBuffer;
 
-use super::constants::PARALLEL_QUEUE_SIZE;
+use super::constants::{DEFAULT_MAX_STAGED_AGE, PARALLEL_QUEUE_SIZE};
+use super::counters::{Counters, InterfaceStats};
+use super::flows::Direction;
+use super::idmap::IdMap;
+use super::ip::{clamp_length, inner_length};
 use super::messages::{TransportHeader, TYPE_TRANSPORT};
 use super::peer::{new_peer, Peer, PeerHandle};
-use super::types::{Callbacks, RouterError};
-use super::SIZE_MESSAGE_PREFIX;
+use super::types::{Action, Callbacks, PacketFilter, RouterError};
+use super::{REJECT_AFTER_MESSAGES, SIZE_MESSAGE_PREFIX, SIZE_TAG};
 
 use super::receive::ReceiveJob;
 use super::route::RoutingTable;
-use super::worker::{worker, JobUnion};
+use super::worker::{busy_worker, busy_writer, worker, JobUnion};
 
+use super::super::affinity::pin_to_core;
+use super::super::crypto::{Backend, Selected};
 use super::super::{tun, udp, Endpoint, KeyPair};
+use super::Memory;
 use super::ParallelQueue;
 
 pub struct DeviceInner<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> {
@@ -31,11 +41,135 @@ pub struct DeviceInner<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer
     pub(super) outbound: RwLock<(bool, Option<B>)>,
 
     // routing
-    pub(super) recv: RwLock<HashMap<u32, Arc<DecryptionState<E, C, T, B>>>>, // receiver id -> decryption state
+    pub(super) recv: IdMap<Arc<DecryptionState<E, C, T, B>>>, // receiver id -> decryption state
     pub(super) table: RoutingTable<Peer<E, C, T, B>>,
 
     // work queue
     pub(super) work: ParallelQueue<JobUnion<E, C, T, B>>,
+
+    // memory accounting (shared with the rest of the WireGuard device)
+    pub(super) memory: Arc<Memory>,
+
+    // addresses of the interface for which echo requests are answered (empty = disabled)
+    pub(super) echo: RwLock<Vec<IpAddr>>,
+
+    // filter of inner packets (if any)
+    pub(super) filter: RwLock<Option<Arc<dyn PacketFilter<C::Opaque>>>>,
+
+    // idle timeout of tracked flows in seconds (0 = flow tracking disabled)
+    pub(super) flow_timeout: AtomicU64,
+
+    // relay packets between peers by default (see RelayPolicy)
+    pub(super) relay: AtomicBool,
+
+    // maximum age of staged packets in milliseconds (0 = staged packets never expire)
+    pub(super) max_staged_age: AtomicU64,
+
+    // counters of the interface (see InterfaceStats)
+    pub(super) counters: Counters,
+
+    // the stages of the performance mode busy poll while the router is up
+    // (and block while it is down)
+    pub(super) spin: Arc<AtomicBool>,
+}
+
+impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> DeviceInner<E, C, T, B> {
+    pub(super) fn flow_timeout(&self) -> Option<Duration> {
+        match self.flow_timeout.load(Ordering::Relaxed) {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+
+    pub(super) fn max_staged_age(&self) -> Option<Duration> {
+        match self.max_staged_age.load(Ordering::Relaxed) {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        }
+    }
+
+    /* Cryptkey routes and sends a plaintext message (IP packet),
+     * used for packets read from the TUN device and packets relayed between peers.
+     *
+     * Relayed packets originate from the workers, which drain the work queue:
+     * these must not block (block = false) and are dropped if the work queue is full.
+     */
+    pub(super) fn send(&self, msg: Vec<u8>, block: bool) -> Result<(), RouterError> {
+        // ignore header prefix (for in-place transport message construction)
+        let packet = &msg[SIZE_MESSAGE_PREFIX..];
+
+        // lookup peer based on IP packet destination address
+        let peer = match self.table.get_route(packet) {
+            Some(peer) => peer,
+            None => {
+                self.counters.tx_error();
+                return Err(RouterError::NoCryptoKeyRoute);
+            }
+        };
+
+        // apply the filter (to the packet without padding)
+        let filter = self.filter.read().clone();
+        let (peer, msg) = match filter {
+            None => (peer, msg),
+            Some(filter) => {
+                let len = inner_length(packet)
+                    .unwrap_or(packet.len())
+                    .min(packet.len());
+                match filter.outbound(&peer.opaque, &packet[..len]) {
+                    Action::Allow => (peer, msg),
+                    Action::Drop => {
+                        log::trace!("send, packet dropped by filter");
+                        self.counters.tx_dropped();
+                        return Ok(());
+                    }
+                    Action::Modify(packet) => {
+                        // the modified packet is routed anew
+                        let peer = match self.table.get_route(&packet) {
+                            Some(peer) => peer,
+                            None => {
+                                self.counters.tx_error();
+                                return Err(RouterError::NoCryptoKeyRoute);
+                            }
+                        };
+                        (peer, PacketBuffer::padded(&packet).into_message())
+                    }
+                }
+            }
+        };
+
+        // remove the padding beyond the MTU of the peer (if any)
+        let mut msg = msg;
+        let mtu = peer.mtu.load(Ordering::Relaxed);
+        if mtu > 0 {
+            match clamp_length(&msg[SIZE_MESSAGE_PREFIX..], mtu) {
+                Some(len) => msg.truncate(SIZE_MESSAGE_PREFIX + len),
+                None => {
+                    log::trace!("send, packet exceeds the MTU of the peer ({})", mtu);
+                    self.counters.tx_error();
+                    return Err(RouterError::ExceedsMtu);
+                }
+            }
+        }
+
+        // account the packet to its flow
+        if let Some(timeout) = self.flow_timeout() {
+            peer.flows
+                .record(&msg[SIZE_MESSAGE_PREFIX..], Direction::Outbound, timeout);
+        }
+
+        // schedule for encryption and transmission to peer
+        if block {
+            peer.send(msg, true);
+        } else if !peer.try_send(msg, true) {
+            return Err(RouterError::QueueFull);
+        }
+        Ok(())
+    }
+
+    /// Returns true if packets between peers are relayed by default
+    pub(super) fn relay(&self) -> bool {
+        self.relay.load(Ordering::Relaxed)
+    }
 }
 
 pub struct EncryptionState {
@@ -103,17 +237,34 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Drop
 }
 
 impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> DeviceHandle<E, C, T, B> {
-    pub fn new(num_workers: usize, tun: T) -> DeviceHandle<E, C, T, B> {
-        let (work, mut consumers) = ParallelQueue::new(num_workers, PARALLEL_QUEUE_SIZE);
+    // creates the device state and the receivers of the work queue
+    fn device(
+        num_workers: usize,
+        tun: T,
+    ) -> (Device<E, C, T, B>, Vec<Receiver<JobUnion<E, C, T, B>>>) {
+        let (work, consumers) = ParallelQueue::new(num_workers, PARALLEL_QUEUE_SIZE);
         let device = Device {
             inner: Arc::new(DeviceInner {
                 work,
                 inbound: tun,
                 outbound: RwLock::new((true, None)),
-                recv: RwLock::new(HashMap::new()),
+                recv: IdMap::new(),
                 table: RoutingTable::new(),
+                memory: Arc::new(Memory::new()),
+                echo: RwLock::new(vec![]),
+                filter: RwLock::new(None),
+                flow_timeout: AtomicU64::new(0),
+                relay: AtomicBool::new(false),
+                max_staged_age: AtomicU64::new(DEFAULT_MAX_STAGED_AGE.as_millis() as u64),
+                counters: Counters::default(),
+                spin: Arc::new(AtomicBool::new(false)),
             }),
         };
+        (device, consumers)
+    }
+
+    pub fn new(num_workers: usize, tun: T) -> DeviceHandle<E, C, T, B> {
+        let (device, mut consumers) = Self::device(num_workers, tun);
 
         // start worker threads
         let mut threads = Vec::with_capacity(num_workers);
@@ -134,6 +285,43 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> DeviceHandle<
         }
     }
 
+    /// Create a device for the performance mode:
+    /// a single crypto thread and a single writer thread,
+    /// each pinned to a dedicated core and busy-polling (rather than blocking)
+    /// while the router is up
+    ///
+    /// # Arguments
+    ///
+    /// - `tun`: The TUN writer
+    /// - `crypto`: Core of the thread encrypting / decrypting messages
+    /// - `writer`: Core of the thread writing the messages in order
+    pub fn new_pinned(tun: T, crypto: usize, writer: usize) -> DeviceHandle<E, C, T, B> {
+        let (device, mut consumers) = Self::device(1, tun);
+        let rx = consumers.pop().unwrap();
+        let (ring_tx, ring_rx) = bounded(PARALLEL_QUEUE_SIZE);
+        let (crypto_spin, writer_spin) = (device.spin.clone(), device.spin.clone());
+
+        let threads = vec![
+            thread::spawn(move || {
+                if !pin_to_core(crypto) {
+                    log::warn!("router: failed to pin crypto stage to core {}", crypto);
+                }
+                busy_worker(rx, ring_tx, crypto_spin)
+            }),
+            thread::spawn(move || {
+                if !pin_to_core(writer) {
+                    log::warn!("router: failed to pin writer stage to core {}", writer);
+                }
+                busy_writer(ring_rx, writer_spin)
+            }),
+        ];
+
+        DeviceHandle {
+            state: device,
+            handles: threads,
+        }
+    }
+
     pub fn send_raw(&self, msg: &[u8], dst: &mut E) -> Result<(), B::Error> {
         let bind = self.state.outbound.read();
         if bind.0 {
@@ -144,17 +332,91 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> DeviceHandle<
         return Ok(());
     }
 
+    /// Returns the memory accounting state of the device,
+    /// which covers the buffers of all queued messages and the state of every peer.
+    pub fn memory(&self) -> &Arc<Memory> {
+        &self.state.memory
+    }
+
+    /// Sets the addresses of the interface for which ICMP(v6) echo requests
+    /// are answered directly by the router (rather than written to the TUN device),
+    /// hence pings to the interface succeed regardless of the configuration of the host.
+    ///
+    /// An empty list disables the responder.
+    pub fn set_echo_addresses(&self, addrs: Vec<IpAddr>) {
+        *self.state.echo.write() = addrs;
+    }
+
+    pub fn get_echo_addresses(&self) -> Vec<IpAddr> {
+        self.state.echo.read().clone()
+    }
+
+    /// Sets the filter invoked on inner packets to and from every peer
+    /// (None removes the filter)
+    pub fn set_filter(&self, filter: Option<Arc<dyn PacketFilter<C::Opaque>>>) {
+        *self.state.filter.write() = filter;
+    }
+
+    /// Enables tracking of the inner flows (5-tuples) to and from every peer,
+    /// flows idle for longer than the timeout are expired.
+    ///
+    /// None disables flow tracking.
+    pub fn set_flow_timeout(&self, timeout: Option<Duration>) {
+        let secs = timeout.map(|t| t.as_secs().max(1)).unwrap_or(0);
+        self.state.flow_timeout.store(secs, Ordering::Relaxed);
+    }
+
+    pub fn get_flow_timeout(&self) -> Option<Duration> {
+        self.state.flow_timeout()
+    }
+
+    /// Sets the maximum age of packets staged while no key-pair is available:
+    /// older packets are dropped rather than sent once a key-pair is confirmed,
+    /// e.g. a stalled handshake does not release a burst of stale real-time packets.
+    ///
+    /// None keeps staged packets until sent (or displaced by newer packets).
+    pub fn set_max_staged_age(&self, age: Option<Duration>) {
+        let ms = age.map(|t| (t.as_millis() as u64).max(1)).unwrap_or(0);
+        self.state.max_staged_age.store(ms, Ordering::Relaxed);
+    }
+
+    pub fn get_max_staged_age(&self) -> Option<Duration> {
+        self.state.max_staged_age()
+    }
+
+    /// Returns the counters of the interface
+    /// (in the buckets of a kernel network interface, see InterfaceStats)
+    pub fn interface_stats(&self) -> InterfaceStats {
+        self.state.counters.get()
+    }
+
+    /// Sets whether packets from a peer destined to (the allowed IPs of) another peer
+    /// are re-encrypted and forwarded to the other peer by the router (hub-and-spoke),
+    /// rather than written to the TUN device (leaving forwarding to the host).
+    ///
+    /// Applies to peers with the default relay policy (see PeerHandle::set_relay_policy).
+    pub fn set_relay(&self, relay: bool) {
+        self.state.relay.store(relay, Ordering::Relaxed);
+    }
+
+    pub fn get_relay(&self) -> bool {
+        self.state.relay()
+    }
+
     /// Brings the router down.
     /// When the router is brought down it:
     /// - Prevents transmission of outbound messages.
+    /// - Parks the stages of the performance mode (they block rather than busy poll).
     pub fn down(&self) {
         self.state.outbound.write().0 = false;
+        self.state.spin.store(false, Ordering::Relaxed);
     }
 
     /// Brints the router up
     /// When the router is brought up it enables the transmission of outbound messages.
     pub fn up(&self) {
         self.state.outbound.write().0 = true;
+        self.state.spin.store(true, Ordering::Relaxed);
     }
 
     /// A new secret key has been set for the device.
@@ -186,19 +448,7 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> DeviceHandle<
             hex::encode(&msg[SIZE_MESSAGE_PREFIX..])
         );
 
-        // ignore header prefix (for in-place transport message construction)
-        let packet = &msg[SIZE_MESSAGE_PREFIX..];
-
-        // lookup peer based on IP packet destination address
-        let peer = self
-            .state
-            .table
-            .get_route(packet)
-            .ok_or(RouterError::NoCryptoKeyRoute)?;
-
-        // schedule for encryption and transmission to peer
-        peer.send(msg, true);
-        Ok(())
+        self.state.send(msg, true)
     }
 
     /// Receive an encrypted transport message
@@ -236,9 +486,10 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> DeviceHandle<
         );
 
         // lookup peer based on receiver id
-        let dec = self.state.recv.read();
-        let dec = dec
-            .get(&header.f_receiver.get())
+        let dec = self
+            .state
+            .recv
+            .get(header.f_receiver.get())
             .ok_or(RouterError::UnknownReceiverId)?;
 
         // create inbound job
@@ -252,6 +503,59 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> DeviceHandle<
         Ok(())
     }
 
+    /// Determine the peer a transport message belongs to
+    /// and whether the message would be accepted,
+    /// without altering the state of the router (used for diagnostics).
+    ///
+    /// # Arguments
+    ///
+    /// - msg: Encrypted transport message (untrusted input)
+    /// - f: Applied to the opaque value of the peer associated with the receiver id
+    ///
+    /// # Returns
+    ///
+    /// The result of f (if a peer was found) and the reason the message would be rejected (if any).
+    pub fn inspect<R, F: FnOnce(&C::Opaque) -> R>(
+        &self,
+        msg: &[u8],
+        f: F,
+    ) -> (Option<R>, Result<(), RouterError>) {
+        // parse header
+        let (header, _): (LayoutVerified<&[u8], TransportHeader>, _) =
+            match LayoutVerified::new_from_prefix(msg) {
+                Some(v) => v,
+                None => return (None, Err(RouterError::MalformedTransportMessage)),
+            };
+
+        // lookup peer based on receiver id
+        let dec = match self.state.recv.get(header.f_receiver.get()) {
+            Some(dec) => dec,
+            None => return (None, Err(RouterError::UnknownReceiverId)),
+        };
+        let res = f(&dec.peer.opaque);
+
+        // attempt to open (and authenticate) a copy of the body
+        let mut body = msg[SIZE_MESSAGE_PREFIX..].to_vec();
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(header.f_counter.as_bytes());
+        if Selected::open(&dec.keypair.recv.key, &nonce, &[], &mut body).is_err() {
+            return (Some(res), Err(RouterError::AuthenticationFailed));
+        }
+
+        // check counter against replay protector (without marking it)
+        let counter = header.f_counter.get();
+        if counter >= REJECT_AFTER_MESSAGES || !dec.protector.lock().check(counter) {
+            return (Some(res), Err(RouterError::Replay));
+        }
+
+        // check crypto-key router
+        let packet = &body[..body.len() - SIZE_TAG];
+        if !packet.is_empty() && !self.state.table.check_route(&dec.peer, packet) {
+            return (Some(res), Err(RouterError::NoCryptoKeyRoute));
+        }
+        (Some(res), Ok(()))
+    }
+
     /// Set outbound writer
     ///
     ///
@@ -0,0 +1,56 @@
+/* Relay encapsulation: messages to a peer behind a symmetric NAT are framed toward a relay,
+ * until the direct path to the peer is confirmed by a handshake (see PeerHandle::set_encapsulation).
+ *
+ * [ type (TYPE_RELAY_FRAME) | tag | WireGuard message ]
+ *
+ * The relay forwards the frame to the peer registered under the tag,
+ * rewriting the tag to that of the sender.
+ * The tag of received frames is not interpreted, since the enclosed message is authenticated.
+ */
+use core::mem;
+
+use zerocopy::LayoutVerified;
+
+use super::messages::{RelayHeader, TYPE_RELAY_FRAME};
+
+pub const SIZE_RELAY_HEADER: usize = mem::size_of::<RelayHeader>();
+
+/// Frames the message for the peer registered under the tag at the relay
+pub fn frame(tag: u32, msg: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0u8; SIZE_RELAY_HEADER + msg.len()];
+    let (mut header, body): (LayoutVerified<&mut [u8], RelayHeader>, &mut [u8]) =
+        LayoutVerified::new_from_prefix(&mut frame[..]).expect("the frame has room for the header");
+    header.f_type.set(TYPE_RELAY_FRAME);
+    header.f_tag.set(tag);
+    body.copy_from_slice(msg);
+    frame
+}
+
+/// Returns the tag and the enclosed message of a relay frame
+/// (None if the message is not a relay frame)
+pub fn unframe(frame: &[u8]) -> Option<(u32, &[u8])> {
+    let (header, msg): (LayoutVerified<&[u8], RelayHeader>, _) =
+        LayoutVerified::new_from_prefix(frame)?;
+    if header.f_type.get() != TYPE_RELAY_FRAME {
+        return None;
+    }
+    Some((header.f_tag.get(), msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame() {
+        let msg = [4u8, 0, 0, 0, 1, 2, 3];
+        let framed = frame(0xdead_beef, &msg);
+        assert_eq!(framed.len(), SIZE_RELAY_HEADER + msg.len());
+        assert_eq!(&framed[..8], &[0x10, 0, 0, 0, 0xef, 0xbe, 0xad, 0xde]);
+        assert_eq!(unframe(&framed), Some((0xdead_beef, &msg[..])));
+
+        // other messages and truncated frames
+        assert_eq!(unframe(&msg), None);
+        assert_eq!(unframe(&framed[..4]), None);
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+use byteorder::{BigEndian, ByteOrder};
+use spin::Mutex;
+
+use super::super::{Flow, FlowStats};
+use super::ip::{inner_length, VERSION_IP4, VERSION_IP6};
+
+// bound on the number of flows tracked per peer (new flows are not tracked beyond this)
+pub const MAX_FLOWS_PER_PEER: usize = 1024;
+
+const PROTOCOL_TCP: u8 = 6;
+const PROTOCOL_UDP: u8 = 17;
+const PROTOCOL_SCTP: u8 = 132;
+
+const SIZE_IP4_MIN_HEADER: usize = 20;
+const SIZE_IP6_HEADER: usize = 40;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,  // received from the peer
+    Outbound, // sent to the peer
+}
+
+fn ports(protocol: u8, transport: &[u8]) -> (u16, u16) {
+    match protocol {
+        PROTOCOL_TCP | PROTOCOL_UDP | PROTOCOL_SCTP if transport.len() >= 4 => (
+            BigEndian::read_u16(&transport[0..2]),
+            BigEndian::read_u16(&transport[2..4]),
+        ),
+        _ => (0, 0),
+    }
+}
+
+/// Extracts the 5-tuple of an inner packet,
+/// returns (protocol, source, source port, destination, destination port, length)
+fn parse(packet: &[u8]) -> Option<(u8, IpAddr, u16, IpAddr, u16, usize)> {
+    let len = inner_length(packet)?;
+    if len == 0 || len > packet.len() {
+        return None;
+    }
+    let packet = &packet[..len];
+    match packet[0] >> 4 {
+        VERSION_IP4 => {
+            let ihl = ((packet[0] & 0xf) as usize) * 4;
+            if ihl < SIZE_IP4_MIN_HEADER || packet.len() < ihl {
+                return None;
+            }
+            let mut src = [0u8; 4];
+            let mut dst = [0u8; 4];
+            src.copy_from_slice(&packet[12..16]);
+            dst.copy_from_slice(&packet[16..20]);
+
+            // only the first fragment carries the ports
+            let offset = BigEndian::read_u16(&packet[6..8]) & 0x1fff;
+            let (sport, dport) = if offset == 0 {
+                ports(packet[9], &packet[ihl..])
+            } else {
+                (0, 0)
+            };
+            Some((
+                packet[9],
+                IpAddr::V4(Ipv4Addr::from(src)),
+                sport,
+                IpAddr::V4(Ipv4Addr::from(dst)),
+                dport,
+                len,
+            ))
+        }
+        VERSION_IP6 => {
+            // extension headers are not followed: the ports are only read
+            // when the transport header follows the IPv6 header
+            let mut src = [0u8; 16];
+            let mut dst = [0u8; 16];
+            src.copy_from_slice(&packet[8..24]);
+            dst.copy_from_slice(&packet[24..40]);
+            let (sport, dport) = ports(packet[6], &packet[SIZE_IP6_HEADER..]);
+            Some((
+                packet[6],
+                IpAddr::V6(Ipv6Addr::from(src)),
+                sport,
+                IpAddr::V6(Ipv6Addr::from(dst)),
+                dport,
+                len,
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Returns the flow of an inner packet (oriented from the local side)
+/// and the length of the packet (without padding).
+pub fn flow_of(packet: &[u8], direction: Direction) -> Option<(Flow, usize)> {
+    let (protocol, src, sport, dst, dport, len) = parse(packet)?;
+    let flow = match direction {
+        Direction::Outbound => Flow {
+            protocol,
+            local: src,
+            local_port: sport,
+            remote: dst,
+            remote_port: dport,
+        },
+        Direction::Inbound => Flow {
+            protocol,
+            local: dst,
+            local_port: dport,
+            remote: src,
+            remote_port: sport,
+        },
+    };
+    Some((flow, len))
+}
+
+/* Table of the inner flows to/from a single peer.
+ *
+ * Flows idle for longer than the timeout are expired
+ * when the table is listed or when it is full.
+ */
+#[derive(Default)]
+pub struct FlowTable {
+    flows: Mutex<HashMap<Flow, FlowStats>>,
+}
+
+impl FlowTable {
+    pub fn record(&self, packet: &[u8], direction: Direction, timeout: Duration) {
+        let (flow, len) = match flow_of(packet, direction) {
+            Some(v) => v,
+            None => return,
+        };
+        let now = Instant::now();
+        let mut flows = self.flows.lock();
+        if flows.len() >= MAX_FLOWS_PER_PEER && !flows.contains_key(&flow) {
+            flows.retain(|_, stats| now.duration_since(stats.last_seen) < timeout);
+            if flows.len() >= MAX_FLOWS_PER_PEER {
+                return;
+            }
+        }
+        let stats = flows.entry(flow).or_insert(FlowStats {
+            rx_bytes: 0,
+            tx_bytes: 0,
+            rx_packets: 0,
+            tx_packets: 0,
+            last_seen: now,
+        });
+        match direction {
+            Direction::Inbound => {
+                stats.rx_bytes += len as u64;
+                stats.rx_packets += 1;
+            }
+            Direction::Outbound => {
+                stats.tx_bytes += len as u64;
+                stats.tx_packets += 1;
+            }
+        }
+        stats.last_seen = now;
+    }
+
+    pub fn list(&self, timeout: Duration) -> Vec<(Flow, FlowStats)> {
+        let now = Instant::now();
+        let mut flows = self.flows.lock();
+        flows.retain(|_, stats| now.duration_since(stats.last_seen) < timeout);
+        flows.iter().map(|(flow, stats)| (*flow, *stats)).collect()
+    }
+
+    pub fn clear(&self) {
+        self.flows.lock().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // UDP datagram from 10.0.0.2:5353 to 10.0.0.1:53 with 4 bytes of data
+    fn datagram4() -> Vec<u8> {
+        let mut packet = vec![0x45, 0, 0, 32, 0, 0, 0x40, 0, 64, PROTOCOL_UDP, 0, 0];
+        packet.extend(&[10, 0, 0, 2, 10, 0, 0, 1]);
+        packet.extend(&[0x14, 0xe9, 0, 53, 0, 12, 0, 0]);
+        packet.extend(b"abcd");
+        packet
+    }
+
+    #[test]
+    fn test_flow_of() {
+        let local: IpAddr = "10.0.0.1".parse().unwrap();
+        let remote: IpAddr = "10.0.0.2".parse().unwrap();
+
+        // padding is ignored
+        let mut packet = datagram4();
+        packet.extend(&[0u8; 16]);
+        let (flow, len) = flow_of(&packet, Direction::Inbound).unwrap();
+        assert_eq!(len, 32);
+        assert_eq!(flow.protocol, PROTOCOL_UDP);
+        assert_eq!((flow.local, flow.local_port), (local, 53));
+        assert_eq!((flow.remote, flow.remote_port), (remote, 5353));
+
+        // later fragments have no ports
+        let mut fragment = datagram4();
+        fragment[7] = 1;
+        let (flow, _) = flow_of(&fragment, Direction::Outbound).unwrap();
+        assert_eq!((flow.local, flow.local_port), (remote, 0));
+
+        assert!(flow_of(&packet[..10], Direction::Inbound).is_none());
+    }
+
+    #[test]
+    fn test_flow_table() {
+        let table = FlowTable::default();
+        let timeout = Duration::from_secs(60);
+        table.record(&datagram4(), Direction::Inbound, timeout);
+        table.record(&datagram4(), Direction::Inbound, timeout);
+
+        let flows = table.list(timeout);
+        assert_eq!(flows.len(), 1);
+        assert_eq!(flows[0].1.rx_packets, 2);
+        assert_eq!(flows[0].1.rx_bytes, 64);
+        assert_eq!(flows[0].1.tx_packets, 0);
+
+        // idle flows are expired
+        assert!(table.list(Duration::from_secs(0)).is_empty());
+        assert!(table.list(timeout).is_empty());
+    }
+}
@@ -0,0 +1,197 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use super::ip::{inner_length, VERSION_IP4, VERSION_IP6};
+
+const PROTOCOL_ICMP: u8 = 1;
+const PROTOCOL_ICMP6: u8 = 58;
+
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP6_ECHO_REQUEST: u8 = 128;
+const ICMP6_ECHO_REPLY: u8 = 129;
+
+const SIZE_IP4_MIN_HEADER: usize = 20;
+const SIZE_IP6_HEADER: usize = 40;
+const SIZE_ICMP_HEADER: usize = 8;
+
+// hop limit of generated replies
+const REPLY_TTL: u8 = 64;
+
+// one's complement sum of the big-endian 16-bit words (odd lengths padded with zero)
+fn sum(data: &[u8], mut acc: u32) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for word in &mut chunks {
+        acc += BigEndian::read_u16(word) as u32;
+    }
+    if let Some(&last) = chunks.remainder().first() {
+        acc += (last as u32) << 8;
+    }
+    acc
+}
+
+// folds and complements a one's complement sum
+fn checksum(mut acc: u32) -> u16 {
+    while acc >> 16 != 0 {
+        acc = (acc & 0xffff) + (acc >> 16);
+    }
+    !(acc as u16)
+}
+
+/// Answers an ICMP(v6) echo request addressed to one of the given addresses.
+///
+/// # Arguments
+///
+/// - `packet`: The (decrypted) inner IP packet, possibly followed by padding
+/// - `addrs`: The addresses of the interface
+///
+/// # Returns
+///
+/// The echo reply (an IP packet) if the packet is an echo request to one of the addresses,
+/// None otherwise (the packet should be handled as usual).
+pub fn echo_reply(packet: &[u8], addrs: &[IpAddr]) -> Option<Vec<u8>> {
+    let len = inner_length(packet)?;
+    if len > packet.len() {
+        return None;
+    }
+    let packet = &packet[..len];
+    match packet[0] >> 4 {
+        VERSION_IP4 => echo_reply4(packet, addrs),
+        VERSION_IP6 => echo_reply6(packet, addrs),
+        _ => None,
+    }
+}
+
+fn echo_reply4(packet: &[u8], addrs: &[IpAddr]) -> Option<Vec<u8>> {
+    let ihl = ((packet[0] & 0xf) as usize) * 4;
+    if ihl < SIZE_IP4_MIN_HEADER || packet.len() < ihl + SIZE_ICMP_HEADER {
+        return None;
+    }
+
+    // only unfragmented echo requests to the interface
+    let fragmented = BigEndian::read_u16(&packet[6..8]) & 0x3fff != 0;
+    let mut dst = [0u8; 4];
+    dst.copy_from_slice(&packet[16..20]);
+    if packet[9] != PROTOCOL_ICMP
+        || fragmented
+        || packet[ihl] != ICMP_ECHO_REQUEST
+        || !addrs.contains(&IpAddr::V4(Ipv4Addr::from(dst)))
+    {
+        return None;
+    }
+
+    // swap the addresses and recompute the header checksum
+    let mut reply = packet.to_vec();
+    reply[12..16].copy_from_slice(&packet[16..20]);
+    reply[16..20].copy_from_slice(&packet[12..16]);
+    reply[8] = REPLY_TTL;
+    reply[10..12].copy_from_slice(&[0, 0]);
+    let csum = checksum(sum(&reply[..ihl], 0));
+    BigEndian::write_u16(&mut reply[10..12], csum);
+
+    // turn the request into a reply (the identifier, sequence number and data are echoed)
+    reply[ihl] = ICMP_ECHO_REPLY;
+    reply[ihl + 2..ihl + 4].copy_from_slice(&[0, 0]);
+    let csum = checksum(sum(&reply[ihl..], 0));
+    BigEndian::write_u16(&mut reply[ihl + 2..ihl + 4], csum);
+    Some(reply)
+}
+
+fn echo_reply6(packet: &[u8], addrs: &[IpAddr]) -> Option<Vec<u8>> {
+    // extension headers are not supported: the ICMPv6 header must follow the IPv6 header
+    if packet.len() < SIZE_IP6_HEADER + SIZE_ICMP_HEADER {
+        return None;
+    }
+    let mut dst = [0u8; 16];
+    dst.copy_from_slice(&packet[24..40]);
+    if packet[6] != PROTOCOL_ICMP6
+        || packet[SIZE_IP6_HEADER] != ICMP6_ECHO_REQUEST
+        || !addrs.contains(&IpAddr::V6(Ipv6Addr::from(dst)))
+    {
+        return None;
+    }
+
+    // swap the addresses (IPv6 has no header checksum)
+    let mut reply = packet.to_vec();
+    reply[8..24].copy_from_slice(&packet[24..40]);
+    reply[24..40].copy_from_slice(&packet[8..24]);
+    reply[7] = REPLY_TTL;
+
+    // turn the request into a reply, the checksum covers the pseudo-header
+    let body = SIZE_IP6_HEADER;
+    reply[body] = ICMP6_ECHO_REPLY;
+    reply[body + 2..body + 4].copy_from_slice(&[0, 0]);
+    let mut acc = sum(&reply[8..40], 0);
+    acc += (reply.len() - body) as u32;
+    acc += PROTOCOL_ICMP6 as u32;
+    let csum = checksum(sum(&reply[body..], acc));
+    BigEndian::write_u16(&mut reply[body + 2..body + 4], csum);
+    Some(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // echo request from 10.0.0.2 to 10.0.0.1 (id = 1, seq = 1, data = "abcd")
+    fn request4() -> Vec<u8> {
+        let mut packet = vec![0x45, 0, 0, 32, 0, 0, 0x40, 0, 64, PROTOCOL_ICMP, 0, 0];
+        packet.extend(&[10, 0, 0, 2, 10, 0, 0, 1]);
+        packet.extend(&[ICMP_ECHO_REQUEST, 0, 0, 0, 0, 1, 0, 1]);
+        packet.extend(b"abcd");
+        let csum = checksum(sum(&packet[..20], 0));
+        BigEndian::write_u16(&mut packet[10..12], csum);
+        let csum = checksum(sum(&packet[20..], 0));
+        BigEndian::write_u16(&mut packet[22..24], csum);
+        packet
+    }
+
+    #[test]
+    fn test_echo_reply4() {
+        let own: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.3".parse().unwrap();
+
+        // padding is ignored
+        let mut request = request4();
+        request.extend(&[0u8; 16]);
+        let reply = echo_reply(&request, &[own]).unwrap();
+        assert_eq!(reply.len(), 32);
+        assert_eq!(&reply[12..16], &[10, 0, 0, 1]);
+        assert_eq!(&reply[16..20], &[10, 0, 0, 2]);
+        assert_eq!(reply[20], ICMP_ECHO_REPLY);
+        assert_eq!(&reply[24..], &request[24..32]);
+
+        // valid checksums sum to zero
+        assert_eq!(checksum(sum(&reply[..20], 0)), 0);
+        assert_eq!(checksum(sum(&reply[20..], 0)), 0);
+
+        // other destinations and other messages are not answered
+        assert!(echo_reply(&request, &[other]).is_none());
+        let mut reply_to_reply = request4();
+        reply_to_reply[20] = ICMP_ECHO_REPLY;
+        assert!(echo_reply(&reply_to_reply, &[own]).is_none());
+    }
+
+    #[test]
+    fn test_echo_reply6() {
+        let src: Ipv6Addr = "fd00::2".parse().unwrap();
+        let dst: Ipv6Addr = "fd00::1".parse().unwrap();
+        let mut request = vec![0x60, 0, 0, 0, 0, 12, PROTOCOL_ICMP6, 64];
+        request.extend(&src.octets());
+        request.extend(&dst.octets());
+        request.extend(&[ICMP6_ECHO_REQUEST, 0, 0, 0, 0, 1, 0, 1]);
+        request.extend(b"abcd");
+
+        let reply = echo_reply(&request, &[IpAddr::V6(dst)]).unwrap();
+        assert_eq!(&reply[8..24], &dst.octets());
+        assert_eq!(&reply[24..40], &src.octets());
+        assert_eq!(reply[40], ICMP6_ECHO_REPLY);
+
+        // verify the checksum (including the pseudo-header)
+        let acc = sum(&reply[8..40], 0) + 12 + PROTOCOL_ICMP6 as u32;
+        assert_eq!(checksum(sum(&reply[40..], acc)), 0);
+
+        assert!(echo_reply(&request, &[IpAddr::V6(src)]).is_none());
+    }
+}
@@ -0,0 +1,122 @@
+/* Map from receiver id to decryption state, read on every transport message.
+ *
+ * Lookups never take a lock: the map is split into shards (by the low bits of the id,
+ * which are chosen uniformly at random by the handshake), each an atomically replaced snapshot.
+ * Updates (once per handshake) copy the shard and publish the copy,
+ * a replaced shard is reclaimed once the last lookup holding it completes (see arc_swap).
+ */
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+use spin::Mutex;
+
+const SHARDS: usize = 64; // power of 2
+
+pub struct IdMap<V> {
+    shards: Vec<ArcSwap<HashMap<u32, V>>>,
+    writer: Mutex<()>, // serializes updates (no update of a shard is lost)
+}
+
+impl<V: Clone> IdMap<V> {
+    pub fn new() -> IdMap<V> {
+        IdMap {
+            shards: (0..SHARDS)
+                .map(|_| ArcSwap::from_pointee(HashMap::new()))
+                .collect(),
+            writer: Mutex::new(()),
+        }
+    }
+
+    #[inline(always)]
+    fn shard(&self, id: u32) -> &ArcSwap<HashMap<u32, V>> {
+        &self.shards[id as usize & (SHARDS - 1)]
+    }
+
+    /// Returns the value associated with the id (lock-free)
+    pub fn get(&self, id: u32) -> Option<V> {
+        self.shard(id).load().get(&id).cloned()
+    }
+
+    pub fn contains_key(&self, id: u32) -> bool {
+        self.shard(id).load().contains_key(&id)
+    }
+
+    pub fn insert(&self, id: u32, value: V) -> Option<V> {
+        self.update(id, |map| map.insert(id, value))
+    }
+
+    pub fn remove(&self, id: u32) -> Option<V> {
+        // most ids released by a peer are no longer mapped: avoid copying the shard
+        if !self.contains_key(id) {
+            return None;
+        }
+        self.update(id, |map| map.remove(&id))
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.load().len()).sum()
+    }
+
+    fn update<R, F: FnOnce(&mut HashMap<u32, V>) -> R>(&self, id: u32, f: F) -> R {
+        let _writer = self.writer.lock();
+        let shard = self.shard(id);
+        let mut map = HashMap::clone(&shard.load());
+        let res = f(&mut map);
+        shard.store(Arc::new(map));
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn test_idmap() {
+        let map = IdMap::new();
+        assert_eq!(map.insert(1, "a"), None);
+        assert_eq!(map.insert(1 + SHARDS as u32, "b"), None); // same shard
+        assert_eq!(map.insert(1, "c"), Some("a"));
+        assert_eq!(map.get(1), Some("c"));
+        assert_eq!(map.get(1 + SHARDS as u32), Some("b"));
+        assert_eq!(map.get(2), None);
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.remove(1), Some("c"));
+        assert_eq!(map.remove(1), None);
+        assert!(!map.contains_key(1));
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn test_idmap_concurrent() {
+        let map = Arc::new(IdMap::new());
+        for id in 0..256 {
+            map.insert(id, id);
+        }
+
+        // lookups proceed while other ids are inserted and removed
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let map = map.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        for id in 0..256 {
+                            assert_eq!(map.get(id), Some(id));
+                        }
+                    }
+                })
+            })
+            .collect();
+        for id in 256..1024 {
+            map.insert(id, id);
+            map.remove(id);
+        }
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(map.len(), 256);
+    }
+}
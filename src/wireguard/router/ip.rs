@@ -47,3 +47,187 @@ pub fn inner_length(packet: &[u8]) -> Option<usize> {
         _ => None,
     }
 }
+
+/// Decrements the TTL (IPv4) or hop limit (IPv6) of a packet being relayed,
+/// returns false if the packet must be dropped (limit exceeded or malformed packet).
+pub fn decrement_ttl(packet: &mut [u8]) -> bool {
+    match packet.get(0).map(|v| v >> 4) {
+        Some(VERSION_IP4) if packet.len() >= mem::size_of::<IPv4Header>() => {
+            if packet[8] <= 1 {
+                return false;
+            }
+            packet[8] -= 1;
+
+            // incremental update of the header checksum (RFC 1624)
+            let sum = u16::from_be_bytes([packet[10], packet[11]]) as u32 + 0x0100;
+            let sum = ((sum & 0xffff) + (sum >> 16)) as u16;
+            packet[10..12].copy_from_slice(&sum.to_be_bytes());
+            true
+        }
+        Some(VERSION_IP6) if packet.len() >= mem::size_of::<IPv6Header>() => {
+            if packet[7] <= 1 {
+                return false;
+            }
+            packet[7] -= 1;
+            true
+        }
+        _ => false,
+    }
+}
+
+/* Fragmentation fields of the IPv4 header (RFC 791) */
+const IP4_FLAG_DF: u16 = 0x4000;
+const IP4_FLAG_MF: u16 = 0x2000;
+const IP4_OFFSET_MASK: u16 = 0x1fff;
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|w| u16::from_be_bytes([w[0], w[1]]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Returns the length to which a (padded) packet is truncated to fit within `mtu` bytes,
+/// i.e. padding beyond the MTU is removed.
+///
+/// Returns None if the packet itself (without padding) exceeds the MTU.
+pub fn clamp_length(packet: &[u8], mtu: usize) -> Option<usize> {
+    if packet.len() <= mtu {
+        return Some(packet.len());
+    }
+    let len = inner_length(packet)
+        .unwrap_or(packet.len())
+        .min(packet.len());
+    if len > mtu {
+        None
+    } else {
+        Some(mtu)
+    }
+}
+
+/// Splits an IPv4 packet into fragments of at most `max` bytes (including the header),
+/// the options of the header are copied into every fragment.
+///
+/// Returns None if the packet is not IPv4, fits within `max`,
+/// may not be fragmented (DF is set) or is malformed.
+pub fn fragment_ipv4(packet: &[u8], max: usize) -> Option<Vec<Vec<u8>>> {
+    if packet.get(0)? >> 4 != VERSION_IP4 {
+        return None;
+    }
+    let ihl = (packet[0] & 0x0f) as usize * 4;
+    let total = u16::from_be_bytes([*packet.get(2)?, *packet.get(3)?]) as usize;
+    if ihl < mem::size_of::<IPv4Header>() || total < ihl || total > packet.len() {
+        return None;
+    }
+    if total <= max {
+        return None;
+    }
+
+    let flags = u16::from_be_bytes([packet[6], packet[7]]);
+    if flags & IP4_FLAG_DF != 0 {
+        return None;
+    }
+
+    // the data of every fragment but the last is a multiple of 8 bytes
+    let chunk = max.checked_sub(ihl)? & !7;
+    if chunk == 0 {
+        return None;
+    }
+
+    // the packet may itself be a fragment
+    let offset = flags & IP4_OFFSET_MASK;
+    let more = flags & IP4_FLAG_MF;
+
+    let header = &packet[..ihl];
+    let data = &packet[ihl..total];
+    let fragments = data
+        .chunks(chunk)
+        .enumerate()
+        .map(|(i, part)| {
+            let last = (i + 1) * chunk >= data.len();
+            let flags = (offset + (i * chunk / 8) as u16) | if last { more } else { IP4_FLAG_MF };
+
+            let mut fragment = Vec::with_capacity(ihl + part.len());
+            fragment.extend_from_slice(header);
+            fragment.extend_from_slice(part);
+            fragment[2..4].copy_from_slice(&((ihl + part.len()) as u16).to_be_bytes());
+            fragment[6..8].copy_from_slice(&flags.to_be_bytes());
+            fragment[10..12].copy_from_slice(&[0, 0]);
+            let checksum = ipv4_checksum(&fragment[..ihl]);
+            fragment[10..12].copy_from_slice(&checksum.to_be_bytes());
+            fragment
+        })
+        .collect();
+    Some(fragments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ipv4(len: usize, flags: u16) -> Vec<u8> {
+        let mut packet = vec![0u8; len];
+        packet[0] = 0x45;
+        packet[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+        packet[6..8].copy_from_slice(&flags.to_be_bytes());
+        packet[8] = 64;
+        packet[9] = 17;
+        for (i, b) in packet[20..].iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let checksum = ipv4_checksum(&packet[..20]);
+        packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn test_clamp_length() {
+        // padded to 1040 bytes
+        let mut packet = ipv4(1000, 0);
+        packet.resize(1040, 0);
+
+        assert_eq!(clamp_length(&packet, 1420), Some(1040));
+        assert_eq!(clamp_length(&packet, 1040), Some(1040));
+        assert_eq!(clamp_length(&packet, 1020), Some(1020));
+        assert_eq!(clamp_length(&packet, 1000), Some(1000));
+        assert_eq!(clamp_length(&packet, 999), None);
+
+        // malformed packets are not truncated
+        assert_eq!(clamp_length(&[0u8; 100], 80), None);
+    }
+
+    #[test]
+    fn test_fragment_ipv4() {
+        let packet = ipv4(1020, 0);
+
+        // fits, may not be fragmented or not IPv4
+        assert!(fragment_ipv4(&packet, 1020).is_none());
+        assert!(fragment_ipv4(&ipv4(1020, IP4_FLAG_DF), 500).is_none());
+        assert!(fragment_ipv4(&[0x60; 1020], 500).is_none());
+
+        // 1000 bytes of data in fragments of at most 500 bytes: 480 + 480 + 40
+        let fragments = fragment_ipv4(&packet, 500).unwrap();
+        let lens: Vec<usize> = fragments.iter().map(|f| f.len()).collect();
+        assert_eq!(lens, vec![500, 500, 60]);
+
+        let mut data = vec![];
+        for (i, fragment) in fragments.iter().enumerate() {
+            assert_eq!(inner_length(fragment), Some(fragment.len()));
+            assert_eq!(ipv4_checksum(&fragment[..20]), 0);
+            let flags = u16::from_be_bytes([fragment[6], fragment[7]]);
+            assert_eq!(flags & IP4_OFFSET_MASK, (i * 480 / 8) as u16);
+            assert_eq!(flags & IP4_FLAG_MF != 0, i < 2);
+            data.extend_from_slice(&fragment[20..]);
+        }
+        assert_eq!(&data[..], &packet[20..]);
+
+        // fragmenting a fragment retains its offset and MF flag
+        let fragments = fragment_ipv4(&ipv4(1020, IP4_FLAG_MF | 10), 500).unwrap();
+        let flags = u16::from_be_bytes([fragments[2][6], fragments[2][7]]);
+        assert_eq!(flags, IP4_FLAG_MF | (10 + 120));
+    }
+}
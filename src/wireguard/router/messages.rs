@@ -11,3 +11,13 @@ pub struct TransportHeader {
     pub f_receiver: U32<LittleEndian>,
     pub f_counter: U64<LittleEndian>,
 }
+
+// type of relay frames (outside the range of WireGuard message types)
+pub const TYPE_RELAY_FRAME: u32 = 0x10;
+
+#[repr(packed)]
+#[derive(Copy, Clone, FromBytes, AsBytes)]
+pub struct RelayHeader {
+    pub f_type: U32<LittleEndian>,
+    pub f_tag: U32<LittleEndian>,
+}
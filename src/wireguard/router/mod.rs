@@ -1,6 +1,12 @@
 mod anti_replay;
+mod buffer;
 mod constants;
+mod counters;
 mod device;
+mod encap;
+mod flows;
+mod icmp;
+mod idmap;
 mod ip;
 mod messages;
 mod peer;
@@ -18,6 +24,7 @@ mod tests;
 use messages::TransportHeader;
 
 use super::constants::REJECT_AFTER_MESSAGES;
+use super::memory::{Memory, Reservation};
 use super::queue::ParallelQueue;
 use super::types::*;
 
@@ -31,7 +38,24 @@ pub const fn message_data_len(payload: usize) -> usize {
     payload + mem::size_of::<TransportHeader>() + SIZE_TAG
 }
 
+// largest UDP payload of an IPv4 datagram (65535 - IPv4 header - UDP header),
+// larger transport messages cannot be transmitted (even with outer fragmentation).
+pub const SIZE_MAX_UDP_PAYLOAD: usize = 65535 - 20 - 8;
+
+// largest UDP payload of any datagram (65535 - UDP header, the IPv6 limit without jumbograms),
+// which bounds every message received (handshake, transport and relay frames).
+pub const SIZE_MAX_DATAGRAM: usize = 65535 - 8;
+
+// largest IP packet (including padding) which fits inside a single transport message
+pub const SIZE_MAX_INNER_PACKET: usize = SIZE_MAX_UDP_PAYLOAD - message_data_len(0);
+
+pub use anti_replay::AntiReplay;
+pub use buffer::PacketBuffer;
+pub use constants::DEFAULT_MAX_STAGED_AGE;
+pub use counters::InterfaceStats;
 pub use device::DeviceHandle as Device;
-pub use messages::TYPE_TRANSPORT;
+pub use encap::unframe;
+pub use ip::{fragment_ipv4, inner_length};
+pub use messages::{TransportHeader, TYPE_RELAY_FRAME, TYPE_TRANSPORT};
 pub use peer::PeerHandle;
-pub use types::Callbacks;
+pub use types::{Action, Callbacks, PacketFilter, RouterError};
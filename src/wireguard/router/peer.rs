@@ -1,14 +1,22 @@
 use super::super::constants::*;
-use super::super::{tun, udp, Endpoint, KeyPair};
+use super::super::udp::{WriteError, WriteErrorKind};
+use super::super::{
+    tun, udp, Encapsulation, Endpoint, EndpointPolicy, Flow, FlowStats, KeyPair, RelayPolicy,
+    RelayStats, SendErrors, SessionInfo, Source,
+};
 
 use super::anti_replay::AntiReplay;
+use super::buffer::PacketBuffer;
 use super::device::DecryptionState;
 use super::device::Device;
 use super::device::EncryptionState;
+use super::encap;
+use super::flows::FlowTable;
+use super::route::contains;
 
 use super::constants::*;
 use super::types::{Callbacks, RouterError};
-use super::SIZE_MESSAGE_PREFIX;
+use super::{Reservation, SIZE_MESSAGE_PREFIX};
 
 use super::queue::Queue;
 use super::receive::ReceiveJob;
@@ -17,13 +25,14 @@ use super::worker::JobUnion;
 
 use core::mem;
 use core::ops::Deref;
-use core::sync::atomic::AtomicBool;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 
 use alloc::sync::Arc;
 
 // TODO: consider no_std alternatives
 use std::fmt;
 use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
 
 use arraydeque::{ArrayDeque, Wrapping};
 use log;
@@ -34,17 +43,97 @@ pub struct KeyWheel {
     current: Option<Arc<KeyPair>>,  // current key state (used for encryption)
     previous: Option<Arc<KeyPair>>, // old key state (used for decryption)
     retired: Vec<u32>,              // retired ids
+    rekey: bool,                    // discard current key state once the next is confirmed
 }
 
+/* Counters of failed sends, by class of error */
+#[derive(Default)]
+pub struct SendErrorCounters {
+    unreachable: AtomicU64,
+    permission: AtomicU64,
+    nobufs: AtomicU64,
+    too_big: AtomicU64,
+    other: AtomicU64,
+}
+
+impl SendErrorCounters {
+    fn record(&self, kind: WriteErrorKind) {
+        let counter = match kind {
+            WriteErrorKind::Unreachable => &self.unreachable,
+            WriteErrorKind::Permission => &self.permission,
+            WriteErrorKind::NoBuffers => &self.nobufs,
+            WriteErrorKind::TooBig => &self.too_big,
+            WriteErrorKind::Other => &self.other,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> SendErrors {
+        SendErrors {
+            unreachable: self.unreachable.load(Ordering::Relaxed),
+            permission: self.permission.load(Ordering::Relaxed),
+            nobufs: self.nobufs.load(Ordering::Relaxed),
+            too_big: self.too_big.load(Ordering::Relaxed),
+            other: self.other.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/* Counters of packets from the peer relayed to other peers */
+#[derive(Default)]
+pub struct RelayCounters {
+    packets: AtomicU64,
+    bytes: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl RelayCounters {
+    pub(super) fn forwarded(&self, len: usize) {
+        self.packets.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub(super) fn dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> RelayStats {
+        RelayStats {
+            packets: self.packets.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// a message without payload (only the prefix reserved for the header)
+fn is_keepalive(msg: &[u8]) -> bool {
+    msg.len() == SIZE_MESSAGE_PREFIX
+}
+
+// packets awaiting a key-pair: (message, accounted memory, staged at)
+type StagedPackets = ArrayDeque<[(Vec<u8>, Reservation, Instant); MAX_QUEUED_PACKETS], Wrapping>;
+
 pub struct PeerInner<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> {
     pub(super) device: Device<E, C, T, B>,
     pub(super) opaque: C::Opaque,
     pub(super) outbound: Queue<SendJob<E, C, T, B>>,
     pub(super) inbound: Queue<ReceiveJob<E, C, T, B>>,
-    pub(super) staged_packets: Mutex<ArrayDeque<[Vec<u8>; MAX_QUEUED_PACKETS], Wrapping>>,
+    pub(super) staged_packets: Mutex<StagedPackets>,
     pub(super) keys: Mutex<KeyWheel>,
     pub(super) enc_key: Mutex<Option<EncryptionState>>,
     pub(super) endpoint: Mutex<Option<E>>,
+    pub(super) endpoint_changes: AtomicU64, // times the endpoint was set or learned anew
+    pub(super) roaming: Mutex<(EndpointPolicy, Option<u16>)>, // (policy, port of learned endpoints)
+    pub(super) source: Mutex<Source>,       // pinned source of outbound messages
+    pub(super) hop_limit: Mutex<Option<u8>>, // hop limit of outbound messages (None = of the socket)
+    pub(super) mtu: AtomicUsize, // inner MTU toward the peer (0 = the MTU of the device)
+    pub(super) encap: Mutex<(Option<Encapsulation>, bool)>, // (relay encapsulation, direct path confirmed)
+    pub(super) send_errors: SendErrorCounters,
+    pub(super) flows: FlowTable, // inner flows (if tracking is enabled)
+    pub(super) relay: Mutex<RelayPolicy>, // relaying of packets to other peers
+    pub(super) relayed: RelayCounters,
+    pub(super) _memory: Reservation, // accounting of the peer state
 }
 
 /// A Peer dereferences to its opaque type:
@@ -149,6 +238,8 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Drop for Peer
         // remove from cryptkey router
 
         self.peer.device.table.remove(peer);
+        self.peer.device.table.remove_policy(peer);
+        self.peer.device.table.remove_ecmp(peer);
 
         // release ids from the receiver map
 
@@ -159,11 +250,8 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Drop for Peer
         keys.current.as_ref().map(|k| release.push(k.recv.id));
         keys.previous.as_ref().map(|k| release.push(k.recv.id));
 
-        if release.len() > 0 {
-            let mut recv = peer.device.recv.write();
-            for id in &release {
-                recv.remove(id);
-            }
+        for id in &release {
+            peer.device.recv.remove(*id);
         }
 
         // null key-material
@@ -186,19 +274,34 @@ pub fn new_peer<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>>(
     // allocate peer object
     let peer = {
         let device = device.clone();
+        let _memory = device
+            .memory
+            .reserve(mem::size_of::<PeerInner<E, C, T, B>>());
         Peer {
             inner: Arc::new(PeerInner {
+                _memory,
                 opaque,
                 device,
                 inbound: Queue::new(),
                 outbound: Queue::new(),
                 enc_key: spin::Mutex::new(None),
                 endpoint: spin::Mutex::new(None),
+                endpoint_changes: AtomicU64::new(0),
+                roaming: spin::Mutex::new((EndpointPolicy::default(), None)),
+                source: spin::Mutex::new(Source::default()),
+                hop_limit: spin::Mutex::new(None),
+                mtu: AtomicUsize::new(0),
+                encap: spin::Mutex::new((None, false)),
+                send_errors: SendErrorCounters::default(),
+                flows: FlowTable::default(),
+                relay: spin::Mutex::new(RelayPolicy::default()),
+                relayed: RelayCounters::default(),
                 keys: spin::Mutex::new(KeyWheel {
                     next: None,
                     current: None,
                     previous: None,
                     retired: vec![],
+                    rekey: false,
                 }),
                 staged_packets: spin::Mutex::new(ArrayDeque::new()),
             }),
@@ -209,7 +312,68 @@ pub fn new_peer<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>>(
 }
 
 impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PeerInner<E, C, T, B> {
-    /// Send a raw message to the peer (used for handshake messages)
+    /// Returns true if packets from the peer destined to another peer are relayed
+    pub(super) fn relays(&self) -> bool {
+        match *self.relay.lock() {
+            RelayPolicy::Default => self.device.relay(),
+            RelayPolicy::Forward => true,
+            RelayPolicy::Deliver => false,
+        }
+    }
+
+    /// Updates the endpoint to the source of an authenticated message,
+    /// according to the endpoint policy of the peer
+    pub(super) fn roam(&self, endpoint: E) {
+        let (policy, port) = *self.roaming.lock();
+        let addr = endpoint.into_address();
+
+        // messages enclosed in relay frames are not from the endpoint of the peer
+        if let (Some(encap), _) = *self.encap.lock() {
+            if encap.relay == addr {
+                log::trace!("peer.roam, source is the relay");
+                return;
+            }
+        }
+
+        let mut current = self.endpoint.lock();
+        match policy {
+            EndpointPolicy::Learn => (),
+            EndpointPolicy::Fixed if current.is_none() => (),
+            EndpointPolicy::Fixed => return,
+            EndpointPolicy::LearnWithin(ip, cidr) => {
+                if !contains((ip, cidr), addr.ip()) {
+                    log::trace!("peer.roam, source outside of the permitted subnet");
+                    return;
+                }
+            }
+        }
+
+        // the port override replaces the port of the source
+        // (the sticky source of the endpoint is then selected anew)
+        let endpoint = match port {
+            Some(port) if port != addr.port() => E::from_address(SocketAddr::new(addr.ip(), port)),
+            _ => endpoint,
+        };
+        if self.update_endpoint(&mut current, endpoint) {
+            log::debug!("peer.roam, endpoint changed to {}", addr);
+        }
+    }
+
+    /* Replaces the endpoint unless unchanged (e.g. every handshake of a stable peer)
+     *
+     * Returns true if the endpoint changed
+     */
+    fn update_endpoint(&self, current: &mut Option<E>, endpoint: E) -> bool {
+        if current.as_ref() == Some(&endpoint) {
+            return false;
+        }
+        *current = Some(endpoint);
+        self.endpoint_changes.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Send a raw message to the peer (used for handshake messages),
+    /// enclosed in a relay frame until the direct path is confirmed (see PeerHandle::set_encapsulation)
     ///
     /// # Arguments
     ///
@@ -219,23 +383,55 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PeerInner<E,
     ///
     /// Unit if packet was sent, or an error indicating why sending failed
     pub fn send_raw(&self, msg: &[u8]) -> Result<(), RouterError> {
+        match *self.encap.lock() {
+            (Some(encap), false) => self.send_relayed(encap, msg),
+            _ => self.send_direct(msg),
+        }
+    }
+
+    /// Send a raw message to the endpoint of the peer (bypassing the relay)
+    pub fn send_direct(&self, msg: &[u8]) -> Result<(), RouterError> {
         // send to endpoint (if known)
         match self.endpoint.lock().as_mut() {
             Some(endpoint) => {
-                let outbound = self.device.outbound.read();
-                if outbound.0 {
-                    outbound
-                        .1
-                        .as_ref()
-                        .ok_or(RouterError::SendError)
-                        .and_then(|w| w.write(msg, endpoint).map_err(|_| RouterError::SendError))
-                } else {
-                    Ok(())
+                // apply the pinned source
+                // (the source of the endpoint is updated on every received message)
+                let source = *self.source.lock();
+                if source.is_pinned() {
+                    endpoint.set_src(source.ifindex, source.addr);
                 }
+                self.write(msg, endpoint)
             }
             None => Err(RouterError::NoEndpoint),
         }
     }
+
+    /// Send a raw message to the peer enclosed in a relay frame
+    fn send_relayed(&self, encap: Encapsulation, msg: &[u8]) -> Result<(), RouterError> {
+        let mut relay = E::from_address(encap.relay);
+        self.write(&encap::frame(encap.tag, msg), &mut relay)
+    }
+
+    fn write(&self, msg: &[u8], endpoint: &mut E) -> Result<(), RouterError> {
+        endpoint.set_hop_limit(*self.hop_limit.lock());
+
+        let outbound = self.device.outbound.read();
+        if outbound.0 {
+            outbound
+                .1
+                .as_ref()
+                .ok_or(RouterError::SendError)
+                .and_then(|w| {
+                    w.write(msg, endpoint).map_err(|e| {
+                        log::trace!("peer.send_raw, failed to send: {}", e);
+                        self.send_errors.record(e.kind());
+                        RouterError::SendError
+                    })
+                })
+        } else {
+            Ok(())
+        }
+    }
 }
 
 impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Peer<E, C, T, B> {
@@ -246,36 +442,61 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Peer<E, C, T,
     /// - `msg` : A padded vector holding the message (allows in-place construction of the transport header)
     /// - `stage`: Should the message be staged if no key is available
     ///
+    /// A key is requested once for the staged messages (by the first message staged),
+    /// while every message which is not staged requests a key.
     pub(super) fn send(&self, msg: Vec<u8>, stage: bool) {
+        self.schedule(msg, stage, true);
+    }
+
+    /// Encrypt and send a message to the peer without waiting for room in the work queue,
+    /// for messages originating from the workers (which drain the work queue, hence must never block on it).
+    ///
+    /// Returns false if the message was dropped (the work queue is full).
+    pub(super) fn try_send(&self, msg: Vec<u8>, stage: bool) -> bool {
+        self.schedule(msg, stage, false)
+    }
+
+    fn schedule(&self, msg: Vec<u8>, stage: bool, block: bool) -> bool {
         // check if key available
         let (job, need_key) = {
             let mut enc_key = self.enc_key.lock();
             match enc_key.as_mut() {
                 None => {
                     log::debug!("no key encryption key available");
-                    if stage {
-                        self.staged_packets.lock().push_back(msg);
-                    };
-                    (None, true)
+                    (None, !stage || self.stage(msg))
                 }
                 Some(mut state) => {
                     // avoid integer overflow in nonce
                     if state.nonce >= REJECT_AFTER_MESSAGES - 1 {
                         log::debug!("encryption key expired");
                         *enc_key = None;
-                        if stage {
-                            self.staged_packets.lock().push_back(msg);
-                        }
-                        (None, true)
+                        (None, !stage || self.stage(msg))
                     } else {
                         log::debug!("encryption state available, nonce = {}", state.nonce);
                         let job =
                             SendJob::new(msg, state.nonce, state.keypair.clone(), self.clone());
-                        if self.outbound.push(job.clone()) {
+                        if !self.outbound.push(job.clone()) {
+                            self.device.counters.tx_dropped();
+                            return false;
+                        }
+                        if block {
                             state.nonce += 1;
                             (Some(job), false)
                         } else {
-                            (None, false)
+                            // schedule while holding the key (no other job is pushed meanwhile),
+                            // hence the job is withdrawn from the back of the in-order queue if the work queue is full
+                            match self.device.work.try_send(JobUnion::Outbound(job)) {
+                                Ok(()) => {
+                                    state.nonce += 1;
+                                    return true;
+                                }
+                                Err(_) => {
+                                    log::debug!("work queue full, dropping outbound job");
+                                    self.outbound.pop_back();
+                                    self.device.counters.tx_dropped();
+                                    return false;
+                                }
+                            }
                         }
                     }
                 }
@@ -292,16 +513,73 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Peer<E, C, T,
             log::debug!("schedule outbound job");
             self.device.work.send(JobUnion::Outbound(job))
         }
+        true
     }
 
-    // Transmit all staged packets
+    // Stage a packet until a key becomes available.
+    //
+    // The queue is bounded: when full (or the memory limit of the device has been reached)
+    // the oldest staged packets of the peer are dropped to make room.
+    // Keepalives carry no data, hence only the newest keepalive is staged.
+    //
+    // Returns true if no packets were staged before,
+    // i.e. the packet is the first awaiting a key (and must request one).
+    fn stage(&self, msg: Vec<u8>) -> bool {
+        let mut staged = self.staged_packets.lock();
+        self.expire_staged(&mut staged);
+        let first = staged.is_empty();
+        if is_keepalive(&msg) {
+            if let Some(idx) = staged.iter().position(|(msg, _, _)| is_keepalive(msg)) {
+                log::trace!("replacing staged keepalive");
+                staged.remove(idx);
+            }
+        }
+        let reservation = loop {
+            match self.device.memory.try_reserve(msg.capacity()) {
+                Some(reservation) => break reservation,
+                None => {
+                    self.device.counters.tx_dropped();
+                    if staged.pop_front().is_none() {
+                        log::debug!("memory limit reached, dropping staged packet");
+                        return first;
+                    }
+                    log::debug!("memory limit reached, dropped oldest staged packet");
+                }
+            }
+        };
+        if staged
+            .push_back((msg, reservation, Instant::now()))
+            .is_some()
+        {
+            self.device.counters.tx_dropped();
+        }
+        first
+    }
+
+    // Drop the staged packets older than the maximum age of the device (if any)
+    // (the packets are staged in order, hence the oldest are at the front)
+    fn expire_staged(&self, staged: &mut StagedPackets) {
+        if let Some(age) = self.device.max_staged_age() {
+            while staged
+                .front()
+                .map_or(false, |(_, _, at)| at.elapsed() > age)
+            {
+                log::debug!("dropping staged packet older than {:?}", age);
+                staged.pop_front();
+                self.device.counters.tx_dropped();
+            }
+        }
+    }
+
+    // Transmit all staged packets (which have not expired)
     fn send_staged(&self) -> bool {
         log::trace!("peer.send_staged");
         let mut sent = false;
         let mut staged = self.staged_packets.lock();
+        self.expire_staged(&mut staged);
         loop {
             match staged.pop_front() {
-                Some(msg) => {
+                Some((msg, _, _)) => {
                     sent = true;
                     self.send(msg, false);
                 }
@@ -334,6 +612,15 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Peer<E, C, T,
             mem::swap(&mut keys.current, &mut swap);
             mem::swap(&mut keys.previous, &mut swap);
 
+            // on requested rekey: discard the prior key state
+            if keys.rekey {
+                keys.rekey = false;
+                if let Some(k) = keys.previous.take() {
+                    self.device.recv.remove(k.local_id());
+                    keys.retired.push(k.local_id());
+                }
+            }
+
             // tell the world outside the router that a key was confirmed
             C::key_confirmed(&self.opaque);
 
@@ -359,7 +646,39 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PeerHandle<E,
     /// as sockets should be "unsticked" when manually updating the endpoint
     pub fn set_endpoint(&self, endpoint: E) {
         log::trace!("peer.set_endpoint");
-        *self.peer.endpoint.lock() = Some(endpoint);
+        let mut current = self.peer.endpoint.lock();
+        self.peer.update_endpoint(&mut current, endpoint);
+    }
+
+    /// Returns the number of times the endpoint changed (set or learned from the peer)
+    pub fn get_endpoint_changes(&self) -> u64 {
+        self.peer.endpoint_changes.load(Ordering::Relaxed)
+    }
+
+    /// Update the endpoint to the source of an authenticated (handshake) message,
+    /// according to the endpoint policy of the peer (see set_endpoint_policy)
+    pub fn roam_endpoint(&self, endpoint: E) {
+        log::trace!("peer.roam_endpoint");
+        self.peer.roam(endpoint);
+    }
+
+    /// Set how the endpoint is learned from the source of authenticated messages
+    pub fn set_endpoint_policy(&self, policy: EndpointPolicy) {
+        self.peer.roaming.lock().0 = policy;
+    }
+
+    pub fn get_endpoint_policy(&self) -> EndpointPolicy {
+        self.peer.roaming.lock().0
+    }
+
+    /// Set the port of learned endpoints (None = the source port of the message),
+    /// e.g. to send to 51820 while the peer sends from another port
+    pub fn set_endpoint_port(&self, port: Option<u16>) {
+        self.peer.roaming.lock().1 = port;
+    }
+
+    pub fn get_endpoint_port(&self) -> Option<u16> {
+        self.peer.roaming.lock().1
     }
 
     pub fn opaque(&self) -> &C::Opaque {
@@ -391,17 +710,265 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PeerHandle<E,
         keys.retired.extend(&release[..]);
 
         // update inbound "recv" map
-        {
-            let mut recv = self.peer.device.recv.write();
-            for id in release {
-                recv.remove(&id);
-            }
+        for id in release {
+            self.peer.device.recv.remove(id);
         }
 
         // clear encryption state
         *self.peer.enc_key.lock() = None;
     }
 
+    /// Remove expired key-material from the peer
+    ///
+    /// # Arguments
+    ///
+    /// - `lifetime`: Key-pairs derived longer than `lifetime` ago are removed
+    ///
+    /// # Returns
+    ///
+    /// A vector of ids which has been released (including previously retired ids).
+    /// These should be released in the handshake module.
+    pub fn expire_keys(&self, lifetime: Duration) -> Vec<u32> {
+        log::trace!("peer.expire_keys");
+
+        fn expire(key: &mut Option<Arc<KeyPair>>, lifetime: Duration) -> Option<u32> {
+            if key
+                .as_ref()
+                .map_or(false, |k| k.birth.elapsed() >= lifetime)
+            {
+                key.take().map(|k| k.local_id())
+            } else {
+                None
+            }
+        }
+
+        let mut keys = self.peer.keys.lock();
+        let mut release = mem::replace(&mut keys.retired, vec![]);
+
+        // update key-wheel
+        let mut expired: Vec<u32> = Vec::with_capacity(3);
+        if let Some(id) = expire(&mut keys.next, lifetime) {
+            expired.push(id);
+        }
+        if let Some(id) = expire(&mut keys.current, lifetime) {
+            *self.peer.enc_key.lock() = None;
+            expired.push(id);
+        }
+        if let Some(id) = expire(&mut keys.previous, lifetime) {
+            expired.push(id);
+        }
+
+        // update inbound "recv" map
+        for id in &expired {
+            self.peer.device.recv.remove(*id);
+        }
+
+        release.extend(expired);
+        release
+    }
+
+    /// Returns the (local) ids of all key-pairs held by the peer
+    /// (including retired ids, not yet released in the handshake module)
+    pub fn local_ids(&self) -> Vec<u32> {
+        let keys = self.peer.keys.lock();
+        let mut ids = keys.retired.clone();
+        ids.extend(
+            [&keys.next, &keys.current, &keys.previous]
+                .iter()
+                .filter_map(|key| key.as_ref())
+                .map(|key| key.local_id()),
+        );
+        ids
+    }
+
+    /// Returns metadata of the current session
+    /// (None if no key-pair is available for encryption)
+    pub fn session(&self) -> Option<SessionInfo> {
+        let (keypair, sent) = match self.peer.enc_key.lock().as_ref() {
+            Some(state) => (state.keypair.clone(), state.nonce),
+            None => return None,
+        };
+        let (received, replay) = self
+            .peer
+            .device
+            .recv
+            .read()
+            .get(&keypair.local_id())
+            .map(|state| {
+                let protector = state.protector.lock();
+                (protector.highest(), protector.stats())
+            })
+            .unwrap_or_default();
+        Some(SessionInfo {
+            local_id: keypair.local_id(),
+            remote_id: keypair.send.id,
+            age: keypair.birth.elapsed(),
+            sent,
+            received,
+            replay,
+        })
+    }
+
+    /// Pins the outgoing interface of messages to the peer
+    ///
+    /// # Arguments
+    ///
+    /// - `ifindex`: The index of the interface (0 = selected by the routing table)
+    pub fn set_source_interface(&self, ifindex: u32) {
+        log::trace!("peer.set_source_interface");
+        self.peer.source.lock().ifindex = ifindex;
+        self.clear_src();
+    }
+
+    /// Pins the source address of messages to the peer
+    ///
+    /// # Arguments
+    ///
+    /// - `addr`: The source address (None = selected by the routing table)
+    pub fn set_source_address(&self, addr: Option<IpAddr>) {
+        log::trace!("peer.set_source_address");
+        self.peer.source.lock().addr = addr;
+        self.clear_src();
+    }
+
+    /// Returns the pinned source of messages to the peer
+    pub fn get_source(&self) -> Source {
+        *self.peer.source.lock()
+    }
+
+    /// Overrides the hop limit (IPv4 TTL) of messages to the peer
+    ///
+    /// # Arguments
+    ///
+    /// - `hops`: The hop limit (None = the hop limit of the device)
+    pub fn set_hop_limit(&self, hops: Option<u8>) {
+        log::trace!("peer.set_hop_limit");
+        *self.peer.hop_limit.lock() = hops;
+    }
+
+    pub fn get_hop_limit(&self) -> Option<u8> {
+        *self.peer.hop_limit.lock()
+    }
+
+    /// Overrides the inner MTU toward the peer,
+    /// e.g. for a peer behind a path with a smaller MTU (PPPoE or nested tunnels).
+    ///
+    /// Padding is clamped to the MTU and larger packets are dropped
+    /// (the MTU should not exceed the MTU of the device).
+    ///
+    /// # Arguments
+    ///
+    /// - `mtu`: The MTU (None = the MTU of the device)
+    pub fn set_mtu(&self, mtu: Option<usize>) {
+        log::trace!("peer.set_mtu");
+        self.peer.mtu.store(mtu.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    pub fn get_mtu(&self) -> Option<usize> {
+        match self.peer.mtu.load(Ordering::Relaxed) {
+            0 => None,
+            mtu => Some(mtu),
+        }
+    }
+
+    /// Encloses the messages to the peer in frames toward the relay,
+    /// until a handshake over the direct path is authenticated (see confirm_direct).
+    /// None sends every message directly.
+    pub fn set_encapsulation(&self, encap: Option<Encapsulation>) {
+        log::trace!("peer.set_encapsulation");
+        *self.peer.encap.lock() = (encap, false);
+    }
+
+    pub fn get_encapsulation(&self) -> Option<Encapsulation> {
+        self.peer.encap.lock().0
+    }
+
+    /// Returns true if messages to the peer are currently enclosed in relay frames
+    pub fn is_relayed(&self) -> bool {
+        match *self.peer.encap.lock() {
+            (Some(_), direct) => !direct,
+            (None, _) => false,
+        }
+    }
+
+    /// Returns true if the address is the relay of the peer
+    pub fn is_relay(&self, addr: SocketAddr) -> bool {
+        self.peer.encap.lock().0.map(|encap| encap.relay) == Some(addr)
+    }
+
+    /// Confirms the direct path to the peer, when a handshake is authenticated from the source:
+    /// messages are no longer relayed (unless the source is the relay).
+    ///
+    /// # Returns
+    ///
+    /// True if the peer switched from the relay to the direct path
+    pub fn confirm_direct(&self, source: SocketAddr) -> bool {
+        let mut encap = self.peer.encap.lock();
+        match *encap {
+            (Some(relay), false) if relay.relay != source => {
+                encap.1 = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Falls back to the relay (if any), e.g. when handshakes over the direct path fail
+    pub fn fallback_relay(&self) {
+        self.peer.encap.lock().1 = false;
+    }
+
+    /// Send a raw message to the peer enclosed in a relay frame (if the peer has a relay),
+    /// e.g. the response to a handshake initiation received from the relay
+    pub fn send_relayed(&self, msg: &[u8]) -> Result<(), RouterError> {
+        match self.get_encapsulation() {
+            Some(encap) => self.peer.send_relayed(encap, msg),
+            None => Err(RouterError::NoEndpoint),
+        }
+    }
+
+    /// Returns the number of messages which could not be sent to the peer
+    pub fn send_errors(&self) -> SendErrors {
+        self.peer.send_errors.get()
+    }
+
+    /// Sets whether packets from the peer destined to another peer are relayed
+    /// (RelayPolicy::Default follows the device, see Device::set_relay)
+    pub fn set_relay_policy(&self, policy: RelayPolicy) {
+        *self.peer.relay.lock() = policy;
+    }
+
+    pub fn get_relay_policy(&self) -> RelayPolicy {
+        *self.peer.relay.lock()
+    }
+
+    /// Returns the number of packets from the peer relayed to other peers
+    pub fn relay_stats(&self) -> RelayStats {
+        self.peer.relayed.get()
+    }
+
+    /// Returns the inner flows to and from the peer
+    /// (empty if flow tracking is disabled)
+    pub fn flows(&self) -> Vec<(Flow, FlowStats)> {
+        match self.peer.device.flow_timeout() {
+            Some(timeout) => self.peer.flows.list(timeout),
+            None => vec![],
+        }
+    }
+
+    /// Forgets all the tracked flows of the peer
+    pub fn clear_flows(&self) {
+        self.peer.flows.clear();
+    }
+
+    /// Request that the current key-pair is discarded
+    /// as soon as a new key-pair has been confirmed
+    /// (rather than being retained as the previous key-pair for late packets).
+    pub fn discard_on_next_key(&self) {
+        log::trace!("peer.discard_on_next_key");
+        self.peer.keys.lock().rekey = true;
+    }
+
     pub fn down(&self) {
         self.zero_keys();
     }
@@ -450,21 +1017,28 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PeerHandle<E,
             // update incoming packet id map
             {
                 log::trace!("peer.add_keypair: updating inbound id map");
-                let mut recv = self.peer.device.recv.write();
+                let recv = &self.peer.device.recv;
 
                 // purge recv map of previous id
                 keys.previous.as_ref().map(|k| {
-                    recv.remove(&k.local_id());
+                    recv.remove(k.local_id());
                     release.push(k.local_id());
                 });
 
                 // map new id to decryption state
-                debug_assert!(!recv.contains_key(&new.recv.id));
+                debug_assert!(!recv.contains_key(new.recv.id));
                 recv.insert(
                     new.recv.id,
                     Arc::new(DecryptionState::new(self.peer.clone(), &new)),
                 );
             }
+
+            // on requested rekey: discard the prior key state
+            // (already purged from the recv map above)
+            if new.initiator && keys.rekey {
+                keys.rekey = false;
+                keys.previous = None;
+            }
             release
         };
 
@@ -488,9 +1062,36 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PeerHandle<E,
         release
     }
 
+    /// Send a keepalive, which is staged (replacing any staged keepalive) if no key is available
     pub fn send_keepalive(&self) {
         log::trace!("peer.send_keepalive");
-        self.peer.send(vec![0u8; SIZE_MESSAGE_PREFIX], false)
+        self.peer.send(PacketBuffer::new(0).into_message(), true)
+    }
+
+    /// Send a dummy transport message of `size` bytes (all padding), e.g. as cover traffic.
+    ///
+    /// The message is only sent within the current session:
+    /// without a key it is dropped (rather than staged) and no key is requested.
+    ///
+    /// Returns false if the message was dropped.
+    pub fn send_cover(&self, size: usize) -> bool {
+        log::trace!("peer.send_cover");
+        if self.peer.enc_key.lock().is_none() {
+            return false;
+        }
+        self.peer
+            .send(PacketBuffer::new(size).into_message(), false);
+        true
+    }
+
+    /// Returns true if messages are queued for encryption / transmission to the peer
+    pub fn outbound_pending(&self) -> bool {
+        !self.peer.outbound.is_empty()
+    }
+
+    /// Returns the number of packets staged until a key becomes available
+    pub fn staged_packets(&self) -> usize {
+        self.peer.staged_packets.lock().len()
     }
 
     /// Map a subnet to the peer
@@ -514,6 +1115,14 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PeerHandle<E,
             .insert(ip, masklen, self.peer.clone())
     }
 
+    /// Remove a subnet from the peer (if mapped to the peer)
+    pub fn remove_allowed_ip(&self, ip: IpAddr, masklen: u32) {
+        self.peer
+            .device
+            .table
+            .remove_subnet(ip, masklen, &self.peer)
+    }
+
     /// List subnets mapped to the peer
     ///
     /// # Returns
@@ -530,6 +1139,59 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PeerHandle<E,
         self.peer.device.table.remove(&self.peer)
     }
 
+    /// Route packets from the source subnet to the destination subnet to the peer
+    /// (taking precedence over the allowed IPs of every peer),
+    /// e.g. to select the exit peer by the source of the packet.
+    ///
+    /// Packets from the destination subnet to the source subnet are accepted from the peer.
+    ///
+    /// # Returns
+    ///
+    /// False if the address families of the subnets differ
+    pub fn add_policy_route(&self, source: (IpAddr, u32), destination: (IpAddr, u32)) -> bool {
+        self.peer
+            .device
+            .table
+            .insert_policy(source, destination, self.peer.clone())
+    }
+
+    /// List the (source, destination) subnets of the policy routes of the peer
+    pub fn list_policy_routes(&self) -> Vec<((IpAddr, u32), (IpAddr, u32))> {
+        self.peer.device.table.list_policy(&self.peer)
+    }
+
+    /// Remove every policy route of the peer
+    pub fn remove_policy_routes(&self) {
+        self.peer.device.table.remove_policy(&self.peer)
+    }
+
+    /// Add a subnet shared with other peers (ECMP):
+    /// outbound flows to the subnet are spread over the peers sharing it,
+    /// taking precedence over allowed IPs of equal or shorter prefixes.
+    ///
+    /// # Arguments
+    ///
+    /// - ip: Subnet mask
+    /// - masklen: Length of the subnet mask
+    pub fn add_ecmp_ip(&self, ip: IpAddr, masklen: u32) {
+        // the address of the peer is stable (for its lifetime) and unique
+        let key = &*self.peer.inner as *const PeerInner<E, C, T, B> as usize as u64;
+        self.peer
+            .device
+            .table
+            .insert_ecmp(ip, masklen, key, self.peer.clone())
+    }
+
+    /// List the subnets shared by the peer with other peers
+    pub fn list_ecmp_ips(&self) -> Vec<(IpAddr, u32)> {
+        self.peer.device.table.list_ecmp(&self.peer)
+    }
+
+    /// Remove the peer from every shared subnet
+    pub fn remove_ecmp_ips(&self) {
+        self.peer.device.table.remove_ecmp(&self.peer)
+    }
+
     pub fn clear_src(&self) {
         (*self.peer.endpoint.lock()).as_mut().map(|e| e.clear_src());
     }
@@ -16,6 +16,14 @@ pub trait ParallelJob: Sized + SequentialJob {
     fn queue(&self) -> &Queue<Self>;
 
     fn parallel_work(&self);
+
+    /// Does the parallel work of a batch of jobs
+    /// (e.g. to seal / open the messages of the jobs in a single invocation of the backend)
+    fn parallel_batch(jobs: &[Self]) {
+        for job in jobs {
+            job.parallel_work();
+        }
+    }
 }
 
 pub struct Queue<J: SequentialJob> {
@@ -41,6 +49,16 @@ impl<J: SequentialJob> Queue<J> {
         self.queue.lock().push_back(job).is_ok()
     }
 
+    /// Removes the job most recently pushed
+    /// (used to withdraw a job which could not be scheduled for parallel work)
+    pub fn pop_back(&self) -> Option<J> {
+        self.queue.lock().pop_back()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.lock().is_empty()
+    }
+
     pub fn consume(&self) {
         // check if we are the first contender
         let pos = self.contenders.fetch_add(1, Ordering::SeqCst);
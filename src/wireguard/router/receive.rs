@@ -1,22 +1,60 @@
+use super::buffer::PacketBuffer;
+use super::counters::RxError;
 use super::device::DecryptionState;
-use super::ip::inner_length;
+use super::flows::Direction;
+use super::icmp::echo_reply;
+use super::ip::{decrement_ttl, inner_length};
 use super::messages::TransportHeader;
+use super::peer::Peer;
 use super::queue::{ParallelJob, Queue, SequentialJob};
-use super::types::Callbacks;
+use super::types::{Action, Callbacks};
+use super::Reservation;
 use super::{REJECT_AFTER_MESSAGES, SIZE_TAG};
 
+use super::super::crypto::{Backend, Open, Selected};
 use super::super::{tun, udp, Endpoint};
 
 use alloc::sync::Arc;
 use core::sync::atomic::{AtomicBool, Ordering};
-use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
 use spin::Mutex;
 use zerocopy::{AsBytes, LayoutVerified};
 
+/* Relays an inner packet from the peer to the peer owning its destination (hub-and-spoke),
+ * returns false if the destination is not another peer (the packet is then written to TUN).
+ */
+fn relay<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>>(
+    peer: &Peer<E, C, T, B>,
+    packet: &[u8],
+) -> bool {
+    match peer.device.table.get_route(packet) {
+        Some(dst) if dst != *peer => (),
+        _ => return false,
+    }
+
+    let mut msg = PacketBuffer::padded(packet);
+    if !decrement_ttl(msg.payload_mut()) {
+        log::trace!("inbound worker: relayed packet exceeds hop limit");
+        peer.relayed.dropped();
+        return true;
+    }
+
+    // the worker must not block on the work queue it drains (the packet is dropped if full)
+    log::trace!("inbound worker: relaying packet to peer");
+    match peer.device.send(msg.into_message(), false) {
+        Ok(()) => peer.relayed.forwarded(packet.len()),
+        Err(e) => {
+            log::debug!("inbound worker: failed to relay packet: {:?}", e);
+            peer.relayed.dropped();
+        }
+    }
+    true
+}
+
 struct Inner<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> {
     ready: AtomicBool,                       // job status
     buffer: Mutex<(Option<E>, Vec<u8>)>,     // endpoint & ciphertext buffer
     state: Arc<DecryptionState<E, C, T, B>>, // decryption state (keys and replay protector)
+    _memory: Reservation,                    // accounting of the buffer
 }
 
 pub struct ReceiveJob<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>>(
@@ -37,7 +75,9 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> ReceiveJob<E,
         state: Arc<DecryptionState<E, C, T, B>>,
         endpoint: E,
     ) -> ReceiveJob<E, C, T, B> {
+        let _memory = state.peer.device.memory.reserve(buffer.capacity());
         ReceiveJob(Arc::new(Inner {
+            _memory,
             ready: AtomicBool::new(false),
             buffer: Mutex::new((Some(endpoint), buffer)),
             state,
@@ -45,6 +85,55 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> ReceiveJob<E,
     }
 }
 
+impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> ReceiveJob<E, C, T, B> {
+    /* Prepares the message of the job for opening,
+     * None if the message is malformed or the counter is beyond REJECT_AFTER_MESSAGES.
+     */
+    fn open<'a>(&'a self, msg: &'a mut [u8]) -> Option<Open<'a>> {
+        debug_assert_eq!(
+            self.is_ready(),
+            false,
+            "doing parallel work on completed job"
+        );
+
+        // cast to header followed by payload
+        let (header, packet): (LayoutVerified<&mut [u8], TransportHeader>, &mut [u8]) =
+            LayoutVerified::new_from_prefix(msg)?;
+
+        // check that counter not after reject
+        if header.f_counter.get() >= REJECT_AFTER_MESSAGES {
+            self.0.state.peer.device.counters.rx_error(RxError::Crc);
+            return None;
+        }
+
+        // create nonce
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(header.f_counter.as_bytes());
+
+        // body opened (and authenticated) in-place
+        Some(Open {
+            key: &self.0.state.keypair.recv.key,
+            nonce,
+            buf: packet,
+            ok: false,
+        })
+    }
+
+    /* Checks an opened message: authentic and permitted by the crypto-key router */
+    fn check(&self, msg: &Open) -> bool {
+        let peer = &self.0.state.peer;
+        if !msg.ok {
+            peer.device.counters.rx_error(RxError::Crc);
+            false
+        } else if msg.buf.len() == SIZE_TAG || peer.device.table.check_route(peer, &msg.buf[..]) {
+            true
+        } else {
+            peer.device.counters.rx_error(RxError::Frame);
+            false
+        }
+    }
+}
+
 impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> ParallelJob
     for ReceiveJob<E, C, T, B>
 {
@@ -64,63 +153,66 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> ParallelJob
      * since this can cause dropping of packets (leaving the window) due to scheduling.
      */
     fn parallel_work(&self) {
-        debug_assert_eq!(
-            self.is_ready(),
-            false,
-            "doing parallel work on completed job"
-        );
         log::trace!("processing parallel receive job");
 
+        // a lone job is opened without the bookkeeping of a batch
+        let mut msg = self.0.buffer.lock();
+        let ok = match self.open(&mut msg.1[..]) {
+            Some(open) => {
+                let mut batch = [open];
+                Selected::open_batch(&mut batch);
+                self.check(&batch[0])
+            }
+            None => false,
+        };
+
+        // remove message in case of failure:
+        // to indicate failure and avoid later accidental use of unauthenticated data.
+        if !ok {
+            msg.1.truncate(0);
+        }
+        drop(msg);
+
+        // mark ready
+        self.0.ready.store(true, Ordering::Release);
+    }
+
+    fn parallel_batch(jobs: &[Self]) {
+        log::trace!("processing {} parallel receive jobs", jobs.len());
+
         // decrypt
+        let mut msgs: Vec<_> = jobs.iter().map(|job| job.0.buffer.lock()).collect();
+        let mut ok = vec![false; jobs.len()];
         {
-            // closure for locking
-            let job = &self.0;
-            let peer = &job.state.peer;
-            let mut msg = job.buffer.lock();
-
-            // process buffer
-            let ok = (|| {
-                // cast to header followed by payload
-                let (header, packet): (LayoutVerified<&mut [u8], TransportHeader>, &mut [u8]) =
-                    match LayoutVerified::new_from_prefix(&mut msg.1[..]) {
-                        Some(v) => v,
-                        None => return false,
-                    };
-
-                // create nonce object
-                let mut nonce = [0u8; 12];
-                debug_assert_eq!(nonce.len(), CHACHA20_POLY1305.nonce_len());
-                nonce[4..].copy_from_slice(header.f_counter.as_bytes());
-                let nonce = Nonce::assume_unique_for_key(nonce);
-                // do the weird ring AEAD dance
-                let key = LessSafeKey::new(
-                    UnboundKey::new(&CHACHA20_POLY1305, &job.state.keypair.recv.key[..]).unwrap(),
-                );
-
-                // attempt to open (and authenticate) the body
-                match key.open_in_place(nonce, Aad::empty(), packet) {
-                    Ok(_) => (),
-                    Err(_) => return false,
-                }
-
-                // check that counter not after reject
-                if header.f_counter.get() >= REJECT_AFTER_MESSAGES {
-                    return false;
+            let mut batch = Vec::with_capacity(jobs.len());
+            let mut index = Vec::with_capacity(jobs.len());
+            for (i, (job, msg)) in jobs.iter().zip(msgs.iter_mut()).enumerate() {
+                if let Some(open) = job.open(&mut msg.1[..]) {
+                    batch.push(open);
+                    index.push(i);
                 }
+            }
+            Selected::open_batch(&mut batch);
 
-                // check crypto-key router
-                packet.len() == SIZE_TAG || peer.device.table.check_route(&peer, &packet)
-            })();
+            // check crypto-key router
+            for (i, msg) in index.into_iter().zip(batch.iter()) {
+                ok[i] = jobs[i].check(msg);
+            }
+        }
 
-            // remove message in case of failure:
-            // to indicate failure and avoid later accidental use of unauthenticated data.
+        // remove message in case of failure:
+        // to indicate failure and avoid later accidental use of unauthenticated data.
+        for (msg, ok) in msgs.iter_mut().zip(ok.into_iter()) {
             if !ok {
                 msg.1.truncate(0);
             }
-        };
+        }
+        drop(msgs);
 
         // mark ready
-        self.0.ready.store(true, Ordering::Release);
+        for job in jobs {
+            job.0.ready.store(true, Ordering::Release);
+        }
     }
 }
 
@@ -149,7 +241,8 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> SequentialJob
             match LayoutVerified::new_from_prefix(&msg.1[..]) {
                 Some(v) => v,
                 None => {
-                    // also covers authentication failure (will fail to parse header)
+                    // also covers authentication failure (will fail to parse header),
+                    // accounted to the interface by the parallel work
                     return;
                 }
             };
@@ -157,6 +250,7 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> SequentialJob
         // check for replay
         if !job.state.protector.lock().update(header.f_counter.get()) {
             log::debug!("inbound worker: replay detected");
+            peer.device.counters.rx_dropped();
             return;
         }
 
@@ -166,16 +260,91 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> SequentialJob
             peer.confirm_key(&job.state.keypair);
         }
 
-        // update endpoint
-        *peer.endpoint.lock() = endpoint;
+        // update endpoint (roaming)
+        if let Some(endpoint) = endpoint {
+            peer.roam(endpoint);
+        }
+
+        // apply the filter (to the packet without padding)
+        let body = &packet[..packet.len() - SIZE_TAG];
+        let filter = peer.device.filter.read().clone();
+        let modified = match (filter, inner_length(body)) {
+            (Some(filter), Some(inner)) if inner <= body.len() => {
+                match filter.inbound(&peer.opaque, &body[..inner]) {
+                    Action::Allow => None,
+                    Action::Drop => {
+                        log::trace!("inbound worker: packet dropped by filter");
+                        peer.device.counters.rx_dropped();
+                        C::recv(&peer.opaque, msg.1.len(), true, &job.state.keypair);
+                        return;
+                    }
+                    Action::Modify(packet) => {
+                        // the modified packet is subject to crypto-key routing
+                        if !peer.device.table.check_route(&peer, &packet) {
+                            log::debug!("inbound worker: modified packet fails crypto-key routing");
+                            peer.device.counters.rx_error(RxError::Frame);
+                            C::recv(&peer.opaque, msg.1.len(), true, &job.state.keypair);
+                            return;
+                        }
+                        Some(packet)
+                    }
+                }
+            }
+            _ => None,
+        };
 
-        // check if should be written to TUN
-        // (keep-alive and malformed packets will have no inner length)
-        if let Some(inner) = inner_length(packet) {
-            if inner + SIZE_TAG <= packet.len() {
-                let _ = peer.device.inbound.write(&packet[..inner]).map_err(|e| {
-                    log::debug!("failed to write inbound packet to TUN: {:?}", e);
-                });
+        // account the packet to its flow
+        if let Some(timeout) = peer.device.flow_timeout() {
+            peer.flows.record(
+                modified.as_ref().map(|p| &p[..]).unwrap_or(body),
+                Direction::Inbound,
+                timeout,
+            );
+        }
+
+        // answer echo requests to the interface (instead of writing them to TUN)
+        let reply = {
+            let addrs = peer.device.echo.read();
+            if addrs.is_empty() {
+                None
+            } else {
+                echo_reply(
+                    modified.as_ref().map(|p| &p[..]).unwrap_or(body),
+                    &addrs[..],
+                )
+            }
+        };
+        if let Some(reply) = reply {
+            log::trace!("inbound worker: answering echo request");
+            peer.device.counters.rx(body.len());
+            // (the reply is dropped if the work queue is full: this worker drains the queue)
+            peer.try_send(PacketBuffer::padded(&reply).into_message(), false);
+        } else {
+            // check if should be written to TUN (or relayed)
+            // (keep-alive, cover traffic and malformed packets will have no inner length)
+            let inner = match modified.as_ref() {
+                Some(packet) => Some(&packet[..]),
+                None => inner_length(packet)
+                    .filter(|inner| inner + SIZE_TAG <= packet.len())
+                    .map(|inner| &packet[..inner]),
+            };
+            match inner {
+                Some(inner) => {
+                    if !(peer.relays() && relay(peer, inner)) {
+                        match peer.device.inbound.write(inner) {
+                            Ok(()) => peer.device.counters.rx(inner.len()),
+                            Err(e) => {
+                                log::debug!("failed to write inbound packet to TUN: {:?}", e);
+                                peer.device.counters.rx_dropped();
+                            }
+                        }
+                    }
+                }
+                // (cover traffic consists of padding only)
+                None if body.iter().any(|b| *b != 0) => {
+                    peer.device.counters.rx_error(RxError::Length)
+                }
+                None => (),
             }
         }
 
@@ -1,6 +1,11 @@
+use super::flows::{flow_of, Direction};
 use super::ip::*;
 
+use core::mem;
+
 // TODO: no_std alternatives
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 use spin::RwLock;
@@ -10,9 +15,99 @@ use zerocopy::LayoutVerified;
 
 /* Functions for obtaining and validating "cryptokey" routes */
 
+/* A route which only applies to packets from the source subnet (policy routing),
+ * e.g. to select the exit peer of a default route by the source of the packet.
+ *
+ * Outbound packets from the source to the destination subnet are routed to the value,
+ * inbound packets from the destination to the source subnet are accepted from the value.
+ */
+struct PolicyRoute<T> {
+    source: (IpAddr, u32),
+    destination: (IpAddr, u32),
+    value: T,
+}
+
+/* A subnet shared by a group of values (ECMP), e.g. multiple tunnels to the same site.
+ *
+ * Outbound flows to the subnet are spread over the members by rendezvous hashing of the flow,
+ * hence every flow sticks to a member (unless the member leaves the group),
+ * inbound packets from the subnet are accepted from every member.
+ */
+struct EcmpRoute<T> {
+    subnet: (IpAddr, u32),
+    members: Vec<(u64, T)>, // (stable key of the member, value)
+}
+
+// true if the address is within the (masked) subnet
+pub(super) fn contains(subnet: (IpAddr, u32), addr: IpAddr) -> bool {
+    match (subnet.0, addr) {
+        (IpAddr::V4(net), IpAddr::V4(addr)) => addr.mask(subnet.1) == net,
+        (IpAddr::V6(net), IpAddr::V6(addr)) => addr.mask(subnet.1) == net,
+        _ => false,
+    }
+}
+
+/* Incremental state of the "client mode" (see RoutingTable::client_mode):
+ *
+ * The owner of both default routes is only recomputed when a default route changes,
+ * otherwise the counts are updated per inserted / removed entry,
+ * hence loading many routes does not rescan the tables on every change.
+ */
+struct ClientMode<T> {
+    candidate: Option<T>, // the value owning 0.0.0.0/0 and ::/0 (if the same)
+    owned: usize,         // entries of the tables owned by the candidate
+    entries: usize,       // entries of both tables
+    active: Option<T>,    // the candidate, if it owns every entry (the fast path is used)
+}
+
+// masks the address of a subnet
+fn masked(subnet: (IpAddr, u32)) -> (IpAddr, u32) {
+    match subnet.0 {
+        IpAddr::V4(v4) => (IpAddr::V4(v4.mask(subnet.1)), subnet.1),
+        IpAddr::V6(v6) => (IpAddr::V6(v6.mask(subnet.1)), subnet.1),
+    }
+}
+
+// extracts the (source, destination) addresses of an IP packet
+fn addresses(packet: &[u8]) -> Option<(IpAddr, IpAddr)> {
+    match packet.get(0)? >> 4 {
+        VERSION_IP4 => {
+            let (header, _): (LayoutVerified<&[u8], IPv4Header>, _) =
+                LayoutVerified::new_from_prefix(packet)?;
+            Some((
+                Ipv4Addr::from(header.f_source).into(),
+                Ipv4Addr::from(header.f_destination).into(),
+            ))
+        }
+        VERSION_IP6 => {
+            let (header, _): (LayoutVerified<&[u8], IPv6Header>, _) =
+                LayoutVerified::new_from_prefix(packet)?;
+            Some((
+                Ipv6Addr::from(header.f_source).into(),
+                Ipv6Addr::from(header.f_destination).into(),
+            ))
+        }
+        _ => None,
+    }
+}
+
 pub struct RoutingTable<T: Eq + Clone> {
     ipv4: RwLock<IpLookupTable<Ipv4Addr, T>>,
     ipv6: RwLock<IpLookupTable<Ipv6Addr, T>>,
+
+    // policy routes, ordered by the length of the source and then the destination prefix
+    // (the first match is the most specific), consulted before the tables above.
+    policy: RwLock<Vec<PolicyRoute<T>>>,
+
+    // subnets shared by groups of values, taking precedence over allowed IPs
+    // of equal or shorter prefixes.
+    ecmp: RwLock<Vec<EcmpRoute<T>>>,
+    hasher: RandomState, // keyed hashing of flows (members cannot be targeted)
+
+    // "client mode": set when a single value owns 0.0.0.0/0 and ::/0
+    // and no other value is present in either table (and there are no policy or ECMP routes).
+    // (locked before the tables when changing them)
+    client: RwLock<ClientMode<T>>,
 }
 
 impl<T: Eq + Clone> RoutingTable<T> {
@@ -20,7 +115,65 @@ impl<T: Eq + Clone> RoutingTable<T> {
         RoutingTable {
             ipv4: RwLock::new(IpLookupTable::new()),
             ipv6: RwLock::new(IpLookupTable::new()),
+            policy: RwLock::new(vec![]),
+            ecmp: RwLock::new(vec![]),
+            hasher: RandomState::new(),
+            client: RwLock::new(ClientMode {
+                candidate: None,
+                owned: 0,
+                entries: 0,
+                active: None,
+            }),
+        }
+    }
+
+    // recomputes the owner of both default routes (after a default route has changed)
+    fn default_owner(
+        client: &mut ClientMode<T>,
+        ipv4: &IpLookupTable<Ipv4Addr, T>,
+        ipv6: &IpLookupTable<Ipv6Addr, T>,
+    ) {
+        let owner4 = ipv4.exact_match(Ipv4Addr::UNSPECIFIED, 0);
+        let owner6 = ipv6.exact_match(Ipv6Addr::UNSPECIFIED, 0);
+        client.candidate = match (owner4, owner6) {
+            (Some(owner4), Some(owner6)) if owner4 == owner6 => Some(owner4.clone()),
+            _ => None,
+        };
+        client.owned = match client.candidate.as_ref() {
+            Some(owner) => {
+                ipv4.iter().filter(|(_, _, v)| *v == owner).count()
+                    + ipv6.iter().filter(|(_, _, v)| *v == owner).count()
+            }
+            None => 0,
+        };
+    }
+
+    // updates the client mode state after the tables, policy or ECMP routes have been changed
+    //
+    // Note: the client lock is held by the caller during the change,
+    // ensuring that the last update always reflects the latest table state.
+    fn update_client_mode(&self, client: &mut ClientMode<T>) {
+        let new = if client.owned == client.entries
+            && self.policy.read().is_empty()
+            && self.ecmp.read().is_empty()
+        {
+            client.candidate.clone()
+        } else {
+            None
+        };
+        if new.is_some() != client.active.is_some() {
+            log::trace!(
+                "router, client mode {}",
+                if new.is_some() { "enabled" } else { "disabled" }
+            );
         }
+        client.active = new;
+    }
+
+    /// Returns true if the fast path for a single peer
+    /// owning both default routes is currently used.
+    pub fn client_mode(&self) -> bool {
+        self.client.read().active.is_some()
     }
 
     // collect keys mapping to the given value
@@ -38,10 +191,54 @@ impl<T: Eq + Clone> RoutingTable<T> {
     }
 
     pub fn insert(&self, ip: IpAddr, cidr: u32, value: T) {
-        match ip {
+        let mut client = self.client.write();
+        let owned = client.candidate.as_ref() == Some(&value);
+        let old = match ip {
             IpAddr::V4(v4) => self.ipv4.write().insert(v4.mask(cidr), cidr, value),
             IpAddr::V6(v6) => self.ipv6.write().insert(v6.mask(cidr), cidr, value),
         };
+        match old {
+            None => client.entries += 1,
+            Some(old) => {
+                if client.candidate.as_ref() == Some(&old) {
+                    client.owned -= 1;
+                }
+            }
+        }
+        if owned {
+            client.owned += 1;
+        }
+        if cidr == 0 {
+            Self::default_owner(&mut client, &*self.ipv4.read(), &*self.ipv6.read());
+        }
+        self.update_client_mode(&mut client);
+    }
+
+    /// Removes the subnet (if owned by the value)
+    pub fn remove_subnet(&self, ip: IpAddr, cidr: u32, value: &T) {
+        let mut client = self.client.write();
+        let removed = match ip {
+            IpAddr::V4(v4) => {
+                let mut table = self.ipv4.write();
+                table.exact_match(v4.mask(cidr), cidr) == Some(value)
+                    && table.remove(v4.mask(cidr), cidr).is_some()
+            }
+            IpAddr::V6(v6) => {
+                let mut table = self.ipv6.write();
+                table.exact_match(v6.mask(cidr), cidr) == Some(value)
+                    && table.remove(v6.mask(cidr), cidr).is_some()
+            }
+        };
+        if removed {
+            client.entries -= 1;
+            if client.candidate.as_ref() == Some(value) {
+                client.owned -= 1;
+            }
+            if cidr == 0 {
+                Self::default_owner(&mut client, &*self.ipv4.read(), &*self.ipv6.read());
+            }
+        }
+        self.update_client_mode(&mut client);
     }
 
     pub fn list(&self, value: &T) -> Vec<(IpAddr, u32)> {
@@ -60,20 +257,225 @@ impl<T: Eq + Clone> RoutingTable<T> {
     }
 
     pub fn remove(&self, value: &T) {
+        let mut client = self.client.write();
+        let mut removed = 0;
+        let mut default = false;
+
         let mut v4 = self.ipv4.write();
         for (ip, cidr) in Self::collect(&*v4, value) {
             v4.remove(ip, cidr);
+            removed += 1;
+            default |= cidr == 0;
         }
 
         let mut v6 = self.ipv6.write();
         for (ip, cidr) in Self::collect(&*v6, value) {
             v6.remove(ip, cidr);
+            removed += 1;
+            default |= cidr == 0;
+        }
+
+        client.entries -= removed;
+        if client.candidate.as_ref() == Some(value) {
+            client.owned -= removed;
+        }
+        if default {
+            Self::default_owner(&mut client, &*v4, &*v6);
+        }
+
+        mem::drop(v4);
+        mem::drop(v6);
+        self.update_client_mode(&mut client);
+    }
+
+    /// Adds a policy route: packets from the source subnet to the destination subnet
+    /// are routed to the value (regardless of the allowed IPs).
+    ///
+    /// Returns false if the address families of the subnets differ.
+    pub fn insert_policy(
+        &self,
+        source: (IpAddr, u32),
+        destination: (IpAddr, u32),
+        value: T,
+    ) -> bool {
+        if source.0.is_ipv4() != destination.0.is_ipv4() {
+            return false;
+        }
+        let (source, destination) = (masked(source), masked(destination));
+        let mut client = self.client.write();
+        {
+            let mut policy = self.policy.write();
+            policy.retain(|r| r.source != source || r.destination != destination);
+            let pos = policy
+                .iter()
+                .position(|r| (r.source.1, r.destination.1) < (source.1, destination.1))
+                .unwrap_or(policy.len());
+            policy.insert(
+                pos,
+                PolicyRoute {
+                    source,
+                    destination,
+                    value,
+                },
+            );
+        }
+        self.update_client_mode(&mut client);
+        true
+    }
+
+    /// Returns the (source, destination) subnets of the policy routes of the value
+    pub fn list_policy(&self, value: &T) -> Vec<((IpAddr, u32), (IpAddr, u32))> {
+        self.policy
+            .read()
+            .iter()
+            .filter(|r| r.value == *value)
+            .map(|r| (r.source, r.destination))
+            .collect()
+    }
+
+    pub fn remove_policy(&self, value: &T) {
+        let mut client = self.client.write();
+        self.policy.write().retain(|r| r.value != *value);
+        self.update_client_mode(&mut client);
+    }
+
+    // the value of the most specific policy route matching the packet (if any)
+    fn get_policy_route(&self, packet: &[u8]) -> Option<T> {
+        let policy = self.policy.read();
+        if policy.is_empty() {
+            return None;
+        }
+        let (src, dst) = addresses(packet)?;
+        policy
+            .iter()
+            .find(|r| contains(r.source, src) && contains(r.destination, dst))
+            .map(|r| r.value.clone())
+    }
+
+    // true if a policy route of the value covers the reverse direction of the packet
+    fn check_policy_route(&self, value: &T, packet: &[u8]) -> bool {
+        let policy = self.policy.read();
+        if policy.is_empty() {
+            return false;
+        }
+        match addresses(packet) {
+            Some((src, dst)) => policy.iter().any(|r| {
+                r.value == *value && contains(r.destination, src) && contains(r.source, dst)
+            }),
+            None => false,
+        }
+    }
+
+    /// Adds the value to the group sharing the subnet (ECMP),
+    /// the key identifies the member when hashing flows (and must be stable and unique).
+    pub fn insert_ecmp(&self, ip: IpAddr, cidr: u32, key: u64, value: T) {
+        let subnet = masked((ip, cidr));
+        let mut client = self.client.write();
+        {
+            let mut ecmp = self.ecmp.write();
+            match ecmp.iter_mut().find(|r| r.subnet == subnet) {
+                Some(route) => {
+                    if !route.members.iter().any(|(_, v)| *v == value) {
+                        route.members.push((key, value));
+                    }
+                }
+                None => ecmp.push(EcmpRoute {
+                    subnet,
+                    members: vec![(key, value)],
+                }),
+            }
+        }
+        self.update_client_mode(&mut client);
+    }
+
+    /// Returns the subnets shared by the value
+    pub fn list_ecmp(&self, value: &T) -> Vec<(IpAddr, u32)> {
+        self.ecmp
+            .read()
+            .iter()
+            .filter(|r| r.members.iter().any(|(_, v)| v == value))
+            .map(|r| r.subnet)
+            .collect()
+    }
+
+    pub fn remove_ecmp(&self, value: &T) {
+        let mut client = self.client.write();
+        {
+            let mut ecmp = self.ecmp.write();
+            for route in ecmp.iter_mut() {
+                route.members.retain(|(_, v)| v != value);
+            }
+            ecmp.retain(|r| !r.members.is_empty());
+        }
+        self.update_client_mode(&mut client);
+    }
+
+    // the (prefix length, member) of the most specific ECMP route to the destination (if any)
+    fn get_ecmp_route(&self, packet: &[u8]) -> Option<(u32, T)> {
+        let ecmp = self.ecmp.read();
+        if ecmp.is_empty() {
+            return None;
+        }
+        let (src, dst) = addresses(packet)?;
+        let route = ecmp
+            .iter()
+            .filter(|r| contains(r.subnet, dst))
+            .max_by_key(|r| r.subnet.1)?;
+
+        // rendezvous hashing: the member with the highest hash of (flow, member) is selected
+        let flow = flow_of(packet, Direction::Outbound).map(|(flow, _)| flow);
+        route
+            .members
+            .iter()
+            .max_by_key(|(key, _)| {
+                let mut hasher = self.hasher.build_hasher();
+                match flow {
+                    Some(flow) => flow.hash(&mut hasher),
+                    None => (src, dst).hash(&mut hasher),
+                }
+                key.hash(&mut hasher);
+                hasher.finish()
+            })
+            .map(|(_, value)| (route.subnet.1, value.clone()))
+    }
+
+    // true if the value is a member of an ECMP route containing the source of the packet
+    fn check_ecmp_route(&self, value: &T, packet: &[u8]) -> bool {
+        let ecmp = self.ecmp.read();
+        if ecmp.is_empty() {
+            return false;
+        }
+        match addresses(packet) {
+            Some((src, _)) => ecmp
+                .iter()
+                .any(|r| contains(r.subnet, src) && r.members.iter().any(|(_, v)| v == value)),
+            None => false,
         }
     }
 
     #[inline(always)]
     pub fn get_route(&self, packet: &[u8]) -> Option<T> {
-        match packet.get(0)? >> 4 {
+        // fast path: a single peer owns every route,
+        // only check that the packet holds a full IP header.
+        if let Some(peer) = self.client.read().active.as_ref() {
+            let header_len = match packet.get(0)? >> 4 {
+                VERSION_IP4 => mem::size_of::<IPv4Header>(),
+                VERSION_IP6 => mem::size_of::<IPv6Header>(),
+                _ => return None,
+            };
+            return if packet.len() >= header_len {
+                Some(peer.clone())
+            } else {
+                None
+            };
+        }
+
+        // policy routes take precedence over the allowed IPs
+        if let Some(peer) = self.get_policy_route(packet) {
+            return Some(peer);
+        }
+
+        let route = match packet.get(0)? >> 4 {
             VERSION_IP4 => {
                 // check length and cast to IPv4 header
                 let (header, _): (LayoutVerified<&[u8], IPv4Header>, _) =
@@ -88,7 +490,7 @@ impl<T: Eq + Clone> RoutingTable<T> {
                 self.ipv4
                     .read()
                     .longest_match(Ipv4Addr::from(header.f_destination))
-                    .and_then(|(_, _, p)| Some(p.clone()))
+                    .and_then(|(_, cidr, p)| Some((cidr, p.clone())))
             }
             VERSION_IP6 => {
                 // check length and cast to IPv6 header
@@ -104,17 +506,27 @@ impl<T: Eq + Clone> RoutingTable<T> {
                 self.ipv6
                     .read()
                     .longest_match(Ipv6Addr::from(header.f_destination))
-                    .and_then(|(_, _, p)| Some(p.clone()))
+                    .and_then(|(_, cidr, p)| Some((cidr, p.clone())))
             }
             v => {
                 log::trace!("router, invalid IP version {}", v);
-                None
+                return None;
             }
+        };
+
+        // ECMP routes take precedence over allowed IPs of equal or shorter prefixes
+        match (route, self.get_ecmp_route(packet)) {
+            (Some((cidr, _)), Some((ecmp_cidr, peer))) if ecmp_cidr >= cidr => Some(peer),
+            (None, Some((_, peer))) => Some(peer),
+            (route, _) => route.map(|(_, peer)| peer),
         }
     }
 
     #[inline(always)]
     pub fn check_route(&self, peer: &T, packet: &[u8]) -> bool {
+        if self.check_policy_route(peer, packet) || self.check_ecmp_route(peer, packet) {
+            return true;
+        }
         match packet.get(0).map(|v| v >> 4) {
             Some(VERSION_IP4) => LayoutVerified::new_from_prefix(packet)
                 .and_then(|(header, _): (LayoutVerified<&[u8], IPv4Header>, _)| {
@@ -137,3 +549,193 @@ impl<T: Eq + Clone> RoutingTable<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(dst: IpAddr) -> Vec<u8> {
+        match dst {
+            IpAddr::V4(_) => packet_from(Ipv4Addr::UNSPECIFIED.into(), dst),
+            IpAddr::V6(_) => packet_from(Ipv6Addr::UNSPECIFIED.into(), dst),
+        }
+    }
+
+    fn packet_from(src: IpAddr, dst: IpAddr) -> Vec<u8> {
+        match (src, dst) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                let mut msg = vec![0u8; mem::size_of::<IPv4Header>()];
+                msg[0] = VERSION_IP4 << 4;
+                msg[12..16].copy_from_slice(&src.octets());
+                msg[16..20].copy_from_slice(&dst.octets());
+                msg
+            }
+            (IpAddr::V6(src), IpAddr::V6(dst)) => {
+                let mut msg = vec![0u8; mem::size_of::<IPv6Header>()];
+                msg[0] = VERSION_IP6 << 4;
+                msg[8..24].copy_from_slice(&src.octets());
+                msg[24..40].copy_from_slice(&dst.octets());
+                msg
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_client_mode() {
+        let table: RoutingTable<u32> = RoutingTable::new();
+        let v4: IpAddr = "10.0.0.1".parse().unwrap();
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+
+        // only one default route: no client mode
+        table.insert("0.0.0.0".parse().unwrap(), 0, 1);
+        assert!(!table.client_mode());
+        assert_eq!(table.get_route(&packet(v4)), Some(1));
+        assert_eq!(table.get_route(&packet(v6)), None);
+
+        // both default routes owned by the same peer
+        table.insert("::".parse().unwrap(), 0, 1);
+        assert!(table.client_mode());
+        assert_eq!(table.get_route(&packet(v4)), Some(1));
+        assert_eq!(table.get_route(&packet(v6)), Some(1));
+        assert_eq!(table.get_route(&packet(v4)[..10]), None);
+        assert_eq!(table.get_route(&[0x10]), None);
+
+        // another peer owning a subnet disables client mode
+        table.insert("10.0.0.0".parse().unwrap(), 24, 2);
+        assert!(!table.client_mode());
+        assert_eq!(table.get_route(&packet(v4)), Some(2));
+        assert_eq!(table.get_route(&packet(v6)), Some(1));
+
+        // removing the peer re-enables it
+        table.remove(&2);
+        assert!(table.client_mode());
+        assert_eq!(table.get_route(&packet(v4)), Some(1));
+
+        // removing the owner leaves an empty table
+        table.remove(&1);
+        assert!(!table.client_mode());
+        assert_eq!(table.get_route(&packet(v4)), None);
+    }
+
+    #[test]
+    fn test_client_mode_updates() {
+        let table: RoutingTable<u32> = RoutingTable::new();
+        let v4: IpAddr = "10.0.0.1".parse().unwrap();
+        let net: IpAddr = "10.0.0.0".parse().unwrap();
+        table.insert("0.0.0.0".parse().unwrap(), 0, 1);
+        table.insert("::".parse().unwrap(), 0, 1);
+        table.insert(net, 8, 1);
+        assert!(table.client_mode());
+
+        // a subnet of the owner replaced by another peer (and back)
+        table.insert(net, 8, 2);
+        assert!(!table.client_mode());
+        table.insert(net, 8, 1);
+        assert!(table.client_mode());
+
+        // only subnets owned by the value are removed
+        table.remove_subnet(net, 8, &2);
+        assert_eq!(table.get_route(&packet(v4)), Some(1));
+        table.remove_subnet(net, 8, &1);
+        assert!(table.client_mode());
+
+        // the default routes move to another peer
+        table.insert("::".parse().unwrap(), 0, 2);
+        assert!(!table.client_mode());
+        table.insert("0.0.0.0".parse().unwrap(), 0, 2);
+        assert!(table.client_mode());
+        assert_eq!(table.get_route(&packet(v4)), Some(2));
+
+        table.remove_subnet("::".parse().unwrap(), 0, &2);
+        assert!(!table.client_mode());
+        assert_eq!(table.get_route(&packet(v4)), Some(2));
+    }
+
+    #[test]
+    fn test_policy_routes() {
+        let table: RoutingTable<u32> = RoutingTable::new();
+        let any: IpAddr = "0.0.0.0".parse().unwrap();
+        let tenant_a: IpAddr = "10.0.1.7".parse().unwrap();
+        let tenant_b: IpAddr = "10.0.2.7".parse().unwrap();
+        let remote: IpAddr = "192.0.2.1".parse().unwrap();
+
+        // peer 1 owns the default route, peer 2 the exit of 10.0.2.0/24
+        table.insert(any, 0, 1);
+        table.insert("::".parse().unwrap(), 0, 1);
+        assert!(table.insert_policy(("10.0.2.0".parse().unwrap(), 24), (any, 0), 2));
+        assert!(!table.insert_policy((tenant_b, 24), ("::".parse().unwrap(), 0), 2));
+        assert!(!table.client_mode());
+        assert_eq!(
+            table.list_policy(&2),
+            vec![(("10.0.2.0".parse().unwrap(), 24), (any, 0))]
+        );
+
+        // outbound: selected by the source
+        assert_eq!(table.get_route(&packet_from(tenant_a, remote)), Some(1));
+        assert_eq!(table.get_route(&packet_from(tenant_b, remote)), Some(2));
+
+        // inbound: replies to the source subnet are accepted from the exit
+        assert!(table.check_policy_route(&2, &packet_from(remote, tenant_b)));
+        assert!(!table.check_policy_route(&2, &packet_from(remote, tenant_a)));
+        assert!(!table.check_policy_route(&2, &packet_from(tenant_b, remote)));
+        assert!(table.check_route(&2, &packet_from(remote, tenant_b)));
+
+        // a more specific source takes precedence
+        assert!(table.insert_policy((tenant_b, 32), (any, 0), 3));
+        assert_eq!(table.get_route(&packet_from(tenant_b, remote)), Some(3));
+
+        table.remove_policy(&3);
+        table.remove_policy(&2);
+        assert!(table.client_mode());
+        assert_eq!(table.get_route(&packet_from(tenant_b, remote)), Some(1));
+    }
+
+    #[test]
+    fn test_ecmp_routes() {
+        let table: RoutingTable<u32> = RoutingTable::new();
+        let site: IpAddr = "10.1.0.0".parse().unwrap();
+        table.insert("0.0.0.0".parse().unwrap(), 0, 1);
+        table.insert("10.1.2.0".parse().unwrap(), 24, 4);
+        table.insert_ecmp(site, 16, 2, 2);
+        table.insert_ecmp(site, 16, 3, 3);
+        assert_eq!(table.list_ecmp(&3), vec![(site, 16)]);
+
+        // flows are spread over the members (and stick to their member)
+        let mut members = vec![];
+        for host in 0..64u8 {
+            let dst = IpAddr::from([10, 1, 7, host]);
+            let peer = table.get_route(&packet(dst)).unwrap();
+            assert!(peer == 2 || peer == 3);
+            assert_eq!(table.get_route(&packet(dst)), Some(peer));
+            members.push(peer);
+        }
+        assert!(members.contains(&2) && members.contains(&3));
+
+        // more specific allowed IPs take precedence
+        assert_eq!(
+            table.get_route(&packet("10.1.2.1".parse().unwrap())),
+            Some(4)
+        );
+        assert_eq!(
+            table.get_route(&packet("10.2.0.1".parse().unwrap())),
+            Some(1)
+        );
+
+        // inbound packets from the subnet are accepted from every member
+        let src: IpAddr = "10.1.7.1".parse().unwrap();
+        let reply = packet_from(src, "10.0.0.1".parse().unwrap());
+        assert!(table.check_ecmp_route(&2, &reply));
+        assert!(table.check_ecmp_route(&3, &reply));
+        assert!(!table.check_ecmp_route(&1, &reply));
+
+        // flows of a member leaving the group move to the remaining members
+        table.remove_ecmp(&2);
+        for host in 0..64u8 {
+            let dst = IpAddr::from([10, 1, 7, host]);
+            assert_eq!(table.get_route(&packet(dst)), Some(3));
+        }
+        table.remove_ecmp(&3);
+        assert_eq!(table.get_route(&packet(site)), Some(1));
+    }
+}
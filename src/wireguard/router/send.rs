@@ -3,14 +3,15 @@ use super::peer::Peer;
 use super::queue::{ParallelJob, Queue, SequentialJob};
 use super::types::Callbacks;
 use super::KeyPair;
-use super::{REJECT_AFTER_MESSAGES, SIZE_TAG};
+use super::Reservation;
+use super::{REJECT_AFTER_MESSAGES, SIZE_MESSAGE_PREFIX, SIZE_TAG};
 
+use super::super::crypto::{Backend, Seal, Selected};
 use super::super::{tun, udp, Endpoint};
 
 use alloc::sync::Arc;
 use core::sync::atomic::{AtomicBool, Ordering};
 
-use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
 use spin::Mutex;
 use zerocopy::{AsBytes, LayoutVerified};
 
@@ -20,6 +21,7 @@ struct Inner<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> {
     counter: u64,
     keypair: Arc<KeyPair>,
     peer: Peer<E, C, T, B>,
+    _memory: Reservation,
 }
 
 pub struct SendJob<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>>(
@@ -39,7 +41,9 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> SendJob<E, C,
         keypair: Arc<KeyPair>,
         peer: Peer<E, C, T, B>,
     ) -> SendJob<E, C, T, B> {
+        let _memory = peer.device.memory.reserve(buffer.capacity());
         SendJob(Arc::new(Inner {
+            _memory,
             buffer: Mutex::new(buffer),
             counter,
             keypair,
@@ -49,6 +53,48 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> SendJob<E, C,
     }
 }
 
+impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> SendJob<E, C, T, B> {
+    /* Prepares the message of the job for sealing:
+     * sets the fields of the header and makes space for the tag.
+     */
+    fn seal<'a>(&'a self, msg: &'a mut Vec<u8>) -> Seal<'a> {
+        debug_assert_eq!(
+            self.is_ready(),
+            false,
+            "doing parallel work on completed job"
+        );
+        let job = &*self.0;
+
+        // make space for the tag
+        msg.extend([0u8; SIZE_TAG].iter());
+
+        // cast to header (should never fail)
+        let (mut header, packet): (LayoutVerified<&mut [u8], TransportHeader>, &mut [u8]) =
+            LayoutVerified::new_from_prefix(&mut msg[..])
+                .expect("earlier code should ensure that there is ample space");
+
+        // set header fields
+        debug_assert!(
+            job.counter < REJECT_AFTER_MESSAGES,
+            "should be checked when assigning counters"
+        );
+        header.f_type.set(TYPE_TRANSPORT);
+        header.f_receiver.set(job.keypair.send.id);
+        header.f_counter.set(job.counter);
+
+        // create a nonce
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(header.f_counter.as_bytes());
+
+        // contents of transport message sealed in-place (tag appended)
+        Seal {
+            key: &job.keypair.send.key,
+            nonce,
+            buf: packet,
+        }
+    }
+}
+
 impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> ParallelJob
     for SendJob<E, C, T, B>
 {
@@ -57,56 +103,38 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> ParallelJob
     }
 
     fn parallel_work(&self) {
-        debug_assert_eq!(
-            self.is_ready(),
-            false,
-            "doing parallel work on completed job"
-        );
         log::trace!("processing parallel send job");
 
-        // encrypt body
+        // a lone job is sealed without the bookkeeping of a batch
         {
-            // make space for the tag
-            let job = &*self.0;
-            let mut msg = job.buffer.lock();
-            msg.extend([0u8; SIZE_TAG].iter());
-
-            // cast to header (should never fail)
-            let (mut header, packet): (LayoutVerified<&mut [u8], TransportHeader>, &mut [u8]) =
-                LayoutVerified::new_from_prefix(&mut msg[..])
-                    .expect("earlier code should ensure that there is ample space");
-
-            // set header fields
-            debug_assert!(
-                job.counter < REJECT_AFTER_MESSAGES,
-                "should be checked when assigning counters"
-            );
-            header.f_type.set(TYPE_TRANSPORT);
-            header.f_receiver.set(job.keypair.send.id);
-            header.f_counter.set(job.counter);
-
-            // create a nonce object
-            let mut nonce = [0u8; 12];
-            debug_assert_eq!(nonce.len(), CHACHA20_POLY1305.nonce_len());
-            nonce[4..].copy_from_slice(header.f_counter.as_bytes());
-            let nonce = Nonce::assume_unique_for_key(nonce);
-
-            // encrypt contents of transport message in-place
-            let tag_offset = packet.len() - SIZE_TAG;
-            let key = LessSafeKey::new(
-                UnboundKey::new(&CHACHA20_POLY1305, &job.keypair.send.key[..]).unwrap(),
-            );
-            let tag = key
-                .seal_in_place_separate_tag(nonce, Aad::empty(), &mut packet[..tag_offset])
-                .unwrap();
-
-            // append tag
-            packet[tag_offset..].copy_from_slice(tag.as_ref());
+            let mut msg = self.0.buffer.lock();
+            let mut batch = [self.seal(&mut msg)];
+            Selected::seal_batch(&mut batch);
         }
 
         // mark ready
         self.0.ready.store(true, Ordering::Release);
     }
+
+    fn parallel_batch(jobs: &[Self]) {
+        log::trace!("processing {} parallel send jobs", jobs.len());
+
+        // encrypt bodies
+        {
+            let mut msgs: Vec<_> = jobs.iter().map(|job| job.0.buffer.lock()).collect();
+            let mut batch: Vec<_> = jobs
+                .iter()
+                .zip(msgs.iter_mut())
+                .map(|(job, msg)| job.seal(msg))
+                .collect();
+            Selected::seal_batch(&mut batch);
+        }
+
+        // mark ready
+        for job in jobs {
+            job.0.ready.store(true, Ordering::Release);
+        }
+    }
 }
 
 impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> SequentialJob
@@ -130,6 +158,14 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> SequentialJob
         let msg = job.buffer.lock();
         let xmit = job.peer.send_raw(&msg[..]).is_ok();
 
+        // account to the interface (keepalives carry no inner packet)
+        let inner = msg.len().saturating_sub(SIZE_MESSAGE_PREFIX + SIZE_TAG);
+        if !xmit {
+            job.peer.device.counters.tx_error();
+        } else if inner > 0 {
+            job.peer.device.counters.tx(inner);
+        }
+
         // trigger callback (for timers)
         C::send(&job.peer.opaque, msg.len(), xmit, &job.keypair, job.counter);
     }
@@ -18,6 +18,30 @@ use num_cpus;
 #[cfg(feature = "unstable")]
 use test::Bencher;
 
+// only used in benchmark
+#[cfg(feature = "unstable")]
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+
+// only used in benchmark
+#[cfg(feature = "unstable")]
+use std::thread;
+
+// only used in benchmark
+#[cfg(feature = "unstable")]
+use std::collections::HashMap;
+
+// only used in benchmark
+#[cfg(feature = "unstable")]
+use spin::RwLock;
+
+// only used in benchmark
+#[cfg(feature = "unstable")]
+use super::super::super::crypto::{Backend, Selected};
+
+// only used in benchmark
+#[cfg(feature = "unstable")]
+use super::super::idmap::IdMap;
+
 //
 struct TransmissionCounter {
     sent: AtomicUsize,
@@ -142,6 +166,173 @@ fn bench_router_outbound(b: &mut Bencher) {
     profiler_stop();
 }
 
+#[cfg(feature = "unstable")]
+#[bench]
+fn bench_router_inspect_concurrent(b: &mut Bencher) {
+    // every thread looks up and decrypts messages of every peer
+    const THREADS: usize = 8;
+    const PEERS: u32 = 256;
+    const MESSAGES_PER_THREAD: usize = 100_000;
+    const BYTES_PER_PACKET: usize = 1440;
+
+    // create device
+    let (_fake, _reader, tun_writer, _mtu) = dummy::TunTest::create(false);
+    let router: Device<_, BencherCallbacks, dummy::TunWriter, dummy::VoidBind> =
+        Device::new(num_cpus::get_physical(), tun_writer);
+
+    // add peers, each with a key-pair of its own receiver id
+    let mut peers = vec![];
+    let mut msgs = vec![];
+    for i in 0..PEERS {
+        let key = Key {
+            key: [i as u8; 32],
+            id: i.wrapping_mul(0x9e37_79b9), // spread over the shards
+        };
+        let peer = router.new_peer(Arc::new(TransmissionCounter::new()));
+        peer.add_keypair(KeyPair {
+            birth: Instant::now(),
+            initiator: false,
+            send: key.clone(),
+            recv: key.clone(),
+        });
+        peer.add_allowed_ip(IpAddr::from([10, (i >> 8) as u8, i as u8, 0]), 24);
+
+        // seal an IP packet from the allowed IPs of the peer
+        let src = IpAddr::from([10, (i >> 8) as u8, i as u8, 1]);
+        let packet = make_packet(BYTES_PER_PACKET, src, "192.168.1.1".parse().unwrap(), 0);
+        let mut body = packet.clone();
+        LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, &key.key[..]).unwrap())
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key([0u8; 12]),
+                Aad::empty(),
+                &mut body,
+            )
+            .unwrap();
+        let mut msg = vec![0u8; SIZE_MESSAGE_PREFIX];
+        msg[0] = 4; // transport message type
+        msg[4..8].copy_from_slice(&key.id.to_le_bytes());
+        msg.extend(body);
+        msgs.push(msg);
+        peers.push(peer);
+    }
+    let router = Arc::new(router);
+    let msgs = Arc::new(msgs);
+
+    b.iter(|| {
+        let workers: Vec<_> = (0..THREADS)
+            .map(|n| {
+                let router = router.clone();
+                let msgs = msgs.clone();
+                thread::spawn(move || {
+                    for i in 0..MESSAGES_PER_THREAD {
+                        let msg = &msgs[(i + n) % msgs.len()];
+                        let (_, res) = router.inspect(msg, |_| ());
+                        assert!(res.is_ok());
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+    });
+}
+
+// map from receiver id to the key of the session (see bench_lookup_concurrent)
+#[cfg(feature = "unstable")]
+trait KeyMap: Send + Sync + 'static {
+    fn insert(&self, id: u32, key: Arc<Key>);
+    fn get(&self, id: u32) -> Option<Arc<Key>>;
+}
+
+#[cfg(feature = "unstable")]
+impl KeyMap for IdMap<Arc<Key>> {
+    fn insert(&self, id: u32, key: Arc<Key>) {
+        IdMap::insert(self, id, key);
+    }
+
+    fn get(&self, id: u32) -> Option<Arc<Key>> {
+        IdMap::get(self, id)
+    }
+}
+
+#[cfg(feature = "unstable")]
+impl KeyMap for RwLock<HashMap<u32, Arc<Key>>> {
+    fn insert(&self, id: u32, key: Arc<Key>) {
+        self.write().insert(id, key);
+    }
+
+    fn get(&self, id: u32) -> Option<Arc<Key>> {
+        self.read().get(&id).cloned()
+    }
+}
+
+/* The receiver id lookup and decryption of Device::inspect (see bench_router_inspect_concurrent),
+ * with the map from receiver id to decryption state replaced by the given map:
+ * compares the IdMap of the router with a RwLock around a HashMap (the previous map of the router).
+ */
+#[cfg(feature = "unstable")]
+fn bench_lookup_concurrent<M: KeyMap>(b: &mut Bencher, map: M) {
+    const THREADS: usize = 8;
+    const PEERS: u32 = 256;
+    const MESSAGES_PER_THREAD: usize = 100_000;
+    const BYTES_PER_PACKET: usize = 1440;
+
+    // seal a message for every receiver id
+    let mut msgs = vec![];
+    for i in 0..PEERS {
+        let key = Key {
+            key: [i as u8; 32],
+            id: i.wrapping_mul(0x9e37_79b9), // spread over the shards
+        };
+        let mut body = vec![0u8; BYTES_PER_PACKET];
+        let tag = Selected::seal(&key.key, &[0u8; 12], &[], &mut body);
+        let mut msg = vec![0u8; SIZE_MESSAGE_PREFIX];
+        msg[0] = 4; // transport message type
+        msg[4..8].copy_from_slice(&key.id.to_le_bytes());
+        msg.extend(body);
+        msg.extend(&tag);
+        map.insert(key.id, Arc::new(key));
+        msgs.push(msg);
+    }
+    let map = Arc::new(map);
+    let msgs = Arc::new(msgs);
+
+    b.iter(|| {
+        let workers: Vec<_> = (0..THREADS)
+            .map(|n| {
+                let map = map.clone();
+                let msgs = msgs.clone();
+                thread::spawn(move || {
+                    for i in 0..MESSAGES_PER_THREAD {
+                        let msg = &msgs[(i + n) % msgs.len()];
+                        let id = u32::from_le_bytes([msg[4], msg[5], msg[6], msg[7]]);
+                        let key = map.get(id).unwrap();
+                        let mut body = msg[SIZE_MESSAGE_PREFIX..].to_vec();
+                        assert!(Selected::open(&key.key, &[0u8; 12], &[], &mut body).is_ok());
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+    });
+}
+
+#[cfg(feature = "unstable")]
+#[bench]
+fn bench_lookup_concurrent_idmap(b: &mut Bencher) {
+    bench_lookup_concurrent(b, IdMap::<Arc<Key>>::new());
+}
+
+// baseline of bench_lookup_concurrent_idmap
+#[cfg(feature = "unstable")]
+#[bench]
+fn bench_lookup_concurrent_rwlock(b: &mut Bencher) {
+    bench_lookup_concurrent(b, RwLock::new(HashMap::<u32, Arc<Key>>::new()));
+}
+
 /*
 #[test]
 fn bench_router_bidirectional(b: &mut Bencher) {
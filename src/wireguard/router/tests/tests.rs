@@ -242,6 +242,90 @@ fn test_outbound() {
     }
 }
 
+#[test]
+fn test_staged() {
+    init();
+
+    // create device
+    let (_fake, _reader, tun_writer, _mtu) = dummy::TunTest::create(false);
+    let router: Device<_, TestCallbacks, _, _> = Device::new(1, tun_writer);
+    router.set_outbound_writer(dummy::VoidBind::new());
+
+    // add new peer (without a key)
+    let opaque = Opaque::new();
+    let peer = router.new_peer(opaque.clone());
+    peer.add_allowed_ip("192.168.1.0".parse().unwrap(), 24);
+
+    let src = "127.0.0.1".parse().unwrap();
+    let dst = "192.168.1.20".parse().unwrap();
+    let msg = make_packet(SIZE_MSG, src, dst, 0);
+
+    // the packets are staged and a key is requested once
+    for _ in 0..3 {
+        router.send(pad(&msg)).unwrap();
+    }
+    assert_eq!(
+        opaque.need_key.wait(TIMEOUT),
+        Some(()),
+        "should have requested a new key for the staged packets"
+    );
+    assert_eq!(peer.staged_packets(), 3);
+    no_events!(opaque);
+
+    // only the newest keepalive is staged
+    peer.send_keepalive();
+    peer.send_keepalive();
+    assert_eq!(peer.staged_packets(), 4);
+    no_events!(opaque);
+
+    // the staged packets are flushed on the new key
+    peer.add_keypair(dummy_keypair(true));
+    let mut sent: Vec<_> = (0..4)
+        .map(|_| opaque.send.wait(TIMEOUT).expect("staged packet not sent"))
+        .collect();
+    sent.sort();
+    assert_eq!(
+        sent,
+        vec![
+            (SIZE_KEEPALIVE, false),
+            (SIZE_KEEPALIVE + msg.len(), false),
+            (SIZE_KEEPALIVE + msg.len(), false),
+            (SIZE_KEEPALIVE + msg.len(), false),
+        ]
+    );
+    assert_eq!(peer.staged_packets(), 0);
+    no_events!(opaque);
+}
+
+#[test]
+fn test_cover() {
+    init();
+
+    // create device
+    let (_fake, _reader, tun_writer, _mtu) = dummy::TunTest::create(false);
+    let router: Device<_, TestCallbacks, _, _> = Device::new(1, tun_writer);
+    router.set_outbound_writer(dummy::VoidBind::new());
+
+    // add new peer (without a key)
+    let opaque = Opaque::new();
+    let peer = router.new_peer(opaque.clone());
+
+    // without a session cover traffic is dropped (neither staged nor requesting a key)
+    assert!(!peer.send_cover(SIZE_MSG));
+    assert_eq!(peer.staged_packets(), 0);
+    no_events!(opaque);
+
+    // within a session the padded message is sent
+    peer.add_keypair(dummy_keypair(true));
+    assert!(peer.send_cover(SIZE_MSG));
+    assert_eq!(
+        opaque.send.wait(TIMEOUT),
+        Some((SIZE_KEEPALIVE + SIZE_MSG, false)),
+        "cover traffic not sent"
+    );
+    no_events!(opaque);
+}
+
 #[test]
 fn test_bidirectional() {
     init();
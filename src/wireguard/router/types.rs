@@ -34,6 +34,23 @@ pub trait Callbacks: Send + Sync + 'static {
     fn key_confirmed(opaque: &Self::Opaque);
 }
 
+/// The verdict of a packet filter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Allow,           // pass the packet unmodified
+    Drop,            // discard the packet
+    Modify(Vec<u8>), // replace the packet (subject to crypto-key routing)
+}
+
+/// A filter of inner IP packets, invoked with the opaque value of the peer:
+///
+/// * `inbound`, on decrypted packets (before they are written to the TUN device)
+/// * `outbound`, on packets from the TUN device (before they are encrypted)
+pub trait PacketFilter<T>: Send + Sync + 'static {
+    fn inbound(&self, opaque: &T, packet: &[u8]) -> Action;
+    fn outbound(&self, opaque: &T, packet: &[u8]) -> Action;
+}
+
 #[derive(Debug)]
 pub enum RouterError {
     NoCryptoKeyRoute,
@@ -41,6 +58,10 @@ pub enum RouterError {
     UnknownReceiverId,
     NoEndpoint,
     SendError,
+    AuthenticationFailed,
+    Replay,
+    ExceedsMtu,
+    QueueFull,
 }
 
 impl fmt::Display for RouterError {
@@ -53,6 +74,10 @@ impl fmt::Display for RouterError {
             }
             RouterError::NoEndpoint => write!(f, "No endpoint for peer"),
             RouterError::SendError => write!(f, "Failed to send packet on bind"),
+            RouterError::AuthenticationFailed => write!(f, "Failed to authenticate message"),
+            RouterError::Replay => write!(f, "Message counter replayed or too old"),
+            RouterError::ExceedsMtu => write!(f, "Packet exceeds the MTU of the peer"),
+            RouterError::QueueFull => write!(f, "Work queue is full"),
         }
     }
 }
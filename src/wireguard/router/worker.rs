@@ -1,3 +1,4 @@
+use super::constants::MAX_CRYPTO_BATCH;
 use super::queue::ParallelJob;
 use super::receive::ReceiveJob;
 use super::send::SendJob;
@@ -5,7 +6,11 @@ use super::send::SendJob;
 use super::super::{tun, udp, Endpoint};
 use super::types::Callbacks;
 
-use crossbeam_channel::Receiver;
+use std::hint::spin_loop;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crossbeam_channel::{Receiver, Sender, TryRecvError, TrySendError};
 use log;
 
 pub enum JobUnion<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> {
@@ -13,9 +18,69 @@ pub enum JobUnion<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>>
     Inbound(ReceiveJob<E, C, T, B>),
 }
 
+/* A batch of jobs for the crypto stage:
+ *
+ * The jobs already waiting in the work queue (up to MAX_CRYPTO_BATCH) are taken along
+ * with the job awaited, hence the batch adapts to the depth of the queue:
+ * a lone job is processed immediately (without the bookkeeping of a batch),
+ * while a backlog is processed with fewer (and larger) invocations of the crypto backend.
+ */
+struct Batch<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> {
+    inbound: Vec<ReceiveJob<E, C, T, B>>,
+    outbound: Vec<SendJob<E, C, T, B>>,
+}
+
+impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Batch<E, C, T, B> {
+    fn new() -> Self {
+        Batch {
+            inbound: Vec::with_capacity(MAX_CRYPTO_BATCH),
+            outbound: Vec::with_capacity(MAX_CRYPTO_BATCH),
+        }
+    }
+
+    fn push(&mut self, job: JobUnion<E, C, T, B>) {
+        match job {
+            JobUnion::Inbound(job) => self.inbound.push(job),
+            JobUnion::Outbound(job) => self.outbound.push(job),
+        }
+    }
+
+    // takes the jobs already queued behind the first job of the batch
+    fn fill(&mut self, receiver: &Receiver<JobUnion<E, C, T, B>>) {
+        let depth = receiver.len().min(MAX_CRYPTO_BATCH - 1);
+        for _ in 0..depth {
+            match receiver.try_recv() {
+                Ok(job) => self.push(job),
+                Err(_) => break,
+            }
+        }
+    }
+
+    fn parallel_work(&self) {
+        match self.inbound.len() {
+            0 => (),
+            1 => self.inbound[0].parallel_work(),
+            _ => ReceiveJob::parallel_batch(&self.inbound),
+        }
+        match self.outbound.len() {
+            0 => (),
+            1 => self.outbound[0].parallel_work(),
+            _ => SendJob::parallel_batch(&self.outbound),
+        }
+    }
+
+    fn drain(&mut self) -> impl Iterator<Item = JobUnion<E, C, T, B>> + '_ {
+        self.inbound
+            .drain(..)
+            .map(JobUnion::Inbound)
+            .chain(self.outbound.drain(..).map(JobUnion::Outbound))
+    }
+}
+
 pub fn worker<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>>(
     receiver: Receiver<JobUnion<E, C, T, B>>,
 ) {
+    let mut batch = Batch::new();
     loop {
         log::trace!("pool worker awaiting job");
         match receiver.recv() {
@@ -23,13 +88,93 @@ pub fn worker<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>>(
                 log::debug!("worker stopped with {}", e);
                 break;
             }
-            Ok(JobUnion::Inbound(job)) => {
-                job.parallel_work();
-                job.queue().consume();
+            Ok(job) => batch.push(job),
+        }
+        batch.fill(&receiver);
+        batch.parallel_work();
+        for job in batch.drain() {
+            match job {
+                JobUnion::Inbound(job) => job.queue().consume(),
+                JobUnion::Outbound(job) => job.queue().consume(),
             }
-            Ok(JobUnion::Outbound(job)) => {
-                job.parallel_work();
-                job.queue().consume();
+        }
+    }
+}
+
+/* Awaits the next job of a stage of the performance mode:
+ *
+ * Spins on the queue while the router is up,
+ * while the router is down the stage blocks (rather than burning the core).
+ *
+ * # Returns
+ *
+ * None if the queue is closed
+ */
+fn next_job<J>(receiver: &Receiver<J>, spin: &AtomicBool) -> Option<J> {
+    loop {
+        match receiver.try_recv() {
+            Ok(job) => return Some(job),
+            Err(TryRecvError::Empty) if spin.load(Ordering::Relaxed) => spin_loop(),
+            Err(TryRecvError::Empty) => return receiver.recv().ok(),
+            Err(TryRecvError::Disconnected) => return None,
+        }
+    }
+}
+
+/* Crypto stage of the performance mode:
+ *
+ * Spins on the work queue (rather than blocking) and hands every processed job
+ * to the writer stage, which writes the messages in order.
+ */
+pub fn busy_worker<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>>(
+    receiver: Receiver<JobUnion<E, C, T, B>>,
+    writer: Sender<JobUnion<E, C, T, B>>,
+    spin: Arc<AtomicBool>,
+) {
+    let mut batch = Batch::new();
+    loop {
+        match next_job(&receiver, &spin) {
+            Some(job) => batch.push(job),
+            None => {
+                log::debug!("busy worker stopped (queue closed)");
+                break;
+            }
+        };
+        batch.fill(&receiver);
+        batch.parallel_work();
+
+        // spin until the ring to the writer has room
+        for mut job in batch.drain() {
+            loop {
+                match writer.try_send(job) {
+                    Ok(()) => break,
+                    Err(TrySendError::Full(back)) => {
+                        job = back;
+                        spin_loop();
+                    }
+                    Err(TrySendError::Disconnected(_)) => return,
+                }
+            }
+        }
+    }
+}
+
+/* Writer stage of the performance mode:
+ *
+ * Spins on the ring from the crypto stage and consumes the in-order queue of every job,
+ * i.e. writes the messages to the UDP / TUN writers.
+ */
+pub fn busy_writer<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>>(
+    receiver: Receiver<JobUnion<E, C, T, B>>,
+    spin: Arc<AtomicBool>,
+) {
+    loop {
+        match next_job(&receiver, &spin) {
+            Some(JobUnion::Inbound(job)) => job.queue().consume(),
+            Some(JobUnion::Outbound(job)) => job.queue().consume(),
+            None => {
+                log::debug!("busy writer stopped (crypto stage stopped)");
+                break;
             }
         }
     }
@@ -0,0 +1,192 @@
+/* Copy-on-write snapshots of state read on every message (e.g. the handshake device):
+ *
+ * readers load the current snapshot without ever blocking on (or contending with) writers,
+ * writers are serialized and modify a copy, which replaces the snapshot when the guard is dropped.
+ * Readers holding an older snapshot keep using it until they release it.
+ *
+ * Every write copies the value, hence it suits values written rarely (e.g. by the configuration),
+ * or values which share most of their state between copies (e.g. the handshake device).
+ *
+ * State which readers may hold beyond the snapshot (e.g. ids not yet handed to the router)
+ * is reclaimed after a grace period of the readers (see Readers).
+ */
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+use arc_swap::{ArcSwap, Guard};
+
+use super::counter::CachePadded;
+
+pub struct Snapshot<T: Clone> {
+    current: ArcSwap<T>,
+    writer: Mutex<()>, // serializes writers (no update is lost)
+}
+
+pub struct SnapshotWriteGuard<'a, T: Clone> {
+    snapshot: &'a Snapshot<T>,
+    value: Option<T>, // the modified copy (taken when published)
+    _writer: MutexGuard<'a, ()>,
+}
+
+impl<T: Clone> Snapshot<T> {
+    pub fn new(value: T) -> Snapshot<T> {
+        Snapshot {
+            current: ArcSwap::from_pointee(value),
+            writer: Mutex::new(()),
+        }
+    }
+
+    /// Returns the current snapshot (lock-free)
+    pub fn read(&self) -> Guard<'static, Arc<T>> {
+        self.current.load()
+    }
+
+    /// Returns a copy of the current snapshot to modify,
+    /// which replaces the snapshot once the guard is dropped
+    pub fn write(&self) -> SnapshotWriteGuard<T> {
+        let writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        SnapshotWriteGuard {
+            snapshot: self,
+            value: Some((**self.current.load()).clone()),
+            _writer: writer,
+        }
+    }
+}
+
+impl<'a, T: Clone> Deref for SnapshotWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value.as_ref().unwrap()
+    }
+}
+
+impl<'a, T: Clone> DerefMut for SnapshotWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value.as_mut().unwrap()
+    }
+}
+
+// publishes the copy (before the writer lock is released)
+impl<'a, T: Clone> Drop for SnapshotWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        if let Some(value) = self.value.take() {
+            self.snapshot.current.store(Arc::new(value));
+        }
+    }
+}
+
+/* Grace periods of a fixed set of readers (e.g. the handshake workers):
+ *
+ * Every reader marks the sections in which it acts on a snapshot in a counter of its own
+ * (odd while inside a section), hence readers never block or contend with each other.
+ * A writer waits for a grace period: until every reader inside a section when the wait began
+ * has left it, e.g. before reclaiming state such a reader may still hold.
+ */
+pub struct Readers {
+    sections: Vec<CachePadded<AtomicU64>>,
+}
+
+pub struct ReadSection<'a> {
+    section: &'a AtomicU64,
+}
+
+impl Readers {
+    pub fn new(readers: usize) -> Readers {
+        Readers {
+            sections: (0..readers)
+                .map(|_| CachePadded::new(AtomicU64::new(0)))
+                .collect(),
+        }
+    }
+
+    /// Enters a section of the reader (left when the section is dropped)
+    pub fn enter(&self, reader: usize) -> ReadSection {
+        let section = &*self.sections[reader];
+        section.fetch_add(1, Ordering::SeqCst);
+        ReadSection { section }
+    }
+
+    /// Waits until every section entered before the call has been left
+    /// (sections entered during the call are not waited for)
+    pub fn synchronize(&self) {
+        let entered: Vec<u64> = self
+            .sections
+            .iter()
+            .map(|section| section.load(Ordering::SeqCst))
+            .collect();
+        for (section, seq) in self.sections.iter().zip(entered) {
+            if seq % 2 == 1 {
+                while section.load(Ordering::SeqCst) == seq {
+                    thread::yield_now();
+                }
+            }
+        }
+    }
+}
+
+impl Drop for ReadSection<'_> {
+    fn drop(&mut self) {
+        self.section.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::AtomicBool;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_snapshot() {
+        let snapshot = Snapshot::new(vec![1]);
+        let old = snapshot.read();
+        {
+            let mut value = snapshot.write();
+            value.push(2);
+
+            // not published until the guard is dropped
+            assert_eq!(**snapshot.read(), vec![1]);
+        }
+        assert_eq!(**snapshot.read(), vec![1, 2]);
+
+        // readers keep the snapshot they loaded
+        assert_eq!(**old, vec![1]);
+    }
+
+    #[test]
+    fn test_readers_grace_period() {
+        let readers = Arc::new(Readers::new(2));
+
+        // no reader inside a section: returns immediately
+        readers.synchronize();
+
+        // a writer waits for the section entered before the wait began
+        let (entered_tx, entered_rx) = channel();
+        let (leave_tx, leave_rx) = channel::<()>();
+        let left = Arc::new(AtomicBool::new(false));
+        let reader = {
+            let readers = readers.clone();
+            let left = left.clone();
+            thread::spawn(move || {
+                let section = readers.enter(1);
+                entered_tx.send(()).unwrap();
+                leave_rx.recv().unwrap();
+                left.store(true, Ordering::SeqCst);
+                drop(section);
+            })
+        };
+        entered_rx.recv().unwrap();
+        leave_tx.send(()).unwrap();
+        readers.synchronize();
+        assert!(left.load(Ordering::SeqCst));
+        reader.join().unwrap();
+
+        // sections left before the wait are not waited for
+        drop(readers.enter(0));
+        readers.synchronize();
+    }
+}
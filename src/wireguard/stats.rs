@@ -0,0 +1,387 @@
+/* Snapshots of the device counters and the rates between two snapshots,
+ * for monitoring integrations polling the device (see WireGuard::stats_snapshot).
+ *
+ * The counters are monotonic (from the creation of the peer / device),
+ * rates are derived from the difference between two snapshots over the interval between them.
+ */
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use super::keys::PeerId;
+use super::router::InterfaceStats;
+
+/// Counters of a peer at the time of a snapshot
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerStats {
+    pub rx_bytes: u64,   // received bytes (transport and handshake messages)
+    pub tx_bytes: u64,   // transmitted bytes (transport and handshake messages)
+    pub rx_packets: u64, // transport messages received (including keepalives)
+    pub tx_packets: u64, // transport messages transmitted (including keepalives)
+    pub handshakes: u64, // completed handshakes
+}
+
+/// Traffic of a peer over an accounting interval (see PeerEvent::Usage)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerUsage {
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub rx_packets: u64,
+    pub tx_packets: u64,
+}
+
+impl PeerUsage {
+    /// Computes the usage from the counters reported earlier (None if never reported)
+    /// to the counters now, and whether the counters were reset in between.
+    ///
+    /// After a reset (e.g. the peer was removed and added again) the counters are counted from zero,
+    /// hence the traffic since the reset is never lost.
+    pub fn between(then: Option<&PeerStats>, now: &PeerStats) -> (PeerUsage, bool) {
+        let then = match then {
+            Some(then) => then,
+            None => return (PeerUsage::from(now), true),
+        };
+        if now.rx_bytes < then.rx_bytes
+            || now.tx_bytes < then.tx_bytes
+            || now.rx_packets < then.rx_packets
+            || now.tx_packets < then.tx_packets
+        {
+            return (PeerUsage::from(now), true);
+        }
+        let usage = PeerUsage {
+            rx_bytes: now.rx_bytes - then.rx_bytes,
+            tx_bytes: now.tx_bytes - then.tx_bytes,
+            rx_packets: now.rx_packets - then.rx_packets,
+            tx_packets: now.tx_packets - then.tx_packets,
+        };
+        (usage, false)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == PeerUsage::default()
+    }
+}
+
+impl From<&PeerStats> for PeerUsage {
+    fn from(stats: &PeerStats) -> PeerUsage {
+        PeerUsage {
+            rx_bytes: stats.rx_bytes,
+            tx_bytes: stats.tx_bytes,
+            rx_packets: stats.rx_packets,
+            tx_packets: stats.tx_packets,
+        }
+    }
+}
+
+/// Classification of received messages by the demultiplexer (the type field)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    Initiation,
+    Response,
+    CookieReply,
+    Transport,
+    Unknown, // unknown type, shorter than a type field or a malformed relay frame
+}
+
+impl MessageKind {
+    pub const ALL: [MessageKind; 5] = [
+        MessageKind::Initiation,
+        MessageKind::Response,
+        MessageKind::CookieReply,
+        MessageKind::Transport,
+        MessageKind::Unknown,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MessageKind::Initiation => "initiation",
+            MessageKind::Response => "response",
+            MessageKind::CookieReply => "cookie_reply",
+            MessageKind::Transport => "transport",
+            MessageKind::Unknown => "unknown",
+        }
+    }
+}
+
+/// Messages (and their bytes) received of a kind
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RxCount {
+    pub packets: u64,
+    pub bytes: u64, // UDP payload (including the relay frame, if any)
+}
+
+impl RxCount {
+    fn add(self, other: RxCount) -> RxCount {
+        RxCount {
+            packets: self.packets + other.packets,
+            bytes: self.bytes + other.bytes,
+        }
+    }
+}
+
+/// Messages received by kind, from sources of an address family
+/// (counted as they arrive: before any validation, hence including garbage)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RxKinds {
+    pub kinds: [RxCount; 5], // indexed by MessageKind (see MessageKind::ALL)
+}
+
+impl RxKinds {
+    pub fn get(&self, kind: MessageKind) -> RxCount {
+        self.kinds[kind as usize]
+    }
+
+    /// Returns the sum over every kind
+    pub fn total(&self) -> RxCount {
+        self.kinds
+            .iter()
+            .fold(RxCount::default(), |sum, count| sum.add(*count))
+    }
+}
+
+/// Messages received by kind and address family of the source
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RxStats {
+    pub v4: RxKinds,
+    pub v6: RxKinds,
+}
+
+impl RxStats {
+    /// Returns the count of the kind over both address families
+    pub fn get(&self, kind: MessageKind) -> RxCount {
+        self.v4.get(kind).add(self.v6.get(kind))
+    }
+
+    /// Returns the sum over every kind and address family
+    pub fn total(&self) -> RxCount {
+        self.v4.total().add(self.v6.total())
+    }
+}
+
+/// Counters of the device and every peer at an instant
+#[derive(Debug, Clone)]
+pub struct StatsSnapshot {
+    pub taken: Instant,
+    pub peers: HashMap<PeerId, PeerStats>,
+    pub handshake_malformed: u64, // handshake messages dropped (invalid length)
+    pub handshake_duplicate: u64, // initiations dropped (duplicate within window)
+    pub stealth_dropped: u64,     // messages left unanswered by stealth
+    pub received: RxStats,        // messages received by kind
+    pub interface: InterfaceStats, // counters of the interface
+}
+
+/// Rates of a peer between two snapshots (per second)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PeerRates {
+    pub rx_bps: f64, // received bits
+    pub tx_bps: f64, // transmitted bits
+    pub rx_pps: f64, // received transport messages
+    pub tx_pps: f64, // transmitted transport messages
+    pub handshakes: f64,
+}
+
+/// Rates of every peer between two snapshots
+#[derive(Debug, Clone)]
+pub struct StatsRates {
+    pub interval: Duration,
+    pub peers: HashMap<PeerId, PeerRates>,
+}
+
+impl StatsSnapshot {
+    /// Computes the rates from an earlier snapshot to this snapshot.
+    ///
+    /// Peers added after the earlier snapshot are counted from zero,
+    /// peers since removed are omitted.
+    /// A counter which decreased (e.g. the peer was removed and added again) yields a rate of zero.
+    pub fn rates_since(&self, earlier: &StatsSnapshot) -> StatsRates {
+        let interval = self
+            .taken
+            .checked_duration_since(earlier.taken)
+            .unwrap_or(Duration::from_secs(0));
+        let secs = interval.as_secs_f64();
+
+        let rate = |now: u64, then: u64| {
+            if secs > 0.0 {
+                now.saturating_sub(then) as f64 / secs
+            } else {
+                0.0
+            }
+        };
+
+        let peers = self
+            .peers
+            .iter()
+            .map(|(id, now)| {
+                let then = earlier.peers.get(id).copied().unwrap_or_default();
+                let rates = PeerRates {
+                    rx_bps: rate(now.rx_bytes, then.rx_bytes) * 8.0,
+                    tx_bps: rate(now.tx_bytes, then.tx_bytes) * 8.0,
+                    rx_pps: rate(now.rx_packets, then.rx_packets),
+                    tx_pps: rate(now.tx_packets, then.tx_packets),
+                    handshakes: rate(now.handshakes, then.handshakes),
+                };
+                (*id, rates)
+            })
+            .collect();
+
+        StatsRates { interval, peers }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use x25519_dalek::PublicKey;
+
+    fn snapshot(taken: Instant, peers: &[(PeerId, PeerStats)]) -> StatsSnapshot {
+        StatsSnapshot {
+            taken,
+            peers: peers.iter().cloned().collect(),
+            handshake_malformed: 0,
+            handshake_duplicate: 0,
+            stealth_dropped: 0,
+            received: RxStats::default(),
+            interface: InterfaceStats::default(),
+        }
+    }
+
+    #[test]
+    fn test_rates_since() {
+        let id1 = PeerId::new(PublicKey::from([1u8; 32]));
+        let id2 = PeerId::new(PublicKey::from([2u8; 32]));
+        let id3 = PeerId::new(PublicKey::from([3u8; 32]));
+        let start = Instant::now();
+
+        let earlier = snapshot(
+            start,
+            &[
+                (
+                    id1,
+                    PeerStats {
+                        rx_bytes: 1000,
+                        tx_bytes: 500,
+                        rx_packets: 10,
+                        tx_packets: 5,
+                        handshakes: 1,
+                    },
+                ),
+                (id3, PeerStats::default()),
+            ],
+        );
+        let later = snapshot(
+            start + Duration::from_secs(2),
+            &[
+                (
+                    id1,
+                    PeerStats {
+                        rx_bytes: 3000,
+                        tx_bytes: 100, // peer re-added: counter reset
+                        rx_packets: 30,
+                        tx_packets: 1,
+                        handshakes: 3,
+                    },
+                ),
+                (
+                    id2,
+                    PeerStats {
+                        rx_bytes: 200,
+                        ..Default::default()
+                    },
+                ),
+            ],
+        );
+
+        let rates = later.rates_since(&earlier);
+        assert_eq!(rates.interval, Duration::from_secs(2));
+        assert_eq!(rates.peers.len(), 2);
+        assert_eq!(
+            rates.peers[&id1],
+            PeerRates {
+                rx_bps: 8000.0,
+                tx_bps: 0.0,
+                rx_pps: 10.0,
+                tx_pps: 0.0,
+                handshakes: 1.0,
+            }
+        );
+        assert_eq!(rates.peers[&id2].rx_bps, 800.0);
+        assert!(!rates.peers.contains_key(&id3));
+
+        // no interval: no rates
+        let rates = later.rates_since(&later);
+        assert_eq!(rates.peers[&id1], PeerRates::default());
+    }
+
+    #[test]
+    fn test_rx_stats() {
+        let mut stats = RxStats::default();
+        stats.v4.kinds[MessageKind::Initiation as usize] = RxCount {
+            packets: 2,
+            bytes: 296,
+        };
+        stats.v6.kinds[MessageKind::Initiation as usize] = RxCount {
+            packets: 1,
+            bytes: 148,
+        };
+        stats.v6.kinds[MessageKind::Unknown as usize] = RxCount {
+            packets: 1,
+            bytes: 3,
+        };
+        assert_eq!(
+            stats.get(MessageKind::Initiation),
+            RxCount {
+                packets: 3,
+                bytes: 444
+            }
+        );
+        assert_eq!(stats.get(MessageKind::Transport), RxCount::default());
+        assert_eq!(stats.v6.total().packets, 2);
+        assert_eq!(
+            stats.total(),
+            RxCount {
+                packets: 4,
+                bytes: 447
+            }
+        );
+        for (idx, kind) in MessageKind::ALL.iter().enumerate() {
+            assert_eq!(*kind as usize, idx);
+        }
+    }
+
+    #[test]
+    fn test_usage_between() {
+        let stats = |rx_bytes, tx_bytes| PeerStats {
+            rx_bytes,
+            tx_bytes,
+            rx_packets: rx_bytes / 100,
+            tx_packets: tx_bytes / 100,
+            handshakes: 1,
+        };
+
+        // first report: counted from zero
+        let (usage, reset) = PeerUsage::between(None, &stats(1000, 500));
+        assert!(reset);
+        assert_eq!(usage, PeerUsage::from(&stats(1000, 500)));
+
+        // delta over the interval
+        let (usage, reset) = PeerUsage::between(Some(&stats(1000, 500)), &stats(1500, 500));
+        assert!(!reset);
+        assert_eq!(
+            usage,
+            PeerUsage {
+                rx_bytes: 500,
+                rx_packets: 5,
+                ..Default::default()
+            }
+        );
+
+        // idle interval
+        let (usage, reset) = PeerUsage::between(Some(&stats(1500, 500)), &stats(1500, 500));
+        assert!(!reset && usage.is_empty());
+
+        // counters reset (e.g. peer re-added): counted from zero
+        let (usage, reset) = PeerUsage::between(Some(&stats(1500, 500)), &stats(200, 700));
+        assert!(reset);
+        assert_eq!(usage, PeerUsage::from(&stats(200, 700)));
+    }
+}
@@ -1,10 +1,22 @@
+use super::constants::MAX_QUEUED_EVENTS;
 use super::dummy;
+use super::types::ProtocolTimers;
 use super::wireguard::WireGuard;
+use super::{
+    parse_message, peek_initiator, Blackhole, HandshakeMessage, MessageBuilder, PeerEvent, PeerId,
+    Verdict,
+};
+
+use crate::platform::udp::Reader;
 
 use std::convert::TryInto;
 use std::net::IpAddr;
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use hex;
+use rand::rngs::OsRng;
 use rand_chacha::ChaCha8Rng;
 use rand_core::{RngCore, SeedableRng};
 use x25519_dalek::{PublicKey, StaticSecret};
@@ -70,23 +82,350 @@ fn init() {
  */
 #[test]
 fn test_pure_wireguard() {
+    pure_wireguard(1500, 50);
+}
+
+/* Same as above with jumbo frames:
+ * IP packets (much) larger than any physical MTU
+ * which must be carried by a single UDP datagram.
+ */
+#[test]
+fn test_pure_wireguard_jumbo() {
+    pure_wireguard(65535, 3000);
+}
+
+/* Inject failures into the transport and TUN devices of a pair of instances.
+ *
+ * Test:
+ *
+ * - Failed writes and messages failing to decrypt drop only the affected packets
+ * - The packets delivered despite the failures are unmodified and in-order
+ * - Stalled TUN reads delay, but do not drop, packets
+ * - Traffic recovers once the failures stop
+ */
+#[test]
+fn test_pure_wireguard_faults() {
+    init();
+
+    let faults = dummy::Faults::new();
+    let (_wg1, _wg2, fake1, fake2) = pure_pair(1500, &faults);
+    let src: IpAddr = "192.168.1.20".parse().unwrap();
+    let dst: IpAddr = "192.168.2.10".parse().unwrap();
+
+    // complete the handshake without failures
+    let packet = make_packet(100, src, dst, 0);
+    fake1.write(packet.clone());
+    assert_eq!(fake2.read(), packet);
+
+    // fail every 4th write, corrupt every 3rd transport message and stall TUN reads
+    faults.fail_writes(4);
+    faults.corrupt_messages(3);
+    faults.stall_reads(Duration::from_millis(5));
+
+    let packets: Vec<Vec<u8>> = (1..=30).map(|id| make_packet(100, src, dst, id)).collect();
+    for packet in packets.iter() {
+        fake1.write(packet.clone());
+    }
+    let mut delivered = vec![];
+    while let Some(packet) = fake2.read_timeout(Duration::from_secs(1)) {
+        delivered.push(packet);
+    }
+
+    assert!(faults.write_errors() > 0, "no write failed");
+    assert!(faults.corrupted() > 0, "no message corrupted");
+    assert!(
+        !delivered.is_empty(),
+        "no packet delivered despite the failures"
+    );
+    assert!(delivered.len() < packets.len(), "no packet dropped");
+
+    // the delivered packets are a subsequence of the packets sent
+    let mut sent = packets.iter();
+    for packet in delivered.iter() {
+        assert!(
+            sent.any(|p| p == packet),
+            "packet modified, duplicated or reordered"
+        );
+    }
+
+    // recovery
+    faults.fail_writes(0);
+    faults.corrupt_messages(0);
+    faults.stall_reads(Duration::from_secs(0));
+    let packet = make_packet(100, src, dst, 100);
+    fake1.write(packet.clone());
+    assert_eq!(fake2.read(), packet);
+}
+
+/* Bring a device down with the shutdown flush enabled.
+ *
+ * Test:
+ *
+ * - The outbound queues are drained before the device is down
+ * - A final keepalive reaches the peer
+ */
+#[test]
+fn test_pure_wireguard_shutdown_flush() {
+    init();
+
+    let (wg1, wg2, fake1, fake2) = pure_pair(1500, &dummy::Faults::new());
+    let src: IpAddr = "192.168.1.20".parse().unwrap();
+    let dst: IpAddr = "192.168.2.10".parse().unwrap();
+
+    // complete the handshake
+    let packet = make_packet(100, src, dst, 0);
+    fake1.write(packet.clone());
+    assert_eq!(fake2.read(), packet);
+
+    let received = || {
+        wg2.peers
+            .read()
+            .iter()
+            .filter_map(|(_, peer)| peer.session())
+            .map(|session| session.received)
+            .sum::<u64>()
+    };
+    let before = received();
+
+    wg1.set_shutdown_flush(Some(Duration::from_secs(1)));
+    wg1.down();
+    assert!(wg1
+        .peers
+        .read()
+        .iter()
+        .all(|(_, peer)| !peer.outbound_pending()));
+
+    // the final keepalive is received
+    let deadline = Instant::now() + Duration::from_secs(1);
+    while received() == before {
+        assert!(
+            Instant::now() < deadline,
+            "no keepalive sent on device down"
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/* Create a device on the dummy platform (see WireGuard::new_dummy).
+ *
+ * Test:
+ *
+ * - A packet to a peer without a session causes a handshake initiation
+ * - The unanswered initiation is retransmitted after the short rekey timeout
+ */
+#[test]
+fn test_dummy_wireguard() {
+    init();
+
+    let (wg, fake, (remote_reader, _remote_writer)) = WireGuard::new_dummy();
+    let rekey_timeout = wg.protocol_timers.rekey_timeout;
+    assert_eq!(rekey_timeout, ProtocolTimers::short().rekey_timeout);
+
+    let sk = StaticSecret::new(&mut OsRng);
+    let pk = PublicKey::from(&StaticSecret::new(&mut OsRng));
+    wg.set_key(Some(sk));
+    wg.add_peer(pk);
+    {
+        let peers = wg.peers.read();
+        let peer = peers.get(&pk).unwrap();
+        peer.add_allowed_ip("192.168.2.0".parse().unwrap(), 24);
+        peer.set_endpoint(dummy::UnitEndpoint::new());
+    }
+
+    // read the messages sent by the device
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        let mut buf = vec![0u8; 2048];
+        while let Ok((len, _)) = remote_reader.read(&mut buf) {
+            if tx.send(buf[..len].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+
+    fake.write(make_packet(
+        100,
+        "192.168.1.20".parse().unwrap(),
+        "192.168.2.10".parse().unwrap(),
+        0,
+    ));
+
+    // initiation and retransmission (well before the default rekey timeout)
+    let start = Instant::now();
+    for _ in 0..2 {
+        let msg = rx
+            .recv_timeout(rekey_timeout * 4)
+            .expect("no handshake initiation sent");
+        assert_eq!(msg[0], 1, "expected a handshake initiation");
+    }
+    assert!(start.elapsed() >= rekey_timeout);
+    assert!(start.elapsed() < ProtocolTimers::default().rekey_timeout);
+}
+
+/* Test that a peer not answering handshakes is reported and its retries are suspended:
+ *
+ * - The unanswered initiation emits an Unreachable event
+ * - Outbound packets do not lift the cool-down (no further initiations are sent)
+ */
+#[test]
+fn test_dummy_wireguard_blackhole() {
     init();
 
+    let (wg, fake, (remote_reader, _remote_writer)) = WireGuard::new_dummy();
+    let rekey_timeout = wg.protocol_timers.rekey_timeout;
+    wg.set_blackhole(Blackhole {
+        attempts: 1,
+        cooldown: Duration::from_secs(3600),
+    });
+    let events = wg.subscribe();
+
+    let sk = StaticSecret::new(&mut OsRng);
+    let pk = PublicKey::from(&StaticSecret::new(&mut OsRng));
+    wg.set_key(Some(sk));
+    wg.add_peer(pk);
+    {
+        let peers = wg.peers.read();
+        let peer = peers.get(&pk).unwrap();
+        peer.add_allowed_ip("192.168.2.0".parse().unwrap(), 24);
+        peer.set_endpoint(dummy::UnitEndpoint::new());
+    }
+
+    // read the messages sent by the device
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        let mut buf = vec![0u8; 2048];
+        while let Ok((len, _)) = remote_reader.read(&mut buf) {
+            if tx.send(buf[..len].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+
+    let packet = |id| {
+        make_packet(
+            100,
+            "192.168.1.20".parse().unwrap(),
+            "192.168.2.10".parse().unwrap(),
+            id,
+        )
+    };
+
+    // the initiation is not answered: the peer is reported unreachable
+    fake.write(packet(0));
+    match events.recv_timeout(rekey_timeout * 4) {
+        Ok(PeerEvent::Unreachable { peer, attempts }) => {
+            assert_eq!(peer, PeerId::new(pk));
+            assert_eq!(attempts, 1);
+        }
+        res => panic!("expected an unreachable event, got {:?}", res),
+    }
+    while rx.recv_timeout(rekey_timeout / 2).is_ok() {}
+
+    // a further packet to the peer does not resume the handshakes
+    fake.write(packet(1));
+    assert!(
+        rx.recv_timeout(rekey_timeout * 2).is_err(),
+        "handshake initiated during the cool-down"
+    );
+    let peers = wg.peers.read();
+    assert!(peers.get(&pk).unwrap().opaque().handshakes_suspended());
+}
+
+/* Test that events are dropped (rather than blocking the device) for a lagging subscriber,
+ * and that closed subscriptions are removed.
+ */
+#[test]
+fn test_events_bounded() {
+    let (wg, _fake, _remote) = WireGuard::new_dummy();
+    let peer = PeerId::new(PublicKey::from([1u8; 32]));
+    let event = |attempts| PeerEvent::Unreachable { peer, attempts };
+
+    let events = wg.subscribe();
+    for attempts in 0..MAX_QUEUED_EVENTS + 10 {
+        wg.emit(event(attempts));
+    }
+    assert_eq!(events.try_iter().count(), MAX_QUEUED_EVENTS);
+
+    // the subscription is retained after lagging
+    wg.emit(event(0));
+    assert!(events.try_recv().is_ok());
+
+    drop(events);
+    wg.emit(event(0));
+    assert!(wg.events.lock().is_empty());
+}
+
+/* Test that a captured initiation is attributed to the initiator (see WireGuard::inspect),
+ * the initiation is crafted without a device (see MessageBuilder).
+ */
+#[test]
+fn test_inspect_initiation() {
+    let (wg, _fake, _remote) = WireGuard::new_dummy();
+    let sk1 = StaticSecret::from([1u8; 32]);
+    let sk2 = StaticSecret::from([2u8; 32]);
+    let pk1 = PublicKey::from(&sk1);
+    wg.set_key(Some(StaticSecret::from([2u8; 32])));
+
+    let builder = MessageBuilder::new(sk1, PublicKey::from(&sk2), [0u8; 32]).unwrap();
+    let msg = builder.initiation(&mut OsRng).unwrap();
+    let sender = match parse_message(&msg).unwrap() {
+        HandshakeMessage::Initiation { sender, .. } => sender,
+        m => panic!("unexpected message: {:?}", m),
+    };
+    assert_eq!(
+        peek_initiator(&sk2, &msg).unwrap().as_bytes(),
+        pk1.as_bytes()
+    );
+
+    // the initiator is not a peer (yet)
+    let inspection = wg.inspect(&msg);
+    assert_eq!(inspection.sender, Some(sender));
+    assert_eq!(inspection.receiver, None);
+    assert_eq!(
+        inspection.peer.map(|pk| *pk.as_bytes()),
+        Some(*pk1.as_bytes())
+    );
+    assert!(inspection
+        .to_string()
+        .contains(&format!("sender = {}", sender)));
+
+    wg.add_peer(pk1);
+    match wg.inspect(&msg).verdict {
+        Verdict::Accepted => (),
+        v => panic!("unexpected verdict: {:?}", v),
+    }
+}
+
+/* Create and configure two matching pure instances of WireGuard,
+ * connected by a pair bind (with the failures injected, see dummy::Faults).
+ *
+ * Returns the instances and the other ends of their TUN devices.
+ */
+fn pure_pair(
+    mtu: usize,
+    faults: &dummy::Faults,
+) -> (
+    WireGuard<dummy::TunTest, dummy::PairBind>,
+    WireGuard<dummy::TunTest, dummy::PairBind>,
+    dummy::TunFakeIO,
+    dummy::TunFakeIO,
+) {
     // create WG instances for dummy TUN devices
 
-    let (fake1, tun_reader1, tun_writer1, _) = dummy::TunTest::create(true);
+    let (fake1, tun_reader1, tun_writer1, _) = dummy::TunTest::create_with(true, faults);
     let wg1: WireGuard<dummy::TunTest, dummy::PairBind> = WireGuard::new(tun_writer1);
     wg1.add_tun_reader(tun_reader1);
-    wg1.up(1500);
+    wg1.up(mtu);
 
-    let (fake2, tun_reader2, tun_writer2, _) = dummy::TunTest::create(true);
+    let (fake2, tun_reader2, tun_writer2, _) = dummy::TunTest::create_with(true, faults);
     let wg2: WireGuard<dummy::TunTest, dummy::PairBind> = WireGuard::new(tun_writer2);
     wg2.add_tun_reader(tun_reader2);
-    wg2.up(1500);
+    wg2.up(mtu);
 
     // create pair bind to connect the interfaces "over the internet"
 
-    let ((bind_reader1, bind_writer1), (bind_reader2, bind_writer2)) = dummy::PairBind::pair();
+    let ((bind_reader1, bind_writer1), (bind_reader2, bind_writer2)) =
+        dummy::PairBind::pair_with(faults);
 
     wg1.set_writer(bind_writer1);
     wg2.set_writer(bind_writer2);
@@ -136,6 +475,14 @@ fn test_pure_wireguard() {
         peer2.set_endpoint(dummy::UnitEndpoint::new());
     }
 
+    (wg1, wg2, fake1, fake2)
+}
+
+fn pure_wireguard(mtu: usize, step: usize) {
+    init();
+
+    let (_wg1, _wg2, fake1, fake2) = pure_pair(mtu, &dummy::Faults::new());
+
     let num_packets = 20;
 
     // send IP packets (causing a new handshake)
@@ -145,7 +492,7 @@ fn test_pure_wireguard() {
 
         for id in 0..num_packets {
             packets.push(make_packet(
-                50 * id as usize,                // size
+                step * id as usize,              // size
                 "192.168.1.20".parse().unwrap(), // src
                 "192.168.2.10".parse().unwrap(), // dst
                 id as u64,                       // prng seed
@@ -176,7 +523,7 @@ fn test_pure_wireguard() {
 
         for id in 0..num_packets {
             packets.push(make_packet(
-                50 + 50 * id as usize,           // size
+                step + step * id as usize,       // size
                 "192.168.2.10".parse().unwrap(), // src
                 "192.168.1.20".parse().unwrap(), // dst
                 (id + 100) as u64,               // prng seed
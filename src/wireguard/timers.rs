@@ -11,7 +11,7 @@ use super::constants::*;
 use super::peer::PeerInner;
 use super::router::{message_data_len, Callbacks};
 use super::tun::Tun;
-use super::types::KeyPair;
+use super::types::{HandshakeState, KeepaliveSuppression, KeyPair, PeerEvent, ProtocolTimers};
 use super::udp::UDP;
 use super::WireGuard;
 
@@ -19,6 +19,8 @@ pub struct Timers {
     // only updated during configuration
     enabled: bool,
     keepalive_interval: u64,
+    keepalive_suppression: KeepaliveSuppression,
+    cover_interval: u64, // milliseconds
 
     handshake_attempts: AtomicUsize,
     sent_lastminute_handshake: AtomicBool,
@@ -27,6 +29,7 @@ pub struct Timers {
     retransmit_handshake: Timer,
     send_keepalive: Timer,
     send_persistent_keepalive: Timer,
+    send_cover_traffic: Timer,
     zero_key_material: Timer,
     new_handshake: Timer,
 }
@@ -43,6 +46,35 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
         self.timers().keepalive_interval
     }
 
+    // delay of the next persistent keepalive (lengthened and coalesced in power-save)
+    fn persistent_keepalive_delay(&self, secs: u64) -> Duration {
+        let after = Duration::from_secs(secs);
+        match *self.wg.power_save.read() {
+            Some((ps, since)) => ps.delay(since.elapsed(), after),
+            None => after,
+        }
+    }
+
+    /* Reschedules the pending persistent keepalive,
+     * e.g. after entering / leaving power-save (see WireGuard::set_power_save)
+     */
+    pub fn rearm_persistent_keepalive(&self) {
+        let timers = self.timers();
+        if timers.enabled && timers.keepalive_interval > 0 {
+            timers
+                .send_persistent_keepalive
+                .reset(self.persistent_keepalive_delay(timers.keepalive_interval));
+        }
+    }
+
+    pub fn get_keepalive_suppression(&self) -> KeepaliveSuppression {
+        self.timers().keepalive_suppression
+    }
+
+    pub fn get_cover_traffic_interval(&self) -> u64 {
+        self.timers().cover_interval
+    }
+
     pub fn stop_timers(&self) {
         // take a write lock preventing simultaneous timer events or "start_timers" call
         let mut timers = self.timers_mut();
@@ -57,6 +89,7 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
         timers.retransmit_handshake.stop();
         timers.send_keepalive.stop();
         timers.send_persistent_keepalive.stop();
+        timers.send_cover_traffic.stop();
         timers.zero_key_material.stop();
         timers.new_handshake.stop();
 
@@ -68,7 +101,10 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
         timers.need_another_keepalive.store(false, Ordering::SeqCst);
     }
 
-    pub fn start_timers(&self) {
+    /* Starts the timers, the first persistent keepalive (initiating a handshake) after the delay
+     * (see WireGuard::set_handshake_spread)
+     */
+    pub fn start_timers(&self, delay: Duration) {
         // take a write lock preventing simultaneous "stop_timers" call
         let mut timers = self.timers_mut();
 
@@ -80,9 +116,14 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
 
         // start send_persistent_keepalive
         if timers.keepalive_interval > 0 {
+            timers.send_persistent_keepalive.start(delay);
+        }
+
+        // start send_cover_traffic
+        if timers.cover_interval > 0 {
             timers
-                .send_persistent_keepalive
-                .start(Duration::from_secs(0));
+                .send_cover_traffic
+                .start(Duration::from_millis(timers.cover_interval));
         }
     }
 
@@ -90,16 +131,21 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
     pub fn timers_data_sent(&self) {
         let timers = self.timers();
         if timers.enabled {
+            let protocol = &self.wg.protocol_timers;
             timers
                 .new_handshake
-                .start(KEEPALIVE_TIMEOUT + REKEY_TIMEOUT);
+                .start(protocol.keepalive_timeout + protocol.rekey_timeout);
         }
     }
 
     /* should be called after an authenticated data packet is received */
     pub fn timers_data_received(&self) {
         let timers = self.timers();
-        if timers.enabled && !timers.send_keepalive.start(KEEPALIVE_TIMEOUT) {
+        if timers.enabled
+            && !timers
+                .send_keepalive
+                .start(self.wg.protocol_timers.keepalive_timeout)
+        {
             timers.need_another_keepalive.store(true, Ordering::SeqCst)
         }
     }
@@ -136,7 +182,9 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
         let timers = self.timers();
         if timers.enabled {
             timers.send_keepalive.stop();
-            timers.retransmit_handshake.reset(REKEY_TIMEOUT);
+            timers
+                .retransmit_handshake
+                .reset(self.wg.protocol_timers.rekey_timeout);
         }
     }
 
@@ -156,6 +204,19 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
         }
     }
 
+    /* Should be called when the peer is disabled (quota exceeded):
+     * stops handshake retries and keepalives and discards the key-material.
+     */
+    pub fn timers_peer_disabled(&self) {
+        log::trace!("timers_peer_disabled");
+        let timers = self.timers();
+        if timers.enabled {
+            timers.retransmit_handshake.stop();
+            timers.send_keepalive.stop();
+            timers.zero_key_material.reset(Duration::from_secs(0));
+        }
+    }
+
     /* Should be called after an ephemeral key is created, which is before sending a
      * handshake response or after receiving a handshake response.
      */
@@ -163,21 +224,26 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
         log::trace!("timers_session_derived");
         let timers = self.timers();
         if timers.enabled {
-            timers.zero_key_material.reset(REJECT_AFTER_TIME * 3);
+            timers
+                .zero_key_material
+                .reset(self.wg.protocol_timers.reject_after_time * 3);
         }
     }
 
     /* Should be called before a packet with authentication, whether
-     * keepalive, data, or handshake is sent, or after one is received.
+     * keepalive, data, or handshake is sent (sent = true), or after one is received.
      */
-    pub fn timers_any_authenticated_packet_traversal(&self) {
+    pub fn timers_any_authenticated_packet_traversal(&self, sent: bool) {
         log::trace!("timers_any_authenticated_packet_traversal");
         let timers = self.timers();
-        if timers.enabled && timers.keepalive_interval > 0 {
+        if timers.enabled
+            && timers.keepalive_interval > 0
+            && timers.keepalive_suppression.postpones(sent)
+        {
             // push persistent_keepalive into the future
             timers
                 .send_persistent_keepalive
-                .reset(Duration::from_secs(timers.keepalive_interval));
+                .reset(self.persistent_keepalive_delay(timers.keepalive_interval));
         }
     }
 
@@ -185,7 +251,9 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
         log::trace!("timers_set_retransmit_handshake");
         let timers = self.timers();
         if timers.enabled {
-            timers.retransmit_handshake.reset(REKEY_TIMEOUT);
+            timers
+                .retransmit_handshake
+                .reset(self.wg.protocol_timers.rekey_timeout);
         }
     }
 
@@ -195,14 +263,16 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
         *self.last_handshake_sent.lock() = Instant::now();
         self.timers_handshake_initiated();
         self.timers_set_retransmit_handshake();
-        self.timers_any_authenticated_packet_traversal();
+        self.timers_any_authenticated_packet_traversal(true);
         self.timers_any_authenticated_packet_sent();
+        self.set_handshake_state(HandshakeState::InitiationSent);
     }
 
     pub fn sent_handshake_response(&self) {
         *self.last_handshake_sent.lock() = Instant::now();
-        self.timers_any_authenticated_packet_traversal();
+        self.timers_any_authenticated_packet_traversal(true);
         self.timers_any_authenticated_packet_sent();
+        self.set_handshake_state(HandshakeState::ResponseSent);
     }
 
     pub fn set_persistent_keepalive_interval(&self, secs: u64) {
@@ -222,7 +292,77 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
         }
     }
 
+    /* Sets which traffic postpones the persistent keepalives (see KeepaliveSuppression),
+     * e.g. KeepaliveSuppression::Sent behind NATs which expire mappings without outbound traffic.
+     */
+    pub fn set_keepalive_suppression(&self, suppression: KeepaliveSuppression) {
+        self.timers_mut().keepalive_suppression = suppression;
+    }
+
+    /* Enables constant-rate cover traffic:
+     * every interval a dummy transport message padded to the MTU is sent,
+     * hiding idle periods from an observer of the outer flow.
+     *
+     * Cover traffic is only sent while a session exists (it never initiates a handshake).
+     * An interval of 0 disables cover traffic.
+     */
+    pub fn set_cover_traffic_interval(&self, millis: u64) {
+        let mut timers = self.timers_mut();
+
+        // update the stored cover_interval
+        timers.cover_interval = millis;
+
+        // stop the cover traffic timer with the old interval
+        timers.send_cover_traffic.stop();
+
+        // schedule the first interval
+        if millis > 0 && timers.enabled {
+            timers
+                .send_cover_traffic
+                .start(Duration::from_millis(millis));
+        }
+    }
+
+    /* Called when `unanswered` successive initiations have not been answered.
+     *
+     * Emits an Unreachable event once the threshold of the blackhole policy is reached.
+     * Returns true if the retries have been suspended.
+     */
+    fn detect_blackhole(&self, timers: &Timers, unanswered: usize) -> bool {
+        let policy = self.wg.get_blackhole();
+        if policy.attempts == 0 || unanswered != policy.attempts {
+            return false;
+        }
+
+        log::info!(
+            "{} : no answer to {} handshake initiations, peer unreachable",
+            self,
+            unanswered
+        );
+        self.wg.emit(PeerEvent::Unreachable {
+            peer: self.id(),
+            attempts: unanswered,
+        });
+
+        if policy.cooldown == Duration::from_secs(0) {
+            return false;
+        }
+
+        debug!(
+            "{} : suspending handshakes for {} seconds",
+            self,
+            policy.cooldown.as_secs()
+        );
+        *self.suspended.lock() = Some(Instant::now());
+        timers.send_keepalive.stop();
+        true
+    }
+
     fn packet_send_queued_handshake_initiation(&self, is_retry: bool) {
+        if self.handshakes_suspended() {
+            log::trace!("{} : handshakes suspended", self);
+            return;
+        }
         if !is_retry {
             self.timers().handshake_attempts.store(0, Ordering::SeqCst);
         }
@@ -263,6 +403,8 @@ impl Timers {
         Timers {
             enabled: running,
             keepalive_interval: 0, // disabled
+            keepalive_suppression: KeepaliveSuppression::default(),
+            cover_interval: 0, // disabled
             need_another_keepalive: AtomicBool::new(false),
             sent_lastminute_handshake: AtomicBool::new(false),
             handshake_attempts: AtomicUsize::new(0),
@@ -283,17 +425,26 @@ impl Timers {
                             attempts + 1
                         );
                         timers.send_keepalive.stop();
-                        timers.zero_key_material.start(REJECT_AFTER_TIME * 3);
+                        timers
+                            .zero_key_material
+                            .start(wg.protocol_timers.reject_after_time * 3);
+                        peer.purge_staged_packets();
+                        peer.set_handshake_state(HandshakeState::Failed);
+                        peer.report_path_failed(peer.get_endpoint(), true);
+                    } else if peer.detect_blackhole(&timers, attempts + 1) {
                         peer.purge_staged_packets();
                     } else {
                         debug!(
-                            "Handshake for {} did not complete after {} seconds, retrying (try {})",
-                            peer,
-                            REKEY_TIMEOUT.as_secs(),
-                            attempts
+                            "Handshake for {} did not complete after {:?}, retrying (try {})",
+                            peer, wg.protocol_timers.rekey_timeout, attempts
                         );
-                        timers.retransmit_handshake.reset(REKEY_TIMEOUT);
+                        timers
+                            .retransmit_handshake
+                            .reset(wg.protocol_timers.rekey_timeout);
                         peer.clear_src();
+                        peer.report_path_failed(peer.get_endpoint(), false);
+                        peer.fallback_relay();
+                        peer.restart_endpoint_race();
                         peer.packet_send_queued_handshake_initiation(true);
                     }
                 })
@@ -309,7 +460,9 @@ impl Timers {
                     // send keepalive and schedule next keepalive
                     peer.send_keepalive();
                     if timers.need_another_keepalive() {
-                        timers.send_keepalive.start(KEEPALIVE_TIMEOUT);
+                        timers
+                            .send_keepalive
+                            .start(wg.protocol_timers.keepalive_timeout);
                     }
                 })
             },
@@ -323,11 +476,14 @@ impl Timers {
 
                     // clear source and retry
                     log::debug!(
-                        "Retrying handshake with {} because we stopped hearing back after {} seconds",
+                        "Retrying handshake with {} because we stopped hearing back after {:?}",
                         peer,
-                        (KEEPALIVE_TIMEOUT + REKEY_TIMEOUT).as_secs()
+                        wg.protocol_timers.keepalive_timeout + wg.protocol_timers.rekey_timeout
                     );
                     peer.clear_src();
+                    peer.report_path_failed(peer.get_endpoint(), false);
+                    peer.fallback_relay();
+                    peer.restart_endpoint_race();
                     peer.packet_send_queued_handshake_initiation(false);
                 })
             },
@@ -341,6 +497,7 @@ impl Timers {
 
                     // null all key-material
                     peer.zero_keys();
+                    peer.set_handshake_state(HandshakeState::Idle);
                 })
             },
             send_persistent_keepalive: {
@@ -359,7 +516,31 @@ impl Timers {
                         log::trace!("{} : keepalive queued", peer);
                         timers
                             .send_persistent_keepalive
-                            .start(Duration::from_secs(timers.keepalive_interval));
+                            .start(peer.persistent_keepalive_delay(timers.keepalive_interval));
+                    }
+                })
+            },
+            send_cover_traffic: {
+                let wg = wg.clone();
+                let pk = pk.clone();
+                runner.timer(move || {
+                    // fetch peer by public key
+                    fetch_peer!(wg, pk, peer);
+                    fetch_timers!(peer, timers);
+                    log::trace!("{} : timer fired (send_cover_traffic)", peer);
+
+                    // send a dummy message padded to the MTU and schedule the next interval
+                    // (suspended in power-save)
+                    if timers.cover_interval > 0 {
+                        let size = peer
+                            .get_mtu()
+                            .unwrap_or_else(|| wg.mtu.load(Ordering::Relaxed));
+                        if size > 0 && wg.power_save.read().is_none() && peer.send_cover(size) {
+                            log::trace!("{} : cover traffic queued", peer);
+                        }
+                        timers
+                            .send_cover_traffic
+                            .start(Duration::from_millis(timers.cover_interval));
                     }
                 })
             },
@@ -379,21 +560,22 @@ impl<T: Tun, B: UDP> Callbacks for PeerInner<T, B> {
 
         // update timers and stats
 
-        peer.timers_any_authenticated_packet_traversal();
+        peer.timers_any_authenticated_packet_traversal(true);
         peer.timers_any_authenticated_packet_sent();
-        peer.tx_bytes.fetch_add(size as u64, Ordering::Relaxed);
+        peer.traffic.add_tx(size as u64, 1);
+        peer.account_quota(size);
         if size > message_data_len(0) && sent {
             peer.timers_data_sent();
         }
 
         // keep_key_fresh
 
-        fn keep_key_fresh(keypair: &Arc<KeyPair>, counter: u64) -> bool {
+        fn keep_key_fresh(keypair: &Arc<KeyPair>, counter: u64, rekey_after: Duration) -> bool {
             counter > REKEY_AFTER_MESSAGES
-                || (keypair.initiator && Instant::now() - keypair.birth > REKEY_AFTER_TIME)
+                || (keypair.initiator && Instant::now() - keypair.birth > rekey_after)
         }
 
-        if keep_key_fresh(keypair, counter) {
+        if keep_key_fresh(keypair, counter, peer.wg.protocol_timers.rekey_after_time) {
             peer.packet_send_queued_handshake_initiation(false);
         }
     }
@@ -411,21 +593,24 @@ impl<T: Tun, B: UDP> Callbacks for PeerInner<T, B> {
 
         // update timers and stats
 
-        peer.timers_any_authenticated_packet_traversal();
+        peer.timers_any_authenticated_packet_traversal(false);
         peer.timers_any_authenticated_packet_received();
-        peer.rx_bytes.fetch_add(size as u64, Ordering::Relaxed);
+        peer.traffic.add_rx(size as u64, 1);
+        peer.account_quota(size);
         if size > 0 && sent {
             peer.timers_data_received();
+            peer.resume_handshakes();
         }
 
         // keep_key_fresh
 
         #[inline(always)]
-        fn keep_key_fresh(keypair: &Arc<KeyPair>) -> bool {
-            Instant::now() - keypair.birth > REJECT_AFTER_TIME - KEEPALIVE_TIMEOUT - REKEY_TIMEOUT
+        fn keep_key_fresh(keypair: &Arc<KeyPair>, protocol: &ProtocolTimers) -> bool {
+            Instant::now() - keypair.birth
+                > protocol.reject_after_time - protocol.keepalive_timeout - protocol.rekey_timeout
         }
 
-        if keep_key_fresh(keypair)
+        if keep_key_fresh(keypair, &peer.wg.protocol_timers)
             && !peer
                 .timers()
                 .sent_lastminute_handshake
@@ -451,5 +636,41 @@ impl<T: Tun, B: UDP> Callbacks for PeerInner<T, B> {
     fn key_confirmed(peer: &Self::Opaque) {
         log::trace!("{} : EVENT(key_confirmed)", peer);
         peer.timers_handshake_complete();
+        peer.set_handshake_state(HandshakeState::Confirmed);
+    }
+}
+
+/// Returns the delay of the first handshake with the index-th of the peers initiating
+/// when the device comes up, spreading the initiations evenly over the window
+pub fn initiation_delay(window: Duration, index: usize, count: usize) -> Duration {
+    if count == 0 {
+        return Duration::from_secs(0);
+    }
+    Duration::from_nanos((window.as_nanos() * index as u128 / count as u128) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_initiation_delay() {
+        let window = Duration::from_secs(10);
+        let delays: Vec<Duration> = (0..4).map(|i| initiation_delay(window, i, 4)).collect();
+        assert_eq!(
+            delays,
+            vec![
+                Duration::from_secs(0),
+                Duration::from_millis(2500),
+                Duration::from_secs(5),
+                Duration::from_millis(7500)
+            ]
+        );
+
+        // no window: all at once
+        assert_eq!(
+            initiation_delay(Duration::from_secs(0), 3, 4),
+            Duration::from_secs(0)
+        );
     }
 }
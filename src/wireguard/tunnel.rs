@@ -0,0 +1,489 @@
+/* A sans-IO tunnel to a single peer:
+ *
+ * The tunnel owns no sockets, threads or timers:
+ * the host feeds it outer messages (received from the peer) and inner IP packets,
+ * and advances time by calling `tick`.
+ * Every message to transmit is returned to the host,
+ * which delivers it over any transport (e.g. a WebRTC data channel).
+ *
+ * Transport messages are sealed with the pure Rust chacha20poly1305 implementation
+ * (also used by the handshake), rather than ring,
+ * hence the tunnel is a step towards targets without threads or sockets (e.g. wasm32).
+ */
+use std::error::Error;
+use std::fmt;
+use std::mem;
+use std::time::Duration;
+
+use aead::{Aead, NewAead, Payload};
+use byteorder::{ByteOrder, LittleEndian};
+use chacha20poly1305::ChaCha20Poly1305;
+use generic_array::GenericArray;
+use rand::rngs::OsRng;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zerocopy::LayoutVerified;
+
+use super::constants::*;
+use super::handshake;
+use super::handshake::{HandshakeError, TYPE_COOKIE_REPLY, TYPE_INITIATION, TYPE_RESPONSE};
+use super::router::{
+    inner_length, AntiReplay, TransportHeader, SIZE_MAX_INNER_PACKET, SIZE_MESSAGE_PREFIX,
+    SIZE_TAG, TYPE_TRANSPORT,
+};
+use super::types::KeyPair;
+
+// number of inner packets staged while no session is available
+const MAX_STAGED_PACKETS: usize = 128;
+
+#[derive(Debug)]
+pub enum TunnelError {
+    Handshake(HandshakeError), // the handshake message was rejected
+    InvalidMessage,            // malformed or unknown message type
+    UnknownReceiverId,         // transport message for no current session
+    DecryptionFailure,         // transport message failed authentication
+    Replay,                    // transport message already received (or too old)
+    PacketTooLarge,            // inner packet does not fit in a transport message
+}
+
+impl fmt::Display for TunnelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TunnelError::Handshake(e) => write!(f, "Handshake failed: {}", e),
+            TunnelError::InvalidMessage => write!(f, "Invalid message"),
+            TunnelError::UnknownReceiverId => write!(f, "Unknown receiver id"),
+            TunnelError::DecryptionFailure => write!(f, "Failed to AEAD:OPEN"),
+            TunnelError::Replay => write!(f, "Replayed transport message"),
+            TunnelError::PacketTooLarge => write!(f, "Inner packet too large"),
+        }
+    }
+}
+
+impl Error for TunnelError {
+    fn description(&self) -> &str {
+        "Tunnel Error"
+    }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TunnelError::Handshake(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<HandshakeError> for TunnelError {
+    fn from(e: HandshakeError) -> Self {
+        TunnelError::Handshake(e)
+    }
+}
+
+/// The result of feeding an outer message to the tunnel
+#[derive(Debug, Default)]
+pub struct Received {
+    pub inner: Option<Vec<u8>>, // decrypted IP packet (None for handshakes and keepalives)
+    pub outgoing: Vec<Vec<u8>>, // messages to transmit to the peer
+}
+
+struct Session {
+    keypair: KeyPair,
+    birth: Duration,    // host time at which the session was derived
+    confirmed: bool,    // may be used for sending (initiator, or received from the peer)
+    send_counter: u64,  // next nonce
+    replay: AntiReplay, // received nonces
+}
+
+impl Session {
+    fn new(keypair: KeyPair, now: Duration) -> Session {
+        Session {
+            confirmed: keypair.initiator,
+            keypair,
+            birth: now,
+            send_counter: 0,
+            replay: AntiReplay::new(),
+        }
+    }
+
+    fn expired(&self, now: Duration) -> bool {
+        now - self.birth >= REJECT_AFTER_TIME || self.send_counter >= REJECT_AFTER_MESSAGES
+    }
+}
+
+fn nonce(counter: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    LittleEndian::write_u64(&mut nonce[4..], counter);
+    nonce
+}
+
+pub struct Tunnel {
+    device: handshake::Device<()>,
+    peer: PublicKey,
+    now: Duration, // host time (as of the last call to tick)
+
+    // sessions
+    current: Option<Session>,
+    previous: Option<Session>,
+    next: Option<Session>, // unconfirmed session (responder)
+
+    // handshake state
+    initiation_sent: Option<Duration>, // time of the last initiation (if unanswered)
+    handshake_started: Duration,       // time of the first initiation of this attempt
+    staged: Vec<Vec<u8>>,
+
+    // keepalive state
+    last_received: Duration,
+    need_keepalive: bool, // data received since the last message sent
+}
+
+impl Tunnel {
+    /// Create a new tunnel
+    ///
+    /// # Arguments
+    ///
+    /// - `sk`: The static private key of the local endpoint
+    /// - `peer`: The public key of the peer
+    /// - `psk`: The pre-shared key (all zero if none)
+    pub fn new(sk: StaticSecret, peer: PublicKey, psk: [u8; 32]) -> Result<Tunnel, TunnelError> {
+        let mut device = handshake::Device::new();
+        device.set_sk(Some(sk));
+        device
+            .add(peer, ())
+            .and_then(|_| device.set_psk(peer, psk))
+            .map_err(|_| TunnelError::Handshake(HandshakeError::UnknownPublicKey))?;
+        Ok(Tunnel {
+            device,
+            peer,
+            now: Duration::from_secs(0),
+            current: None,
+            previous: None,
+            next: None,
+            initiation_sent: None,
+            handshake_started: Duration::from_secs(0),
+            staged: vec![],
+            last_received: Duration::from_secs(0),
+            need_keepalive: false,
+        })
+    }
+
+    /// Returns true if a session is available for sending
+    pub fn is_established(&self) -> bool {
+        self.current
+            .as_ref()
+            .map(|s| s.confirmed && !s.expired(self.now))
+            .unwrap_or(false)
+    }
+
+    /// Encrypts an inner IP packet for the peer.
+    ///
+    /// If no session is available the packet is staged (and a handshake initiated),
+    /// the staged packets are returned once the handshake completes.
+    pub fn send(&mut self, packet: &[u8]) -> Result<Vec<Vec<u8>>, TunnelError> {
+        if packet.len() > SIZE_MAX_INNER_PACKET {
+            return Err(TunnelError::PacketTooLarge);
+        }
+
+        let mut outgoing = vec![];
+        if self.is_established() {
+            outgoing.push(self.seal(packet));
+            if self.needs_rekey() {
+                self.initiate(&mut outgoing);
+            }
+        } else {
+            if self.staged.len() >= MAX_STAGED_PACKETS {
+                self.staged.remove(0);
+            }
+            self.staged.push(packet.to_owned());
+            self.initiate(&mut outgoing);
+        }
+        Ok(outgoing)
+    }
+
+    /// Processes an outer message received from the peer
+    pub fn receive(&mut self, msg: &[u8]) -> Result<Received, TunnelError> {
+        if msg.len() < mem::size_of::<u32>() {
+            return Err(TunnelError::InvalidMessage);
+        }
+        match LittleEndian::read_u32(msg) {
+            TYPE_INITIATION | TYPE_RESPONSE | TYPE_COOKIE_REPLY => self.receive_handshake(msg),
+            TYPE_TRANSPORT => self.receive_transport(msg),
+            _ => Err(TunnelError::InvalidMessage),
+        }
+    }
+
+    /// Advances the time of the tunnel (the time is chosen by the host, e.g. since creation)
+    ///
+    /// Returns the messages due at this time:
+    /// retransmitted initiations and keepalives.
+    pub fn tick(&mut self, now: Duration) -> Vec<Vec<u8>> {
+        self.now = std::cmp::max(self.now, now);
+
+        // discard sessions which can no longer be used to receive
+        let now = self.now;
+        for slot in [&mut self.previous, &mut self.current, &mut self.next].iter_mut() {
+            if let Some(session) = slot.take() {
+                if now - session.birth >= REJECT_AFTER_TIME * 3 {
+                    self.device.release(session.keypair.local_id());
+                } else {
+                    **slot = Some(session);
+                }
+            }
+        }
+
+        let mut outgoing = vec![];
+
+        // retransmit an unanswered initiation
+        if let Some(sent) = self.initiation_sent {
+            if self.now - self.handshake_started >= REKEY_ATTEMPT_TIME {
+                log::debug!("tunnel : handshake did not complete, giving up");
+                self.initiation_sent = None;
+                self.staged.clear();
+            } else if self.now - sent >= REKEY_TIMEOUT {
+                self.send_initiation(&mut outgoing);
+            }
+        }
+
+        // acknowledge received data with a keepalive
+        if self.is_established()
+            && self.need_keepalive
+            && self.now - self.last_received >= KEEPALIVE_TIMEOUT
+        {
+            outgoing.push(self.seal(&[]));
+        }
+        outgoing
+    }
+
+    fn needs_rekey(&self) -> bool {
+        match self.current.as_ref() {
+            Some(s) => {
+                s.keypair.initiator
+                    && (self.now - s.birth >= REKEY_AFTER_TIME
+                        || s.send_counter >= REKEY_AFTER_MESSAGES)
+            }
+            None => true,
+        }
+    }
+
+    // starts a handshake (unless one is in progress)
+    fn initiate(&mut self, outgoing: &mut Vec<Vec<u8>>) {
+        if self.initiation_sent.is_none() {
+            self.handshake_started = self.now;
+            self.send_initiation(outgoing);
+        }
+    }
+
+    fn send_initiation(&mut self, outgoing: &mut Vec<Vec<u8>>) {
+        match self.device.begin(&mut OsRng, &self.peer) {
+            Ok(msg) => {
+                self.initiation_sent = Some(self.now);
+                outgoing.push(msg);
+            }
+            Err(e) => log::debug!("tunnel : failed to create initiation, error = {}", e),
+        }
+    }
+
+    fn receive_handshake(&mut self, msg: &[u8]) -> Result<Received, TunnelError> {
+        let (_, reply, keypair) = self.device.process(&mut OsRng, msg, None)?;
+
+        let mut received = Received::default();
+        if let Some(reply) = reply {
+            received.outgoing.push(reply);
+        }
+
+        if let Some(keypair) = keypair {
+            let session = Session::new(keypair, self.now);
+            if session.confirmed {
+                // initiator: the handshake is complete
+                self.rotate(session);
+                self.initiation_sent = None;
+                for packet in mem::replace(&mut self.staged, vec![]) {
+                    received.outgoing.push(self.seal(&packet));
+                }
+
+                // confirm the session to the responder
+                if received.outgoing.is_empty() {
+                    received.outgoing.push(self.seal(&[]));
+                }
+            } else {
+                // responder: wait for the first transport message
+                if let Some(old) = self.next.replace(session) {
+                    self.device.release(old.keypair.local_id());
+                }
+            }
+        }
+        Ok(received)
+    }
+
+    fn receive_transport(&mut self, msg: &[u8]) -> Result<Received, TunnelError> {
+        let (header, ciphertext): (LayoutVerified<&[u8], TransportHeader>, &[u8]) =
+            LayoutVerified::new_from_prefix(msg).ok_or(TunnelError::InvalidMessage)?;
+        if ciphertext.len() < SIZE_TAG {
+            return Err(TunnelError::InvalidMessage);
+        }
+        let id = header.f_receiver.get();
+        let counter = header.f_counter.get();
+
+        // locate the session
+        let matches = |s: &Option<Session>| s.as_ref().map(|s| s.keypair.local_id()) == Some(id);
+        let (slot, session) = if matches(&self.next) {
+            (0, self.next.as_mut())
+        } else if matches(&self.current) {
+            (1, self.current.as_mut())
+        } else if matches(&self.previous) {
+            (2, self.previous.as_mut())
+        } else {
+            return Err(TunnelError::UnknownReceiverId);
+        };
+        let session = session.unwrap();
+        if self.now - session.birth >= REJECT_AFTER_TIME || !session.replay.check(counter) {
+            return Err(TunnelError::Replay);
+        }
+
+        // authenticate and decrypt
+        let mut packet =
+            ChaCha20Poly1305::new(*GenericArray::from_slice(&session.keypair.recv.key))
+                .decrypt(
+                    GenericArray::from_slice(&nonce(counter)),
+                    Payload {
+                        msg: ciphertext,
+                        aad: &[],
+                    },
+                )
+                .map_err(|_| TunnelError::DecryptionFailure)?;
+        session.replay.update(counter);
+        self.last_received = self.now;
+
+        // the first transport message confirms the session of the responder
+        if slot == 0 {
+            let mut session = self.next.take().unwrap();
+            session.confirmed = true;
+            self.rotate(session);
+        }
+
+        let mut received = Received::default();
+        if !packet.is_empty() {
+            // remove padding
+            match inner_length(&packet) {
+                Some(len) if len <= packet.len() => packet.truncate(len),
+                _ => return Err(TunnelError::InvalidMessage),
+            }
+            self.need_keepalive = true;
+            received.inner = Some(packet);
+        }
+        Ok(received)
+    }
+
+    // installs a new current session
+    fn rotate(&mut self, session: Session) {
+        if let Some(old) = mem::replace(&mut self.previous, self.current.take()) {
+            self.device.release(old.keypair.local_id());
+        }
+        self.current = Some(session);
+    }
+
+    // seals an inner packet (or keepalive) with the current session
+    fn seal(&mut self, packet: &[u8]) -> Vec<u8> {
+        let session = self.current.as_mut().unwrap();
+        let counter = session.send_counter;
+        session.send_counter += 1;
+        self.need_keepalive = false;
+
+        // pad the packet to a multiple of MESSAGE_PADDING_MULTIPLE
+        let padded = (packet.len() + MESSAGE_PADDING_MULTIPLE - 1) / MESSAGE_PADDING_MULTIPLE
+            * MESSAGE_PADDING_MULTIPLE;
+        let mut plaintext = vec![0u8; padded];
+        plaintext[..packet.len()].copy_from_slice(packet);
+
+        let mut msg = vec![0u8; SIZE_MESSAGE_PREFIX];
+        {
+            let (mut header, _): (LayoutVerified<&mut [u8], TransportHeader>, _) =
+                LayoutVerified::new_from_prefix(&mut msg[..])
+                    .expect("the buffer has the size of the header");
+            header.f_type.set(TYPE_TRANSPORT);
+            header.f_receiver.set(session.keypair.send.id);
+            header.f_counter.set(counter);
+        }
+        msg.extend(
+            ChaCha20Poly1305::new(*GenericArray::from_slice(&session.keypair.send.key))
+                .encrypt(
+                    GenericArray::from_slice(&nonce(counter)),
+                    Payload {
+                        msg: &plaintext[..],
+                        aad: &[],
+                    },
+                )
+                .unwrap(),
+        );
+        msg
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ipv4_packet(len: usize) -> Vec<u8> {
+        let mut packet = vec![0u8; len];
+        packet[0] = 0x45;
+        packet[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn test_tunnel_handshake_and_transport() {
+        let sk1 = StaticSecret::from([1u8; 32]);
+        let sk2 = StaticSecret::from([2u8; 32]);
+        let pk1 = PublicKey::from(&sk1);
+        let pk2 = PublicKey::from(&sk2);
+        let mut t1 = Tunnel::new(sk1, pk2, [0u8; 32]).unwrap();
+        let mut t2 = Tunnel::new(sk2, pk1, [0u8; 32]).unwrap();
+
+        // the first packet is staged and an initiation sent
+        let packet = ipv4_packet(40);
+        let out = t1.send(&packet).unwrap();
+        assert_eq!(out.len(), 1);
+        assert!(!t1.is_established());
+
+        // the responder replies
+        let r = t2.receive(&out[0]).unwrap();
+        assert!(r.inner.is_none());
+        assert_eq!(r.outgoing.len(), 1);
+
+        // the initiator completes the handshake and flushes the staged packet
+        let r = t1.receive(&r.outgoing[0]).unwrap();
+        assert!(t1.is_established());
+        assert_eq!(r.outgoing.len(), 1);
+
+        // the responder confirms the session on the first transport message
+        let r = t2.receive(&r.outgoing[0]).unwrap();
+        assert_eq!(r.inner.as_ref(), Some(&packet));
+        assert!(t2.is_established());
+
+        // replays are rejected
+        let msg = t2.send(&packet).unwrap().pop().unwrap();
+        assert_eq!(t1.receive(&msg).unwrap().inner.as_ref(), Some(&packet));
+        assert!(t1.receive(&msg).is_err());
+
+        // time drives retransmissions and keepalives
+        assert!(t1.tick(KEEPALIVE_TIMEOUT).len() == 1);
+        assert!(t1.tick(KEEPALIVE_TIMEOUT * 2).is_empty());
+    }
+
+    #[test]
+    fn test_tunnel_staged_and_abandoned() {
+        let sk1 = StaticSecret::from([1u8; 32]);
+        let sk2 = StaticSecret::from([2u8; 32]);
+        let mut t1 = Tunnel::new(sk1, PublicKey::from(&sk2), [0u8; 32]).unwrap();
+
+        // oversized packets are rejected (rather than staged)
+        assert!(t1.send(&ipv4_packet(SIZE_MAX_INNER_PACKET + 1)).is_err());
+
+        // a single initiation is sent for the staged packets
+        assert_eq!(t1.send(&ipv4_packet(40)).unwrap().len(), 1);
+        assert!(t1.send(&ipv4_packet(40)).unwrap().is_empty());
+
+        // the unanswered initiation is retransmitted, then abandoned
+        assert_eq!(t1.tick(REKEY_TIMEOUT).len(), 1);
+        assert!(t1.tick(REKEY_ATTEMPT_TIME).is_empty());
+        assert!(t1.staged.is_empty());
+        assert!(!t1.is_established());
+    }
+}
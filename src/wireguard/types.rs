@@ -1,6 +1,13 @@
 use clear_on_drop::clear::Clear;
 use std::fmt;
-use std::time::Instant;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant, SystemTime};
+
+use super::constants::{
+    KEEPALIVE_TIMEOUT, PERFORMANCE_BUSY_POLL, REJECT_AFTER_TIME, REKEY_AFTER_TIME, REKEY_TIMEOUT,
+};
+use super::keys::PeerId;
+use super::stats::PeerUsage;
 
 #[derive(Clone)]
 pub struct Key {
@@ -54,3 +61,444 @@ impl KeyPair {
         self.recv.id
     }
 }
+
+/// Describes the current session of a peer (the key-pair used for encryption)
+#[derive(Debug, Clone, Copy)]
+pub struct SessionInfo {
+    pub local_id: u32,       // receiver index assigned by the device
+    pub remote_id: u32,      // receiver index assigned by the peer
+    pub age: Duration,       // time since the key-pair was derived
+    pub sent: u64,           // number of messages sent (next nonce)
+    pub received: u64,       // highest counter received (approximates the messages received)
+    pub replay: ReplayStats, // replay protection of the received messages
+}
+
+/// Statistics of the replay protection (window) of a session:
+/// only authenticated messages are counted, i.e. duplicated or reordered by the network
+/// (or replayed by an attacker)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReplayStats {
+    pub too_old: u64,     // messages dropped behind the window
+    pub duplicate: u64,   // messages dropped as already received
+    pub max_reorder: u64, // largest distance of a late message behind the highest counter
+}
+
+/// Number of messages to a peer which could not be sent, by class of error
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SendErrors {
+    pub unreachable: u64, // destination or network unreachable
+    pub permission: u64,  // sending not permitted (e.g. by a firewall rule)
+    pub nobufs: u64,      // out of buffer space
+    pub too_big: u64,     // exceeding the path MTU (with DF set)
+    pub other: u64,       // any other failure
+}
+
+/// An inner flow through the tunnel, oriented from the local to the remote side
+/// (the ports are zero for protocols without ports)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Flow {
+    pub protocol: u8,     // IP protocol number (e.g. 6 = TCP, 17 = UDP)
+    pub local: IpAddr,    // address on this side of the tunnel
+    pub local_port: u16,  // port on this side of the tunnel
+    pub remote: IpAddr,   // address behind the peer
+    pub remote_port: u16, // port behind the peer
+}
+
+/// Traffic of an inner flow (see Flow)
+#[derive(Debug, Clone, Copy)]
+pub struct FlowStats {
+    pub rx_bytes: u64,      // bytes received from the peer
+    pub tx_bytes: u64,      // bytes sent to the peer
+    pub rx_packets: u64,    // packets received from the peer
+    pub tx_packets: u64,    // packets sent to the peer
+    pub last_seen: Instant, // time of the last packet (in either direction)
+}
+
+/// The local interface and/or address used as the source of messages to a peer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Source {
+    pub ifindex: u32,         // index of the outgoing interface (0 = any)
+    pub addr: Option<IpAddr>, // source address (None = any)
+}
+
+impl Source {
+    /// Returns true if any part of the source is pinned
+    pub fn is_pinned(&self) -> bool {
+        self.ifindex != 0 || self.addr.is_some()
+    }
+}
+
+/// Policy for peers which do not answer handshake initiations
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Blackhole {
+    pub attempts: usize, // unanswered initiations before the peer is unreachable (0 = disabled)
+    pub cooldown: Duration, // retries are suspended for this long (0 = keep retrying)
+}
+
+/// Power-save mode of the device, e.g. while the screen of a mobile embedder is off
+/// (see WireGuard::set_power_save)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PowerSave {
+    pub keepalive_multiplier: u32, // persistent keepalive intervals are lengthened by this factor
+    pub coalesce: Duration, // keepalives are aligned to multiples of this window (0 = unaligned)
+}
+
+impl Default for PowerSave {
+    fn default() -> Self {
+        PowerSave {
+            keepalive_multiplier: 4,
+            coalesce: Duration::from_secs(5),
+        }
+    }
+}
+
+impl PowerSave {
+    /// Returns the delay of a timer due after `after` (in normal mode),
+    /// lengthened and rounded up to the next multiple of the coalescing window,
+    /// such that the timers of all peers expire (and wake the device) together
+    ///
+    /// # Arguments
+    ///
+    /// - `elapsed`: The time since power-save was entered (the origin of the windows)
+    /// - `after`: The delay in normal mode
+    pub fn delay(&self, elapsed: Duration, after: Duration) -> Duration {
+        let after = after * self.keepalive_multiplier.max(1);
+        let window = self.coalesce.as_millis() as u64;
+        if window == 0 {
+            return after;
+        }
+        let deadline = (elapsed + after).as_millis() as u64;
+        let aligned = (deadline + window - 1) / window * window;
+        Duration::from_millis(aligned) - elapsed.min(Duration::from_millis(aligned))
+    }
+}
+
+/// Quota on the transport traffic (received and transmitted bytes) of a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    pub limit: u64,          // bytes, the peer is disabled once exceeded
+    pub period: QuotaPeriod, // when the usage is reset
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaPeriod {
+    Absolute, // never reset
+    Monthly,  // reset at the start of every calendar month (UTC)
+}
+
+/// A live peer eligible as the exit, i.e. the owner of the default routes
+/// (see WireGuard::set_exit_selector)
+#[derive(Debug, Clone, Copy)]
+pub struct ExitCandidate {
+    pub peer: PeerId,          // the peer
+    pub weight: u32,           // static weight (higher is preferred)
+    pub rtt: Option<Duration>, // round-trip time of the last handshake initiated by the device
+    pub active: bool,          // currently the exit
+}
+
+/// Built-in policies for selecting the exit among the candidates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitPolicy {
+    Rtt,    // lowest handshake round-trip time
+    Weight, // highest static weight
+}
+
+/// Events emitted by the device (see WireGuard::subscribe)
+#[derive(Debug, Clone)]
+pub enum PeerEvent {
+    Unreachable {
+        peer: PeerId,    // the peer
+        attempts: usize, // number of unanswered initiations
+    },
+    QuotaExceeded {
+        peer: PeerId, // the peer (now disabled)
+        usage: u64,   // bytes used in the current period
+        limit: u64,   // quota in bytes
+    },
+    ExitChanged {
+        exit: Option<PeerId>, // the peer now owning the default routes (if any)
+    },
+    Handshake {
+        peer: PeerId,          // the peer
+        state: HandshakeState, // the new state of the handshake
+        at: SystemTime,        // walltime of the transition
+    },
+    WakeRequired {
+        peer: PeerId, // a handshake with the peer is due while in power-save (e.g. rekey)
+    },
+    Usage {
+        peer: PeerId,
+        session: Option<u32>, // local id of the current session (if any)
+        usage: PeerUsage,     // traffic since the previous usage event of the peer
+        reset: bool,          // counters restarted (e.g. device restart): counted from zero
+        at: SystemTime,       // walltime at the end of the interval
+    },
+}
+
+/// State of the handshake with a peer (see PeerEvent::Handshake)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeState {
+    Idle,             // no handshake attempted, or the key-material was discarded
+    InitiationSent,   // initiation sent, awaiting the response
+    ResponseReceived, // response to the initiation of the device received
+    ResponseSent,     // initiation of the peer answered, awaiting its first transport message
+    Confirmed,        // key-pair confirmed, the session is established
+    Failed,           // no response to the initiations, the device gave up
+}
+
+impl Default for HandshakeState {
+    fn default() -> Self {
+        HandshakeState::Idle
+    }
+}
+
+/// Whether packets from a peer destined to (the allowed IPs of) another peer
+/// are relayed to the other peer by the device, or written to the TUN device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayPolicy {
+    Default, // follow the device (see WireGuard::set_relay)
+    Forward, // re-encrypt and forward to the other peer (hub-and-spoke)
+    Deliver, // write to the TUN device (forwarding is left to the host)
+}
+
+impl Default for RelayPolicy {
+    fn default() -> Self {
+        RelayPolicy::Default
+    }
+}
+
+/// Whether the endpoint of a peer is learned from the source of authenticated messages (roaming)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndpointPolicy {
+    Learn,                    // roam to the source of any authenticated message (default)
+    Fixed,                    // keep the endpoint (learned only if none is known)
+    LearnWithin(IpAddr, u32), // roam only to sources within the subnet (address, masklen)
+}
+
+impl Default for EndpointPolicy {
+    fn default() -> Self {
+        EndpointPolicy::Learn
+    }
+}
+
+/// Encapsulation of the messages to a peer in frames toward a relay
+/// (for peers behind symmetric NATs), until the direct path to the peer is confirmed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Encapsulation {
+    pub relay: SocketAddr, // endpoint of the relay
+    pub tag: u32,          // tag of the peer at the relay
+}
+
+/// Which end of a handshake the device may take with a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeRole {
+    Both,      // initiate and accept handshakes (default)
+    Responder, // never initiate a handshake (server role)
+    Initiator, // never accept an initiation (client role)
+}
+
+impl Default for HandshakeRole {
+    fn default() -> Self {
+        HandshakeRole::Both
+    }
+}
+
+impl HandshakeRole {
+    pub fn initiates(self) -> bool {
+        self != HandshakeRole::Responder
+    }
+
+    pub fn accepts(self) -> bool {
+        self != HandshakeRole::Initiator
+    }
+}
+
+/// Packets from a peer relayed to other peers
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RelayStats {
+    pub packets: u64, // packets forwarded to other peers
+    pub bytes: u64,   // bytes forwarded to other peers (inner packets, without padding)
+    pub dropped: u64, // packets not forwarded (e.g. hop limit exceeded)
+}
+
+/// OS scheduling priority of the handshake workers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakePriority {
+    Normal, // same priority as the router and reader workers (default)
+    Low,    // lower priority: a handshake flood delays handshakes rather than transport messages
+}
+
+impl Default for HandshakePriority {
+    fn default() -> Self {
+        HandshakePriority::Normal
+    }
+}
+
+/// Configuration of the worker threads of the device (see WireGuard::with_config)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WorkerConfig {
+    pub handshake_priority: HandshakePriority,
+    pub performance: Option<PerformanceMode>, // dedicated cores for the data path (if any)
+}
+
+/// Performance mode: the data path runs on dedicated cores, busy-polling rather than blocking
+/// (targets latency-sensitive deployments which can spare the cores).
+///
+/// The readers (TUN and UDP) are pinned to the reader core,
+/// a single crypto thread encrypts / decrypts every message
+/// and a single writer thread writes the messages in order.
+/// While the device is down every stage blocks as usual (rather than spinning).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PerformanceMode {
+    pub reader: usize,  // core of the reader threads
+    pub crypto: usize,  // core of the crypto thread
+    pub writer: usize,  // core of the writer thread
+    pub busy_poll: u32, // busy polling of the UDP sockets in microseconds (SO_BUSY_POLL)
+}
+
+impl PerformanceMode {
+    /// Performance mode with the stages on the given cores
+    /// (and the default busy polling of the sockets)
+    pub fn new(reader: usize, crypto: usize, writer: usize) -> PerformanceMode {
+        PerformanceMode {
+            reader,
+            crypto,
+            writer,
+            busy_poll: PERFORMANCE_BUSY_POLL,
+        }
+    }
+}
+
+/// Durations of the protocol timers (see WireGuard::with_timers)
+///
+/// The defaults are those of the protocol,
+/// shorter timers are meant for tests (see ProtocolTimers::short).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolTimers {
+    pub rekey_timeout: Duration, // retransmission of unanswered handshake initiations
+    pub keepalive_timeout: Duration, // keepalive after received data without outbound data
+    pub rekey_after_time: Duration, // age of a session renewed by the initiator
+    pub reject_after_time: Duration, // age of a session no longer used
+}
+
+impl Default for ProtocolTimers {
+    fn default() -> Self {
+        ProtocolTimers {
+            rekey_timeout: REKEY_TIMEOUT,
+            keepalive_timeout: KEEPALIVE_TIMEOUT,
+            rekey_after_time: REKEY_AFTER_TIME,
+            reject_after_time: REJECT_AFTER_TIME,
+        }
+    }
+}
+
+impl ProtocolTimers {
+    /// The protocol timers scaled down 10 times (e.g. handshake retries every 500 ms),
+    /// which retains their ratios (and hence the number of handshake attempts)
+    pub fn short() -> ProtocolTimers {
+        let default = ProtocolTimers::default();
+        ProtocolTimers {
+            rekey_timeout: default.rekey_timeout / 10,
+            keepalive_timeout: default.keepalive_timeout / 10,
+            rekey_after_time: default.rekey_after_time / 10,
+            reject_after_time: default.reject_after_time / 10,
+        }
+    }
+}
+
+/// Padding applied to IP packets before encryption
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Padding {
+    Multiple, // pad to a multiple of MESSAGE_PADDING_MULTIPLE (default)
+    Mtu,      // pad every packet to the MTU (constant-size packets)
+    Disabled, // no padding (minimal overhead)
+}
+
+impl Default for Padding {
+    fn default() -> Self {
+        Padding::Multiple
+    }
+}
+
+/// Which traffic postpones the persistent keepalives to a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeepaliveSuppression {
+    Any,   // any authenticated message, sent or received (default, as the protocol)
+    Sent,  // only messages sent to the peer (keepalives continue while only receiving)
+    Never, // a keepalive every interval, regardless of traffic
+}
+
+impl Default for KeepaliveSuppression {
+    fn default() -> Self {
+        KeepaliveSuppression::Any
+    }
+}
+
+impl KeepaliveSuppression {
+    /// Whether an authenticated message (sent to or received from the peer)
+    /// postpones the next persistent keepalive
+    pub fn postpones(self, sent: bool) -> bool {
+        match self {
+            KeepaliveSuppression::Any => true,
+            KeepaliveSuppression::Sent => sent,
+            KeepaliveSuppression::Never => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_power_save_delay() {
+        let ps = PowerSave {
+            keepalive_multiplier: 4,
+            coalesce: Duration::from_secs(5),
+        };
+
+        // lengthened and aligned to the window
+        assert_eq!(
+            ps.delay(Duration::from_secs(0), Duration::from_secs(25)),
+            Duration::from_secs(100)
+        );
+        assert_eq!(
+            ps.delay(Duration::from_secs(3), Duration::from_secs(25)),
+            Duration::from_secs(102)
+        );
+
+        // timers of different peers expire together
+        let a = Duration::from_millis(1200);
+        let b = Duration::from_millis(3700);
+        assert_eq!(
+            a + ps.delay(a, Duration::from_secs(10)),
+            b + ps.delay(b, Duration::from_secs(10))
+        );
+
+        // without a window
+        let ps = PowerSave {
+            keepalive_multiplier: 2,
+            coalesce: Duration::from_secs(0),
+        };
+        assert_eq!(
+            ps.delay(Duration::from_secs(3), Duration::from_secs(25)),
+            Duration::from_secs(50)
+        );
+    }
+
+    #[test]
+    fn test_keepalive_suppression() {
+        // by default, traffic in either direction postpones the keepalive
+        assert_eq!(KeepaliveSuppression::default(), KeepaliveSuppression::Any);
+        assert!(KeepaliveSuppression::Any.postpones(true));
+        assert!(KeepaliveSuppression::Any.postpones(false));
+
+        // only outbound traffic
+        assert!(KeepaliveSuppression::Sent.postpones(true));
+        assert!(!KeepaliveSuppression::Sent.postpones(false));
+
+        // unconditional keepalives
+        assert!(!KeepaliveSuppression::Never.postpones(true));
+        assert!(!KeepaliveSuppression::Never.postpones(false));
+    }
+}
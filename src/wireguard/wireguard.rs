@@ -1,27 +1,59 @@
+use super::authorization::PeerAuthorization;
+use super::capabilities::Capabilities;
 use super::constants::*;
+use super::counter::{CachePadded, RxCounters, TrafficCounters};
+use super::crypto::{Backend, Selected};
+use super::dedup::Dedup;
+use super::discovery::EndpointDiscovery;
+use super::exit::{self, ExitSelector, EXIT_CHECK_INTERVAL};
+use super::filter::{Filter, PeerFilter};
 use super::handshake;
+use super::health::{Alive, Health, HealthReport, HEALTH_STALL_TIMEOUT, HEALTH_TICK_INTERVAL};
+#[cfg(feature = "ipfix")]
+use super::ipfix;
+use super::keys::PeerId;
+use super::load::UnderLoad;
 use super::peer::PeerInner;
+use super::quota::{self, QuotaUsage, QUOTA_SAVE_INTERVAL};
 use super::router;
-use super::timers::Timers;
+use super::snapshot::{Readers, Snapshot};
+use super::stats::{PeerStats, PeerUsage, RxStats, StatsSnapshot};
+use super::timers::{self, Timers};
+use super::types::{
+    Blackhole, ExitCandidate, ExitPolicy, HandshakeRole, HandshakeState, Padding, PeerEvent,
+    PowerSave, ProtocolTimers, Quota, WorkerConfig,
+};
 
-use super::queue::ParallelQueue;
+use super::queue::PriorityQueue;
 use super::workers::HandshakeJob;
 
 use super::tun::Tun;
 use super::udp::UDP;
+use super::Endpoint;
+
+#[cfg(any(test, feature = "dummy"))]
+use super::dummy;
 
 use super::workers::{handshake_worker, tun_worker, udp_worker};
 
+use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::ops::Deref;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
 use std::sync::Arc;
 use std::sync::Condvar;
 use std::sync::Mutex as StdMutex;
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant, SystemTime};
+
+#[cfg(feature = "ipfix")]
+use std::net::UdpSocket;
 
-use hjul::Runner;
+use hjul::{Runner, Timer};
 use rand::rngs::OsRng;
 use rand::Rng;
 use spin::{Mutex, RwLock};
@@ -32,30 +64,111 @@ pub struct WireguardInner<T: Tun, B: UDP> {
     // identifier (for logging)
     pub id: u32,
 
+    // name of the device, e.g. the interface name (for logging)
+    pub name: RwLock<Option<String>>,
+
     // timer wheel
     pub runner: Mutex<Runner>,
 
+    // configuration of the worker threads (fixed once the workers are started)
+    pub workers: WorkerConfig,
+
+    // durations of the protocol timers (fixed at creation)
+    pub protocol_timers: ProtocolTimers,
+
     // device enabled
     pub enabled: RwLock<bool>,
 
     // number of tun readers
     pub tun_readers: WaitCounter,
 
+    // liveness of the workers and timers
+    pub health: Health,
+    pub health_timer: Mutex<Option<Timer>>,
+
     // current MTU
     pub mtu: AtomicUsize,
 
-    // peer map
-    pub peers: RwLock<
+    // padding applied to outbound IP packets
+    pub padding: RwLock<Padding>,
+
+    // inner IPv4 packets larger than this are fragmented before encryption (0 = disabled)
+    pub prefragment: AtomicUsize,
+
+    // peer map (read without locking by the workers, see Snapshot)
+    pub peers: Snapshot<
         handshake::Device<router::PeerHandle<B::Endpoint, PeerInner<T, B>, T::Writer, B::Writer>>,
     >,
 
+    // sections of the handshake workers (one reader per worker), in which a job is processed:
+    // the reclaim of ids (see gc) and changes of the epoch wait for the sections in progress
+    pub processing: Readers,
+
+    // configuration epoch, incremented when the private key changes or the device goes down:
+    // handshake jobs queued under a previous epoch are discarded by the workers
+    pub epoch: AtomicU64,
+
     // cryptokey router
     pub router: router::Device<B::Endpoint, PeerInner<T, B>, T::Writer, B::Writer>,
 
     // handshake related state
-    pub last_under_load: Mutex<Instant>,
-    pub pending: AtomicUsize, // number of pending handshake packets in queue
-    pub queue: ParallelQueue<HandshakeJob<B::Endpoint>>,
+    pub under_load: UnderLoad, // DoS mitigation (shared by the readers and handshake workers)
+    pub pending: Arc<AtomicUsize>, // number of pending handshake jobs (see workers::Pending)
+
+    // anti-amplification counters
+    pub handshake_malformed: AtomicU64, // handshake messages dropped (invalid length)
+    pub handshake_duplicate: AtomicU64, // initiations dropped (duplicate within window)
+
+    // messages left unanswered by stealth (which would otherwise be answered by a cookie reply),
+    // e.g. scan attempts: visible to the operator but never to the sender
+    pub stealth_dropped: AtomicU64,
+    pub received: RxCounters, // messages received by kind (see workers::udp_worker)
+    pub dedup: Dedup,
+    pub queue: PriorityQueue<HandshakeJob<B::Endpoint>>, // initiations in the normal lane
+
+    // destinations reported unreachable by the transport (e.g. ICMP errors)
+    pub endpoint_unreachable: AtomicU64,
+
+    // detection of peers which do not answer handshake initiations
+    pub blackhole: RwLock<Blackhole>,
+
+    // window over which the initiations are spread when the device comes up
+    pub handshake_spread: RwLock<Duration>,
+
+    // bound on the flush of the outbound queues on device down (None = no flush)
+    pub shutdown_flush: RwLock<Option<Duration>>,
+
+    // power-save mode and when it was entered (the origin of the coalescing windows)
+    pub power_save: RwLock<Option<(PowerSave, Instant)>>,
+
+    // subscribers to peer events
+    pub events: Mutex<Vec<SyncSender<PeerEvent>>>,
+
+    // persistence of the usage of peer quotas
+    pub quota_file: RwLock<Option<PathBuf>>,
+    pub quota_saved: Mutex<QuotaUsage>, // usage last read from / written to the file
+    pub quota_timer: Mutex<Option<Timer>>, // rollover of periods and saving of the usage
+
+    // external discovery of candidate endpoints (if any)
+    pub endpoint_discovery: RwLock<Option<Arc<dyn EndpointDiscovery>>>,
+
+    // external authorization of peers and their handshakes (if any)
+    pub peer_authorization: RwLock<Option<Arc<dyn PeerAuthorization>>>,
+
+    // selection of the peer owning the default routes (if any)
+    pub exit_selector: RwLock<Option<Arc<dyn ExitSelector>>>,
+    pub exit_policy: RwLock<Option<ExitPolicy>>, // built-in policy of the selector (if any)
+    pub exit: Mutex<Option<PublicKey>>,          // the current exit
+    pub exit_timer: Mutex<Option<Timer>>,        // re-evaluation of the selection
+
+    // periodic usage events for accounting (see set_usage_interval)
+    pub usage_interval: RwLock<Option<Duration>>,
+    pub usage_reported: Mutex<HashMap<PeerId, PeerStats>>, // counters at the last usage event
+    pub usage_timer: Mutex<Option<Timer>>,
+
+    // exporter of inner flows (if any)
+    #[cfg(feature = "ipfix")]
+    pub ipfix: Mutex<Option<ipfix::Exporter>>,
 }
 
 pub struct WireGuard<T: Tun, B: UDP> {
@@ -66,7 +179,10 @@ pub struct WaitCounter(StdMutex<usize>, Condvar);
 
 impl<T: Tun, B: UDP> fmt::Display for WireGuard<T, B> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "wireguard({:x})", self.id)
+        match self.name.read().as_ref() {
+            Some(name) => write!(f, "wireguard({})", name),
+            None => write!(f, "wireguard({:x})", self.id),
+        }
     }
 }
 
@@ -133,24 +249,75 @@ impl<T: Tun, B: UDP> WireGuard<T, B> {
         // set mtu
         self.mtu.store(0, Ordering::Relaxed);
 
+        // send the in-flight messages (while the sockets are still open)
+        if let Some(timeout) = *self.shutdown_flush.read() {
+            self.flush(timeout);
+        }
+
         // avoid transmission from router
         self.router.down();
 
+        // discard the handshake jobs queued while up
+        // (and wait for the jobs already being processed)
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+        self.processing.synchronize();
+
         // set all peers down (stops timers)
         for (_, peer) in self.peers.write().iter() {
             peer.stop_timers();
             peer.down();
         }
 
+        // persist the usage of the quotas
+        self.save_quotas();
+
         *enabled = false;
     }
 
+    // sends a final keepalive to every peer with a session,
+    // then waits (at most the timeout) for the outbound queues to drain
+    fn flush(&self, timeout: Duration) {
+        let peers = self.peers.read();
+        for (_, peer) in peers.iter() {
+            if peer.session().is_some() {
+                peer.send_keepalive();
+            }
+        }
+
+        let deadline = Instant::now() + timeout;
+        while peers.iter().any(|(_, peer)| peer.outbound_pending()) {
+            if Instant::now() >= deadline {
+                log::debug!(
+                    "{} : outbound queues not flushed within {:?}",
+                    self,
+                    timeout
+                );
+                return;
+            }
+            thread::sleep(SHUTDOWN_FLUSH_POLL);
+        }
+    }
+
     /// Brings the WireGuard device up.
     /// Usually called when the associated interface is brought up.
+    ///
+    /// The MTU may be up to 65535 (jumbo frames),
+    /// however IP packets larger than router::SIZE_MAX_INNER_PACKET
+    /// cannot be carried by a single UDP datagram and are dropped.
     pub fn up(&self, mtu: usize) {
         // ensure exclusive access (to avoid race with "up" call)
         let mut enabled = self.enabled.write();
 
+        // warn if some IP packets cannot be transmitted
+        if mtu > router::SIZE_MAX_INNER_PACKET {
+            log::info!(
+                "{} : MTU ({}) exceeds largest transport payload ({}), larger packets are dropped",
+                self,
+                mtu,
+                router::SIZE_MAX_INNER_PACKET
+            );
+        }
+
         // set mtu
         self.mtu.store(mtu, Ordering::Relaxed);
 
@@ -162,27 +329,793 @@ impl<T: Tun, B: UDP> WireGuard<T, B> {
         // enable transmission from router
         self.router.up();
 
-        // set all peers up (restarts timers)
-        for (_, peer) in self.peers.write().iter() {
+        // set all peers up (restarts timers),
+        // spreading the initiations to peers with a persistent keepalive over the window
+        let spread = *self.handshake_spread.read();
+        let peers = self.peers.write();
+        let initiating = peers
+            .iter()
+            .filter(|(_, peer)| peer.get_keepalive_interval() > 0)
+            .count();
+        let mut index = 0;
+        for (_, peer) in peers.iter() {
             peer.up();
-            peer.start_timers();
+            if peer.get_keepalive_interval() > 0 {
+                peer.start_timers(timers::initiation_delay(spread, index, initiating));
+                index += 1;
+            } else {
+                peer.start_timers(Duration::from_secs(0));
+            }
         }
 
         *enabled = true;
     }
 
+    /// Sets the padding policy for outbound IP packets.
+    ///
+    /// Padding to the MTU hides the size of packets (at the cost of bandwidth),
+    /// while disabling padding minimizes the overhead on constrained links.
+    pub fn set_padding(&self, padding: Padding) {
+        *self.padding.write() = padding;
+    }
+
+    pub fn get_padding(&self) -> Padding {
+        *self.padding.read()
+    }
+
+    /// Enables fragmentation of inner IPv4 packets before encryption,
+    /// such that no transport message exceeds `datagram` bytes (the UDP payload).
+    /// None disables fragmentation before encryption.
+    pub fn set_prefragment(&self, datagram: Option<usize>) {
+        let limit = datagram
+            .map(|size| size.saturating_sub(router::message_data_len(0)))
+            .unwrap_or(0);
+        self.prefragment.store(limit, Ordering::Relaxed);
+    }
+
+    /// Sets the filter of inner packets to and from every peer (None removes the filter)
+    pub fn set_filter(&self, filter: Option<Arc<dyn Filter>>) {
+        self.router.set_filter(filter.map(
+            |filter| -> Arc<dyn router::PacketFilter<PeerInner<T, B>>> {
+                Arc::new(PeerFilter(filter))
+            },
+        ));
+    }
+
+    /// Starts exporting the inner flows of every peer as IPFIX records
+    /// to the collector (replacing any previous exporter), None stops the export.
+    ///
+    /// Only tracked flows are exported, see router::Device::set_flow_timeout.
+    #[cfg(feature = "ipfix")]
+    pub fn set_ipfix_collector(&self, collector: Option<SocketAddr>) {
+        let mut exporter = self.ipfix.lock();
+        *exporter = None;
+
+        let collector = match collector {
+            Some(collector) => collector,
+            None => return,
+        };
+
+        let socket = match UdpSocket::bind(match collector {
+            SocketAddr::V4(_) => SocketAddr::from(([0u8; 4], 0)),
+            SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+        }) {
+            Ok(socket) => socket,
+            Err(e) => {
+                log::info!("{} : failed to create IPFIX socket: {}", self, e);
+                return;
+            }
+        };
+
+        // the thread holds a weak reference, exiting when the device is dropped
+        let stop = Arc::new(AtomicBool::new(false));
+        let weak = Arc::downgrade(&self.inner);
+        let handle = {
+            let stop = stop.clone();
+            let mut encoder = ipfix::Encoder::new(self.id);
+            thread::spawn(move || {
+                while ipfix::wait(&stop) {
+                    let wg = match weak.upgrade() {
+                        Some(inner) => WireGuard { inner },
+                        None => return,
+                    };
+                    let now = SystemTime::now();
+                    let mut records = vec![];
+                    for (_, peer) in wg.peers.read().iter() {
+                        for (flow, stats) in peer.flows() {
+                            records.extend(ipfix::records(&flow, &stats, now));
+                        }
+                    }
+                    for msg in encoder.encode(&records, now) {
+                        if let Err(e) = socket.send_to(&msg, collector) {
+                            log::debug!("{} : failed to export flows: {}", wg, e);
+                        }
+                    }
+                }
+            })
+        };
+        *exporter = Some(ipfix::Exporter::new(
+            collector,
+            stop,
+            handle.thread().clone(),
+        ));
+    }
+
+    #[cfg(feature = "ipfix")]
+    pub fn get_ipfix_collector(&self) -> Option<SocketAddr> {
+        self.ipfix
+            .lock()
+            .as_ref()
+            .map(|exporter| exporter.collector)
+    }
+
+    /// Names the device (usually after the interface).
+    ///
+    /// The name identifies the device and its peers in all log output,
+    /// hence the messages of multiple devices in a process can be told apart.
+    pub fn set_name(&self, name: &str) {
+        *self.name.write() = Some(name.to_owned());
+    }
+
+    pub fn get_name(&self) -> Option<String> {
+        self.name.read().clone()
+    }
+
+    /// Sets the policy for peers which do not answer handshake initiations.
+    ///
+    /// After `attempts` successive unanswered initiations a PeerEvent::Unreachable is emitted,
+    /// if a cool-down is set, handshake retries are then suspended
+    /// until data is received from the peer, the endpoint changes or the cool-down expires.
+    pub fn set_blackhole(&self, blackhole: Blackhole) {
+        *self.blackhole.write() = blackhole;
+    }
+
+    pub fn get_blackhole(&self) -> Blackhole {
+        *self.blackhole.read()
+    }
+
+    /// Sets the window over which the handshakes with peers with a persistent keepalive
+    /// are spread when the device comes up (0 initiates them all at once),
+    /// avoiding a burst of initiations with hundreds of peers.
+    pub fn set_handshake_spread(&self, window: Duration) {
+        *self.handshake_spread.write() = window;
+    }
+
+    pub fn get_handshake_spread(&self) -> Duration {
+        *self.handshake_spread.read()
+    }
+
+    /// Flushes the outbound queues when the device is brought down (None disables the flush):
+    /// the messages already queued are transmitted and a final keepalive is sent to every peer
+    /// with a session, before transmission is disabled and the sockets are closed,
+    /// so that a brief reconfiguration does not drop in-flight data.
+    ///
+    /// The timeout bounds how long bringing the device down may block.
+    pub fn set_shutdown_flush(&self, timeout: Option<Duration>) {
+        *self.shutdown_flush.write() = timeout;
+    }
+
+    pub fn get_shutdown_flush(&self) -> Option<Duration> {
+        *self.shutdown_flush.read()
+    }
+
+    /// Enters (Some) or leaves (None) power-save, e.g. when the embedder signals "screen off":
+    ///
+    /// - Persistent keepalives are sent less often
+    ///   and aligned across peers, coalescing the wakeups of the radio.
+    /// - Cover traffic is suspended.
+    /// - A PeerEvent::WakeRequired is emitted whenever a handshake is due (e.g. rekey),
+    ///   for the embedder to wake the radio.
+    ///
+    /// The pending persistent keepalives are rescheduled at once.
+    pub fn set_power_save(&self, power_save: Option<PowerSave>) {
+        *self.power_save.write() = power_save.map(|ps| (ps, Instant::now()));
+        for (_, peer) in self.peers.read().iter() {
+            peer.opaque().rearm_persistent_keepalive();
+        }
+    }
+
+    pub fn get_power_save(&self) -> Option<PowerSave> {
+        self.power_save.read().map(|(ps, _)| ps)
+    }
+
+    /// Enables/disables stealth: initiations from unknown static keys are never answered,
+    /// not even by a cookie reply under load (see handshake::Device::set_stealth).
+    pub fn set_stealth(&self, stealth: bool) {
+        self.peers.write().set_stealth(stealth);
+    }
+
+    pub fn get_stealth(&self) -> bool {
+        self.peers.read().get_stealth()
+    }
+
+    /// Sets the source of the timestamps of handshake initiations,
+    /// e.g. a PersistedClock on systems without a trustworthy real-time clock.
+    pub fn set_clock(&self, clock: Arc<dyn handshake::Clock>) {
+        self.peers.write().set_clock(clock);
+    }
+
+    /// Returns the number of messages left unanswered by stealth (see set_stealth)
+    pub fn stealth_dropped(&self) -> u64 {
+        self.stealth_dropped.load(Ordering::Relaxed)
+    }
+
+    /// Returns the messages received by kind and address family of the source
+    pub fn received(&self) -> RxStats {
+        self.received.get()
+    }
+
+    /// Returns the counters of the interface,
+    /// in the buckets of a kernel network interface (/proc/net/dev)
+    pub fn interface_stats(&self) -> router::InterfaceStats {
+        self.router.interface_stats()
+    }
+
+    /// Returns the counters of the device and every peer
+    /// (see StatsSnapshot::rates_since for the rates between two snapshots)
+    pub fn stats_snapshot(&self) -> StatsSnapshot {
+        let peers = self
+            .peers
+            .read()
+            .iter()
+            .map(|(pk, peer)| (PeerId::new(pk), Self::peer_stats(peer.opaque())))
+            .collect();
+
+        StatsSnapshot {
+            taken: Instant::now(),
+            peers,
+            handshake_malformed: self.handshake_malformed.load(Ordering::Relaxed),
+            handshake_duplicate: self.handshake_duplicate.load(Ordering::Relaxed),
+            stealth_dropped: self.stealth_dropped.load(Ordering::Relaxed),
+            received: self.received.get(),
+            interface: self.router.interface_stats(),
+        }
+    }
+
+    fn peer_stats(peer: &PeerInner<T, B>) -> PeerStats {
+        let traffic = peer.traffic.get();
+        PeerStats {
+            rx_bytes: traffic.rx_bytes,
+            tx_bytes: traffic.tx_bytes,
+            rx_packets: traffic.rx_packets,
+            tx_packets: traffic.tx_packets,
+            handshakes: peer.handshakes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Sets the usage quota of the peer (None removes the quota).
+    ///
+    /// The usage is retained when the quota is replaced (in the same period)
+    /// and restored from the quota file when a quota is first set.
+    /// A peer exceeding its quota is disabled (see PeerEvent::QuotaExceeded)
+    /// until the quota is raised or removed, or a new period starts.
+    ///
+    /// # Returns
+    ///
+    /// False if no such peer exists
+    pub fn set_quota(&self, pk: &PublicKey, quota: Option<Quota>) -> bool {
+        {
+            let peers = self.peers.read();
+            let peer = match peers.get(pk) {
+                Some(peer) => peer.opaque(),
+                None => return false,
+            };
+
+            // look up the saved usage before locking the quota (save_quotas locks in the opposite order)
+            let saved = self.quota_saved.lock().get(pk.as_bytes()).cloned();
+
+            let mut current = peer.quota.write();
+            match quota {
+                None => peer.quota_usage.store(0, Ordering::SeqCst),
+                Some(quota) => {
+                    let period = quota::period_of(quota.period, SystemTime::now());
+                    let retain = current.map(|c| c.period) == Some(quota.period)
+                        && peer.quota_period.load(Ordering::SeqCst) == period;
+                    if !retain {
+                        let usage = match saved {
+                            Some((saved, usage)) if saved == period => usage,
+                            _ => 0,
+                        };
+                        peer.quota_period.store(period, Ordering::SeqCst);
+                        peer.quota_usage.store(usage, Ordering::SeqCst);
+                    }
+                }
+            }
+            *current = quota;
+            peer.quota_limit
+                .store(quota.map(|q| q.limit).unwrap_or(u64::MAX), Ordering::SeqCst);
+            drop(current);
+            peer.check_quota();
+        }
+        self.save_quotas();
+        true
+    }
+
+    pub fn get_quota(&self, pk: &PublicKey) -> Option<Quota> {
+        self.peers
+            .read()
+            .get(pk)
+            .and_then(|peer| *peer.opaque().quota.read())
+    }
+
+    /// Sets the file in which the usage of the peer quotas is persisted
+    /// (None stops persisting the usage).
+    ///
+    /// The usage in the file is restored for the peers with a quota,
+    /// subsequently the file is written periodically (see QUOTA_SAVE_INTERVAL),
+    /// whenever a quota is set and when the device is brought down.
+    pub fn set_quota_file(&self, path: Option<PathBuf>) -> io::Result<()> {
+        let usage = match path.as_ref() {
+            Some(path) => quota::load(path)?,
+            None => QuotaUsage::new(),
+        };
+        *self.quota_file.write() = path;
+        *self.quota_saved.lock() = usage.clone();
+
+        let peers = self.peers.read();
+        for (pk, peer) in peers.iter() {
+            let peer = peer.opaque();
+            if let (Some(quota), Some(&(period, bytes))) =
+                (*peer.quota.read(), usage.get(pk.as_bytes()))
+            {
+                if period == quota::period_of(quota.period, SystemTime::now()) {
+                    peer.quota_period.store(period, Ordering::SeqCst);
+                    peer.quota_usage.store(bytes, Ordering::SeqCst);
+                    peer.check_quota();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_quota_file(&self) -> Option<PathBuf> {
+        self.quota_file.read().clone()
+    }
+
+    /// Writes the usage of every peer quota to the quota file (if any)
+    pub fn save_quotas(&self) {
+        let path = match self.quota_file.read().clone() {
+            Some(path) => path,
+            None => return,
+        };
+
+        let mut usage = self.quota_saved.lock();
+        for (pk, peer) in self.peers.read().iter() {
+            let peer = peer.opaque();
+            if peer.quota.read().is_some() {
+                usage.insert(
+                    *pk.as_bytes(),
+                    (
+                        peer.quota_period.load(Ordering::SeqCst),
+                        peer.quota_usage.load(Ordering::SeqCst),
+                    ),
+                );
+            }
+        }
+        if let Err(e) = quota::save(&path, &usage) {
+            log::info!("{} : failed to save quota usage, error = {}", self, e);
+        }
+    }
+
+    // starts a new period for the quotas whose period has passed (re-enabling the peers),
+    // then writes the quota file (called every QUOTA_SAVE_INTERVAL, off the data path)
+    fn update_quotas(&self) {
+        for (_, peer) in self.peers.read().iter() {
+            peer.opaque().quota_exceeded();
+        }
+        self.save_quotas();
+    }
+
+    /// Sets the selector of the exit: the peer to which the default routes
+    /// (0.0.0.0/0 and ::/0) are routed, among the peers with an exit weight (see set_exit_weight).
+    ///
+    /// The selection is re-evaluated every EXIT_CHECK_INTERVAL,
+    /// failing over to another candidate once the exit goes stale (see PeerEvent::ExitChanged).
+    /// None stops the selection and removes the default routes of the exit.
+    pub fn set_exit_selector(&self, selector: Option<Arc<dyn ExitSelector>>) {
+        *self.exit_policy.write() = None;
+        self.update_exit_selector(selector);
+    }
+
+    /// Sets the selector of the exit to a built-in policy (see set_exit_selector)
+    pub fn set_exit_policy(&self, policy: Option<ExitPolicy>) {
+        self.update_exit_selector(policy.map(exit::selector));
+        *self.exit_policy.write() = policy;
+    }
+
+    pub fn get_exit_policy(&self) -> Option<ExitPolicy> {
+        *self.exit_policy.read()
+    }
+
+    fn update_exit_selector(&self, selector: Option<Arc<dyn ExitSelector>>) {
+        let enabled = selector.is_some();
+        *self.exit_selector.write() = selector;
+
+        let mut timer = self.exit_timer.lock();
+        if !enabled {
+            if let Some(timer) = timer.take() {
+                timer.stop();
+            }
+            self.set_exit(None);
+            return;
+        }
+
+        // the timer holds a weak reference (the device owns the timer)
+        if timer.is_none() {
+            let weak = Arc::downgrade(&self.inner);
+            *timer = Some(self.runner.lock().timer(move || {
+                if let Some(inner) = weak.upgrade() {
+                    let wg = WireGuard { inner };
+                    wg.select_exit();
+                    if let Some(timer) = wg.exit_timer.lock().as_ref() {
+                        timer.reset(EXIT_CHECK_INTERVAL);
+                    }
+                }
+            }));
+        }
+        drop(timer);
+        self.select_exit();
+        if let Some(timer) = self.exit_timer.lock().as_ref() {
+            timer.reset(EXIT_CHECK_INTERVAL);
+        }
+    }
+
+    /// Sets the weight of the peer as an exit candidate (0 = not a candidate)
+    ///
+    /// # Returns
+    ///
+    /// False if no such peer exists
+    pub fn set_exit_weight(&self, pk: &PublicKey, weight: u32) -> bool {
+        match self.peers.read().get(pk) {
+            Some(peer) => peer.opaque().exit_weight.store(weight, Ordering::SeqCst),
+            None => return false,
+        }
+        if self.exit_selector.read().is_some() {
+            self.select_exit();
+        }
+        true
+    }
+
+    /// Returns the current exit (if any)
+    pub fn get_exit(&self) -> Option<PublicKey> {
+        *self.exit.lock()
+    }
+
+    /* Re-evaluates the selection of the exit among the live candidates */
+    fn select_exit(&self) {
+        let selector = match self.exit_selector.read().clone() {
+            Some(selector) => selector,
+            None => return,
+        };
+
+        let current = *self.exit.lock();
+        let candidates: Vec<ExitCandidate> = {
+            let peers = self.peers.read();
+            peers
+                .iter()
+                .filter_map(|(pk, peer)| {
+                    let inner = peer.opaque();
+                    let weight = inner.exit_weight.load(Ordering::Relaxed);
+                    let live = peer
+                        .session()
+                        .map(|session| session.age < self.protocol_timers.reject_after_time)
+                        .unwrap_or(false)
+                        && !inner.handshakes_suspended()
+                        && !inner.disabled.load(Ordering::Relaxed);
+                    if weight == 0 || !live {
+                        return None;
+                    }
+                    Some(ExitCandidate {
+                        peer: inner.id(),
+                        weight,
+                        rtt: *inner.handshake_rtt.lock(),
+                        active: current == Some(pk),
+                    })
+                })
+                .collect()
+        };
+
+        // the exit is retained (even if stale) while no candidate is live,
+        // unless it is no longer a candidate
+        let exit = if candidates.is_empty() {
+            current.filter(|pk| {
+                self.peers
+                    .read()
+                    .get(pk)
+                    .map(|peer| peer.opaque().exit_weight.load(Ordering::Relaxed) > 0)
+                    .unwrap_or(false)
+            })
+        } else {
+            selector.select(&candidates).map(|id| *id.public_key())
+        };
+        if exit != current {
+            self.set_exit(exit);
+        }
+    }
+
+    /* Moves the default routes to the new exit */
+    fn set_exit(&self, exit: Option<PublicKey>) {
+        let mut current = self.exit.lock();
+        if *current == exit {
+            return;
+        }
+        let peers = self.peers.read();
+        if let Some(peer) = current.and_then(|pk| peers.get(&pk)) {
+            peer.remove_allowed_ip(Ipv4Addr::UNSPECIFIED.into(), 0);
+            peer.remove_allowed_ip(Ipv6Addr::UNSPECIFIED.into(), 0);
+        }
+        let exit = exit.filter(|pk| peers.get(pk).is_some());
+        if let Some(peer) = exit.and_then(|pk| peers.get(&pk)) {
+            peer.add_allowed_ip(Ipv4Addr::UNSPECIFIED.into(), 0);
+            peer.add_allowed_ip(Ipv6Addr::UNSPECIFIED.into(), 0);
+        }
+        *current = exit;
+        drop(peers);
+        drop(current);
+
+        let exit = exit.map(PeerId::new);
+        match exit {
+            Some(id) => log::info!("{} : exit changed to {}", self, id),
+            None => log::info!("{} : no exit", self),
+        }
+        self.emit(PeerEvent::ExitChanged { exit });
+    }
+
+    /// Emits a PeerEvent::Usage for every peer every interval (None stops the events):
+    /// the traffic of the peer since its previous usage event,
+    /// such that accounting consumers need not diff snapshots of the counters.
+    ///
+    /// Peers without traffic over the interval are skipped,
+    /// the remaining usage of a peer is emitted when the peer is removed.
+    pub fn set_usage_interval(&self, interval: Option<Duration>) {
+        *self.usage_interval.write() = interval;
+
+        let mut timer = self.usage_timer.lock();
+        let interval = match interval {
+            Some(interval) => interval,
+            None => {
+                if let Some(timer) = timer.take() {
+                    timer.stop();
+                }
+                return;
+            }
+        };
+
+        // the timer holds a weak reference (the device owns the timer)
+        if timer.is_none() {
+            let weak = Arc::downgrade(&self.inner);
+            *timer = Some(self.runner.lock().timer(move || {
+                if let Some(inner) = weak.upgrade() {
+                    let wg = WireGuard { inner };
+                    wg.report_usage();
+                    if let Some(interval) = *wg.usage_interval.read() {
+                        if let Some(timer) = wg.usage_timer.lock().as_ref() {
+                            timer.reset(interval);
+                        }
+                    }
+                }
+            }));
+        }
+        if let Some(timer) = timer.as_ref() {
+            timer.reset(interval);
+        }
+    }
+
+    pub fn get_usage_interval(&self) -> Option<Duration> {
+        *self.usage_interval.read()
+    }
+
+    /// Emits the usage of every peer since its previous usage event
+    pub fn report_usage(&self) {
+        let stats = self.stats_snapshot();
+        let mut reported = self.usage_reported.lock();
+        reported.retain(|id, _| stats.peers.contains_key(id));
+        for (id, now) in stats.peers.iter() {
+            self.emit_usage(&mut reported, *id, now);
+        }
+    }
+
+    fn emit_usage(&self, reported: &mut HashMap<PeerId, PeerStats>, id: PeerId, now: &PeerStats) {
+        let (usage, reset) = PeerUsage::between(reported.get(&id), now);
+        reported.insert(id, *now);
+        if usage.is_empty() && !reset {
+            return;
+        }
+        let session = self
+            .peers
+            .read()
+            .get(id.public_key())
+            .and_then(|peer| peer.session())
+            .map(|session| session.local_id);
+        self.emit(PeerEvent::Usage {
+            peer: id,
+            session,
+            usage,
+            reset,
+            at: SystemTime::now(),
+        });
+    }
+
+    /// Returns a receiver for all subsequent peer events.
+    ///
+    /// At most MAX_QUEUED_EVENTS events are buffered for the subscriber,
+    /// further events are dropped until the subscriber catches up.
+    /// The subscription ends when the receiver is dropped.
+    pub fn subscribe(&self) -> Receiver<PeerEvent> {
+        let (tx, rx) = sync_channel(MAX_QUEUED_EVENTS);
+        self.events.lock().push(tx);
+        rx
+    }
+
+    /// Emits an event to every subscriber (dropping closed subscriptions),
+    /// never blocks: the event is dropped for subscribers which fall behind
+    pub fn emit(&self, event: PeerEvent) {
+        self.events
+            .lock()
+            .retain(|tx| match tx.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    log::debug!("event subscriber lagging, dropped event {:?}", event);
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+    }
+
     pub fn clear_peers(&self) {
         self.peers.write().clear();
     }
 
     pub fn remove_peer(&self, pk: &PublicKey) {
+        // emit the usage since the last usage event (which would otherwise be lost)
+        if self.usage_interval.read().is_some() {
+            let id = PeerId::new(*pk);
+            let now = self
+                .peers
+                .read()
+                .get(pk)
+                .map(|peer| Self::peer_stats(peer.opaque()));
+            if let Some(now) = now {
+                let mut reported = self.usage_reported.lock();
+                self.emit_usage(&mut reported, id, &now);
+                reported.remove(&id);
+            }
+        }
         let _ = self.peers.write().remove(pk);
     }
 
+    /// Forces a new handshake with the peer (ignoring the rate limit)
+    /// and discards the current key-pair once the new key-pair is confirmed,
+    /// e.g. to obtain fresh session keys after a suspected compromise.
+    ///
+    /// # Returns
+    ///
+    /// False if no such peer exists
+    pub fn rekey_peer(&self, pk: &PublicKey) -> bool {
+        match self.peers.read().get(pk) {
+            Some(peer) => {
+                peer.discard_on_next_key();
+                *peer.opaque().last_handshake_sent.lock() = Instant::now() - TIME_HORIZON;
+                peer.opaque().packet_send_handshake_initiation();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Sets the candidate endpoints of the peer (e.g. the IPv4 and IPv6 addresses of a dual-stack peer).
+    ///
+    /// Until a handshake completes, initiations are sent to every candidate,
+    /// the endpoint from which the handshake completes first is used for all further messages.
+    /// The race is repeated when a handshake fails.
+    ///
+    /// # Returns
+    ///
+    /// False if the peer does not exist
+    pub fn set_endpoint_candidates(&self, pk: &PublicKey, addrs: Vec<SocketAddr>) -> bool {
+        match self.peers.read().get(pk) {
+            Some(peer) => {
+                if peer.get_endpoint().is_none() {
+                    if let Some(addr) = addrs.first() {
+                        peer.set_endpoint(B::Endpoint::from_address(*addr));
+                    }
+                }
+                let race = addrs.len() > 1;
+                *peer.opaque().endpoint_candidates.lock() = addrs;
+                peer.opaque().race_endpoints.store(race, Ordering::SeqCst);
+                peer.opaque().resume_handshakes();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Sets the external discovery of endpoints (None removes the discovery),
+    /// which supplies candidate endpoints when handshakes fail
+    /// and is notified of the handshakes completed over the direct path (see EndpointDiscovery).
+    pub fn set_endpoint_discovery(&self, discovery: Option<Arc<dyn EndpointDiscovery>>) {
+        *self.endpoint_discovery.write() = discovery;
+    }
+
+    /// Sets the external authorization of peers (None removes the authorization),
+    /// which may veto the addition of peers by the configuration
+    /// and every completed handshake (see PeerAuthorization).
+    pub fn set_peer_authorization(&self, authorization: Option<Arc<dyn PeerAuthorization>>) {
+        *self.peer_authorization.write() = authorization;
+    }
+
+    /// Returns true if the authorization (if any) permits adding the peer
+    pub fn authorize_peer(&self, pk: &PublicKey) -> bool {
+        match self.peer_authorization.read().clone() {
+            Some(authorization) => authorization.authorize_peer(PeerId::new(*pk)),
+            None => true,
+        }
+    }
+
+    /// Handles destinations reported unreachable by the transport:
+    /// the cached source address of peers at these endpoints is cleared,
+    /// so that subsequent messages use the source selected by the routing table.
+    pub fn unreachable(&self, addrs: &[SocketAddr]) {
+        for (_, peer) in self.peers.read().iter() {
+            if let Some(endpoint) = peer.get_endpoint() {
+                if addrs.contains(&endpoint) {
+                    log::debug!("{} : endpoint {} unreachable", self, endpoint);
+                    self.endpoint_unreachable.fetch_add(1, Ordering::Relaxed);
+                    peer.clear_src();
+                }
+            }
+        }
+    }
+
+    /// Returns the number of receiver ids allocated by the device
+    pub fn receiver_ids(&self) -> usize {
+        self.peers.read().allocated_ids()
+    }
+
+    /// Returns the receiver ids allocated to the peer
+    /// (pending initiations and key-pairs, including expired key-pairs not yet reaped)
+    pub fn peer_receiver_ids(&self, pk: &PublicKey) -> Vec<u32> {
+        self.peers.read().peer_ids(pk)
+    }
+
+    /// Reaps the receiver ids of expired sessions
+    /// (and any ids no longer held by a key-pair or pending initiation).
+    ///
+    /// Ids are otherwise only released once a new key-pair is added to the peer,
+    /// hence long-running devices with idle peers would accumulate stale ids.
+    ///
+    /// # Returns
+    ///
+    /// The number of ids released.
+    pub fn gc(&self) -> usize {
+        let peers = self.peers.read();
+        let mut released = 0;
+        for (_, peer) in peers.iter() {
+            for id in peer.expire_keys(self.protocol_timers.reject_after_time) {
+                peers.release(id);
+                released += 1;
+            }
+        }
+
+        // only ids allocated before the handshakes in progress complete are candidates,
+        // since a handshake could be holding an id not yet handed to the router
+        let candidates = peers.ids();
+        self.processing.synchronize();
+        let mut live = HashSet::new();
+        for (_, peer) in peers.iter() {
+            live.extend(peer.local_ids());
+        }
+        released + peers.gc(|id| !candidates.contains(&id) || live.contains(&id))
+    }
+
     pub fn set_key(&self, sk: Option<StaticSecret>) {
-        let mut peers = self.peers.write();
-        peers.set_sk(sk);
+        self.peers.write().set_sk(sk);
         self.router.clear_sending_keys();
+
+        // handshake jobs queued under the previous key are discarded
+        // (and the jobs already being processed are awaited)
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+        self.processing.synchronize();
     }
 
     pub fn get_sk(&self) -> Option<StaticSecret> {
@@ -192,6 +1125,35 @@ impl<T: Tun, B: UDP> WireGuard<T, B> {
             .map(|sk| StaticSecret::from(sk.to_bytes()))
     }
 
+    /// Adds a secondary private key to the device,
+    /// initiations addressed to the corresponding public key are accepted
+    /// (e.g. to rotate the device key with an overlap window).
+    ///
+    /// # Arguments
+    ///
+    /// - `sk`: The secondary private key
+    /// - `peers`: The peers permitted to use the key (None, if every peer may use it)
+    pub fn add_secondary_key(&self, sk: StaticSecret, peers: Option<&[PublicKey]>) -> PublicKey {
+        self.peers.write().add_secondary_sk(sk, peers)
+    }
+
+    pub fn remove_secondary_key(&self, pk: &PublicKey) -> bool {
+        self.peers.write().remove_secondary_sk(pk)
+    }
+
+    pub fn clear_secondary_keys(&self) {
+        self.peers.write().clear_secondary_sks()
+    }
+
+    pub fn get_secondary_sks(&self) -> Vec<(StaticSecret, Option<Vec<PublicKey>>)> {
+        self.peers
+            .read()
+            .get_secondary_sks()
+            .into_iter()
+            .map(|(sk, peers)| (StaticSecret::from(sk.to_bytes()), peers))
+            .collect()
+    }
+
     pub fn set_psk(&self, pk: PublicKey, psk: [u8; 32]) -> bool {
         self.peers.write().set_psk(pk, psk).is_ok()
     }
@@ -199,6 +1161,52 @@ impl<T: Tun, B: UDP> WireGuard<T, B> {
         self.peers.read().get_psk(pk).ok()
     }
 
+    /// Sets which end of a handshake the device may take with the peer:
+    /// a responder-only peer is never sent initiations (the timers do not initiate handshakes),
+    /// initiations from an initiator-only peer are dropped.
+    ///
+    /// # Returns
+    ///
+    /// False if no such peer exists
+    pub fn set_handshake_role(&self, pk: PublicKey, role: HandshakeRole) -> bool {
+        self.peers.write().set_role(pk, role).is_ok()
+    }
+
+    pub fn get_handshake_role(&self, pk: &PublicKey) -> Option<HandshakeRole> {
+        self.peers.read().get_role(pk).ok()
+    }
+
+    /// Returns the state of the handshake with the peer
+    /// and the walltime at which the state was entered (see PeerEvent::Handshake)
+    pub fn get_handshake_state(&self, pk: &PublicKey) -> Option<(HandshakeState, SystemTime)> {
+        self.peers
+            .read()
+            .get(pk)
+            .map(|peer| *peer.opaque().handshake_state.lock())
+    }
+
+    /// Sets a cap on the memory used by queued messages and peer state.
+    ///
+    /// When the cap is reached new handshake messages are dropped
+    /// and the oldest staged packets are discarded to make room for new packets.
+    ///
+    /// # Arguments
+    ///
+    /// - `limit`: The cap in bytes (or None, if the memory usage should be unbounded)
+    pub fn set_memory_limit(&self, limit: Option<usize>) {
+        self.router.memory().set_limit(limit);
+    }
+
+    pub fn get_memory_limit(&self) -> Option<usize> {
+        self.router.memory().limit()
+    }
+
+    /// Returns the (approximate) number of bytes
+    /// used by queued messages and peer state.
+    pub fn get_memory_usage(&self) -> usize {
+        self.router.memory().used()
+    }
+
     pub fn add_peer(&self, pk: PublicKey) -> bool {
         let mut peers = self.peers.write();
         if peers.contains_key(&pk) {
@@ -214,14 +1222,25 @@ impl<T: Tun, B: UDP> WireGuard<T, B> {
         // create new router peer
         let peer: router::PeerHandle<B::Endpoint, PeerInner<T, B>, T::Writer, B::Writer> =
             self.router.new_peer(PeerInner {
-                id: OsRng.gen(),
                 pk,
                 wg: self.clone(),
                 walltime_last_handshake: Mutex::new(None),
                 last_handshake_sent: Mutex::new(Instant::now() - TIME_HORIZON),
                 handshake_queued: AtomicBool::new(false),
-                rx_bytes: AtomicU64::new(0),
-                tx_bytes: AtomicU64::new(0),
+                suspended: Mutex::new(None),
+                handshake_state: Mutex::new((HandshakeState::Idle, SystemTime::now())),
+                endpoint_candidates: Mutex::new(vec![]),
+                race_endpoints: AtomicBool::new(false),
+                traffic: TrafficCounters::new(),
+                handshakes: AtomicU64::new(0),
+                quota: RwLock::new(None),
+                quota_period: AtomicU64::new(0),
+                quota_usage: CachePadded::new(AtomicU64::new(0)),
+                quota_limit: AtomicU64::new(u64::MAX),
+                disabled: AtomicBool::new(false),
+                exit_weight: AtomicU32::new(0),
+                handshake_rtt: Mutex::new(None),
+                cookie_retries: AtomicU64::new(0),
                 timers: RwLock::new(timers),
             });
 
@@ -237,6 +1256,7 @@ impl<T: Tun, B: UDP> WireGuard<T, B> {
     pub fn add_udp_reader(&self, reader: B::Reader) {
         let wg = self.clone();
         thread::spawn(move || {
+            let _alive = Alive::new(&wg.health.udp_readers);
             udp_worker(&wg, reader);
         });
     }
@@ -253,48 +1273,199 @@ impl<T: Tun, B: UDP> WireGuard<T, B> {
 
         // start worker
         thread::spawn(move || {
+            let alive = Alive::new(&wg.health.tun_readers);
             tun_worker(&wg, reader);
+            drop(alive);
             wg.tun_readers.decrease();
         });
     }
 
+    /// Returns the health of the device:
+    /// whether the workers are running, the handshake queue drains and the timers fire
+    /// (see HealthReport::is_healthy).
+    pub fn health(&self) -> HealthReport {
+        let pending = self.pending.load(Ordering::SeqCst);
+        HealthReport {
+            up: *self.enabled.read(),
+            handshake_workers: self.health.handshake_workers.load(Ordering::SeqCst),
+            handshake_expected: self.health.handshake_expected,
+            udp_readers: self.health.udp_readers.load(Ordering::SeqCst),
+            tun_readers: self.health.tun_readers.load(Ordering::SeqCst),
+            pending_handshakes: pending,
+            queue_stalled: pending > 0
+                && self.health.last_dequeue.lock().elapsed() > HEALTH_STALL_TIMEOUT,
+            last_timer_tick: self.health.last_tick.lock().elapsed(),
+        }
+    }
+
+    /// Returns the optional features compiled into the build and active on the device,
+    /// and the support of the host for them (probed on every call).
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities::new(self.workers.performance.is_some())
+    }
+
     pub fn wait(&self) {
         self.tun_readers.wait();
     }
 
     pub fn new(writer: T::Writer) -> WireGuard<T, B> {
+        Self::with_config(writer, WorkerConfig::default())
+    }
+
+    /// Create a device with the given configuration of the worker threads
+    /// (e.g. handshake workers with lower priority, see HandshakePriority)
+    pub fn with_config(writer: T::Writer, workers: WorkerConfig) -> WireGuard<T, B> {
+        Self::with_timers(writer, workers, ProtocolTimers::default())
+    }
+
+    /// Create a device with the given configuration of the worker threads
+    /// and durations of the protocol timers (e.g. shorter timers for tests)
+    pub fn with_timers(
+        writer: T::Writer,
+        workers: WorkerConfig,
+        protocol_timers: ProtocolTimers,
+    ) -> WireGuard<T, B> {
         // workers equal to number of physical cores
         let cpus = num_cpus::get();
 
         // create handshake queue
-        let (tx, mut rxs) = ParallelQueue::new(cpus, 128);
+        let (tx, mut rxs) = PriorityQueue::new(cpus, 128);
 
-        // create router
+        // create router (with the crypto and writer stages on dedicated cores in performance mode)
         let router: router::Device<B::Endpoint, PeerInner<T, B>, T::Writer, B::Writer> =
-            router::Device::new(num_cpus::get(), writer);
+            match workers.performance {
+                Some(mode) => router::Device::new_pinned(writer, mode.crypto, mode.writer),
+                None => router::Device::new(num_cpus::get(), writer),
+            };
 
         // create arc to state
         let wg = WireGuard {
             inner: Arc::new(WireguardInner {
                 enabled: RwLock::new(false),
                 tun_readers: WaitCounter::new(),
+                health: Health::new(cpus),
+                health_timer: Mutex::new(None),
                 id: OsRng.gen(),
+                name: RwLock::new(None),
                 mtu: AtomicUsize::new(0),
-                last_under_load: Mutex::new(Instant::now() - TIME_HORIZON),
+                padding: RwLock::new(Padding::default()),
+                prefragment: AtomicUsize::new(0),
+                under_load: UnderLoad::new(
+                    UNDER_LOAD_QUEUE_HIGH,
+                    UNDER_LOAD_QUEUE_LOW,
+                    THRESHOLD_UNDER_LOAD,
+                    DURATION_UNDER_LOAD,
+                ),
                 router,
-                pending: AtomicUsize::new(0),
-                peers: RwLock::new(handshake::Device::new()),
+                pending: Arc::new(AtomicUsize::new(0)),
+                handshake_malformed: AtomicU64::new(0),
+                handshake_duplicate: AtomicU64::new(0),
+                stealth_dropped: AtomicU64::new(0),
+                received: RxCounters::default(),
+                dedup: Dedup::new(DEDUP_INITIATION_WINDOW, MAX_QUEUED_INCOMING_HANDSHAKES),
+                peers: Snapshot::new(handshake::Device::new()),
+                processing: Readers::new(cpus),
+                epoch: AtomicU64::new(0),
                 runner: Mutex::new(Runner::new(TIMERS_TICK, TIMERS_SLOTS, TIMERS_CAPACITY)),
+                workers,
+                protocol_timers,
                 queue: tx,
+                endpoint_unreachable: AtomicU64::new(0),
+                blackhole: RwLock::new(Blackhole::default()),
+                handshake_spread: RwLock::new(Duration::from_secs(0)),
+                shutdown_flush: RwLock::new(None),
+                power_save: RwLock::new(None),
+                events: Mutex::new(vec![]),
+                quota_file: RwLock::new(None),
+                quota_saved: Mutex::new(QuotaUsage::new()),
+                quota_timer: Mutex::new(None),
+                endpoint_discovery: RwLock::new(None),
+                peer_authorization: RwLock::new(None),
+                exit_selector: RwLock::new(None),
+                exit_policy: RwLock::new(None),
+                exit: Mutex::new(None),
+                exit_timer: Mutex::new(None),
+                usage_interval: RwLock::new(None),
+                usage_reported: Mutex::new(HashMap::new()),
+                usage_timer: Mutex::new(None),
+                #[cfg(feature = "ipfix")]
+                ipfix: Mutex::new(None),
             }),
         };
+        log::debug!("{} : crypto backend {}", wg, Selected::NAME);
 
         // start handshake workers
         while let Some(rx) = rxs.pop() {
             let wg = wg.clone();
-            thread::spawn(move || handshake_worker(&wg, rx));
+            let reader = rxs.len();
+            thread::spawn(move || {
+                let _alive = Alive::new(&wg.health.handshake_workers);
+                handshake_worker(&wg, rx, reader)
+            });
         }
 
+        // start the health timer (holding a weak reference, the device owns the timer)
+        let weak = Arc::downgrade(&wg.inner);
+        let timer = wg.runner.lock().timer(move || {
+            if let Some(inner) = weak.upgrade() {
+                *inner.health.last_tick.lock() = Instant::now();
+                if let Some(timer) = inner.health_timer.lock().as_ref() {
+                    timer.reset(HEALTH_TICK_INTERVAL);
+                }
+            }
+        });
+        timer.start(HEALTH_TICK_INTERVAL);
+        *wg.health_timer.lock() = Some(timer);
+
+        // start the quota timer (likewise)
+        let weak = Arc::downgrade(&wg.inner);
+        let timer = wg.runner.lock().timer(move || {
+            if let Some(inner) = weak.upgrade() {
+                let wg = WireGuard { inner };
+                wg.update_quotas();
+                if let Some(timer) = wg.quota_timer.lock().as_ref() {
+                    timer.reset(QUOTA_SAVE_INTERVAL);
+                }
+            }
+        });
+        timer.start(QUOTA_SAVE_INTERVAL);
+        *wg.quota_timer.lock() = Some(timer);
+
         wg
     }
 }
+
+#[cfg(any(test, feature = "dummy"))]
+impl WireGuard<dummy::TunTest, dummy::PairBind> {
+    /// Create a device on the dummy platform with short protocol timers,
+    /// e.g. for tests of management logic against a device (requires no privileges).
+    ///
+    /// The device is up (with an MTU of 1500) and reads from the dummy TUN device.
+    ///
+    /// # Returns
+    ///
+    /// A triple of:
+    ///
+    /// - The device
+    /// - The other end of the TUN device (IP packets written to / read from the device)
+    /// - The other end of the UDP transport (messages sent to / by the device)
+    pub fn new_dummy() -> (
+        WireGuard<dummy::TunTest, dummy::PairBind>,
+        dummy::TunFakeIO,
+        (
+            dummy::PairReader<dummy::UnitEndpoint>,
+            dummy::PairWriter<dummy::UnitEndpoint>,
+        ),
+    ) {
+        let (fake, tun_reader, tun_writer, _) = dummy::TunTest::create(true);
+        let wg =
+            WireGuard::with_timers(tun_writer, WorkerConfig::default(), ProtocolTimers::short());
+        wg.add_tun_reader(tun_reader);
+        wg.up(1500);
+
+        let ((bind_reader, bind_writer), remote) = dummy::PairBind::pair();
+        wg.set_writer(bind_writer);
+        wg.add_udp_reader(bind_reader);
+        (wg, fake, remote)
+    }
+}
@@ -1,8 +1,10 @@
-use std::sync::atomic::Ordering;
+use std::cmp;
+use std::hint::spin_loop;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Instant;
 
 use byteorder::{ByteOrder, LittleEndian};
-use crossbeam_channel::Receiver;
 use log::debug;
 use rand::rngs::OsRng;
 use x25519_dalek::PublicKey;
@@ -11,25 +13,67 @@ use x25519_dalek::PublicKey;
 use super::Endpoint;
 
 use super::tun::Reader as TunReader;
-use super::tun::Tun;
+use super::tun::{ReadError, ReadErrorKind, Tun};
 
+use super::udp::ReadError as UDPReadError;
+use super::udp::ReadErrorKind as UDPReadErrorKind;
 use super::udp::Reader as UDPReader;
 use super::udp::UDP;
 
 // constants
 use super::constants::{
-    DURATION_UNDER_LOAD, MAX_QUEUED_INCOMING_HANDSHAKES, MESSAGE_PADDING_MULTIPLE,
-    THRESHOLD_UNDER_LOAD,
+    HANDSHAKE_LOW_PRIORITY_NICE, MAX_QUEUED_INCOMING_HANDSHAKES, MESSAGE_PADDING_MULTIPLE,
+};
+use super::handshake::{handshake_message_size, SIZE_MAC};
+use super::handshake::{HandshakeError, TYPE_COOKIE_REPLY, TYPE_INITIATION, TYPE_RESPONSE};
+use super::router::{
+    fragment_ipv4, unframe, PacketBuffer, SIZE_MAX_DATAGRAM, SIZE_MAX_INNER_PACKET,
+    SIZE_MESSAGE_PREFIX, TYPE_RELAY_FRAME, TYPE_TRANSPORT,
 };
-use super::handshake::MAX_HANDSHAKE_MSG_SIZE;
-use super::handshake::{TYPE_COOKIE_REPLY, TYPE_INITIATION, TYPE_RESPONSE};
-use super::router::{CAPACITY_MESSAGE_POSTFIX, SIZE_MESSAGE_PREFIX, TYPE_TRANSPORT};
 
+use super::affinity::pin_to_core;
+use super::memory::Reservation;
+use super::queue::PriorityReceiver;
+use super::stats::MessageKind;
+use super::types::{HandshakePriority, HandshakeState, Padding};
 use super::wireguard::WireGuard;
 
+// every job holds its accounting in the handshake queue (see Pending)
+// and the configuration epoch under which it was queued (see WireguardInner::epoch)
 pub enum HandshakeJob<E> {
-    Message(Vec<u8>, E),
-    New(PublicKey),
+    Message(Vec<u8>, E, Reservation, Instant, Pending, u64), // (message, source, memory, enqueued at)
+    New(PublicKey, Pending, u64),
+}
+
+impl<E> HandshakeJob<E> {
+    fn epoch(&self) -> u64 {
+        match self {
+            HandshakeJob::Message(_, _, _, _, _, epoch) => *epoch,
+            HandshakeJob::New(_, _, epoch) => *epoch,
+        }
+    }
+}
+
+/* Accounts a job in the handshake queue (see WireguardInner::pending):
+ *
+ * The count is incremented when the job is created and decremented when the job is dropped,
+ * i.e. after the job is processed, but equally when it is discarded by a closed queue
+ * (on shutdown), hence the count never drifts.
+ */
+pub struct Pending(Arc<AtomicUsize>);
+
+impl Pending {
+    pub fn new(pending: &Arc<AtomicUsize>) -> Pending {
+        pending.fetch_add(1, Ordering::SeqCst);
+        Pending(pending.clone())
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        let pending = self.0.fetch_sub(1, Ordering::SeqCst);
+        debug_assert!(pending > 0, "pending handshake jobs underflow");
+    }
 }
 
 /* Returns the padded length of a message:
@@ -54,20 +98,47 @@ const fn padding(size: usize, mtu: usize) -> usize {
     min(mtu, size + (pad - size % pad) % pad)
 }
 
+// Returns the padded length of a message according to the padding policy of the device
+#[inline(always)]
+fn padded_len(policy: Padding, size: usize, mtu: usize) -> usize {
+    match policy {
+        Padding::Multiple => padding(size, mtu),
+        Padding::Mtu => mtu,
+        Padding::Disabled => cmp::min(size, mtu),
+    }
+}
+
 pub fn tun_worker<T: Tun, B: UDP>(wg: &WireGuard<T, B>, reader: T::Reader) {
+    pin_reader(wg);
+    let mut poll = BusyPoll::new(wg);
+
     loop {
-        // create vector big enough for any transport message (based on MTU)
+        // create buffer big enough for any IP packet read from the TUN device (based on MTU)
+        // note that the MTU of the TUN device can be larger than what fits in a transport message
         let mtu = wg.mtu.load(Ordering::Relaxed);
-        let size = mtu + SIZE_MESSAGE_PREFIX + 1;
-        let mut msg: Vec<u8> = vec![0; size + CAPACITY_MESSAGE_POSTFIX];
+        let mut msg = PacketBuffer::new(mtu + 1);
+        let busy = poll.update(mtu != 0, |nonblocking| reader.set_nonblocking(nonblocking));
 
         // read a new IP packet
-        let payload = match reader.read(&mut msg[..], SIZE_MESSAGE_PREFIX) {
+        let payload = match reader.read(msg.message_mut(), SIZE_MESSAGE_PREFIX) {
             Ok(payload) => payload,
-            Err(e) => {
-                debug!("TUN worker, failed to read from tun device: {}", e);
-                break;
-            }
+            Err(e) => match e.kind() {
+                ReadErrorKind::Transient if busy => {
+                    spin_loop();
+                    continue;
+                }
+                ReadErrorKind::Transient => {
+                    debug!(
+                        "TUN worker, transient failure reading from tun device: {}",
+                        e
+                    );
+                    continue;
+                }
+                ReadErrorKind::Closed => {
+                    debug!("TUN worker, failed to read from tun device: {}", e);
+                    break;
+                }
+            },
         };
         debug!("TUN worker, IP packet of {} bytes (MTU = {})", payload, mtu);
 
@@ -76,18 +147,52 @@ pub fn tun_worker<T: Tun, B: UDP>(wg: &WireGuard<T, B>, reader: T::Reader) {
             continue;
         }
 
+        // jumbo frames: the IP packet must fit in a single UDP datagram
+        // (the outer datagram may still be fragmented)
+        if payload > SIZE_MAX_INNER_PACKET {
+            debug!(
+                "TUN worker, IP packet of {} bytes too large for transport message (max {})",
+                payload, SIZE_MAX_INNER_PACKET
+            );
+            continue;
+        }
+        let mtu = cmp::min(mtu, SIZE_MAX_INNER_PACKET);
+        let policy = *wg.padding.read();
+
+        // fragment inner IPv4 packets which would exceed the size of the outer datagram
+        // (packets which may not be fragmented are sent as is)
+        let limit = wg.prefragment.load(Ordering::Relaxed);
+        if limit > 0 && payload > limit {
+            if let Some(fragments) = fragment_ipv4(&msg.payload()[..payload], limit) {
+                debug!(
+                    "TUN worker, IP packet of {} bytes split into {} fragments",
+                    payload,
+                    fragments.len()
+                );
+                for fragment in fragments {
+                    let padded = padded_len(policy, fragment.len(), limit);
+                    let mut msg = PacketBuffer::new(padded);
+                    msg.payload_mut()[..fragment.len()].copy_from_slice(&fragment);
+                    let e = wg.router.send(msg.into_message());
+                    debug!("TUN worker, router returned {:?}", e);
+                }
+                continue;
+            }
+        }
+
         // truncate padding
-        let padded = padding(payload, mtu);
+        let padded = padded_len(policy, payload, mtu);
         log::trace!(
             "TUN worker, payload length = {}, padded length = {}",
             payload,
             padded
         );
-        msg.truncate(SIZE_MESSAGE_PREFIX + padded);
+        let truncated = msg.truncate(padded);
+        debug_assert!(truncated);
         debug_assert!(padded <= mtu);
         debug_assert_eq!(
-            if padded < mtu {
-                (msg.len() - SIZE_MESSAGE_PREFIX) % MESSAGE_PADDING_MULTIPLE
+            if padded < mtu && policy == Padding::Multiple {
+                msg.payload().len() % MESSAGE_PADDING_MULTIPLE
             } else {
                 0
             },
@@ -95,89 +200,315 @@ pub fn tun_worker<T: Tun, B: UDP>(wg: &WireGuard<T, B>, reader: T::Reader) {
         );
 
         // crypt-key route
-        let e = wg.router.send(msg);
+        let e = wg.router.send(msg.into_message());
         debug!("TUN worker, router returned {:?}", e);
     }
 }
 
 pub fn udp_worker<T: Tun, B: UDP>(wg: &WireGuard<T, B>, reader: B::Reader) {
+    pin_reader(wg);
+    let mut poll = BusyPoll::new(wg);
+
+    // every message is kept in an allocation of its exact size,
+    // e.g. a handshake message occupies 148 bytes rather than a buffer sized by the MTU:
+    // if the reader can peek at the size, the message is read directly into the allocation,
+    // otherwise a single buffer receives every datagram and the message is copied.
+    let mut buf: Vec<u8> = vec![];
     loop {
-        // create vector big enough for any message given current MTU
-        let mtu = wg.mtu.load(Ordering::Relaxed);
-        let size = mtu + MAX_HANDSHAKE_MSG_SIZE;
-        let mut msg: Vec<u8> = vec![0; size];
-
-        // read UDP packet into vector
-        let (size, src) = match reader.read(&mut msg) {
-            Err(e) => {
-                debug!("Bind reader closed with {}", e);
-                return;
+        // read UDP packet
+        let up = wg.mtu.load(Ordering::Relaxed) != 0;
+        let busy = poll.update(up, |nonblocking| reader.set_nonblocking(nonblocking));
+        let res = match reader.peek_type() {
+            Ok(Some((_, size))) => {
+                let mut data = vec![0; cmp::max(size, 1)];
+                reader.read(&mut data[..]).map(|(size, src)| {
+                    data.truncate(size);
+                    (data, src)
+                })
+            }
+            Ok(None) => {
+                buf.resize(SIZE_MAX_DATAGRAM, 0);
+                reader
+                    .read(&mut buf[..])
+                    .map(|(size, src)| (buf[..size].to_vec(), src))
             }
+            Err(e) => Err(e),
+        };
+        let (mut data, src) = match res {
             Ok(v) => v,
+            Err(e) => match e.kind() {
+                UDPReadErrorKind::Transient if busy => {
+                    spin_loop();
+                    continue;
+                }
+                UDPReadErrorKind::Transient => {
+                    debug!("Bind reader, transient failure: {}", e);
+                    continue;
+                }
+                UDPReadErrorKind::Unreachable => {
+                    debug!("Bind reader, {}", e);
+                    wg.unreachable(e.unreachable());
+                    continue;
+                }
+                UDPReadErrorKind::Closed => {
+                    debug!("Bind reader closed with {}", e);
+                    return;
+                }
+            },
         };
-        msg.truncate(size);
+
+        // account every message by kind, even while the device is down
+        // (e.g. to tell whether anything arrives at all)
+        wg.received
+            .add(src.into_address().is_ipv6(), classify(&data), data.len());
 
         // TODO: start device down
-        if mtu == 0 {
+        if wg.mtu.load(Ordering::Relaxed) == 0 {
             continue;
         }
 
         // message type de-multiplexer
-        if msg.len() < std::mem::size_of::<u32>() {
+        if data.len() < std::mem::size_of::<u32>() {
             continue;
         }
-        match LittleEndian::read_u32(&msg[..]) {
-            TYPE_COOKIE_REPLY | TYPE_INITIATION | TYPE_RESPONSE => {
+
+        // messages enclosed in relay frames are handled as if received from the relay
+        // (the tag is not interpreted: the enclosed message is authenticated)
+        if LittleEndian::read_u32(&data) == TYPE_RELAY_FRAME {
+            data = match unframe(&data) {
+                Some((_, inner)) if inner.len() >= std::mem::size_of::<u32>() => inner.to_vec(),
+                _ => {
+                    debug!("{} : reader, malformed relay frame", wg);
+                    continue;
+                }
+            };
+        }
+        let msg = &data[..];
+        match LittleEndian::read_u32(msg) {
+            ty @ TYPE_COOKIE_REPLY | ty @ TYPE_INITIATION | ty @ TYPE_RESPONSE => {
                 debug!("{} : reader, received handshake message", wg);
-                wg.pending.fetch_add(1, Ordering::SeqCst);
-                wg.queue.send(HandshakeJob::Message(msg, src));
+
+                // every handshake message has a fixed size:
+                // drop malformed messages before they consume any resources
+                if handshake_message_size(ty) != Some(msg.len()) {
+                    debug!(
+                        "{} : reader, handshake message has invalid length ({} bytes)",
+                        wg,
+                        msg.len()
+                    );
+                    wg.handshake_malformed.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+
+                // drop duplicate initiations (mac1 precedes mac2 at the end of the message)
+                if ty == TYPE_INITIATION {
+                    let mac1 = &msg[msg.len() - 2 * SIZE_MAC..msg.len() - SIZE_MAC];
+                    if !wg.dedup.check(src.into_address(), mac1) {
+                        debug!("{} : reader, dropping duplicate initiation", wg);
+                        wg.handshake_duplicate.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                }
+
+                // drop new handshake messages if the memory limit is reached
+                let reservation = match wg.router.memory().try_reserve(msg.len()) {
+                    Some(reservation) => reservation,
+                    None => {
+                        debug!(
+                            "{} : reader, memory limit reached, dropping handshake message",
+                            wg
+                        );
+                        continue;
+                    }
+                };
+                let queued = Pending::new(&wg.pending);
+                let pending = wg.pending.load(Ordering::SeqCst);
+                if wg.under_load.report_queue(pending) {
+                    log::trace!(
+                        "{} : reader, under load ({} handshakes queued)",
+                        wg,
+                        pending
+                    );
+                }
+
+                // responses and cookie replies (to our own initiations) are cheap,
+                // they must not be stuck behind a backlog of (expensive) initiations.
+                let epoch = wg.epoch.load(Ordering::SeqCst);
+                let job =
+                    HandshakeJob::Message(data, src, reservation, Instant::now(), queued, epoch);
+                if ty == TYPE_INITIATION {
+                    wg.queue.send(job);
+                } else {
+                    wg.queue.send_priority(job);
+                }
             }
             TYPE_TRANSPORT => {
                 debug!("{} : reader, received transport message", wg);
 
                 // transport message
-                let _ = wg.router.recv(src, msg).map_err(|e| {
+                let _ = wg.router.recv(src, data).map_err(|e| {
                     debug!("Failed to handle incoming transport message: {}", e);
                 });
             }
-            _ => (),
+            _ => {
+                debug!("{} : reader, unknown message type", wg);
+            }
+        }
+    }
+}
+
+// Classifies a received message by its type (the type of the enclosed message of relay frames)
+fn classify(msg: &[u8]) -> MessageKind {
+    if msg.len() < std::mem::size_of::<u32>() {
+        return MessageKind::Unknown;
+    }
+    let msg = if LittleEndian::read_u32(msg) == TYPE_RELAY_FRAME {
+        match unframe(msg) {
+            Some((_, inner)) if inner.len() >= std::mem::size_of::<u32>() => inner,
+            _ => return MessageKind::Unknown,
+        }
+    } else {
+        msg
+    };
+    match LittleEndian::read_u32(msg) {
+        TYPE_INITIATION => MessageKind::Initiation,
+        TYPE_RESPONSE => MessageKind::Response,
+        TYPE_COOKIE_REPLY => MessageKind::CookieReply,
+        TYPE_TRANSPORT => MessageKind::Transport,
+        _ => MessageKind::Unknown,
+    }
+}
+
+/* Lowers the OS scheduling priority of the calling thread.
+ *
+ * The nice value is an attribute of the thread (rather than the process) on Linux,
+ * on other platforms the priority is left unchanged.
+ *
+ * # Returns
+ *
+ * True if the priority was lowered
+ */
+#[cfg(target_os = "linux")]
+fn lower_priority() -> bool {
+    unsafe {
+        let tid = libc::syscall(libc::SYS_gettid) as libc::id_t;
+        libc::setpriority(libc::PRIO_PROCESS, tid, HANDSHAKE_LOW_PRIORITY_NICE) == 0
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn lower_priority() -> bool {
+    false
+}
+
+/* Pins the calling reader thread to the reader core (in performance mode) */
+fn pin_reader<T: Tun, B: UDP>(wg: &WireGuard<T, B>) {
+    if let Some(mode) = wg.workers.performance {
+        if pin_to_core(mode.reader) {
+            debug!("{} : reader, pinned to core {}", wg, mode.reader);
+        } else {
+            log::warn!("{} : reader, failed to pin to core {}", wg, mode.reader);
+        }
+    }
+}
+
+/* Busy polling of a reader in performance mode:
+ *
+ * While the device is up the reads are nonblocking and the reader spins on an empty queue,
+ * while the device is down the reads block, i.e. the reader is parked until the next message.
+ */
+struct BusyPoll {
+    enabled: bool,
+    nonblocking: bool,
+}
+
+impl BusyPoll {
+    fn new<T: Tun, B: UDP>(wg: &WireGuard<T, B>) -> BusyPoll {
+        BusyPoll {
+            enabled: wg.workers.performance.is_some(),
+            nonblocking: false,
+        }
+    }
+
+    /* Switches the reads between nonblocking (device up) and blocking (device down),
+     * returns true if the reads are nonblocking (the reader should spin on transient failures).
+     */
+    fn update<F: FnOnce(bool) -> bool>(&mut self, up: bool, set_nonblocking: F) -> bool {
+        if self.enabled && self.nonblocking != up {
+            if set_nonblocking(up) {
+                self.nonblocking = up;
+            } else {
+                log::warn!("reader does not support nonblocking reads, busy polling disabled");
+                self.enabled = false;
+            }
         }
+        self.nonblocking
     }
 }
 
 pub fn handshake_worker<T: Tun, B: UDP>(
     wg: &WireGuard<T, B>,
-    rx: Receiver<HandshakeJob<B::Endpoint>>,
+    rx: PriorityReceiver<HandshakeJob<B::Endpoint>>,
+    reader: usize,
 ) {
     debug!("{} : handshake worker, started", wg);
 
+    // yield to the router and reader workers (if configured)
+    if wg.workers.handshake_priority == HandshakePriority::Low {
+        if lower_priority() {
+            debug!("{} : handshake worker, lowered priority", wg);
+        } else {
+            log::warn!("{} : handshake worker, failed to lower priority", wg);
+        }
+    }
+
     // process elements from the handshake queue
     for job in rx {
-        // check if under load
-        let mut under_load = false;
         let job: HandshakeJob<B::Endpoint> = job;
-        let pending = wg.pending.fetch_sub(1, Ordering::SeqCst);
+        let pending = wg.pending.load(Ordering::SeqCst);
+        *wg.health.last_dequeue.lock() = Instant::now();
         debug_assert!(pending < MAX_QUEUED_INCOMING_HANDSHAKES + (1 << 16));
 
-        // immediate go under load if too many handshakes pending
-        if pending > THRESHOLD_UNDER_LOAD {
-            log::trace!("{} : handshake worker, under load (above threshold)", wg);
-            *wg.last_under_load.lock() = Instant::now();
-            under_load = true;
+        // immediate go under load if messages wait too long in the queue
+        if let HandshakeJob::Message(_, _, _, enqueued, _, _) = &job {
+            let wait = enqueued.elapsed();
+            if wg.under_load.report_wait(wait) {
+                log::trace!(
+                    "{} : handshake worker, under load (queued for {:?})",
+                    wg,
+                    wait
+                );
+            }
+        }
+
+        // check if under load (remains so until the load subsides, see UnderLoad)
+        // the job at hand remains pending until processed (dropped)
+        let under_load = wg.under_load.check(pending.saturating_sub(1));
+        if under_load {
+            log::trace!("{} : handshake worker, under load", wg);
         }
 
-        // remain under load for DURATION_UNDER_LOAD
-        if !under_load {
-            let elapsed = wg.last_under_load.lock().elapsed();
-            if DURATION_UNDER_LOAD >= elapsed {
-                log::trace!("{} : handshake worker, under load (recent)", wg);
-                under_load = true;
+        // the job is processed within a section of the worker (see WireGuard::set_key and gc),
+        // jobs queued under a previous configuration are discarded rather than processed
+        let _processing = wg.processing.enter(reader);
+        if job.epoch() != wg.epoch.load(Ordering::SeqCst) {
+            debug!(
+                "{} : handshake worker, discarding job of previous configuration",
+                wg
+            );
+            if let HandshakeJob::New(pk, _, _) = &job {
+                if let Some(peer) = wg.peers.read().get(pk) {
+                    peer.opaque()
+                        .handshake_queued
+                        .store(false, Ordering::SeqCst);
+                }
             }
+            continue;
         }
 
         // de-multiplex staged handshake jobs and handshake messages
         match job {
-            HandshakeJob::Message(msg, mut src) => {
+            HandshakeJob::Message(msg, mut src, _, _, _, _) => {
                 // process message
                 let device = wg.peers.read();
                 match device.process(
@@ -190,17 +521,49 @@ pub fn handshake_worker<T: Tun, B: UDP>(
                     },
                 ) {
                     Ok((peer, resp, keypair)) => {
+                        // ignore handshakes of peers disabled by their quota
+                        if peer.map(|peer| peer.opaque().quota_exceeded()) == Some(true) {
+                            debug!("{} : handshake worker, peer disabled (quota exceeded)", wg);
+                            if let Some(kp) = keypair {
+                                device.release(kp.local_id());
+                            }
+                            continue;
+                        }
+
+                        // ignore handshakes vetoed by the authorization (if any)
+                        if let (Some(peer), Some(kp)) = (peer, keypair.as_ref()) {
+                            if !peer.opaque().authorize_handshake(src.into_address()) {
+                                debug!("{} : handshake worker, handshake of {} denied", wg, peer);
+                                device.release(kp.local_id());
+                                continue;
+                            }
+                        }
+
                         // send response (might be cookie reply or handshake response)
                         let mut resp_len: u64 = 0;
-                        if let Some(msg) = resp {
-                            resp_len = msg.len() as u64;
-                            // TODO: consider a more elegant solution for accessing the bind
-                            let _ = wg.router.send_raw(&msg[..], &mut src).map_err(|e| {
-                                debug!(
-                                    "{} : handshake worker, failed to send response, error = {}",
-                                    wg, e
-                                );
-                            });
+                        if let Some(resp) = resp {
+                            resp_len = resp.len() as u64;
+
+                            // replies to messages from the relay are enclosed in relay frames
+                            match peer {
+                                Some(peer) if peer.is_relay(src.into_address()) => {
+                                    let _ = peer.send_relayed(&resp[..]).map_err(|e| {
+                                        debug!(
+                                            "{} : handshake worker, failed to relay response, error = {}",
+                                            wg, e
+                                        );
+                                    });
+                                }
+                                _ => {
+                                    // TODO: consider a more elegant solution for accessing the bind
+                                    let _ = wg.router.send_raw(&resp[..], &mut src).map_err(|e| {
+                                        debug!(
+                                            "{} : handshake worker, failed to send response, error = {}",
+                                            wg, e
+                                        );
+                                    });
+                                }
+                            }
                         }
 
                         // update peer state
@@ -209,13 +572,26 @@ pub fn handshake_worker<T: Tun, B: UDP>(
 
                             // add to rx_bytes and tx_bytes
                             let req_len = msg.len() as u64;
-                            peer.opaque().rx_bytes.fetch_add(req_len, Ordering::Relaxed);
-                            peer.opaque()
-                                .tx_bytes
-                                .fetch_add(resp_len, Ordering::Relaxed);
+                            peer.opaque().traffic.add_rx(req_len, 0);
+                            peer.opaque().traffic.add_tx(resp_len, 0);
 
                             // update endpoint
-                            peer.set_endpoint(src);
+                            // (a handshake over the direct path ends any race over the candidate
+                            // endpoints and confirms the direct path to a relayed peer)
+                            let addr = src.into_address();
+                            peer.roam_endpoint(src);
+                            if peer.is_relay(addr) {
+                                debug!("{} : handshake worker, handshake over the relay", wg);
+                            } else {
+                                peer.opaque().race_endpoints.store(false, Ordering::SeqCst);
+                                peer.opaque().report_path_up(addr);
+                                if peer.confirm_direct(addr) {
+                                    debug!(
+                                        "{} : handshake worker, direct path to {} confirmed",
+                                        wg, peer
+                                    );
+                                }
+                            }
 
                             if resp_len > 0 {
                                 // update timers after sending handshake response
@@ -227,7 +603,11 @@ pub fn handshake_worker<T: Tun, B: UDP>(
                                     "{} : handshake worker, handshake response was received",
                                     wg
                                 );
+                                let rtt = peer.opaque().last_handshake_sent.lock().elapsed();
+                                *peer.opaque().handshake_rtt.lock() = Some(rtt);
                                 peer.opaque().timers_handshake_complete();
+                                peer.opaque()
+                                    .set_handshake_state(HandshakeState::ResponseReceived);
                             }
 
                             // add any new keypair to peer
@@ -236,28 +616,80 @@ pub fn handshake_worker<T: Tun, B: UDP>(
 
                                 // this means that a handshake response was processed or sent
                                 peer.opaque().timers_session_derived();
+                                peer.opaque().handshakes.fetch_add(1, Ordering::Relaxed);
 
                                 // free any unused ids
+                                let initiator = kp.initiator;
                                 for id in peer.add_keypair(kp) {
                                     device.release(id);
                                 }
+
+                                // the response confirms the key-pair of the initiator
+                                if initiator {
+                                    peer.opaque().set_handshake_state(HandshakeState::Confirmed);
+                                }
                             });
                         }
                     }
-                    Err(e) => debug!("{} : handshake worker, error = {:?}", wg, e),
+                    Err(e) => {
+                        debug!("{} : handshake worker, error = {:?}", wg, e);
+                        if let HandshakeError::Stealth = e {
+                            wg.stealth_dropped.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
                 }
             }
-            HandshakeJob::New(pk) => {
+            HandshakeJob::New(pk, _, _) => {
                 if let Some(peer) = wg.peers.read().get(&pk) {
                     debug!(
                         "{} : handshake worker, new handshake requested for {}",
                         wg, peer
                     );
                     let device = wg.peers.read();
-                    let _ = device.begin(&mut OsRng, &pk).map(|msg| {
+                    let initiation = device.begin(&mut OsRng, &pk);
+                    if let Err(e) = &initiation {
+                        debug!(
+                            "{} : handshake worker, no initiation created, error = {}",
+                            wg, e
+                        );
+                    }
+                    let _ = initiation.map(|msg| {
+                        // mac2 (the last field) is only set when a cookie of the peer is held
+                        if msg[msg.len() - SIZE_MAC..] != [0u8; SIZE_MAC] {
+                            debug!("{} : handshake worker, initiation carries cookie", wg);
+                            peer.opaque().cookie_retries.fetch_add(1, Ordering::Relaxed);
+                        }
+
                         let _ = peer.send_raw(&msg[..]).map_err(|e| {
                             debug!("{} : handshake worker, failed to send handshake initiation, error = {}", wg, e)
                         });
+
+                        // probe the direct path while the peer is relayed
+                        // (the responder rejects the later copy as a replay)
+                        if peer.is_relayed() {
+                            let _ = peer.send_direct(&msg[..]).map_err(|e| {
+                                debug!("{} : handshake worker, failed to probe direct path, error = {}", wg, e)
+                            });
+                        }
+
+                        // race the initiation over the candidate endpoints
+                        // (the responder rejects every copy but the first as a replay)
+                        if peer.opaque().race_endpoints.load(Ordering::SeqCst) {
+                            let current = peer.get_endpoint();
+                            for addr in peer.opaque().endpoint_candidates.lock().iter() {
+                                if Some(*addr) == current {
+                                    continue;
+                                }
+                                debug!("{} : handshake worker, racing initiation to {}", wg, addr);
+                                let mut dst = B::Endpoint::from_address(*addr);
+                                let _ = wg.router.send_raw(&msg[..], &mut dst).map_err(|e| {
+                                    debug!(
+                                        "{} : handshake worker, failed to send handshake initiation, error = {}",
+                                        wg, e
+                                    )
+                                });
+                            }
+                        }
                         peer.opaque().sent_handshake_initiation();
                     });
                     peer.opaque()